@@ -8,47 +8,34 @@ extern crate userspace_slaballoc;
 
 pub mod bitfields;
 
-use core::{
-    iter::Cycle,
-    mem::size_of,
-    ops::{ControlFlow, Range},
-    slice,
-};
+use core::{mem::size_of, slice};
 
 use alloc::{sync::Arc, vec::Vec};
 use spin::Mutex;
-use x86_64::instructions::port::Port;
 
+use driver_sdk::{
+    driver_main,
+    net::{self, Nic},
+    BindContext, Driver,
+};
 use kernel_userspace::{
-    backoff_sleep,
-    channel::{
-        channel_read_resize, channel_read_rs, channel_write_rs, channel_write_val,
-        ChannelReadResult,
-    },
-    interrupt::interrupt_wait,
-    net::PhysicalNet,
-    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
-    pci::PCIDevice,
-    process::get_handle,
-    service::{deserialize, serialize, Service, SimpleService},
-    syscall::{exit, mmap_page32, spawn_thread, yield_now},
-    INT_PCI,
+    channel::channel_write_rs,
+    dma::{DmaBuffer, DmaConstraints},
+    memory::MemoryHandle,
+    net::ring::{PacketRing, SLOT_SIZE},
+    object::KernelReference,
+    syscall::yield_now,
 };
 
 use self::bitfields::InitBlock;
 
-pub enum SendError {
-    BufferFull,
-}
-
-const IP_ADDR: u32 = 100 << 24 | 1 << 16 | 168 << 8 | 192;
-
-const BUFFER_ENTRY_SIZE: u32 = 2048;
+const BUFFER_ENTRY_SIZE: u32 = SLOT_SIZE as u32;
 const BUFFER_SIZE_MASK: u32 = 0xF000 | (0xFFF & (1 + !(BUFFER_ENTRY_SIZE)));
 const SEND_BUFFER_CNT_LOG: u8 = 3;
 const RECV_BUFFER_CNT_LOG: u8 = 3;
 const SEND_BUFFER_CNT: usize = 2usize.pow(SEND_BUFFER_CNT_LOG as u32);
 const RECV_BUFFER_CNT: usize = 2usize.pow(RECV_BUFFER_CNT_LOG as u32);
+const CSR0_STOP: u32 = 1 << 2;
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
@@ -59,140 +46,97 @@ struct BufferDescriptor {
     avail: u32,
 }
 
-#[export_name = "_start"]
-pub extern "C" fn main() {
-    let pci_ref = KernelReferenceID::from_usize(2).unwrap();
-    assert_eq!(get_type(pci_ref), KernelObjectType::Channel);
-    let pci_device = SimpleService::new(KernelReference::from_id(pci_ref));
+/// The card, bound through the sdk so it gets its interrupt and is stopped on shutdown
+impl Driver for PCNET<'static> {
+    const NAME: &'static str = "pcnet";
 
-    let pcnet = Arc::new(Mutex::new(
-        PCNET::new(PCIDevice {
-            device_service: pci_device,
-        })
-        .unwrap(),
-    ));
+    fn bind(ctx: &mut BindContext) -> Option<Self> {
+        ctx.subscribe_pci()?;
+        PCNET::new(ctx.take_pci()?)
+    }
 
-    spawn_thread({
-        let pcnet = pcnet.clone();
-        move || {
-            let interrupts = backoff_sleep(|| get_handle("INTERRUPTS"));
+    fn interrupt(&mut self, _source: usize) {
+        self.interrupt_handler();
+    }
 
-            channel_write_val(interrupts, &INT_PCI, &[]);
+    fn suspend(&mut self) {
+        // Nothing can be written to memory once the machine is going off
+        self.io.write_csr_32(0, CSR0_STOP)
+    }
 
-            let mut handles_buffer = Vec::with_capacity(1);
+    fn serve(this: &Arc<Mutex<Self>>) {
+        net::serve("pcnet", this);
+    }
+}
 
-            match channel_read_rs(interrupts, &mut Vec::new(), &mut handles_buffer) {
-                ChannelReadResult::Ok => (),
-                _ => panic!(),
-            }
-            let pci_ev = handles_buffer[0];
-            loop {
-                interrupt_wait(pci_ev);
-                pcnet.lock().interrupt_handler();
-            }
-        }
-    });
-
-    let mut buffer = Vec::new();
-    let mut handles_buffer = Vec::new();
-    Service::new(
-        "PCNET",
-        || (),
-        |handle, ()| {
-            match channel_read_resize(handle.id(), &mut buffer, &mut handles_buffer) {
-                ChannelReadResult::Ok => (),
-                e => {
-                    println!("Error: {e:?}");
-                    return ControlFlow::Break(());
-                }
-            }
+driver_main!(PCNET<'static>);
 
-            match deserialize(&buffer).unwrap() {
-                PhysicalNet::MacAddrGet => {
-                    if !handles_buffer.is_empty() {
-                        println!("Bad amount of handles");
-                        return ControlFlow::Break(());
-                    }
-                    let resp = pcnet.lock().read_mac_addr();
-                    let resp = serialize(&resp, &mut buffer);
-                    channel_write_rs(handle.id(), &resp, &[]);
-                }
-                PhysicalNet::SendPacket(packet) => {
-                    if !handles_buffer.is_empty() {
-                        println!("Bad amount of handles");
-                        return ControlFlow::Break(());
-                    }
-                    // Keep trying to send
-                    while pcnet.lock().send_packet(packet).is_err() {
-                        yield_now()
-                    }
-                    channel_write_rs(handle.id(), &[], &[]);
-                }
-                PhysicalNet::ListenToPackets => {
-                    if handles_buffer.len() != 1 {
-                        println!("Bad amount of handles");
-                        return ControlFlow::Break(());
-                    }
-                    pcnet
-                        .lock()
-                        .listeners
-                        .push(KernelReference::from_id(handles_buffer[0]));
-                    channel_write_rs(handle.id(), &[], &[]);
-                }
-            };
-            ControlFlow::Continue(())
-        },
-    )
-    .run();
+/// The stack uses the rings, so frames never go through [`Nic::send`]
+impl Nic for PCNET<'static> {
+    const RINGS: bool = true;
+
+    fn mac(&mut self) -> u64 {
+        self.read_mac_addr()
+    }
+
+    fn listen(&mut self, listener: KernelReference) {
+        self.listeners.push(listener);
+    }
+
+    fn share_rings(&mut self) -> Option<(MemoryHandle, MemoryHandle)> {
+        Some((self.rx.share()?, self.tx.share()?))
+    }
+
+    fn kick(&mut self) {
+        self.receive();
+        self.transmit();
+    }
 }
 
-pub struct PCNETIOPort(u16);
+/// The card's registers through its memory bar, laid out the same as the io ports
+pub struct PCNETRegs(usize);
+
+impl PCNETRegs {
+    fn read_32(&mut self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.0 + offset) as *const u32) }
+    }
+
+    fn write_32(&mut self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile((self.0 + offset) as *mut u32, val) }
+    }
 
-impl PCNETIOPort {
     fn write_rap_32(&mut self, val: u32) {
-        let mut port = Port::new(self.0 + 0x14);
-        unsafe { port.write(val) }
+        self.write_32(0x14, val)
     }
 
     fn read_csr_32(&mut self, csr_no: u32) -> u32 {
         self.write_rap_32(csr_no);
-        let mut port = Port::new(self.0 + 0x10);
-        unsafe { port.read() }
+        self.read_32(0x10)
     }
 
     fn write_csr_32(&mut self, csr_no: u32, val: u32) {
         self.write_rap_32(csr_no);
-        let mut port = Port::new(self.0 + 0x10);
-        unsafe { port.write(val) }
+        self.write_32(0x10, val)
     }
 
     fn read_bcr_32(&mut self, bcr: u32) -> u32 {
         self.write_rap_32(bcr);
-        let mut port = Port::new(self.0 + 0x1C);
-        unsafe { port.read() }
+        self.read_32(0x1C)
     }
 
     fn write_bcr_32(&mut self, bcr: u32, val: u32) {
         self.write_rap_32(bcr);
-        let mut port = Port::new(self.0 + 0x1C);
-        unsafe { port.write(val) }
+        self.write_32(0x1C, val)
     }
 
     fn reset_device(&mut self) {
-        let mut reset_port_32: Port<u32> = Port::new(self.0 + 0x18);
-        let mut reset_port_16: Port<u32> = Port::new(self.0 + 0x14);
         // Reset to defaults
-        unsafe {
-            reset_port_32.read();
-            reset_port_16.read();
-        }
+        self.read_32(0x18);
+        self.read_32(0x14);
         // We need to wait 1ms
         yield_now();
         // 32 bit mode
-        let mut data_register: Port<u32> = Port::new(self.0 + 0x10);
-        unsafe {
-            data_register.write(0);
-        }
+        self.write_32(0x10, 0);
         // SWSTYLE (32 bit buffers)
         let mut csr58 = self.read_csr_32(58);
         csr58 &= 0xFF00;
@@ -206,25 +150,27 @@ impl PCNETIOPort {
     }
 
     fn read_mac_addr(&mut self) -> u64 {
-        let mut mac_address: Port<u32> = Port::new(self.0);
-        let mut mac_address2: Port<u32> = Port::new(self.0 + 0x4);
-        unsafe {
-            let mac = mac_address.read() as u64;
-            let mac2 = mac_address2.read() as u64 & 0xFFFF;
-            mac2 << 32 | mac
-        }
+        let mac = self.read_32(0) as u64;
+        let mac2 = self.read_32(0x4) as u64 & 0xFFFF;
+        mac2 << 32 | mac
     }
 }
 
 #[allow(dead_code)]
 pub struct PCNET<'b> {
-    io: PCNETIOPort,
+    io: PCNETRegs,
     init_block: &'b mut InitBlock,
     send_buffer_desc: &'b mut [BufferDescriptor],
-    send_buffer_pos: Cycle<Range<usize>>,
     recv_buffer_desc: &'b mut [BufferDescriptor],
-    revc_buffer_pos: Cycle<Range<usize>>,
-    owned_pages: Vec<u32>,
+    header_mem: DmaBuffer,
+    /// The card receives straight into this ring's slots, descriptor i being slot i
+    rx: PacketRing,
+    /// Where the receive descriptors have been given to the card up to
+    rx_armed: u32,
+    /// The card sends straight out of this ring's slots, descriptor i being slot i
+    tx: PacketRing,
+    /// Where the transmit descriptors have been given to the card up to
+    tx_submitted: u32,
     listeners: Vec<KernelReference>,
 }
 
@@ -238,10 +184,12 @@ impl PCNET<'_> {
             return None;
         };
 
-        let pci_device = unsafe { common_header.get_as_header0() };
-
-        let port_base: u16 = pci_device.get_port_base().unwrap().try_into().unwrap();
-        let mut port = PCNETIOPort(port_base);
+        // Bar 0 is the same registers in io space, which a user process can't use
+        let Some(regs) = common_header.device.lock().get_bar(1).and_then(|b| b.map()) else {
+            println!("PCNET has no memory bar");
+            return None;
+        };
+        let mut port = PCNETRegs(regs as usize);
 
         port.reset_device();
 
@@ -252,18 +200,17 @@ impl PCNET<'_> {
 
         assert!(header_mem_size <= 0x1000);
 
-        let mut owned_pages = Vec::new();
+        // The card only takes 32 bit addresses
+        let header_mem = DmaBuffer::new_32(header_mem_size).unwrap();
+        let buffer_constraints = DmaConstraints {
+            below_4g: true,
+            ..Default::default()
+        };
+        let tx = PacketRing::new(SEND_BUFFER_CNT as u32, buffer_constraints).unwrap();
+        let rx = PacketRing::new(RECV_BUFFER_CNT as u32, buffer_constraints).unwrap();
 
         let (init_block, send_buffer_desc, recv_buffer_desc) = unsafe {
-            // Allocate page below 4gb location.
-            // let buffer = frame_alloc_exec(|m| m.request_32bit_reserved_page()).unwrap();
-            // ident_map_curr_process(*buffer, true);
-            let buffer = mmap_page32();
-
-            let buffer_start = buffer;
-            owned_pages.push(buffer);
-
-            let mut buffer_start = buffer_start as *const u8;
+            let mut buffer_start = header_mem.as_ptr() as *const u8;
 
             // Init block
             let init_block = &mut *(buffer_start as *mut InitBlock);
@@ -284,46 +231,38 @@ impl PCNET<'_> {
         init_block.set_num_send_buffers(SEND_BUFFER_CNT_LOG);
         init_block.set_num_recv_buffers(RECV_BUFFER_CNT_LOG);
         init_block.set_physical_address(mac);
-        init_block.set_logical_address(IP_ADDR.into());
-        init_block
-            .set_send_buffer_desc_addr(&send_buffer_desc[0] as *const BufferDescriptor as u32);
-
-        init_block
-            .set_recv_buffer_desc_addr(&recv_buffer_desc[0] as *const BufferDescriptor as u32);
-
-        // Alloc buffer each 2 buffer
-        for i in (0..SEND_BUFFER_CNT).step_by(2) {
-            // Allocate page below 4gb location.
-            let buffer = mmap_page32();
-            owned_pages.push(buffer);
-
-            send_buffer_desc[i].address = buffer;
-            send_buffer_desc[i].flags = BUFFER_SIZE_MASK;
-            send_buffer_desc[i + 1].address = buffer + 2048;
-            send_buffer_desc[i + 1].flags = BUFFER_SIZE_MASK;
+        // The logical address is the multicast hash filter. Every group is let through as ipv6
+        // neighbour discovery needs them, the network stack works out which are ours.
+        init_block.set_logical_address(u64::MAX);
+        init_block.set_send_buffer_desc_addr(header_mem.phys_addr(size_of::<InitBlock>()) as u32);
+
+        init_block.set_recv_buffer_desc_addr(
+            header_mem.phys_addr(
+                size_of::<InitBlock>() + size_of::<[BufferDescriptor; SEND_BUFFER_CNT]>(),
+            ) as u32,
+        );
+
+        for (i, desc) in send_buffer_desc.iter_mut().enumerate() {
+            desc.address = tx.buffer_phys(i as u32).unwrap() as u32;
+            desc.flags = BUFFER_SIZE_MASK;
         }
-        // Alloc buffer each 2 buffer
-        for i in (0..RECV_BUFFER_CNT).step_by(2) {
-            // Allocate page below 4gb location.
-            let buffer = mmap_page32();
-            owned_pages.push(buffer);
-
-            recv_buffer_desc[i].address = buffer;
-            recv_buffer_desc[i].flags = BUFFER_SIZE_MASK | 0x80000000;
-            recv_buffer_desc[i + 1].address = buffer + 2048;
-            recv_buffer_desc[i + 1].flags = BUFFER_SIZE_MASK | 0x80000000;
+        for (i, desc) in recv_buffer_desc.iter_mut().enumerate() {
+            desc.address = rx.buffer_phys(i as u32).unwrap() as u32;
+            desc.flags = BUFFER_SIZE_MASK | 0x80000000;
         }
 
-        let init_block_addr = init_block as *const InitBlock as u32;
+        let init_block_addr = header_mem.phys_addr(0) as u32;
 
         let mut this = Self {
             io: port,
             init_block,
-            send_buffer_pos: (0..send_buffer_desc.len()).cycle(),
             send_buffer_desc,
-            revc_buffer_pos: (0..recv_buffer_desc.len()).cycle(),
             recv_buffer_desc,
-            owned_pages,
+            header_mem,
+            rx,
+            rx_armed: RECV_BUFFER_CNT as u32,
+            tx,
+            tx_submitted: 0,
             listeners: Vec::new(),
         };
 
@@ -371,12 +310,15 @@ impl PCNET<'_> {
         } else {
             // TODO: QEMU For some reason doesn't assert the bitflags in csr 0 to saw what caused the interrupts
             // At least it sends a PCI interrupt so just check the buffers whenever there is an interrupt.
+            // Without a vector of its own that interrupt may have been another device's.
             println!("AMD am79c973 Checking receive buffers.");
             self.receive();
         }
         if tmp & 0x200 > 0 {
             println!("AMD am79c973 DATA SENT")
         }
+        // Hands the stack back the slots of what has been sent
+        self.transmit();
         if tmp & 0x100 > 0 {
             println!("AMD am79c973 INIT DONE")
         }
@@ -386,66 +328,70 @@ impl PCNET<'_> {
 }
 
 impl PCNET<'_> {
-    fn send_packet(&mut self, data: &[u8]) -> Result<(), SendError> {
-        for buffer in self
-            .send_buffer_pos
-            .by_ref()
-            .take(self.send_buffer_desc.len())
+    /// Gives the card the frames the stack has put in the transmit ring, and hands the stack
+    /// back the slots of those the card has sent
+    fn transmit(&mut self) {
+        let slots = self.tx.slots();
+        while self.tx.tail() != self.tx_submitted
+            && self.send_buffer_desc[(self.tx.tail() % slots) as usize].flags & 0x80000000 == 0
         {
-            let buffer_desc = &mut self.send_buffer_desc[buffer];
-            // Find a buffer which we own
-            if buffer_desc.flags & 0x80000000 == 0 {
-                let send_buffer = unsafe {
-                    slice::from_raw_parts_mut(
-                        buffer_desc.address as *mut u8,
-                        BUFFER_ENTRY_SIZE as usize,
-                    )
-                };
-                send_buffer[..data.len()].clone_from_slice(data);
-
-                buffer_desc.avail = 0;
-                buffer_desc.flags_2 = 0;
-                // Then length is twos complement of bytes
-                buffer_desc.flags = 0x8300F000 | ((!data.len() + 1) as u16 as u32);
-
-                // Set TDMD
-                let tmp = self.io.read_csr_32(0);
-                self.io.write_csr_32(0, tmp | 0x8);
-                return Ok(());
-            }
+            self.tx.pop();
+        }
+
+        let mut sent = false;
+        while self.tx_submitted != self.tx.head() {
+            let len = self.tx.get(self.tx_submitted).len();
+            let buffer_desc = &mut self.send_buffer_desc[(self.tx_submitted % slots) as usize];
+            buffer_desc.avail = 0;
+            buffer_desc.flags_2 = 0;
+            // Then length is twos complement of bytes
+            buffer_desc.flags = 0x8300F000 | ((!len + 1) as u16 as u32);
+            self.tx_submitted = self.tx_submitted.wrapping_add(1);
+            sent = true;
+        }
+
+        if sent {
+            // Set TDMD
+            let tmp = self.io.read_csr_32(0);
+            self.io.write_csr_32(0, tmp | 0x8);
         }
-        Err(SendError::BufferFull)
     }
 
     fn read_mac_addr(&mut self) -> u64 {
         self.io.read_mac_addr()
     }
 
+    /// Hands the stack what the card has received, and gives the card back the slots the stack
+    /// is done with
     pub fn receive(&mut self) {
-        for buffer in self
-            .revc_buffer_pos
-            .by_ref()
-            .take(self.recv_buffer_desc.len())
-        {
-            let buffer_desc = &mut self.recv_buffer_desc[buffer];
+        let slots = self.rx.slots();
+        while self.rx_armed != self.rx.tail().wrapping_add(slots) {
+            let buffer_desc = &mut self.recv_buffer_desc[(self.rx_armed % slots) as usize];
+            buffer_desc.flags_2 = 0;
+            buffer_desc.flags = 0x80000000 | BUFFER_SIZE_MASK;
+            self.rx_armed = self.rx_armed.wrapping_add(1);
+        }
+
+        let mut received = false;
+        while self.rx.head() != self.rx_armed {
+            let buffer_desc = &self.recv_buffer_desc[(self.rx.head() % slots) as usize];
             let flags = buffer_desc.flags;
-            if flags & 0x80000000 == 0 {
-                if flags & 0x40000000 == 0 && flags & 0x03000000 > 0 {
-                    let size: usize = buffer_desc.flags_2 as usize & 0xFFFF;
-                    let packet =
-                        unsafe { slice::from_raw_parts(buffer_desc.address as *const u8, size) };
-                    self.listeners
-                        .retain(|l| channel_write_rs(l.id(), packet, &[]));
-                }
-                buffer_desc.flags = 0x80000000 | BUFFER_SIZE_MASK;
-                buffer_desc.flags_2 = 0;
+            if flags & 0x80000000 != 0 {
+                break;
             }
+            // Frames with errors are left empty for the stack to skip
+            let size = if flags & 0x40000000 == 0 && flags & 0x03000000 > 0 {
+                buffer_desc.flags_2 as usize & 0xFFFF
+            } else {
+                0
+            };
+            self.rx.push(size);
+            received = true;
         }
-    }
-}
 
-#[panic_handler]
-fn panic(i: &core::panic::PanicInfo) -> ! {
-    println!("{}", i);
-    exit()
+        if received {
+            self.listeners
+                .retain(|l| channel_write_rs(l.id(), &[], &[]));
+        }
+    }
 }
@@ -0,0 +1,204 @@
+//! Driver for the Bochs display interface (DISPI), which QEMU's standard vga and bochs-display
+//! have.
+//!
+//! The firmware's mode is left alone until the `DISPLAY` service is asked for another, then the
+//! DISPI registers are set to it and the console is moved onto the framebuffer again.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+use core::{
+    ops::ControlFlow,
+    ptr::{read_volatile, write_volatile},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use kernel_userspace::{
+    backoff_sleep,
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    display::{Console, DisplayMessage, DisplayMode, DisplayResponse, Framebuffer},
+    memory::MemoryHandle,
+    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
+    pci::{Bar, PCIDevice, PCIHeaderCommon},
+    service::{deserialize, serialize, Service, SimpleService},
+    syscall::exit,
+};
+
+/// Where the DISPI registers are in the mmio bar, each is 16 bits
+const DISPI_OFFSET: usize = 0x500;
+
+const INDEX_ID: usize = 0x0;
+const INDEX_XRES: usize = 0x1;
+const INDEX_YRES: usize = 0x2;
+const INDEX_BPP: usize = 0x3;
+const INDEX_ENABLE: usize = 0x4;
+const INDEX_VIRT_WIDTH: usize = 0x6;
+const INDEX_X_OFFSET: usize = 0x8;
+const INDEX_Y_OFFSET: usize = 0x9;
+/// How much video memory there is in 64kb blocks
+const INDEX_VIDEO_MEMORY_64K: usize = 0xA;
+
+/// The first version with the registers above
+const ID_MIN: u16 = 0xB0C2;
+const ID_MAX: u16 = 0xB0CF;
+
+const ENABLE_ENABLED: u16 = 0x01;
+const ENABLE_LFB: u16 = 0x40;
+
+/// The console only draws 32 bit pixels
+const BPP: u16 = 32;
+/// The biggest mode DISPI allows
+const MAX_XRES: u32 = 16000;
+const MAX_YRES: u32 = 12000;
+
+struct Bochs {
+    dispi: usize,
+    framebuffer: MemoryHandle,
+    /// Bytes of video memory
+    vram: usize,
+}
+
+impl Bochs {
+    fn read(&self, index: usize) -> u16 {
+        unsafe { read_volatile((self.dispi + index * 2) as *const u16) }
+    }
+
+    fn write(&mut self, index: usize, val: u16) {
+        unsafe { write_volatile((self.dispi + index * 2) as *mut u16, val) }
+    }
+
+    fn mode(&self) -> DisplayMode {
+        DisplayMode {
+            width: self.read(INDEX_XRES) as u32,
+            height: self.read(INDEX_YRES) as u32,
+        }
+    }
+
+    /// Switches to the mode and moves the console onto it
+    fn set_mode(&mut self, console: &mut Console, mode: DisplayMode) -> Option<DisplayMode> {
+        let DisplayMode { width, height } = mode;
+        // Widths are kept to a multiple of 8 as not all of them work
+        if width == 0 || height == 0 || width > MAX_XRES || height > MAX_YRES || width % 8 != 0 {
+            return None;
+        }
+        if width as usize * height as usize * 4 > self.vram {
+            return None;
+        }
+
+        self.write(INDEX_ENABLE, 0);
+        self.write(INDEX_XRES, width as u16);
+        self.write(INDEX_YRES, height as u16);
+        self.write(INDEX_BPP, BPP);
+        self.write(INDEX_ENABLE, ENABLE_ENABLED | ENABLE_LFB);
+        self.write(INDEX_X_OFFSET, 0);
+        self.write(INDEX_Y_OFFSET, 0);
+
+        // The device can round the mode, so it's read back
+        let mode = self.mode();
+        let framebuffer = Framebuffer {
+            width: mode.width,
+            height: mode.height,
+            stride: self.read(INDEX_VIRT_WIDTH) as u32,
+        };
+        if !console.set_framebuffer(framebuffer, &self.framebuffer) {
+            println!("Bochs VBE couldn't move the console");
+        }
+        Some(mode)
+    }
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let pci_ref = KernelReferenceID::from_usize(2).unwrap();
+    assert_eq!(get_type(pci_ref), KernelObjectType::Channel);
+    let header = PCIHeaderCommon {
+        device: Arc::new(Mutex::new(PCIDevice {
+            device_service: SimpleService::new(KernelReference::from_id(pci_ref)),
+        })),
+    };
+
+    // The framebuffer is the whole of bar 0, and the registers are in bar 2
+    let framebuffer = match header.device.lock().get_bar(0) {
+        Some(Bar::Memory {
+            handle,
+            offset: 0,
+            size,
+        }) => Some((handle, size as usize)),
+        _ => None,
+    };
+    let Some((framebuffer, bar_size)) = framebuffer else {
+        println!("Bochs VBE has no framebuffer");
+        exit()
+    };
+    let Some(regs) = header.device.lock().get_bar(2).and_then(|b| b.map()) else {
+        println!("Bochs VBE has no mmio bar, only the io ports are there");
+        exit()
+    };
+
+    let mut bochs = Bochs {
+        dispi: regs as usize + DISPI_OFFSET,
+        framebuffer,
+        vram: 0,
+    };
+    let id = bochs.read(INDEX_ID);
+    if !(ID_MIN..=ID_MAX).contains(&id) {
+        println!("Bochs VBE has an unknown id {id:#x}");
+        exit()
+    }
+    bochs.vram = match bochs.read(INDEX_VIDEO_MEMORY_64K) {
+        0 => bar_size,
+        blocks => (blocks as usize * 0x10000).min(bar_size),
+    };
+    let mode = bochs.mode();
+    println!(
+        "Bochs VBE ready at {}x{} with {}MB",
+        mode.width,
+        mode.height,
+        bochs.vram / 0x100000
+    );
+
+    let mut console = backoff_sleep(Console::open);
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+    let mut out = Vec::new();
+
+    Service::new(
+        "DISPLAY",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            let response = match deserialize(&buffer) {
+                Ok(DisplayMessage::GetMode) => DisplayResponse::Mode(bochs.mode()),
+                Ok(DisplayMessage::SetMode(mode)) => match bochs.set_mode(&mut console, mode) {
+                    Some(mode) => DisplayResponse::Mode(mode),
+                    None => DisplayResponse::Unsupported,
+                },
+                Err(e) => {
+                    println!("Bochs VBE got a bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+            channel_write_rs(handle.id(), serialize(&response, &mut out), &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
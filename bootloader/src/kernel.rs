@@ -1,11 +1,14 @@
-use core::cmp::{max, min};
+use core::{
+    arch::x86_64::__cpuid,
+    cmp::{max, min},
+};
 
 use uefi::{
     prelude::BootServices,
     table::boot::{AllocateType, MemoryType},
 };
 
-use crate::{paging::get_uefi_active_mapper, BootInfo, OwnedBuffer};
+use crate::{paging::get_uefi_active_mapper, random, BootInfo, OwnedBuffer};
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -39,15 +42,99 @@ pub struct Elf64Phdr {
     pub p_align: u64,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Elf64Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Elf64Rela {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
 // For the ELF Header https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
 const ELFCLASS64: u8 = 2; // 64 BIT
 const ELFDATA2LSB: u8 = 1; // LSB not MSB
 
 const ET_EXEC: u16 = 2; // Executable file
+const ET_DYN: u16 = 3; // Position independent executable
 const EM_X86_64: u16 = 62; // AMD x86-64 architecture
 
 // For the ELF Program Header https://refspecs.linuxbase.org/elf/gabi4+/ch5.pheader.html
 const PT_LOAD: u32 = 1; // A loadable segment
+const PT_DYNAMIC: u32 = 2; // Dynamic linking information
+
+// For the dynamic section https://refspecs.linuxbase.org/elf/gabi4+/ch5.dynamic.html
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// The kernel is slid in 2mb steps
+const KASLR_ALIGN: u64 = 0x20_0000;
+/// Number of positions the kernel can be slid to, 8gb past `MemoryLoc::KernelStart` which keeps it
+/// within the kernels pml4 entry
+const KASLR_SLOTS: u64 = 0x1000;
+
+/// Gets a random number from rdrand, falling back to the tsc if it is not supported
+fn random_u64() -> u64 {
+    let rdrand = unsafe { __cpuid(1) }.ecx & (1 << 30) != 0;
+    if rdrand {
+        match random::rdrand_u64() {
+            Some(val) => return val,
+            None => warn!("RDRAND failed, falling back to the TSC for KASLR"),
+        }
+    }
+    random::tsc_u64()
+}
+
+fn kernel_slide() -> u64 {
+    (random_u64() % KASLR_SLOTS) * KASLR_ALIGN
+}
+
+/// Applies the kernels R_X86_64_RELATIVE relocations for it being loaded `slide` bytes above where
+/// it was linked. The kernel must already be copied into memory.
+unsafe fn relocate_kernel(dynamic: u64, slide: u64) {
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_ent = core::mem::size_of::<Elf64Rela>() as u64;
+
+    let mut entry = dynamic as *const Elf64Dyn;
+    loop {
+        let dyn_entry = *entry;
+        match dyn_entry.d_tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(dyn_entry.d_val + slide),
+            DT_RELASZ => rela_size = dyn_entry.d_val,
+            DT_RELAENT => rela_ent = dyn_entry.d_val,
+            _ => (),
+        }
+        entry = entry.add(1);
+    }
+
+    let Some(rela) = rela else {
+        return;
+    };
+
+    for ptr in (rela..rela + rela_size).step_by(rela_ent as usize) {
+        let rel = *(ptr as *const Elf64Rela);
+        match (rel.r_info & 0xFFFF_FFFF) as u32 {
+            R_X86_64_NONE => (),
+            R_X86_64_RELATIVE => {
+                *((rel.r_offset + slide) as *mut u64) = (rel.r_addend as u64).wrapping_add(slide)
+            }
+            t => panic!("Unsupported kernel relocation type {t}"),
+        }
+    }
+}
 
 pub fn load_kernel(
     boot_services: &BootServices,
@@ -66,7 +153,7 @@ pub fn load_kernel(
             ELFCLASS64,
             ELFDATA2LSB,
         ]
-        && elf_header.e_type == ET_EXEC
+        && (elf_header.e_type == ET_EXEC || elf_header.e_type == ET_DYN)
         && elf_header.e_machine == EM_X86_64
         && elf_header.e_version == 1
     {
@@ -75,7 +162,8 @@ pub fn load_kernel(
         panic!("Kernel Header Invalid")
     }
 
-    let headers = (elf_header.e_phoff..((elf_header.e_phnum * elf_header.e_phentsize).into()))
+    let headers_size = elf_header.e_phnum as u64 * elf_header.e_phentsize as u64;
+    let headers = (elf_header.e_phoff..elf_header.e_phoff + headers_size)
         .step_by(elf_header.e_phentsize.into());
 
     let mut base = u64::MAX;
@@ -85,11 +173,23 @@ pub fn load_kernel(
         let program_header = unsafe {
             *(kernel_data.buf.as_ptr().offset(program_header_ptr as isize) as *const Elf64Phdr)
         };
+        if program_header.p_type != PT_LOAD {
+            continue;
+        }
         base = min(base, program_header.p_vaddr);
         size = max(size, program_header.p_vaddr + program_header.p_memsz);
     }
 
-    let mem_start = (base / 0x1000) * 0x1000;
+    // Only a position independent kernel can be moved
+    let slide = if elf_header.e_type == ET_DYN {
+        kernel_slide()
+    } else {
+        warn!("Kernel is not position independent, KASLR disabled");
+        0
+    };
+    info!("Kernel slide: {:#x}", slide);
+
+    let mem_start = (base / 0x1000) * 0x1000 + slide;
     // The size from start to finish
     let size = size - base;
     let pages = size / 4096 + 1;
@@ -107,6 +207,7 @@ pub fn load_kernel(
 
     boot_info.kernel_start = page;
     boot_info.kernel_pages = pages;
+    boot_info.kernel_slide = slide;
 
     let mut mapper = unsafe { get_uefi_active_mapper() };
 
@@ -128,6 +229,7 @@ pub fn load_kernel(
     unsafe { core::ptr::write_bytes(mem_start as *mut u8, 0, size as usize) }
 
     info!("Copying kernel");
+    let mut dynamic = None;
     // Iterate over each header
     for program_header_ptr in headers {
         // Transpose the program header as an elf header
@@ -141,12 +243,19 @@ pub fn load_kernel(
                         .buf
                         .as_ptr()
                         .offset(program_header.p_offset.try_into().unwrap()),
-                    program_header.p_vaddr as *mut u8,
+                    (program_header.p_vaddr + slide) as *mut u8,
                     program_header.p_filesz.try_into().unwrap(),
                 )
             }
+        } else if program_header.p_type == PT_DYNAMIC {
+            dynamic = Some(program_header.p_vaddr + slide);
         }
     }
 
-    elf_header.e_entry
+    if let Some(dynamic) = dynamic {
+        info!("Relocating kernel");
+        unsafe { relocate_kernel(dynamic, slide) }
+    }
+
+    elf_header.e_entry + slide
 }
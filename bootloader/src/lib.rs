@@ -7,6 +7,7 @@ pub mod fs;
 pub mod gop;
 pub mod kernel;
 pub mod paging;
+pub mod random;
 
 pub use uefi;
 
@@ -65,6 +66,8 @@ pub struct BootInfo {
     pub mmap_len: usize,
     pub kernel_start: u64,
     pub kernel_pages: u64,
+    /// How far the kernel was moved from its linked address by KASLR
+    pub kernel_slide: u64,
 }
 
 pub type EntryPoint = fn(*const BootInfo) -> !;
@@ -0,0 +1,34 @@
+//! Randomness for layout randomization, shared with the kernel. Not suitable for anything that
+//! needs to be cryptographically secure.
+
+use core::arch::x86_64::_rdtsc;
+
+/// Gets a random number from rdrand, None if the entropy stayed exhausted. The caller has to
+/// check that the cpu supports rdrand.
+pub fn rdrand_u64() -> Option<u64> {
+    // rdrand can fail if the entropy is exhausted, the carry flag is set on success
+    for _ in 0..10 {
+        let val: u64;
+        let ok: u8;
+        unsafe {
+            core::arch::asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) val,
+                out(reg_byte) ok,
+                options(nomem, nostack)
+            )
+        };
+        if ok != 0 {
+            return Some(val);
+        }
+    }
+    None
+}
+
+/// The tsc mixed so that the low bits aren't just the time since boot, for when there is no
+/// rdrand
+pub fn tsc_u64() -> u64 {
+    let tsc = unsafe { _rdtsc() };
+    tsc.wrapping_mul(0x9E37_79B9_7F4A_7C15).rotate_left(32)
+}
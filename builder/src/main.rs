@@ -23,9 +23,29 @@ const TO_BUILD: &[(&'static str, &'static str)] = &[
     ("bootloader", "EFI/BOOT/BOOTx64.efi"),
     ("test_elf", "elf.elf"),
     ("amd_pcnet", "amd_pcnet.driver"),
+    ("e1000", "e1000.driver"),
+    ("rtl8139", "rtl8139.driver"),
+    ("hda", "hda.driver"),
+    ("bochs", "bochs.driver"),
+    ("virtio_gpu", "virtio_gpu.driver"),
     ("calc", "calc.elf"),
+    ("fsck", "fsck.elf"),
+    ("fdisk", "fdisk.elf"),
+    ("diskbench", "diskbench.elf"),
     ("net", "net.elf"),
+    ("ping", "ping.elf"),
+    ("netstat", "netstat.elf"),
+    ("echo_server", "echo_server.elf"),
+    ("fetch", "fetch.elf"),
+    ("tftp", "tftp.elf"),
+    ("tcpdump", "tcpdump.elf"),
+    ("fw", "fw.elf"),
+    ("mdns", "mdns.elf"),
+    ("sntp", "sntp.elf"),
+    ("play", "play.elf"),
     ("ps2", "ps2.driver"),
+    ("power", "power.elf"),
+    ("serial", "serial.driver"),
     ("terminal", "terminal.elf"),
     // ! MUST BE LAST
     ("kernel", "fioxa.elf"),
@@ -42,6 +62,7 @@ fn main() -> Result<()> {
     let mut dirs = DirBuilder::new();
 
     dirs.recursive(true).create("fioxa/EFI/BOOT")?;
+    dirs.recursive(true).create("tftp")?;
     copy("assets/startup.nsh", "fioxa/startup.nsh")?;
     copy("assets/zap-light16.psf", "fioxa/font.psf")?;
 
@@ -55,15 +76,18 @@ fn main() -> Result<()> {
         })?;
     }
 
-    if args().any(|a| a == "qemu") {
-        qemu().context("Failed to launch qemu")?;
+    // Without a window the serial port is on the terminal qemu was started from, and the
+    // shell can be used from there
+    let nographic = args().any(|a| a == "qemu-nox");
+    if nographic || args().any(|a| a == "qemu") {
+        qemu(nographic).context("Failed to launch qemu")?;
     }
 
     Ok(())
 }
 
 /// **Warning:** Contains intentional memory leaks, because I am lazy
-fn qemu() -> Result<()> {
+fn qemu(nographic: bool) -> Result<()> {
     let mut qemu_args = vec![
         // GDB server
         "-s".into(),
@@ -77,17 +101,38 @@ fn qemu() -> Result<()> {
         "cores=4".into(),
         "-m".into(),
         "512M".into(),
-        "-serial".into(),
-        "stdio".into(),
         "-netdev".into(),
-        "user,id=mynet0".into(),
+        // Port 7777 on the host reaches echo_server in the guest, and the tftp app can pull files
+        // in builder/tftp from 10.0.2.2
+        "user,id=mynet0,hostfwd=tcp::7777-:7,tftp=tftp".into(),
         "-device".into(),
         "pcnet,netdev=mynet0,mac=00:11:22:33:44:55".into(),
-        // Log network trafic
-        "-object".into(),
-        "filter-dump,id=id,netdev=mynet0,file=fioxa.pcap".into(),
+        "-device".into(),
+        "ich9-intel-hda".into(),
+        "-device".into(),
+        "hda-output,audiodev=snd0".into(),
     ];
 
+    // Where the sound goes, AUDIODEV=none keeps it quiet
+    let audiodev = env::var("AUDIODEV").unwrap_or_else(|_| "pa".to_string());
+    qemu_args.push("-audiodev".to_string());
+    qemu_args.push(format!("{audiodev},id=snd0"));
+
+    // The firmware still boots on the standard vga, the console moves to the virtio-gpu's own
+    // display once its driver starts
+    if args().any(|a| a == "virtio-gpu") {
+        qemu_args.push("-device".to_string());
+        qemu_args.push("virtio-gpu-pci".to_string());
+    }
+
+    if nographic {
+        // Puts the monitor on stdio as well, ctrl-a c switches to it
+        qemu_args.push("-nographic".to_string());
+    } else {
+        qemu_args.push("-serial".to_string());
+        qemu_args.push("stdio".to_string());
+    }
+
     if has_kvm() {
         qemu_args.push("-enable-kvm".to_string());
     }
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+
+use alloc::{vec, vec::Vec};
+use kernel_userspace::{
+    fs::{self, FSServiceError, File, StatResponse},
+    stats::get_sched_stats,
+    syscall::{exit, read_args, unmmap_page},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: diskbench <file> [runs]";
+/// Size of each read, the most the FS service sends back at once
+const CHUNK: usize = 0x10000;
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let Some(path) = args.next() else {
+        println!("{USAGE}");
+        exit()
+    };
+    let runs = match args.next().map(str::parse::<usize>) {
+        None => 3,
+        Some(Ok(runs)) if runs > 0 => runs,
+        Some(_) => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+
+    let mut buffer = Vec::new();
+    let file = match fs::stat(path, &mut buffer) {
+        Ok(StatResponse::File(f)) => f,
+        Ok(StatResponse::Folder(_)) => {
+            println!("diskbench: {path}: not a file");
+            exit()
+        }
+        Err(e) => {
+            println!("diskbench: {path}: {e:?}");
+            exit()
+        }
+    };
+    println!(
+        "{path}: {}KiB, the first run reads from the disk and later ones from the cache",
+        file.file_size / 1024
+    );
+
+    for run in 1..=runs {
+        let start = now(&mut buffer);
+        if let Err(e) = read(path) {
+            println!("diskbench: reading {path}: {e:?}");
+            exit()
+        }
+        let read_ms = now(&mut buffer) - start;
+
+        let start = now(&mut buffer);
+        if let Err(e) = map(file.disk_id, file.node_id, &mut buffer) {
+            println!("diskbench: mapping {path}: {e:?}");
+            exit()
+        }
+        let map_ms = now(&mut buffer) - start;
+
+        println!(
+            "run {run}: read {}ms ({}KiB/s), mapped {}ms ({}KiB/s)",
+            read_ms,
+            rate(file.file_size, read_ms),
+            map_ms,
+            rate(file.file_size, map_ms)
+        );
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
+
+fn now(buffer: &mut Vec<u8>) -> u64 {
+    get_sched_stats(buffer).uptime
+}
+
+fn rate(bytes: usize, ms: u64) -> u64 {
+    bytes as u64 * 1000 / 1024 / ms.max(1)
+}
+
+/// Reads the file from start to end the way `cat` does
+fn read(path: &str) -> Result<(), FSServiceError> {
+    let mut file = File::open(path)?;
+    let mut chunk = vec![0; CHUNK];
+    while file.read(&mut chunk)? != 0 {}
+    Ok(())
+}
+
+/// Maps the file and touches every page the way running it does
+fn map(disk: usize, node: usize, buffer: &mut Vec<u8>) -> Result<(), FSServiceError> {
+    let (mem, size) = fs::mmap(disk, node, buffer)?;
+    let ptr = mem.map().ok_or(FSServiceError::InvalidRequest)?;
+    for offset in (0..size).step_by(0x1000) {
+        unsafe { core::ptr::read_volatile(ptr.add(offset)) };
+    }
+    unmmap_page(ptr as usize, size);
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use core::{
+    marker::PhantomData,
+    mem::size_of,
+    ops::{Deref, DerefMut},
+};
+
+pub use kernel_userspace::dma::{DmaBuffer, DmaConstraints, DmaSegment};
+
+/// A value a device reads or writes directly, in one physically contiguous piece so the device
+/// only needs its address. T has to be valid when zeroed, which is how it starts.
+pub struct DmaBox<T> {
+    buffer: DmaBuffer,
+    _value: PhantomData<T>,
+}
+
+impl<T> DmaBox<T> {
+    /// Zeroed memory for the value, the constraints' segment limit is always 1
+    pub fn new_zeroed(constraints: DmaConstraints) -> Option<Self> {
+        let buffer = DmaBuffer::new(
+            size_of::<T>(),
+            DmaConstraints {
+                max_segments: 1,
+                ..constraints
+            },
+        )?;
+        Some(Self {
+            buffer,
+            _value: PhantomData,
+        })
+    }
+
+    /// Zeroed memory below 4gb, for devices that only take 32 bit addresses
+    pub fn new_zeroed_32() -> Option<Self> {
+        Self::new_zeroed(DmaConstraints {
+            below_4g: true,
+            ..Default::default()
+        })
+    }
+
+    /// Where the value is for the device
+    pub fn phys(&self) -> u64 {
+        self.buffer.segments()[0].phys
+    }
+
+    /// Where a field offset bytes into the value is for the device
+    pub fn phys_at(&self, offset: usize) -> u64 {
+        assert!(offset < size_of::<T>());
+        self.phys() + offset as u64
+    }
+}
+
+impl<T> Deref for DmaBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*(self.buffer.as_ptr() as *const T) }
+    }
+}
+
+impl<T> DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *(self.buffer.as_ptr() as *mut T) }
+    }
+}
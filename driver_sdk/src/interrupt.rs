@@ -0,0 +1,20 @@
+use alloc::vec::Vec;
+use kernel_userspace::{
+    backoff_sleep,
+    channel::{channel_read_rs, channel_write_val, ChannelReadResult},
+    object::KernelReference,
+    process::get_handle,
+};
+
+/// Asks the kernel's `INTERRUPTS` service for a handle to one of the shared interrupts, like
+/// [`kernel_userspace::INT_KB`]
+pub fn subscribe(interrupt: usize) -> KernelReference {
+    let interrupts = backoff_sleep(|| get_handle("INTERRUPTS"));
+    channel_write_val(interrupts, &interrupt, &[]);
+
+    let mut handles = Vec::with_capacity(1);
+    match channel_read_rs(interrupts, &mut Vec::new(), &mut handles) {
+        ChannelReadResult::Ok => KernelReference::from_id(handles[0]),
+        e => panic!("Couldn't subscribe to interrupt {interrupt}: {e:?}"),
+    }
+}
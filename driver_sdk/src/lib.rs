@@ -0,0 +1,176 @@
+//! What every driver needs, so a driver is only the code that talks to its device.
+//!
+//! A driver implements [`Driver`] and hands it to [`driver_main!`]. [`run`] binds it, calls
+//! [`Driver::interrupt`] for the interrupts it subscribed to while binding, [`Driver::suspend`]
+//! when the machine is about to go off and [`Driver::unbind`] if its pci device is removed, after
+//! which the process exits.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use kernel_userspace::{
+    interrupt::{interrupt_acknowledge, interrupt_set_port},
+    object::{object_wait_port_rs, KernelReference, ObjectSignal},
+    pci::PCIDevice,
+    port::{port_create, port_wait_rs},
+    power::on_shutdown,
+    syscall::{exit, exit_process, spawn_thread},
+};
+use spin::Mutex;
+
+pub mod dma;
+pub mod interrupt;
+pub mod log;
+pub mod net;
+pub mod pci;
+pub mod service;
+
+/// The port key for the removal watcher, interrupts are keyed by their index
+const REMOVAL_KEY: u64 = u64::MAX;
+
+pub trait Driver: Send + Sized + 'static {
+    /// Put in front of everything it logs
+    const NAME: &'static str;
+
+    /// Sets the device up, None if it isn't a device this driver can drive
+    fn bind(ctx: &mut BindContext) -> Option<Self>;
+
+    /// One of the interrupts subscribed to in [`Driver::bind`] fired, source being what
+    /// subscribing returned. It is acknowledged after this returns.
+    fn interrupt(&mut self, _source: usize) {}
+
+    /// The machine is about to go off, the device has to stop writing to memory
+    fn suspend(&mut self) {}
+
+    /// The pci device was removed, the process exits after this returns
+    fn unbind(&mut self) {}
+
+    /// Runs on the main thread once bound, for serving requests. Interrupts keep being handled
+    /// after it returns.
+    fn serve(_this: &Arc<Mutex<Self>>) {}
+}
+
+/// What a driver is given to bind with
+pub struct BindContext {
+    pci: Option<PCIDevice>,
+    /// Closed when the pci device is removed
+    removal: Option<KernelReference>,
+    interrupts: Vec<KernelReference>,
+}
+
+impl BindContext {
+    fn new() -> Self {
+        let mut pci = pci::acquire();
+        let removal = pci.as_mut().map(|p| p.watch_removal());
+        Self {
+            pci,
+            removal,
+            interrupts: Vec::new(),
+        }
+    }
+
+    /// The device the driver was started for, None if it wasn't started for a pci device
+    pub fn pci(&mut self) -> Option<&mut PCIDevice> {
+        self.pci.as_mut()
+    }
+
+    /// Takes the device for the driver to keep, [`BindContext::subscribe_pci`] has to be called
+    /// before
+    pub fn take_pci(&mut self) -> Option<PCIDevice> {
+        self.pci.take()
+    }
+
+    /// Subscribes to one of the shared interrupts, like [`kernel_userspace::INT_KB`]
+    pub fn subscribe(&mut self, interrupt: usize) -> usize {
+        self.interrupts.push(interrupt::subscribe(interrupt));
+        self.interrupts.len() - 1
+    }
+
+    /// Subscribes to the pci device's interrupt, which [`pci::interrupt`] picks
+    pub fn subscribe_pci(&mut self) -> Option<usize> {
+        let int = pci::interrupt(self.pci.as_mut()?);
+        self.interrupts.push(int);
+        Some(self.interrupts.len() - 1)
+    }
+}
+
+/// Binds the driver and runs it, see the [crate docs](crate)
+pub fn run<D: Driver>() {
+    log::set_name(D::NAME);
+
+    let mut ctx = BindContext::new();
+    let Some(driver) = D::bind(&mut ctx) else {
+        error!("Couldn't bind");
+        exit_process();
+    };
+    let driver = Arc::new(Mutex::new(driver));
+    let BindContext {
+        removal,
+        interrupts,
+        ..
+    } = ctx;
+
+    if !interrupts.is_empty() || removal.is_some() {
+        let driver = driver.clone();
+        spawn_thread(move || handle_events(&driver, &interrupts, removal));
+    }
+
+    on_shutdown({
+        let driver = driver.clone();
+        move || driver.lock().suspend()
+    });
+
+    D::serve(&driver);
+    exit()
+}
+
+fn handle_events<D: Driver>(
+    driver: &Mutex<D>,
+    interrupts: &[KernelReference],
+    removal: Option<KernelReference>,
+) {
+    let port = port_create();
+    for (i, int) in interrupts.iter().enumerate() {
+        interrupt_set_port(int.id(), port, i as u64);
+    }
+    if let Some(removal) = &removal {
+        object_wait_port_rs(
+            removal.id(),
+            port,
+            ObjectSignal::CHANNEL_CLOSED,
+            REMOVAL_KEY,
+        );
+    }
+
+    loop {
+        let ev = port_wait_rs(port);
+        if ev.key == REMOVAL_KEY {
+            info!("The device was removed");
+            driver.lock().unbind();
+            exit_process();
+        }
+        let Some(int) = interrupts.get(ev.key as usize) else {
+            continue;
+        };
+        driver.lock().interrupt(ev.key as usize);
+        interrupt_acknowledge(int.id());
+    }
+}
+
+/// Makes the driver the process's entry point, and a panic in it end the whole process
+#[macro_export]
+macro_rules! driver_main {
+    ($driver:ty) => {
+        #[export_name = "_start"]
+        pub extern "C" fn main() {
+            $crate::run::<$driver>()
+        }
+
+        #[panic_handler]
+        fn panic(i: &core::panic::PanicInfo) -> ! {
+            $crate::log::panic(i)
+        }
+    };
+}
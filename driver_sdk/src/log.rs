@@ -0,0 +1,40 @@
+//! Printing with the driver's name in front, so a driver's lines can be told apart from the rest
+//! of the console
+
+use core::{fmt::Arguments, panic::PanicInfo};
+
+use kernel_userspace::syscall::exit_process;
+use spin::Once;
+
+static NAME: Once<&'static str> = Once::new();
+
+/// Set by [`crate::run`], drivers with their own main set it themselves
+pub fn set_name(name: &'static str) {
+    NAME.call_once(|| name);
+}
+
+pub fn _log(level: &str, args: Arguments) {
+    let name = NAME.get().copied().unwrap_or("driver");
+    userspace::print::_print(format_args!("[{name}] {level}{args}\n"));
+}
+
+/// Prints where it panicked and ends the process, rather than only the thread that panicked
+pub fn panic(info: &PanicInfo) -> ! {
+    _log("PANIC: ", format_args!("{info}"));
+    exit_process()
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log::_log("", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log::_log("WARN: ", format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log::_log("ERROR: ", format_args!($($arg)*)));
+}
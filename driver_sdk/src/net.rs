@@ -0,0 +1,143 @@
+//! Serving the network stack for a card, so a network driver only has to implement [`Nic`]
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    channel::{channel_read_resize, channel_write_rs, ChannelReadResult},
+    memory::MemoryHandle,
+    net::{register_nic, NicCapabilities, PhysicalNet, TxOffload},
+    object::KernelReference,
+    service::{deserialize, serialize},
+    syscall::yield_now,
+};
+use spin::Mutex;
+
+use crate::{error, info, warn};
+
+pub enum SendError {
+    /// Every descriptor is in use, sending is tried again once the card has got through some
+    BufferFull,
+    /// The frame is bigger than the card can send, it is dropped
+    TooLong,
+}
+
+pub trait Nic: Send {
+    /// Whether the card shares [`kernel_userspace::net::ring::PacketRing`]s with the stack,
+    /// frames then never come through [`Nic::send`]
+    const RINGS: bool = false;
+
+    fn mac(&mut self) -> u64;
+
+    /// The channel is written to whenever something is received
+    fn listen(&mut self, listener: KernelReference);
+
+    /// Only asked for by cards that don't share rings
+    fn capabilities(&self) -> NicCapabilities {
+        NicCapabilities::default()
+    }
+
+    /// Fragments only come one at a time unless the card has capabilities
+    fn send(&mut self, _fragments: &[&[u8]], _offload: TxOffload) -> Result<(), SendError> {
+        unreachable!("a card sharing rings doesn't send frames itself")
+    }
+
+    /// The receive ring's memory and the transmit ring's, None if they couldn't be shared
+    fn share_rings(&mut self) -> Option<(MemoryHandle, MemoryHandle)> {
+        None
+    }
+
+    /// The stack has pushed to or popped from the rings
+    fn kick(&mut self) {}
+}
+
+/// Registers the card with the stack and answers its requests until the channel breaks
+pub fn serve<N: Nic>(name: &str, nic: &Mutex<N>) {
+    // The stack sends us requests one at a time down the channel we register
+    let (id, stack) = register_nic(name);
+    info!("{name} is interface {id}");
+
+    let mut buffer = Vec::new();
+    let mut handles_buffer = Vec::new();
+    loop {
+        match channel_read_resize(stack.id(), &mut buffer, &mut handles_buffer) {
+            ChannelReadResult::Ok => (),
+            e => {
+                error!("Stack channel {e:?}");
+                return;
+            }
+        }
+
+        let request = deserialize(&buffer).unwrap();
+        let handles = match request {
+            PhysicalNet::ListenToPackets => 1,
+            _ => 0,
+        };
+        if handles_buffer.len() != handles {
+            error!("Bad amount of handles");
+            return;
+        }
+
+        match request {
+            PhysicalNet::MacAddrGet => {
+                let resp = nic.lock().mac();
+                let resp = serialize(&resp, &mut buffer);
+                channel_write_rs(stack.id(), resp, &[]);
+            }
+            PhysicalNet::ListenToPackets => {
+                nic.lock()
+                    .listen(KernelReference::from_id(handles_buffer[0]));
+                channel_write_rs(stack.id(), &[], &[]);
+            }
+            PhysicalNet::CapabilitiesGet => {
+                let resp = serialize(&nic.lock().capabilities(), &mut buffer);
+                channel_write_rs(stack.id(), resp, &[]);
+            }
+            // Frames go through the rings when they are shared, and only come as fragments to
+            // cards that advertised something
+            PhysicalNet::SendPacket(packet) if !N::RINGS => {
+                send(nic, &[packet], TxOffload::default());
+                channel_write_rs(stack.id(), &[], &[]);
+            }
+            PhysicalNet::SendFragments(fragments, offload)
+                if !N::RINGS && nic.lock().capabilities().any() =>
+            {
+                send(nic, &fragments, offload);
+                channel_write_rs(stack.id(), &[], &[]);
+            }
+            PhysicalNet::RingsGet if !N::RINGS => {
+                let resp = serialize(&false, &mut buffer);
+                channel_write_rs(stack.id(), resp, &[]);
+            }
+            PhysicalNet::RingsGet => {
+                let Some((rx, tx)) = nic.lock().share_rings() else {
+                    error!("Couldn't share the rings");
+                    return;
+                };
+                let resp = serialize(&true, &mut buffer);
+                channel_write_rs(stack.id(), resp, &[rx.kref().id(), tx.kref().id()]);
+            }
+            PhysicalNet::RingKick if N::RINGS => {
+                nic.lock().kick();
+                channel_write_rs(stack.id(), &[], &[]);
+            }
+            e => {
+                error!("Unexpected request {e:?}");
+                return;
+            }
+        }
+    }
+}
+
+fn send<N: Nic>(nic: &Mutex<N>, fragments: &[&[u8]], offload: TxOffload) {
+    // Keep trying to send
+    loop {
+        match nic.lock().send(fragments, offload) {
+            Ok(()) => break,
+            Err(SendError::BufferFull) => yield_now(),
+            Err(SendError::TooLong) => {
+                let len: usize = fragments.iter().map(|f| f.len()).sum();
+                warn!("Dropping frame of {len} bytes");
+                break;
+            }
+        }
+    }
+}
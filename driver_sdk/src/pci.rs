@@ -0,0 +1,29 @@
+use kernel_userspace::{
+    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
+    pci::PCIDevice,
+    service::SimpleService,
+    INT_PCI,
+};
+
+/// The kernel starts pci drivers with the device's channel as their second handle
+const PCI_HANDLE: usize = 2;
+
+/// The device the driver was started for, None if it wasn't started for one
+pub fn acquire() -> Option<PCIDevice> {
+    let handle = KernelReferenceID::from_usize(PCI_HANDLE)?;
+    if get_type(handle) != KernelObjectType::Channel {
+        return None;
+    }
+    Some(PCIDevice {
+        device_service: SimpleService::new(KernelReference::from_id(handle)),
+    })
+}
+
+/// The device's own message signalled interrupt if it can have one, otherwise the pci line every
+/// device without one shares. On the shared line the handler has to check the device's status,
+/// the interrupt may have been for another device.
+pub fn interrupt(device: &mut PCIDevice) -> KernelReference {
+    device
+        .enable_msi()
+        .unwrap_or_else(|| super::interrupt::subscribe(INT_PCI))
+}
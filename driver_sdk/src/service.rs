@@ -0,0 +1,13 @@
+use kernel_userspace::{
+    channel::channel_create_rs, object::KernelReference, process::publish_handle,
+};
+
+/// Publishes a channel under the name and returns this end of it, what connects to it comes
+/// through the other end
+pub fn publish(name: &str) -> KernelReference {
+    let (service, client) = channel_create_rs();
+    if !publish_handle(name, client.id()) {
+        crate::warn!("Couldn't publish {name}");
+    }
+    service
+}
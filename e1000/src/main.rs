@@ -0,0 +1,422 @@
+//! Driver for Intel's 8254x and 82574 gigabit ethernet controllers, QEMU's default e1000 and
+//! e1000e among them.
+//!
+//! Drivers can't map device memory, so the registers are reached through the I/O window
+//! (IOADDR and IODATA) every one of these cards has as well. Frames go through rings of legacy
+//! descriptors each pointing at a buffer of its own, and the interrupt throttling register
+//! keeps a busy link from interrupting us more than a few thousand times a second.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+pub mod regs;
+
+use core::{
+    mem::size_of,
+    ptr::{read_volatile, write_volatile},
+    slice,
+};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use driver_sdk::{
+    log,
+    net::{self, Nic, SendError},
+    pci,
+};
+use kernel_userspace::{
+    channel::channel_write_rs,
+    dma::{DmaBuffer, DmaConstraints},
+    interrupt::interrupt_wait,
+    net::{NicCapabilities, TxOffload},
+    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
+    pci::PCIDevice,
+    service::SimpleService,
+    syscall::{exit, spawn_thread, yield_now},
+};
+
+use self::regs::*;
+
+/// The devices we drive, and whether they have the newer EERD layout
+const DEVICES: &[(u16, bool)] = &[
+    (0x1004, false), // 82543GC copper
+    (0x100E, false), // 82540EM, QEMU's e1000
+    (0x100F, false), // 82545EM copper
+    (0x107C, true),  // 82541PI
+    (0x10D3, true),  // 82574L, QEMU's e1000e
+];
+
+const BUFFER_SIZE: usize = 2048;
+/// Both rings have to be a multiple of 128 bytes, so of 8 descriptors
+const RX_DESCS: usize = 32;
+const TX_DESCS: usize = 16;
+/// The least time between interrupts in 256ns units, about 6000 a second
+const INTERRUPT_INTERVAL: u32 = 651;
+/// Fragments are gathered into the descriptor's buffer, and tcp and udp checksums are left to
+/// the card
+const CAPABILITIES: NicCapabilities = NicCapabilities {
+    ipv4_checksum: false,
+    l4_checksum: true,
+    scatter_gather: true,
+    tso_max: 0,
+};
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    log::set_name("e1000");
+    let pci_ref = KernelReferenceID::from_usize(2).unwrap();
+    assert_eq!(get_type(pci_ref), KernelObjectType::Channel);
+    let mut pci_device = PCIDevice {
+        device_service: SimpleService::new(KernelReference::from_id(pci_ref)),
+    };
+    let pci_ev = pci::interrupt(&mut pci_device);
+
+    let Some(e1000) = E1000::new(pci_device) else {
+        println!("E1000 failed to init");
+        exit()
+    };
+    let e1000 = Arc::new(Mutex::new(e1000));
+
+    spawn_thread({
+        let e1000 = e1000.clone();
+        move || loop {
+            interrupt_wait(pci_ev.id());
+            e1000.lock().interrupt_handler();
+        }
+    });
+
+    net::serve("e1000", &e1000);
+}
+
+impl Nic for E1000<'_> {
+    fn mac(&mut self) -> u64 {
+        self.mac
+    }
+
+    fn listen(&mut self, listener: KernelReference) {
+        self.listeners.push(listener);
+    }
+
+    fn capabilities(&self) -> NicCapabilities {
+        CAPABILITIES
+    }
+
+    fn send(&mut self, fragments: &[&[u8]], offload: TxOffload) -> Result<(), SendError> {
+        self.send_packet(fragments, offload)
+    }
+}
+
+/// The IOADDR and IODATA registers, which give access to every other register
+pub struct E1000IOPort(u16);
+
+impl E1000IOPort {
+    fn read(&mut self, reg: u32) -> u32 {
+        let mut addr: Port<u32> = Port::new(self.0);
+        let mut data: Port<u32> = Port::new(self.0 + 4);
+        unsafe {
+            addr.write(reg);
+            data.read()
+        }
+    }
+
+    fn write(&mut self, reg: u32, val: u32) {
+        let mut addr: Port<u32> = Port::new(self.0);
+        let mut data: Port<u32> = Port::new(self.0 + 4);
+        unsafe {
+            addr.write(reg);
+            data.write(val);
+        }
+    }
+
+    fn reset_device(&mut self) {
+        self.write(IMC, u32::MAX);
+        let ctrl = self.read(CTRL);
+        self.write(CTRL, ctrl | CTRL_RST);
+        // The card clears it once done, which takes a few microseconds
+        while self.read(CTRL) & CTRL_RST != 0 {
+            yield_now();
+        }
+        // Interrupts have to be masked again after a reset
+        self.write(IMC, u32::MAX);
+        self.read(ICR);
+    }
+
+    fn read_eeprom(&mut self, word: u8, new_layout: bool) -> Option<u16> {
+        let (shift, done) = if new_layout { (2, 1 << 1) } else { (8, 1 << 4) };
+        self.write(EERD, (word as u32) << shift | EERD_START);
+        for _ in 0..1000 {
+            let eerd = self.read(EERD);
+            if eerd & done != 0 {
+                return Some((eerd >> 16) as u16);
+            }
+            yield_now();
+        }
+        None
+    }
+
+    /// The mac from the eeprom, or from the receive address the card loaded at reset if it
+    /// doesn't have one
+    fn read_mac_addr(&mut self, new_layout: bool) -> u64 {
+        let words: Option<Vec<u16>> = (0..3).map(|w| self.read_eeprom(w, new_layout)).collect();
+        match words {
+            Some(w) => w[0] as u64 | (w[1] as u64) << 16 | (w[2] as u64) << 32,
+            None => {
+                println!("E1000 has no eeprom, using the receive address");
+                self.read(RAL0) as u64 | (self.read(RAH0) as u64 & 0xFFFF) << 32
+            }
+        }
+    }
+}
+
+pub struct E1000<'b> {
+    io: E1000IOPort,
+    mac: u64,
+    rx_ring: &'b mut [RxDescriptor],
+    rx_next: usize,
+    tx_ring: &'b mut [TxDescriptor],
+    tx_next: usize,
+    #[allow(dead_code)]
+    rings: DmaBuffer,
+    send_buffers: DmaBuffer,
+    recv_buffers: DmaBuffer,
+    listeners: Vec<KernelReference>,
+}
+
+impl E1000<'_> {
+    fn new(pci_device: PCIDevice) -> Option<Self> {
+        let common_header = kernel_userspace::pci::PCIHeaderCommon {
+            device: Arc::new(Mutex::new(pci_device)),
+        };
+        // Ensure device is actually supported
+        let device_id = common_header.get_device_id();
+        let &(_, new_layout) = DEVICES.iter().find(|(id, _)| *id == device_id)?;
+        if common_header.get_vendor_id() != 0x8086 {
+            return None;
+        }
+
+        let pci_device = unsafe { common_header.get_as_header0() };
+        let mut io = E1000IOPort(pci_device.get_port_base()?.try_into().ok()?);
+
+        io.reset_device();
+        let mac = io.read_mac_addr(new_layout);
+
+        let rings_size =
+            size_of::<RxDescriptor>() * RX_DESCS + size_of::<TxDescriptor>() * TX_DESCS;
+        assert!(rings_size <= 0x1000);
+        let rings = DmaBuffer::new(
+            rings_size,
+            DmaConstraints {
+                max_segments: 1,
+                ..Default::default()
+            },
+        )?;
+        // Buffers start on a page and are half a page long, so none cross into another page
+        let send_buffers = DmaBuffer::new(BUFFER_SIZE * TX_DESCS, Default::default())?;
+        let recv_buffers = DmaBuffer::new(BUFFER_SIZE * RX_DESCS, Default::default())?;
+
+        let (rx_ring, tx_ring) = unsafe {
+            let rx = slice::from_raw_parts_mut(rings.as_ptr() as *mut RxDescriptor, RX_DESCS);
+            let tx = slice::from_raw_parts_mut(
+                rings.as_ptr().add(size_of::<RxDescriptor>() * RX_DESCS) as *mut TxDescriptor,
+                TX_DESCS,
+            );
+            (rx, tx)
+        };
+        for (i, desc) in rx_ring.iter_mut().enumerate() {
+            *desc = RxDescriptor {
+                address: recv_buffers.phys_addr(i * BUFFER_SIZE),
+                length: 0,
+                checksum: 0,
+                status: 0,
+                errors: 0,
+                special: 0,
+            };
+        }
+        // Free descriptors are the ones the card is done with
+        for (i, desc) in tx_ring.iter_mut().enumerate() {
+            *desc = TxDescriptor {
+                address: send_buffers.phys_addr(i * BUFFER_SIZE),
+                length: 0,
+                cso: 0,
+                cmd: 0,
+                status: DESC_DD,
+                css: 0,
+                special: 0,
+            };
+        }
+
+        // Link up, with the speed worked out by the card
+        let ctrl = io.read(CTRL);
+        io.write(CTRL, (ctrl | CTRL_SLU | CTRL_ASDE) & !CTRL_PHY_RST);
+
+        io.write(RAL0, mac as u32);
+        io.write(RAH0, (mac >> 32) as u32 & 0xFFFF | RAH_AV);
+        for i in 0..128 {
+            io.write(MTA + i * 4, 0);
+        }
+
+        let rx_addr = rings.phys_addr(0);
+        io.write(RDBAL, rx_addr as u32);
+        io.write(RDBAH, (rx_addr >> 32) as u32);
+        io.write(RDLEN, (size_of::<RxDescriptor>() * RX_DESCS) as u32);
+        io.write(RDH, 0);
+        // Every descriptor but one is the card's, as head == tail means the ring is empty
+        io.write(RDT, RX_DESCS as u32 - 1);
+        // 2KiB buffers (BSIZE 0) with the crc stripped
+        io.write(RCTL, RCTL_EN | RCTL_MPE | RCTL_BAM | RCTL_SECRC);
+
+        let tx_addr = rings.phys_addr(size_of::<RxDescriptor>() * RX_DESCS);
+        io.write(TDBAL, tx_addr as u32);
+        io.write(TDBAH, (tx_addr >> 32) as u32);
+        io.write(TDLEN, (size_of::<TxDescriptor>() * TX_DESCS) as u32);
+        io.write(TDH, 0);
+        io.write(TDT, 0);
+        io.write(
+            TCTL,
+            TCTL_EN | TCTL_PSP | 0x0F << TCTL_CT_SHIFT | 0x40 << TCTL_COLD_SHIFT,
+        );
+        // The inter packet gap the manual gives for copper
+        io.write(TIPG, 10 | 8 << 10 | 6 << 20);
+
+        io.write(ITR, INTERRUPT_INTERVAL);
+        io.write(IMS, INT_LSC | INT_RXDMT0 | INT_RXO | INT_RXT0);
+        io.read(ICR);
+
+        println!(
+            "E1000 inited, link is {}",
+            if io.read(STATUS) & STATUS_LU != 0 {
+                "up"
+            } else {
+                "down"
+            }
+        );
+        Some(Self {
+            io,
+            mac,
+            rx_ring,
+            rx_next: 0,
+            tx_ring,
+            tx_next: 0,
+            rings,
+            send_buffers,
+            recv_buffers,
+            listeners: Vec::new(),
+        })
+    }
+
+    fn interrupt_handler(&mut self) {
+        // Reading the cause clears it. The interrupt line is shared, so it may not be ours.
+        let cause = self.io.read(ICR);
+        if cause & INT_LSC != 0 {
+            let up = self.io.read(STATUS) & STATUS_LU != 0;
+            println!("E1000 link {}", if up { "up" } else { "down" });
+        }
+        if cause & INT_RXO != 0 {
+            println!("E1000 receive overrun")
+        }
+        if cause & (INT_RXT0 | INT_RXDMT0 | INT_RXO) != 0 {
+            self.receive();
+        }
+    }
+
+    fn send_packet(&mut self, fragments: &[&[u8]], offload: TxOffload) -> Result<(), SendError> {
+        let len: usize = fragments.iter().map(|f| f.len()).sum();
+        if len > BUFFER_SIZE {
+            return Err(SendError::TooLong);
+        }
+        let i = self.tx_next;
+        let desc = &mut self.tx_ring[i];
+        if unsafe { read_volatile(&desc.status) } & DESC_DD == 0 {
+            return Err(SendError::BufferFull);
+        }
+
+        let send_buffer = unsafe {
+            slice::from_raw_parts_mut(self.send_buffers.as_ptr().add(i * BUFFER_SIZE), BUFFER_SIZE)
+        };
+        let mut at = 0;
+        for fragment in fragments {
+            send_buffer[at..at + fragment.len()].copy_from_slice(fragment);
+            at += fragment.len();
+        }
+
+        desc.length = len as u16;
+        desc.cmd = TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS;
+        desc.css = 0;
+        desc.cso = 0;
+        // Legacy descriptors can sum from one offset to the end of the frame, which is all
+        // a tcp or udp checksum needs
+        if let Some(field) = offload.l4_checksum {
+            desc.css = offload.l4_start as u8;
+            desc.cso = (offload.l4_start + field) as u8;
+            desc.cmd |= TX_CMD_IC;
+        }
+        unsafe { write_volatile(&mut desc.status, 0) };
+
+        self.tx_next = (i + 1) % TX_DESCS;
+        self.io.write(TDT, self.tx_next as u32);
+        Ok(())
+    }
+
+    pub fn receive(&mut self) {
+        loop {
+            let i = self.rx_next;
+            let desc = &mut self.rx_ring[i];
+            let status = unsafe { read_volatile(&desc.status) };
+            if status & DESC_DD == 0 {
+                return;
+            }
+            // Long packets are turned off, so every frame fits in one buffer
+            if status & RX_EOP != 0 && desc.errors == 0 {
+                let packet = unsafe {
+                    slice::from_raw_parts(
+                        self.recv_buffers.as_ptr().add(i * BUFFER_SIZE),
+                        desc.length as usize,
+                    )
+                };
+                self.listeners
+                    .retain(|l| channel_write_rs(l.id(), packet, &[]));
+            }
+            unsafe { write_volatile(&mut desc.status, 0) };
+
+            // Give the buffer back
+            self.io.write(RDT, i as u32);
+            self.rx_next = (i + 1) % RX_DESCS;
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
@@ -0,0 +1,62 @@
+//! The registers and bits of the 8254x/82574 we use, from Intel's software developer's manuals
+
+pub const CTRL: u32 = 0x0000;
+pub const STATUS: u32 = 0x0008;
+pub const EERD: u32 = 0x0014;
+pub const ICR: u32 = 0x00C0;
+pub const ITR: u32 = 0x00C4;
+pub const IMS: u32 = 0x00D0;
+pub const IMC: u32 = 0x00D8;
+pub const RCTL: u32 = 0x0100;
+pub const TCTL: u32 = 0x0400;
+pub const TIPG: u32 = 0x0410;
+pub const RDBAL: u32 = 0x2800;
+pub const RDBAH: u32 = 0x2804;
+pub const RDLEN: u32 = 0x2808;
+pub const RDH: u32 = 0x2810;
+pub const RDT: u32 = 0x2818;
+pub const TDBAL: u32 = 0x3800;
+pub const TDBAH: u32 = 0x3804;
+pub const TDLEN: u32 = 0x3808;
+pub const TDH: u32 = 0x3810;
+pub const TDT: u32 = 0x3818;
+/// The multicast table array, 128 registers
+pub const MTA: u32 = 0x5200;
+pub const RAL0: u32 = 0x5400;
+pub const RAH0: u32 = 0x5404;
+
+pub const CTRL_ASDE: u32 = 1 << 5;
+pub const CTRL_SLU: u32 = 1 << 6;
+pub const CTRL_RST: u32 = 1 << 26;
+pub const CTRL_PHY_RST: u32 = 1 << 31;
+
+pub const STATUS_LU: u32 = 1 << 1;
+
+pub const EERD_START: u32 = 1;
+
+pub const INT_LSC: u32 = 1 << 2;
+pub const INT_RXDMT0: u32 = 1 << 4;
+pub const INT_RXO: u32 = 1 << 6;
+pub const INT_RXT0: u32 = 1 << 7;
+
+pub const RCTL_EN: u32 = 1 << 1;
+/// Multicast promiscuous, the network stack works out which groups are ours
+pub const RCTL_MPE: u32 = 1 << 4;
+pub const RCTL_BAM: u32 = 1 << 15;
+pub const RCTL_SECRC: u32 = 1 << 26;
+
+pub const TCTL_EN: u32 = 1 << 1;
+pub const TCTL_PSP: u32 = 1 << 3;
+pub const TCTL_CT_SHIFT: u32 = 4;
+pub const TCTL_COLD_SHIFT: u32 = 12;
+
+pub const RAH_AV: u32 = 1 << 31;
+
+/// Descriptor done, in both kinds of descriptor
+pub const DESC_DD: u8 = 1;
+pub const RX_EOP: u8 = 1 << 1;
+pub const TX_CMD_EOP: u8 = 1;
+pub const TX_CMD_IFCS: u8 = 1 << 1;
+/// Insert the checksum from css to the end of the frame at cso
+pub const TX_CMD_IC: u8 = 1 << 2;
+pub const TX_CMD_RS: u8 = 1 << 3;
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    net::{mdns::MdnsAdvertisement, IPAddr, TcpListener, TcpStream},
+    syscall::{exit, read_args, spawn_thread},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: echo_server [port]";
+/// The port of the echo protocol
+const DEFAULT_PORT: u16 = 7;
+const BACKLOG: u32 = 8;
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let port = match args.next().map(str::parse::<u16>) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+
+    let mut listener = match TcpListener::bind(port, BACKLOG) {
+        Ok(l) => l,
+        Err(e) => {
+            println!("echo_server: {e}");
+            exit()
+        }
+    };
+    println!("Echoing on port {}", listener.local_port());
+    // Others on the link can find us for as long as we are listening
+    let _advertisement =
+        MdnsAdvertisement::register("_echo._tcp", listener.local_port(), Vec::new())
+            .inspect_err(|e| println!("echo_server: not advertising: {e}"));
+
+    // Each connection is served by a thread of its own
+    loop {
+        match listener.accept() {
+            Ok((stream, ip, port)) => {
+                println!("Connection from {ip}:{port}");
+                spawn_thread(move || echo(stream, ip, port));
+            }
+            Err(e) => {
+                println!("echo_server: {e}");
+                exit()
+            }
+        }
+    }
+}
+
+/// Sends everything back until the peer closes its side, then closes ours
+fn echo(mut stream: TcpStream, ip: IPAddr, port: u16) {
+    let mut buf = [0; 1024];
+    let mut total = 0;
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                if let Err(e) = stream.write(&buf[..len]) {
+                    println!("{ip}:{port}: {e}");
+                    return;
+                }
+                total += len;
+            }
+            Err(e) => {
+                println!("{ip}:{port}: {e}");
+                return;
+            }
+        }
+    }
+    let _ = stream.shutdown();
+    println!("{ip}:{port} closed after echoing {total} bytes");
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
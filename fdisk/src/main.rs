@@ -0,0 +1,460 @@
+#![no_std]
+#![no_main]
+
+//! Creates and deletes MBR and GPT partitions on a disk in `/dev`, nothing is written until `w`.
+//! The partitions are left unformatted.
+
+use alloc::{string::String, vec::Vec};
+use core::arch::x86_64::_rdtsc;
+use kernel_userspace::{
+    disk::gpt::{
+        crc32, GptEntry, GptHeader, ENTRY_COUNT, ENTRY_SIZE, PROTECTIVE_MBR_ID, REVISION,
+        SIGNATURE, TYPE_BASIC_DATA, TYPE_EFI_SYSTEM, TYPE_LINUX_FS, TYPE_LINUX_SWAP, TYPE_UNUSED,
+    },
+    fs::{rescan, FSServiceError, File, SeekFrom},
+    syscall::{exit, read_args},
+};
+use userspace::input::{read_line, KBInputDecoder};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: fdisk <disk name in /dev>";
+
+const HELP: &str = "\
+p                      print the partitions
+o                      start a new empty MBR table
+g                      start a new empty GPT table
+n <type> [size in MiB] add a partition in the first free space, filling it without a size
+d <number>             delete a partition
+w                      write the table, reload the disk and quit
+q                      quit without writing
+types: fat exfat linux swap efi";
+
+/// Partitions start on 1MiB boundaries
+const ALIGN: u64 = 2048;
+/// Sectors the GPT entries take up
+const ENTRY_SECTORS: u64 = (ENTRY_COUNT * ENTRY_SIZE / 512) as u64;
+
+const EMPTY_ENTRY: GptEntry = GptEntry {
+    type_guid: TYPE_UNUSED,
+    unique_guid: [0; 16],
+    first_lba: 0,
+    last_lba: 0,
+    attributes: 0,
+    name: [0; 36],
+};
+
+/// Name, MBR id and GPT type of what can be created
+const TYPES: [(&str, u8, [u8; 16]); 5] = [
+    ("fat", 0x0C, TYPE_BASIC_DATA),
+    ("exfat", 0x07, TYPE_BASIC_DATA),
+    ("linux", 0x83, TYPE_LINUX_FS),
+    ("swap", 0x82, TYPE_LINUX_SWAP),
+    ("efi", 0xEF, TYPE_EFI_SYSTEM),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Scheme {
+    None,
+    Mbr,
+    Gpt,
+}
+
+#[derive(Clone, Copy)]
+struct Partition {
+    /// Only one of these is used depending on the scheme
+    mbr_id: u8,
+    entry: GptEntry,
+    start: u64,
+    sectors: u64,
+}
+
+struct Table {
+    scheme: Scheme,
+    disk_guid: [u8; 16],
+    partitions: Vec<Partition>,
+    /// Of the whole disk
+    sectors: u64,
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let (Some(name), None) = (args.next(), args.next()) else {
+        println!("{USAGE}");
+        exit()
+    };
+
+    let path = alloc::format!("/dev/{name}");
+    let mut disk = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("fdisk: {path}: {e:?}");
+            exit()
+        }
+    };
+    let mut table = match Table::load(&mut disk) {
+        Ok(t) => t,
+        Err(e) => {
+            println!("fdisk: {path}: {e:?}");
+            exit()
+        }
+    };
+    match table.scheme {
+        Scheme::None => println!("{name} has no partition table"),
+        Scheme::Mbr => println!("{name} has an MBR partition table"),
+        Scheme::Gpt => println!("{name} has a GPT partition table"),
+    }
+
+    let mut input = KBInputDecoder::new();
+    loop {
+        print!("fdisk> ");
+        let line = read_line(&mut input);
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (None, ..) => (),
+            (Some("p"), None, _) => table.print(),
+            (Some("o"), None, _) => table.reset(Scheme::Mbr),
+            (Some("g"), None, _) => table.reset(Scheme::Gpt),
+            (Some("n"), Some(kind), size) => {
+                let size = match size.map(str::parse::<u64>) {
+                    None => None,
+                    Some(Ok(mib)) if mib > 0 => Some(mib * 2048),
+                    Some(_) => {
+                        println!("Size should be a number of MiB");
+                        continue;
+                    }
+                };
+                if let Err(e) = table.add(kind, size) {
+                    println!("{e}");
+                }
+            }
+            (Some("d"), Some(n), None) => match n.parse::<usize>() {
+                Ok(n) if (1..=table.partitions.len()).contains(&n) => {
+                    table.partitions.remove(n - 1);
+                }
+                _ => println!("No partition {n}"),
+            },
+            (Some("w"), None, _) => {
+                if table.scheme == Scheme::None {
+                    println!("There is no table to write, create one with o or g");
+                    continue;
+                }
+                if let Err(e) = table.write(&mut disk) {
+                    println!("fdisk: writing {path}: {e:?}");
+                    exit()
+                }
+                match rescan(name, &mut Vec::new()) {
+                    Ok(ids) => println!("{name} was reloaded, partitions found: {ids:?}"),
+                    Err(e) => {
+                        println!("The table was written but {name} couldn't be reloaded: {e:?}")
+                    }
+                }
+                exit()
+            }
+            (Some("q"), None, _) => exit(),
+            (Some("h"), None, _) => println!("{HELP}"),
+            _ => println!("Unknown command, h for help"),
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
+
+fn read_sectors(disk: &mut File, lba: u64, buf: &mut [u8]) -> Result<(), FSServiceError> {
+    disk.seek(SeekFrom::Start(lba as usize * 512))?;
+    let mut read = 0;
+    while read < buf.len() {
+        match disk.read(&mut buf[read..])? {
+            0 => return Err(FSServiceError::InvalidRequest),
+            n => read += n,
+        }
+    }
+    Ok(())
+}
+
+fn write_sectors(disk: &mut File, lba: u64, data: &[u8]) -> Result<(), FSServiceError> {
+    disk.seek(SeekFrom::Start(lba as usize * 512))?;
+    disk.write(data)?;
+    Ok(())
+}
+
+fn as_bytes<T>(val: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(val as *const T as *const u8, core::mem::size_of::<T>()) }
+}
+
+/// A random version 4 GUID, only needs to be unlikely to collide
+fn random_guid(state: &mut u64) -> [u8; 16] {
+    let mut guid = [0; 16];
+    for chunk in guid.chunks_exact_mut(8) {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        chunk.copy_from_slice(&state.to_le_bytes());
+    }
+    guid[7] = (guid[7] & 0x0F) | 0x40;
+    guid[8] = (guid[8] & 0x3F) | 0x80;
+    guid
+}
+
+fn type_name(scheme: Scheme, part: &Partition) -> String {
+    let found = match scheme {
+        Scheme::Gpt if part.entry.type_guid == TYPE_BASIC_DATA => return "data".into(),
+        Scheme::Gpt => TYPES.iter().find(|t| t.2 == part.entry.type_guid),
+        _ => TYPES.iter().find(|t| t.1 == part.mbr_id),
+    };
+    match (found, scheme) {
+        (Some(t), _) => t.0.into(),
+        (None, Scheme::Gpt) => alloc::format!("{:x?}", { part.entry.type_guid }),
+        (None, _) => alloc::format!("{:#04x}", part.mbr_id),
+    }
+}
+
+impl Table {
+    fn load(disk: &mut File) -> Result<Self, FSServiceError> {
+        let sectors = (disk.stat()?.file_size / 512) as u64;
+        let mut table = Self {
+            scheme: Scheme::None,
+            disk_guid: [0; 16],
+            partitions: Vec::new(),
+            sectors,
+        };
+
+        let mbr = &mut [0u8; 512];
+        read_sectors(disk, 0, mbr)?;
+        if mbr[510..] != [0x55, 0xAA] {
+            return Ok(table);
+        }
+        let entries: Vec<(u8, u64, u64)> = mbr[446..510]
+            .chunks_exact(16)
+            .map(|e| {
+                let start = u32::from_le_bytes(e[8..12].try_into().unwrap());
+                let len = u32::from_le_bytes(e[12..16].try_into().unwrap());
+                (e[4], start as u64, len as u64)
+            })
+            .filter(|e| e.0 != 0)
+            .collect();
+
+        if !entries.iter().any(|e| e.0 == PROTECTIVE_MBR_ID) {
+            table.scheme = Scheme::Mbr;
+            table.partitions = entries
+                .into_iter()
+                .map(|(id, start, sectors)| Partition {
+                    mbr_id: id,
+                    entry: EMPTY_ENTRY,
+                    start,
+                    sectors,
+                })
+                .collect();
+            return Ok(table);
+        }
+
+        let buf = &mut [0u8; 512];
+        read_sectors(disk, 1, buf)?;
+        let header = unsafe { *(buf.as_ptr() as *const GptHeader) };
+        if header.signature != SIGNATURE
+            || { header.header_crc } != header.compute_crc()
+            || header.entry_size as usize != ENTRY_SIZE
+        {
+            println!("The GPT header is damaged, it will be replaced on write");
+            table.scheme = Scheme::Gpt;
+            table.disk_guid = random_guid(&mut unsafe { _rdtsc() });
+            return Ok(table);
+        }
+
+        let len = header.entry_count as usize * ENTRY_SIZE;
+        let mut entries = alloc::vec![0u8; len.div_ceil(512) * 512];
+        read_sectors(disk, header.entries_lba, &mut entries)?;
+        table.scheme = Scheme::Gpt;
+        table.disk_guid = header.disk_guid;
+        table.partitions = entries[..len]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|e| unsafe { *(e.as_ptr() as *const GptEntry) })
+            .filter(|e| e.type_guid != TYPE_UNUSED)
+            .map(|entry| Partition {
+                mbr_id: 0,
+                entry,
+                start: entry.first_lba,
+                sectors: entry.last_lba + 1 - entry.first_lba,
+            })
+            .collect();
+        Ok(table)
+    }
+
+    fn reset(&mut self, scheme: Scheme) {
+        self.scheme = scheme;
+        self.partitions.clear();
+        self.disk_guid = random_guid(&mut unsafe { _rdtsc() });
+    }
+
+    /// The range partitions can go in, end is exclusive
+    fn usable(&self) -> (u64, u64) {
+        match self.scheme {
+            // Room for the backup entries and header
+            Scheme::Gpt => (ALIGN, self.sectors.saturating_sub(ENTRY_SECTORS + 1)),
+            _ => (ALIGN, self.sectors.min(u32::MAX as u64)),
+        }
+    }
+
+    fn print(&self) {
+        if self.scheme == Scheme::None {
+            println!("There is no partition table, create one with o or g");
+            return;
+        }
+        println!("Disk: {} sectors, {}MiB", self.sectors, self.sectors / 2048);
+        println!(" #  start       end         size      type");
+        for (i, p) in self.partitions.iter().enumerate() {
+            println!(
+                "{:>2}  {:<10}  {:<10}  {:>6}MiB  {}",
+                i + 1,
+                p.start,
+                p.start + p.sectors - 1,
+                p.sectors / 2048,
+                type_name(self.scheme, p)
+            );
+        }
+    }
+
+    fn add(&mut self, kind: &str, size: Option<u64>) -> Result<(), &'static str> {
+        let (_, mbr_id, type_guid) = *TYPES
+            .iter()
+            .find(|t| t.0 == kind)
+            .ok_or("Unknown partition type, h for help")?;
+        let max = match self.scheme {
+            Scheme::None => return Err("There is no partition table, create one with o or g"),
+            Scheme::Mbr => 4,
+            Scheme::Gpt => ENTRY_COUNT,
+        };
+        if self.partitions.len() >= max {
+            return Err("The table is full");
+        }
+
+        let (first, end) = self.usable();
+        let mut used: Vec<(u64, u64)> = self
+            .partitions
+            .iter()
+            .map(|p| (p.start, p.start + p.sectors))
+            .collect();
+        used.sort_unstable();
+        used.push((end, end));
+
+        // First gap the partition fits in
+        let mut start = first;
+        let mut found = None;
+        for (used_start, used_end) in used {
+            let gap = used_start.saturating_sub(start);
+            match size {
+                Some(size) if gap >= size => found = Some((start, size)),
+                None if gap > 0 => found = Some((start, gap)),
+                _ => (),
+            }
+            if found.is_some() {
+                break;
+            }
+            start = start.max(used_end.next_multiple_of(ALIGN));
+        }
+        let (start, sectors) = found.ok_or("There is no free space big enough")?;
+
+        let mut entry = EMPTY_ENTRY;
+        if self.scheme == Scheme::Gpt {
+            let mut seed = unsafe { _rdtsc() } ^ start;
+            entry.type_guid = type_guid;
+            entry.unique_guid = random_guid(&mut seed);
+            entry.first_lba = start;
+            entry.last_lba = start + sectors - 1;
+            let mut name = [0; 36];
+            for (c, n) in kind.encode_utf16().zip(name.iter_mut()) {
+                *n = c;
+            }
+            entry.name = name;
+        }
+        self.partitions.push(Partition {
+            mbr_id,
+            entry,
+            start,
+            sectors,
+        });
+        self.partitions.sort_unstable_by_key(|p| p.start);
+        Ok(())
+    }
+
+    fn write(&self, disk: &mut File) -> Result<(), FSServiceError> {
+        // The boot code in front of the table is kept
+        let mbr = &mut [0u8; 512];
+        read_sectors(disk, 0, mbr)?;
+        mbr[446..510].fill(0);
+        let mut set = |i: usize, id: u8, start: u64, sectors: u64| {
+            let e = &mut mbr[446 + i * 16..446 + (i + 1) * 16];
+            // CHS addresses aren't used, these say as much
+            e[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+            e[4] = id;
+            e[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+            e[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+            e[12..16].copy_from_slice(&(sectors.min(u32::MAX as u64) as u32).to_le_bytes());
+        };
+        match self.scheme {
+            Scheme::Gpt => {
+                set(0, PROTECTIVE_MBR_ID, 1, self.sectors - 1);
+                self.write_gpt(disk)?;
+            }
+            _ => {
+                for (i, p) in self.partitions.iter().enumerate() {
+                    set(i, p.mbr_id, p.start, p.sectors);
+                }
+            }
+        }
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        write_sectors(disk, 0, mbr)
+    }
+
+    fn write_gpt(&self, disk: &mut File) -> Result<(), FSServiceError> {
+        let mut entries = alloc::vec![0u8; ENTRY_COUNT * ENTRY_SIZE];
+        for (p, e) in self
+            .partitions
+            .iter()
+            .zip(entries.chunks_exact_mut(ENTRY_SIZE))
+        {
+            e.copy_from_slice(as_bytes(&p.entry));
+        }
+
+        let last = self.sectors - 1;
+        let mut header = GptHeader {
+            signature: SIGNATURE,
+            revision: REVISION,
+            header_size: core::mem::size_of::<GptHeader>() as u32,
+            header_crc: 0,
+            _reserved: 0,
+            current_lba: 1,
+            backup_lba: last,
+            first_usable_lba: 2 + ENTRY_SECTORS,
+            last_usable_lba: last - ENTRY_SECTORS - 1,
+            disk_guid: self.disk_guid,
+            entries_lba: 2,
+            entry_count: ENTRY_COUNT as u32,
+            entry_size: ENTRY_SIZE as u32,
+            entries_crc: crc32(&entries),
+        };
+
+        let sector = &mut [0u8; 512];
+        header.header_crc = header.compute_crc();
+        sector[..header.header_size as usize].copy_from_slice(as_bytes(&header));
+        write_sectors(disk, 2, &entries)?;
+        write_sectors(disk, 1, sector)?;
+
+        header.current_lba = last;
+        header.backup_lba = 1;
+        header.entries_lba = last - ENTRY_SECTORS;
+        header.header_crc = header.compute_crc();
+        sector[..header.header_size as usize].copy_from_slice(as_bytes(&header));
+        write_sectors(disk, last - ENTRY_SECTORS, &entries)?;
+        write_sectors(disk, last, sector)
+    }
+}
@@ -0,0 +1,353 @@
+#![no_std]
+#![no_main]
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Display;
+use kernel_userspace::{
+    fs::{FSServiceError, File},
+    net::{resolve, IPAddr, NetError, TcpStream},
+    syscall::{exit, read_args},
+};
+use userspace::print::WRITER;
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: fetch <http://host[:port]/path> [file]";
+/// The longest status, header or chunk size line that is accepted
+const MAX_LINE: usize = 0x2000;
+/// How much is read from the connection at a time
+const READ_SIZE: usize = 0x1000;
+
+enum Error {
+    Net(NetError),
+    Fs(FSServiceError),
+    Http(&'static str),
+}
+
+impl From<NetError> for Error {
+    fn from(e: NetError) -> Self {
+        Self::Net(e)
+    }
+}
+
+impl From<FSServiceError> for Error {
+    fn from(e: FSServiceError) -> Self {
+        Self::Fs(e)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::Net(e) => write!(f, "{e}"),
+            Error::Fs(e) => write!(f, "{e:?}"),
+            Error::Http(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+struct Url<'a> {
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+/// Splits up `http://host[:port][/path]`, ipv6 hosts go in brackets
+fn parse_url(url: &str) -> Option<Url<'_>> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(v6) => {
+            let (host, port) = v6.split_once(']')?;
+            match port {
+                "" => (host, 80),
+                port => (host, port.strip_prefix(':')?.parse().ok()?),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        },
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some(Url { host, port, path })
+}
+
+/// Buffers the connection so the head and chunk sizes can be read a line at a time
+struct Reader {
+    stream: TcpStream,
+    buf: Vec<u8>,
+    start: usize,
+}
+
+impl Reader {
+    /// Reads more from the connection, returns false once the server has closed it
+    fn fill(&mut self) -> Result<bool, NetError> {
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        }
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+        let read = self.stream.read(&mut self.buf[len..])?;
+        self.buf.truncate(len + read);
+        Ok(read != 0)
+    }
+
+    /// The next line without its line ending
+    fn line(&mut self) -> Result<String, Error> {
+        loop {
+            if let Some(i) = self.buf[self.start..].iter().position(|b| *b == b'\n') {
+                let line = &self.buf[self.start..self.start + i];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                let line = core::str::from_utf8(line).map_err(|_| Error::Http("invalid line"))?;
+                let line = line.to_string();
+                self.start += i + 1;
+                return Ok(line);
+            }
+            if self.buf.len() - self.start > MAX_LINE {
+                return Err(Error::Http("line too long"));
+            }
+            if !self.fill()? {
+                return Err(Error::Http("connection closed early"));
+            }
+        }
+    }
+
+    /// Up to max bytes of whatever comes next, empty once the server has closed the connection
+    fn read(&mut self, max: usize) -> Result<&[u8], NetError> {
+        if self.start == self.buf.len() && !self.fill()? {
+            return Ok(&[]);
+        }
+        let start = self.start;
+        self.start += (self.buf.len() - start).min(max);
+        Ok(&self.buf[start..self.start])
+    }
+
+    /// Passes exactly len bytes to out
+    fn copy(&mut self, mut len: usize, out: &mut Output) -> Result<(), Error> {
+        while len > 0 {
+            let data = self.read(len)?;
+            if data.is_empty() {
+                return Err(Error::Http("connection closed before the end of the body"));
+            }
+            len -= data.len();
+            out.write(data)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the body goes
+enum Output {
+    Stdout,
+    File(File),
+}
+
+impl Output {
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            Output::Stdout => WRITER.lock().write_raw(data),
+            Output::File(f) => {
+                f.write(data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How the server marks the end of the body
+enum Body {
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+struct Head {
+    status: u16,
+    reason: String,
+    body: Body,
+    location: Option<String>,
+}
+
+fn read_head(reader: &mut Reader) -> Result<Head, Error> {
+    let line = reader.line()?;
+    let mut parts = line.splitn(3, ' ');
+    if !parts.next().is_some_and(|v| v.starts_with("HTTP/")) {
+        return Err(Error::Http("not an http response"));
+    }
+    let status = parts
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Error::Http("invalid status"))?;
+    let reason = parts.next().unwrap_or_default().to_string();
+
+    let mut head = Head {
+        status,
+        reason,
+        body: Body::UntilClose,
+        location: None,
+    };
+    loop {
+        let line = reader.line()?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(Error::Http("invalid header"));
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            if value.to_ascii_lowercase().contains("chunked") {
+                head.body = Body::Chunked;
+            }
+        } else if name.eq_ignore_ascii_case("content-length") {
+            // Chunked takes priority over a length
+            if !matches!(head.body, Body::Chunked) {
+                let len = value
+                    .parse()
+                    .map_err(|_| Error::Http("invalid content length"))?;
+                head.body = Body::Length(len);
+            }
+        } else if name.eq_ignore_ascii_case("location") {
+            head.location = Some(value.to_string());
+        }
+    }
+    // These never have a body
+    if head.status == 204 || head.status == 304 {
+        head.body = Body::Length(0);
+    }
+    Ok(head)
+}
+
+fn copy_body(reader: &mut Reader, body: Body, out: &mut Output) -> Result<usize, Error> {
+    match body {
+        Body::Length(len) => {
+            reader.copy(len, out)?;
+            Ok(len)
+        }
+        Body::UntilClose => {
+            let mut total = 0;
+            loop {
+                let data = reader.read(usize::MAX)?;
+                if data.is_empty() {
+                    return Ok(total);
+                }
+                total += data.len();
+                out.write(data)?;
+            }
+        }
+        Body::Chunked => {
+            let mut total = 0;
+            loop {
+                let line = reader.line()?;
+                let size = line.split(';').next().unwrap_or_default().trim();
+                let size = usize::from_str_radix(size, 16)
+                    .map_err(|_| Error::Http("invalid chunk size"))?;
+                if size == 0 {
+                    // Skip the trailers
+                    while !reader.line()?.is_empty() {}
+                    return Ok(total);
+                }
+                reader.copy(size, out)?;
+                total += size;
+                if !reader.line()?.is_empty() {
+                    return Err(Error::Http("chunk is longer than its size"));
+                }
+            }
+        }
+    }
+}
+
+fn fetch(url: &Url, path: Option<&str>) -> Result<(), Error> {
+    let ip = resolve(url.host)?
+        .preferred()
+        .ok_or(NetError::HostNotFound)?;
+    let stream = TcpStream::connect(ip, url.port)?;
+    let mut reader = Reader {
+        stream,
+        buf: Vec::new(),
+        start: 0,
+    };
+
+    let host = match (ip, url.port) {
+        (IPAddr::V6(_), 80) if url.host.contains(':') => format!("[{}]", url.host),
+        (IPAddr::V6(_), port) if url.host.contains(':') => format!("[{}]:{port}", url.host),
+        (_, 80) => url.host.to_string(),
+        (_, port) => format!("{}:{port}", url.host),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: fioxa-fetch\r\nAccept: */*\r\nConnection: close\r\n\r\n",
+        url.path
+    );
+    reader.stream.write(request.as_bytes())?;
+
+    // Informational responses come before the real one
+    let head = loop {
+        let head = read_head(&mut reader)?;
+        if !(100..200).contains(&head.status) {
+            break head;
+        }
+    };
+    if !(200..300).contains(&head.status) {
+        println!("fetch: server replied {} {}", head.status, head.reason);
+        if let Some(location) = head.location {
+            println!("fetch: it points to {location}");
+        }
+        return Ok(());
+    }
+
+    // The file is only replaced once the request has worked
+    let mut out = match path {
+        Some(path) => Output::File(File::create(path)?),
+        None => Output::Stdout,
+    };
+    let total = copy_body(&mut reader, head.body, &mut out)?;
+    if let Some(path) = path {
+        println!("saved {total} bytes to {path}");
+    }
+    Ok(())
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let Some(url) = args.next() else {
+        println!("{USAGE}");
+        exit()
+    };
+    let path = args.next();
+
+    let Some(url) = parse_url(url) else {
+        if url.starts_with("https://") {
+            println!("fetch: https is not supported");
+        } else {
+            println!("{USAGE}");
+        }
+        exit()
+    };
+
+    if let Err(e) = fetch(&url, path) {
+        println!("fetch: {e}");
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    fs::{check, stat, FsProblem, StatResponse},
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: fsck [-r] <disk id | absolute path on the disk>";
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut buffer = Vec::new();
+
+    let mut repair = false;
+    let mut target = None;
+    for arg in args.split_whitespace() {
+        match arg {
+            "-r" => repair = true,
+            _ if target.is_none() => target = Some(arg),
+            _ => {
+                println!("{USAGE}");
+                exit()
+            }
+        }
+    }
+    let Some(target) = target else {
+        println!("{USAGE}");
+        exit()
+    };
+
+    let disk = match target.parse() {
+        Ok(disk) => disk,
+        Err(_) => match stat(target, &mut buffer) {
+            Ok(StatResponse::File(f)) => f.disk_id,
+            Ok(StatResponse::Folder(f)) => f.disk_id,
+            Err(e) => {
+                println!("fsck: {target}: {e:?}");
+                exit()
+            }
+        },
+    };
+
+    let problems = match check(disk, repair, &mut buffer) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("fsck: disk {disk}: {e:?}");
+            exit()
+        }
+    };
+
+    for problem in &problems {
+        match problem {
+            FsProblem::CrossLinked { path, cluster } => {
+                println!("{path}: cross-linked with another file at cluster {cluster}")
+            }
+            FsProblem::BadChain { path, cluster } => {
+                println!("{path}: chain is broken at cluster {cluster}")
+            }
+            FsProblem::SizeMismatch {
+                path,
+                size,
+                allocated,
+            } => println!("{path}: size is {size} but only {allocated} bytes are allocated"),
+            FsProblem::LostClusters(n) => println!("{n} lost clusters"),
+            FsProblem::FreeCount { recorded, actual } => {
+                println!("Free cluster count is {recorded} but should be {actual}")
+            }
+        }
+    }
+
+    match (problems.len(), repair) {
+        (0, _) => println!("disk {disk}: clean"),
+        (n, true) => println!("disk {disk}: fixed {n} problems"),
+        (n, false) => println!("disk {disk}: {n} problems, run with -r to fix them"),
+    }
+    exit();
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
@@ -0,0 +1,207 @@
+#![no_std]
+#![no_main]
+
+use alloc::{format, string::String};
+use kernel_userspace::{
+    net::{
+        firewall::{self, FirewallAction, FirewallDirection, FirewallProtocol, FirewallRule},
+        interfaces, IPAddr, InterfaceInfo,
+    },
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str =
+    "Usage: fw [list | add [number] <rule> | del <number> | policy <in|out> <accept|drop> | flush]
+A rule is <in|out> <accept|drop> [on <interface>] [proto <tcp|udp|icmp>] [addr <ip>[/prefix]]
+[port <our port>] [rport <their port>]";
+
+fn parse_direction(s: &str) -> Option<FirewallDirection> {
+    match s {
+        "in" => Some(FirewallDirection::In),
+        "out" => Some(FirewallDirection::Out),
+        _ => None,
+    }
+}
+
+fn parse_action(s: &str) -> Option<FirewallAction> {
+    match s {
+        "accept" => Some(FirewallAction::Accept),
+        "drop" => Some(FirewallAction::Drop),
+        _ => None,
+    }
+}
+
+/// An address with or without a prefix length, without one it is just that address
+fn parse_addr(s: &str) -> Option<(IPAddr, u8)> {
+    match s.split_once('/') {
+        Some((addr, len)) => Some((addr.parse().ok()?, len.parse().ok()?)),
+        None => {
+            let addr: IPAddr = s.parse().ok()?;
+            Some((addr, if addr.is_ipv6() { 128 } else { 32 }))
+        }
+    }
+}
+
+fn parse_rule<'a>(mut args: impl Iterator<Item = &'a str>) -> Option<FirewallRule> {
+    let mut rule = FirewallRule {
+        direction: parse_direction(args.next()?)?,
+        action: parse_action(args.next()?)?,
+        interface: None,
+        protocol: None,
+        remote: None,
+        local_port: None,
+        remote_port: None,
+    };
+    while let Some(key) = args.next() {
+        let value = args.next()?;
+        match key {
+            "on" => match interfaces().into_iter().find(|i| i.name == value) {
+                Some(i) => rule.interface = Some(i.id),
+                None => {
+                    println!("fw: no interface called {value}");
+                    exit()
+                }
+            },
+            "proto" => {
+                rule.protocol = Some(match value {
+                    "tcp" => FirewallProtocol::Tcp,
+                    "udp" => FirewallProtocol::Udp,
+                    "icmp" => FirewallProtocol::Icmp,
+                    _ => return None,
+                })
+            }
+            "addr" => rule.remote = Some(parse_addr(value)?),
+            "port" => rule.local_port = Some(value.parse().ok()?),
+            "rport" => rule.remote_port = Some(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some(rule)
+}
+
+fn action_name(action: FirewallAction) -> &'static str {
+    match action {
+        FirewallAction::Accept => "accept",
+        FirewallAction::Drop => "drop",
+    }
+}
+
+fn describe(rule: &FirewallRule, names: &[InterfaceInfo]) -> String {
+    let mut s = String::from(match rule.direction {
+        FirewallDirection::In => "in ",
+        FirewallDirection::Out => "out",
+    });
+    s += &format!(" {:<6}", action_name(rule.action));
+    if let Some(id) = rule.interface {
+        let name = names.iter().find(|i| i.id == id).map_or("?", |i| &i.name);
+        s += &format!(" on {name}");
+    }
+    if let Some(protocol) = rule.protocol {
+        s += match protocol {
+            FirewallProtocol::Icmp => " proto icmp",
+            FirewallProtocol::Tcp => " proto tcp",
+            FirewallProtocol::Udp => " proto udp",
+        };
+    }
+    if let Some((addr, len)) = rule.remote {
+        s += &format!(" addr {addr}/{len}");
+    }
+    if let Some(port) = rule.local_port {
+        s += &format!(" port {port}");
+    }
+    if let Some(port) = rule.remote_port {
+        s += &format!(" rport {port}");
+    }
+    s
+}
+
+fn list() {
+    let table = firewall::table();
+    let names = interfaces();
+    println!(
+        "policy in {}, out {}",
+        action_name(table.policy_in),
+        action_name(table.policy_out)
+    );
+    for (i, entry) in table.rules.iter().enumerate() {
+        println!(
+            "{:>3}  {:<60} {} packets",
+            i + 1,
+            describe(&entry.rule, &names),
+            entry.matched
+        );
+    }
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace().peekable();
+
+    let res = match args.next() {
+        None | Some("list") => {
+            list();
+            Ok(())
+        }
+        Some("add") => {
+            // Rules are numbered from 1 for people
+            let index = match args.peek().map(|a| a.parse::<u32>()) {
+                Some(Ok(0)) => {
+                    println!("{USAGE}");
+                    exit()
+                }
+                Some(Ok(n)) => {
+                    args.next();
+                    Some(n - 1)
+                }
+                _ => None,
+            };
+            let Some(rule) = parse_rule(args) else {
+                println!("{USAGE}");
+                exit()
+            };
+            firewall::insert(index, rule)
+        }
+        Some("del") => match args.next().map(|a| a.parse::<u32>()) {
+            Some(Ok(n)) if n > 0 => firewall::delete(n - 1),
+            _ => {
+                println!("{USAGE}");
+                exit()
+            }
+        },
+        Some("policy") => {
+            let (Some(direction), Some(action)) = (
+                args.next().and_then(parse_direction),
+                args.next().and_then(parse_action),
+            ) else {
+                println!("{USAGE}");
+                exit()
+            };
+            firewall::set_policy(direction, action);
+            Ok(())
+        }
+        Some("flush") => {
+            firewall::flush();
+            Ok(())
+        }
+        Some(_) => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+    if let Err(e) = res {
+        println!("fw: {e}");
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
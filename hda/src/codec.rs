@@ -0,0 +1,207 @@
+//! Finding a way through a codec's widgets from an output pin back to a converter, and
+//! setting everything on it up to play our stream.
+
+use alloc::vec::Vec;
+
+use crate::{controller::Controller, regs::*};
+
+/// How many widgets there can be between a pin and its converter
+const MAX_DEPTH: usize = 5;
+
+struct Widget {
+    node: u8,
+    caps: u32,
+    connections: Vec<u8>,
+}
+
+impl Widget {
+    fn kind(&self) -> u32 {
+        self.caps >> 20 & 0xF
+    }
+}
+
+/// The widgets from an output pin to the converter that feeds it
+pub struct OutputPath {
+    codec: u8,
+    function_group: u8,
+    /// The pin first and the converter last
+    widgets: Vec<Widget>,
+    headphones: bool,
+}
+
+/// Looks for the audio function group of the codec and the best output on it. Line outs are
+/// preferred over speakers, and speakers over headphones.
+pub fn find_output(hda: &mut Controller, codec: u8) -> Option<OutputPath> {
+    let (start, count) = sub_nodes(hda, codec, 0);
+    let function_group = (start..start + count).find(|&node| {
+        hda.parameter(codec, node, PARAM_FUNCTION_GROUP_TYPE) & 0xFF == FUNCTION_GROUP_AUDIO
+    })?;
+
+    let (start, count) = sub_nodes(hda, codec, function_group);
+    let mut widgets: Vec<Widget> = (start..start + count)
+        .map(|node| Widget {
+            node,
+            caps: hda.parameter(codec, node, PARAM_AUDIO_WIDGET_CAP),
+            connections: connections(hda, codec, node),
+        })
+        .collect();
+
+    let mut pins = Vec::new();
+    for w in widgets.iter().filter(|w| w.kind() == WIDGET_TYPE_PIN) {
+        if hda.parameter(codec, w.node, PARAM_PIN_CAP) & PIN_CAP_OUTPUT == 0 {
+            continue;
+        }
+        let Some(config) = hda.verb(codec, w.node, VERB_GET_CONFIG_DEFAULT, 0) else {
+            continue;
+        };
+        let device = config >> 20 & 0xF;
+        let outputs = [DEVICE_LINE_OUT, DEVICE_SPEAKER, DEVICE_HP_OUT];
+        if config >> 30 != CONFIG_NO_CONNECTION && outputs.contains(&device) {
+            pins.push((device, w.node));
+        }
+    }
+    pins.sort();
+
+    for (device, pin) in pins {
+        let mut path = Vec::new();
+        if search(&widgets, pin, &mut path) {
+            let on_path = path
+                .iter()
+                .map(|node| {
+                    let i = widgets.iter().position(|w| w.node == *node).unwrap();
+                    widgets.swap_remove(i)
+                })
+                .collect();
+            return Some(OutputPath {
+                codec,
+                function_group,
+                widgets: on_path,
+                headphones: device == DEVICE_HP_OUT,
+            });
+        }
+    }
+    None
+}
+
+/// The first node below this one and how many there are
+fn sub_nodes(hda: &mut Controller, codec: u8, node: u8) -> (u8, u8) {
+    let count = hda.parameter(codec, node, PARAM_NODE_COUNT);
+    ((count >> 16) as u8, count as u8)
+}
+
+/// The nodes a widget can take its input from. Ranges in the list are taken as their two ends.
+fn connections(hda: &mut Controller, codec: u8, node: u8) -> Vec<u8> {
+    let length = hda.parameter(codec, node, PARAM_CONNECTION_LIST_LENGTH);
+    let long = length & 1 << 7 != 0;
+    let length = length & 0x7F;
+    let per_response = if long { 2 } else { 4 };
+
+    let mut list = Vec::new();
+    for first in (0..length).step_by(per_response) {
+        let Some(entries) = hda.verb(codec, node, VERB_GET_CONNECTION_ENTRY, first) else {
+            break;
+        };
+        for i in 0..per_response.min((length - first) as usize) {
+            let entry = match long {
+                true => entries >> (i * 16) & 0x7FFF,
+                false => entries >> (i * 8) & 0x7F,
+            };
+            list.push(entry as u8);
+        }
+    }
+    list
+}
+
+/// Walks back from the node to a converter, leaving the nodes on the way in path
+fn search(widgets: &[Widget], node: u8, path: &mut Vec<u8>) -> bool {
+    if path.len() == MAX_DEPTH || path.contains(&node) {
+        return false;
+    }
+    let Some(widget) = widgets.iter().find(|w| w.node == node) else {
+        return false;
+    };
+    path.push(node);
+    if widget.kind() == WIDGET_TYPE_OUTPUT {
+        return true;
+    }
+    if path.len() == 1 || [WIDGET_TYPE_MIXER, WIDGET_TYPE_SELECTOR].contains(&widget.kind()) {
+        for &next in &widget.connections {
+            if search(widgets, next, path) {
+                return true;
+            }
+        }
+    }
+    path.pop();
+    false
+}
+
+/// Powers up and unmutes everything on the path, and points the converter at our stream
+pub fn configure(hda: &mut Controller, output: &OutputPath, stream_tag: u8, format: u16) {
+    let codec = output.codec;
+    hda.verb(codec, output.function_group, VERB_SET_POWER_STATE, 0);
+
+    for (i, widget) in output.widgets.iter().enumerate() {
+        let node = widget.node;
+        hda.verb(codec, node, VERB_SET_POWER_STATE, 0);
+
+        if widget.caps & WIDGET_CAP_OUT_AMP != 0 {
+            let gain = zero_db(hda, codec, node, output.function_group, PARAM_OUT_AMP_CAP);
+            hda.verb(
+                codec,
+                node,
+                VERB_SET_AMP_GAIN_MUTE,
+                AMP_OUTPUT | AMP_LEFT_RIGHT | gain,
+            );
+        }
+
+        // Which of its inputs the next widget along is
+        let Some(next) = output.widgets.get(i + 1) else {
+            continue;
+        };
+        let Some(index) = widget.connections.iter().position(|&n| n == next.node) else {
+            continue;
+        };
+        if widget.caps & WIDGET_CAP_IN_AMP != 0 {
+            let gain = zero_db(hda, codec, node, output.function_group, PARAM_IN_AMP_CAP);
+            hda.verb(
+                codec,
+                node,
+                VERB_SET_AMP_GAIN_MUTE,
+                AMP_INPUT | AMP_LEFT_RIGHT | (index as u32) << 8 | gain,
+            );
+        }
+        // Mixers add up all their inputs, everything else picks one
+        if widget.kind() != WIDGET_TYPE_MIXER && widget.connections.len() > 1 {
+            hda.verb(codec, node, VERB_SET_CONNECTION_SELECT, index as u32);
+        }
+    }
+
+    let pin = &output.widgets[0];
+    let mut control = PIN_CONTROL_OUT;
+    if output.headphones {
+        control |= PIN_CONTROL_HP;
+    }
+    hda.verb(codec, pin.node, VERB_SET_PIN_CONTROL, control);
+    if hda.parameter(codec, pin.node, PARAM_PIN_CAP) & PIN_CAP_EAPD != 0 {
+        hda.verb(codec, pin.node, VERB_SET_EAPD, EAPD_ENABLE);
+    }
+
+    let converter = output.widgets.last().unwrap().node;
+    hda.verb(codec, converter, VERB_SET_FORMAT, format as u32);
+    hda.verb(
+        codec,
+        converter,
+        VERB_SET_STREAM_CHANNEL,
+        (stream_tag as u32) << 4,
+    );
+}
+
+/// The gain step of an amp that leaves the volume as it is. Widgets without amp capabilities of
+/// their own use the function group's.
+fn zero_db(hda: &mut Controller, codec: u8, node: u8, function_group: u8, param: u32) -> u32 {
+    let mut caps = hda.parameter(codec, node, param);
+    if caps == 0 {
+        caps = hda.parameter(codec, function_group, param);
+    }
+    caps & 0x7F
+}
@@ -0,0 +1,268 @@
+use core::{
+    mem::size_of,
+    ptr::{read_volatile, write_volatile},
+};
+
+use kernel_userspace::{audio::Frame, dma::DmaBuffer, syscall::sleep, time::uptime};
+
+use crate::regs::*;
+
+const CORB_ENTRIES: usize = 256;
+const RIRB_OFFSET: usize = 0x400;
+const BDL_OFFSET: usize = 0xC00;
+
+/// The cyclic buffer is split into this many parts, each interrupting when it's been played
+pub const BDL_ENTRIES: usize = 4;
+/// About 21ms each
+pub const ENTRY_FRAMES: usize = 1024;
+const ENTRY_BYTES: usize = ENTRY_FRAMES * size_of::<Frame>();
+
+/// The tag the codec knows our stream by, 0 is reserved
+pub const STREAM_TAG: u8 = 1;
+
+/// How long the controller and codecs get to answer, in ms
+const TIMEOUT: u64 = 100;
+
+#[repr(C)]
+struct BufferDescriptor {
+    address: u64,
+    length: u32,
+    /// Bit 0 interrupts when the entry has been played
+    flags: u32,
+}
+
+/// The controller's registers, its command rings and the one output stream we play through
+pub struct Controller {
+    regs: usize,
+    /// The CORB, then the RIRB and the buffer descriptor list
+    rings: DmaBuffer,
+    buffer: DmaBuffer,
+    corb_wp: usize,
+    rirb_rp: usize,
+    /// Where the output stream's descriptor is
+    stream: usize,
+    /// The part of the cyclic buffer to fill next
+    next_fill: usize,
+}
+
+unsafe impl Send for Controller {}
+
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let end = uptime() + TIMEOUT;
+    while !done() {
+        if uptime() > end {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+impl Controller {
+    fn read8(&self, reg: usize) -> u8 {
+        unsafe { read_volatile((self.regs + reg) as *const u8) }
+    }
+
+    fn read16(&self, reg: usize) -> u16 {
+        unsafe { read_volatile((self.regs + reg) as *const u16) }
+    }
+
+    fn read32(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.regs + reg) as *const u32) }
+    }
+
+    fn write8(&mut self, reg: usize, val: u8) {
+        unsafe { write_volatile((self.regs + reg) as *mut u8, val) }
+    }
+
+    fn write16(&mut self, reg: usize, val: u16) {
+        unsafe { write_volatile((self.regs + reg) as *mut u16, val) }
+    }
+
+    fn write32(&mut self, reg: usize, val: u32) {
+        unsafe { write_volatile((self.regs + reg) as *mut u32, val) }
+    }
+
+    /// Resets the controller and starts its command rings, returns it and the codecs that are
+    /// there as a mask
+    pub fn new(regs: usize) -> Option<(Self, u16)> {
+        let gcap = unsafe { read_volatile((regs + GCAP) as *const u16) };
+        let input_streams = (gcap >> 8 & 0xF) as usize;
+        if gcap >> 12 == 0 {
+            println!("HDA has no output streams");
+            return None;
+        }
+
+        // The controller only takes 32 bit addresses if it doesn't say otherwise
+        let rings = DmaBuffer::new_32(0x1000)?;
+        let buffer = DmaBuffer::new_32(BDL_ENTRIES * ENTRY_BYTES)?;
+        let mut hda = Self {
+            regs,
+            rings,
+            buffer,
+            corb_wp: 0,
+            rirb_rp: 0,
+            stream: SD_BASE + input_streams * SD_SIZE,
+            next_fill: 0,
+        };
+
+        // The rings have to be stopped before the reset
+        hda.write8(CORBCTL, 0);
+        hda.write8(RIRBCTL, 0);
+
+        let gctl = hda.read32(GCTL);
+        hda.write32(GCTL, gctl & !GCTL_CRST);
+        if !wait_for(|| hda.read32(GCTL) & GCTL_CRST == 0) {
+            println!("HDA didn't go into reset");
+            return None;
+        }
+        hda.write32(GCTL, gctl | GCTL_CRST);
+        if !wait_for(|| hda.read32(GCTL) & GCTL_CRST != 0) {
+            println!("HDA didn't come out of reset");
+            return None;
+        }
+        // Codecs have 521us after the reset to say they're there
+        sleep(1);
+        let codecs = hda.read16(STATESTS);
+        hda.write16(STATESTS, codecs);
+
+        let corb = hda.rings.phys_addr(0);
+        hda.write32(CORBLBASE, corb as u32);
+        hda.write32(CORBUBASE, (corb >> 32) as u32);
+        hda.write8(CORBSIZE, RING_SIZE_256);
+        // The read pointer reset has to be seen to be set and then cleared
+        hda.write16(CORBRP, CORBRP_RST);
+        wait_for(|| hda.read16(CORBRP) & CORBRP_RST != 0);
+        hda.write16(CORBRP, 0);
+        wait_for(|| hda.read16(CORBRP) & CORBRP_RST == 0);
+        hda.write16(CORBWP, 0);
+
+        let rirb = hda.rings.phys_addr(RIRB_OFFSET);
+        hda.write32(RIRBLBASE, rirb as u32);
+        hda.write32(RIRBUBASE, (rirb >> 32) as u32);
+        hda.write8(RIRBSIZE, RING_SIZE_256);
+        hda.write16(RIRBWP, RIRBWP_RST);
+        hda.write16(RINTCNT, 0xFF);
+
+        hda.write8(CORBCTL, CORBCTL_RUN);
+        hda.write8(RIRBCTL, RIRBCTL_DMAEN);
+
+        Some((hda, codecs))
+    }
+
+    /// Sends a verb to a node and waits for the answer. Verbs with a 16 bit payload have 4 bit
+    /// ids and the rest have 12.
+    pub fn verb(&mut self, codec: u8, node: u8, verb: u32, payload: u32) -> Option<u32> {
+        let command = match verb {
+            0..=0xF => verb << 16 | payload & 0xFFFF,
+            _ => verb << 8 | payload & 0xFF,
+        };
+        let command = (codec as u32) << 28 | (node as u32) << 20 | command;
+
+        self.corb_wp = (self.corb_wp + 1) % CORB_ENTRIES;
+        unsafe {
+            let corb = self.rings.as_ptr() as *mut u32;
+            write_volatile(corb.add(self.corb_wp), command);
+        }
+        self.write16(CORBWP, self.corb_wp as u16);
+
+        if !wait_for(|| self.read16(RIRBWP) as usize & 0xFF != self.rirb_rp) {
+            println!("HDA codec {codec} didn't answer {command:#x}");
+            return None;
+        }
+        self.rirb_rp = (self.rirb_rp + 1) % CORB_ENTRIES;
+        let response = unsafe {
+            let rirb = self.rings.as_ptr().add(RIRB_OFFSET) as *const u64;
+            read_volatile(rirb.add(self.rirb_rp))
+        };
+        let status = self.read8(RIRBSTS);
+        self.write8(RIRBSTS, status);
+        Some(response as u32)
+    }
+
+    pub fn parameter(&mut self, codec: u8, node: u8, parameter: u32) -> u32 {
+        self.verb(codec, node, VERB_GET_PARAMETER, parameter)
+            .unwrap_or(0)
+    }
+
+    /// Fills the cyclic buffer and starts playing it round and round. The codec has to be
+    /// listening for [`STREAM_TAG`] already.
+    pub fn start_stream(&mut self, mut fill: impl FnMut(&mut [Frame])) {
+        let stream = self.stream;
+        let ctl = self.read8(stream + SD_CTL);
+        self.write8(stream + SD_CTL, ctl & !SD_CTL_RUN);
+        self.write8(stream + SD_CTL, SD_CTL_SRST);
+        wait_for(|| self.read8(stream + SD_CTL) & SD_CTL_SRST != 0);
+        self.write8(stream + SD_CTL, 0);
+        wait_for(|| self.read8(stream + SD_CTL) & SD_CTL_SRST == 0);
+
+        for i in 0..BDL_ENTRIES {
+            let desc = BufferDescriptor {
+                address: self.buffer.phys_addr(i * ENTRY_BYTES),
+                length: ENTRY_BYTES as u32,
+                flags: 1,
+            };
+            unsafe {
+                let bdl = self.rings.as_ptr().add(BDL_OFFSET) as *mut BufferDescriptor;
+                write_volatile(bdl.add(i), desc);
+            }
+            fill(self.entry(i));
+        }
+        self.next_fill = 0;
+
+        let bdl = self.rings.phys_addr(BDL_OFFSET);
+        self.write32(stream + SD_BDPL, bdl as u32);
+        self.write32(stream + SD_BDPU, (bdl >> 32) as u32);
+        self.write32(stream + SD_CBL, (BDL_ENTRIES * ENTRY_BYTES) as u32);
+        self.write16(stream + SD_LVI, BDL_ENTRIES as u16 - 1);
+        self.write16(stream + SD_FMT, FORMAT_48K_16_STEREO);
+        self.write8(stream + SD_CTL_STREAM, STREAM_TAG << 4);
+
+        let index = (stream - SD_BASE) / SD_SIZE;
+        self.write32(INTCTL, INTCTL_GIE | 1 << index);
+        self.write8(stream + SD_CTL, SD_CTL_IOCE | SD_CTL_RUN);
+    }
+
+    /// Stops the stream so the last of the buffer doesn't keep repeating
+    pub fn stop_stream(&mut self) {
+        let ctl = self.read8(self.stream + SD_CTL);
+        self.write8(self.stream + SD_CTL, ctl & !SD_CTL_RUN);
+        self.write32(INTCTL, 0);
+    }
+
+    fn entry(&mut self, i: usize) -> &mut [Frame] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                self.buffer.as_ptr().add(i * ENTRY_BYTES) as *mut Frame,
+                ENTRY_FRAMES,
+            )
+        }
+    }
+
+    /// Refills the parts of the buffer that have been played, returns false if the interrupt
+    /// wasn't ours
+    pub fn interrupt_handler(&mut self, mut fill: impl FnMut(&mut [Frame])) -> bool {
+        if self.read32(INTSTS) == 0 {
+            return false;
+        }
+        let stream = self.stream;
+        let status = self.read8(stream + SD_STS);
+        self.write8(
+            stream + SD_STS,
+            status & (SD_STS_BCIS | SD_STS_FIFOE | SD_STS_DESE),
+        );
+        if status & SD_STS_FIFOE != 0 {
+            println!("HDA stream underran");
+        }
+
+        // Everything before the part being played can be filled again, even if an interrupt
+        // was missed
+        let playing = self.read32(stream + SD_LPIB) as usize / ENTRY_BYTES % BDL_ENTRIES;
+        while self.next_fill != playing {
+            let i = self.next_fill;
+            fill(self.entry(i));
+            self.next_fill = (i + 1) % BDL_ENTRIES;
+        }
+        true
+    }
+}
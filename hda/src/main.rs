@@ -0,0 +1,145 @@
+//! Driver for Intel High Definition Audio controllers, like QEMU's intel-hda and ich9-intel-hda.
+//!
+//! Codecs are talked to through the CORB and RIRB command rings. The first output found on a
+//! codec is set up and played from one output stream, whose cyclic buffer is refilled from the
+//! mixer as each part of it finishes. The mixer adds together the streams of everyone
+//! connected to the `AUDIO` service.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+pub mod codec;
+pub mod controller;
+pub mod mixer;
+pub mod regs;
+
+use core::ops::ControlFlow;
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use driver_sdk::pci;
+use kernel_userspace::{
+    audio::{AudioServiceMessage, AudioServiceResponse},
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    interrupt::interrupt_wait,
+    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
+    pci::{PCIDevice, PCIHeaderCommon},
+    power::on_shutdown,
+    service::{deserialize, serialize, Service, SimpleService},
+    syscall::{exit, spawn_thread},
+};
+
+use self::{
+    controller::{Controller, STREAM_TAG},
+    mixer::Mixer,
+    regs::FORMAT_48K_16_STEREO,
+};
+
+struct Hda {
+    controller: Controller,
+    mixer: Mixer,
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let pci_ref = KernelReferenceID::from_usize(2).unwrap();
+    assert_eq!(get_type(pci_ref), KernelObjectType::Channel);
+    let mut pci_device = PCIDevice {
+        device_service: SimpleService::new(KernelReference::from_id(pci_ref)),
+    };
+    let pci_ev = pci::interrupt(&mut pci_device);
+
+    let header = PCIHeaderCommon {
+        device: Arc::new(Mutex::new(pci_device)),
+    };
+    let Some(regs) = header.device.lock().get_bar(0).and_then(|b| b.map()) else {
+        println!("HDA has no memory bar");
+        exit()
+    };
+
+    let Some((mut controller, codecs)) = Controller::new(regs as usize) else {
+        println!("HDA failed to init");
+        exit()
+    };
+
+    let output = (0..15)
+        .filter(|codec| codecs & 1 << codec != 0)
+        .find_map(|codec| codec::find_output(&mut controller, codec));
+    let Some(output) = output else {
+        println!("HDA has no codec with an output");
+        exit()
+    };
+    codec::configure(&mut controller, &output, STREAM_TAG, FORMAT_48K_16_STEREO);
+
+    let mut mixer = Mixer::default();
+    controller.start_stream(|out| mixer.mix(out));
+    let hda = Arc::new(Mutex::new(Hda { controller, mixer }));
+
+    on_shutdown({
+        let hda = hda.clone();
+        move || hda.lock().controller.stop_stream()
+    });
+
+    spawn_thread({
+        let hda = hda.clone();
+        move || loop {
+            interrupt_wait(pci_ev.id());
+            let Hda { controller, mixer } = &mut *hda.lock();
+            controller.interrupt_handler(|out| mixer.mix(out));
+        }
+    });
+
+    println!("HDA ready");
+
+    let mut buffer = Vec::with_capacity(0x1000);
+    let mut handles = Vec::new();
+
+    Service::new(
+        "AUDIO",
+        || hda.lock().mixer.open(),
+        |handle, stream| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => {
+                    hda.lock().mixer.close(*stream);
+                    return ControlFlow::Break(());
+                }
+            }
+
+            let response = match deserialize(&buffer) {
+                Ok(AudioServiceMessage::Write(data)) => {
+                    AudioServiceResponse::Written(hda.lock().mixer.write(*stream, data))
+                }
+                Ok(AudioServiceMessage::Queued) => {
+                    AudioServiceResponse::Queued(hda.lock().mixer.queued(*stream))
+                }
+                Ok(AudioServiceMessage::SetVolume(volume)) => {
+                    hda.lock().mixer.set_volume(*stream, volume);
+                    AudioServiceResponse::Ok
+                }
+                Err(e) => {
+                    println!("HDA got a bad message: {e:?}");
+                    hda.lock().mixer.close(*stream);
+                    return ControlFlow::Break(());
+                }
+            };
+            let mut out = Vec::new();
+            channel_write_rs(handle.id(), serialize(&response, &mut out), &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
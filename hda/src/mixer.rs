@@ -0,0 +1,88 @@
+use alloc::collections::{BTreeMap, VecDeque};
+
+use kernel_userspace::audio::{Frame, SAMPLE_RATE};
+
+/// Each stream can queue a quarter of a second
+const QUEUE_FRAMES: usize = SAMPLE_RATE as usize / 4;
+
+struct Stream {
+    queue: VecDeque<Frame>,
+    volume: u8,
+}
+
+/// The streams the `AUDIO` service's connections play, added together into what the card
+/// plays
+#[derive(Default)]
+pub struct Mixer {
+    streams: BTreeMap<u64, Stream>,
+    next_id: u64,
+}
+
+impl Mixer {
+    pub fn open(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.streams.insert(
+            id,
+            Stream {
+                queue: VecDeque::new(),
+                volume: u8::MAX,
+            },
+        );
+        id
+    }
+
+    pub fn close(&mut self, id: u64) {
+        self.streams.remove(&id);
+    }
+
+    /// Queues as many of the little endian frames as fit, returns how many that was
+    pub fn write(&mut self, id: u64, data: &[u8]) -> usize {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return 0;
+        };
+        let room = QUEUE_FRAMES - stream.queue.len();
+        let frames = data.chunks_exact(4).take(room).map(|f| {
+            [
+                i16::from_le_bytes([f[0], f[1]]),
+                i16::from_le_bytes([f[2], f[3]]),
+            ]
+        });
+        let before = stream.queue.len();
+        stream.queue.extend(frames);
+        stream.queue.len() - before
+    }
+
+    pub fn queued(&self, id: u64) -> usize {
+        self.streams.get(&id).map_or(0, |s| s.queue.len())
+    }
+
+    pub fn set_volume(&mut self, id: u64, volume: u8) {
+        if let Some(stream) = self.streams.get_mut(&id) {
+            stream.volume = volume;
+        }
+    }
+
+    /// Fills out with the next frames of every stream added together, and silence where they
+    /// run out
+    pub fn mix(&mut self, out: &mut [Frame]) {
+        let mut sums = [[0i32; 2]; 256];
+        for chunk in out.chunks_mut(sums.len()) {
+            let sums = &mut sums[..chunk.len()];
+            sums.fill([0; 2]);
+            for stream in self.streams.values_mut() {
+                let volume = stream.volume as i32;
+                for (sum, frame) in sums
+                    .iter_mut()
+                    .zip(stream.queue.drain(..chunk.len().min(stream.queue.len())))
+                {
+                    sum[0] += frame[0] as i32 * volume / 255;
+                    sum[1] += frame[1] as i32 * volume / 255;
+                }
+            }
+            for (frame, sum) in chunk.iter_mut().zip(sums.iter()) {
+                *frame = sum.map(|s| s.clamp(i16::MIN as i32, i16::MAX as i32) as i16);
+            }
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! The controller registers and codec verbs we use, from Intel's High Definition Audio
+//! specification
+
+pub const GCAP: usize = 0x00;
+pub const GCTL: usize = 0x08;
+pub const STATESTS: usize = 0x0E;
+pub const INTCTL: usize = 0x20;
+pub const INTSTS: usize = 0x24;
+pub const CORBLBASE: usize = 0x40;
+pub const CORBUBASE: usize = 0x44;
+pub const CORBWP: usize = 0x48;
+pub const CORBRP: usize = 0x4A;
+pub const CORBCTL: usize = 0x4C;
+pub const CORBSIZE: usize = 0x4E;
+pub const RIRBLBASE: usize = 0x50;
+pub const RIRBUBASE: usize = 0x54;
+pub const RIRBWP: usize = 0x58;
+pub const RINTCNT: usize = 0x5A;
+pub const RIRBCTL: usize = 0x5C;
+pub const RIRBSTS: usize = 0x5D;
+pub const RIRBSIZE: usize = 0x5E;
+
+/// The stream descriptors start here, input ones first then output then bidirectional
+pub const SD_BASE: usize = 0x80;
+pub const SD_SIZE: usize = 0x20;
+pub const SD_CTL: usize = 0x00;
+/// The byte of the control register with the stream number in its top half
+pub const SD_CTL_STREAM: usize = 0x02;
+pub const SD_STS: usize = 0x03;
+pub const SD_LPIB: usize = 0x04;
+pub const SD_CBL: usize = 0x08;
+pub const SD_LVI: usize = 0x0C;
+pub const SD_FMT: usize = 0x12;
+pub const SD_BDPL: usize = 0x18;
+pub const SD_BDPU: usize = 0x1C;
+
+pub const GCTL_CRST: u32 = 1 << 0;
+pub const INTCTL_GIE: u32 = 1 << 31;
+pub const CORBRP_RST: u16 = 1 << 15;
+pub const CORBCTL_RUN: u8 = 1 << 1;
+pub const RIRBWP_RST: u16 = 1 << 15;
+pub const RIRBCTL_DMAEN: u8 = 1 << 1;
+/// Both rings with 256 entries
+pub const RING_SIZE_256: u8 = 0b10;
+
+pub const SD_CTL_SRST: u8 = 1 << 0;
+pub const SD_CTL_RUN: u8 = 1 << 1;
+pub const SD_CTL_IOCE: u8 = 1 << 2;
+pub const SD_STS_BCIS: u8 = 1 << 2;
+pub const SD_STS_FIFOE: u8 = 1 << 3;
+pub const SD_STS_DESE: u8 = 1 << 4;
+
+/// 48kHz, 16 bits and 2 channels, for both the stream and the converter
+pub const FORMAT_48K_16_STEREO: u16 = 0b001 << 4 | 1;
+
+pub const VERB_GET_PARAMETER: u32 = 0xF00;
+pub const VERB_GET_CONNECTION_ENTRY: u32 = 0xF02;
+pub const VERB_GET_CONFIG_DEFAULT: u32 = 0xF1C;
+pub const VERB_SET_CONNECTION_SELECT: u32 = 0x701;
+pub const VERB_SET_POWER_STATE: u32 = 0x705;
+pub const VERB_SET_STREAM_CHANNEL: u32 = 0x706;
+pub const VERB_SET_PIN_CONTROL: u32 = 0x707;
+pub const VERB_SET_EAPD: u32 = 0x70C;
+/// The verbs with a 16 bit payload only have 4 bits
+pub const VERB_SET_FORMAT: u32 = 0x2;
+pub const VERB_SET_AMP_GAIN_MUTE: u32 = 0x3;
+
+pub const PARAM_NODE_COUNT: u32 = 0x04;
+pub const PARAM_FUNCTION_GROUP_TYPE: u32 = 0x05;
+pub const PARAM_AUDIO_WIDGET_CAP: u32 = 0x09;
+pub const PARAM_PIN_CAP: u32 = 0x0C;
+pub const PARAM_IN_AMP_CAP: u32 = 0x0D;
+pub const PARAM_CONNECTION_LIST_LENGTH: u32 = 0x0E;
+pub const PARAM_OUT_AMP_CAP: u32 = 0x12;
+
+pub const FUNCTION_GROUP_AUDIO: u32 = 0x01;
+
+pub const WIDGET_TYPE_OUTPUT: u32 = 0x0;
+pub const WIDGET_TYPE_MIXER: u32 = 0x2;
+pub const WIDGET_TYPE_SELECTOR: u32 = 0x3;
+pub const WIDGET_TYPE_PIN: u32 = 0x4;
+pub const WIDGET_CAP_IN_AMP: u32 = 1 << 1;
+pub const WIDGET_CAP_OUT_AMP: u32 = 1 << 2;
+
+pub const PIN_CAP_OUTPUT: u32 = 1 << 4;
+pub const PIN_CAP_EAPD: u32 = 1 << 16;
+pub const PIN_CONTROL_OUT: u32 = 1 << 6;
+pub const PIN_CONTROL_HP: u32 = 1 << 7;
+pub const EAPD_ENABLE: u32 = 1 << 1;
+
+pub const CONFIG_NO_CONNECTION: u32 = 0b01;
+pub const DEVICE_LINE_OUT: u32 = 0x0;
+pub const DEVICE_SPEAKER: u32 = 0x1;
+pub const DEVICE_HP_OUT: u32 = 0x2;
+
+pub const AMP_OUTPUT: u32 = 1 << 15;
+pub const AMP_INPUT: u32 = 1 << 14;
+pub const AMP_LEFT_RIGHT: u32 = 0b11 << 12;
@@ -2,7 +2,7 @@ use serde::{Deserialize, Serialize};
 
 use self::virtual_code::VirtualKeyCode;
 
-pub mod us_keyboard;
+pub mod layout;
 pub mod virtual_code;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use super::virtual_code::{Control, Number, Numpad, VirtualKeyCode};
+
+mod de;
+mod dvorak;
+mod fr;
+mod uk;
+mod us;
+
+/// What a key types without modifiers, with shift and with AltGr. `'\0'` if it types nothing.
+type Key = [char; 3];
+
+/// Which character each key types. Keys are named by where they are on a US keyboard, so
+/// [`super::virtual_code::Letter::Q`] is the key to the right of tab whatever it says on it.
+///
+/// Dead keys like the circumflex type themselves rather than combining with the next key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyboardLayout {
+    Us,
+    Uk,
+    De,
+    Fr,
+    Dvorak,
+}
+
+impl KeyboardLayout {
+    pub const ALL: [KeyboardLayout; 5] = [
+        KeyboardLayout::Us,
+        KeyboardLayout::Uk,
+        KeyboardLayout::De,
+        KeyboardLayout::Fr,
+        KeyboardLayout::Dvorak,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyboardLayout::Us => "us",
+            KeyboardLayout::Uk => "uk",
+            KeyboardLayout::De => "de",
+            KeyboardLayout::Fr => "fr",
+            KeyboardLayout::Dvorak => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.name() == name)
+    }
+
+    /// The printable keys, None for the ones that are the same as US
+    fn key(self, keycode: VirtualKeyCode) -> Option<Key> {
+        match self {
+            KeyboardLayout::Us => None,
+            KeyboardLayout::Uk => uk::key(keycode),
+            KeyboardLayout::De => de::key(keycode),
+            KeyboardLayout::Fr => fr::key(keycode),
+            KeyboardLayout::Dvorak => dvorak::key(keycode),
+        }
+        .or_else(|| us::key(keycode))
+    }
+
+    pub fn get_unicode(
+        self,
+        keycode: VirtualKeyCode,
+        shift: bool,
+        altgr: bool,
+        caps: bool,
+        numlock: bool,
+    ) -> char {
+        if let Some([normal, shifted, alt]) = self.key(keycode) {
+            if altgr && alt != '\0' {
+                return alt;
+            }
+            // Caps lock only changes the keys that type letters
+            let upper = match normal.is_alphabetic() {
+                true => shift ^ caps,
+                false => shift,
+            };
+            return if upper { shifted } else { normal };
+        }
+
+        match keycode {
+            VirtualKeyCode::Numpad(key) => {
+                if numlock {
+                    match key {
+                        Numpad::N0 => '0',
+                        Numpad::N1 => '1',
+                        Numpad::N2 => '2',
+                        Numpad::N3 => '3',
+                        Numpad::N4 => '4',
+                        Numpad::N5 => '5',
+                        Numpad::N6 => '6',
+                        Numpad::N7 => '7',
+                        Numpad::N8 => '8',
+                        Numpad::N9 => '9',
+                        Numpad::Enter => '\n',
+                        Numpad::Period => '.',
+                        _ => '\0',
+                    }
+                } else {
+                    match key {
+                        Numpad::N5 => '5',
+                        Numpad::Enter => '\n',
+                        Numpad::Period => '.',
+                        _ => '\0',
+                    }
+                }
+            }
+            VirtualKeyCode::Control(key) => match key {
+                Control::Enter => '\n',
+                Control::Space => ' ',
+                Control::Backspace => '\x08',
+                Control::Delete => '\u{7F}',
+                Control::Tab => '\x09',
+                Control::ArrowUp => '\u{2191}',
+                Control::ArrowDown => '\u{2193}',
+                _ => '\0',
+            },
+            _ => '\0',
+        }
+    }
+}
+
+/// A key that types an ascii letter
+fn letter(c: char) -> Key {
+    [c, c.to_ascii_uppercase(), '\0']
+}
+
+/// The digit the key types on a US keyboard
+fn digit(number: Number) -> char {
+    (b'0' + number as u8) as char
+}
@@ -0,0 +1,41 @@
+use super::{letter, Key};
+use crate::keyboard::virtual_code::{Letter, Misc, Number, VirtualKeyCode};
+
+pub fn key(keycode: VirtualKeyCode) -> Option<Key> {
+    Some(match keycode {
+        VirtualKeyCode::Number(number) => match number {
+            Number::N2 => ['2', '"', '²'],
+            Number::N3 => ['3', '§', '³'],
+            Number::N6 => ['6', '&', '\0'],
+            Number::N7 => ['7', '/', '{'],
+            Number::N8 => ['8', '(', '['],
+            Number::N9 => ['9', ')', ']'],
+            Number::N0 => ['0', '=', '}'],
+            _ => return None,
+        },
+        VirtualKeyCode::Letter(l) => match l {
+            Letter::Q => ['q', 'Q', '@'],
+            Letter::E => ['e', 'E', '€'],
+            Letter::M => ['m', 'M', 'µ'],
+            Letter::Y => letter('z'),
+            Letter::Z => letter('y'),
+            _ => return None,
+        },
+        VirtualKeyCode::Misc(key) => match key {
+            Misc::Hyphen => ['ß', '?', '\\'],
+            Misc::Equals => ['´', '`', '\0'],
+            Misc::Comma => [',', ';', '\0'],
+            Misc::Period => ['.', ':', '\0'],
+            Misc::SemiColon => ['ö', 'Ö', '\0'],
+            Misc::ForwardSlash => ['-', '_', '\0'],
+            Misc::BackSlash => ['#', '\'', '\0'],
+            Misc::BackTick => ['^', '°', '\0'],
+            Misc::LeftBracket => ['ü', 'Ü', '\0'],
+            Misc::RightBracket => ['+', '*', '~'],
+            Misc::Quote => ['ä', 'Ä', '\0'],
+            Misc::NonUsBackSlash => ['<', '>', '|'],
+            Misc::MenuKey => return None,
+        },
+        _ => return None,
+    })
+}
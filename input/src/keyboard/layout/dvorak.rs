@@ -0,0 +1,49 @@
+use super::{letter, Key};
+use crate::keyboard::virtual_code::{Letter, Misc, VirtualKeyCode};
+
+/// The US Dvorak layout, the numbers are where they are on US
+pub fn key(keycode: VirtualKeyCode) -> Option<Key> {
+    Some(match keycode {
+        VirtualKeyCode::Letter(l) => match l {
+            Letter::Q => ['\'', '"', '\0'],
+            Letter::W => [',', '<', '\0'],
+            Letter::E => ['.', '>', '\0'],
+            Letter::R => letter('p'),
+            Letter::T => letter('y'),
+            Letter::Y => letter('f'),
+            Letter::U => letter('g'),
+            Letter::I => letter('c'),
+            Letter::O => letter('r'),
+            Letter::P => letter('l'),
+            Letter::A => letter('a'),
+            Letter::S => letter('o'),
+            Letter::D => letter('e'),
+            Letter::F => letter('u'),
+            Letter::G => letter('i'),
+            Letter::H => letter('d'),
+            Letter::J => letter('h'),
+            Letter::K => letter('t'),
+            Letter::L => letter('n'),
+            Letter::Z => [';', ':', '\0'],
+            Letter::X => letter('q'),
+            Letter::C => letter('j'),
+            Letter::V => letter('k'),
+            Letter::B => letter('x'),
+            Letter::N => letter('b'),
+            Letter::M => letter('m'),
+        },
+        VirtualKeyCode::Misc(key) => match key {
+            Misc::Hyphen => ['[', '{', '\0'],
+            Misc::Equals => [']', '}', '\0'],
+            Misc::LeftBracket => ['/', '?', '\0'],
+            Misc::RightBracket => ['=', '+', '\0'],
+            Misc::SemiColon => letter('s'),
+            Misc::Quote => ['-', '_', '\0'],
+            Misc::Comma => letter('w'),
+            Misc::Period => letter('v'),
+            Misc::ForwardSlash => letter('z'),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
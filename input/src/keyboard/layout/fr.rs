@@ -0,0 +1,45 @@
+use super::{letter, Key};
+use crate::keyboard::virtual_code::{Letter, Misc, Number, VirtualKeyCode};
+
+/// AZERTY, where the digits need shift
+pub fn key(keycode: VirtualKeyCode) -> Option<Key> {
+    Some(match keycode {
+        VirtualKeyCode::Number(number) => match number {
+            Number::N1 => ['&', '1', '\0'],
+            Number::N2 => ['é', '2', '~'],
+            Number::N3 => ['"', '3', '#'],
+            Number::N4 => ['\'', '4', '{'],
+            Number::N5 => ['(', '5', '['],
+            Number::N6 => ['-', '6', '|'],
+            Number::N7 => ['è', '7', '`'],
+            Number::N8 => ['_', '8', '\\'],
+            Number::N9 => ['ç', '9', '^'],
+            Number::N0 => ['à', '0', '@'],
+        },
+        VirtualKeyCode::Letter(l) => match l {
+            Letter::A => letter('q'),
+            Letter::Q => letter('a'),
+            Letter::W => letter('z'),
+            Letter::Z => letter('w'),
+            Letter::E => ['e', 'E', '€'],
+            Letter::M => [',', '?', '\0'],
+            _ => return None,
+        },
+        VirtualKeyCode::Misc(key) => match key {
+            Misc::Hyphen => [')', '°', ']'],
+            Misc::Equals => ['=', '+', '}'],
+            Misc::Comma => [';', '.', '\0'],
+            Misc::Period => [':', '/', '\0'],
+            Misc::SemiColon => letter('m'),
+            Misc::ForwardSlash => ['!', '§', '\0'],
+            Misc::BackSlash => ['*', 'µ', '\0'],
+            Misc::BackTick => ['²', '\0', '\0'],
+            Misc::LeftBracket => ['^', '¨', '\0'],
+            Misc::RightBracket => ['$', '£', '¤'],
+            Misc::Quote => ['ù', '%', '\0'],
+            Misc::NonUsBackSlash => ['<', '>', '\0'],
+            Misc::MenuKey => return None,
+        },
+        _ => return None,
+    })
+}
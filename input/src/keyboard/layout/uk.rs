@@ -0,0 +1,18 @@
+use super::Key;
+use crate::keyboard::virtual_code::{Misc, Number, VirtualKeyCode};
+
+pub fn key(keycode: VirtualKeyCode) -> Option<Key> {
+    Some(match keycode {
+        VirtualKeyCode::Number(Number::N2) => ['2', '"', '\0'],
+        VirtualKeyCode::Number(Number::N3) => ['3', '£', '\0'],
+        VirtualKeyCode::Number(Number::N4) => ['4', '$', '€'],
+        VirtualKeyCode::Misc(key) => match key {
+            Misc::BackTick => ['`', '¬', '¦'],
+            Misc::Quote => ['\'', '@', '\0'],
+            Misc::BackSlash => ['#', '~', '\0'],
+            Misc::NonUsBackSlash => ['\\', '|', '\0'],
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
@@ -0,0 +1,39 @@
+use super::{digit, letter, Key};
+use crate::keyboard::virtual_code::{Misc, Number, VirtualKeyCode};
+
+pub fn key(keycode: VirtualKeyCode) -> Option<Key> {
+    Some(match keycode {
+        VirtualKeyCode::Number(number) => {
+            let shifted = match number {
+                Number::N0 => ')',
+                Number::N1 => '!',
+                Number::N2 => '@',
+                Number::N3 => '#',
+                Number::N4 => '$',
+                Number::N5 => '%',
+                Number::N6 => '^',
+                Number::N7 => '&',
+                Number::N8 => '*',
+                Number::N9 => '(',
+            };
+            [digit(number), shifted, '\0']
+        }
+        VirtualKeyCode::Letter(l) => letter((b'a' + l as u8) as char),
+        VirtualKeyCode::Misc(key) => match key {
+            Misc::Hyphen => ['-', '_', '\0'],
+            Misc::Equals => ['=', '+', '\0'],
+            Misc::Comma => [',', '<', '\0'],
+            Misc::Period => ['.', '>', '\0'],
+            Misc::SemiColon => [';', ':', '\0'],
+            Misc::ForwardSlash => ['/', '?', '\0'],
+            Misc::BackSlash => ['\\', '|', '\0'],
+            Misc::BackTick => ['`', '~', '\0'],
+            Misc::LeftBracket => ['[', '{', '\0'],
+            Misc::RightBracket => [']', '}', '\0'],
+            Misc::Quote => ['\'', '"', '\0'],
+            Misc::NonUsBackSlash => ['\\', '|', '\0'],
+            Misc::MenuKey => return None,
+        },
+        _ => return None,
+    })
+}
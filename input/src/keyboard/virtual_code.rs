@@ -166,6 +166,8 @@ pub enum Misc {
     LeftBracket,
     RightBracket,
     Quote,
+    /// The key between left shift and Z that ISO keyboards have
+    NonUsBackSlash,
     MenuKey,
 }
 
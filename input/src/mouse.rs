@@ -7,4 +7,26 @@ pub struct MousePacket {
     pub middle: bool,
     pub x_mov: i8,
     pub y_mov: i8,
+    /// Scroll wheel movement, positive is towards the user
+    pub wheel: i8,
+    /// The side buttons, usually back and forward
+    pub button4: bool,
+    pub button5: bool,
 }
+
+/// Where the pointer is rather than how far it moved, from pointers like a virtual machine's
+/// that follow the host's cursor
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AbsoluteMousePacket {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    /// 0 is the left edge of the screen and [`ABSOLUTE_MAX`] the right, whatever its size
+    pub x: u16,
+    /// 0 is the top edge of the screen and [`ABSOLUTE_MAX`] the bottom
+    pub y: u16,
+    /// Scroll wheel movement, positive is towards the user
+    pub wheel: i8,
+}
+
+pub const ABSOLUTE_MAX: u16 = 0xFFFF;
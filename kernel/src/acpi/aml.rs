@@ -0,0 +1,333 @@
+//! The AML interpreter. Firmware describes what isn't in the static tables as code in the DSDT
+//! and SSDTs, like how pci interrupt pins are wired to the ioapic, what resources a device
+//! uses and how to talk to the embedded controller.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use ::aml::{
+    pci_routing::{PciRoutingTable, Pin},
+    resource::{resource_descriptor_list, InterruptPolarity, Resource},
+    value::Args,
+    AmlContext, AmlError, AmlName, AmlValue, DebugVerbosity, Handler, LevelType,
+};
+use acpi::{AcpiHandler, AcpiTables, AmlTable};
+use alloc::{boxed::Box, vec::Vec};
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    mutex::Mutex,
+    paging::{
+        ensure_ident_map_curr_process,
+        page::{Page, Size4KB},
+        MemoryMappingFlags,
+    },
+    pci::config_space,
+};
+
+use super::FioxaAcpiHandler;
+
+static AML: OnceCell<Mutex<Aml>> = OnceCell::uninit();
+
+struct Aml {
+    context: AmlContext,
+    /// The routing table of the root bridge, None if it doesn't have one
+    prt: Option<PciRoutingTable>,
+}
+
+/// Loads the DSDT and SSDTs and runs their initialisation. Without them none of the functions
+/// here find anything.
+pub fn init(tables: &AcpiTables<FioxaAcpiHandler>) {
+    let mut context = AmlContext::new(Box::new(FioxaAmlHandler), DebugVerbosity::None);
+
+    let Ok(dsdt) = tables.dsdt() else {
+        error!("No DSDT");
+        return;
+    };
+    for table in core::iter::once(dsdt).chain(tables.ssdts()) {
+        let address = table.address;
+        if let Err(e) = parse_table(&mut context, &FioxaAcpiHandler, table) {
+            error!("Couldn't parse aml table at {address:#x}: {e:?}");
+        }
+    }
+    if let Err(e) = context.initialize_objects() {
+        error!("Couldn't initialise aml objects: {e:?}");
+    }
+
+    // Tells firmware interrupts go through the ioapic, which changes what _PRT returns
+    let pic = Args::from_list(vec![AmlValue::Integer(1)]).unwrap();
+    match context.invoke_method(&AmlName::from_str("\\_PIC").unwrap(), pic) {
+        Ok(_) | Err(AmlError::ValueDoesNotExist(_)) => (),
+        Err(e) => error!("\\_PIC failed: {e:?}"),
+    }
+
+    let prt = find_devices(&mut context, &[eisa_id("PNP0A08"), eisa_id("PNP0A03")])
+        .first()
+        .and_then(|bridge| {
+            let path = AmlName::from_str("_PRT").ok()?.resolve(bridge).ok()?;
+            PciRoutingTable::from_prt_path(&path, &mut context)
+                .map_err(|e| error!("Couldn't read {}: {e:?}", path.as_string()))
+                .ok()
+        });
+
+    AML.init_once(|| Mutex::new(Aml { context, prt }));
+}
+
+fn parse_table(
+    context: &mut AmlContext,
+    handler: &FioxaAcpiHandler,
+    table: AmlTable,
+) -> Result<(), AmlError> {
+    let mapping =
+        unsafe { handler.map_physical_region::<u8>(table.address, table.length as usize) };
+    let stream = unsafe {
+        core::slice::from_raw_parts(mapping.virtual_start().as_ptr(), table.length as usize)
+    };
+    context.parse_table(stream)
+}
+
+/// The compressed form of an id like `PNP0A03` that `_HID` usually returns
+pub fn eisa_id(id: &str) -> u64 {
+    let b = id.as_bytes();
+    let c = |i: usize| (b[i] - 0x40) as u32 & 0x1F;
+    let h = |i: usize| (b[i] as char).to_digit(16).unwrap_or(0);
+    let value = c(0) << 26 | c(1) << 21 | c(2) << 16 | h(3) << 12 | h(4) << 8 | h(5) << 4 | h(6);
+    value.swap_bytes() as u64
+}
+
+/// The devices whose `_HID` is one of the ids
+fn find_devices(context: &mut AmlContext, ids: &[u64]) -> Vec<AmlName> {
+    let mut devices = Vec::new();
+    let _ = context.namespace.traverse(|name, level| {
+        if level.typ == LevelType::Device {
+            devices.push(name.clone());
+        }
+        Ok(true)
+    });
+
+    let hid = AmlName::from_str("_HID").unwrap();
+    devices.retain(|device| {
+        let Ok(path) = hid.resolve(device) else {
+            return false;
+        };
+        match context.invoke_method(&path, Args::EMPTY) {
+            Ok(AmlValue::Integer(id)) => ids.contains(&id),
+            Ok(AmlValue::String(id)) if id.len() == 7 => ids.contains(&eisa_id(&id)),
+            _ => false,
+        }
+    });
+    devices
+}
+
+/// Runs the closure with the interpreter, None if there isn't one
+pub fn with_context<R>(f: impl FnOnce(&mut AmlContext) -> R) -> Option<R> {
+    Some(f(&mut AML.get()?.lock().context))
+}
+
+/// The paths of the devices with any of the hardware ids, see [`eisa_id`]
+pub fn devices_with_id(ids: &[u64]) -> Vec<AmlName> {
+    with_context(|context| find_devices(context, ids)).unwrap_or_default()
+}
+
+/// The device's `_CRS`, which should be a resource template buffer
+fn current_resources(device: &AmlName) -> Option<AmlValue> {
+    with_context(|context| {
+        let path = AmlName::from_str("_CRS").ok()?.resolve(device).ok()?;
+        context
+            .invoke_method(&path, Args::EMPTY)
+            .map_err(|e| error!("{} failed: {e:?}", path.as_string()))
+            .ok()
+    })
+    .flatten()
+}
+
+/// The resources the device is using, from its `_CRS`
+pub fn device_resources(device: &AmlName) -> Option<Vec<Resource>> {
+    let crs = current_resources(device)?;
+    resource_descriptor_list(&crs)
+        .map_err(|e| error!("Bad resources in {}: {e:?}", device.as_string()))
+        .ok()
+}
+
+/// The base of each io port range in the device's `_CRS`, in order. The interpreter's
+/// [`Resource::IOPort`] keeps its range private, so the template is read here instead.
+pub fn device_io_ports(device: &AmlName) -> Option<Vec<u16>> {
+    let AmlValue::Buffer(crs) = current_resources(device)? else {
+        error!("{} has no resource template", device.as_string());
+        return None;
+    };
+    let crs = crs.lock();
+    let mut ports = Vec::new();
+    let mut rest = crs.as_slice();
+    while let Some(&tag) = rest.first() {
+        // Large items have a 16 bit length after the tag, small ones have it in the tag
+        let (name, len) = if tag & 0x80 != 0 {
+            let len = rest.get(1..3)?;
+            (tag, 3 + u16::from_le_bytes([len[0], len[1]]) as usize)
+        } else {
+            ((tag >> 3) & 0xF, 1 + (tag & 0x7) as usize)
+        };
+        let item = rest.get(..len)?;
+        match name {
+            // I/O port, the minimum base is what is decoded
+            0x8 if len >= 4 => ports.push(u16::from_le_bytes([item[2], item[3]])),
+            // Fixed location I/O port, with a 10 bit base
+            0x9 if len >= 3 => ports.push(u16::from_le_bytes([item[1], item[2]]) & 0x3FF),
+            // End tag
+            0xF => break,
+            _ => (),
+        }
+        rest = &rest[len..];
+    }
+    Some(ports)
+}
+
+/// The global system interrupt a pin of a device on the root bus is wired to and whether it
+/// is active low, from the root bridge's `_PRT`. Devices behind bridges aren't routed.
+pub fn route_pci_interrupt(device: u8, function: u8, pin: u8) -> Option<(u32, bool)> {
+    let pin = match pin {
+        1 => Pin::IntA,
+        2 => Pin::IntB,
+        3 => Pin::IntC,
+        4 => Pin::IntD,
+        _ => return None,
+    };
+    let mut aml = AML.get()?.lock();
+    let Aml { context, prt } = &mut *aml;
+    let irq = prt
+        .as_ref()?
+        .route(device as u16, function as u16, pin, context)
+        .map_err(|e| error!("Couldn't route {device}.{function} {pin:?}: {e:?}"))
+        .ok()?;
+    Some((irq.irq, irq.polarity == InterruptPolarity::ActiveLow))
+}
+
+/// Gives the interpreter access to memory, io ports and pci config space
+struct FioxaAmlHandler;
+
+impl FioxaAmlHandler {
+    fn map(address: usize) -> usize {
+        unsafe {
+            ensure_ident_map_curr_process(
+                Page::<Size4KB>::containing(address as u64),
+                MemoryMappingFlags::WRITEABLE,
+            )
+        };
+        address
+    }
+}
+
+impl Handler for FioxaAmlHandler {
+    fn read_u8(&self, address: usize) -> u8 {
+        unsafe { read_volatile(Self::map(address) as *const u8) }
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        unsafe { read_volatile(Self::map(address) as *const u16) }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        unsafe { read_volatile(Self::map(address) as *const u32) }
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        unsafe { read_volatile(Self::map(address) as *const u64) }
+    }
+
+    fn write_u8(&mut self, address: usize, value: u8) {
+        unsafe { write_volatile(Self::map(address) as *mut u8, value) }
+    }
+
+    fn write_u16(&mut self, address: usize, value: u16) {
+        unsafe { write_volatile(Self::map(address) as *mut u16, value) }
+    }
+
+    fn write_u32(&mut self, address: usize, value: u32) {
+        unsafe { write_volatile(Self::map(address) as *mut u32, value) }
+    }
+
+    fn write_u64(&mut self, address: usize, value: u64) {
+        unsafe { write_volatile(Self::map(address) as *mut u64, value) }
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        config_space(segment, bus, device, function)
+            .map_or(0xFF, |d| unsafe { d.read_u8(offset as u32) })
+    }
+
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        config_space(segment, bus, device, function)
+            .map_or(0xFFFF, |d| unsafe { d.read_u16(offset as u32) })
+    }
+
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        config_space(segment, bus, device, function)
+            .map_or(u32::MAX, |d| unsafe { d.read_u32(offset as u32) })
+    }
+
+    fn write_pci_u8(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
+    ) {
+        if let Some(mut d) = config_space(segment, bus, device, function) {
+            unsafe { d.write_u8(offset as u32, value) }
+        }
+    }
+
+    fn write_pci_u16(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
+    ) {
+        if let Some(mut d) = config_space(segment, bus, device, function) {
+            unsafe { d.write_u16(offset as u32, value) }
+        }
+    }
+
+    fn write_pci_u32(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
+    ) {
+        if let Some(mut d) = config_space(segment, bus, device, function) {
+            unsafe { d.write_u32(offset as u32, value) }
+        }
+    }
+}
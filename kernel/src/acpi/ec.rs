@@ -0,0 +1,115 @@
+//! The embedded controller, which laptops use for the battery, lid, fans and hotkeys. Its
+//! registers are read and written a byte at a time through a data and a command port, which
+//! its `_CRS` gives in that order.
+
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::port::Port;
+
+use crate::{mutex::Mutex, time::uptime_us};
+
+use super::aml::{device_io_ports, devices_with_id, eisa_id};
+
+const STATUS_OBF: u8 = 1 << 0;
+const STATUS_IBF: u8 = 1 << 1;
+
+const CMD_READ: u8 = 0x80;
+const CMD_WRITE: u8 = 0x81;
+
+/// How long the controller gets to take or give each byte
+const TIMEOUT_US: u64 = 10_000;
+
+static EC: OnceCell<Mutex<EmbeddedController>> = OnceCell::uninit();
+
+struct EmbeddedController {
+    data: Port<u8>,
+    command: Port<u8>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EcTimeout;
+
+impl EmbeddedController {
+    fn wait_status(&mut self, mask: u8, set: bool) -> Result<(), EcTimeout> {
+        let end = uptime_us() + TIMEOUT_US;
+        while (unsafe { self.command.read() } & mask != 0) != set {
+            if uptime_us() > end {
+                return Err(EcTimeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    fn send_command(&mut self, cmd: u8) -> Result<(), EcTimeout> {
+        self.wait_status(STATUS_IBF, false)?;
+        unsafe { self.command.write(cmd) };
+        Ok(())
+    }
+
+    fn send_data(&mut self, data: u8) -> Result<(), EcTimeout> {
+        self.wait_status(STATUS_IBF, false)?;
+        unsafe { self.data.write(data) };
+        Ok(())
+    }
+
+    fn read(&mut self, address: u8) -> Result<u8, EcTimeout> {
+        self.send_command(CMD_READ)?;
+        self.send_data(address)?;
+        self.wait_status(STATUS_OBF, true)?;
+        Ok(unsafe { self.data.read() })
+    }
+
+    fn write(&mut self, address: u8, value: u8) -> Result<(), EcTimeout> {
+        self.send_command(CMD_WRITE)?;
+        self.send_data(address)?;
+        self.send_data(value)
+    }
+}
+
+/// Finds the controller through the aml namespace, which has to have been loaded
+pub fn init() {
+    let Some(device) = devices_with_id(&[eisa_id("PNP0C09")]).into_iter().next() else {
+        return;
+    };
+    let Some(ports) = device_io_ports(&device) else {
+        return;
+    };
+    let mut ports = ports.into_iter();
+    let (Some(data), Some(command)) = (ports.next(), ports.next()) else {
+        error!("Embedded controller {} has no ports", device.as_string());
+        return;
+    };
+    info!("Embedded controller at {data:#x} {command:#x}");
+    EC.init_once(|| {
+        Mutex::new(EmbeddedController {
+            data: Port::new(data),
+            command: Port::new(command),
+        })
+    });
+}
+
+pub fn is_present() -> bool {
+    EC.get().is_some()
+}
+
+/// Reads one of the controller's registers, None if there isn't a controller or it didn't
+/// answer
+pub fn read(address: u8) -> Option<u8> {
+    EC.get()?
+        .lock()
+        .read(address)
+        .map_err(|e| warn!("Embedded controller read of {address:#x}: {e:?}"))
+        .ok()
+}
+
+/// Writes one of the controller's registers, false if there isn't a controller or it didn't
+/// take it
+pub fn write(address: u8, value: u8) -> bool {
+    let Some(ec) = EC.get() else {
+        return false;
+    };
+    ec.lock()
+        .write(address, value)
+        .map_err(|e| warn!("Embedded controller write of {address:#x}: {e:?}"))
+        .is_ok()
+}
@@ -0,0 +1,158 @@
+//! The fixed events, which the chipset signals through the SCI. Only the power and sleep
+//! buttons are turned on. The handler clears them and keeps what happened until the power
+//! manager takes it with [`take_events`].
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use acpi::{
+    address::{AddressSpace, GenericAddress},
+    fadt::Fadt,
+    AcpiTables,
+};
+use conquer_once::spin::OnceCell;
+use kernel_userspace::system::FixedEvents;
+use x86_64::instructions::port::Port;
+
+use crate::{ioapic::route_sci, time::uptime_us};
+
+use super::{power::read_register, FioxaAcpiHandler};
+
+const PM1_PWRBTN: u16 = 1 << 8;
+const PM1_SLPBTN: u16 = 1 << 9;
+const PM1_SCI_EN: u64 = 1;
+
+/// How long firmware gets to hand the hardware over
+const ACPI_ENABLE_TIMEOUT_US: u64 = 3_000_000;
+
+static EVENTS: OnceCell<EventRegisters> = OnceCell::uninit();
+static PENDING: AtomicU32 = AtomicU32::new(0);
+
+struct EventRegisters {
+    /// The status and enable ports of pm1a and pm1b
+    blocks: [Option<(u16, u16)>; 2],
+    /// The status bits that were enabled
+    enabled: u16,
+}
+
+/// Takes the hardware from firmware, turns on the button events and routes the SCI. Has to
+/// run before pci devices are routed so they can find out if they share its line.
+pub fn init(tables: &AcpiTables<FioxaAcpiHandler>) {
+    let fadt = match tables.find_table::<Fadt>() {
+        Ok(fadt) => fadt,
+        Err(e) => {
+            error!("No FADT: {e:?}");
+            return;
+        }
+    };
+    let (Ok(pm1a), Ok(pm1b), Ok(control)) = (
+        fadt.pm1a_event_block(),
+        fadt.pm1b_event_block(),
+        fadt.pm1a_control_block(),
+    ) else {
+        error!("FADT has bad pm1 blocks");
+        return;
+    };
+    let Some(pm1a) = io_block(&pm1a) else {
+        error!("Pm1a event block isn't in io space");
+        return;
+    };
+    let pm1b = pm1b.as_ref().and_then(io_block);
+
+    // Firmware owns the events until it's asked to give them up
+    if unsafe { read_register(&control) } & PM1_SCI_EN == 0 {
+        let (smi_cmd, acpi_enable) = (fadt.smi_cmd_port, fadt.acpi_enable);
+        if smi_cmd == 0 || acpi_enable == 0 {
+            error!("Acpi mode is off and can't be turned on");
+            return;
+        }
+        unsafe { Port::<u8>::new(smi_cmd as u16).write(acpi_enable) };
+        let end = uptime_us() + ACPI_ENABLE_TIMEOUT_US;
+        while unsafe { read_register(&control) } & PM1_SCI_EN == 0 {
+            if uptime_us() > end {
+                error!("Firmware didn't turn on acpi mode");
+                return;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // Buttons that are control methods come through general purpose events instead
+    let flags = fadt.flags;
+    let mut enabled = 0;
+    if !flags.power_button_is_control_method() {
+        enabled |= PM1_PWRBTN;
+    }
+    if !flags.sleep_button_is_control_method() {
+        enabled |= PM1_SLPBTN;
+    }
+
+    // General purpose events aren't handled, and one left on could hold the line
+    if let Ok(Some(gpe0)) = fadt.gpe0_block() {
+        disable_gpes(&gpe0);
+    }
+
+    let blocks = [Some(pm1a), pm1b];
+    for (status, enable) in blocks.iter().flatten() {
+        unsafe {
+            Port::<u16>::new(*status).write(u16::MAX);
+            Port::<u16>::new(*enable).write(enabled);
+        }
+    }
+
+    EVENTS.init_once(|| EventRegisters { blocks, enabled });
+    let sci = fadt.sci_interrupt;
+    route_sci(sci as u8);
+    info!("Acpi events on irq {sci}");
+}
+
+/// The status port and the enable port after it, the block being split in half between them
+fn io_block(block: &GenericAddress) -> Option<(u16, u16)> {
+    if block.address_space != AddressSpace::SystemIo || block.address == 0 {
+        return None;
+    }
+    let status = block.address as u16;
+    Some((status, status + block.bit_width as u16 / 16))
+}
+
+fn disable_gpes(block: &GenericAddress) {
+    if block.address_space != AddressSpace::SystemIo || block.address == 0 {
+        return;
+    }
+    let half = block.bit_width as u16 / 16;
+    for i in 0..half {
+        unsafe {
+            Port::<u8>::new(block.address as u16 + half + i).write(0);
+            Port::<u8>::new(block.address as u16 + i).write(u8::MAX);
+        }
+    }
+}
+
+/// Clears the events that caused the SCI, returns if there were any. Runs in the interrupt
+/// handler.
+pub fn handle_sci() -> bool {
+    let Some(regs) = EVENTS.get() else {
+        return false;
+    };
+
+    let mut status = 0;
+    for (port, _) in regs.blocks.iter().flatten() {
+        let mut port = Port::<u16>::new(*port);
+        let set = unsafe { port.read() } & regs.enabled;
+        if set != 0 {
+            // Status bits are cleared by writing ones
+            unsafe { port.write(set) };
+            status |= set;
+        }
+    }
+
+    let mut events = FixedEvents::empty();
+    events.set(FixedEvents::POWER_BUTTON, status & PM1_PWRBTN != 0);
+    events.set(FixedEvents::SLEEP_BUTTON, status & PM1_SLPBTN != 0);
+    PENDING.fetch_or(events.bits(), Ordering::Relaxed);
+    !events.is_empty()
+}
+
+/// The events since it was last called
+pub fn take_events() -> FixedEvents {
+    FixedEvents::from_bits_truncate(PENDING.swap(0, Ordering::Relaxed))
+}
@@ -1,3 +1,8 @@
+pub mod aml;
+pub mod ec;
+pub mod events;
+pub mod power;
+
 use core::ptr::NonNull;
 
 use acpi::{AcpiHandler, PhysicalMapping};
@@ -0,0 +1,228 @@
+//! Turning the machine off and restarting it. Off is the S5 sleep state, entered by writing the
+//! sleep type from the `\_S5_` object to the pm1 control registers. Restarting uses the FADT's
+//! reset register when it has one and the keyboard controller when it doesn't or that did
+//! nothing.
+
+use ::aml::{value::Args, AmlError, AmlName, AmlValue};
+use acpi::{
+    address::{AddressSpace, GenericAddress},
+    fadt::Fadt,
+    AcpiTables,
+};
+use conquer_once::spin::OnceCell;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    paging::{
+        ensure_ident_map_curr_process,
+        page::{Page, Size4KB},
+        MemoryMappingFlags,
+    },
+    pci::config_space,
+    time::uptime_us,
+};
+
+use super::{aml::with_context, FioxaAcpiHandler};
+
+const SLP_TYP_SHIFT: u64 = 10;
+const SLP_TYP_MASK: u64 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u64 = 1 << 13;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+const KBC_PULSE_RESET: u8 = 0xFE;
+
+/// How long the machine gets to go off or restart before the next way is tried
+const GRACE_US: u64 = 500_000;
+
+static POWER: OnceCell<PowerRegisters> = OnceCell::uninit();
+
+struct PowerRegisters {
+    pm1a_control: GenericAddress,
+    pm1b_control: Option<GenericAddress>,
+    /// The register and the value to write to it
+    reset: Option<(GenericAddress, u8)>,
+}
+
+/// Finds the registers in the FADT
+pub fn init(tables: &AcpiTables<FioxaAcpiHandler>) {
+    let fadt = match tables.find_table::<Fadt>() {
+        Ok(fadt) => fadt,
+        Err(e) => {
+            error!("No FADT: {e:?}");
+            return;
+        }
+    };
+    let (Ok(pm1a_control), Ok(pm1b_control)) =
+        (fadt.pm1a_control_block(), fadt.pm1b_control_block())
+    else {
+        error!("FADT has bad pm1 control blocks");
+        return;
+    };
+
+    let flags = fadt.flags;
+    let reset = flags
+        .supports_system_reset_via_fadt()
+        .then(|| fadt.reset_register().ok())
+        .flatten()
+        .map(|reg| (reg, fadt.reset_value));
+
+    POWER.init_once(|| PowerRegisters {
+        pm1a_control,
+        pm1b_control,
+        reset,
+    });
+}
+
+/// Turns the machine off, only returns if it couldn't. Everything has to have been written
+/// back by now.
+pub fn shutdown() {
+    let Some(power) = POWER.get() else {
+        error!("Can't shutdown without the FADT");
+        return;
+    };
+    let Some((typ_a, typ_b)) = s5_sleep_type() else {
+        error!("Can't shutdown without \\_S5_");
+        return;
+    };
+
+    // Lets the firmware get ready, like saving state to the embedded controller
+    let _ = with_context(|context| {
+        let pts = Args::from_list(vec![AmlValue::Integer(5)]).unwrap();
+        match context.invoke_method(&AmlName::from_str("\\_PTS").unwrap(), pts) {
+            Ok(_) | Err(AmlError::ValueDoesNotExist(_)) => (),
+            Err(e) => error!("\\_PTS failed: {e:?}"),
+        }
+    });
+
+    info!("Entering S5");
+    without_interrupts(|| unsafe {
+        enter_sleep(&power.pm1a_control, typ_a);
+        if let Some(pm1b) = &power.pm1b_control {
+            enter_sleep(pm1b, typ_b);
+        }
+    });
+
+    spin_us(GRACE_US);
+    error!("Still running after entering S5");
+}
+
+/// Restarts the machine, only returns if it couldn't
+pub fn reboot() {
+    if let Some((reg, value)) = POWER.get().and_then(|p| p.reset.as_ref()) {
+        info!("Resetting through the reset register");
+        unsafe { write_register(reg, *value as u64) };
+        spin_us(GRACE_US);
+    }
+
+    info!("Resetting through the keyboard controller");
+    unsafe {
+        let mut status = Port::<u8>::new(KBC_STATUS);
+        let end = uptime_us() + GRACE_US;
+        while status.read() & KBC_INPUT_FULL != 0 && uptime_us() < end {
+            core::hint::spin_loop();
+        }
+        status.write(KBC_PULSE_RESET);
+    }
+
+    spin_us(GRACE_US);
+    error!("Still running after reset");
+}
+
+/// The values for SLP_TYPa and SLP_TYPb
+fn s5_sleep_type() -> Option<(u64, u64)> {
+    with_context(|context| {
+        let s5 = context
+            .invoke_method(&AmlName::from_str("\\_S5_").unwrap(), Args::EMPTY)
+            .ok()?;
+        let AmlValue::Package(values) = s5 else {
+            return None;
+        };
+        let a = values.first()?.as_integer(context).ok()?;
+        // Some firmware packs both into the first byte
+        let b = match values.get(1) {
+            Some(b) => b.as_integer(context).ok()?,
+            None => a >> 8,
+        };
+        Some((a & 0b111, b & 0b111))
+    })
+    .flatten()
+}
+
+unsafe fn enter_sleep(control: &GenericAddress, typ: u64) {
+    let value = read_register(control) & !SLP_TYP_MASK;
+    write_register(control, value | typ << SLP_TYP_SHIFT);
+    write_register(control, value | typ << SLP_TYP_SHIFT | SLP_EN);
+}
+
+fn spin_us(us: u64) {
+    let end = uptime_us() + us;
+    while uptime_us() < end {
+        core::hint::spin_loop();
+    }
+}
+
+pub(super) unsafe fn read_register(reg: &GenericAddress) -> u64 {
+    match reg.address_space {
+        AddressSpace::SystemIo => {
+            let port = reg.address as u16;
+            match reg.bit_width {
+                8 => Port::<u8>::new(port).read() as u64,
+                32 => Port::<u32>::new(port).read() as u64,
+                _ => Port::<u16>::new(port).read() as u64,
+            }
+        }
+        AddressSpace::SystemMemory => {
+            let address = map(reg.address);
+            match reg.bit_width {
+                8 => (address as *const u8).read_volatile() as u64,
+                32 => (address as *const u32).read_volatile() as u64,
+                64 => (address as *const u64).read_volatile(),
+                _ => (address as *const u16).read_volatile() as u64,
+            }
+        }
+        space => {
+            warn!("Can't read a register in {space:?}");
+            0
+        }
+    }
+}
+
+unsafe fn write_register(reg: &GenericAddress, value: u64) {
+    match reg.address_space {
+        AddressSpace::SystemIo => {
+            let port = reg.address as u16;
+            match reg.bit_width {
+                8 => Port::<u8>::new(port).write(value as u8),
+                32 => Port::<u32>::new(port).write(value as u32),
+                _ => Port::<u16>::new(port).write(value as u16),
+            }
+        }
+        AddressSpace::SystemMemory => {
+            let address = map(reg.address);
+            match reg.bit_width {
+                8 => (address as *mut u8).write_volatile(value as u8),
+                32 => (address as *mut u32).write_volatile(value as u32),
+                64 => (address as *mut u64).write_volatile(value),
+                _ => (address as *mut u16).write_volatile(value as u16),
+            }
+        }
+        // The device and function of bus 0 are in the top half, the register in the bottom
+        AddressSpace::PciConfigSpace => {
+            let device = (reg.address >> 32) as u8;
+            let function = (reg.address >> 16) as u8;
+            if let Some(mut dev) = config_space(0, 0, device, function) {
+                dev.write_u8(reg.address as u16 as u32, value as u8);
+            }
+        }
+        space => warn!("Can't write a register in {space:?}"),
+    }
+}
+
+unsafe fn map(address: u64) -> u64 {
+    ensure_ident_map_curr_process(
+        Page::<Size4KB>::containing(address),
+        MemoryMappingFlags::WRITEABLE,
+    );
+    address
+}
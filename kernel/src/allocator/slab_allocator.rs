@@ -1,18 +1,53 @@
-use core::alloc::{GlobalAlloc, Layout};
+//! Kernel heap allocator.
+//!
+//! Small allocations are carved out of pages split into equally sized blocks (slabs), with a per
+//! core magazine in front of the shared depot of free blocks. Every slab page has a count of its
+//! blocks that are free in the depot, stored in a table at the top of the heap, so that pages that
+//! are completely free can be unmapped and given back when memory is short.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use kernel_userspace::memory::MemoryPressure;
+use x86_64::align_up;
 
 use crate::{
+    cpu_localstorage::{is_ls_enabled, CPULocalStorageRW},
     locked_mutex::Locked,
     paging::{
-        page::{Page, Size4KB},
-        page_allocator::global_allocator,
-        page_table::Mapper,
-        MemoryMappingFlags, PageAllocator, KERNEL_HEAP_MAP,
+        page::{Page, PageSize, Size2MB, Size4KB},
+        page_allocator::{frame_alloc_exec, global_allocator},
+        page_table::{Mapper, UnMapMemoryError},
+        pressure::Shrinker,
+        tlb::TlbShootdown,
+        MemoryLoc, MemoryMappingFlags, PageAllocator, KERNEL_HEAP_MAP,
     },
     scheduling::with_held_interrupts,
 };
 
 const SLAB_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// Free block counts of every heap page, the last 4gb of the heap is kept for it
+const SLAB_META_BASE: u64 = MemoryLoc::KernelHeap as u64 + 0x7F_0000_0000;
+
+/// Marks a page that is being released
+const RELEASING: u16 = u16::MAX;
+
+/// Most pages released while holding the depot lock
+const RELEASE_BATCH: usize = 32;
+
+/// Most frames of a large allocation that are kept until the tlb has been flushed of them
+const LARGE_FREE_BATCH: usize = 32;
+
+/// Number of blocks of this page that are free in the depot
+unsafe fn page_free(block: u64) -> &'static mut u16 {
+    let index = (block - MemoryLoc::KernelHeap as u64) / 0x1000;
+    &mut *((SLAB_META_BASE + index * 2) as *mut u16)
+}
+
 fn block_size(min_size: usize) -> Option<usize> {
     // Find smallest block
     SLAB_SIZES.iter().position(|&size| size >= min_size)
@@ -22,6 +57,86 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+/// Number of blocks each core can cache per slab size before giving some back
+const MAGAZINE_SIZE: usize = 32;
+
+/// A per core cache of free blocks, only touched by its own core while interrupts are held
+struct Magazine {
+    head: Option<&'static mut ListNode>,
+    len: usize,
+}
+
+impl Magazine {
+    fn pop(&mut self) -> Option<*mut u8> {
+        let node = self.head.take()?;
+        self.head = node.next.take();
+        self.len -= 1;
+        Some(node as *mut ListNode as *mut u8)
+    }
+
+    unsafe fn push(&mut self, ptr: *mut u8) {
+        let node = ptr as *mut ListNode;
+        node.write(ListNode {
+            next: self.head.take(),
+        });
+        self.head = Some(&mut *node);
+        self.len += 1;
+    }
+}
+
+struct CpuMagazines(UnsafeCell<[[Magazine; SLAB_SIZES.len()]; 256]>);
+
+unsafe impl Sync for CpuMagazines {}
+
+static MAGAZINES: CpuMagazines = CpuMagazines(UnsafeCell::new(
+    [const { [const { Magazine { head: None, len: 0 } }; SLAB_SIZES.len()] }; 256],
+));
+
+/// Returns the current cores magazine, interrupts must be held while it is used
+unsafe fn cpu_magazine(index: usize) -> Option<&'static mut Magazine> {
+    if !is_ls_enabled() {
+        return None;
+    }
+    let core = CPULocalStorageRW::get_core_id() as usize;
+    Some(&mut (*MAGAZINES.0.get())[core][index])
+}
+
+struct SlabCounters {
+    pages: AtomicUsize,
+    in_use: AtomicUsize,
+}
+
+static SLAB_COUNTERS: [SlabCounters; SLAB_SIZES.len()] = [const {
+    SlabCounters {
+        pages: AtomicUsize::new(0),
+        in_use: AtomicUsize::new(0),
+    }
+}; SLAB_SIZES.len()];
+
+static LARGE_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub block_size: usize,
+    /// Pages that have been carved into blocks of this size
+    pub pages: usize,
+    /// Blocks that are allocated, the rest are free in the depot or a cores magazine
+    pub in_use: usize,
+}
+
+pub fn slab_stats() -> [SlabStats; SLAB_SIZES.len()] {
+    core::array::from_fn(|i| SlabStats {
+        block_size: SLAB_SIZES[i],
+        pages: SLAB_COUNTERS[i].pages.load(Ordering::Relaxed),
+        in_use: SLAB_COUNTERS[i].in_use.load(Ordering::Relaxed),
+    })
+}
+
+/// Pages used by allocations too large for a slab
+pub fn large_alloc_pages() -> usize {
+    LARGE_PAGES.load(Ordering::Relaxed)
+}
+
 pub struct SlabAllocator {
     slab_heads: [Option<&'static mut ListNode>; SLAB_SIZES.len()],
     base_address: u64,
@@ -38,103 +153,320 @@ impl SlabAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for Locked<SlabAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        with_held_interrupts(|| {
-            let mut allocator = self.lock();
+impl SlabAllocator {
+    /// Takes a block from the depot, carving a new page if it is empty
+    unsafe fn alloc_block(&mut self, index: usize) -> *mut u8 {
+        if let Some(node) = self.slab_heads[index].take() {
+            self.slab_heads[index] = node.next.take();
+            let ptr = node as *mut ListNode as *mut u8;
+            *page_free(ptr as u64) -= 1;
+            return ptr;
+        }
 
-            let min_size = layout.size().max(layout.align());
+        let alloc = global_allocator();
+        // No block exists in list => allocate new block
+        let block_size = SLAB_SIZES[index];
+        // Only works if all blocks are powers of 2
+        let frame = alloc.allocate_page().unwrap();
 
-            match block_size(min_size) {
-                Some(index) => match allocator.slab_heads[index].take() {
-                    Some(node) => {
-                        allocator.slab_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        let alloc = global_allocator();
-                        // No block exists in list => allocate new block
-                        let block_size = SLAB_SIZES[index];
-                        // Only works if all blocks are powers of 2
-                        let frame = alloc.allocate_page().unwrap();
-
-                        let base = allocator.base_address;
-                        allocator.base_address += 0x1000;
-
-                        KERNEL_HEAP_MAP
-                            .lock()
-                            .map(alloc, Page::new(base), frame, MemoryMappingFlags::WRITEABLE)
-                            .unwrap()
-                            .flush();
-
-                        let mut current_node = None;
-                        for block in (base..(base + 0x1000)).step_by(block_size) {
-                            let node = &mut *(block as *mut ListNode);
-                            node.next = current_node;
-                            current_node = Some(node);
+        let base = self.base_address;
+        self.base_address += 0x1000;
+        assert!(self.base_address <= SLAB_META_BASE, "kernel heap is full");
+
+        let mut m = KERNEL_HEAP_MAP.lock();
+        m.map(alloc, Page::new(base), frame, MemoryMappingFlags::WRITEABLE)
+            .unwrap()
+            .flush();
+
+        let meta = page_free(base);
+        let meta_page = Page::<Size4KB>::containing(meta as *mut u16 as u64);
+        if m.address_of(meta_page).is_none() {
+            let frame = alloc.allocate_page().unwrap();
+            m.map(alloc, meta_page, frame, MemoryMappingFlags::WRITEABLE)
+                .unwrap()
+                .flush();
+        }
+        drop(m);
+        // Every block but the one being returned
+        *meta = (0x1000 / block_size - 1) as u16;
+        SLAB_COUNTERS[index].pages.fetch_add(1, Ordering::Relaxed);
+
+        let mut current_node = None;
+        for block in (base..(base + 0x1000)).step_by(block_size) {
+            let node = &mut *(block as *mut ListNode);
+            node.next = current_node;
+            current_node = Some(node);
+        }
+        let nxt = current_node.take().unwrap();
+        self.slab_heads[index] = nxt.next.take();
+        nxt as *mut ListNode as *mut u8
+    }
+
+    /// Returns a block to the depot
+    unsafe fn free_block(&mut self, index: usize, ptr: *mut u8) {
+        let new_node = ListNode {
+            next: self.slab_heads[index].take(),
+        };
+
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(new_node);
+        self.slab_heads[index] = Some(&mut *new_node_ptr);
+        *page_free(ptr as u64) += 1;
+    }
+
+    /// Unmaps up to [`RELEASE_BATCH`] slab pages that have all of their blocks free in the depot,
+    /// returns how many were released
+    unsafe fn release_empty_pages(&mut self, max: usize) -> usize {
+        let max = max.min(RELEASE_BATCH);
+        // Can't allocate while holding the depot lock
+        let mut released = [0u64; RELEASE_BATCH];
+        let mut count = 0;
+
+        for (index, &block_size) in SLAB_SIZES.iter().enumerate() {
+            if count == max {
+                break;
+            }
+            let per_page = (0x1000 / block_size) as u16;
+            let released_before = count;
+
+            // Rebuild the free list without the blocks of the pages being released
+            let mut head = self.slab_heads[index].take();
+            let mut kept: Option<&'static mut ListNode> = None;
+            while let Some(node) = head {
+                head = node.next.take();
+                let addr = node as *mut ListNode as u64;
+                let free = page_free(addr);
+                if *free == per_page && count < max {
+                    *free = RELEASING;
+                    released[count] = addr & !0xFFF;
+                    count += 1;
+                }
+                if *free != RELEASING {
+                    node.next = kept;
+                    kept = Some(node);
+                }
+            }
+            self.slab_heads[index] = kept;
+
+            SLAB_COUNTERS[index]
+                .pages
+                .fetch_sub(count - released_before, Ordering::Relaxed);
+        }
+
+        let alloc = global_allocator();
+        let mut frames = [0u64; RELEASE_BATCH];
+        // The heap is mapped in every address space
+        let mut shootdown = TlbShootdown::new(None);
+        let mut m = KERNEL_HEAP_MAP.lock();
+        for (&page, frame) in released[..count].iter().zip(&mut frames) {
+            let page = Page::<Size4KB>::new(page);
+            *frame = m.address_of(page).unwrap().get_address();
+            m.unmap(alloc, page).unwrap().ignore();
+            shootdown.add(page.get_address());
+        }
+        drop(m);
+        // Other cores can reach the frames until they have flushed
+        drop(shootdown);
+        for &frame in &frames[..count] {
+            alloc.free_page(Page::new(frame));
+        }
+        count
+    }
+
+    unsafe fn alloc_large(&mut self, min_size: usize) -> *mut u8 {
+        // Round to next page
+        let length = ((min_size + 0xFFF) & !0xFFF) as u64;
+        LARGE_PAGES.fetch_add(length as usize / 0x1000, Ordering::Relaxed);
+
+        // Large allocations are aligned so that they can use 2mb pages
+        let base = if length >= Size2MB::PAGE_SIZE {
+            align_up(self.base_address, Size2MB::PAGE_SIZE)
+        } else {
+            self.base_address
+        };
+        self.base_address = base + length;
+        assert!(self.base_address <= SLAB_META_BASE, "kernel heap is full");
+
+        let alloc = global_allocator();
+        let mut page = base;
+        while page < base + length {
+            if page + Size2MB::PAGE_SIZE <= base + length {
+                if let Some(frame) = frame_alloc_exec(|a| a.allocate_page_2mb()) {
+                    KERNEL_HEAP_MAP
+                        .lock()
+                        .map(
+                            alloc,
+                            Page::<Size2MB>::new(page),
+                            frame,
+                            MemoryMappingFlags::WRITEABLE,
+                        )
+                        .unwrap()
+                        .flush();
+                    page += Size2MB::PAGE_SIZE;
+                    continue;
+                }
+            }
+
+            let frame = alloc.allocate_page().unwrap();
+
+            KERNEL_HEAP_MAP
+                .lock()
+                .map(alloc, Page::new(page), frame, MemoryMappingFlags::WRITEABLE)
+                .unwrap()
+                .flush();
+            page += 0x1000;
+        }
+        base as *mut u8
+    }
+
+    unsafe fn dealloc_large(&mut self, ptr: *mut u8, max_size: usize) {
+        let base = ptr as u64;
+        let length = ((max_size + 0xFFF) & !0xFFF) as u64;
+        LARGE_PAGES.fetch_sub(length as usize / 0x1000, Ordering::Relaxed);
+
+        let alloc = global_allocator();
+        // Can't allocate while holding the heap lock, so the frames are freed a batch at a time
+        // once no core can reach them. Each is the address and if it is a 2mb page.
+        let mut frames = [(0u64, false); LARGE_FREE_BATCH];
+        let mut count = 0;
+        let free_frames = |frames: &[(u64, bool)]| {
+            for &(frame, huge) in frames {
+                match huge {
+                    true => frame_alloc_exec(|a| a.free_page_2mb(Page::new(frame))),
+                    false => alloc.free_page(Page::new(frame)),
+                }
+            }
+        };
+        // The heap is mapped in every address space
+        let mut shootdown = TlbShootdown::new(None);
+
+        // We assume that we allocted cont pages
+        let mut page = base;
+        while page < base + length {
+            if count == LARGE_FREE_BATCH {
+                drop(core::mem::replace(&mut shootdown, TlbShootdown::new(None)));
+                free_frames(&frames);
+                count = 0;
+            }
+            let mut m = KERNEL_HEAP_MAP.lock();
+
+            if page % Size2MB::PAGE_SIZE == 0 && page + Size2MB::PAGE_SIZE <= base + length {
+                let huge = Page::<Size2MB>::new(page);
+                if let Some(phys_page) = m.address_of(huge) {
+                    match m.unmap(alloc, huge) {
+                        Ok(f) => {
+                            f.ignore();
+                            shootdown.add(page);
+                            frames[count] = (phys_page.get_address(), true);
+                            count += 1;
+                            page += Size2MB::PAGE_SIZE;
+                            continue;
                         }
-                        let nxt = current_node.take().unwrap();
-                        allocator.slab_heads[index] = nxt.next.take();
-                        // current_node as *mut ListNode as *mut u8
-                        nxt as *mut ListNode as *mut u8
+                        // Was mapped with 4kb pages
+                        Err(UnMapMemoryError::WrongPageSize(_)) => (),
+                        Err(e) => panic!("{e}"),
                     }
-                },
-                None => {
-                    // Round to next page
-                    let length = ((min_size + 0xFFF) & !0xFFF) as u64;
-
-                    let base = allocator.base_address;
-                    allocator.base_address += length;
-
-                    let alloc = global_allocator();
-                    for page in (base..(base + length)).step_by(0x1000) {
-                        let frame = alloc.allocate_page().unwrap();
-
-                        KERNEL_HEAP_MAP
-                            .lock()
-                            .map(alloc, Page::new(page), frame, MemoryMappingFlags::WRITEABLE)
-                            .unwrap()
-                            .flush();
+                }
+            }
+
+            let small = Page::<Size4KB>::new(page);
+            let phys_page = m.address_of(small).unwrap();
+            m.unmap(alloc, small).unwrap().ignore();
+            shootdown.add(page);
+            frames[count] = (phys_page.get_address(), false);
+            count += 1;
+            page += 0x1000;
+        }
+        drop(shootdown);
+        free_frames(&frames[..count]);
+    }
+}
+
+impl Locked<SlabAllocator> {
+    /// Gives up to `pages` completely free slab pages back to the page allocator. Only this cores
+    /// cached blocks are put back into the depot first, pages with blocks cached on other cores
+    /// stay.
+    pub fn release_empty_pages(&self, pages: usize) -> usize {
+        let mut freed = 0;
+        while freed < pages {
+            let released = with_held_interrupts(|| unsafe {
+                let mut allocator = self.lock();
+                for index in 0..SLAB_SIZES.len() {
+                    if let Some(magazine) = cpu_magazine(index) {
+                        while let Some(ptr) = magazine.pop() {
+                            allocator.free_block(index, ptr);
+                        }
                     }
-                    base as *mut u8
                 }
+                allocator.release_empty_pages(pages - freed)
+            });
+            if released == 0 {
+                break;
             }
+            freed += released;
+        }
+        freed
+    }
+}
+
+/// Releases empty slab pages when memory is short
+pub struct HeapShrinker;
+
+impl Shrinker for HeapShrinker {
+    fn name(&self) -> &'static str {
+        "kernel heap"
+    }
+
+    fn shrink(&self, pages: usize, _level: MemoryPressure) -> usize {
+        crate::allocator::ALLOCATOR.release_empty_pages(pages)
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        with_held_interrupts(|| {
+            let min_size = layout.size().max(layout.align());
+
+            let Some(index) = block_size(min_size) else {
+                return self.lock().alloc_large(min_size);
+            };
+            SLAB_COUNTERS[index].in_use.fetch_add(1, Ordering::Relaxed);
+
+            let Some(magazine) = cpu_magazine(index) else {
+                return self.lock().alloc_block(index);
+            };
+            if magazine.len == 0 {
+                // Refill half the magazine at once so the depot lock is taken less often
+                let mut allocator = self.lock();
+                for _ in 0..MAGAZINE_SIZE / 2 {
+                    magazine.push(allocator.alloc_block(index));
+                }
+            }
+            magazine.pop().unwrap()
         })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         with_held_interrupts(|| {
-            let mut allocator = self.lock();
-
             let max_size = layout.size().max(layout.align());
 
-            match block_size(max_size) {
-                Some(index) => {
-                    // Return block to correct list size
-                    let new_node = ListNode {
-                        next: allocator.slab_heads[index].take(),
-                    };
+            let Some(index) = block_size(max_size) else {
+                self.lock().dealloc_large(ptr, max_size);
+                return;
+            };
+            SLAB_COUNTERS[index].in_use.fetch_sub(1, Ordering::Relaxed);
 
-                    let new_node_ptr = ptr as *mut ListNode;
-                    new_node_ptr.write(new_node);
-                    allocator.slab_heads[index] = Some(&mut *new_node_ptr);
-                }
-                None => {
-                    let base = ptr as u64;
-                    let length = ((max_size + 0xFFF) & !0xFFF) as u64;
-
-                    let alloc = global_allocator();
-                    // We assume that we allocted cont pages
-                    for page in (base..(base + length)).step_by(0x1000) {
-                        let page = Page::<Size4KB>::new(page);
-
-                        let mut m = KERNEL_HEAP_MAP.lock();
-                        let phys_page = m.address_of(page).unwrap();
-                        m.unmap(alloc, page).unwrap().flush();
-                        alloc.free_page(phys_page);
-                    }
+            let Some(magazine) = cpu_magazine(index) else {
+                self.lock().free_block(index, ptr);
+                return;
+            };
+            if magazine.len == MAGAZINE_SIZE {
+                let mut allocator = self.lock();
+                for _ in 0..MAGAZINE_SIZE / 2 {
+                    allocator.free_block(index, magazine.pop().unwrap());
                 }
             }
+            magazine.push(ptr);
         });
     }
 }
@@ -35,9 +35,10 @@ PROTECTED_MODE:
     mov eax, [pml4_ptr]
 	mov cr3, eax
 
+    ; Set EFER.LME and EFER.NXE
     mov ecx, 0xC0000080
     rdmsr
-    or eax, 1 << 8
+    or eax, 1 << 8 | 1 << 11
     wrmsr
 
     mov eax, cr0
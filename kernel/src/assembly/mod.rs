@@ -11,3 +11,15 @@ pub unsafe fn wrmsr(register: u32, value: u64) {
         options(preserves_flags, nostack)
     );
 }
+
+pub unsafe fn rdmsr(register: u32) -> u64 {
+    let (high, low): (u32, u32);
+    core::arch::asm!(
+        "rdmsr",
+        in("ecx") register,
+        out("edx") high,
+        out("eax") low,
+        options(preserves_flags, nostack)
+    );
+    (high as u64) << 32 | low as u64
+}
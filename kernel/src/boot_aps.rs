@@ -6,6 +6,7 @@ use core::{
 
 use crate::{
     assembly::AP_TRAMPOLINE,
+    cpu,
     cpu_localstorage::{new_cpu, CPULocalStorageRW},
     gdt::CPULocalGDT,
     interrupts::IDT,
@@ -16,7 +17,7 @@ use crate::{
         page_allocator::{frame_alloc_exec, global_allocator},
         page_mapper::PageMapping,
         page_table::Mapper,
-        MemoryLoc, MemoryMappingFlags, KERNEL_LVL4,
+        pat, MemoryLoc, MemoryMappingFlags, KERNEL_LVL4,
     },
     scheduling::taskmanager::core_start_multitasking,
     time::spin_sleep_ms,
@@ -44,11 +45,12 @@ pub unsafe fn boot_aps(madt: &Madt) {
             PageMapping::new_mmap(0x8000, 0x1000),
             MemoryMappingFlags::WRITEABLE,
         );
+        // The trampoline runs from and writes to the same page so it's the exception to W^X
         kernel_mem
             .identity_map(
                 global_allocator(),
                 Page::<Size4KB>::new(0x8000),
-                MemoryMappingFlags::WRITEABLE,
+                MemoryMappingFlags::WRITEABLE | MemoryMappingFlags::EXECUTABLE,
             )
             .unwrap()
             .ignore();
@@ -157,6 +159,11 @@ pub extern "C" fn ap_startup_f(core_id: u32) {
         // Load IDT
         IDT.lock().load_unsafe();
 
+        cpu::init_core(core_id as u8);
+
+        // Every core needs the same PAT
+        pat::init_core();
+
         // Enable lapic
         enable_localapic();
     }
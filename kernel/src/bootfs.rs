@@ -1,5 +1,29 @@
 pub const DEFAULT_FONT: &[u8] = include_bytes!("../../builder/assets/zap-light16.psf");
+pub const NET_CONFIG: &[u8] = include_bytes!("../../builder/assets/net.conf");
 
 pub const TERMINAL_ELF: &[u8] = include_bytes!("../../builder/fioxa/terminal.elf");
 pub const AMD_PCNET_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/amd_pcnet.driver");
+pub const E1000_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/e1000.driver");
+pub const RTL8139_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/rtl8139.driver");
+pub const HDA_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/hda.driver");
+pub const BOCHS_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/bochs.driver");
+pub const VIRTIO_GPU_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/virtio_gpu.driver");
 pub const PS2_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/ps2.driver");
+pub const POWER_MANAGER: &[u8] = include_bytes!("../../builder/fioxa/power.elf");
+pub const SERIAL_DRIVER: &[u8] = include_bytes!("../../builder/fioxa/serial.driver");
+
+/// Everything built into the kernel by the name it has in `/boot`
+pub const FILES: [(&str, &[u8]); 12] = [
+    ("font.psf", DEFAULT_FONT),
+    ("net.conf", NET_CONFIG),
+    ("terminal.elf", TERMINAL_ELF),
+    ("amd_pcnet.driver", AMD_PCNET_DRIVER),
+    ("e1000.driver", E1000_DRIVER),
+    ("rtl8139.driver", RTL8139_DRIVER),
+    ("hda.driver", HDA_DRIVER),
+    ("bochs.driver", BOCHS_DRIVER),
+    ("virtio_gpu.driver", VIRTIO_GPU_DRIVER),
+    ("ps2.driver", PS2_DRIVER),
+    ("power.elf", POWER_MANAGER),
+    ("serial.driver", SERIAL_DRIVER),
+];
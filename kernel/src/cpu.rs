@@ -0,0 +1,133 @@
+//! What the cpu can do, read from cpuid once per core as it boots. Anything the kernel only
+//! turns on when the cpu has it checks [`features`] rather than assuming QEMU's `qemu64`.
+
+use core::{
+    arch::x86_64::{__cpuid, __cpuid_count},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use bitflags::bitflags;
+
+pub mod monitor;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuFeatures: u64 {
+        const SSE = 1 << 0;
+        const SSE2 = 1 << 1;
+        const SSE3 = 1 << 2;
+        const SSSE3 = 1 << 3;
+        const SSE4_1 = 1 << 4;
+        const SSE4_2 = 1 << 5;
+        const AVX = 1 << 6;
+        const AVX2 = 1 << 7;
+        const AVX512F = 1 << 8;
+        const XSAVE = 1 << 9;
+        const FSGSBASE = 1 << 10;
+        const SMEP = 1 << 11;
+        const SMAP = 1 << 12;
+        /// The tsc counts at a fixed rate through frequency and sleep state changes
+        const INVARIANT_TSC = 1 << 13;
+        const TSC_DEADLINE = 1 << 14;
+        const RDTSCP = 1 << 15;
+        const RDRAND = 1 << 16;
+        const RDSEED = 1 << 17;
+        const PAT = 1 << 18;
+        const MTRR = 1 << 19;
+        const X2APIC = 1 << 20;
+        const PCID = 1 << 21;
+        const INVPCID = 1 << 22;
+        const NX = 1 << 23;
+        const PAGE_1GB = 1 << 24;
+        /// The APERF and MPERF msrs
+        const APERFMPERF = 1 << 25;
+        /// The digital thermal sensor in IA32_THERM_STATUS
+        const DTS = 1 << 26;
+    }
+}
+
+impl CpuFeatures {
+    /// Asks the current core
+    pub fn detect() -> Self {
+        let mut f = Self::empty();
+        let mut set = |flag, reg: u32, bit: u32| f.set(flag, reg & (1 << bit) != 0);
+
+        let max_basic = unsafe { __cpuid(0) }.eax;
+        let leaf1 = unsafe { __cpuid(1) };
+        set(Self::MTRR, leaf1.edx, 12);
+        set(Self::PAT, leaf1.edx, 16);
+        set(Self::SSE, leaf1.edx, 25);
+        set(Self::SSE2, leaf1.edx, 26);
+        set(Self::SSE3, leaf1.ecx, 0);
+        set(Self::SSSE3, leaf1.ecx, 9);
+        set(Self::PCID, leaf1.ecx, 17);
+        set(Self::SSE4_1, leaf1.ecx, 19);
+        set(Self::SSE4_2, leaf1.ecx, 20);
+        set(Self::X2APIC, leaf1.ecx, 21);
+        set(Self::TSC_DEADLINE, leaf1.ecx, 24);
+        set(Self::XSAVE, leaf1.ecx, 26);
+        set(Self::AVX, leaf1.ecx, 28);
+        set(Self::RDRAND, leaf1.ecx, 30);
+
+        if max_basic >= 6 {
+            let leaf6 = unsafe { __cpuid(6) };
+            set(Self::DTS, leaf6.eax, 0);
+            set(Self::APERFMPERF, leaf6.ecx, 0);
+        }
+
+        if max_basic >= 7 {
+            let leaf7 = unsafe { __cpuid_count(7, 0) };
+            set(Self::FSGSBASE, leaf7.ebx, 0);
+            set(Self::AVX2, leaf7.ebx, 5);
+            set(Self::SMEP, leaf7.ebx, 7);
+            set(Self::INVPCID, leaf7.ebx, 10);
+            set(Self::AVX512F, leaf7.ebx, 16);
+            set(Self::RDSEED, leaf7.ebx, 18);
+            set(Self::SMAP, leaf7.ebx, 20);
+        }
+
+        let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+        if max_extended >= 0x8000_0001 {
+            let ext1 = unsafe { __cpuid(0x8000_0001) };
+            set(Self::NX, ext1.edx, 20);
+            set(Self::PAGE_1GB, ext1.edx, 26);
+            set(Self::RDTSCP, ext1.edx, 27);
+        }
+        if max_extended >= 0x8000_0007 {
+            set(Self::INVARIANT_TSC, unsafe { __cpuid(0x8000_0007) }.edx, 8);
+        }
+        f
+    }
+}
+
+/// What each core has, empty for cores that haven't booted
+static CORE_FEATURES: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+/// What every core that has booted has
+static COMMON: AtomicU64 = AtomicU64::new(0);
+static DETECTED: AtomicBool = AtomicBool::new(false);
+
+/// Reads the current core's features, has to be called on every core before anything that
+/// checks them
+pub fn init_core(core_id: u8) {
+    let features = CpuFeatures::detect();
+    CORE_FEATURES[core_id as usize].store(features.bits(), Ordering::Relaxed);
+    if !DETECTED.swap(true, Ordering::AcqRel) {
+        COMMON.store(features.bits(), Ordering::Release);
+        return;
+    }
+    let common = CpuFeatures::from_bits_retain(COMMON.fetch_and(features.bits(), Ordering::AcqRel));
+    let missing = common - features;
+    if !missing.is_empty() {
+        warn!("Core {core_id} is missing {missing:?} that the cores before it have");
+    }
+}
+
+/// What every core can do, anything a core was missing when it booted is taken out
+pub fn features() -> CpuFeatures {
+    CpuFeatures::from_bits_retain(COMMON.load(Ordering::Acquire))
+}
+
+/// What the core could do when it booted
+pub fn core_features(core_id: u8) -> CpuFeatures {
+    CpuFeatures::from_bits_retain(CORE_FEATURES[core_id as usize].load(Ordering::Relaxed))
+}
@@ -0,0 +1,193 @@
+//! How fast and how hot each core is, sampled by every core from its own tick about once a second.
+//!
+//! APERF counts at the frequency the core actually runs at and MPERF at the rate the tsc does, both
+//! only while the core isn't halted. So the tsc's rate scaled by how much more APERF counted is how
+//! fast the core ran while it was busy. The temperature comes from the digital thermal sensor,
+//! which says how far below the hottest the core is allowed to get it is. Only Intel has it.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    assembly::{rdmsr, wrmsr},
+    cpu_localstorage::CPULocalStorageRW,
+    mutex::Spinlock,
+    time::uptime_ns,
+};
+
+use super::{core_features, CpuFeatures};
+
+const IA32_MPERF: u32 = 0xE7;
+const IA32_APERF: u32 = 0xE8;
+const IA32_THERM_STATUS: u32 = 0x19C;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+
+/// The core is being throttled right now
+const THERM_THROTTLED: u64 = 1 << 0;
+/// Set when the core was throttled since it was last cleared
+const THERM_THROTTLED_LOG: u64 = 1 << 1;
+/// Set when the core got hot enough that it can't work reliably since it was last cleared
+const THERM_CRITICAL_LOG: u64 = 1 << 5;
+const THERM_READING_VALID: u64 = 1 << 31;
+
+/// What TjMax is when the cpu doesn't say
+const DEFAULT_TJMAX: u32 = 100;
+
+const SAMPLE_NS: u64 = 1_000_000_000;
+
+const UNKNOWN: u32 = u32::MAX;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    time_ns: u64,
+    tsc: u64,
+    aperf: u64,
+    mperf: u64,
+}
+
+struct CoreMonitor {
+    /// Only ever locked by its own core
+    last: Spinlock<Option<Sample>>,
+    tjmax: AtomicU32,
+    freq_khz: AtomicU32,
+    temperature: AtomicU32,
+}
+
+impl CoreMonitor {
+    const fn new() -> Self {
+        Self {
+            last: Spinlock::new(None),
+            tjmax: AtomicU32::new(UNKNOWN),
+            freq_khz: AtomicU32::new(UNKNOWN),
+            temperature: AtomicU32::new(UNKNOWN),
+        }
+    }
+}
+
+/// Indexed by core id
+static MONITORS: [CoreMonitor; 256] = [const { CoreMonitor::new() }; 256];
+
+/// The temperature the sensor counts down from. Only Nehalem and later have the msr saying what
+/// it is, and reading it on anything else faults.
+fn read_tjmax() -> u32 {
+    let leaf1 = unsafe { __cpuid(1) }.eax;
+    let family = (leaf1 >> 8) & 0xF;
+    let model = ((leaf1 >> 4) & 0xF) | ((leaf1 >> 12) & 0xF0);
+    if family != 6 || model < 0x1A {
+        return DEFAULT_TJMAX;
+    }
+    match (unsafe { rdmsr(MSR_TEMPERATURE_TARGET) } >> 16) as u32 & 0xFF {
+        0 => DEFAULT_TJMAX,
+        t => t,
+    }
+}
+
+fn sample_frequency(monitor: &CoreMonitor, now_ns: u64) {
+    let next = unsafe {
+        Sample {
+            time_ns: now_ns,
+            tsc: _rdtsc(),
+            aperf: rdmsr(IA32_APERF),
+            mperf: rdmsr(IA32_MPERF),
+        }
+    };
+    let Some(prev) = monitor.last.lock().replace(next) else {
+        return;
+    };
+
+    let ns = next.time_ns.saturating_sub(prev.time_ns);
+    let mperf = next.mperf.wrapping_sub(prev.mperf);
+    // It never left halt, the last frequency is as good as any
+    if ns == 0 || mperf == 0 {
+        return;
+    }
+    let tsc = next.tsc.wrapping_sub(prev.tsc) as u128;
+    let aperf = next.aperf.wrapping_sub(prev.aperf) as u128;
+    let khz = tsc * 1_000_000 * aperf / (ns as u128 * mperf as u128);
+    monitor
+        .freq_khz
+        .store(khz.min(UNKNOWN as u128 - 1) as u32, Ordering::Relaxed);
+}
+
+fn sample_temperature(monitor: &CoreMonitor, core_id: u8) {
+    let mut tjmax = monitor.tjmax.load(Ordering::Relaxed);
+    if tjmax == UNKNOWN {
+        tjmax = read_tjmax();
+        monitor.tjmax.store(tjmax, Ordering::Relaxed);
+    }
+
+    let status = unsafe { rdmsr(IA32_THERM_STATUS) };
+    if status & THERM_READING_VALID == 0 {
+        return;
+    }
+    let temperature = tjmax.saturating_sub((status >> 16) as u32 & 0x7F);
+    monitor.temperature.store(temperature, Ordering::Relaxed);
+
+    if status & (THERM_THROTTLED_LOG | THERM_CRITICAL_LOG) == 0 {
+        return;
+    }
+    if status & THERM_CRITICAL_LOG != 0 {
+        error!("Core {core_id} reached its critical temperature, it is at {temperature}C");
+    } else if status & THERM_THROTTLED != 0 {
+        warn!("Core {core_id} is being thermally throttled at {temperature}C");
+    } else {
+        warn!("Core {core_id} was thermally throttled, it is now at {temperature}C");
+    }
+    // The logs are cleared by writing 0, the other bits ignore writes
+    unsafe {
+        wrmsr(
+            IA32_THERM_STATUS,
+            status & !(THERM_THROTTLED_LOG | THERM_CRITICAL_LOG),
+        )
+    };
+}
+
+/// Takes a sample on the current core if one is due, should be called from a periodic tick
+pub fn sample() {
+    let core_id = CPULocalStorageRW::get_core_id();
+    let features = core_features(core_id);
+    if !features.intersects(CpuFeatures::APERFMPERF | CpuFeatures::DTS) {
+        return;
+    }
+    let monitor = &MONITORS[core_id as usize];
+    let now_ns = uptime_ns();
+    if now_ns == 0
+        || monitor
+            .last
+            .lock()
+            .is_some_and(|l| now_ns.saturating_sub(l.time_ns) < SAMPLE_NS)
+    {
+        return;
+    }
+
+    if features.contains(CpuFeatures::APERFMPERF) {
+        sample_frequency(monitor, now_ns);
+    } else {
+        // Only the time is used to know when the next sample is due
+        *monitor.last.lock() = Some(Sample {
+            time_ns: now_ns,
+            tsc: 0,
+            aperf: 0,
+            mperf: 0,
+        });
+    }
+    if features.contains(CpuFeatures::DTS) {
+        sample_temperature(monitor, core_id);
+    }
+}
+
+/// How fast the core ran while it was busy over the last second, in kHz
+pub fn frequency_khz(core_id: u8) -> Option<u32> {
+    let khz = MONITORS[core_id as usize].freq_khz.load(Ordering::Relaxed);
+    (khz != UNKNOWN).then_some(khz)
+}
+
+/// The core's temperature in degrees celsius when it was last sampled
+pub fn temperature(core_id: u8) -> Option<u32> {
+    let temp = MONITORS[core_id as usize]
+        .temperature
+        .load(Ordering::Relaxed);
+    (temp != UNKNOWN).then_some(temp)
+}
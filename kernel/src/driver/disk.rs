@@ -1,15 +1,28 @@
 pub mod ahci;
+pub mod usb_msc;
+pub mod virtio_blk;
 
 use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use kernel_userspace::disk::ata::ATADiskIdentify;
 
-use crate::mutex::Spinlock;
+use crate::mutex::Mutex;
 
 use super::driver::Driver;
 
+/// A disk being plugged in or removed from a bus
+pub enum DiskEvent {
+    Added(Arc<Mutex<dyn DiskDevice>>),
+    Removed(Arc<Mutex<dyn DiskDevice>>),
+}
+
 pub trait DiskBusDriver: Driver {
-    fn get_disks(&mut self) -> Vec<Arc<Spinlock<dyn DiskDevice>>>;
-    fn get_disk_by_id(&mut self, id: usize) -> Option<Arc<Spinlock<dyn DiskDevice>>>;
+    fn get_disks(&mut self) -> Vec<Arc<Mutex<dyn DiskDevice>>>;
+    fn get_disk_by_id(&mut self, id: usize) -> Option<Arc<Mutex<dyn DiskDevice>>>;
+
+    /// Disks that came or went since the last call, checked whenever a PCI interrupt arrives
+    fn poll_events(&mut self) -> Vec<DiskEvent> {
+        Vec::new()
+    }
 }
 
 pub trait DiskDevice: Send + Sync {
@@ -8,8 +8,11 @@ use bit_field::BitField;
 use volatile::Volatile;
 
 use crate::{
-    driver::{disk::DiskDevice, driver::Driver},
-    mutex::Spinlock,
+    driver::{
+        disk::{DiskDevice, DiskEvent},
+        driver::Driver,
+    },
+    mutex::Mutex,
     paging::{
         get_task_mapper,
         page::{Page, Size4KB},
@@ -39,11 +42,19 @@ const HBA_PX_CMD_FRE: u32 = 0x0010;
 const HBA_PX_CMD_FR: u32 = 0x4000;
 const HBA_PX_CMD_CR: u32 = 0x8000;
 
+/// Global interrupt enable
+const HBA_GHC_IE: u32 = 1 << 1;
+/// Port connect change
+const HBA_PX_IS_PCS: u32 = 1 << 6;
+/// PhyRdy change, the link came up or went down
+const HBA_PX_IS_PRCS: u32 = 1 << 22;
+
 pub struct AHCIDriver {
     #[allow(dead_code)]
     pci_device: PCIHeader0,
-    // abar: HBAMemory,
-    ports: [Option<Arc<Spinlock<Port>>>; 32],
+    /// The ports also point into this so it can only be used for registers they don't touch
+    abar: *mut HBAMemory,
+    ports: [Option<Arc<Mutex<Port>>>; 32],
 }
 
 #[repr(C)]
@@ -138,17 +149,19 @@ impl Driver for AHCIDriver {
                 m.identity_map(
                     global_allocator(),
                     Page::<Size4KB>::new(abar as u64),
-                    MemoryMappingFlags::all(),
+                    MemoryMappingFlags::WRITEABLE,
                 )
             })
             .unwrap()
             .flush();
         }
 
-        let abar = unsafe { &mut *(header0.get_bar(5) as *mut HBAMemory) };
+        let abar_ptr = header0.get_bar(5) as *mut HBAMemory;
+        let abar = unsafe { &mut *abar_ptr };
 
         let mut ahci = Self {
             pci_device: header0,
+            abar: abar_ptr,
             ports: Default::default(),
         };
 
@@ -158,6 +171,11 @@ impl Driver for AHCIDriver {
 
         for (i, port) in (abar.ports).iter_mut().enumerate() {
             if ports_implemented.get_bit(i) {
+                // Only interrupt for disks coming and going, transfers are polled
+                port.sata_error.write(u32::MAX);
+                port.interrupt_status.write(u32::MAX);
+                port.interrupt_enable.write(HBA_PX_IS_PCS | HBA_PX_IS_PRCS);
+
                 let port_type = Self::check_port_type(port);
 
                 trace!("SATA: {:?}", port_type);
@@ -167,12 +185,15 @@ impl Driver for AHCIDriver {
 
                     // Test read
                     if port.read(0, 1, buffer).is_some() {
-                        ahci.ports[i] = Some(Arc::new(Spinlock::new(port)));
+                        ahci.ports[i] = Some(Arc::new(Mutex::new(port)));
                     }
                 }
             }
         }
 
+        abar.interrupt_status.write(u32::MAX);
+        abar.global_host_control.update(|g| *g |= HBA_GHC_IE);
+
         Some(ahci)
     }
 
@@ -186,19 +207,60 @@ impl Driver for AHCIDriver {
 }
 
 impl DiskBusDriver for AHCIDriver {
-    fn get_disks(&mut self) -> Vec<Arc<Spinlock<dyn DiskDevice>>> {
+    fn get_disks(&mut self) -> Vec<Arc<Mutex<dyn DiskDevice>>> {
         self.ports
             .clone()
             .into_iter()
             .flatten()
-            .map(|a| a as Arc<Spinlock<dyn DiskDevice>>)
+            .map(|a| a as Arc<Mutex<dyn DiskDevice>>)
             .collect()
     }
 
-    fn get_disk_by_id(&mut self, id: usize) -> Option<Arc<Spinlock<dyn DiskDevice>>> {
+    fn get_disk_by_id(&mut self, id: usize) -> Option<Arc<Mutex<dyn DiskDevice>>> {
         if let Some(Some(port)) = self.ports.get(id) {
             return Some(port.clone());
         }
         None
     }
+
+    fn poll_events(&mut self) -> Vec<DiskEvent> {
+        let mut events = Vec::new();
+        let pending = unsafe { (*self.abar).interrupt_status.read() };
+
+        for i in 0..32 {
+            if !pending.get_bit(i) {
+                continue;
+            }
+            let port = unsafe { &mut (*self.abar).ports[i] };
+            let status = port.interrupt_status.read();
+            // The change bits only clear once the matching errors are cleared
+            port.sata_error.write(u32::MAX);
+            port.interrupt_status.write(status);
+            if status & (HBA_PX_IS_PCS | HBA_PX_IS_PRCS) == 0 {
+                continue;
+            }
+
+            let present = Self::check_port_type(port) == PortType::SATA;
+            match &self.ports[i] {
+                None if present => {
+                    let mut port = Port::new(port);
+                    if port.read(0, 1, &mut [0u8; 512]).is_some() {
+                        info!("AHCI: disk plugged into port {i}");
+                        let port = Arc::new(Mutex::new(port));
+                        self.ports[i] = Some(port.clone());
+                        events.push(DiskEvent::Added(port));
+                    }
+                }
+                Some(_) if !present => {
+                    info!("AHCI: disk removed from port {i}");
+                    let port = self.ports[i].take().unwrap();
+                    events.push(DiskEvent::Removed(port));
+                }
+                _ => (),
+            }
+        }
+
+        unsafe { (*self.abar).interrupt_status.write(pending) };
+        events
+    }
 }
@@ -112,19 +112,13 @@ impl Port {
         port.cmd_sts.update(|v| *v |= HBA_PX_CMD_ST);
     }
 
-    pub fn stop_cmd(port: &mut HBAPort) {
-        // Stop port
-        port.cmd_sts.update(|x| *x &= !HBA_PX_CMD_ST);
-        // LIST_ON
-        while port.cmd_sts.read() | HBA_PX_CMD_CR == 1 {}
-
-        port.cmd_sts.update(|x| *x &= !HBA_PX_CMD_FRE);
-        while port.cmd_sts.read() | HBA_PX_CMD_FR == 1 {}
-    }
-}
-
-impl DiskDevice for Port {
-    fn read(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+    fn transfer(
+        &mut self,
+        sector: usize,
+        sector_count: u32,
+        buffer: &mut [u8],
+        write: bool,
+    ) -> Option<()> {
         // because of alignment we can't ensure a full transfer
         const MAX_SECTORS: usize = (PRDT_LENGTH - 1) * 8;
         if sector_count as usize > MAX_SECTORS {
@@ -143,7 +137,7 @@ impl DiskDevice for Port {
 
         let cmd_list = &mut self.cmd_list[slot];
         cmd_list.set_command_fis_length((size_of::<FisRegH2D>() / 4) as u8);
-        cmd_list.set_write(false); // This is read
+        cmd_list.set_write(write);
 
         let cmd_table = &mut self.cmd_tables[slot];
 
@@ -208,7 +202,12 @@ impl DiskDevice for Port {
         cmd_fis.set_control(1); // COMMAND
 
         const ATA_CMD_READ_DMA_EX: u8 = 0x25;
-        cmd_fis.set_command(ATA_CMD_READ_DMA_EX);
+        const ATA_CMD_WRITE_DMA_EX: u8 = 0x35;
+        cmd_fis.set_command(if write {
+            ATA_CMD_WRITE_DMA_EX
+        } else {
+            ATA_CMD_READ_DMA_EX
+        });
         cmd_fis.set_command_control(true);
 
         cmd_fis.set_lba0(sector_low as u8);
@@ -255,8 +254,24 @@ impl DiskDevice for Port {
         Some(())
     }
 
-    fn write(&mut self, _sector: usize, _sector_count: u32, _buffer: &mut [u8]) -> Option<()> {
-        todo!()
+    pub fn stop_cmd(port: &mut HBAPort) {
+        // Stop port
+        port.cmd_sts.update(|x| *x &= !HBA_PX_CMD_ST);
+        // LIST_ON
+        while port.cmd_sts.read() | HBA_PX_CMD_CR == 1 {}
+
+        port.cmd_sts.update(|x| *x &= !HBA_PX_CMD_FRE);
+        while port.cmd_sts.read() | HBA_PX_CMD_FR == 1 {}
+    }
+}
+
+impl DiskDevice for Port {
+    fn read(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        self.transfer(sector, sector_count, buffer, false)
+    }
+
+    fn write(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        self.transfer(sector, sector_count, buffer, true)
     }
 
     fn identify(&mut self) -> Box<ATADiskIdentify> {
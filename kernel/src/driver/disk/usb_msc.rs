@@ -0,0 +1,366 @@
+//! Usb mass storage devices such as usb sticks, using the bulk-only transport and scsi
+//! commands. Each command is a command block wrapper sent to the bulk out endpoint, then the
+//! data if there is any, then a command status wrapper read from the bulk in endpoint. Only
+//! the first logical unit is used and it has to have 512 byte blocks.
+//!
+//! Commands are issued one at a time and data goes straight to and from the caller's buffer,
+//! the host controller takes care of getting it on the bus.
+
+use core::{fmt::Display, mem::MaybeUninit};
+
+use alloc::{boxed::Box, sync::Arc};
+use kernel_userspace::{disk::ata::ATADiskIdentify, syscall::sleep};
+
+use crate::{
+    driver::usb::{
+        SetupPacket, UsbBulkInterface, UsbError, REQUEST_IN_CLASS_INTERFACE,
+        REQUEST_OUT_CLASS_INTERFACE,
+    },
+    fs::FSDRIVES,
+    mutex::Mutex,
+};
+
+use super::DiskDevice;
+
+#[cfg(debug_assertions)]
+pub mod test;
+
+pub const CLASS_MASS_STORAGE: u8 = 0x08;
+/// The scsi transparent command set
+pub const SUBCLASS_SCSI: u8 = 0x06;
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const REQUEST_RESET: u8 = 0xFF;
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+const CBW_FLAG_IN: u8 = 0x80;
+
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2A;
+
+const SENSE_NOT_READY: u8 = 0x2;
+const SENSE_UNIT_ATTENTION: u8 = 0x6;
+const INQUIRY_LEN: u8 = 36;
+const SENSE_LEN: u8 = 18;
+
+const SECTOR_SIZE: usize = 512;
+/// Most sectors moved by one command, plenty of sticks can't take more
+const MAX_SECTORS: usize = 128;
+/// How many times a unit that isn't ready yet is asked again, 100ms apart
+const READY_ATTEMPTS: u32 = 20;
+
+/// Whether an interface with the class codes is one we drive
+pub fn supports(class: u8, subclass: u8, protocol: u8) -> bool {
+    class == CLASS_MASS_STORAGE && subclass == SUBCLASS_SCSI && protocol == PROTOCOL_BULK_ONLY
+}
+
+/// Makes the interface a disk, for the host controller to call when it finds an interface we
+/// [`supports`]. The disk is to be given to [`detach`] once the device is unplugged.
+pub fn attach(iface: Box<dyn UsbBulkInterface>) -> Option<Arc<Mutex<dyn DiskDevice>>> {
+    let disk: Arc<Mutex<dyn DiskDevice>> = Arc::new(Mutex::new(UsbMassStorage::new(iface)?));
+    FSDRIVES.lock().attach(disk.clone());
+    Some(disk)
+}
+
+pub fn detach(disk: &Arc<Mutex<dyn DiskDevice>>) {
+    FSDRIVES.lock().detach(disk);
+}
+
+enum Data<'a> {
+    None,
+    In(&'a mut [u8]),
+    Out(&'a [u8]),
+}
+
+enum CommandError {
+    /// The device says the command failed, the sense data says why
+    Failed,
+    Usb(UsbError),
+    /// The device got out of step with us and has been reset
+    Phase,
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CommandError::Failed => f.write_str("command failed"),
+            CommandError::Usb(e) => write!(f, "{e:?}"),
+            CommandError::Phase => f.write_str("phase error"),
+        }
+    }
+}
+
+impl From<UsbError> for CommandError {
+    fn from(value: UsbError) -> Self {
+        CommandError::Usb(value)
+    }
+}
+
+pub struct UsbMassStorage {
+    iface: Box<dyn UsbBulkInterface>,
+    tag: u32,
+    blocks: u64,
+    /// The vendor and product from the inquiry, byte swapped like ata strings
+    model: [u8; 40],
+    revision: [u8; 8],
+}
+
+/// Copies an ascii string into an ata string, which has the bytes of each word swapped
+fn ata_string(dst: &mut [u8], src: &[u8]) {
+    for (i, b) in src.iter().enumerate().take(dst.len()) {
+        dst[i ^ 1] = *b;
+    }
+}
+
+impl UsbMassStorage {
+    pub fn new(mut iface: Box<dyn UsbBulkInterface>) -> Option<Self> {
+        // Devices with one unit are allowed to stall this
+        let mut max_lun = [0];
+        let setup = SetupPacket {
+            request_type: REQUEST_IN_CLASS_INTERFACE,
+            request: REQUEST_GET_MAX_LUN,
+            value: 0,
+            index: iface.number() as u16,
+        };
+        if let Ok(1) = iface.control(setup, &mut max_lun) {
+            if max_lun[0] > 0 {
+                info!(
+                    "USB mass storage has {} units, using the first",
+                    max_lun[0] + 1
+                );
+            }
+        }
+
+        let mut this = Self {
+            iface,
+            tag: 0,
+            blocks: 0,
+            model: [b' '; 40],
+            revision: [b' '; 8],
+        };
+
+        let mut inquiry = [0; INQUIRY_LEN as usize];
+        let cb = [SCSI_INQUIRY, 0, 0, 0, INQUIRY_LEN, 0];
+        if let Err(e) = this.command(&cb, Data::In(&mut inquiry)) {
+            warn!("USB mass storage inquiry failed: {e}");
+            return None;
+        }
+        // Direct access block devices only, not cd drives and the like
+        if inquiry[0] & 0x1F != 0 {
+            warn!(
+                "USB mass storage of type {:#X} isn't supported",
+                inquiry[0] & 0x1F
+            );
+            return None;
+        }
+        let mut name = [b' '; 25];
+        name[..8].copy_from_slice(&inquiry[8..16]);
+        name[9..].copy_from_slice(&inquiry[16..32]);
+        ata_string(&mut this.model, &name);
+        ata_string(&mut this.revision, &inquiry[32..36]);
+
+        this.wait_ready()?;
+
+        let mut capacity = [0; 8];
+        let cb = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        if let Err(e) = this.command(&cb, Data::In(&mut capacity)) {
+            warn!("USB mass storage read capacity failed: {e}");
+            return None;
+        }
+        let last = u32::from_be_bytes(capacity[..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(capacity[4..].try_into().unwrap());
+        if block_size as usize != SECTOR_SIZE {
+            warn!("USB mass storage with {block_size} byte blocks isn't supported");
+            return None;
+        }
+        // Anything bigger says u32::MAX and needs READ CAPACITY(16), so is used up to 2TiB
+        this.blocks = last as u64 + 1;
+        Some(this)
+    }
+
+    /// Waits for the unit to spin up or get over having just been plugged in
+    fn wait_ready(&mut self) -> Option<()> {
+        for _ in 0..READY_ATTEMPTS {
+            match self.command(&[SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0], Data::None) {
+                Ok(()) => return Some(()),
+                Err(CommandError::Failed) => match self.sense_key() {
+                    Some(SENSE_NOT_READY | SENSE_UNIT_ATTENTION) => {
+                        sleep(100);
+                    }
+                    key => {
+                        warn!("USB mass storage isn't ready, sense key {key:?}");
+                        return None;
+                    }
+                },
+                Err(e) => {
+                    warn!("USB mass storage isn't ready: {e}");
+                    return None;
+                }
+            }
+        }
+        warn!("USB mass storage never became ready");
+        None
+    }
+
+    /// Why the last command failed, which has to be asked straight after
+    fn sense_key(&mut self) -> Option<u8> {
+        let mut sense = [0; SENSE_LEN as usize];
+        let cb = [SCSI_REQUEST_SENSE, 0, 0, 0, SENSE_LEN, 0];
+        self.command(&cb, Data::In(&mut sense)).ok()?;
+        Some(sense[2] & 0xF)
+    }
+
+    /// Sends the command block and moves the data
+    fn command(&mut self, cb: &[u8], data: Data) -> Result<(), CommandError> {
+        self.tag = self.tag.wrapping_add(1);
+        let (len, flags) = match &data {
+            Data::None => (0, 0),
+            Data::In(d) => (d.len(), CBW_FLAG_IN),
+            Data::Out(d) => (d.len(), 0),
+        };
+        let mut cbw = [0; CBW_LEN];
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = flags;
+        // The unit is left at 0
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        if let Err(e) = self.iface.bulk_out(&cbw) {
+            self.reset_recovery();
+            return Err(e.into());
+        }
+
+        let (res, endpoint) = match data {
+            Data::None => (Ok(()), 0),
+            Data::In(d) => (
+                self.iface.bulk_in(d).map(|_| ()),
+                self.iface.bulk_in_endpoint(),
+            ),
+            Data::Out(d) => (self.iface.bulk_out(d), self.iface.bulk_out_endpoint()),
+        };
+        match res {
+            Ok(()) => (),
+            // The device stalls when it has less data than asked for, the status still follows
+            Err(UsbError::Stall) => self.iface.clear_halt(endpoint)?,
+            Err(e) => return Err(e.into()),
+        }
+
+        let csw = self.read_status()?;
+        let signature = u32::from_le_bytes(csw[0..4].try_into().unwrap());
+        let tag = u32::from_le_bytes(csw[4..8].try_into().unwrap());
+        match csw[12] {
+            _ if signature != CSW_SIGNATURE || tag != self.tag => {
+                self.reset_recovery();
+                Err(CommandError::Phase)
+            }
+            CSW_PASSED => Ok(()),
+            CSW_FAILED => Err(CommandError::Failed),
+            _ => {
+                self.reset_recovery();
+                Err(CommandError::Phase)
+            }
+        }
+    }
+
+    fn read_status(&mut self) -> Result<[u8; CSW_LEN], UsbError> {
+        let mut csw = [0; CSW_LEN];
+        // A stall on the status is cleared and the status read again
+        let len = match self.iface.bulk_in(&mut csw) {
+            Err(UsbError::Stall) => {
+                self.iface.clear_halt(self.iface.bulk_in_endpoint())?;
+                self.iface.bulk_in(&mut csw)?
+            }
+            res => res?,
+        };
+        if len != CSW_LEN {
+            // Not a status, which the signature check catches
+            csw = [0; CSW_LEN];
+        }
+        Ok(csw)
+    }
+
+    /// Gets the device back in step with us
+    fn reset_recovery(&mut self) {
+        let setup = SetupPacket {
+            request_type: REQUEST_OUT_CLASS_INTERFACE,
+            request: REQUEST_RESET,
+            value: 0,
+            index: self.iface.number() as u16,
+        };
+        let _ = self.iface.control(setup, &mut []);
+        let _ = self.iface.clear_halt(self.iface.bulk_in_endpoint());
+        let _ = self.iface.clear_halt(self.iface.bulk_out_endpoint());
+    }
+
+    fn transfer(
+        &mut self,
+        sector: usize,
+        sector_count: u32,
+        buffer: &mut [u8],
+        write: bool,
+    ) -> Option<()> {
+        let end = sector.checked_add(sector_count as usize)?;
+        if end as u64 > self.blocks {
+            return None;
+        }
+        assert!(
+            buffer.len() >= sector_count as usize * SECTOR_SIZE,
+            "Buffer is not large enough"
+        );
+
+        let buffer = &mut buffer[..sector_count as usize * SECTOR_SIZE];
+        for (i, chunk) in buffer.chunks_mut(MAX_SECTORS * SECTOR_SIZE).enumerate() {
+            let lba = ((sector + i * MAX_SECTORS) as u32).to_be_bytes();
+            let count = ((chunk.len() / SECTOR_SIZE) as u16).to_be_bytes();
+            let (op, data) = match write {
+                true => (SCSI_WRITE_10, Data::Out(chunk)),
+                false => (SCSI_READ_10, Data::In(chunk)),
+            };
+            let cb = [
+                op, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+            ];
+            if let Err(e) = self.command(&cb, data) {
+                warn!("USB mass storage transfer at sector {sector} failed: {e}");
+                return None;
+            }
+        }
+        Some(())
+    }
+}
+
+impl DiskDevice for UsbMassStorage {
+    fn read(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        self.transfer(sector, sector_count, buffer, false)
+    }
+
+    fn write(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        self.transfer(sector, sector_count, buffer, true)
+    }
+
+    fn identify(&mut self) -> Box<ATADiskIdentify> {
+        let mut identify: Box<MaybeUninit<ATADiskIdentify>> = Box::new_uninit();
+        let mut identify = unsafe {
+            identify.as_mut_ptr().write_bytes(0, 1);
+            identify.assume_init()
+        };
+        identify.model = self.model;
+        identify.firmware_revision = self.revision;
+        identify.lba_size48_1 = self.blocks as u16;
+        identify.lba_size48_2 = (self.blocks >> 16) as u16;
+        identify.lba_size48_3 = (self.blocks >> 32) as u16;
+        identify.lba_size48_4 = (self.blocks >> 48) as u16;
+        identify
+    }
+}
@@ -0,0 +1,219 @@
+//! A stick kept in memory to run the driver against, for debug builds
+
+use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+
+use crate::{
+    driver::{
+        disk::DiskDevice,
+        usb::{SetupPacket, UsbBulkInterface, UsbError, REQUEST_CLEAR_FEATURE},
+    },
+    mutex::Spinlock,
+};
+
+use super::{
+    UsbMassStorage, CBW_LEN, CBW_SIGNATURE, CSW_FAILED, CSW_LEN, CSW_PASSED, CSW_SIGNATURE,
+    INQUIRY_LEN, MAX_SECTORS, REQUEST_GET_MAX_LUN, REQUEST_RESET, SCSI_INQUIRY, SCSI_READ_10,
+    SCSI_READ_CAPACITY_10, SCSI_TEST_UNIT_READY, SCSI_WRITE_10, SECTOR_SIZE,
+};
+
+/// A stick kept in memory for [`self_test`], speaking the bulk-only transport well enough for
+/// the driver. Faults can be queued up to check that the driver recovers from them.
+struct TestStick {
+    sectors: Vec<u8>,
+    state: TestState,
+    tag: u32,
+    /// Set while the endpoint is halted, in and then out
+    halted: [bool; 2],
+    /// The next data the device sends is stalled and the command fails
+    stall_data: bool,
+    /// The next status has the wrong tag
+    bad_tag: bool,
+    halts_cleared: u32,
+    resets: u32,
+}
+
+enum TestState {
+    Command,
+    DataIn(Vec<u8>),
+    DataOut(usize),
+    Status(u8),
+}
+
+impl TestStick {
+    const IN: u8 = 0x81;
+    const OUT: u8 = 0x02;
+
+    fn command(&mut self, cbw: &[u8]) -> Result<(), UsbError> {
+        if cbw.len() != CBW_LEN || cbw[0..4] != CBW_SIGNATURE.to_le_bytes() {
+            self.halted = [true; 2];
+            return Err(UsbError::Stall);
+        }
+        self.tag = u32::from_le_bytes(cbw[4..8].try_into().unwrap());
+        let cb = &cbw[15..15 + cbw[14] as usize];
+        let lba = || u32::from_be_bytes(cb[2..6].try_into().unwrap()) as usize;
+        let count = || u16::from_be_bytes(cb[7..9].try_into().unwrap()) as usize;
+        let blocks = self.sectors.len() / SECTOR_SIZE;
+
+        self.state = match cb[0] {
+            SCSI_TEST_UNIT_READY => TestState::Status(CSW_PASSED),
+            SCSI_INQUIRY => {
+                let mut inquiry = vec![0; INQUIRY_LEN as usize];
+                inquiry[8..32].copy_from_slice(b"Fioxa   Test stick      ");
+                inquiry[32..36].copy_from_slice(b"1.00");
+                TestState::DataIn(inquiry)
+            }
+            SCSI_READ_CAPACITY_10 => {
+                let mut capacity = ((blocks - 1) as u32).to_be_bytes().to_vec();
+                capacity.extend_from_slice(&(SECTOR_SIZE as u32).to_be_bytes());
+                TestState::DataIn(capacity)
+            }
+            SCSI_READ_10 if lba() + count() <= blocks => TestState::DataIn(
+                self.sectors[lba() * SECTOR_SIZE..(lba() + count()) * SECTOR_SIZE].to_vec(),
+            ),
+            SCSI_WRITE_10 if lba() + count() <= blocks => TestState::DataOut(lba()),
+            _ => TestState::Status(CSW_FAILED),
+        };
+        Ok(())
+    }
+}
+
+struct TestTransport(Arc<Spinlock<TestStick>>);
+
+impl UsbBulkInterface for TestTransport {
+    fn number(&self) -> u8 {
+        0
+    }
+
+    fn bulk_in_endpoint(&self) -> u8 {
+        TestStick::IN
+    }
+
+    fn bulk_out_endpoint(&self) -> u8 {
+        TestStick::OUT
+    }
+
+    fn control(&mut self, setup: SetupPacket, data: &mut [u8]) -> Result<usize, UsbError> {
+        let mut stick = self.0.lock();
+        match setup.request {
+            REQUEST_GET_MAX_LUN => {
+                data[0] = 0;
+                Ok(1)
+            }
+            REQUEST_RESET => {
+                stick.resets += 1;
+                stick.state = TestState::Command;
+                Ok(0)
+            }
+            REQUEST_CLEAR_FEATURE => {
+                let index = (setup.index as u8 == TestStick::OUT) as usize;
+                if stick.halted[index] {
+                    stick.halted[index] = false;
+                    stick.halts_cleared += 1;
+                }
+                Ok(0)
+            }
+            _ => Err(UsbError::Stall),
+        }
+    }
+
+    fn bulk_in(&mut self, data: &mut [u8]) -> Result<usize, UsbError> {
+        let stick = &mut *self.0.lock();
+        if stick.halted[0] {
+            return Err(UsbError::Stall);
+        }
+        match core::mem::replace(&mut stick.state, TestState::Command) {
+            TestState::DataIn(_) if stick.stall_data => {
+                stick.stall_data = false;
+                stick.halted[0] = true;
+                stick.state = TestState::Status(CSW_FAILED);
+                Err(UsbError::Stall)
+            }
+            TestState::DataIn(d) => {
+                let len = d.len().min(data.len());
+                data[..len].copy_from_slice(&d[..len]);
+                stick.state = TestState::Status(CSW_PASSED);
+                Ok(len)
+            }
+            TestState::Status(status) => {
+                let tag = match stick.bad_tag {
+                    true => stick.tag.wrapping_add(1),
+                    false => stick.tag,
+                };
+                stick.bad_tag = false;
+                data[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+                data[4..8].copy_from_slice(&tag.to_le_bytes());
+                data[8..12].fill(0);
+                data[12] = status;
+                Ok(CSW_LEN)
+            }
+            _ => Err(UsbError::Transfer),
+        }
+    }
+
+    fn bulk_out(&mut self, data: &[u8]) -> Result<(), UsbError> {
+        let stick = &mut *self.0.lock();
+        if stick.halted[1] {
+            return Err(UsbError::Stall);
+        }
+        match core::mem::replace(&mut stick.state, TestState::Command) {
+            TestState::Command => stick.command(data),
+            TestState::DataOut(lba) => {
+                stick.sectors[lba * SECTOR_SIZE..][..data.len()].copy_from_slice(data);
+                stick.state = TestState::Status(CSW_PASSED);
+                Ok(())
+            }
+            _ => Err(UsbError::Transfer),
+        }
+    }
+}
+
+/// Runs the driver against a stick in memory, reading and writing across more than one command
+/// and recovering from a stalled endpoint and a device that got out of step
+pub fn self_test() {
+    const BLOCKS: usize = MAX_SECTORS + 32;
+    let stick = Arc::new(Spinlock::new(TestStick {
+        sectors: vec![0; BLOCKS * SECTOR_SIZE],
+        state: TestState::Command,
+        tag: 0,
+        halted: [false; 2],
+        stall_data: false,
+        bad_tag: false,
+        halts_cleared: 0,
+        resets: 0,
+    }));
+    let mut disk = UsbMassStorage::new(Box::new(TestTransport(stick.clone())))
+        .expect("test stick should attach");
+    assert_eq!(disk.blocks, BLOCKS as u64);
+
+    let count = MAX_SECTORS + 2;
+    let mut written: Vec<u8> = (0..count * SECTOR_SIZE).map(|i| (i / 7) as u8).collect();
+    disk.write(1, count as u32, &mut written)
+        .expect("write should succeed");
+    let mut read = vec![0; count * SECTOR_SIZE];
+    disk.read(1, count as u32, &mut read)
+        .expect("read should succeed");
+    assert!(read == written, "read back something else than was written");
+    assert!(disk.read(BLOCKS, 1, &mut read).is_none());
+
+    stick.lock().stall_data = true;
+    assert!(disk.read(0, 1, &mut read).is_none());
+    assert_eq!(
+        stick.lock().halts_cleared,
+        1,
+        "stalled endpoint wasn't cleared"
+    );
+    disk.read(1, 1, &mut read)
+        .expect("read after a stall should succeed");
+    assert!(read[..SECTOR_SIZE] == written[..SECTOR_SIZE]);
+
+    stick.lock().bad_tag = true;
+    assert!(disk.read(1, 1, &mut read).is_none());
+    assert_eq!(
+        stick.lock().resets,
+        1,
+        "device wasn't reset after a phase error"
+    );
+    disk.read(1, 1, &mut read)
+        .expect("read after a reset should succeed");
+    assert!(read[..SECTOR_SIZE] == written[..SECTOR_SIZE]);
+}
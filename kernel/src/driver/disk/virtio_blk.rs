@@ -0,0 +1,315 @@
+//! Virtio block device using the legacy (virtio 0.9.5) PCI interface, which transitional devices
+//! like the one QEMU provides by default support.
+//!
+//! There is a single request queue and requests are issued one at a time and polled, data goes
+//! through a bounce buffer so callers can pass any buffer.
+
+use core::{
+    mem::{size_of, MaybeUninit},
+    ptr::{read_volatile, write_volatile},
+};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use kernel_userspace::{
+    disk::ata::ATADiskIdentify,
+    dma::{DmaConstraints, DmaSegment},
+    virtio::{SplitQueue, VirtqLayout},
+};
+use x86_64::instructions::port::Port;
+
+use crate::{
+    driver::{disk::DiskDevice, driver::Driver},
+    mutex::Mutex,
+    paging::{
+        dma::{alloc_dma, DmaPages},
+        virt_addr_for_phys,
+    },
+    pci::PCIHeaderCommon,
+};
+
+use super::DiskBusDriver;
+
+// Legacy register offsets from the start of the IO BAR
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_DRIVER_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+/// Device specific config, this moves up by 4 if MSI-X is enabled which we never do
+const REG_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+/// Features we understand, everything else is left off
+const SUPPORTED_FEATURES: u32 = VIRTIO_BLK_F_RO;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Legacy queues are laid out with the used ring on its own page
+const QUEUE_ALIGN: usize = 0x1000;
+/// Most sectors moved by a single request, the size of the bounce buffer
+const MAX_SECTORS: usize = 128;
+const REQUEST_SPIN: usize = 10_000_000;
+
+#[repr(C)]
+struct BlkRequestHeader {
+    kind: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+/// Allocates physically contiguous zeroed memory, returns it with its physical address
+fn alloc_contiguous(size: usize) -> Option<(DmaPages, u64)> {
+    let (pages, segments) = alloc_dma(
+        size,
+        &DmaConstraints {
+            max_segments: 1,
+            ..Default::default()
+        },
+    )?;
+    let DmaSegment { phys, len } = segments[0];
+    unsafe { core::ptr::write_bytes(virt_addr_for_phys(phys) as *mut u8, 0, len) };
+    Some((pages, phys))
+}
+
+struct Virtqueue {
+    ring: SplitQueue,
+    _memory: DmaPages,
+}
+
+impl Virtqueue {
+    /// Sets up the queue and tells the device where it is
+    fn new(io_base: u16, index: u16) -> Option<Self> {
+        unsafe {
+            Port::<u16>::new(io_base + REG_QUEUE_SELECT).write(index);
+            let size = Port::<u16>::new(io_base + REG_QUEUE_SIZE).read();
+            if size < 3 {
+                return None;
+            }
+
+            let layout = VirtqLayout::new(size, QUEUE_ALIGN);
+            let (memory, phys) = alloc_contiguous(layout.len)?;
+            let pfn = (phys / QUEUE_ALIGN as u64) as u32;
+            Port::<u32>::new(io_base + REG_QUEUE_ADDRESS).write(pfn);
+
+            Some(Self {
+                ring: SplitQueue::new(virt_addr_for_phys(phys) as *mut u8, size, &layout),
+                _memory: memory,
+            })
+        }
+    }
+
+    /// Waits for the device to finish the request
+    fn wait(&mut self) -> Option<()> {
+        for _ in 0..REQUEST_SPIN {
+            if self.ring.poll() {
+                return Some(());
+            }
+            core::hint::spin_loop();
+        }
+        error!("virtio-blk request timed out");
+        None
+    }
+}
+
+pub struct VirtioBlk {
+    io_base: u16,
+    queue: Virtqueue,
+    /// Size in 512 byte sectors
+    capacity: u64,
+    read_only: bool,
+    serial: [u8; 20],
+    /// The request header followed by the status byte
+    request: (DmaPages, u64),
+    bounce: (DmaPages, u64),
+}
+
+// The queue only points at memory the disk owns
+unsafe impl Send for VirtioBlk {}
+unsafe impl Sync for VirtioBlk {}
+
+impl VirtioBlk {
+    fn new(io_base: u16) -> Option<Self> {
+        let status = |s: u8| unsafe { Port::<u8>::new(io_base + REG_DEVICE_STATUS).write(s) };
+
+        // Reset, then say we found it and know how to drive it
+        status(0);
+        status(STATUS_ACKNOWLEDGE);
+        status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features = unsafe { Port::<u32>::new(io_base + REG_DEVICE_FEATURES).read() };
+        let features = features & SUPPORTED_FEATURES;
+        unsafe { Port::<u32>::new(io_base + REG_DRIVER_FEATURES).write(features) };
+
+        let setup = || {
+            let queue = Virtqueue::new(io_base, 0)?;
+            let request = alloc_contiguous(0x1000)?;
+            let bounce = alloc_contiguous(MAX_SECTORS * 512)?;
+            let capacity = unsafe {
+                let low = Port::<u32>::new(io_base + REG_CONFIG).read() as u64;
+                let high = Port::<u32>::new(io_base + REG_CONFIG + 4).read() as u64;
+                high << 32 | low
+            };
+            Some(Self {
+                io_base,
+                queue,
+                capacity,
+                read_only: features & VIRTIO_BLK_F_RO != 0,
+                serial: [0; 20],
+                request,
+                bounce,
+            })
+        };
+        let Some(mut disk) = setup() else {
+            status(STATUS_FAILED);
+            return None;
+        };
+        status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+
+        // Not every device has a serial
+        if disk.request(VIRTIO_BLK_T_GET_ID, 0, 20).is_some() {
+            let serial = unsafe { &*(virt_addr_for_phys(disk.bounce.1) as *const [u8; 20]) };
+            disk.serial = *serial;
+        }
+        info!(
+            "virtio-blk: {} sectors{}",
+            disk.capacity,
+            if disk.read_only { ", read only" } else { "" }
+        );
+        Some(disk)
+    }
+
+    /// Sends a request with len bytes of the bounce buffer as its data
+    fn request(&mut self, kind: u32, sector: u64, len: usize) -> Option<()> {
+        let header_phys = self.request.1;
+        let status_phys = header_phys + size_of::<BlkRequestHeader>() as u64;
+        unsafe {
+            write_volatile(
+                virt_addr_for_phys(header_phys) as *mut BlkRequestHeader,
+                BlkRequestHeader {
+                    kind,
+                    _reserved: 0,
+                    sector,
+                },
+            );
+            write_volatile(virt_addr_for_phys(status_phys) as *mut u8, 0xFF);
+        }
+
+        self.queue.ring.submit(&[
+            (header_phys, size_of::<BlkRequestHeader>() as u32, false),
+            (self.bounce.1, len as u32, kind != VIRTIO_BLK_T_OUT),
+            (status_phys, 1, true),
+        ]);
+        unsafe { Port::<u16>::new(self.io_base + REG_QUEUE_NOTIFY).write(0) };
+        self.queue.wait()?;
+
+        let status = unsafe { read_volatile(virt_addr_for_phys(status_phys) as *const u8) };
+        (status == VIRTIO_BLK_S_OK).then_some(())
+    }
+
+    fn transfer(
+        &mut self,
+        sector: usize,
+        sector_count: u32,
+        buffer: &mut [u8],
+        write: bool,
+    ) -> Option<()> {
+        let end = sector.checked_add(sector_count as usize)?;
+        if end as u64 > self.capacity || (write && self.read_only) {
+            return None;
+        }
+        assert!(
+            buffer.len() >= sector_count as usize * 512,
+            "Buffer is not large enough"
+        );
+
+        let bounce = virt_addr_for_phys(self.bounce.1) as *mut u8;
+        let buffer = &mut buffer[..sector_count as usize * 512];
+        for (i, chunk) in buffer.chunks_mut(MAX_SECTORS * 512).enumerate() {
+            let sector = (sector + i * MAX_SECTORS) as u64;
+            if write {
+                unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), bounce, chunk.len()) };
+                self.request(VIRTIO_BLK_T_OUT, sector, chunk.len())?;
+            } else {
+                self.request(VIRTIO_BLK_T_IN, sector, chunk.len())?;
+                unsafe { core::ptr::copy_nonoverlapping(bounce, chunk.as_mut_ptr(), chunk.len()) };
+            }
+        }
+        Some(())
+    }
+}
+
+impl DiskDevice for VirtioBlk {
+    fn read(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        self.transfer(sector, sector_count, buffer, false)
+    }
+
+    fn write(&mut self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        self.transfer(sector, sector_count, buffer, true)
+    }
+
+    fn identify(&mut self) -> Box<ATADiskIdentify> {
+        let mut identify: Box<MaybeUninit<ATADiskIdentify>> = Box::new_uninit();
+        let mut identify = unsafe {
+            identify.as_mut_ptr().write_bytes(0, 1);
+            identify.assume_init()
+        };
+        identify.serial = self.serial;
+        identify.lba_size48_1 = self.capacity as u16;
+        identify.lba_size48_2 = (self.capacity >> 16) as u16;
+        identify.lba_size48_3 = (self.capacity >> 32) as u16;
+        identify.lba_size48_4 = (self.capacity >> 48) as u16;
+        identify
+    }
+}
+
+pub struct VirtioBlkDriver {
+    disk: Arc<Mutex<VirtioBlk>>,
+}
+
+impl Driver for VirtioBlkDriver {
+    fn new(device: PCIHeaderCommon) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        let header0 = unsafe { device.get_as_header0() };
+        let Some(io_base) = header0.get_port_base() else {
+            error!("virtio-blk: no IO BAR, only the legacy interface is supported");
+            return None;
+        };
+        let disk = VirtioBlk::new(io_base as u16)?;
+        Some(Self {
+            disk: Arc::new(Mutex::new(disk)),
+        })
+    }
+
+    fn unload(self) -> ! {
+        todo!()
+    }
+
+    fn interrupt_handler(&mut self) {
+        todo!()
+    }
+}
+
+impl DiskBusDriver for VirtioBlkDriver {
+    fn get_disks(&mut self) -> Vec<Arc<Mutex<dyn DiskDevice>>> {
+        alloc::vec![self.disk.clone()]
+    }
+
+    fn get_disk_by_id(&mut self, id: usize) -> Option<Arc<Mutex<dyn DiskDevice>>> {
+        match id {
+            0 => Some(self.disk.clone()),
+            _ => None,
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod disk;
 pub mod driver;
+pub mod usb;
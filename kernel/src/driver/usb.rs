@@ -0,0 +1,60 @@
+//! What usb class drivers need from a host controller. There is no host controller driver
+//! yet; one would enumerate the devices plugged into it and hand the interfaces it finds to
+//! class drivers like [`super::disk::usb_msc`].
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The endpoint answered with a stall and has to have its halt cleared
+    Stall,
+    /// The transfer failed on the bus or the device didn't answer
+    Transfer,
+    /// The device has been unplugged
+    Disconnected,
+}
+
+/// The request types of setup packets, direction, type and recipient
+pub const REQUEST_OUT_STANDARD_ENDPOINT: u8 = 0x02;
+pub const REQUEST_OUT_CLASS_INTERFACE: u8 = 0x21;
+pub const REQUEST_IN_CLASS_INTERFACE: u8 = 0xA1;
+
+pub const REQUEST_CLEAR_FEATURE: u8 = 1;
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// The start of a control transfer
+#[derive(Debug, Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+/// An interface of a device with a bulk in and a bulk out endpoint, set up by the host
+/// controller in the configuration it picked
+pub trait UsbBulkInterface: Send + Sync {
+    /// The interface's number in the configuration
+    fn number(&self) -> u8;
+    /// The endpoint addresses, with the top bit set for in
+    fn bulk_in_endpoint(&self) -> u8;
+    fn bulk_out_endpoint(&self) -> u8;
+
+    /// Makes a control transfer on the device's default pipe, the data goes in or out as the
+    /// request type says. Returns how much was moved.
+    fn control(&mut self, setup: SetupPacket, data: &mut [u8]) -> Result<usize, UsbError>;
+    fn bulk_in(&mut self, data: &mut [u8]) -> Result<usize, UsbError>;
+    fn bulk_out(&mut self, data: &[u8]) -> Result<(), UsbError>;
+
+    /// Lets a stalled endpoint be used again
+    fn clear_halt(&mut self, endpoint: u8) -> Result<(), UsbError> {
+        self.control(
+            SetupPacket {
+                request_type: REQUEST_OUT_STANDARD_ENDPOINT,
+                request: REQUEST_CLEAR_FEATURE,
+                value: FEATURE_ENDPOINT_HALT,
+                index: endpoint as u16,
+            },
+            &mut [],
+        )
+        .map(|_| ())
+    }
+}
@@ -1,11 +1,15 @@
 use alloc::{sync::Arc, vec::Vec};
 use kernel_userspace::{
     channel::{channel_create_rs, channel_read_rs, channel_write_rs, ChannelReadResult},
-    elf::{validate_elf_header, Elf64Ehdr, Elf64Phdr, LoadElfError, PT_LOAD},
+    elf::{
+        validate_elf_header, Elf64Dyn, Elf64Ehdr, Elf64Phdr, Elf64Rela, LoadElfError,
+        SpawnElfProcess, DT_NULL, DT_RELA, DT_RELAENT, DT_RELASZ, ET_DYN, PT_DYNAMIC, PT_LOAD,
+        PT_TLS, R_X86_64_NONE, R_X86_64_RELATIVE,
+    },
     message::MessageHandle,
     object::KernelReference,
     process::publish_handle,
-    service::serialize,
+    service::{deserialize, serialize},
     syscall::spawn_thread,
 };
 use x86_64::{align_down, align_up};
@@ -13,8 +17,9 @@ use x86_64::{align_down, align_up};
 use crate::{
     cpu_localstorage::CPULocalStorageRW,
     paging::{page_mapper::PageMapping, MemoryMappingFlags},
+    random::random_below,
     scheduling::{
-        process::{Process, ProcessPrivilige},
+        process::{KernelValue, Process, ProcessPrivilige, TLSTemplate},
         taskmanager::{PROCESSES, SCHEDULER},
         with_held_interrupts,
     },
@@ -29,20 +34,114 @@ bitflags::bitflags! {
 }
 
 impl ElfSegmentFlags {
-    pub fn to_mapping_flags(&self) -> MemoryMappingFlags {
-        let mut flags = MemoryMappingFlags::USERSPACE;
+    /// The segment's flags for a process with the given privilege, a kernel process' segments
+    /// can't be user pages as SMEP and SMAP would fault on them
+    pub fn to_mapping_flags(&self, privilege: ProcessPrivilige) -> MemoryMappingFlags {
+        let mut flags = privilege.mapping_flags();
         if self.contains(ElfSegmentFlags::PF_W) {
             flags |= MemoryMappingFlags::WRITEABLE;
         }
+        if self.contains(ElfSegmentFlags::PF_X) {
+            flags |= MemoryMappingFlags::EXECUTABLE;
+        }
         flags
     }
 }
 
+/// Where position independent executables are loaded without ASLR
+const PIE_BASE: u64 = 0x20_0000;
+/// Position independent executables are loaded at a random 2mb aligned address in this range with
+/// ASLR, it sits between the APIC and stacks
+const PIE_ASLR_BASE: u64 = 0x1_0000_0000;
+const PIE_ASLR_SLOTS: u64 = 0x8000;
+
+/// Reads a T from the elf at offset
+fn read_at<T: Copy>(data: &[u8], offset: u64) -> Option<T> {
+    let offset = usize::try_from(offset).ok()?;
+    data.get(offset..offset.checked_add(core::mem::size_of::<T>())?)?;
+    Some(unsafe { core::ptr::read_unaligned(data.as_ptr().add(offset) as *const T) })
+}
+
+/// Translates a virtual address in the elf to its offset in the file
+fn vaddr_to_offset<'a>(headers: impl Iterator<Item = &'a Elf64Phdr>, vaddr: u64) -> Option<u64> {
+    headers
+        .filter(|h| h.p_type == PT_LOAD)
+        .find(|h| h.p_vaddr <= vaddr && vaddr < h.p_vaddr + h.p_filesz)
+        .map(|h| vaddr - h.p_vaddr + h.p_offset)
+}
+
+/// Applies the R_X86_64_RELATIVE relocations of a position independent executable that has been
+/// loaded bias bytes above where it was linked
+fn relocate<'a>(
+    data: &'a [u8],
+    headers: impl Iterator<Item = &'a Elf64Phdr>,
+    dynamic: &Elf64Phdr,
+    segments: &[(u64, Arc<PageMapping>)],
+    bias: u64,
+) -> Result<(), LoadElfError<'a>> {
+    let mut rela = None;
+    let mut rela_size = 0;
+    let mut rela_ent = core::mem::size_of::<Elf64Rela>() as u64;
+
+    for offset in (dynamic.p_offset..dynamic.p_offset + dynamic.p_filesz)
+        .step_by(core::mem::size_of::<Elf64Dyn>())
+    {
+        let entry: Elf64Dyn = read_at(data, offset).ok_or(LoadElfError::InternalError)?;
+        match entry.d_tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(entry.d_val),
+            DT_RELASZ => rela_size = entry.d_val,
+            DT_RELAENT => rela_ent = entry.d_val,
+            _ => (),
+        }
+    }
+
+    let Some(rela) = rela else {
+        return Ok(());
+    };
+    let rela = vaddr_to_offset(headers, rela).ok_or(LoadElfError::InternalError)?;
+
+    for offset in (rela..rela + rela_size).step_by(rela_ent as usize) {
+        let rel: Elf64Rela = read_at(data, offset).ok_or(LoadElfError::InternalError)?;
+        match (rel.r_info & 0xFFFF_FFFF) as u32 {
+            R_X86_64_NONE => (),
+            R_X86_64_RELATIVE => {
+                let target = rel.r_offset + bias;
+                let (base, mem) = segments
+                    .iter()
+                    .find(|(base, mem)| *base <= target && target + 8 <= *base + mem.size() as u64)
+                    .ok_or(LoadElfError::InternalError)?;
+                let value = (rel.r_addend as u64).wrapping_add(bias);
+                mem.write_at((target - base) as usize, &value.to_ne_bytes());
+            }
+            t => return Err(LoadElfError::Relocation(t)),
+        }
+    }
+    Ok(())
+}
+
+/// Loads the elf into a new process. With file set data is mapped from it, read only segments
+/// then share the file's pages instead of being copied.
 pub fn load_elf<'a>(
     data: &'a [u8],
+    file: Option<&Arc<PageMapping>>,
+    args: &[u8],
+    references: &[KernelReference],
+    kernel: bool,
+    aslr: bool,
+) -> Result<Arc<Process>, LoadElfError<'a>> {
+    load_elf_with(data, file, args, references, kernel, aslr, |_| ())
+}
+
+/// [`load_elf`], calling setup on the process before its first thread starts
+pub fn load_elf_with<'a>(
+    data: &'a [u8],
+    file: Option<&Arc<PageMapping>>,
     args: &[u8],
     references: &[KernelReference],
     kernel: bool,
+    aslr: bool,
+    setup: impl FnOnce(&Process),
 ) -> Result<Arc<Process>, LoadElfError<'a>> {
     // Transpose the header as an elf header
     let elf_header = unsafe { &*(data.as_ptr() as *const Elf64Ehdr) };
@@ -59,6 +158,19 @@ pub fn load_elf<'a>(
         "ELF SPAWNED",
     );
 
+    if aslr {
+        process.memory.lock().randomize_layout();
+    }
+
+    // Only position independent executables can be moved
+    let bias = if elf_header.e_type != ET_DYN {
+        0
+    } else if aslr {
+        PIE_ASLR_BASE + random_below(PIE_ASLR_SLOTS) * 0x20_0000
+    } else {
+        PIE_BASE
+    };
+
     // build initial refs
     with_held_interrupts(|| unsafe {
         let mut refs = process.references.lock();
@@ -75,32 +187,69 @@ pub fn load_elf<'a>(
         }
     });
 
-    let headers = (elf_header.e_phoff..((elf_header.e_phnum * elf_header.e_phentsize).into()))
+    let headers_size = elf_header.e_phnum as u64 * elf_header.e_phentsize as u64;
+    let headers = (elf_header.e_phoff..elf_header.e_phoff + headers_size)
         .step_by(elf_header.e_phentsize.into())
         // Transpose the program header as an elf header
         .map(|header| unsafe { &*(data.as_ptr().add(header as usize) as *const Elf64Phdr) });
 
     let this_mem = unsafe { &CPULocalStorageRW::get_current_task().process().memory };
 
+    let mut segments = Vec::new();
+    let mut dynamic = None;
+
     // Iterate over each header
-    for program_header in headers {
+    for program_header in headers.clone() {
         if program_header.p_type == PT_LOAD {
-            let vstart = align_down(program_header.p_vaddr, 0x1000);
+            let vstart = align_down(program_header.p_vaddr + bias, 0x1000);
             // let vallocend = align_up(program_header.p_vaddr + program_header.p_filesz, 0x1000);
-            let vend = align_up(program_header.p_vaddr + program_header.p_memsz, 0x1000);
+            let vend = align_up(
+                program_header.p_vaddr + bias + program_header.p_memsz,
+                0x1000,
+            );
 
             let size = (vend - vstart) as usize;
-            let mem = PageMapping::new_lazy(size);
 
-            let flags = ElfSegmentFlags::from_bits_truncate(program_header.p_flags);
+            let flags = ElfSegmentFlags::from_bits_truncate(program_header.p_flags)
+                .to_mapping_flags(process.privilege);
+            if !flags.is_valid() {
+                return Err(LoadElfError::WritableExecutableSegment(
+                    program_header.p_vaddr,
+                ));
+            }
+
+            // The file can only be used if the segment lines up with its pages and nothing has to
+            // be zeroed. Relocations can't be applied to it as it isn't in segments.
+            let shared = match file {
+                Some(file)
+                    if !flags.contains(MemoryMappingFlags::WRITEABLE)
+                        && program_header.p_filesz == program_header.p_memsz
+                        && program_header.p_offset & 0xFFF == program_header.p_vaddr & 0xFFF =>
+                {
+                    file.new_file_within(program_header.p_offset as usize / 0x1000, size)
+                }
+                _ => None,
+            };
+            if let Some(mem) = shared {
+                process
+                    .memory
+                    .lock()
+                    .page_mapper
+                    .insert_mapping_at(vstart as usize, mem, flags)
+                    .ok_or(LoadElfError::InternalError)?;
+                continue;
+            }
+
+            let mem = PageMapping::new_lazy(size);
 
             // Map into the new processes address space
             process
                 .memory
                 .lock()
                 .page_mapper
-                .insert_mapping_at(vstart as usize, mem.clone(), flags.to_mapping_flags())
+                .insert_mapping_at(vstart as usize, mem.clone(), flags)
                 .ok_or(LoadElfError::InternalError)?;
+            segments.push((vstart, mem.clone()));
 
             unsafe {
                 // Map into our address space
@@ -108,7 +257,7 @@ pub fn load_elf<'a>(
                     this_mem
                         .lock()
                         .page_mapper
-                        .insert_mapping(mem, MemoryMappingFlags::all())
+                        .insert_mapping(mem, MemoryMappingFlags::WRITEABLE)
                 });
 
                 assert_eq!(CPULocalStorageRW::hold_interrupts_depth(), 0, "We will be causing page faults on the copy so ensure we aren't holding interrupts");
@@ -126,9 +275,28 @@ pub fn load_elf<'a>(
                 })
                 .unwrap();
             }
+        } else if program_header.p_type == PT_TLS {
+            let start = program_header.p_offset as usize;
+            let image = data
+                .get(start..start + program_header.p_filesz as usize)
+                .ok_or(LoadElfError::InternalError)?;
+
+            process.memory.lock().tls_template = Some(TLSTemplate {
+                image: image.into(),
+                mem_size: program_header.p_memsz as usize,
+                align: program_header.p_align as usize,
+            });
+        } else if program_header.p_type == PT_DYNAMIC {
+            dynamic = Some(program_header);
         }
     }
-    let thread = process.new_thread(elf_header.e_entry as *const u64, 0);
+
+    if let Some(dynamic) = dynamic {
+        relocate(data, headers, dynamic, &segments, bias)?;
+    }
+
+    setup(&process);
+    let thread = process.new_thread((elf_header.e_entry + bias) as *const u64, 0);
     PROCESSES.lock().insert(process.pid, process.clone());
     SCHEDULER
         .lock()
@@ -160,13 +328,56 @@ pub fn elf_new_process_loader() {
                         return;
                     }
                 };
-                if handles.len() != 2 {
+                let spawn: SpawnElfProcess = match deserialize(&data) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("{e:?}");
+                        return;
+                    }
+                };
+                if handles.len() != spawn.init_references_count + 1 {
                     warn!("wrong args");
                     return;
                 }
 
-                let elf = MessageHandle::from_kref(KernelReference::from_id(handles[0])).read_vec();
-                let res = load_elf(&elf, &data, &[KernelReference::from_id(handles[1])], false);
+                let elf = KernelReference::from_id(handles[0]);
+                let this = unsafe { CPULocalStorageRW::get_current_task().process() };
+                let file = match this.get_value(elf.id()) {
+                    Some(KernelValue::Memory(file)) => Some(file),
+                    _ => None,
+                };
+
+                // A file is mapped here to read the headers and anything that has to be copied
+                let contents;
+                let mut mapped = None;
+                let elf_data = match &file {
+                    Some(file) => {
+                        let base = with_held_interrupts(|| {
+                            this.memory
+                                .lock()
+                                .page_mapper
+                                .insert_mapping(file.clone(), MemoryMappingFlags::empty())
+                        });
+                        mapped = Some(base..base + file.size());
+                        unsafe { core::slice::from_raw_parts(base as *const u8, file.size()) }
+                    }
+                    None => {
+                        contents = MessageHandle::from_kref(elf).read_vec();
+                        &contents[..]
+                    }
+                };
+                let references: Vec<_> = handles[1..]
+                    .iter()
+                    .map(|&h| KernelReference::from_id(h))
+                    .collect();
+                let res = load_elf(
+                    elf_data,
+                    file.as_ref(),
+                    spawn.args,
+                    &references,
+                    false,
+                    spawn.aslr,
+                );
 
                 match res {
                     Ok(proc) => {
@@ -181,6 +392,13 @@ pub fn elf_new_process_loader() {
                         channel_write_rs(handle.id(), msg, &[]);
                     }
                 }
+
+                if let Some(range) = mapped {
+                    with_held_interrupts(|| unsafe {
+                        this.memory.lock().page_mapper.free_mapping(range)
+                    })
+                    .unwrap();
+                }
             }
         });
     }
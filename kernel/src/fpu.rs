@@ -0,0 +1,155 @@
+//! Lazy FPU/SSE/AVX state switching.
+//!
+//! Every thread starts each time slice with CR0.TS set, so the first SIMD instruction raises a
+//! device not available exception which restores the threads state. Threads that used the FPU
+//! during their slice are saved when they are switched out, threads that never touch it skip
+//! both the save and restore.
+
+use core::{
+    alloc::Layout,
+    arch::x86_64::__cpuid_count,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{
+    cpu::{features, CpuFeatures},
+    cpu_localstorage::CPULocalStorageRW,
+    scheduling::{process::ThreadSched, taskmanager::kill_bad_task},
+};
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+/// Size of the save area, 512 is the size used by fxsave
+static SAVE_AREA_SIZE: AtomicUsize = AtomicUsize::new(512);
+
+/// Enables the FPU on the current core, should be called on every core before it starts scheduling
+///
+/// # Safety
+///
+/// Must run in ring 0 after the cpu features are detected, and before any thread on this core
+/// touches the FPU.
+pub unsafe fn init_core() {
+    let xsave = features().contains(CpuFeatures::XSAVE);
+    let avx = features().contains(CpuFeatures::AVX);
+
+    let mut cr4 = Cr4::read();
+    cr4 |= Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE;
+    if xsave {
+        cr4 |= Cr4Flags::OSXSAVE;
+    }
+    Cr4::write(cr4);
+
+    let mut cr0 = Cr0::read();
+    cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
+    cr0 |= Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::TASK_SWITCHED;
+    Cr0::write(cr0);
+
+    if xsave {
+        let mut xcr0 = XCR0_X87 | XCR0_SSE;
+        if avx {
+            xcr0 |= XCR0_AVX;
+        }
+        core::arch::asm!(
+            "xsetbv",
+            in("ecx") 0,
+            in("eax") xcr0 as u32,
+            in("edx") (xcr0 >> 32) as u32,
+            options(nostack)
+        );
+
+        // ebx holds the size needed for the features enabled in xcr0
+        let size = __cpuid_count(0xD, 0).ebx as usize;
+        SAVE_AREA_SIZE.fetch_max(size, Ordering::Relaxed);
+        USE_XSAVE.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A threads saved FPU state (xsave or fxsave area)
+pub struct FpuState {
+    area: NonNull<u8>,
+    size: usize,
+}
+
+unsafe impl Send for FpuState {}
+unsafe impl Sync for FpuState {}
+
+impl FpuState {
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 64).unwrap()
+    }
+
+    fn new() -> Self {
+        let size = SAVE_AREA_SIZE.load(Ordering::Relaxed);
+        unsafe {
+            let area = alloc::alloc::alloc_zeroed(Self::layout(size));
+            let area = NonNull::new(area).expect("failed to allocate fpu state");
+            // Default FCW and MXCSR (all exceptions masked)
+            *(area.as_ptr() as *mut u16) = 0x37F;
+            *(area.as_ptr().add(24) as *mut u32) = 0x1F80;
+            Self { area, size }
+        }
+    }
+
+    unsafe fn save(&mut self) {
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            core::arch::asm!("xsave64 [{}]", in(reg) self.area.as_ptr(), in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            core::arch::asm!("fxsave64 [{}]", in(reg) self.area.as_ptr(), options(nostack));
+        }
+    }
+
+    unsafe fn restore(&self) {
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            core::arch::asm!("xrstor64 [{}]", in(reg) self.area.as_ptr(), in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+        } else {
+            core::arch::asm!("fxrstor64 [{}]", in(reg) self.area.as_ptr(), options(nostack));
+        }
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        unsafe { alloc::alloc::dealloc(self.area.as_ptr(), Self::layout(self.size)) }
+    }
+}
+
+/// Called by the scheduler after a thread has been switched out
+pub fn switch_out(sched: &mut ThreadSched) {
+    // If TS is clear the thread used the fpu this slice and its state is live
+    if Cr0::read().contains(Cr0Flags::TASK_SWITCHED) {
+        return;
+    }
+    unsafe {
+        sched.fpu_state.get_or_insert_with(FpuState::new).save();
+        Cr0::update(|f| f.insert(Cr0Flags::TASK_SWITCHED));
+    }
+}
+
+pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    if CPULocalStorageRW::get_context() == 0 {
+        panic!(
+            "EXCEPTION: DEVICE NOT AVAILABLE in context 0 {:?}",
+            stack_frame
+        );
+    }
+
+    unsafe {
+        core::arch::asm!("clts", options(nostack));
+
+        let thread = CPULocalStorageRW::get_current_task();
+        let Some(mut sched) = thread.sched().try_lock() else {
+            error!("EXCEPTION: DEVICE NOT AVAILABLE while holding the threads sched lock");
+            kill_bad_task()
+        };
+        sched.fpu_state.get_or_insert_with(FpuState::new).restore();
+    }
+}
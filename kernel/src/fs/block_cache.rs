@@ -0,0 +1,465 @@
+//! Sector cache shared by every disk backed file system.
+//!
+//! Sits under the page cache and mostly holds metadata that file systems read over and over, like
+//! FAT sectors, directories and inode tables. Writes are cached as dirty blocks and go to the disk
+//! on [`flush`], which the page cache does as part of its periodic writeback. The least recently
+//! used clean blocks are evicted once the cache is over its capacity, which shrinks as memory
+//! pressure rises.
+//!
+//! Reads that carry on from where the last read of the disk ended are treated as a stream and the
+//! sectors after them are read ahead by [`readahead_task`], so that the next read finds them
+//! cached. The window doubles with every read that continues the stream. Queued read aheads are
+//! sorted and merged before going to the disk, the same as dirty blocks are when flushing.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use kernel_userspace::memory::MemoryPressure;
+
+use crate::{
+    cpu_localstorage::CPULocalStorageRW,
+    driver::disk::DiskDevice,
+    mutex::{Mutex, Spinlock},
+    paging::pressure::{memory_pressure, Shrinker},
+    scheduling::{
+        process::{Thread, ThreadState},
+        taskmanager::enter_sched,
+    },
+};
+
+pub type DiskRef = Arc<Mutex<dyn DiskDevice>>;
+
+/// Default capacity in sectors (2mb)
+const DEFAULT_CAPACITY: usize = 0x1000;
+/// The most sectors written to the disk in one go when flushing
+const MAX_FLUSH_RUN: usize = 128;
+/// Sectors read ahead once a stream is noticed, and the most it grows to
+const MIN_READAHEAD: usize = 16;
+const MAX_READAHEAD: usize = 256;
+
+static CAPACITY: AtomicUsize = AtomicUsize::new(DEFAULT_CAPACITY);
+
+/// Indexed by the disk and the sector on it
+type BlockKey = (usize, usize);
+
+struct CachedBlock {
+    data: Box<[u8; 512]>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct BlockCache {
+    blocks: BTreeMap<BlockKey, CachedBlock>,
+    /// Blocks by when they were last used, oldest first
+    lru: BTreeMap<u64, BlockKey>,
+    /// The disks that have dirty blocks
+    disks: BTreeMap<usize, DiskRef>,
+    streams: BTreeMap<usize, Stream>,
+    clock: u64,
+}
+
+static BLOCK_CACHE: Spinlock<BlockCache> = Spinlock::new(BlockCache {
+    blocks: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    disks: BTreeMap::new(),
+    streams: BTreeMap::new(),
+    clock: 0,
+});
+
+/// The last read of a disk
+struct Stream {
+    /// The sector after it
+    next: usize,
+    /// How far ahead to read if the next read starts at next, 0 if it isn't a stream yet
+    window: usize,
+}
+
+struct ReadaheadRequest {
+    disk: DiskRef,
+    sector: usize,
+    count: usize,
+}
+
+struct Readahead {
+    thread: Option<Arc<Thread>>,
+    queue: VecDeque<ReadaheadRequest>,
+    /// Sector counts of the disks that have been read ahead on, so it never goes past the end
+    sizes: BTreeMap<usize, usize>,
+}
+
+static READAHEAD: Spinlock<Readahead> = Spinlock::new(Readahead {
+    thread: None,
+    queue: VecDeque::new(),
+    sizes: BTreeMap::new(),
+});
+
+fn disk_key(disk: &DiskRef) -> usize {
+    Arc::as_ptr(disk) as *const () as usize
+}
+
+impl BlockCache {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn get(&mut self, key: BlockKey) -> Option<&CachedBlock> {
+        let now = self.tick();
+        let block = self.blocks.get_mut(&key)?;
+        self.lru.remove(&block.last_used);
+        self.lru.insert(now, key);
+        block.last_used = now;
+        Some(block)
+    }
+
+    fn insert(&mut self, key: BlockKey, data: &[u8], dirty: bool) {
+        let now = self.tick();
+        match self.blocks.get_mut(&key) {
+            Some(block) => {
+                self.lru.remove(&block.last_used);
+                block.data.copy_from_slice(data);
+                block.dirty |= dirty;
+                block.last_used = now;
+            }
+            None => {
+                let mut block = Box::new([0; 512]);
+                block.copy_from_slice(data);
+                self.blocks.insert(
+                    key,
+                    CachedBlock {
+                        data: block,
+                        dirty,
+                        last_used: now,
+                    },
+                );
+            }
+        }
+        self.lru.insert(now, key);
+    }
+
+    /// Drops the oldest clean blocks until there are at most limit, returns how many blocks
+    /// over the limit are left. Dirty blocks stay cached until they have been written so that a
+    /// read never sees the disk before the write reaches it.
+    fn evict(&mut self, limit: usize) -> usize {
+        let over = self.blocks.len().saturating_sub(limit);
+        let evicted: Vec<_> = self
+            .lru
+            .iter()
+            .filter(|(_, key)| !self.blocks[key].dirty)
+            .map(|(&used, &key)| (used, key))
+            .take(over)
+            .collect();
+        for (used, key) in &evicted {
+            self.lru.remove(used);
+            self.blocks.remove(key);
+        }
+        over - evicted.len()
+    }
+
+    /// Follows the stream on the disk, returns the sectors that should be read ahead
+    fn track(&mut self, key: usize, sector: usize, count: usize) -> Option<(usize, usize)> {
+        let stream = self.streams.entry(key).or_insert(Stream {
+            next: usize::MAX,
+            window: 0,
+        });
+        if sector != stream.next {
+            stream.next = sector + count;
+            stream.window = 0;
+            return None;
+        }
+        stream.next = sector + count;
+        stream.window = (stream.window * 2).clamp(MIN_READAHEAD, MAX_READAHEAD);
+
+        // Most of the window will already be there from the last read ahead
+        let end = sector + count + stream.window;
+        let first = (sector + count..end).find(|&s| !self.blocks.contains_key(&(key, s)))?;
+        Some((first, end - first))
+    }
+
+    /// Takes a copy of every dirty block, marking them clean
+    fn take_dirty(&mut self) -> Vec<(BlockKey, Box<[u8; 512]>)> {
+        self.blocks
+            .iter_mut()
+            .filter(|(_, b)| b.dirty)
+            .map(|(&key, b)| {
+                b.dirty = false;
+                (key, b.data.clone())
+            })
+            .collect()
+    }
+}
+
+/// The capacity scaled down by the current memory pressure
+fn limit() -> usize {
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    match memory_pressure() {
+        MemoryPressure::Ok => capacity,
+        MemoryPressure::Low => capacity / 4,
+        MemoryPressure::Critical => 0,
+    }
+}
+
+/// Sets how many sectors can be cached when memory isn't short
+pub fn set_capacity(sectors: usize) {
+    CAPACITY.store(sectors, Ordering::Relaxed);
+    shrink_to_limit();
+}
+
+/// Evicts blocks until the cache is within its limit, flushing if only dirty blocks are left
+fn shrink_to_limit() {
+    if BLOCK_CACHE.lock().evict(limit()) > 0 {
+        flush();
+        BLOCK_CACHE.lock().evict(limit());
+    }
+}
+
+/// The number of cached sectors and how many of them are dirty
+pub fn stats() -> (usize, usize) {
+    let cache = BLOCK_CACHE.lock();
+    let dirty = cache.blocks.values().filter(|b| b.dirty).count();
+    (cache.blocks.len(), dirty)
+}
+
+/// Writes the blocks to their disks, consecutive sectors are written together
+fn write_blocks(mut blocks: Vec<(BlockKey, Box<[u8; 512]>)>) {
+    if blocks.is_empty() {
+        return;
+    }
+    blocks.sort_by_key(|(key, _)| *key);
+    let disks = BLOCK_CACHE.lock().disks.clone();
+
+    let mut blocks = blocks.into_iter().peekable();
+    while let Some(((disk, first), data)) = blocks.next() {
+        let mut run = data.to_vec();
+        let mut count = 1;
+        while let Some(((d, s), _)) = blocks.peek() {
+            if *d != disk || *s != first + count || count == MAX_FLUSH_RUN {
+                break;
+            }
+            run.extend_from_slice(&blocks.next().unwrap().1[..]);
+            count += 1;
+        }
+
+        let Some(device) = disks.get(&disk) else {
+            error!("Block cache has dirty sectors for an unknown disk");
+            continue;
+        };
+        if device.lock().write(first, count as u32, &mut run).is_none() {
+            error!("Failed to write back sectors {first}..{}", first + count);
+        }
+    }
+}
+
+/// Reads sectors through the cache, the disk is only read if a sector isn't cached
+pub fn read(disk: &DiskRef, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+    let key = disk_key(disk);
+    let buffer = &mut buffer[..sector_count as usize * 512];
+
+    let mut missing = false;
+    let ahead = {
+        let mut cache = BLOCK_CACHE.lock();
+        for (i, chunk) in buffer.chunks_mut(512).enumerate() {
+            match cache.get((key, sector + i)) {
+                Some(block) => chunk.copy_from_slice(&block.data[..]),
+                None => missing = true,
+            }
+        }
+        cache.track(key, sector, sector_count as usize)
+    };
+    if let Some((sector, count)) = ahead {
+        queue_readahead(disk, sector, count);
+    }
+    if !missing {
+        return Some(());
+    }
+
+    disk.lock().read(sector, sector_count, buffer)?;
+
+    let mut cache = BLOCK_CACHE.lock();
+    for (i, chunk) in buffer.chunks_mut(512).enumerate() {
+        // Anything written while we were reading is newer than the disk
+        match cache.get((key, sector + i)) {
+            Some(block) => chunk.copy_from_slice(&block.data[..]),
+            None => cache.insert((key, sector + i), chunk, false),
+        }
+    }
+    drop(cache);
+    shrink_to_limit();
+    Some(())
+}
+
+/// Writes sectors into the cache, they reach the disk on the next [`flush`]
+pub fn write(disk: &DiskRef, sector: usize, sector_count: u32, buffer: &[u8]) -> Option<()> {
+    let key = disk_key(disk);
+    let buffer = &buffer[..sector_count as usize * 512];
+
+    let mut cache = BLOCK_CACHE.lock();
+    cache.disks.entry(key).or_insert_with(|| disk.clone());
+    for (i, chunk) in buffer.chunks(512).enumerate() {
+        cache.insert((key, sector + i), chunk, true);
+    }
+    drop(cache);
+    shrink_to_limit();
+    Some(())
+}
+
+/// Writes every dirty sector to its disk
+pub fn flush() {
+    let dirty = BLOCK_CACHE.lock().take_dirty();
+    write_blocks(dirty);
+}
+
+/// Drops every block of a disk that has gone away, anything dirty is lost
+pub fn forget(disk: &DiskRef) {
+    let key = disk_key(disk);
+    let mut cache = BLOCK_CACHE.lock();
+    let cache = &mut *cache;
+    let mut lost = 0;
+    cache.blocks.retain(|&(d, _), b| {
+        if d != key {
+            return true;
+        }
+        lost += b.dirty as usize;
+        cache.lru.remove(&b.last_used);
+        false
+    });
+    cache.disks.remove(&key);
+    cache.streams.remove(&key);
+    let mut readahead = READAHEAD.lock();
+    readahead.queue.retain(|r| disk_key(&r.disk) != key);
+    readahead.sizes.remove(&key);
+    drop(readahead);
+    if lost > 0 {
+        warn!("Lost {lost} unwritten sectors of a removed disk");
+    }
+}
+
+fn queue_readahead(disk: &DiskRef, sector: usize, count: usize) {
+    let mut readahead = READAHEAD.lock();
+    readahead.queue.push_back(ReadaheadRequest {
+        disk: disk.clone(),
+        sector,
+        count,
+    });
+    let waker = readahead.thread.clone();
+    drop(readahead);
+
+    // Anything queued before the task starts is picked up on its first pass
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+/// Reads the queued sectors into the cache. The requests are sorted by disk and sector and
+/// overlapping or adjacent ones are merged so the disk sees as few reads as possible.
+pub fn readahead_task() {
+    let this = unsafe { CPULocalStorageRW::get_current_task() };
+    READAHEAD.lock().thread = Some(this.thread());
+
+    loop {
+        let mut readahead = READAHEAD.lock();
+        if readahead.queue.is_empty() {
+            let mut sched = this.sched().lock();
+            sched.state = ThreadState::Sleeping;
+            drop(readahead);
+            enter_sched(&mut sched);
+            continue;
+        }
+        let mut requests: Vec<_> = readahead.queue.drain(..).collect();
+        drop(readahead);
+
+        requests.sort_by_key(|r| (disk_key(&r.disk), r.sector));
+        let mut requests = requests.into_iter().peekable();
+        while let Some(first) = requests.next() {
+            let key = disk_key(&first.disk);
+            let mut end = first.sector + first.count;
+            while let Some(next) = requests.peek() {
+                if disk_key(&next.disk) != key
+                    || next.sector > end
+                    || next.sector + next.count - first.sector > MAX_READAHEAD * 2
+                {
+                    break;
+                }
+                end = end.max(next.sector + next.count);
+                requests.next();
+            }
+            read_ahead(&first.disk, first.sector, end);
+        }
+    }
+}
+
+/// Reads the sectors from start to end that aren't cached yet
+fn read_ahead(disk: &DiskRef, start: usize, end: usize) {
+    let key = disk_key(disk);
+    let size = *READAHEAD
+        .lock()
+        .sizes
+        .entry(key)
+        .or_insert_with(|| disk.lock().identify().sector_count() as usize);
+    let end = end.min(size);
+
+    let (start, end) = {
+        let cache = BLOCK_CACHE.lock();
+        let cached = |s: &usize| cache.blocks.contains_key(&(key, *s));
+        let Some(start) = (start..end).find(|s| !cached(s)) else {
+            return;
+        };
+        let end = (start..end).rev().find(|s| !cached(s)).unwrap() + 1;
+        (start, end)
+    };
+
+    let mut buffer = vec![0; (end - start) * 512];
+    if disk
+        .lock()
+        .read(start, (end - start) as u32, &mut buffer)
+        .is_none()
+    {
+        return;
+    }
+
+    let mut cache = BLOCK_CACHE.lock();
+    for (i, chunk) in buffer.chunks(512).enumerate() {
+        // Anything written or read in the meantime is at least as new
+        if !cache.blocks.contains_key(&(key, start + i)) {
+            cache.insert((key, start + i), chunk, false);
+        }
+    }
+    drop(cache);
+    shrink_to_limit();
+}
+
+/// Drops clean blocks, oldest first
+pub struct BlockCacheShrinker;
+
+impl Shrinker for BlockCacheShrinker {
+    fn name(&self) -> &'static str {
+        "block cache"
+    }
+
+    fn shrink(&self, pages: usize, _level: MemoryPressure) -> usize {
+        let mut cache = BLOCK_CACHE.lock();
+        let cache = &mut *cache;
+        let mut dropped = 0;
+        let mut freed = Vec::new();
+        for (&used, key) in cache.lru.iter() {
+            if dropped == pages * 8 {
+                break;
+            }
+            if !cache.blocks[key].dirty {
+                freed.push(used);
+                cache.blocks.remove(key);
+                dropped += 1;
+            }
+        }
+        for used in freed {
+            cache.lru.remove(&used);
+        }
+        dropped / 8
+    }
+}
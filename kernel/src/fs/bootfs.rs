@@ -0,0 +1,85 @@
+//! The files built into the kernel image, mounted read only at `/boot`.
+//!
+//! The root folder is file id 0 and each file is its index in [`FILES`] plus one. The data is
+//! already in memory so nothing goes through the page cache.
+
+use alloc::{string::String, vec::Vec};
+use kernel_userspace::fs::FSServiceError;
+
+use crate::bootfs::FILES;
+
+use super::{
+    add_partition, next_partition_id, vfs, FileSystemDev, PartitionId, VFile, VFileSpecialized,
+};
+
+pub struct BootFs {
+    partition_id: PartitionId,
+}
+
+pub fn mount_bootfs(path: &str) {
+    let partition_id = next_partition_id();
+    add_partition(partition_id, BootFs { partition_id });
+    if let Err(e) = vfs::mount(partition_id, path) {
+        warn!("Failed to mount bootfs at {path}: {e:?}");
+    }
+}
+
+fn contents(file_id: usize) -> Result<&'static [u8], FSServiceError> {
+    match file_id.checked_sub(1) {
+        None => Err(FSServiceError::InvalidRequestForFileType),
+        Some(i) => FILES
+            .get(i)
+            .map(|f| f.1)
+            .ok_or(FSServiceError::FileNotFound),
+    }
+}
+
+impl FileSystemDev for BootFs {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let partition_id = self.partition_id;
+        let specialized = match file_id {
+            0 => VFileSpecialized::Folder(
+                FILES
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, _))| (String::from(*name), (partition_id, i + 1)))
+                    .collect(),
+            ),
+            _ => VFileSpecialized::File(contents(file_id)?.len()),
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        buffer.clear();
+        buffer.extend_from_slice(contents(file_id)?);
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        let data = contents(file_id)?;
+        let start = file_sector * 512;
+        if start >= data.len() {
+            return Ok(None);
+        }
+        let len = (data.len() - start).min(512);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(Some(len))
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,339 @@
+//! Devices as files, mounted at `/dev`.
+//!
+//! Drivers register a [`Device`] under a name and reads and writes on the file are handed straight
+//! to it, nothing goes through the page cache. Input devices are streams, they report a size of 0
+//! and a read returns the postcard encoded [`InputServiceMessage`]s that came in since the last
+//! read.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec,
+    vec::Vec,
+};
+use kernel_userspace::{
+    fs::FSServiceError,
+    input::{InputClient, InputServiceMessage},
+    service::serialize,
+    syscall::spawn_thread,
+};
+
+use crate::{
+    cpu_localstorage::CPULocalStorageRW,
+    driver::disk::DiskDevice,
+    mutex::{Mutex, Spinlock},
+    paging::{offset_map::get_gop_range, MemoryMappingFlags},
+    scheduling::with_held_interrupts,
+    BOOT_INFO,
+};
+
+use super::{
+    add_partition, block_cache, next_partition_id, vfs, FileSystemDev, PartitionId, VFile,
+    VFileSpecialized,
+};
+
+pub trait Device: Send + Sync {
+    /// Size in bytes, streams are always 0
+    fn size(&self) -> usize;
+
+    /// Reads from the offset, returns how much was read
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, FSServiceError>;
+
+    /// Writes at the offset, devices can't grow
+    fn write(&mut self, _offset: usize, _data: &[u8]) -> Result<(), FSServiceError> {
+        Err(FSServiceError::ReadOnly)
+    }
+}
+
+type NamedDevice = (String, Box<dyn Device>);
+
+/// Indexed by file id, the root folder is 0
+static DEVICES: Spinlock<BTreeMap<usize, NamedDevice>> = Spinlock::new(BTreeMap::new());
+
+/// Adds a device to `/dev`, returns its file id
+pub fn register(name: String, device: Box<dyn Device>) -> usize {
+    let mut devices = DEVICES.lock();
+    let id = devices.last_key_value().map(|e| *e.0 + 1).unwrap_or(1);
+    info!("Registered /dev/{name}");
+    devices.insert(id, (name, device));
+    id
+}
+
+/// Removes a device from `/dev`
+pub fn unregister(name: &str) {
+    DEVICES.lock().retain(|_, (n, _)| n != name);
+}
+
+pub struct DevFs {
+    partition_id: PartitionId,
+}
+
+/// Mounts `/dev` and registers the devices the kernel knows about itself
+pub fn mount_devfs(path: &str) {
+    let partition_id = next_partition_id();
+    add_partition(partition_id, DevFs { partition_id });
+    if let Err(e) = vfs::mount(partition_id, path) {
+        warn!("Failed to mount devfs at {path}: {e:?}");
+        return;
+    }
+
+    register(String::from("fb0"), Box::new(Framebuffer::new()));
+    spawn_thread(keyboard_task);
+    spawn_thread(mouse_task);
+}
+
+impl FileSystemDev for DevFs {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let devices = DEVICES.lock();
+        let specialized = if file_id == 0 {
+            VFileSpecialized::Folder(
+                devices
+                    .iter()
+                    .map(|(&id, (name, _))| (name.clone(), (self.partition_id, id)))
+                    .collect(),
+            )
+        } else {
+            let (_, device) = devices.get(&file_id).ok_or(FSServiceError::FileNotFound)?;
+            VFileSpecialized::File(device.size())
+        };
+        Ok(VFile {
+            location: (self.partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        with_device(file_id, |device| {
+            buffer.resize(device.size().max(0x1000), 0);
+            let len = device.read(0, buffer)?;
+            buffer.truncate(len);
+            Ok(())
+        })?;
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        with_device(file_id, |device| {
+            let offset = file_sector * 512;
+            // Streams don't have an end
+            if device.size() != 0 && offset >= device.size() {
+                return Ok(None);
+            }
+            device.read(offset, buffer).map(Some)
+        })
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+
+    fn write_file_direct(
+        &mut self,
+        file_id: usize,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), FSServiceError> {
+        with_device(file_id, |device| device.write(offset, data))
+    }
+}
+
+fn with_device<R>(
+    file_id: usize,
+    f: impl FnOnce(&mut Box<dyn Device>) -> Result<R, FSServiceError>,
+) -> Result<R, FSServiceError> {
+    let mut devices = DEVICES.lock();
+    match devices.get_mut(&file_id) {
+        Some((_, device)) => f(device),
+        None if file_id == 0 => Err(FSServiceError::InvalidRequestForFileType),
+        None => Err(FSServiceError::FileNotFound),
+    }
+}
+
+/// Checks the range is inside of a device of the size
+fn check_range(size: usize, offset: usize, len: usize) -> Result<(), FSServiceError> {
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(FSServiceError::FileTooLarge),
+    }
+}
+
+/// A whole disk, reads and writes don't have to be sector aligned
+pub struct DiskNode {
+    disk: Arc<Mutex<dyn DiskDevice>>,
+    sectors: usize,
+}
+
+impl DiskNode {
+    pub fn new(disk: Arc<Mutex<dyn DiskDevice>>) -> Self {
+        let sectors = disk.lock().identify().sector_count() as usize;
+        Self { disk, sectors }
+    }
+}
+
+impl Device for DiskNode {
+    fn size(&self) -> usize {
+        self.sectors * 512
+    }
+
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, FSServiceError> {
+        let len = buffer.len().min(self.size().saturating_sub(offset));
+        if len == 0 {
+            return Ok(0);
+        }
+        let first = offset / 512;
+        let count = (offset + len).div_ceil(512) - first;
+        let mut sectors = vec![0; count * 512];
+        block_cache::read(&self.disk, first, count as u32, &mut sectors)
+            .ok_or(FSServiceError::DiskError)?;
+
+        let start = offset % 512;
+        buffer[..len].copy_from_slice(&sectors[start..start + len]);
+        Ok(len)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FSServiceError> {
+        check_range(self.size(), offset, data.len())?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let first = offset / 512;
+        let count = (offset + data.len()).div_ceil(512) - first;
+        let mut sectors = vec![0; count * 512];
+
+        // Partial sectors at the ends have to keep what was there
+        let start = offset % 512;
+        if start != 0 || data.len() % 512 != 0 {
+            block_cache::read(&self.disk, first, count as u32, &mut sectors)
+                .ok_or(FSServiceError::DiskError)?;
+        }
+        sectors[start..start + data.len()].copy_from_slice(data);
+        block_cache::write(&self.disk, first, count as u32, &sectors)
+            .ok_or(FSServiceError::DiskError)?;
+        block_cache::flush();
+        Ok(())
+    }
+}
+
+/// The GOP framebuffer, 4 bytes a pixel with each line `stride` pixels long
+pub struct Framebuffer {
+    buffer: *mut u8,
+    size: usize,
+}
+
+// The framebuffer is only touched with the devices locked
+unsafe impl Send for Framebuffer {}
+unsafe impl Sync for Framebuffer {}
+
+impl Framebuffer {
+    /// Maps the framebuffer into the current process, this has to be the process the FS service
+    /// runs in
+    fn new() -> Self {
+        with_held_interrupts(|| unsafe {
+            let gop = get_gop_range(&(*BOOT_INFO).gop);
+            let proc = CPULocalStorageRW::get_current_task().process();
+            let mut mem = proc.memory.lock();
+
+            mem.page_mapper
+                .insert_mapping_at_set(
+                    gop.0,
+                    gop.1,
+                    MemoryMappingFlags::WRITEABLE | MemoryMappingFlags::WRITE_COMBINING,
+                )
+                .unwrap();
+
+            Self {
+                buffer: *(*BOOT_INFO).gop.buffer.as_ptr(),
+                size: (*BOOT_INFO).gop.buffer_size,
+            }
+        })
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, FSServiceError> {
+        let len = buffer.len().min(self.size.saturating_sub(offset));
+        unsafe { core::ptr::copy_nonoverlapping(self.buffer.add(offset), buffer.as_mut_ptr(), len) }
+        Ok(len)
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FSServiceError> {
+        check_range(self.size, offset, data.len())?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.buffer.add(offset), data.len())
+        }
+        Ok(())
+    }
+}
+
+/// Events that haven't been read yet, old events are dropped once it is full
+pub struct InputQueue {
+    events: Arc<Spinlock<VecDeque<InputServiceMessage>>>,
+}
+
+const INPUT_QUEUE_LEN: usize = 256;
+
+impl Device for InputQueue {
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn read(&mut self, _offset: usize, buffer: &mut [u8]) -> Result<usize, FSServiceError> {
+        let mut events = self.events.lock();
+        let mut len = 0;
+        let mut msg = Vec::new();
+        while let Some(ev) = events.front() {
+            let data = serialize(ev, &mut msg);
+            if len + data.len() > buffer.len() {
+                break;
+            }
+            buffer[len..len + data.len()].copy_from_slice(data);
+            len += data.len();
+            events.pop_front();
+        }
+        Ok(len)
+    }
+}
+
+/// Registers the device and forwards every event from the input service into it
+fn input_task(service: &'static str, name: &str) {
+    let events = Arc::new(Spinlock::new(VecDeque::new()));
+    register(
+        String::from(name),
+        Box::new(InputQueue {
+            events: events.clone(),
+        }),
+    );
+
+    let mut input = InputClient::new(service);
+    loop {
+        let ev = input.recv_val();
+        let mut events = events.lock();
+        if events.len() == INPUT_QUEUE_LEN {
+            events.pop_front();
+        }
+        events.push_back(ev);
+    }
+}
+
+fn keyboard_task() {
+    input_task("INPUT:KB", "kb");
+}
+
+fn mouse_task() {
+    input_task("INPUT:MOUSE", "mouse");
+}
@@ -0,0 +1,389 @@
+//! Read only exFAT driver, for USB sticks and SD cards too large for FAT32.
+//!
+//! Names are matched exactly, the up-case table isn't used. File ids are handed out as folders
+//! are read, the root is always file id 0.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use kernel_userspace::fs::FSServiceError;
+
+use super::{
+    add_partition, next_partition_id, vfs, FSPartitionDisk, FileSystemDev, PartitionId, VFile,
+    VFileSpecialized,
+};
+
+const EXFAT_NAME: &[u8; 8] = b"EXFAT   ";
+const END_OF_CHAIN: u32 = 0xFFFFFFFF;
+/// Anything above this in the FAT is bad or the end of a chain
+const MAX_CLUSTER: u32 = 0xFFFFFFF6;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+const ENTRY_SIZE: usize = 32;
+/// Characters in each file name entry
+const NAME_CHARS: usize = 15;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+/// The file is contiguous and has no chain in the FAT
+const STREAM_NO_FAT_CHAIN: u8 = 0x2;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct BootSector {
+    _jump: [u8; 3],
+    name: [u8; 8],
+    /// Where the BPB would be on FAT, all zero
+    _zero: [u8; 53],
+    _partition_offset: u64,
+    _volume_length: u64,
+    fat_offset: u32,
+    _fat_length: u32,
+    cluster_heap_offset: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+    _serial: u32,
+    revision: u16,
+    _volume_flags: u16,
+    bytes_per_sector_shift: u8,
+    sectors_per_cluster_shift: u8,
+    _fat_count: u8,
+    _drive_select: u8,
+    _percent_in_use: u8,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct FileEntry {
+    _entry_type: u8,
+    secondary_count: u8,
+    _checksum: u16,
+    attributes: u16,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct StreamEntry {
+    _entry_type: u8,
+    flags: u8,
+    _reserved: u8,
+    name_length: u8,
+    _name_hash: u16,
+    _reserved2: u16,
+    valid_data_length: u64,
+    _reserved3: u32,
+    first_cluster: u32,
+    data_length: u64,
+}
+
+struct ExFatFile {
+    first_cluster: u32,
+    /// Anything between this and the size reads as zeros
+    valid_size: usize,
+    contiguous: bool,
+    kind: ExFatFileType,
+}
+
+enum ExFatFileType {
+    /// Children by name, read when first needed
+    Folder(Option<BTreeMap<String, usize>>),
+    File(usize),
+}
+
+pub struct ExFat {
+    partition_id: PartitionId,
+    disk: FSPartitionDisk,
+    /// Disk sectors to each exFAT sector
+    sector_scale: usize,
+    fat_offset: usize,
+    cluster_heap_offset: usize,
+    cluster_count: u32,
+    cluster_bytes: usize,
+    files: BTreeMap<usize, ExFatFile>,
+    next_file_id: usize,
+}
+
+impl ExFat {
+    fn get_file(&self, file_id: usize) -> Result<&ExFatFile, FSServiceError> {
+        self.files.get(&file_id).ok_or(FSServiceError::FileNotFound)
+    }
+
+    fn next_cluster(&self, cluster: u32) -> Result<u32, FSServiceError> {
+        let offset = cluster as usize * 4;
+        let sector = self.fat_offset * self.sector_scale + offset / 512;
+        let buffer = &mut [0u8; 512];
+        self.disk
+            .read(sector, 1, buffer)
+            .ok_or(FSServiceError::DiskError)?;
+        let offset = offset % 512;
+        Ok(u32::from_le_bytes(
+            buffer[offset..offset + 4].try_into().unwrap(),
+        ))
+    }
+
+    /// The disk cluster holding the index'th cluster of the file
+    fn file_cluster(&self, file: &ExFatFile, index: usize) -> Result<u32, FSServiceError> {
+        if file.contiguous {
+            return Ok(file.first_cluster + index as u32);
+        }
+        let mut cluster = file.first_cluster;
+        for _ in 0..index {
+            cluster = self.next_cluster(cluster)?;
+            if cluster > MAX_CLUSTER {
+                return Err(FSServiceError::CorruptedFilesystem);
+            }
+        }
+        Ok(cluster)
+    }
+
+    /// Reads from the file at offset, buffer must not go past the end of the file
+    fn read_at(
+        &self,
+        file: &ExFatFile,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), FSServiceError> {
+        let mut done = 0;
+        while done < buffer.len() {
+            let pos = offset + done;
+            let in_cluster = pos % self.cluster_bytes;
+            let len = (self.cluster_bytes - in_cluster).min(buffer.len() - done);
+            let chunk = &mut buffer[done..done + len];
+            done += len;
+
+            if pos >= file.valid_size {
+                chunk.fill(0);
+                continue;
+            }
+            let cluster = self.file_cluster(file, pos / self.cluster_bytes)?;
+            if cluster < 2 || cluster >= self.cluster_count + 2 {
+                return Err(FSServiceError::CorruptedFilesystem);
+            }
+
+            let start = (self.cluster_heap_offset * self.sector_scale) * 512
+                + (cluster as usize - 2) * self.cluster_bytes
+                + in_cluster;
+            let first = start / 512;
+            let count = (start + len).div_ceil(512) - first;
+            let mut sectors = vec![0u8; count * 512];
+            self.disk
+                .read(first, count as u32, &mut sectors)
+                .ok_or(FSServiceError::DiskError)?;
+            let skip = start % 512;
+            chunk.copy_from_slice(&sectors[skip..skip + len]);
+        }
+        // The cluster holding the end of the valid data is read in full
+        let valid = file.valid_size.saturating_sub(offset).min(buffer.len());
+        buffer[valid..].fill(0);
+        Ok(())
+    }
+
+    fn read_directory(
+        &mut self,
+        file_id: usize,
+    ) -> Result<BTreeMap<String, usize>, FSServiceError> {
+        let dir = self.get_file(file_id)?;
+        if let ExFatFileType::Folder(Some(children)) = &dir.kind {
+            return Ok(children.clone());
+        }
+        let mut data = vec![0u8; dir.valid_size];
+        self.read_at(dir, 0, &mut data)?;
+
+        let mut children = BTreeMap::new();
+        let mut entries = data.chunks_exact(ENTRY_SIZE);
+        while let Some(entry) = entries.next() {
+            match entry[0] {
+                ENTRY_END => break,
+                ENTRY_FILE => (),
+                // Bitmap, up-case table, label or an unused entry
+                _ => continue,
+            }
+            let file = unsafe { *(entry.as_ptr() as *const FileEntry) };
+            let secondary: Vec<&[u8]> = entries
+                .by_ref()
+                .take(file.secondary_count as usize)
+                .collect();
+            let Some(stream) = secondary.first().filter(|s| s[0] == ENTRY_STREAM) else {
+                warn!("exFAT file entry without a stream extension");
+                continue;
+            };
+            let stream = unsafe { *(stream.as_ptr() as *const StreamEntry) };
+
+            let name_units: Vec<u16> = secondary
+                .iter()
+                .filter(|e| e[0] == ENTRY_NAME)
+                .flat_map(|e| {
+                    e[2..2 + NAME_CHARS * 2]
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                })
+                .take(stream.name_length as usize)
+                .collect();
+            let name: String = char::decode_utf16(name_units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+
+            let size = stream.data_length as usize;
+            let kind = match { file.attributes } & ATTR_DIRECTORY != 0 {
+                true => ExFatFileType::Folder(None),
+                false => ExFatFileType::File(size),
+            };
+            let id = self.next_file_id;
+            self.next_file_id += 1;
+            self.files.insert(
+                id,
+                ExFatFile {
+                    first_cluster: stream.first_cluster,
+                    valid_size: (stream.valid_data_length as usize).min(size),
+                    contiguous: stream.flags & STREAM_NO_FAT_CHAIN != 0,
+                    kind,
+                },
+            );
+            children.insert(name, id);
+        }
+
+        if let Some(ExFatFile {
+            kind: ExFatFileType::Folder(cached),
+            ..
+        }) = self.files.get_mut(&file_id)
+        {
+            *cached = Some(children.clone());
+        }
+        Ok(children)
+    }
+
+    /// The size of a chain in the FAT, only needed for the root as it has no stream entry
+    fn chain_bytes(&self, mut cluster: u32) -> Result<usize, FSServiceError> {
+        let mut clusters = 1;
+        loop {
+            cluster = self.next_cluster(cluster)?;
+            if cluster == END_OF_CHAIN {
+                return Ok(clusters * self.cluster_bytes);
+            }
+            if cluster > MAX_CLUSTER || clusters > self.cluster_count as usize {
+                return Err(FSServiceError::CorruptedFilesystem);
+            }
+            clusters += 1;
+        }
+    }
+
+    fn file_size(&self, file_id: usize) -> Result<usize, FSServiceError> {
+        match self.get_file(file_id)?.kind {
+            ExFatFileType::File(size) => Ok(size),
+            ExFatFileType::Folder(_) => Err(FSServiceError::InvalidRequestForFileType),
+        }
+    }
+}
+
+pub fn read_boot_sector(disk: FSPartitionDisk) -> Option<PartitionId> {
+    let buffer = &mut [0u8; 512];
+    disk.read(0, 1, buffer)?;
+    let boot = unsafe { *(buffer.as_ptr() as *const BootSector) };
+
+    if &boot.name != EXFAT_NAME || buffer[510..] != [0x55, 0xAA] {
+        warn!("Partition isn't exFAT");
+        return None;
+    }
+    if boot.revision >> 8 != 1 {
+        warn!("exFAT revision {:#x} isn't supported", { boot.revision });
+        return None;
+    }
+    if !(9..=12).contains(&boot.bytes_per_sector_shift) {
+        warn!("exFAT sector size isn't valid");
+        return None;
+    }
+
+    let sector_scale = 1 << (boot.bytes_per_sector_shift - 9);
+    let cluster_bytes = 1 << (boot.bytes_per_sector_shift + boot.sectors_per_cluster_shift);
+    let partition_id = next_partition_id();
+    let mut exfat = ExFat {
+        partition_id,
+        disk,
+        sector_scale,
+        fat_offset: boot.fat_offset as usize,
+        cluster_heap_offset: boot.cluster_heap_offset as usize,
+        cluster_count: boot.cluster_count,
+        cluster_bytes,
+        files: BTreeMap::new(),
+        next_file_id: 1,
+    };
+
+    let root_size = match exfat.chain_bytes(boot.root_cluster) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to read the exFAT root directory: {e:?}");
+            return None;
+        }
+    };
+    exfat.files.insert(
+        0,
+        ExFatFile {
+            first_cluster: boot.root_cluster,
+            valid_size: root_size,
+            contiguous: false,
+            kind: ExFatFileType::Folder(None),
+        },
+    );
+
+    add_partition(partition_id, exfat);
+    vfs::auto_mount(partition_id);
+    Some(partition_id)
+}
+
+impl FileSystemDev for ExFat {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let partition_id = self.partition_id;
+        let file = self.get_file(file_id)?;
+
+        let specialized = match file.kind {
+            ExFatFileType::File(size) => VFileSpecialized::File(size),
+            ExFatFileType::Folder(_) => VFileSpecialized::Folder(
+                self.read_directory(file_id)?
+                    .into_iter()
+                    .map(|(name, id)| (name, (partition_id, id)))
+                    .collect(),
+            ),
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        buffer.resize(self.file_size(file_id)?, 0);
+        self.read_at(self.get_file(file_id)?, 0, buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        let size = self.file_size(file_id)?;
+        let start = file_sector * 512;
+        if start >= size {
+            return Ok(None);
+        }
+        let len = (size - start).min(512);
+        self.read_at(self.get_file(file_id)?, start, &mut buffer[..len])?;
+        Ok(Some(len))
+    }
+
+    fn read_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        buffer: &mut [u8; 0x1000],
+    ) -> Result<(), FSServiceError> {
+        let start = page * 0x1000;
+        let len = self.file_size(file_id)?.saturating_sub(start).min(0x1000);
+        self.read_at(self.get_file(file_id)?, start, &mut buffer[..len])
+    }
+}
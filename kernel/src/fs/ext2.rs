@@ -0,0 +1,437 @@
+//! Read only ext2 driver, so that disks made with the usual Linux tools can be read.
+//!
+//! File ids are inode numbers, except for the root which is always file id 0.
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use kernel_userspace::fs::FSServiceError;
+
+use super::{
+    add_partition, next_partition_id, vfs, FSPartitionDisk, FileSystemDev, PartitionId, VFile,
+    VFileSpecialized,
+};
+
+const EXT2_MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+/// Anything else changes the on disk layout
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_REGULAR: u16 = 0x8000;
+const MODE_SYMLINK: u16 = 0xA000;
+/// Targets shorter than this are kept in the block pointers instead of a data block
+const FAST_SYMLINK_LEN: usize = 60;
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Superblock {
+    inodes_count: u32,
+    blocks_count: u32,
+    _r_blocks_count: u32,
+    _free_blocks_count: u32,
+    _free_inodes_count: u32,
+    first_data_block: u32,
+    log_block_size: u32,
+    _log_frag_size: u32,
+    blocks_per_group: u32,
+    _frags_per_group: u32,
+    inodes_per_group: u32,
+    _mtime: u32,
+    _wtime: u32,
+    _mnt_count: u16,
+    _max_mnt_count: u16,
+    magic: u16,
+    state: u16,
+    _errors: u16,
+    _minor_rev_level: u16,
+    _lastcheck: u32,
+    _checkinterval: u32,
+    _creator_os: u32,
+    rev_level: u32,
+    _def_resuid: u16,
+    _def_resgid: u16,
+    // Only valid from revision 1
+    _first_ino: u32,
+    inode_size: u16,
+    _block_group_nr: u16,
+    _feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct GroupDescriptor {
+    _block_bitmap: u32,
+    _inode_bitmap: u32,
+    inode_table: u32,
+    _free_blocks_count: u16,
+    _free_inodes_count: u16,
+    _used_dirs_count: u16,
+    _pad: u16,
+    _reserved: [u8; 12],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Inode {
+    mode: u16,
+    _uid: u16,
+    size: u32,
+    _atime: u32,
+    _ctime: u32,
+    _mtime: u32,
+    _dtime: u32,
+    _gid: u16,
+    _links_count: u16,
+    /// 512 byte sectors in use, 0 for fast symlinks
+    sectors: u32,
+    _flags: u32,
+    _osd1: u32,
+    block: [u32; 15],
+    _generation: u32,
+    _file_acl: u32,
+    size_high: u32,
+    _faddr: u32,
+    _osd2: [u8; 12],
+}
+
+#[repr(C, packed)]
+pub struct DirectoryEntry {
+    inode: u32,
+    rec_len: u16,
+    name_len: u8,
+    _file_type: u8,
+}
+
+pub struct Ext2 {
+    partition_id: PartitionId,
+    disk: FSPartitionDisk,
+    superblock: Superblock,
+    block_size: usize,
+    inode_size: usize,
+    /// First block of the inode table of each block group
+    inode_tables: Vec<u32>,
+    inodes: BTreeMap<u32, Inode>,
+    /// Children of the folders that have been read by inode
+    folders: BTreeMap<u32, BTreeMap<String, usize>>,
+}
+
+fn inode_number(file_id: usize) -> u32 {
+    match file_id {
+        0 => ROOT_INODE,
+        id => id as u32,
+    }
+}
+
+impl Ext2 {
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), FSServiceError> {
+        let sectors = self.block_size / 512;
+        self.disk
+            .read(block as usize * sectors, sectors as u32, buffer)
+            .ok_or(FSServiceError::DiskError)
+    }
+
+    fn get_inode(&mut self, inode: u32) -> Result<Inode, FSServiceError> {
+        if let Some(i) = self.inodes.get(&inode) {
+            return Ok(*i);
+        }
+        if inode == 0 || inode > self.superblock.inodes_count {
+            return Err(FSServiceError::FileNotFound);
+        }
+
+        let index = (inode - 1) as usize;
+        let per_group = self.superblock.inodes_per_group as usize;
+        let table = *self
+            .inode_tables
+            .get(index / per_group)
+            .ok_or(FSServiceError::FileNotFound)?;
+        let offset = (index % per_group) * self.inode_size;
+
+        // Inodes are at least 128 bytes so never cross a sector
+        let buffer = &mut [0u8; 512];
+        let sector = table as usize * (self.block_size / 512) + offset / 512;
+        self.disk
+            .read(sector, 1, buffer)
+            .ok_or(FSServiceError::DiskError)?;
+        let i = unsafe { *(buffer.as_ptr().add(offset % 512) as *const Inode) };
+        self.inodes.insert(inode, i);
+        Ok(i)
+    }
+
+    fn file_size(&self, inode: &Inode) -> usize {
+        let mut size = inode.size as usize;
+        // The high half is the directory acl for anything else
+        if inode.mode & MODE_TYPE_MASK == MODE_REGULAR
+            && self.superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE != 0
+        {
+            size |= (inode.size_high as usize) << 32;
+        }
+        size
+    }
+
+    fn read_symlink(&self, inode: &Inode) -> Result<String, FSServiceError> {
+        let len = inode.size as usize;
+        let target = if len < FAST_SYMLINK_LEN && { inode.sectors } == 0 {
+            let blocks = { inode.block };
+            let bytes = unsafe {
+                core::slice::from_raw_parts(blocks.as_ptr() as *const u8, FAST_SYMLINK_LEN)
+            };
+            bytes[..len].to_vec()
+        } else {
+            let mut target = vec![0; len];
+            self.read_at(inode, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FSServiceError::InvalidName)
+    }
+
+    /// The entry of an indirect block
+    fn read_indirect(&self, block: u32, entry: usize) -> Result<u32, FSServiceError> {
+        let buffer = &mut [0u8; 512];
+        let sector = block as usize * (self.block_size / 512) + entry * 4 / 512;
+        self.disk
+            .read(sector, 1, buffer)
+            .ok_or(FSServiceError::DiskError)?;
+        let offset = entry * 4 % 512;
+        Ok(u32::from_le_bytes(
+            buffer[offset..offset + 4].try_into().unwrap(),
+        ))
+    }
+
+    /// The disk block holding the index'th block of the file, 0 for holes
+    fn file_block(&self, inode: &Inode, mut index: usize) -> Result<u32, FSServiceError> {
+        let blocks = { inode.block };
+        if index < 12 {
+            return Ok(blocks[index]);
+        }
+        index -= 12;
+
+        // Single, double then triple indirect
+        let per_block = self.block_size / 4;
+        let mut span = 1;
+        for level in 0..3 {
+            span *= per_block;
+            if index >= span {
+                index -= span;
+                continue;
+            }
+            let mut block = blocks[12 + level];
+            let mut span = span;
+            for _ in 0..=level {
+                if block == 0 {
+                    return Ok(0);
+                }
+                span /= per_block;
+                block = self.read_indirect(block, index / span)?;
+                index %= span;
+            }
+            return Ok(block);
+        }
+        Err(FSServiceError::FileTooLarge)
+    }
+
+    /// Reads from the file at offset, buffer must not go past the end of the file
+    fn read_at(
+        &self,
+        inode: &Inode,
+        offset: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), FSServiceError> {
+        let mut block_buf = vec![0u8; self.block_size];
+        let mut done = 0;
+        while done < buffer.len() {
+            let pos = offset + done;
+            let in_block = pos % self.block_size;
+            let len = (self.block_size - in_block).min(buffer.len() - done);
+
+            match self.file_block(inode, pos / self.block_size)? {
+                0 => block_buf.fill(0),
+                block => self.read_block(block, &mut block_buf)?,
+            }
+            buffer[done..done + len].copy_from_slice(&block_buf[in_block..in_block + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn read_directory(&mut self, inode: u32) -> Result<&BTreeMap<String, usize>, FSServiceError> {
+        // Reading the folder needs all of self, so the entry is only taken once it is parsed
+        if !self.folders.contains_key(&inode) {
+            let dir = self.get_inode(inode)?;
+            let mut data = vec![0u8; self.file_size(&dir)];
+            self.read_at(&dir, 0, &mut data)?;
+
+            let mut children = BTreeMap::new();
+            let mut offset = 0;
+            while offset + core::mem::size_of::<DirectoryEntry>() <= data.len() {
+                let entry = unsafe { &*(data.as_ptr().add(offset) as *const DirectoryEntry) };
+                let rec_len = entry.rec_len as usize;
+                let name_start = offset + core::mem::size_of::<DirectoryEntry>();
+                let name_end = name_start + entry.name_len as usize;
+                if rec_len == 0 || name_end > data.len() {
+                    warn!("Corrupt ext2 directory {inode}");
+                    break;
+                }
+
+                let name = String::from_utf8_lossy(&data[name_start..name_end]);
+                // Unused entries have inode 0
+                if entry.inode != 0 && name != "." && name != ".." {
+                    children.insert(name.to_string(), entry.inode as usize);
+                }
+                offset += rec_len;
+            }
+            return Ok(self.folders.entry(inode).or_insert(children));
+        }
+        Ok(&self.folders[&inode])
+    }
+
+    /// The inode of a file, which can't be a folder
+    fn file_inode(&mut self, file_id: usize) -> Result<Inode, FSServiceError> {
+        let inode = self.get_inode(inode_number(file_id))?;
+        if inode.mode & MODE_TYPE_MASK == MODE_DIRECTORY {
+            return Err(FSServiceError::InvalidRequestForFileType);
+        }
+        Ok(inode)
+    }
+}
+
+pub fn read_superblock(disk: FSPartitionDisk) -> Option<PartitionId> {
+    // The superblock is always 1024 bytes in
+    let buffer = &mut [0u8; 1024];
+    disk.read(2, 2, buffer);
+    let superblock = unsafe { *(buffer.as_ptr() as *const Superblock) };
+
+    if { superblock.magic } != EXT2_MAGIC {
+        warn!("Linux partition isn't ext2");
+        return None;
+    }
+
+    let (inode_size, incompat) = match superblock.rev_level {
+        0 => (128, 0),
+        _ => (superblock.inode_size as usize, superblock.feature_incompat),
+    };
+    if incompat & !SUPPORTED_INCOMPAT != 0 {
+        warn!("ext2 partition uses unsupported features {incompat:#x}");
+        return None;
+    }
+    if { superblock.state } != 1 {
+        warn!("ext2 partition wasn't cleanly unmounted");
+    }
+
+    let block_size = 1024 << superblock.log_block_size;
+    let groups = superblock
+        .blocks_count
+        .div_ceil(superblock.blocks_per_group) as usize;
+
+    // The descriptors start in the block after the superblock
+    let descriptor_size = core::mem::size_of::<GroupDescriptor>();
+    let per_block = block_size / descriptor_size;
+    let mut inode_tables = Vec::with_capacity(groups);
+    let mut block_buf = vec![0u8; block_size];
+    for block in 0..groups.div_ceil(per_block) {
+        let sectors = block_size / 512;
+        let block = (superblock.first_data_block as usize + 1 + block) * sectors;
+        if disk.read(block, sectors as u32, &mut block_buf).is_none() {
+            warn!("Failed to read the ext2 group descriptors");
+            return None;
+        }
+        let descriptors = unsafe {
+            core::slice::from_raw_parts(block_buf.as_ptr() as *const GroupDescriptor, per_block)
+        };
+        inode_tables.extend(
+            descriptors
+                .iter()
+                .take(groups - inode_tables.len())
+                .map(|d| d.inode_table),
+        );
+    }
+
+    let partition_id = next_partition_id();
+    let ext2 = Ext2 {
+        partition_id,
+        disk,
+        superblock,
+        block_size,
+        inode_size,
+        inode_tables,
+        inodes: BTreeMap::new(),
+        folders: BTreeMap::new(),
+    };
+    add_partition(partition_id, ext2);
+    vfs::auto_mount(partition_id);
+    Some(partition_id)
+}
+
+impl FileSystemDev for Ext2 {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let number = inode_number(file_id);
+        let inode = self.get_inode(number)?;
+        let partition_id = self.partition_id;
+
+        let specialized = if inode.mode & MODE_TYPE_MASK == MODE_DIRECTORY {
+            VFileSpecialized::Folder(
+                self.read_directory(number)?
+                    .iter()
+                    .map(|(name, &id)| (name.clone(), (partition_id, id)))
+                    .collect(),
+            )
+        } else if inode.mode & MODE_TYPE_MASK == MODE_SYMLINK {
+            VFileSpecialized::Symlink(self.read_symlink(&inode)?)
+        } else {
+            VFileSpecialized::File(self.file_size(&inode))
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        let inode = self.file_inode(file_id)?;
+        buffer.resize(self.file_size(&inode), 0);
+        self.read_at(&inode, 0, buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        let inode = self.file_inode(file_id)?;
+        let size = self.file_size(&inode);
+        let start = file_sector * 512;
+        if start >= size {
+            return Ok(None);
+        }
+        let len = (size - start).min(512);
+        self.read_at(&inode, start, &mut buffer[..len])?;
+        Ok(Some(len))
+    }
+
+    fn read_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        buffer: &mut [u8; 0x1000],
+    ) -> Result<(), FSServiceError> {
+        let inode = self.file_inode(file_id)?;
+        let start = page * 0x1000;
+        let len = self.file_size(&inode).saturating_sub(start).min(0x1000);
+        self.read_at(&inode, start, &mut buffer[..len])
+    }
+}
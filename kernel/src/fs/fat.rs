@@ -11,9 +11,28 @@ use alloc::{
     string::{String, ToString},
     vec::Vec,
 };
-use kernel_userspace::fs::FSServiceError;
+use kernel_userspace::fs::{FSServiceError, FsProblem};
 
-use super::{next_partition_id, FSPartitionDisk, FileSystemDev, PartitionId, PARTITION};
+use super::{
+    add_partition, next_partition_id, vfs, FSPartitionDisk, FileSystemDev, PartitionId, VFile,
+    VFileSpecialized,
+};
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+/// Set on the first long file name entry, which holds the end of the name
+const LFN_LAST: u8 = 0x40;
+const LFN_ORDER_MASK: u8 = 0x1F;
+
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// 1980-01-01, there is no wall clock to stamp entries with yet
+const FAT_EPOCH: u16 = 1 << 5 | 1;
+
+const FS_INFO_LEAD_SIG: u32 = 0x41615252;
+const FS_INFO_STRUCT_SIG: u32 = 0x61417272;
 
 #[derive(Clone, Copy)]
 #[repr(C, packed)]
@@ -34,12 +53,14 @@ pub struct BiosParameterBlock {
     total_sectors_ext: u32,
 }
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct DirectoryEntry {
     name: [u8; 8],
     ext: [u8; 3],
     attributes: u8,
-    _reserved: u8,
+    /// Flags set by Windows for 8.3 names that are all lower case
+    case_flags: u8,
     c_time_tenth: u8,
     c_time: u16,
     c_date: u16,
@@ -51,6 +72,7 @@ pub struct DirectoryEntry {
     size: u32,
 }
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct LongFileName {
     order: u8,
@@ -111,8 +133,20 @@ pub struct FAT {
     pub disk: FSPartitionDisk,
     pub file_id_lookup: BTreeMap<usize, FATFile>,
     pub cluster_chain_buffer: BTreeMap<u32, Box<[u8]>>,
+    pub fs_info: Option<FsInfo>,
+}
+
+/// The allocation hints FAT32 keeps in the FSInfo sector
+pub struct FsInfo {
+    sector: u32,
+    /// u32::MAX if unknown
+    free_clusters: u32,
+    next_free: u32,
 }
 
+/// A directory entry on disk, the sector and index within it
+pub type EntrySlot = (u32, usize);
+
 pub fn next_file_id() -> usize {
     static ID: AtomicUsize = AtomicUsize::new(1);
     ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed)
@@ -122,6 +156,8 @@ pub fn next_file_id() -> usize {
 pub struct FATFile {
     cluster: u32,
     entry_type: FATFileType,
+    /// Long file name entries followed by the entry itself, empty for the root
+    slots: Vec<EntrySlot>,
 }
 
 #[derive(Debug, Clone)]
@@ -175,44 +211,274 @@ impl FAT {
 
     // pub fn get_cluster_from_sector()
 
-    pub fn get_next_cluster(&mut self, cluster: u32) -> u32 {
-        let fat_size = match self.fat_ebr {
+    fn fat_entry_size(&self) -> u32 {
+        match self.fat_ebr {
             FatExtendedBootRecord::FAT16(_) => 2,
             FatExtendedBootRecord::FAT32(_) => 4,
-        };
+        }
+    }
+
+    fn sectors_per_fat(&self) -> u32 {
+        match self.fat_ebr {
+            FatExtendedBootRecord::FAT16(_) => self.bios_parameter_block.fat_sector_cnt as u32,
+            FatExtendedBootRecord::FAT32(fat32) => fat32.sectors_per_fat,
+        }
+    }
+
+    /// One past the highest cluster number
+    pub fn cluster_count(&self) -> u32 {
         let bpb = self.bios_parameter_block;
+        let total_sectors = match bpb.total_sectors {
+            0 => bpb.total_sectors_ext,
+            n => n as u32,
+        };
+        (total_sectors - self.first_data_sector()) / bpb.sectors_per_cluster as u32 + 2
+    }
 
-        let fat_buf_offset = cluster / (512 / fat_size) + bpb.reserved_sectors as u32;
+    fn cluster_bytes(&self) -> u32 {
+        self.bios_parameter_block.sectors_per_cluster as u32 * 512
+    }
 
-        let fat_buffer = match self.cluster_chain_buffer.get(&fat_buf_offset) {
-            Some(b) => b,
-            None => {
-                let mut buf = unsafe { Box::new_uninit_slice(512).assume_init() };
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_ebr {
+            FatExtendedBootRecord::FAT16(_) => 0xFFFF,
+            FatExtendedBootRecord::FAT32(_) => 0x0FFFFFFF,
+        }
+    }
 
-                self.disk.read(fat_buf_offset as usize, 1, &mut buf);
+    /// If a chain stops before this cluster, free and bad clusters also end a chain
+    pub fn is_end_of_chain(&self, cluster: u32) -> bool {
+        cluster < 2 || cluster >= self.end_of_chain() - 8
+    }
 
-                self.cluster_chain_buffer.insert(fat_buf_offset, buf);
-                self.cluster_chain_buffer.get(&fat_buf_offset).unwrap()
+    /// FAT32 can turn off mirroring and only use one of the FATs
+    fn active_fat(&self) -> Option<u32> {
+        match self.fat_ebr {
+            FatExtendedBootRecord::FAT32(fat32) if fat32.flags & 0x80 != 0 => {
+                Some((fat32.flags & 0xF) as u32)
             }
-        };
+            _ => None,
+        }
+    }
+
+    /// The sector of the FAT that is read from holding the cluster and the index within it
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let per_sector = 512 / self.fat_entry_size();
+        let sector = self.bios_parameter_block.reserved_sectors as u32
+            + self.active_fat().unwrap_or(0) * self.sectors_per_fat()
+            + cluster / per_sector;
+        (sector, (cluster % per_sector) as usize)
+    }
+
+    fn fat_sector(&mut self, sector: u32) -> &mut Box<[u8]> {
+        let disk = &self.disk;
+        self.cluster_chain_buffer.entry(sector).or_insert_with(|| {
+            let mut buf = unsafe { Box::new_uninit_slice(512).assume_init() };
+            disk.read(sector as usize, 1, &mut buf);
+            buf
+        })
+    }
 
-        let idx = cluster % (512 / fat_size);
+    pub fn get_next_cluster(&mut self, cluster: u32) -> u32 {
+        let (sector, idx) = self.fat_position(cluster);
+        let fat_size = self.fat_entry_size();
+        let fat_buffer = self.fat_sector(sector);
 
         if fat_size == 4 {
-            unsafe { read_volatile((fat_buffer.as_ptr() as *const u32).add(idx as usize)) }
-        } else if fat_size == 2 {
-            unsafe { read_volatile((fat_buffer.as_ptr() as *const u16).add(idx as usize)) as u32 }
+            // The top 4 bits are reserved
+            unsafe { read_volatile((fat_buffer.as_ptr() as *const u32).add(idx)) & 0x0FFFFFFF }
         } else {
-            todo!()
+            unsafe { read_volatile((fat_buffer.as_ptr() as *const u16).add(idx)) as u32 }
+        }
+    }
+
+    /// Updates the FAT entry of the cluster in every copy of the FAT
+    fn set_next_cluster(&mut self, cluster: u32, next: u32) -> Result<(), FSServiceError> {
+        let (sector, idx) = self.fat_position(cluster);
+        let fat_size = self.fat_entry_size();
+        let fat_buffer = self.fat_sector(sector);
+
+        if fat_size == 4 {
+            let entry = unsafe { &mut *(fat_buffer.as_mut_ptr() as *mut u32).add(idx) };
+            *entry = *entry & 0xF0000000 | next & 0x0FFFFFFF;
+        } else {
+            let entry = unsafe { &mut *(fat_buffer.as_mut_ptr() as *mut u16).add(idx) };
+            *entry = next as u16;
+        }
+        let mut data = fat_buffer.clone();
+
+        let sectors_per_fat = self.sectors_per_fat();
+        let (first, copies) = match self.active_fat() {
+            Some(active) => (active, 1),
+            None => (0, self.bios_parameter_block.fat_copies as u32),
+        };
+        let offset = sector - first * sectors_per_fat;
+        for fat in first..first + copies {
+            self.write_sectors(offset + fat * sectors_per_fat, 1, &mut data)?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(
+        &self,
+        sector: u32,
+        sector_count: u32,
+        buffer: &mut [u8],
+    ) -> Result<(), FSServiceError> {
+        self.disk
+            .write(sector as usize, sector_count, buffer)
+            .ok_or(FSServiceError::DiskError)
+    }
+
+    fn read_fs_info(&mut self) {
+        let FatExtendedBootRecord::FAT32(fat32) = self.fat_ebr else {
+            return;
+        };
+        let sector = fat32.fat_info as u32;
+        if sector == 0 || sector == 0xFFFF {
+            return;
+        }
+
+        let buffer = &mut [0u8; 512];
+        self.disk.read(sector as usize, 1, buffer);
+        let field =
+            |offset: usize| u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap());
+        if field(0) != FS_INFO_LEAD_SIG || field(484) != FS_INFO_STRUCT_SIG {
+            warn!("FAT32 FSInfo sector is invalid");
+            return;
+        }
+
+        let mut free_clusters = field(488);
+        if free_clusters > self.cluster_count() {
+            free_clusters = u32::MAX;
+        }
+        self.fs_info = Some(FsInfo {
+            sector,
+            free_clusters,
+            next_free: field(492),
+        });
+    }
+
+    /// Writes the allocation hints back to the FSInfo sector
+    fn write_fs_info(&self) -> Result<(), FSServiceError> {
+        let Some(info) = &self.fs_info else {
+            return Ok(());
+        };
+        let buffer = &mut [0u8; 512];
+        self.disk
+            .read(info.sector as usize, 1, buffer)
+            .ok_or(FSServiceError::DiskError)?;
+        buffer[488..492].copy_from_slice(&info.free_clusters.to_le_bytes());
+        buffer[492..496].copy_from_slice(&info.next_free.to_le_bytes());
+        self.write_sectors(info.sector, 1, buffer)
+    }
+
+    /// Zeroes the cluster from offset to the end
+    fn zero_cluster_tail(&self, cluster: u32, offset: u32) -> Result<(), FSServiceError> {
+        let sector = self.get_start_sector_of_cluster(cluster) + offset / 512;
+        let count = self.bios_parameter_block.sectors_per_cluster as u32 - offset / 512;
+        let mut buffer = vec![0u8; count as usize * 512];
+        if offset % 512 != 0 {
+            self.disk
+                .read(sector as usize, 1, &mut buffer[..512])
+                .ok_or(FSServiceError::DiskError)?;
+            buffer[offset as usize % 512..512].fill(0);
+        }
+        self.write_sectors(sector, count, &mut buffer)
+    }
+
+    /// Allocates a zeroed cluster and marks it as the end of a chain
+    fn alloc_cluster(&mut self) -> Result<u32, FSServiceError> {
+        let count = self.cluster_count();
+        let start = match &self.fs_info {
+            Some(info) if (2..count).contains(&info.next_free) => info.next_free,
+            _ => 2,
+        };
+        let cluster = (start..count)
+            .chain(2..start)
+            .find(|&c| self.get_next_cluster(c) == 0)
+            .ok_or(FSServiceError::DiskFull)?;
+
+        self.zero_cluster_tail(cluster, 0)?;
+        self.set_next_cluster(cluster, self.end_of_chain())?;
+        if let Some(info) = &mut self.fs_info {
+            info.next_free = cluster + 1;
+            if info.free_clusters != u32::MAX {
+                info.free_clusters -= 1;
+            }
+        }
+        Ok(cluster)
+    }
+
+    /// Allocates a chain of count zeroed clusters, nothing is left allocated if it fails
+    fn alloc_chain(&mut self, count: usize) -> Result<u32, FSServiceError> {
+        let mut first = 0;
+        let mut last = 0;
+        for _ in 0..count {
+            let cluster = match self.alloc_cluster() {
+                Ok(c) => c,
+                Err(e) => {
+                    self.free_chain(first)?;
+                    return Err(e);
+                }
+            };
+            if last == 0 {
+                first = cluster;
+            } else {
+                self.set_next_cluster(last, cluster)?;
+            }
+            last = cluster;
+        }
+        Ok(first)
+    }
+
+    /// Frees every cluster of the chain starting at cluster
+    fn free_chain(&mut self, mut cluster: u32) -> Result<(), FSServiceError> {
+        while !self.is_end_of_chain(cluster) {
+            let next = self.get_next_cluster(cluster);
+            self.set_next_cluster(cluster, 0)?;
+            if let Some(info) = &mut self.fs_info {
+                // Stays unknown
+                info.free_clusters = info.free_clusters.saturating_add(1);
+            }
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    /// Follows the chain n clusters, stopping early at the end
+    fn nth_cluster(&mut self, mut cluster: u32, n: usize) -> u32 {
+        for _ in 0..n {
+            if self.is_end_of_chain(cluster) {
+                break;
+            }
+            cluster = self.get_next_cluster(cluster);
+        }
+        cluster
+    }
+
+    /// Every sector of the directory, the FAT16 root directory (cluster 0) is before the data
+    /// area
+    fn directory_sectors(&mut self, mut cluster: u32) -> Vec<u32> {
+        if cluster == 0 {
+            return (self.get_root_directory_sector()..self.first_data_sector()).collect();
         }
+        let sectors = self.bios_parameter_block.sectors_per_cluster as u32;
+        let mut result = Vec::new();
+        while !self.is_end_of_chain(cluster) {
+            let start = self.get_start_sector_of_cluster(cluster);
+            result.extend(start..start + sectors);
+            cluster = self.get_next_cluster(cluster);
+        }
+        result
     }
 
     pub fn read_directory(&mut self, mut cluster: u32) -> BTreeMap<String, usize> {
         let mut entries = BTreeMap::new();
         let sectors = self.bios_parameter_block.sectors_per_cluster as u32;
         let mut buffer = vec![0u8; 512 * sectors as usize];
-        let mut lfn_buf = String::new();
-        while cluster > 0 {
+        let mut lfn = LfnBuilder::default();
+        while !self.is_end_of_chain(cluster) {
             let sector = self.get_start_sector_of_cluster(cluster);
             self.disk.read(sector as usize, sectors, &mut buffer);
 
@@ -223,7 +489,7 @@ impl FAT {
                 )
             };
 
-            if self.parse_entries(directory_entry, &mut entries, &mut lfn_buf) {
+            if self.parse_entries(directory_entry, sector, &mut entries, &mut lfn) {
                 break;
             }
 
@@ -232,75 +498,68 @@ impl FAT {
         entries
     }
 
+    /// Parses the entries which start at first_sector, the long file name is carried over
+    /// between calls
     fn parse_entries(
         &mut self,
         entries: &[DirectoryEntry],
+        first_sector: u32,
         dir_entries: &mut BTreeMap<String, usize>,
-        lfn_buf: &mut String,
+        lfn: &mut LfnBuilder,
     ) -> bool {
-        for entry in entries {
+        for (i, entry) in entries.iter().enumerate() {
+            let slot = (first_sector + i as u32 / 16, i % 16);
             // No more entries
             if entry.name[0] == 0 {
                 return true;
             }
             // Unused entry
             if entry.name[0] == 0xE5 {
+                lfn.clear();
                 continue;
             }
             // Long file name entry
-            if entry.attributes == 0x0F {
-                let lfn: &LongFileName = unsafe { transmute(entry) };
-                let iter = { lfn.chars_1 }
-                    .into_iter()
-                    .chain(lfn.chars_2)
-                    .chain(lfn.chars_3);
-
-                // The name is null terminated
-                let iter = iter.take_while(|b| *b != 0);
-
-                let chars = char::decode_utf16(iter)
-                    .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER))
-                    .collect::<String>();
-
-                // LFN are supposed to be stored in reverse order
-                // TODO: Actually check lfn.order
-                *lfn_buf = chars + lfn_buf.as_str();
+            if entry.attributes == ATTR_LFN {
+                lfn.push(
+                    unsafe { transmute::<&DirectoryEntry, &LongFileName>(entry) },
+                    slot,
+                );
                 continue;
             }
-
-            let mut name;
-            if lfn_buf.is_empty() {
-                name = String::from_utf8_lossy(&entry.name).trim().to_string();
-                if entry.attributes & 0x10 == 0 {
-                    let n = String::from_utf8_lossy(&entry.ext);
-                    let n = n.trim();
-                    if !n.is_empty() {
-                        name += ".";
-                        name += n;
-                    }
-                };
-            } else {
-                name = lfn_buf.clone();
-                lfn_buf.clear();
+            // Volume label
+            if entry.attributes & 0x08 != 0 {
+                lfn.clear();
+                continue;
             }
 
+            let mut short = [0; 11];
+            short[..8].copy_from_slice(&entry.name);
+            short[8..].copy_from_slice(&entry.ext);
+            let (name, mut slots) = match lfn.finish(&short) {
+                Some(long) => long,
+                None => (short_name(entry), Vec::new()),
+            };
+            slots.push(slot);
+
             if name == "." || name == ".." {
                 continue;
             };
 
-            let cluster = (entry.first_cluster_hi as u32) << 8 | entry.first_cluster_low as u32;
+            let cluster = (entry.first_cluster_hi as u32) << 16 | entry.first_cluster_low as u32;
 
             let file_id = next_file_id();
             // Directory
-            let file = if entry.attributes & 0x10 == 0x10 {
+            let file = if entry.attributes & ATTR_DIRECTORY == ATTR_DIRECTORY {
                 FATFile {
                     cluster,
                     entry_type: FATFileType::Folder(None),
+                    slots,
                 }
             } else {
                 FATFile {
                     cluster,
                     entry_type: FATFileType::File(entry.size),
+                    slots,
                 }
             };
             dir_entries.insert(name, file_id);
@@ -315,15 +574,18 @@ impl FAT {
         }
 
         let mut children;
+        let cluster;
 
         // Fat32 uses a normal cluster directory for root
         if let FatExtendedBootRecord::FAT32(fat32) = self.fat_ebr {
-            children = self.read_directory(fat32.root_cluster);
+            cluster = fat32.root_cluster;
+            children = self.read_directory(cluster);
         } else {
+            cluster = 0;
             children = BTreeMap::new();
             let buffer = &mut [0u8; 512];
 
-            let mut lfn_buf = String::new();
+            let mut lfn = LfnBuilder::default();
 
             for sector in
                 self.first_data_sector() - self.root_dir_sectors()..self.first_data_sector()
@@ -334,20 +596,205 @@ impl FAT {
                     core::slice::from_raw_parts(buffer.as_ptr() as *const DirectoryEntry, 16)
                 };
 
-                if self.parse_entries(directory_entry, &mut children, &mut lfn_buf) {
+                if self.parse_entries(directory_entry, sector, &mut children, &mut lfn) {
                     break;
                 }
             }
         }
 
         let folder = FATFile {
-            cluster: 0,
+            cluster,
             entry_type: FATFileType::Folder(Some(children.clone())),
+            slots: Vec::new(),
         };
         self.file_id_lookup.insert(0, folder);
         children
     }
 
+    /// The children of the folder, reading them in if they haven't been yet
+    fn folder_children(
+        &mut self,
+        folder_id: usize,
+    ) -> Result<&mut BTreeMap<String, usize>, FSServiceError> {
+        let folder = self.get_fat_file(folder_id)?;
+        let cluster = folder.cluster;
+        match folder.entry_type {
+            FATFileType::File(_) => return Err(FSServiceError::InvalidRequestForFileType),
+            FATFileType::Folder(Some(_)) => (),
+            FATFileType::Folder(None) => {
+                let children = self.read_directory(cluster);
+                self.file_id_lookup.get_mut(&folder_id).unwrap().entry_type =
+                    FATFileType::Folder(Some(children));
+            }
+        }
+        match &mut self.file_id_lookup.get_mut(&folder_id).unwrap().entry_type {
+            FATFileType::Folder(Some(children)) => Ok(children),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Runs f on the directory entry in the slot and writes it back
+    fn update_entry(
+        &self,
+        slot: EntrySlot,
+        f: impl FnOnce(&mut DirectoryEntry),
+    ) -> Result<(), FSServiceError> {
+        let buffer = &mut [0u8; 512];
+        self.disk
+            .read(slot.0 as usize, 1, buffer)
+            .ok_or(FSServiceError::DiskError)?;
+        let entries = unsafe {
+            core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut DirectoryEntry, 16)
+        };
+        f(&mut entries[slot.1]);
+        self.write_sectors(slot.0, 1, buffer)
+    }
+
+    /// Finds count consecutive unused entries in the directory, growing it if there aren't
+    /// enough. Also returns the short names that are in use.
+    fn find_free_slots(
+        &mut self,
+        folder_cluster: u32,
+        count: usize,
+    ) -> Result<(Vec<EntrySlot>, Vec<[u8; 11]>), FSServiceError> {
+        let mut run = Vec::new();
+        let mut taken = Vec::new();
+        let buffer = &mut [0u8; 512];
+        for sector in self.directory_sectors(folder_cluster) {
+            self.disk
+                .read(sector as usize, 1, buffer)
+                .ok_or(FSServiceError::DiskError)?;
+            let entries = unsafe {
+                core::slice::from_raw_parts(buffer.as_ptr() as *const DirectoryEntry, 16)
+            };
+
+            for (i, entry) in entries.iter().enumerate() {
+                if entry.name[0] == 0 || entry.name[0] == 0xE5 {
+                    if run.len() < count {
+                        run.push((sector, i));
+                    }
+                    continue;
+                }
+                if run.len() < count {
+                    run.clear();
+                }
+                if entry.attributes != ATTR_LFN {
+                    let mut short = [0; 11];
+                    short[..8].copy_from_slice(&entry.name);
+                    short[8..].copy_from_slice(&entry.ext);
+                    taken.push(short);
+                }
+            }
+        }
+
+        if run.len() < count {
+            // The FAT16 root directory can't grow
+            if folder_cluster == 0 {
+                return Err(FSServiceError::DiskFull);
+            }
+            let missing = count - run.len();
+            let per_cluster = 16 * self.bios_parameter_block.sectors_per_cluster as usize;
+            let new = self.alloc_chain(missing.div_ceil(per_cluster))?;
+
+            let mut last = folder_cluster;
+            loop {
+                let next = self.get_next_cluster(last);
+                if self.is_end_of_chain(next) {
+                    break;
+                }
+                last = next;
+            }
+            self.set_next_cluster(last, new)?;
+
+            let slots = self
+                .directory_sectors(new)
+                .into_iter()
+                .flat_map(|sector| (0..16).map(move |i| (sector, i)));
+            run.extend(slots.take(missing));
+        }
+        Ok((run, taken))
+    }
+
+    /// Follows the chain of the file marking its clusters as used. Chains that run into a free,
+    /// out of range or already used cluster are cut short when repairing.
+    fn check_chain(
+        &mut self,
+        file_id: usize,
+        path: &str,
+        used: &mut [u64],
+        repair: bool,
+        problems: &mut Vec<FsProblem>,
+    ) -> Result<(), FSServiceError> {
+        let file = self.get_fat_file(file_id)?.clone();
+        let count = self.cluster_count();
+        let mut prev = 0;
+        let mut cluster = file.cluster;
+        let mut clusters = 0;
+        while !self.is_end_of_chain(cluster) {
+            let (word, bit) = (cluster as usize / 64, 1 << (cluster % 64));
+            let problem = if cluster >= count || self.get_next_cluster(cluster) == 0 {
+                Some(FsProblem::BadChain {
+                    path: path.to_string(),
+                    cluster,
+                })
+            } else if used[word] & bit != 0 {
+                Some(FsProblem::CrossLinked {
+                    path: path.to_string(),
+                    cluster,
+                })
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                problems.push(problem);
+                if repair {
+                    self.cut_chain(file_id, prev)?;
+                }
+                break;
+            }
+
+            used[word] |= bit;
+            clusters += 1;
+            prev = cluster;
+            cluster = self.get_next_cluster(cluster);
+        }
+
+        let FATFileType::File(size) = file.entry_type else {
+            return Ok(());
+        };
+        let allocated = clusters as usize * self.cluster_bytes() as usize;
+        if size as usize > allocated {
+            problems.push(FsProblem::SizeMismatch {
+                path: path.to_string(),
+                size: size as usize,
+                allocated,
+            });
+            if repair {
+                self.update_entry(*file.slots.last().unwrap(), |e| e.size = allocated as u32)?;
+                self.file_id_lookup.get_mut(&file_id).unwrap().entry_type =
+                    FATFileType::File(allocated as u32);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the chain of the file after prev, or empties it if prev is 0
+    fn cut_chain(&mut self, file_id: usize, prev: u32) -> Result<(), FSServiceError> {
+        if prev != 0 {
+            return self.set_next_cluster(prev, self.end_of_chain());
+        }
+        let fat_file = self.file_id_lookup.get_mut(&file_id).unwrap();
+        fat_file.cluster = 0;
+        // The root has no entry to update
+        let Some(&slot) = fat_file.slots.last() else {
+            return Ok(());
+        };
+        self.update_entry(slot, |e| {
+            e.first_cluster_hi = 0;
+            e.first_cluster_low = 0;
+        })
+    }
+
     fn get_fat_file(&self, file_id: usize) -> Result<&FATFile, FSServiceError> {
         self.file_id_lookup
             .get(&file_id)
@@ -355,11 +802,15 @@ impl FAT {
     }
 }
 
-pub fn read_bios_block(disk: FSPartitionDisk) {
+pub fn read_bios_block(disk: FSPartitionDisk) -> Option<PartitionId> {
     let buffer = &mut [0u8; 512];
-    disk.read(0, 1, buffer);
+    disk.read(0, 1, buffer)?;
 
     let bpb = unsafe { *(buffer.as_ptr() as *const BiosParameterBlock) };
+    if bpb.sectors_per_cluster == 0 {
+        warn!("Partition isn't FAT");
+        return None;
+    }
 
     let mut total_clusters = bpb.total_sectors as usize / bpb.sectors_per_cluster as usize;
 
@@ -372,7 +823,8 @@ pub fn read_bios_block(disk: FSPartitionDisk) {
     let partition_id = next_partition_id();
 
     if total_clusters < 4085 {
-        todo!("FAT12 Not supported yet")
+        warn!("FAT12 Not supported yet");
+        return None;
     } else if total_clusters < 65535 {
         let fat16ext =
             unsafe { *(buffer.as_ptr().add(size_of::<BiosParameterBlock>()) as *const FAT16Ext) };
@@ -383,6 +835,7 @@ pub fn read_bios_block(disk: FSPartitionDisk) {
             file_id_lookup: BTreeMap::new(),
             disk,
             cluster_chain_buffer: Default::default(),
+            fs_info: None,
         };
     } else {
         let fat32ext =
@@ -394,50 +847,33 @@ pub fn read_bios_block(disk: FSPartitionDisk) {
             file_id_lookup: BTreeMap::new(),
             disk,
             cluster_chain_buffer: Default::default(),
+            fs_info: None,
         };
     }
 
+    fat.read_fs_info();
     fat.enumerate_root();
-    PARTITION.lock().insert(partition_id, Box::new(fat));
+    add_partition(partition_id, fat);
+    vfs::auto_mount(partition_id);
+    Some(partition_id)
 }
 
 impl FileSystemDev for FAT {
-    fn get_file_by_id(&mut self, file_id: usize) -> Result<super::VFile, FSServiceError> {
-        let mut fat_file = self.get_fat_file(file_id)?.clone();
-        let res;
-        let mut update = false;
-        match &mut fat_file.entry_type {
-            FATFileType::Folder(f) => {
-                let file;
-                let mut tmp;
-                if let Some(f) = f {
-                    file = f;
-                } else {
-                    tmp = self.read_directory(fat_file.cluster);
-                    file = &mut tmp;
-                    update = true;
-                }
-                res = super::VFile {
-                    location: (self.partition_id, file_id),
-                    specialized: super::VFileSpecialized::Folder(
-                        file.clone()
-                            .into_iter()
-                            .map(|(a, b)| (a, (self.partition_id, b)))
-                            .collect(),
-                    ),
-                };
-            }
-            FATFileType::File(f) => {
-                return Ok(super::VFile {
-                    location: (self.partition_id, file_id),
-                    specialized: super::VFileSpecialized::File(*f as usize),
-                })
-            }
-        }
-        if update {
-            self.file_id_lookup.insert(file_id, fat_file);
-        }
-        Ok(res)
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let partition_id = self.partition_id;
+        let specialized = match self.get_fat_file(file_id)?.entry_type {
+            FATFileType::File(size) => VFileSpecialized::File(size as usize),
+            FATFileType::Folder(_) => VFileSpecialized::Folder(
+                self.folder_children(file_id)?
+                    .iter()
+                    .map(|(name, &id)| (name.clone(), (partition_id, id)))
+                    .collect(),
+            ),
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
     }
 
     fn read_file<'a>(
@@ -520,19 +956,506 @@ impl FileSystemDev for FAT {
             return Ok(None);
         }
         let length = if file_sector + 1 == sectors_to_read as usize {
-            (length % 512) as usize
+            length as usize - file_sector * 512
         } else {
             512
         };
 
-        let mut cluster = fat_file.cluster;
-        for _ in 0..(file_sector / self.bios_parameter_block.sectors_per_cluster as usize) {
-            cluster = self.get_next_cluster(cluster);
-        }
+        let cluster = self.nth_cluster(
+            fat_file.cluster,
+            file_sector / self.bios_parameter_block.sectors_per_cluster as usize,
+        );
         let file_sector = self.get_start_sector_of_cluster(cluster)
             + file_sector as u32 % self.bios_parameter_block.sectors_per_cluster as u32;
 
         self.disk.read(file_sector as usize, 1, buffer);
         Ok(Some(length))
     }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn write_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        data: &[u8],
+    ) -> Result<(), FSServiceError> {
+        let fat_file = self.get_fat_file(file_id)?;
+
+        let size = match fat_file.entry_type {
+            FATFileType::Folder(_) => return Err(FSServiceError::InvalidRequestForFileType),
+            FATFileType::File(f) => f as usize,
+        };
+        let start = page * 0x1000;
+        let data = &data[..data.len().min(size.saturating_sub(start))];
+
+        let sectors_per_cluster = self.bios_parameter_block.sectors_per_cluster as usize;
+        let mut file_sector = start / 512;
+        let mut cluster = self.nth_cluster(fat_file.cluster, file_sector / sectors_per_cluster);
+
+        let mut buffer = Vec::new();
+        let mut written = 0;
+        while written < data.len() {
+            // The chain is shorter than the file
+            if self.is_end_of_chain(cluster) {
+                return Err(FSServiceError::DiskError);
+            }
+            let in_cluster = file_sector % sectors_per_cluster;
+            let count =
+                (sectors_per_cluster - in_cluster).min((data.len() - written).div_ceil(512));
+            let len = (count * 512).min(data.len() - written);
+
+            buffer.clear();
+            buffer.extend_from_slice(&data[written..written + len]);
+            buffer.resize(count * 512, 0);
+            self.write_sectors(
+                self.get_start_sector_of_cluster(cluster) + in_cluster as u32,
+                count as u32,
+                &mut buffer,
+            )?;
+
+            written += len;
+            file_sector += count;
+            cluster = self.get_next_cluster(cluster);
+        }
+        Ok(())
+    }
+
+    fn create(
+        &mut self,
+        folder_id: usize,
+        name: &str,
+        is_folder: bool,
+    ) -> Result<usize, FSServiceError> {
+        if !valid_name(name) {
+            return Err(FSServiceError::InvalidName);
+        }
+        // Names are case insensitive
+        if self
+            .folder_children(folder_id)?
+            .keys()
+            .any(|n| n.eq_ignore_ascii_case(name))
+        {
+            return Err(FSServiceError::AlreadyExists);
+        }
+        let folder_cluster = self.get_fat_file(folder_id)?.cluster;
+
+        let exact = exact_short_name(name);
+        let lfn_count = match exact {
+            Some(_) => 0,
+            None => name.encode_utf16().count().div_ceil(13),
+        };
+        let (slots, taken) = self.find_free_slots(folder_cluster, lfn_count + 1)?;
+        let short = match exact {
+            Some(short) if taken.contains(&short) => return Err(FSServiceError::AlreadyExists),
+            Some(short) => short,
+            None => generate_short_name(name, &taken).ok_or(FSServiceError::AlreadyExists)?,
+        };
+
+        let (cluster, attributes, entry_type) = if is_folder {
+            let cluster = self.alloc_chain(1)?;
+            // Folders in the root point to cluster 0 even on FAT32
+            let parent = if folder_id == 0 { 0 } else { folder_cluster };
+            let sector = self.get_start_sector_of_cluster(cluster);
+            let res = self
+                .update_entry((sector, 0), |e| {
+                    *e = new_entry(*b".          ", ATTR_DIRECTORY, cluster)
+                })
+                .and_then(|()| {
+                    self.update_entry((sector, 1), |e| {
+                        *e = new_entry(*b"..         ", ATTR_DIRECTORY, parent)
+                    })
+                });
+            if let Err(e) = res {
+                self.free_chain(cluster)?;
+                return Err(e);
+            }
+            (
+                cluster,
+                ATTR_DIRECTORY,
+                FATFileType::Folder(Some(BTreeMap::new())),
+            )
+        } else {
+            (0, ATTR_ARCHIVE, FATFileType::File(0))
+        };
+
+        let lfns = match lfn_count {
+            0 => Vec::new(),
+            _ => lfn_entries(name, lfn_checksum(&short)),
+        };
+        for (&slot, lfn) in slots.iter().zip(lfns) {
+            self.update_entry(slot, |e| {
+                *e = unsafe { transmute::<LongFileName, DirectoryEntry>(lfn) }
+            })?;
+        }
+        self.update_entry(*slots.last().unwrap(), |e| {
+            *e = new_entry(short, attributes, cluster)
+        })?;
+        self.write_fs_info()?;
+
+        let file_id = next_file_id();
+        self.file_id_lookup.insert(
+            file_id,
+            FATFile {
+                cluster,
+                entry_type,
+                slots,
+            },
+        );
+        self.folder_children(folder_id)?
+            .insert(name.to_string(), file_id);
+        Ok(file_id)
+    }
+
+    fn set_file_size(&mut self, file_id: usize, size: usize) -> Result<(), FSServiceError> {
+        let fat_file = self.get_fat_file(file_id)?.clone();
+        let FATFileType::File(old_size) = fat_file.entry_type else {
+            return Err(FSServiceError::InvalidRequestForFileType);
+        };
+        let size: u32 = size.try_into().map_err(|_| FSServiceError::FileTooLarge)?;
+        let cluster_bytes = self.cluster_bytes();
+
+        // Anything past the old end has to read as zeros
+        if size > old_size && old_size % cluster_bytes != 0 {
+            let tail = self.nth_cluster(fat_file.cluster, (old_size / cluster_bytes) as usize);
+            if !self.is_end_of_chain(tail) {
+                self.zero_cluster_tail(tail, old_size % cluster_bytes)?;
+            }
+        }
+
+        let needed = size.div_ceil(cluster_bytes);
+        let mut first = fat_file.cluster;
+        let mut last = 0;
+        let mut cluster = first;
+        let mut kept = 0;
+        while kept < needed && !self.is_end_of_chain(cluster) {
+            last = cluster;
+            cluster = self.get_next_cluster(cluster);
+            kept += 1;
+        }
+
+        if kept == needed {
+            if last == 0 {
+                first = 0;
+            } else if !self.is_end_of_chain(cluster) {
+                self.set_next_cluster(last, self.end_of_chain())?;
+            }
+            self.free_chain(cluster)?;
+        } else {
+            let new = self.alloc_chain((needed - kept) as usize)?;
+            if last == 0 {
+                first = new;
+            } else {
+                self.set_next_cluster(last, new)?;
+            }
+        }
+
+        // The root can't be resized so there is always an entry
+        self.update_entry(*fat_file.slots.last().unwrap(), |e| {
+            e.size = size;
+            e.first_cluster_hi = (first >> 16) as u16;
+            e.first_cluster_low = first as u16;
+            e.w_date = FAT_EPOCH;
+        })?;
+        self.write_fs_info()?;
+
+        let fat_file = self.file_id_lookup.get_mut(&file_id).unwrap();
+        fat_file.cluster = first;
+        fat_file.entry_type = FATFileType::File(size);
+        Ok(())
+    }
+
+    fn check(&mut self, repair: bool) -> Result<Vec<FsProblem>, FSServiceError> {
+        let mut problems = Vec::new();
+        let count = self.cluster_count();
+        let mut used = vec![0u64; (count as usize).div_ceil(64)];
+
+        let mut folders = vec![(0, String::from("/"))];
+        while let Some((folder_id, path)) = folders.pop() {
+            self.check_chain(folder_id, &path, &mut used, repair, &mut problems)?;
+            for (name, id) in self.folder_children(folder_id)?.clone() {
+                let path = match path.as_str() {
+                    "/" => format!("/{name}"),
+                    _ => format!("{path}/{name}"),
+                };
+                match self.get_fat_file(id)?.entry_type {
+                    FATFileType::Folder(_) => folders.push((id, path)),
+                    FATFileType::File(_) => {
+                        self.check_chain(id, &path, &mut used, repair, &mut problems)?
+                    }
+                }
+            }
+        }
+
+        // Anything left allocated that isn't marked bad was lost
+        let bad = self.end_of_chain() - 8;
+        let mut lost = 0;
+        let mut free = 0;
+        for cluster in 2..count {
+            let next = self.get_next_cluster(cluster);
+            if next == 0 {
+                free += 1;
+            } else if next != bad && used[cluster as usize / 64] & 1 << (cluster % 64) == 0 {
+                lost += 1;
+                if repair {
+                    self.set_next_cluster(cluster, 0)?;
+                    free += 1;
+                }
+            }
+        }
+        if lost != 0 {
+            problems.push(FsProblem::LostClusters(lost));
+        }
+
+        if let Some(info) = &mut self.fs_info {
+            // An unknown count is allowed
+            if info.free_clusters != u32::MAX && info.free_clusters != free {
+                problems.push(FsProblem::FreeCount {
+                    recorded: info.free_clusters,
+                    actual: free,
+                });
+                if repair {
+                    info.free_clusters = free;
+                }
+            }
+        }
+        if repair {
+            self.write_fs_info()?;
+        }
+        Ok(problems)
+    }
+
+    fn delete(&mut self, folder_id: usize, name: &str) -> Result<(), FSServiceError> {
+        let file_id = *self
+            .folder_children(folder_id)?
+            .get(name)
+            .ok_or(FSServiceError::FileNotFound)?;
+        if let FATFileType::Folder(_) = self.get_fat_file(file_id)?.entry_type {
+            if !self.folder_children(file_id)?.is_empty() {
+                return Err(FSServiceError::FolderNotEmpty);
+            }
+        }
+
+        let fat_file = self.get_fat_file(file_id)?.clone();
+        for &slot in &fat_file.slots {
+            self.update_entry(slot, |e| e.name[0] = 0xE5)?;
+        }
+        self.free_chain(fat_file.cluster)?;
+        self.write_fs_info()?;
+
+        self.file_id_lookup.remove(&file_id);
+        self.folder_children(folder_id)?.remove(name);
+        Ok(())
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && name.encode_utf16().count() <= 255
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+fn valid_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// The 8.3 name if the name can be stored as one without a long file name
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.chars().chain(ext.chars()).all(valid_short_char)
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// Makes up an unused 8.3 name like `LONGNA~1.TXT` to go with a long file name
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+    let convert = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| c.to_ascii_uppercase())
+            .map(|c| if valid_short_char(c) { c as u8 } else { b'_' })
+            .collect()
+    };
+    let base = convert(base);
+    let ext = convert(ext);
+
+    let mut short = [b' '; 11];
+    let ext_len = ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1..1_000_000 {
+        let tail = format!("~{n}");
+        let base_len = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..base_len].copy_from_slice(&base[..base_len]);
+        short[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short) {
+            return Some(short);
+        }
+    }
+    None
+}
+
+/// The name of an entry without a long file name
+fn short_name(entry: &DirectoryEntry) -> String {
+    let mut base = entry.name;
+    // A name really starting with 0xE5 is stored as 0x05 as that marks unused entries
+    if base[0] == 0x05 {
+        base[0] = 0xE5;
+    }
+    let mut name = String::from_utf8_lossy(&base).trim_end().to_string();
+    if entry.case_flags & CASE_LOWER_BASE != 0 {
+        name.make_ascii_lowercase();
+    }
+
+    // Folders can have extensions too
+    let ext = String::from_utf8_lossy(&entry.ext);
+    let ext = ext.trim_end();
+    if !ext.is_empty() {
+        name.push('.');
+        match entry.case_flags & CASE_LOWER_EXT != 0 {
+            true => name.push_str(&ext.to_ascii_lowercase()),
+            false => name.push_str(ext),
+        }
+    }
+    name
+}
+
+/// A long file name being put together from its entries, which are stored last part first
+/// just before the 8.3 entry they belong to
+#[derive(Default)]
+struct LfnBuilder {
+    /// The parts read so far, the last one first
+    parts: Vec<[u16; 13]>,
+    slots: Vec<EntrySlot>,
+    checksum: u8,
+    /// The order of the part that should come next, 0 once they have all been read
+    next: u8,
+}
+
+impl LfnBuilder {
+    fn clear(&mut self) {
+        self.parts.clear();
+        self.slots.clear();
+        self.next = 0;
+    }
+
+    /// Entries that are out of order or from a different name throw away what was read, they
+    /// are left over from a name that was changed by something that doesn't know about LFNs
+    fn push(&mut self, lfn: &LongFileName, slot: EntrySlot) {
+        let order = lfn.order & LFN_ORDER_MASK;
+        if lfn.order & LFN_LAST != 0 {
+            self.clear();
+            self.checksum = lfn.checksum;
+            self.next = order;
+        }
+        if order == 0 || order != self.next || lfn.checksum != self.checksum {
+            self.clear();
+            return;
+        }
+
+        let mut part = [0; 13];
+        part[..5].copy_from_slice(&{ lfn.chars_1 });
+        part[5..11].copy_from_slice(&{ lfn.chars_2 });
+        part[11..].copy_from_slice(&{ lfn.chars_3 });
+        self.parts.push(part);
+        self.slots.push(slot);
+        self.next -= 1;
+    }
+
+    /// The long name and its slots if every part was read and they belong to the 8.3 name
+    fn finish(&mut self, short: &[u8; 11]) -> Option<(String, Vec<EntrySlot>)> {
+        let complete = !self.parts.is_empty() && self.next == 0;
+        let valid = complete && self.checksum == lfn_checksum(short);
+        let parts = core::mem::take(&mut self.parts);
+        let slots = core::mem::take(&mut self.slots);
+        self.clear();
+        if !valid {
+            return None;
+        }
+
+        // The name is null terminated unless it fills the last part
+        let chars = parts
+            .iter()
+            .rev()
+            .flatten()
+            .copied()
+            .take_while(|&c| c != 0);
+        let name = char::decode_utf16(chars)
+            .map(|c| c.unwrap_or(REPLACEMENT_CHARACTER))
+            .collect();
+        Some((name, slots))
+    }
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// The long file name entries for the name in the order they are stored
+fn lfn_entries(name: &str, checksum: u8) -> Vec<LongFileName> {
+    let chars: Vec<u16> = name.encode_utf16().collect();
+    let count = chars.len().div_ceil(13);
+    (0..count)
+        .rev()
+        .map(|i| {
+            let chunk = &chars[i * 13..chars.len().min(i * 13 + 13)];
+            // Null terminated if there is space and padded with 0xFFFF
+            let mut part = [0xFFFF; 13];
+            part[..chunk.len()].copy_from_slice(chunk);
+            if chunk.len() < 13 {
+                part[chunk.len()] = 0;
+            }
+            let mut order = i as u8 + 1;
+            if i + 1 == count {
+                order |= LFN_LAST;
+            }
+            LongFileName {
+                order,
+                chars_1: part[..5].try_into().unwrap(),
+                attribute: ATTR_LFN,
+                entry_type: 0,
+                checksum,
+                chars_2: part[5..11].try_into().unwrap(),
+                _zero: 0,
+                chars_3: part[11..].try_into().unwrap(),
+            }
+        })
+        .collect()
+}
+
+fn new_entry(short: [u8; 11], attributes: u8, cluster: u32) -> DirectoryEntry {
+    DirectoryEntry {
+        name: short[..8].try_into().unwrap(),
+        ext: short[8..].try_into().unwrap(),
+        attributes,
+        case_flags: 0,
+        c_time_tenth: 0,
+        c_time: 0,
+        c_date: FAT_EPOCH,
+        a_time: FAT_EPOCH,
+        first_cluster_hi: (cluster >> 16) as u16,
+        w_time: 0,
+        w_date: FAT_EPOCH,
+        first_cluster_low: cluster as u16,
+        size: 0,
+    }
 }
@@ -0,0 +1,97 @@
+//! GUID partition tables, found behind a protective MBR.
+//!
+//! Only the primary table is read, if it is damaged the backup at the end of the disk is used
+//! instead.
+
+use alloc::{sync::Arc, vec::Vec};
+use kernel_userspace::disk::gpt::{
+    crc32, GptEntry, GptHeader, SIGNATURE, TYPE_BASIC_DATA, TYPE_EFI_SYSTEM, TYPE_LINUX_FS,
+    TYPE_LINUX_SWAP, TYPE_UNUSED,
+};
+
+use crate::{driver::disk::DiskDevice, mutex::Mutex, paging::swap::add_swap_partition};
+
+use super::{ext2, mbr::read_fat, FSPartitionDisk, PartitionId};
+
+/// Reads and checks the header at the lba and the entries it points to
+fn read_table(drive: &Arc<Mutex<dyn DiskDevice>>, lba: u64) -> Option<Vec<GptEntry>> {
+    let buffer = &mut [0u8; 512];
+    drive.lock().read(lba as usize, 1, buffer)?;
+    let header = unsafe { *(buffer.as_ptr() as *const GptHeader) };
+
+    if header.signature != SIGNATURE
+        || header.header_size as usize != core::mem::size_of::<GptHeader>()
+        || { header.header_crc } != header.compute_crc()
+        || header.entry_size as usize != core::mem::size_of::<GptEntry>()
+    {
+        return None;
+    }
+
+    let len = header.entry_count as usize * header.entry_size as usize;
+    let mut entries = vec![0u8; len.div_ceil(512) * 512];
+    drive.lock().read(
+        header.entries_lba as usize,
+        (entries.len() / 512) as u32,
+        &mut entries,
+    )?;
+    if crc32(&entries[..len]) != header.entries_crc {
+        return None;
+    }
+
+    Some(
+        entries[..len]
+            .chunks_exact(header.entry_size as usize)
+            .map(|e| unsafe { *(e.as_ptr() as *const GptEntry) })
+            .collect(),
+    )
+}
+
+/// Loads the file systems in the table, last_lba is the end of the protective MBR partition
+/// which is where the backup header is
+pub fn read_partitions(drive: Arc<Mutex<dyn DiskDevice>>, last_lba: u64) -> Vec<PartitionId> {
+    let mut partitions = Vec::new();
+
+    let entries = match read_table(&drive, 1) {
+        Some(e) => e,
+        None => {
+            warn!("GPT header is damaged, trying the backup");
+            let Some(e) = read_table(&drive, last_lba) else {
+                warn!("GPT backup header is damaged too");
+                return partitions;
+            };
+            e
+        }
+    };
+
+    for entry in entries {
+        if entry.type_guid == TYPE_UNUSED {
+            continue;
+        }
+        let (first, last) = (entry.first_lba, entry.last_lba);
+        if last < first {
+            warn!("GPT partition {first}..{last} is backwards");
+            continue;
+        }
+        info!(
+            "GPT partition start:{first} size:{}mb",
+            (last - first + 1) / 2048
+        );
+
+        let fs_disk =
+            FSPartitionDisk::new(drive.clone(), first as usize, (last - first + 1) as usize);
+        let id = match entry.type_guid {
+            TYPE_LINUX_SWAP => {
+                add_swap_partition(fs_disk);
+                None
+            }
+            TYPE_LINUX_FS => ext2::read_superblock(fs_disk),
+            TYPE_BASIC_DATA | TYPE_EFI_SYSTEM => read_fat(fs_disk),
+            _ => {
+                warn!("Unknown GPT partition type {:x?}", entry.type_guid);
+                None
+            }
+        };
+        partitions.extend(id);
+    }
+    partitions
+}
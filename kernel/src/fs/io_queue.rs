@@ -0,0 +1,77 @@
+//! Queued reads and writes for clients that don't want to wait on each request.
+//!
+//! Each queue is a channel with its own thread behind it. A client sends batches of
+//! [`IoRequest`]s and gets an [`IoCompletion`] back for each one as it finishes, so it can keep
+//! a number of requests in flight instead of making a call per sector.
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    channel::{channel_create_rs, channel_read_resize, channel_write_rs, ChannelReadResult},
+    fs::{FSServiceError, IoCompletion, IoOp, IoRequest},
+    message::MessageHandle,
+    object::{KernelReference, KernelReferenceID},
+    service::{deserialize, serialize},
+    syscall::spawn_thread,
+};
+
+use super::{read_file_range, write_file, PartitionId, VFileID};
+
+/// Creates a queue, returns the client end of it
+pub fn open() -> KernelReference {
+    let (server, client) = channel_create_rs();
+    spawn_thread(move || queue_task(server));
+    client
+}
+
+/// Runs requests until the client closes the queue
+fn queue_task(queue: KernelReference) {
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+    let mut data = Vec::new();
+
+    loop {
+        match channel_read_resize(queue.id(), &mut buffer, &mut handles) {
+            ChannelReadResult::Ok => (),
+            ChannelReadResult::Closed => return,
+            e => {
+                error!("IO queue read failed: {e:?}");
+                return;
+            }
+        }
+        let requests: Vec<IoRequest> = match deserialize(&buffer) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Bad IO queue request: {e:?}");
+                return;
+            }
+        };
+
+        let mut write_data = handles
+            .drain(..)
+            .map(|h| MessageHandle::from_kref(KernelReference::from_id(h)));
+        for req in requests {
+            let file: VFileID = (PartitionId(req.disk_id as u64), req.node_id);
+            let (result, reply) = match req.op {
+                IoOp::Read(len) => match read_file_range(file, req.offset, len, &mut data) {
+                    Ok(d) => (Ok(d.len()), Some(MessageHandle::create(d))),
+                    Err(e) => (Err(e), None),
+                },
+                IoOp::Write => match write_data.next() {
+                    Some(d) => (write_file(file, Some(req.offset), &d.read_vec()), None),
+                    None => (Err(FSServiceError::InvalidRequest), None),
+                },
+            };
+
+            let completion = IoCompletion {
+                key: req.key,
+                result,
+            };
+            let msg = serialize(&completion, &mut buffer);
+            let reply: Vec<KernelReferenceID> = reply.iter().map(|d| d.kref().id()).collect();
+            if !channel_write_rs(queue.id(), msg, &reply) {
+                // The client went away
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Read only ISO9660 driver, so that the system can read the CD or ISO image it booted from.
+//!
+//! Joliet names are used when the image has them, otherwise the plain ISO9660 names are shown
+//! in lower case without their version. Rock Ridge isn't supported. File ids are the byte offset
+//! of the directory record on the disk, except for the root which is always file id 0.
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use kernel_userspace::fs::FSServiceError;
+
+use crate::{driver::disk::DiskDevice, mutex::Mutex};
+
+use super::{
+    add_partition, next_partition_id, vfs, FSPartitionDisk, FileSystemDev, PartitionId, VFile,
+    VFileSpecialized,
+};
+
+const BLOCK_SIZE: usize = 2048;
+const SECTORS_PER_BLOCK: usize = BLOCK_SIZE / 512;
+/// The first 16 blocks are the system area
+const FIRST_DESCRIPTOR: usize = 16;
+/// Stop looking for the terminator after this many descriptors
+const MAX_DESCRIPTORS: usize = 32;
+
+const DESCRIPTOR_BOOT: u8 = 0;
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+const STANDARD_ID: &[u8; 5] = b"CD001";
+const EL_TORITO_ID: &[u8] = b"EL TORITO SPECIFICATION";
+
+const FLAG_DIRECTORY: u8 = 0x2;
+/// The file carries on in the next record, only used for files over 4GiB
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+/// Numbers are stored both little and big endian, only the little endian half is used
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct BothEndian32 {
+    le: u32,
+    _be: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct VolumeDescriptor {
+    descriptor_type: u8,
+    id: [u8; 5],
+    _version: u8,
+    _flags: u8,
+    _system_id: [u8; 32],
+    volume_id: [u8; 32],
+    _unused: [u8; 8],
+    volume_space_size: BothEndian32,
+    /// Only used by supplementary descriptors, marks Joliet
+    escape_sequences: [u8; 32],
+    _volume_set_size: u32,
+    _volume_sequence_number: u32,
+    logical_block_size: u32,
+    _path_table_size: BothEndian32,
+    _path_tables: [u32; 4],
+    root_directory: [u8; 34],
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct BootRecord {
+    _descriptor_type: u8,
+    _id: [u8; 5],
+    _version: u8,
+    boot_system_id: [u8; 32],
+    _boot_id: [u8; 32],
+    catalog: u32,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct DirectoryRecord {
+    length: u8,
+    _extended_attribute_length: u8,
+    extent: BothEndian32,
+    data_length: BothEndian32,
+    _date: [u8; 7],
+    flags: u8,
+    _unit_size: u8,
+    _gap_size: u8,
+    _volume_sequence_number: u32,
+    name_length: u8,
+}
+
+/// The initial entry of the El Torito boot catalog, after the validation entry
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct BootCatalogEntry {
+    boot_indicator: u8,
+    _media_type: u8,
+    _load_segment: u16,
+    _system_type: u8,
+    _unused: u8,
+    sector_count: u16,
+    load_rba: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Record {
+    extent: u32,
+    size: usize,
+    flags: u8,
+}
+
+impl Record {
+    fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        let header = core::mem::size_of::<DirectoryRecord>();
+        if data.len() < header {
+            return None;
+        }
+        let record = unsafe { *(data.as_ptr() as *const DirectoryRecord) };
+        let name_end = header + record.name_length as usize;
+        if (record.length as usize) < name_end || record.length as usize > data.len() {
+            return None;
+        }
+        Some((
+            Self {
+                extent: record.extent.le,
+                size: record.data_length.le as usize,
+                flags: record.flags,
+            },
+            &data[header..name_end],
+        ))
+    }
+}
+
+pub struct Iso9660 {
+    partition_id: PartitionId,
+    disk: FSPartitionDisk,
+    root: Record,
+    joliet: bool,
+    /// Records of everything in the folders that have been read
+    records: BTreeMap<usize, Record>,
+    /// Children of the folders that have been read by file id
+    folders: BTreeMap<usize, BTreeMap<String, usize>>,
+}
+
+impl Iso9660 {
+    /// Reads from the extent at offset
+    fn read_at(&self, extent: u32, offset: usize, buffer: &mut [u8]) -> Result<(), FSServiceError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let start = extent as usize * BLOCK_SIZE + offset;
+        let first = start / 512;
+        let count = (start + buffer.len()).div_ceil(512) - first;
+        let mut sectors = vec![0u8; count * 512];
+        self.disk
+            .read(first, count as u32, &mut sectors)
+            .ok_or(FSServiceError::DiskError)?;
+        let skip = start % 512;
+        buffer.copy_from_slice(&sectors[skip..skip + buffer.len()]);
+        Ok(())
+    }
+
+    fn record(&mut self, file_id: usize) -> Result<Record, FSServiceError> {
+        if file_id == 0 {
+            return Ok(self.root);
+        }
+        if let Some(r) = self.records.get(&file_id) {
+            return Ok(*r);
+        }
+
+        // Records never cross a block
+        let block = &mut [0u8; BLOCK_SIZE];
+        self.read_at((file_id / BLOCK_SIZE) as u32, 0, block)?;
+        let (record, _) =
+            Record::parse(&block[file_id % BLOCK_SIZE..]).ok_or(FSServiceError::FileNotFound)?;
+        self.records.insert(file_id, record);
+        Ok(record)
+    }
+
+    fn decode_name(&self, name: &[u8]) -> String {
+        let name = match self.joliet {
+            true => char::decode_utf16(
+                name.chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]])),
+            )
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect(),
+            false => String::from_utf8_lossy(name).to_lowercase(),
+        };
+        // Drop the version and the dot of names without an extension
+        let name = name.split_once(';').map_or(name.as_str(), |(n, _)| n);
+        String::from(name.strip_suffix('.').unwrap_or(name))
+    }
+
+    fn read_directory(
+        &mut self,
+        file_id: usize,
+    ) -> Result<&BTreeMap<String, usize>, FSServiceError> {
+        // Reading the folder needs all of self, so the entry is only taken once it is parsed
+        if !self.folders.contains_key(&file_id) {
+            let dir = self.record(file_id)?;
+            let mut data = vec![0u8; dir.size];
+            self.read_at(dir.extent, 0, &mut data)?;
+
+            let mut children = BTreeMap::new();
+            let mut multi_extent = BTreeSet::new();
+            for (block, block_data) in data.chunks(BLOCK_SIZE).enumerate() {
+                let mut offset = 0;
+                // A zero length pads out the rest of the block
+                while offset < block_data.len() && block_data[offset] != 0 {
+                    let Some((record, name)) = Record::parse(&block_data[offset..]) else {
+                        warn!("Corrupt ISO9660 directory {file_id}");
+                        break;
+                    };
+                    let id = dir.extent as usize * BLOCK_SIZE + block * BLOCK_SIZE + offset;
+                    offset += block_data[offset] as usize;
+
+                    // The current and parent folders are called 0 and 1
+                    if name == [0] || name == [1] {
+                        continue;
+                    }
+                    let name = self.decode_name(name);
+                    if record.flags & FLAG_MULTI_EXTENT != 0 {
+                        multi_extent.insert(name.clone());
+                    }
+                    self.records.insert(id, record);
+                    children.insert(name, id);
+                }
+            }
+            for name in multi_extent {
+                warn!("Skipping {name}, files split into multiple extents aren't supported");
+                children.remove(&name);
+            }
+            return Ok(self.folders.entry(file_id).or_insert(children));
+        }
+        Ok(&self.folders[&file_id])
+    }
+
+    /// The record of a file, which can't be a folder
+    fn file_record(&mut self, file_id: usize) -> Result<Record, FSServiceError> {
+        let record = self.record(file_id)?;
+        if record.flags & FLAG_DIRECTORY != 0 {
+            return Err(FSServiceError::InvalidRequestForFileType);
+        }
+        Ok(record)
+    }
+}
+
+/// Reads a block straight from the disk, the size of the image isn't known yet
+fn read_block(drive: &Arc<Mutex<dyn DiskDevice>>, block: usize) -> Option<[u8; BLOCK_SIZE]> {
+    let mut buffer = [0u8; BLOCK_SIZE];
+    drive.lock().read(
+        block * SECTORS_PER_BLOCK,
+        SECTORS_PER_BLOCK as u32,
+        &mut buffer,
+    )?;
+    Some(buffer)
+}
+
+/// Logs where the El Torito boot image is
+fn read_boot_catalog(drive: &Arc<Mutex<dyn DiskDevice>>, catalog: u32) {
+    let Some(block) = read_block(drive, catalog as usize) else {
+        warn!("Failed to read the El Torito boot catalog");
+        return;
+    };
+    // The validation entry comes first and ends with 55 AA
+    if block[0] != 1 || block[30..32] != [0x55, 0xAA] {
+        warn!("El Torito boot catalog isn't valid");
+        return;
+    }
+    let entry = unsafe { *(block.as_ptr().add(32) as *const BootCatalogEntry) };
+    info!(
+        "El Torito boot image at block {} ({} sectors), bootable: {}",
+        { entry.load_rba },
+        { entry.sector_count },
+        entry.boot_indicator == 0x88
+    );
+}
+
+/// Loads the image if the disk holds one, CDs have no partition table so it covers the disk
+pub fn read_volume_descriptors(drive: Arc<Mutex<dyn DiskDevice>>) -> Option<PartitionId> {
+    let mut primary = None;
+    let mut joliet = None;
+
+    for block in FIRST_DESCRIPTOR..FIRST_DESCRIPTOR + MAX_DESCRIPTORS {
+        let buffer = read_block(&drive, block)?;
+        let descriptor = unsafe { *(buffer.as_ptr() as *const VolumeDescriptor) };
+        if &descriptor.id != STANDARD_ID {
+            return None;
+        }
+
+        match descriptor.descriptor_type {
+            DESCRIPTOR_BOOT => {
+                let boot = unsafe { *(buffer.as_ptr() as *const BootRecord) };
+                if boot.boot_system_id.starts_with(EL_TORITO_ID) {
+                    read_boot_catalog(&drive, boot.catalog);
+                }
+            }
+            DESCRIPTOR_PRIMARY => primary = Some(descriptor),
+            // Joliet is marked by the UCS-2 level 1, 2 or 3 escape sequences
+            DESCRIPTOR_SUPPLEMENTARY
+                if matches!(
+                    descriptor.escape_sequences[..3],
+                    [0x25, 0x2F, 0x40 | 0x43 | 0x45]
+                ) =>
+            {
+                joliet = Some(descriptor)
+            }
+            DESCRIPTOR_TERMINATOR => break,
+            _ => (),
+        }
+    }
+
+    let Some(primary) = primary else {
+        warn!("ISO9660 image has no primary volume descriptor");
+        return None;
+    };
+    if primary.logical_block_size as u16 as usize != BLOCK_SIZE {
+        warn!(
+            "ISO9660 block size of {} isn't supported",
+            primary.logical_block_size as u16
+        );
+        return None;
+    }
+
+    let volume = joliet.unwrap_or(primary);
+    let Some((root, _)) = Record::parse(&volume.root_directory) else {
+        warn!("ISO9660 root directory record isn't valid");
+        return None;
+    };
+    info!(
+        "ISO9660 volume {:?}{}",
+        String::from_utf8_lossy(&primary.volume_id).trim_end(),
+        if joliet.is_some() { " with Joliet" } else { "" }
+    );
+
+    let sectors = primary.volume_space_size.le as usize * SECTORS_PER_BLOCK;
+    let partition_id = next_partition_id();
+    let iso = Iso9660 {
+        partition_id,
+        disk: FSPartitionDisk::new(drive, 0, sectors),
+        root,
+        joliet: joliet.is_some(),
+        records: BTreeMap::new(),
+        folders: BTreeMap::new(),
+    };
+    add_partition(partition_id, iso);
+    vfs::auto_mount(partition_id);
+    Some(partition_id)
+}
+
+impl FileSystemDev for Iso9660 {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let record = self.record(file_id)?;
+        let partition_id = self.partition_id;
+
+        let specialized = if record.flags & FLAG_DIRECTORY != 0 {
+            VFileSpecialized::Folder(
+                self.read_directory(file_id)?
+                    .iter()
+                    .map(|(name, &id)| (name.clone(), (partition_id, id)))
+                    .collect(),
+            )
+        } else {
+            VFileSpecialized::File(record.size)
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        let record = self.file_record(file_id)?;
+        buffer.resize(record.size, 0);
+        self.read_at(record.extent, 0, buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        let record = self.file_record(file_id)?;
+        let start = file_sector * 512;
+        if start >= record.size {
+            return Ok(None);
+        }
+        let len = (record.size - start).min(512);
+        self.read_at(record.extent, start, &mut buffer[..len])?;
+        Ok(Some(len))
+    }
+
+    fn read_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        buffer: &mut [u8; 0x1000],
+    ) -> Result<(), FSServiceError> {
+        let record = self.file_record(file_id)?;
+        let start = page * 0x1000;
+        let len = record.size.saturating_sub(start).min(0x1000);
+        self.read_at(record.extent, start, &mut buffer[..len])
+    }
+}
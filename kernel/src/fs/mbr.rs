@@ -1,11 +1,18 @@
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec::Vec};
+use kernel_userspace::disk::gpt::PROTECTIVE_MBR_ID;
 
 use crate::{
     driver::disk::DiskDevice,
-    fs::{fat::read_bios_block, FSPartitionDisk},
-    mutex::Spinlock,
+    fs::{exfat, ext2, fat::read_bios_block, gpt, iso9660, FSPartitionDisk, PartitionId},
+    mutex::Mutex,
+    paging::swap::add_swap_partition,
 };
 
+/// The partition id linux uses for swap
+const SWAP_PARTITION_ID: u8 = 0x82;
+/// The partition id linux uses for its native file systems
+const LINUX_PARTITION_ID: u8 = 0x83;
+
 #[repr(C, packed)]
 pub struct PartitionTableEntry {
     bootable: u8,
@@ -28,18 +35,41 @@ pub struct MasterBootRecord {
 
 const MBR_SIZE: usize = 512;
 
-pub fn read_partitions(drive: Arc<Spinlock<dyn DiskDevice>>) {
+/// Loads the file systems on the disk, returns the partitions that were added
+pub fn read_partitions(drive: Arc<Mutex<dyn DiskDevice>>) -> Vec<PartitionId> {
+    let mut partitions = Vec::new();
+
+    // CDs and ISO images have no partition table, the file system covers the whole disk. Hybrid
+    // images also have an MBR but it only points back at the same file system.
+    if let Some(id) = iso9660::read_volume_descriptors(drive.clone()) {
+        partitions.push(id);
+        return partitions;
+    }
+
     // Round up to nearest 512 bytes
     let mbr_buf = &mut [0u8; MBR_SIZE];
-    drive.lock().read(0, 1, mbr_buf);
+    if drive.lock().read(0, 1, mbr_buf).is_none() {
+        warn!("Failed to read the MBR");
+        return partitions;
+    }
 
     let mbr = unsafe { &mut *(mbr_buf.as_ptr() as *mut MasterBootRecord) };
 
-    assert!(
-        { mbr.magic_number } == [0x55, 0xAA],
-        "MBR Magic number not valid, was given: {:?}",
-        { mbr.magic_number }
-    );
+    if { mbr.magic_number } != [0x55, 0xAA] {
+        warn!("MBR Magic number not valid, was given: {:?}", {
+            mbr.magic_number
+        });
+        return partitions;
+    }
+
+    if let Some(part) = mbr
+        .partitions
+        .iter()
+        .find(|p| p.partition_id == PROTECTIVE_MBR_ID)
+    {
+        let last_lba = part.start_lba as u64 + part.length as u64 - 1;
+        return gpt::read_partitions(drive, last_lba);
+    }
 
     for part in &mbr.partitions {
         if part.start_lba > 0 || part.bootable > 0 {
@@ -52,7 +82,26 @@ pub fn read_partitions(drive: Arc<Spinlock<dyn DiskDevice>>) {
             );
             let fs_disk =
                 FSPartitionDisk::new(drive.clone(), part.start_lba as usize, part.length as usize);
-            read_bios_block(fs_disk);
+            let id = match part.partition_id {
+                SWAP_PARTITION_ID => {
+                    add_swap_partition(fs_disk);
+                    None
+                }
+                LINUX_PARTITION_ID => ext2::read_superblock(fs_disk),
+                _ => read_fat(fs_disk),
+            };
+            partitions.extend(id);
         }
     }
+    partitions
+}
+
+/// FAT and exFAT share partition ids, exFAT is told apart by the name in its boot sector
+pub fn read_fat(disk: FSPartitionDisk) -> Option<PartitionId> {
+    let buffer = &mut [0u8; 512];
+    disk.read(0, 1, buffer)?;
+    match &buffer[3..11] {
+        b"EXFAT   " => exfat::read_boot_sector(disk),
+        _ => read_bios_block(disk),
+    }
 }
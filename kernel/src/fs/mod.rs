@@ -1,36 +1,75 @@
+pub mod block_cache;
+pub mod bootfs;
+pub mod devfs;
+pub mod exfat;
+pub mod ext2;
 pub mod fat;
+pub mod gpt;
+pub mod io_queue;
+pub mod iso9660;
 pub mod mbr;
+pub mod page_cache;
+pub mod procfs;
+pub mod tmpfs;
+pub mod vfs;
+pub mod watch;
+
+use core::{
+    fmt::Debug,
+    ops::{
+        Bound::{Excluded, Unbounded},
+        ControlFlow,
+    },
+    sync::atomic::AtomicU64,
+};
 
-use core::{fmt::Debug, ops::ControlFlow, sync::atomic::AtomicU64};
-
-use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, sync::Arc, vec::Vec};
 use conquer_once::spin::Lazy;
 use kernel_userspace::{
     channel::{channel_read_rs, channel_write_rs},
     fs::{
-        FSServiceError, FSServiceMessage, FSServiceMessageResp, StatResponse, StatResponseFile,
-        StatResponseFolder,
+        FSServiceError, FSServiceMessage, FSServiceMessageResp, FsProblem, MountInfo, ReadDirPage,
+        StatResponse, StatResponseFile, StatResponseFolder,
     },
+    interrupt::interrupt_wait,
     message::MessageHandle,
-    service::{deserialize, serialize, Service},
+    object::{KernelReference, KernelReferenceID},
+    service::{deserialize, serialize, Service, SimpleService},
+    INT_PCI,
 };
 
 use crate::{
-    driver::disk::{DiskBusDriver, DiskDevice},
+    cpu_localstorage::CPULocalStorageRW,
+    driver::disk::{DiskBusDriver, DiskDevice, DiskEvent},
     fs::mbr::read_partitions,
-    mutex::Spinlock,
+    mutex::Mutex,
+    paging::page_mapper::PageMapping,
+    scheduling::with_held_interrupts,
 };
 
-pub static PARTITION: Lazy<Spinlock<BTreeMap<PartitionId, Box<dyn FileSystemDev>>>> =
-    Lazy::new(|| Spinlock::new(BTreeMap::new()));
-pub static FSDRIVES: Lazy<Spinlock<FileSystemDrives>> = Lazy::new(|| {
-    Spinlock::new(FileSystemDrives {
+/// Each file system has its own lock, the map is only held long enough to look one up
+static PARTITION: Lazy<Mutex<BTreeMap<PartitionId, Arc<Mutex<dyn FileSystemDev>>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+pub static FSDRIVES: Lazy<Mutex<FileSystemDrives>> = Lazy::new(|| {
+    Mutex::new(FileSystemDrives {
         disks_buses: Default::default(),
+        disks: Default::default(),
+        next_disk: 0,
     })
 });
 
 pub struct FileSystemDrives {
     disks_buses: Vec<Box<dyn DiskBusDriver>>,
+    disks: Vec<AttachedDisk>,
+    next_disk: usize,
+}
+
+/// A disk that has been identified and had its partitions loaded
+struct AttachedDisk {
+    disk: Arc<Mutex<dyn DiskDevice>>,
+    /// The name in `/dev`
+    name: String,
+    partitions: Vec<PartitionId>,
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
@@ -47,30 +86,125 @@ pub fn next_partition_id() -> PartitionId {
     PartitionId(ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed))
 }
 
+pub fn add_partition(id: PartitionId, fs: impl FileSystemDev + 'static) {
+    PARTITION.lock().insert(id, Arc::new(Mutex::new(fs)));
+}
+
 impl FileSystemDrives {
     pub fn add_device(&mut self, device: Box<dyn DiskBusDriver>) {
         self.disks_buses.push(device);
     }
 
     pub fn identify(&mut self) {
-        for bus in &mut self.disks_buses {
-            for disk in bus.get_disks() {
-                info!("{:?}", disk.lock().identify());
-                read_partitions(disk);
+        let disks: Vec<_> = self
+            .disks_buses
+            .iter_mut()
+            .flat_map(|bus| bus.get_disks())
+            .collect();
+        for disk in disks {
+            self.attach(disk);
+        }
+    }
+
+    /// Loads or drops the disks that were plugged in or removed since the last check
+    pub fn poll_hotplug(&mut self) {
+        let events: Vec<_> = self
+            .disks_buses
+            .iter_mut()
+            .flat_map(|bus| bus.poll_events())
+            .collect();
+        for event in events {
+            match event {
+                DiskEvent::Added(disk) => self.attach(disk),
+                DiskEvent::Removed(disk) => self.detach(&disk),
             }
         }
     }
+
+    /// Loads a disk, those that aren't on a [`DiskBusDriver`] like usb sticks come in here
+    pub fn attach(&mut self, disk: Arc<Mutex<dyn DiskDevice>>) {
+        info!("{:?}", disk.lock().identify());
+        let name = format!("disk{}", self.next_disk);
+        self.next_disk += 1;
+        devfs::register(name.clone(), Box::new(devfs::DiskNode::new(disk.clone())));
+        let partitions = read_partitions(disk.clone());
+        self.disks.push(AttachedDisk {
+            disk,
+            name,
+            partitions,
+        });
+    }
+
+    /// Drops the partitions of the disk in `/dev` and reads its partition table again, for after
+    /// the table has been rewritten. Fails if the root is on the disk.
+    pub fn rescan(&mut self, name: &str) -> Result<Vec<PartitionId>, FSServiceError> {
+        let attached = self
+            .disks
+            .iter_mut()
+            .find(|d| d.name == name)
+            .ok_or(FSServiceError::FileNotFound)?;
+        let mounts = vfs::mounts();
+        if mounts
+            .iter()
+            .any(|(path, id)| path == "/" && attached.partitions.contains(id))
+        {
+            return Err(FSServiceError::Busy);
+        }
+
+        page_cache::writeback();
+        for id in attached.partitions.drain(..) {
+            vfs::unmount_partition(id);
+            page_cache::forget_partition(id);
+            PARTITION.lock().remove(&id);
+        }
+        block_cache::forget(&attached.disk);
+        attached.partitions = read_partitions(attached.disk.clone());
+        Ok(attached.partitions.clone())
+    }
+
+    /// Unmounts and drops everything on a disk that has been removed. Swap partitions stay as
+    /// they are, there is no way to get the pages on them back.
+    pub fn detach(&mut self, disk: &Arc<Mutex<dyn DiskDevice>>) {
+        let same =
+            |d: &AttachedDisk| Arc::as_ptr(&d.disk) as *const () == Arc::as_ptr(disk) as *const ();
+        let Some(index) = self.disks.iter().position(same) else {
+            return;
+        };
+        let attached = self.disks.remove(index);
+        info!("{} was removed", attached.name);
+
+        devfs::unregister(&attached.name);
+        for id in attached.partitions {
+            vfs::unmount_partition(id);
+            page_cache::forget_partition(id);
+            PARTITION.lock().remove(&id);
+        }
+        block_cache::forget(disk);
+    }
+}
+
+/// Checks the disk buses for hotplug events whenever a PCI interrupt comes in
+pub fn disk_hotplug() {
+    let mut ints = SimpleService::with_name("INTERRUPTS");
+    let mut handles = Vec::with_capacity(1);
+    let _: () = ints.call_val(&INT_PCI, &mut handles);
+    let ints = handles[0];
+
+    loop {
+        interrupt_wait(ints);
+        FSDRIVES.lock().poll_hotplug();
+    }
 }
 
 pub struct FSPartitionDisk {
-    backing_disk: Arc<Spinlock<dyn DiskDevice>>,
+    backing_disk: Arc<Mutex<dyn DiskDevice>>,
     partition_offset: usize,
     partition_length: usize,
 }
 
 impl FSPartitionDisk {
     pub fn new(
-        backing_disk: Arc<Spinlock<dyn DiskDevice>>,
+        backing_disk: Arc<Mutex<dyn DiskDevice>>,
         partition_offset: usize,
         partition_length: usize,
     ) -> Self {
@@ -81,39 +215,261 @@ impl FSPartitionDisk {
         }
     }
 
-    fn read(&self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+    /// Length of the partition in sectors
+    pub fn sectors(&self) -> usize {
+        self.partition_length
+    }
+
+    pub fn read(&self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
+        assert!(sector + sector_count as usize <= self.partition_length);
+        block_cache::read(
+            &self.backing_disk,
+            sector + self.partition_offset,
+            sector_count,
+            buffer,
+        )
+    }
+
+    pub fn write(&self, sector: usize, sector_count: u32, buffer: &mut [u8]) -> Option<()> {
         assert!(sector + sector_count as usize <= self.partition_length);
-        self.backing_disk
-            .lock()
-            .read(sector + self.partition_offset, sector_count, buffer)
+        block_cache::write(
+            &self.backing_disk,
+            sector + self.partition_offset,
+            sector_count,
+            buffer,
+        )
     }
 }
 
+/// Runs f with the partition locked. Interrupts stay enabled so that the disk io can sleep,
+/// including when it is for the page fault handler.
 fn with_partition<F, R>(id: PartitionId, f: F) -> Result<R, FSServiceError>
 where
-    F: FnOnce(&mut Box<dyn FileSystemDev>) -> Result<R, FSServiceError>,
+    F: FnOnce(&mut dyn FileSystemDev) -> Result<R, FSServiceError>,
 {
-    let mut p = PARTITION.lock();
-    let p = p
-        .get_mut(&id)
+    let partition = PARTITION
+        .lock()
+        .get(&id)
+        .cloned()
         .ok_or(FSServiceError::NoSuchPartition(id.0))?;
-    f(p)
+    let mut p = partition.lock();
+    f(&mut *p)
 }
 
 pub fn get_file_by_id(id: VFileID) -> Result<VFile, FSServiceError> {
     with_partition(id.0, |p| p.get_file_by_id(id.1))
 }
 
+pub fn file_size(id: VFileID) -> Result<usize, FSServiceError> {
+    match get_file_by_id(id)?.specialized {
+        VFileSpecialized::File(size) => Ok(size),
+        _ => Err(FSServiceError::InvalidRequestForFileType),
+    }
+}
+
+pub fn is_writable(id: PartitionId) -> Result<bool, FSServiceError> {
+    with_partition(id, |p| Ok(p.writable()))
+}
+
+pub fn is_cached(id: PartitionId) -> Result<bool, FSServiceError> {
+    with_partition(id, |p| Ok(p.cached()))
+}
+
+/// Wraps the file in a memory object sharing its pages with the page cache, the reference is
+/// added to the current process
+fn mmap_file(file: VFileID) -> Result<(KernelReference, usize), FSServiceError> {
+    let size = file_size(file)?;
+    // There would be nothing to map
+    if size == 0 {
+        return Err(FSServiceError::InvalidRequest);
+    }
+    let mapping = PageMapping::new_file(file, 0, size);
+    let id = with_held_interrupts(|| unsafe {
+        CPULocalStorageRW::get_current_task()
+            .process()
+            .add_value(mapping.into())
+    });
+    Ok((KernelReference::from_id(id), size))
+}
+
+/// Reads the whole file through the page cache
 pub fn read_file(id: VFileID, buffer: &mut Vec<u8>) -> Result<&[u8], FSServiceError> {
-    with_partition(id.0, |p| p.read_file(id.1, buffer))
+    if !is_cached(id.0)? {
+        let len = with_partition(id.0, |p| p.read_file(id.1, buffer).map(|b| b.len()))?;
+        return Ok(&buffer[..len]);
+    }
+    let size = file_size(id)?;
+    buffer.resize(size, 0);
+    for (index, chunk) in buffer.chunks_mut(0x1000).enumerate() {
+        let page = page_cache::get_page(id, index)?;
+        chunk.copy_from_slice(&page_cache::page_data(&page)[..chunk.len()]);
+    }
+    Ok(buffer)
 }
 
-pub fn read_file_sector(
+/// Reads up to len bytes from the offset, less if the file ends first
+pub fn read_file_range(
     id: VFileID,
-    sector: usize,
-    buf: &mut [u8; 512],
-) -> Result<Option<usize>, FSServiceError> {
-    with_partition(id.0, |p| p.read_file_sector(id.1, sector, buf))
+    offset: usize,
+    len: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<&[u8], FSServiceError> {
+    buffer.clear();
+    if !is_cached(id.0)? {
+        let mut sector = [0; 512];
+        while buffer.len() < len {
+            let pos = offset + buffer.len();
+            let start = pos % 512;
+            let read = with_partition(id.0, |p| p.read_file_sector(id.1, pos / 512, &mut sector))?;
+            match read {
+                Some(read) if read > start => {
+                    let take = (read - start).min(len - buffer.len());
+                    buffer.extend_from_slice(&sector[start..start + take]);
+                    // A short sector is the end of the file
+                    if read < 512 {
+                        break;
+                    }
+                }
+                _ => break,
+            }
+        }
+        return Ok(buffer);
+    }
+
+    let end = offset.saturating_add(len).min(file_size(id)?);
+    let mut pos = offset;
+    while pos < end {
+        let page = page_cache::get_page(id, pos / 0x1000)?;
+        let start = pos % 0x1000;
+        let take = (0x1000 - start).min(end - pos);
+        buffer.extend_from_slice(&page_cache::page_data(&page)[start..start + take]);
+        pos += take;
+    }
+    Ok(buffer)
+}
+
+/// Splits a path into the folder and the name in it
+fn split_path(path: &str) -> Result<(&str, &str), FSServiceError> {
+    let path = path.trim_end_matches('/');
+    let (folder, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err(FSServiceError::InvalidName);
+    }
+    Ok((folder, name))
+}
+
+/// Creates an empty file or folder at the path
+pub fn create_file(
+    partition_id: PartitionId,
+    path: &str,
+    is_folder: bool,
+) -> Result<VFileID, FSServiceError> {
+    let (folder, name) = split_path(path)?;
+    let folder = get_file_from_path(partition_id, folder)?;
+    let id = with_partition(partition_id, |p| {
+        p.create(folder.location.1, name, is_folder)
+    })?;
+    Ok((partition_id, id))
+}
+
+/// Removes the file or empty folder at the path
+pub fn delete_file(partition_id: PartitionId, path: &str) -> Result<(), FSServiceError> {
+    let (folder, name) = split_path(path)?;
+    let folder = get_file_from_path(partition_id, folder)?;
+    let VFileSpecialized::Folder(children) = &folder.specialized else {
+        return Err(FSServiceError::CouldNotFollowPath);
+    };
+    let id = *children.get(name).ok_or(FSServiceError::FileNotFound)?;
+
+    page_cache::truncate(id, 0);
+    with_partition(partition_id, |p| p.delete(folder.location.1, name))
+}
+
+/// Moves the file or folder at from to to, both paths are in the partition
+pub fn rename_file(partition_id: PartitionId, from: &str, to: &str) -> Result<(), FSServiceError> {
+    let (from_folder, from_name) = split_path(from)?;
+    let (to_folder, to_name) = split_path(to)?;
+    let from_folder = get_file_from_path(partition_id, from_folder)?;
+    let to_folder = get_file_from_path(partition_id, to_folder)?;
+    with_partition(partition_id, |p| {
+        p.rename(
+            from_folder.location.1,
+            from_name,
+            to_folder.location.1,
+            to_name,
+        )
+    })
+}
+
+/// Creates a symlink at the path pointing at target
+pub fn create_symlink(
+    partition_id: PartitionId,
+    path: &str,
+    target: &str,
+) -> Result<VFileID, FSServiceError> {
+    let (folder, name) = split_path(path)?;
+    let folder = get_file_from_path(partition_id, folder)?;
+    let id = with_partition(partition_id, |p| {
+        p.create_symlink(folder.location.1, name, target)
+    })?;
+    Ok((partition_id, id))
+}
+
+/// Grows or shrinks the file, keeping the page cache in sync
+pub fn set_file_size(id: VFileID, size: usize) -> Result<(), FSServiceError> {
+    let old_size = file_size(id)?;
+    page_cache::truncate(id, old_size.min(size));
+    with_partition(id.0, |p| p.set_file_size(id.1, size))
+}
+
+/// Writes data to the file through the page cache growing it if needed, offset None appends.
+/// Returns the new size of the file.
+pub fn write_file(
+    id: VFileID,
+    offset: Option<usize>,
+    data: &[u8],
+) -> Result<usize, FSServiceError> {
+    let size = file_size(id)?;
+    let offset = offset.unwrap_or(size);
+    if !is_cached(id.0)? {
+        with_partition(id.0, |p| p.write_file_direct(id.1, offset, data))?;
+        watch::file_modified(id);
+        return file_size(id);
+    }
+    let end = offset
+        .checked_add(data.len())
+        .ok_or(FSServiceError::FileTooLarge)?;
+    if end > size {
+        set_file_size(id, end)?;
+    }
+
+    let mut written = 0;
+    while written < data.len() {
+        let pos = offset + written;
+        let in_page = pos % 0x1000;
+        let len = (0x1000 - in_page).min(data.len() - written);
+
+        let page = page_cache::get_page(id, pos / 0x1000)?;
+        let page_data = unsafe { page_cache::page_data_mut(&page) };
+        page_data[in_page..in_page + len].copy_from_slice(&data[written..written + len]);
+        page_cache::mark_dirty(id, pos / 0x1000);
+        written += len;
+    }
+
+    page_cache::flush_file(id);
+    block_cache::flush();
+    watch::file_modified(id);
+    Ok(size.max(end))
+}
+
+/// Writes everything cached back first so the check sees what is really on the disk
+pub fn check_partition(id: PartitionId, repair: bool) -> Result<Vec<FsProblem>, FSServiceError> {
+    page_cache::writeback();
+    let problems = with_partition(id, |p| p.check(repair))?;
+    if repair {
+        block_cache::flush();
+    }
+    Ok(problems)
 }
 
 pub trait FileSystemDev: Send + Sync {
@@ -131,6 +487,106 @@ pub trait FileSystemDev: Send + Sync {
         file_sector: usize,
         buffer: &mut [u8; 512],
     ) -> Result<Option<usize>, FSServiceError>;
+
+    /// Reads a page of the file, anything past the end of the file is left untouched
+    fn read_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        buffer: &mut [u8; 0x1000],
+    ) -> Result<(), FSServiceError> {
+        let mut sector = [0; 512];
+        for (i, chunk) in buffer.chunks_mut(512).enumerate() {
+            match self.read_file_sector(file_id, page * 8 + i, &mut sector)? {
+                Some(len) => chunk[..len].copy_from_slice(&sector[..len]),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// If the page cache can write pages back with [`Self::write_file_page`]
+    fn writable(&self) -> bool {
+        false
+    }
+
+    /// Writes data to the file starting at the page, data won't extend past the end of the file
+    fn write_file_page(
+        &mut self,
+        _file_id: usize,
+        _page: usize,
+        _data: &[u8],
+    ) -> Result<(), FSServiceError> {
+        Err(FSServiceError::ReadOnly)
+    }
+
+    /// Creates an empty file or folder called name in the folder, returns its file id
+    fn create(
+        &mut self,
+        _folder_id: usize,
+        _name: &str,
+        _is_folder: bool,
+    ) -> Result<usize, FSServiceError> {
+        Err(FSServiceError::ReadOnly)
+    }
+
+    /// Grows or shrinks the file, anything it grows by reads as zeros
+    fn set_file_size(&mut self, _file_id: usize, _size: usize) -> Result<(), FSServiceError> {
+        Err(FSServiceError::ReadOnly)
+    }
+
+    /// Removes the file or empty folder called name from the folder
+    fn delete(&mut self, _folder_id: usize, _name: &str) -> Result<(), FSServiceError> {
+        Err(FSServiceError::ReadOnly)
+    }
+
+    /// If reads and writes go through the page cache, otherwise they use [`Self::read_file`],
+    /// [`Self::read_file_sector`] and [`Self::write_file_direct`]
+    fn cached(&self) -> bool {
+        true
+    }
+
+    /// Writes the data at the offset when the file system isn't cached
+    fn write_file_direct(
+        &mut self,
+        _file_id: usize,
+        _offset: usize,
+        _data: &[u8],
+    ) -> Result<(), FSServiceError> {
+        Err(FSServiceError::ReadOnly)
+    }
+
+    /// Creates a symlink called name in the folder pointing at target, returns its file id
+    fn create_symlink(
+        &mut self,
+        _folder_id: usize,
+        _name: &str,
+        _target: &str,
+    ) -> Result<usize, FSServiceError> {
+        match self.writable() {
+            true => Err(FSServiceError::Unsupported),
+            false => Err(FSServiceError::ReadOnly),
+        }
+    }
+
+    /// Looks for inconsistencies in the file system, fixing them if repair is set
+    fn check(&mut self, _repair: bool) -> Result<Vec<FsProblem>, FSServiceError> {
+        Err(FSServiceError::Unsupported)
+    }
+
+    /// Moves the entry called from_name in from_folder to to_name in to_folder
+    fn rename(
+        &mut self,
+        _from_folder: usize,
+        _from_name: &str,
+        _to_folder: usize,
+        _to_name: &str,
+    ) -> Result<(), FSServiceError> {
+        match self.writable() {
+            true => Err(FSServiceError::Unsupported),
+            false => Err(FSServiceError::ReadOnly),
+        }
+    }
 }
 
 impl Debug for dyn FileSystemDev {
@@ -159,6 +615,8 @@ impl VFile {
 pub enum VFileSpecialized {
     Folder(BTreeMap<String, VFileID>),
     File(usize),
+    /// The path it points at, relative to the folder it is in unless it starts with `/`
+    Symlink(String),
 }
 
 pub fn get_file_from_path(partition_id: PartitionId, path: &str) -> Result<VFile, FSServiceError> {
@@ -168,11 +626,10 @@ pub fn get_file_from_path(partition_id: PartitionId, path: &str) -> Result<VFile
         if sect.is_empty() {
             continue;
         }
+        // Symlinks have been followed by the vfs before getting here
         let folder = match file.specialized {
             VFileSpecialized::Folder(f) => f,
-            VFileSpecialized::File(_) => {
-                return Err(FSServiceError::CouldNotFollowPath);
-            }
+            _ => return Err(FSServiceError::CouldNotFollowPath),
         };
         let id = folder.get(sect).ok_or(FSServiceError::CouldNotFollowPath)?;
         file = get_file_by_id(*id)?;
@@ -205,13 +662,13 @@ pub fn file_handler() {
     let mut buffer = Vec::with_capacity(0x1000);
     let mut fs_buffer = Vec::new();
     let mut btree_child_buffer = BTreeMap::new();
-    let mut sec_buf = [0; 512];
 
     let mut service = Service::new(
         "FS",
         || (),
         |handle, ()| {
-            let mut handles_buffer = Vec::new();
+            // Writes send their data as a message
+            let mut handles_buffer = Vec::with_capacity(1);
             match channel_read_rs(handle.id(), &mut buffer, &mut handles_buffer) {
                 kernel_userspace::channel::ChannelReadResult::Ok => (),
                 kernel_userspace::channel::ChannelReadResult::Empty => {
@@ -233,12 +690,17 @@ pub fn file_handler() {
                     return ControlFlow::Break(());
                 }
             };
-            let res = run_fs_query(msg, &mut fs_buffer, &mut sec_buf, &mut btree_child_buffer);
+            let res = run_fs_query(
+                msg,
+                &handles_buffer,
+                &mut fs_buffer,
+                &mut btree_child_buffer,
+            );
             match res {
                 Ok((a, b)) => {
                     let m = serialize(&Ok::<_, FSServiceError>(a), &mut buffer);
                     match b {
-                        Some(h) => channel_write_rs(handle.id(), &m, &[h.kref().id()]),
+                        Some(h) => channel_write_rs(handle.id(), &m, &[h.id()]),
                         None => channel_write_rs(handle.id(), &m, &[]),
                     };
                 }
@@ -256,54 +718,156 @@ pub fn file_handler() {
 
 fn run_fs_query<'a>(
     query: FSServiceMessage,
+    handles: &[KernelReferenceID],
     buffer: &'a mut Vec<u8>,
-    sec_buffer: &'a mut [u8; 512],
     btree_child_buf: &'a mut BTreeMap<String, VFileID>,
-) -> Result<(FSServiceMessageResp<'a>, Option<MessageHandle>), FSServiceError> {
+) -> Result<(FSServiceMessageResp<'a>, Option<KernelReference>), FSServiceError> {
     match query {
-        FSServiceMessage::RunStat(disk, path) => {
-            let file = get_file_from_path(PartitionId(disk as u64), path)?;
+        FSServiceMessage::RunStat(path) => {
+            let file = vfs::lookup(path)?;
             let stat = match file.specialized {
-                VFileSpecialized::Folder(children) => {
-                    *btree_child_buf = children;
-                    let keys = btree_child_buf.keys();
-                    StatResponse::Folder(StatResponseFolder {
-                        node_id: file.location.1,
-                        children: keys.map(|c| c.as_str()).collect(),
-                    })
-                }
+                VFileSpecialized::Folder(children) => StatResponse::Folder(StatResponseFolder {
+                    disk_id: file.location.0 .0 as usize,
+                    node_id: file.location.1,
+                    entries: children.len(),
+                }),
                 VFileSpecialized::File(size) => StatResponse::File(StatResponseFile {
+                    disk_id: file.location.0 .0 as usize,
                     node_id: file.location.1,
                     file_size: size,
                 }),
+                // Lookups follow symlinks
+                VFileSpecialized::Symlink(_) => return Err(FSServiceError::CouldNotFollowPath),
             };
 
             Ok((FSServiceMessageResp::StatResponse(stat), None))
         }
+        FSServiceMessage::ReadDir(req) => {
+            let VFileSpecialized::Folder(children) = vfs::lookup(req.path)?.specialized else {
+                return Err(FSServiceError::InvalidRequestForFileType);
+            };
+            *btree_child_buf = children;
+            let mut names = match req.after {
+                Some(after) => btree_child_buf.range::<str, _>((Excluded(after), Unbounded)),
+                None => btree_child_buf.range::<str, _>(..),
+            }
+            .map(|(name, _)| name.as_str());
+            let entries: Vec<&str> = names.by_ref().take(req.max.max(1)).collect();
+            let page = ReadDirPage {
+                entries,
+                more: names.next().is_some(),
+            };
+            Ok((FSServiceMessageResp::ReadDirResponse(page), None))
+        }
         FSServiceMessage::ReadRequest(req) => {
-            if let Some(len) = read_file_sector(
+            let data = read_file_range(
                 (PartitionId(req.disk_id as u64), req.node_id),
-                req.sector as usize,
-                sec_buffer,
-            )? {
-                Ok((
-                    FSServiceMessageResp::ReadResponse(Some(len)),
-                    Some(MessageHandle::create(&sec_buffer[0..len])),
-                ))
-            } else {
-                Ok((FSServiceMessageResp::ReadResponse(None), None))
+                req.offset,
+                req.len,
+                buffer,
+            )?;
+            if data.is_empty() {
+                return Ok((FSServiceMessageResp::ReadResponse(None), None));
             }
+            Ok((
+                FSServiceMessageResp::ReadResponse(Some(data.len())),
+                Some(MessageHandle::create(data).into_kref()),
+            ))
         }
         FSServiceMessage::ReadFullFileRequest(req) => {
             let file_vec = read_file((PartitionId(req.disk_id as u64), req.node_id), buffer)?;
             Ok((
                 FSServiceMessageResp::ReadResponse(Some(file_vec.len())),
-                Some(MessageHandle::create(file_vec)),
+                Some(MessageHandle::create(file_vec).into_kref()),
             ))
         }
         FSServiceMessage::GetDisksRequest => {
             let disks = PARTITION.lock().keys().map(|p| p.0).collect();
             Ok((FSServiceMessageResp::GetDisksResponse(disks), None))
         }
+        FSServiceMessage::CreateFile(path) => {
+            let (_, node) = vfs::create(path, false)?;
+            Ok((FSServiceMessageResp::CreateResponse(node), None))
+        }
+        FSServiceMessage::CreateFolder(path) => {
+            let (_, node) = vfs::create(path, true)?;
+            Ok((FSServiceMessageResp::CreateResponse(node), None))
+        }
+        FSServiceMessage::Delete(path) => {
+            vfs::delete(path)?;
+            Ok((FSServiceMessageResp::DeleteResponse, None))
+        }
+        FSServiceMessage::Rename(from, to) => {
+            vfs::rename(from, to)?;
+            Ok((FSServiceMessageResp::DeleteResponse, None))
+        }
+        FSServiceMessage::WriteRequest(req) => {
+            let data = handles.first().ok_or(FSServiceError::InvalidRequest)?;
+            let data = MessageHandle::from_kref(KernelReference::from_id(*data)).read_vec();
+            let size = write_file(
+                (PartitionId(req.disk_id as u64), req.node_id),
+                req.offset,
+                &data,
+            )?;
+            Ok((FSServiceMessageResp::WriteResponse(size), None))
+        }
+        FSServiceMessage::TruncateRequest(req) => {
+            let file = (PartitionId(req.disk_id as u64), req.node_id);
+            set_file_size(file, req.size)?;
+            watch::file_modified(file);
+            Ok((FSServiceMessageResp::WriteResponse(req.size), None))
+        }
+        FSServiceMessage::Mount(disk, path) => {
+            vfs::mount(PartitionId(disk as u64), path)?;
+            Ok((FSServiceMessageResp::MountResponse, None))
+        }
+        FSServiceMessage::Umount(path) => {
+            vfs::umount(path)?;
+            Ok((FSServiceMessageResp::MountResponse, None))
+        }
+        FSServiceMessage::CreateSymlink(path, target) => {
+            let (_, node) = vfs::symlink(path, target)?;
+            Ok((FSServiceMessageResp::CreateResponse(node), None))
+        }
+        FSServiceMessage::ReadLink(path) => {
+            let target = vfs::read_link(path)?;
+            Ok((FSServiceMessageResp::PathResponse(target), None))
+        }
+        FSServiceMessage::Canonicalize(path) => {
+            let path = vfs::canonicalize(path, true)?;
+            vfs::lookup(&path)?;
+            Ok((FSServiceMessageResp::PathResponse(path), None))
+        }
+        FSServiceMessage::Watch(path) => {
+            let watcher = watch::add(path)?;
+            Ok((FSServiceMessageResp::WatchResponse, Some(watcher)))
+        }
+        FSServiceMessage::Mmap(disk, node) => {
+            let (mem, size) = mmap_file((PartitionId(disk as u64), node))?;
+            Ok((FSServiceMessageResp::MmapResponse(size), Some(mem)))
+        }
+        FSServiceMessage::OpenIoQueue => {
+            let queue = io_queue::open();
+            Ok((FSServiceMessageResp::IoQueueResponse, Some(queue)))
+        }
+        FSServiceMessage::Check(disk, repair) => {
+            let problems = check_partition(PartitionId(disk as u64), repair)?;
+            Ok((FSServiceMessageResp::CheckResponse(problems), None))
+        }
+        FSServiceMessage::Rescan(name) => {
+            let partitions = FSDRIVES.lock().rescan(name)?;
+            let partitions = partitions.into_iter().map(|p| p.0).collect();
+            Ok((FSServiceMessageResp::GetDisksResponse(partitions), None))
+        }
+        FSServiceMessage::GetMountsRequest => {
+            let mounts = vfs::mounts()
+                .into_iter()
+                .map(|(path, id)| MountInfo {
+                    path,
+                    disk_id: id.0,
+                })
+                .collect();
+            Ok((FSServiceMessageResp::GetMountsResponse(mounts), None))
+        }
     }
 }
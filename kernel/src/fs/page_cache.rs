@@ -0,0 +1,171 @@
+//! Unified page cache for file contents.
+//!
+//! Every read of a file goes through here, as do file backed mappings which share the cached
+//! pages directly. Pages written through a mapping are marked dirty and written back to the file
+//! system periodically by [`page_cache_writeback`]. Clean pages that nothing else references are
+//! dropped when memory is short.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use kernel_userspace::{fs::FSServiceError, memory::MemoryPressure, syscall::sleep};
+
+use crate::{
+    mutex::Spinlock,
+    paging::{
+        pressure::{register_shrinker, Shrinker},
+        virt_addr_for_phys, AllocatedPage, GlobalPageAllocator,
+    },
+};
+
+use super::{
+    block_cache::{self, BlockCacheShrinker},
+    file_size, with_partition, PartitionId, VFileID,
+};
+
+const WRITEBACK_INTERVAL_MS: u64 = 5000;
+
+pub type CachePage = Arc<AllocatedPage<GlobalPageAllocator>>;
+
+struct CachedPage {
+    page: CachePage,
+    dirty: bool,
+}
+
+/// Indexed by file and page within the file
+static PAGE_CACHE: Spinlock<BTreeMap<(VFileID, usize), CachedPage>> =
+    Spinlock::new(BTreeMap::new());
+
+pub fn page_data(page: &CachePage) -> &[u8; 0x1000] {
+    unsafe { &*(virt_addr_for_phys(page.get_address()) as *const [u8; 0x1000]) }
+}
+
+/// The page can be mapped and written to at the same time, it has to be marked dirty after
+///
+/// # Safety
+/// Nothing else may write to the same part of the page while the reference is alive
+#[allow(clippy::mut_from_ref)]
+pub unsafe fn page_data_mut(page: &CachePage) -> &mut [u8; 0x1000] {
+    &mut *(virt_addr_for_phys(page.get_address()) as *mut [u8; 0x1000])
+}
+
+/// Gets a page of the file, reading it in if it isn't cached. Anything past the end of the file
+/// is zeroed.
+pub fn get_page(file: VFileID, index: usize) -> Result<CachePage, FSServiceError> {
+    if let Some(cached) = PAGE_CACHE.lock().get(&(file, index)) {
+        return Ok(cached.page.clone());
+    }
+
+    let page = AllocatedPage::new(GlobalPageAllocator).unwrap();
+    let buffer = unsafe { &mut *(virt_addr_for_phys(page.get_address()) as *mut [u8; 0x1000]) };
+    with_partition(file.0, |p| p.read_file_page(file.1, index, buffer))?;
+
+    // Someone else might have read it in at the same time
+    let mut cache = PAGE_CACHE.lock();
+    let cached = cache.entry((file, index)).or_insert(CachedPage {
+        page: Arc::new(page),
+        dirty: false,
+    });
+    Ok(cached.page.clone())
+}
+
+/// The number of cached pages and how many of them are dirty
+pub fn stats() -> (usize, usize) {
+    let cache = PAGE_CACHE.lock();
+    let dirty = cache.values().filter(|c| c.dirty).count();
+    (cache.len(), dirty)
+}
+
+pub fn mark_dirty(file: VFileID, index: usize) {
+    if let Some(cached) = PAGE_CACHE.lock().get_mut(&(file, index)) {
+        cached.dirty = true;
+    }
+}
+
+/// Drops every cached page of the file past size and zeroes the rest of the last page, so that
+/// nothing stale shows up if the file grows again. Pages that are mapped keep their contents but
+/// are no longer written back.
+pub fn truncate(file: VFileID, size: usize) {
+    let mut cache = PAGE_CACHE.lock();
+    let pages = size.div_ceil(0x1000);
+    cache.retain(|&(f, index), _| f != file || index < pages);
+
+    if size % 0x1000 != 0 {
+        if let Some(cached) = cache.get(&(file, size / 0x1000)) {
+            let data = unsafe { page_data_mut(&cached.page) };
+            data[size % 0x1000..].fill(0);
+        }
+    }
+}
+
+/// Drops every page of the partition, dirty or not, for when its disk has gone away
+pub fn forget_partition(partition: PartitionId) {
+    PAGE_CACHE
+        .lock()
+        .retain(|&(file, _), _| file.0 != partition);
+}
+
+/// Writes every dirty page back to its file system. Pages that are still mapped stay dirty as
+/// they can be written to again without faulting.
+pub fn writeback() {
+    write_dirty(|_| true);
+    block_cache::flush();
+}
+
+/// Writes the dirty pages of the file back now
+pub fn flush_file(file: VFileID) {
+    write_dirty(|f| f == file)
+}
+
+fn write_dirty(filter: impl Fn(VFileID) -> bool) {
+    let dirty: Vec<_> = PAGE_CACHE
+        .lock()
+        .iter_mut()
+        .filter(|(&(file, _), c)| c.dirty && filter(file))
+        .map(|(&key, c)| {
+            c.dirty = Arc::strong_count(&c.page) > 1;
+            (key, c.page.clone())
+        })
+        .collect();
+
+    for ((file, index), page) in dirty {
+        let res = file_size(file).and_then(|size| {
+            let len = size.saturating_sub(index * 0x1000).min(0x1000);
+            with_partition(file.0, |p| {
+                p.write_file_page(file.1, index, &page_data(&page)[..len])
+            })
+        });
+        if let Err(e) = res {
+            error!("Failed to write back page {index} of {file:?}: {e:?}");
+        }
+    }
+}
+
+/// Drops clean pages that are only referenced by the cache
+struct PageCacheShrinker;
+
+impl Shrinker for PageCacheShrinker {
+    fn name(&self) -> &'static str {
+        "page cache"
+    }
+
+    fn shrink(&self, pages: usize, _level: MemoryPressure) -> usize {
+        let mut freed = 0;
+        PAGE_CACHE.lock().retain(|_, c| {
+            if freed == pages || c.dirty || Arc::strong_count(&c.page) > 1 {
+                return true;
+            }
+            freed += 1;
+            false
+        });
+        freed
+    }
+}
+
+pub fn page_cache_writeback() {
+    register_shrinker(&PageCacheShrinker);
+    register_shrinker(&BlockCacheShrinker);
+
+    loop {
+        sleep(WRITEBACK_INTERVAL_MS);
+        writeback();
+    }
+}
@@ -0,0 +1,228 @@
+//! Kernel and process information as text files, mounted at `/proc`.
+//!
+//! Nothing is stored, every read generates the file again from the process table and the kernel
+//! counters. Each process gets a folder named after its pid with a `status` and a `cmdline` file.
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt::Write;
+use kernel_userspace::{
+    fs::FSServiceError, ids::ProcessID, process::ProcessExit, stats::LOADAVG_FSHIFT,
+};
+
+use crate::{
+    interrupts::interrupt_counts,
+    paging::{page_allocator::frame_alloc_exec, pressure::memory_pressure},
+    scheduling::{stats::collect_sched_stats, taskmanager::PROCESSES},
+    time::uptime,
+};
+
+use super::{
+    add_partition, block_cache, next_partition_id, page_cache, vfs, FileSystemDev, PartitionId,
+    VFile, VFileSpecialized,
+};
+
+const MEMINFO: usize = 1;
+const UPTIME: usize = 2;
+const LOADAVG: usize = 3;
+const INTERRUPTS: usize = 4;
+
+const KERNEL_FILES: [(&str, usize); 4] = [
+    ("meminfo", MEMINFO),
+    ("uptime", UPTIME),
+    ("loadavg", LOADAVG),
+    ("interrupts", INTERRUPTS),
+];
+
+/// Process file ids are `(pid + 1) << PID_SHIFT | node`, the folder itself is node 0
+const PID_SHIFT: usize = 8;
+const PROCESS_FILES: [(&str, usize); 2] = [("status", 1), ("cmdline", 2)];
+
+pub struct ProcFs {
+    partition_id: PartitionId,
+}
+
+pub fn mount_procfs(path: &str) {
+    let partition_id = next_partition_id();
+    add_partition(partition_id, ProcFs { partition_id });
+    if let Err(e) = vfs::mount(partition_id, path) {
+        warn!("Failed to mount procfs at {path}: {e:?}");
+    }
+}
+
+fn process_file(pid: ProcessID, node: usize) -> usize {
+    (pid.0 as usize + 1) << PID_SHIFT | node
+}
+
+fn split_process_file(file_id: usize) -> Option<(ProcessID, usize)> {
+    let pid = (file_id >> PID_SHIFT).checked_sub(1)?;
+    Some((ProcessID(pid as u64), file_id & ((1 << PID_SHIFT) - 1)))
+}
+
+impl ProcFs {
+    fn folder(&self, file_id: usize) -> Option<BTreeMap<String, usize>> {
+        if file_id == 0 {
+            let mut children: BTreeMap<String, usize> = KERNEL_FILES
+                .iter()
+                .map(|&(name, id)| (String::from(name), id))
+                .collect();
+            for &pid in PROCESSES.lock().keys() {
+                children.insert(pid.0.to_string(), process_file(pid, 0));
+            }
+            return Some(children);
+        }
+
+        match split_process_file(file_id)? {
+            (pid, 0) if PROCESSES.lock().contains_key(&pid) => Some(
+                PROCESS_FILES
+                    .iter()
+                    .map(|&(name, node)| (String::from(name), process_file(pid, node)))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Generates the contents of the file
+    fn contents(&self, file_id: usize) -> Result<Vec<u8>, FSServiceError> {
+        let text = match file_id {
+            0 => return Err(FSServiceError::InvalidRequestForFileType),
+            MEMINFO => meminfo(),
+            UPTIME => {
+                let ms = uptime();
+                format!("{}.{:03}\n", ms / 1000, ms % 1000)
+            }
+            LOADAVG => loadavg(),
+            INTERRUPTS => interrupts(),
+            _ => match split_process_file(file_id) {
+                Some((pid, 0)) if PROCESSES.lock().contains_key(&pid) => {
+                    return Err(FSServiceError::InvalidRequestForFileType)
+                }
+                Some((pid, 1)) => status(pid)?,
+                Some((pid, 2)) => {
+                    let process = PROCESSES.lock().get(&pid).cloned();
+                    return Ok(process.ok_or(FSServiceError::FileNotFound)?.args.clone());
+                }
+                _ => return Err(FSServiceError::FileNotFound),
+            },
+        };
+        Ok(text.into_bytes())
+    }
+}
+
+fn meminfo() -> String {
+    let stats = frame_alloc_exec(|a| a.stats());
+    let (cached, dirty) = page_cache::stats();
+    let (blocks, dirty_blocks) = block_cache::stats();
+
+    let mut s = String::new();
+    writeln!(s, "MemTotal: {} kB", stats.total_pages * 4).unwrap();
+    writeln!(s, "MemFree: {} kB", stats.free_pages * 4).unwrap();
+    writeln!(s, "MemFreeBelow4G: {} kB", stats.free_pages_below_32 * 4).unwrap();
+    writeln!(s, "PageCache: {} kB", cached * 4).unwrap();
+    writeln!(s, "Dirty: {} kB", dirty * 4).unwrap();
+    writeln!(s, "BlockCache: {} kB", blocks / 2).unwrap();
+    writeln!(s, "BlockCacheDirty: {} kB", dirty_blocks / 2).unwrap();
+    writeln!(s, "Pressure: {:?}", memory_pressure()).unwrap();
+    s
+}
+
+fn loadavg() -> String {
+    let stats = collect_sched_stats();
+    let [a, b, c] = stats.loadavg.map(|l| {
+        let l = (l * 100) >> LOADAVG_FSHIFT;
+        format!("{}.{:02}", l / 100, l % 100)
+    });
+    format!("{a} {b} {c} {} {}\n", stats.run_queue_depth, stats.threads)
+}
+
+fn interrupts() -> String {
+    let mut s = String::new();
+    for (vector, count) in interrupt_counts() {
+        writeln!(s, "{vector:>3}: {count}").unwrap();
+    }
+    s
+}
+
+fn status(pid: ProcessID) -> Result<String, FSServiceError> {
+    let process = PROCESSES
+        .lock()
+        .get(&pid)
+        .cloned()
+        .ok_or(FSServiceError::FileNotFound)?;
+    let usage = process.get_rusage();
+    let mem = process.get_mem_info();
+    let threads = process.threads.lock().threads.len();
+    let state = match *process.exit_status.lock() {
+        ProcessExit::Exited => "exited",
+        ProcessExit::NotExitedYet => "running",
+    };
+
+    let mut s = String::new();
+    writeln!(s, "Name: {}", process.name).unwrap();
+    writeln!(s, "Pid: {}", pid.0).unwrap();
+    writeln!(s, "State: {state}").unwrap();
+    writeln!(s, "Privilege: {:?}", process.privilege).unwrap();
+    writeln!(s, "Threads: {threads}").unwrap();
+    writeln!(s, "Handles: {}", usage.handle_count).unwrap();
+    writeln!(s, "UserTime: {} us", usage.user_time_us).unwrap();
+    writeln!(s, "KernelTime: {} us", usage.kernel_time_us).unwrap();
+    writeln!(s, "ContextSwitches: {}", usage.context_switches).unwrap();
+    writeln!(s, "Mapped: {} kB", mem.mapped_pages * 4).unwrap();
+    writeln!(s, "PeakMapped: {} kB", mem.peak_mapped_pages * 4).unwrap();
+    writeln!(s, "Committed: {} kB", mem.committed_pages * 4).unwrap();
+    writeln!(s, "Shared: {} kB", mem.shared_pages * 4).unwrap();
+    Ok(s)
+}
+
+impl FileSystemDev for ProcFs {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let partition_id = self.partition_id;
+        let specialized = match self.folder(file_id) {
+            Some(children) => VFileSpecialized::Folder(
+                children
+                    .into_iter()
+                    .map(|(name, id)| (name, (partition_id, id)))
+                    .collect(),
+            ),
+            None => VFileSpecialized::File(self.contents(file_id)?.len()),
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        *buffer = self.contents(file_id)?;
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        let data = self.contents(file_id)?;
+        let start = file_sector * 512;
+        if start >= data.len() {
+            return Ok(None);
+        }
+        let len = (data.len() - start).min(512);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(Some(len))
+    }
+
+    fn cached(&self) -> bool {
+        false
+    }
+}
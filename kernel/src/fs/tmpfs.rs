@@ -0,0 +1,242 @@
+//! In memory file system, mounted at `/tmp` and handy as scratch space that doesn't need a disk.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use kernel_userspace::fs::FSServiceError;
+
+use super::{
+    add_partition, next_partition_id, vfs, FileSystemDev, PartitionId, VFile, VFileSpecialized,
+};
+
+enum TmpNode {
+    Folder(BTreeMap<String, usize>),
+    File(Vec<u8>),
+    Symlink(String),
+}
+
+pub struct TmpFs {
+    partition_id: PartitionId,
+    /// The root is always file id 0
+    nodes: BTreeMap<usize, TmpNode>,
+    next_id: usize,
+}
+
+impl TmpFs {
+    pub fn new(partition_id: PartitionId) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(0, TmpNode::Folder(BTreeMap::new()));
+        Self {
+            partition_id,
+            nodes,
+            next_id: 1,
+        }
+    }
+
+    fn folder(&mut self, folder_id: usize) -> Result<&mut BTreeMap<String, usize>, FSServiceError> {
+        match self.nodes.get_mut(&folder_id) {
+            Some(TmpNode::Folder(children)) => Ok(children),
+            Some(_) => Err(FSServiceError::InvalidRequestForFileType),
+            None => Err(FSServiceError::FileNotFound),
+        }
+    }
+
+    fn file(&mut self, file_id: usize) -> Result<&mut Vec<u8>, FSServiceError> {
+        match self.nodes.get_mut(&file_id) {
+            Some(TmpNode::File(data)) => Ok(data),
+            Some(_) => Err(FSServiceError::InvalidRequestForFileType),
+            None => Err(FSServiceError::FileNotFound),
+        }
+    }
+
+    /// Adds the node to the folder, returns its id
+    fn insert(
+        &mut self,
+        folder_id: usize,
+        name: &str,
+        node: TmpNode,
+    ) -> Result<usize, FSServiceError> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FSServiceError::InvalidName);
+        }
+        let id = self.next_id;
+        let folder = self.folder(folder_id)?;
+        if folder.contains_key(name) {
+            return Err(FSServiceError::AlreadyExists);
+        }
+        folder.insert(String::from(name), id);
+
+        self.nodes.insert(id, node);
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    /// If node is folder or has it somewhere inside of it
+    fn contains(&self, node: usize, folder: usize) -> bool {
+        if node == folder {
+            return true;
+        }
+        match self.nodes.get(&node) {
+            Some(TmpNode::Folder(children)) => children.values().any(|&c| self.contains(c, folder)),
+            _ => false,
+        }
+    }
+}
+
+/// Creates an empty tmpfs and mounts it at the path
+pub fn mount_tmpfs(path: &str) {
+    let partition_id = next_partition_id();
+    add_partition(partition_id, TmpFs::new(partition_id));
+    if let Err(e) = vfs::mount(partition_id, path) {
+        warn!("Failed to mount tmpfs at {path}: {e:?}");
+    }
+}
+
+impl FileSystemDev for TmpFs {
+    fn get_file_by_id(&mut self, file_id: usize) -> Result<VFile, FSServiceError> {
+        let partition_id = self.partition_id;
+        let specialized = match self.nodes.get(&file_id) {
+            Some(TmpNode::Folder(children)) => VFileSpecialized::Folder(
+                children
+                    .iter()
+                    .map(|(name, &id)| (name.clone(), (partition_id, id)))
+                    .collect(),
+            ),
+            Some(TmpNode::File(data)) => VFileSpecialized::File(data.len()),
+            Some(TmpNode::Symlink(target)) => VFileSpecialized::Symlink(target.clone()),
+            None => return Err(FSServiceError::FileNotFound),
+        };
+        Ok(VFile {
+            location: (partition_id, file_id),
+            specialized,
+        })
+    }
+
+    fn read_file<'a>(
+        &mut self,
+        file_id: usize,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], FSServiceError> {
+        buffer.clear();
+        buffer.extend_from_slice(self.file(file_id)?);
+        Ok(buffer)
+    }
+
+    fn read_file_sector(
+        &mut self,
+        file_id: usize,
+        file_sector: usize,
+        buffer: &mut [u8; 512],
+    ) -> Result<Option<usize>, FSServiceError> {
+        let data = self.file(file_id)?;
+        let start = file_sector * 512;
+        if start >= data.len() {
+            return Ok(None);
+        }
+        let len = (data.len() - start).min(512);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(Some(len))
+    }
+
+    fn read_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        buffer: &mut [u8; 0x1000],
+    ) -> Result<(), FSServiceError> {
+        let data = self.file(file_id)?;
+        let start = (page * 0x1000).min(data.len());
+        let len = (data.len() - start).min(0x1000);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(())
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn write_file_page(
+        &mut self,
+        file_id: usize,
+        page: usize,
+        data: &[u8],
+    ) -> Result<(), FSServiceError> {
+        let file = self.file(file_id)?;
+        let start = (page * 0x1000).min(file.len());
+        let len = (file.len() - start).min(data.len());
+        file[start..start + len].copy_from_slice(&data[..len]);
+        Ok(())
+    }
+
+    fn create(
+        &mut self,
+        folder_id: usize,
+        name: &str,
+        is_folder: bool,
+    ) -> Result<usize, FSServiceError> {
+        let node = match is_folder {
+            true => TmpNode::Folder(BTreeMap::new()),
+            false => TmpNode::File(Vec::new()),
+        };
+        self.insert(folder_id, name, node)
+    }
+
+    fn create_symlink(
+        &mut self,
+        folder_id: usize,
+        name: &str,
+        target: &str,
+    ) -> Result<usize, FSServiceError> {
+        if target.is_empty() {
+            return Err(FSServiceError::InvalidRequest);
+        }
+        self.insert(folder_id, name, TmpNode::Symlink(String::from(target)))
+    }
+
+    fn set_file_size(&mut self, file_id: usize, size: usize) -> Result<(), FSServiceError> {
+        let file = self.file(file_id)?;
+        file.resize(size, 0);
+        file.shrink_to_fit();
+        Ok(())
+    }
+
+    fn delete(&mut self, folder_id: usize, name: &str) -> Result<(), FSServiceError> {
+        let id = *self
+            .folder(folder_id)?
+            .get(name)
+            .ok_or(FSServiceError::FileNotFound)?;
+        if let Some(TmpNode::Folder(children)) = self.nodes.get(&id) {
+            if !children.is_empty() {
+                return Err(FSServiceError::FolderNotEmpty);
+            }
+        }
+        self.folder(folder_id)?.remove(name);
+        self.nodes.remove(&id);
+        Ok(())
+    }
+
+    fn rename(
+        &mut self,
+        from_folder: usize,
+        from_name: &str,
+        to_folder: usize,
+        to_name: &str,
+    ) -> Result<(), FSServiceError> {
+        if to_name.is_empty() || to_name == "." || to_name == ".." || to_name.contains('/') {
+            return Err(FSServiceError::InvalidName);
+        }
+        let id = *self
+            .folder(from_folder)?
+            .get(from_name)
+            .ok_or(FSServiceError::FileNotFound)?;
+        if self.folder(to_folder)?.contains_key(to_name) {
+            return Err(FSServiceError::AlreadyExists);
+        }
+        // A folder can't be moved inside of itself
+        if self.contains(id, to_folder) {
+            return Err(FSServiceError::InvalidRequest);
+        }
+
+        self.folder(from_folder)?.remove(from_name);
+        self.folder(to_folder)?.insert(String::from(to_name), id);
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+//! The single namespace every file system is mounted into.
+//!
+//! File systems only know about paths from their own root. The mount table maps absolute paths to
+//! partitions and the path walker picks the deepest mount a path is under before handing the rest
+//! of the path to that file system. Mount points show up as children of the folder they are
+//! mounted in, even if that folder doesn't have an entry for them on disk.
+//!
+//! Every path is made canonical here before it is used. `.`, `..` and repeated separators are
+//! dropped and symlinks are replaced by their targets, so file systems only ever see plain paths.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use kernel_userspace::fs::{FSServiceError, FsEventKind};
+
+use crate::mutex::Spinlock;
+
+use super::{
+    create_file, create_symlink, delete_file, get_file_from_path, page_cache, rename_file, watch,
+    with_partition, PartitionId, VFile, VFileID, VFileSpecialized,
+};
+
+/// Most symlinks followed while resolving one path, anything more is taken to be a loop
+const MAX_SYMLINKS: usize = 40;
+
+/// Indexed by the normalized path
+static MOUNTS: Spinlock<BTreeMap<String, PartitionId>> = Spinlock::new(BTreeMap::new());
+
+/// The components of the path, `.` and `..` are handled here so file systems never see them
+fn components(path: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    parts
+}
+
+fn join<S: AsRef<str>>(parts: &[S]) -> String {
+    let mut path = String::new();
+    for part in parts {
+        path.push('/');
+        path.push_str(part.as_ref());
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// The absolute path with no `.`, `..` or symlinks in it. If follow_last is false a symlink at
+/// the end is left as it is, and the last component doesn't have to exist so that it can be
+/// created.
+pub fn canonicalize(path: &str, follow_last: bool) -> Result<String, FSServiceError> {
+    let mut resolved: Vec<String> = Vec::new();
+    // The components still to walk, the next one is at the end
+    let mut pending: Vec<String> = path.split('/').rev().map(String::from).collect();
+    let mut links = 0;
+
+    while let Some(part) = pending.pop() {
+        match part.as_str() {
+            "" | "." => continue,
+            ".." => {
+                resolved.pop();
+                continue;
+            }
+            _ => resolved.push(part),
+        }
+        let last = pending.iter().all(|p| p.is_empty() || p == ".");
+        if last && !follow_last {
+            break;
+        }
+
+        let (id, rest) = resolve(&join(&resolved))?;
+        // Mount points are never links
+        if rest == "/" {
+            continue;
+        }
+        let target = match get_file_from_path(id, &rest) {
+            Ok(VFile {
+                specialized: VFileSpecialized::Symlink(target),
+                ..
+            }) => target,
+            Ok(_) => continue,
+            Err(FSServiceError::CouldNotFollowPath) if last => break,
+            Err(e) => return Err(e),
+        };
+
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(FSServiceError::SymlinkLoop);
+        }
+        resolved.pop();
+        if target.starts_with('/') {
+            resolved.clear();
+        }
+        pending.extend(target.split('/').rev().map(String::from));
+    }
+    Ok(join(&resolved))
+}
+
+/// Finds the deepest mount the path is under, returns its partition and the path within it
+pub fn resolve(path: &str) -> Result<(PartitionId, String), FSServiceError> {
+    let parts = components(path);
+    let mounts = MOUNTS.lock();
+    for i in (0..=parts.len()).rev() {
+        if let Some(&id) = mounts.get(&join(&parts[..i])) {
+            return Ok((id, join(&parts[i..])));
+        }
+    }
+    Err(FSServiceError::CouldNotFollowPath)
+}
+
+/// Walks the path following any symlinks, folders include whatever is mounted in them
+pub fn lookup(path: &str) -> Result<VFile, FSServiceError> {
+    let path = canonicalize(path, true)?;
+    let (id, rest) = resolve(&path)?;
+    let mut file = get_file_from_path(id, &rest)?;
+
+    if let VFileSpecialized::Folder(children) = &mut file.specialized {
+        let folder = path;
+        for (mount, &id) in MOUNTS.lock().iter() {
+            let Some((parent, name)) = mount.rsplit_once('/') else {
+                continue;
+            };
+            let parent = if parent.is_empty() { "/" } else { parent };
+            if !name.is_empty() && parent == folder {
+                children.insert(String::from(name), (id, 0));
+            }
+        }
+    }
+    Ok(file)
+}
+
+/// Creates an empty file or folder at the path
+pub fn create(path: &str, is_folder: bool) -> Result<VFileID, FSServiceError> {
+    let path = canonicalize(path, false)?;
+    let (id, rest) = resolve(&path)?;
+    if rest == "/" {
+        return Err(FSServiceError::AlreadyExists);
+    }
+    let file = create_file(id, &rest, is_folder)?;
+    watch::path_changed(&path, FsEventKind::Created);
+    Ok(file)
+}
+
+/// Removes the file or empty folder at the path, mount points can't be removed
+pub fn delete(path: &str) -> Result<(), FSServiceError> {
+    let path = canonicalize(path, false)?;
+    let (id, rest) = resolve(&path)?;
+    if rest == "/" {
+        return Err(FSServiceError::Busy);
+    }
+    delete_file(id, &rest)?;
+    watch::path_changed(&path, FsEventKind::Deleted);
+    Ok(())
+}
+
+/// Moves the file or folder, both paths have to be on the same partition and neither can be a
+/// mount point. Watchers see it as the old path being deleted and the new one created.
+pub fn rename(from: &str, to: &str) -> Result<(), FSServiceError> {
+    let from_path = canonicalize(from, false)?;
+    let to_path = canonicalize(to, false)?;
+    let (from_id, from) = resolve(&from_path)?;
+    let (to_id, to) = resolve(&to_path)?;
+    if from_id != to_id {
+        return Err(FSServiceError::CrossDevice);
+    }
+    if from == "/" || to == "/" {
+        return Err(FSServiceError::Busy);
+    }
+    rename_file(from_id, &from, &to)?;
+    watch::path_changed(&from_path, FsEventKind::Deleted);
+    watch::path_changed(&to_path, FsEventKind::Created);
+    Ok(())
+}
+
+/// Creates a symlink at the path pointing at target, which doesn't have to exist
+pub fn symlink(path: &str, target: &str) -> Result<VFileID, FSServiceError> {
+    let path = canonicalize(path, false)?;
+    let (id, rest) = resolve(&path)?;
+    if rest == "/" {
+        return Err(FSServiceError::AlreadyExists);
+    }
+    let file = create_symlink(id, &rest, target)?;
+    watch::path_changed(&path, FsEventKind::Created);
+    Ok(file)
+}
+
+/// Where the symlink at the path points
+pub fn read_link(path: &str) -> Result<String, FSServiceError> {
+    let (id, rest) = resolve(&canonicalize(path, false)?)?;
+    match get_file_from_path(id, &rest)?.specialized {
+        VFileSpecialized::Symlink(target) => Ok(target),
+        _ => Err(FSServiceError::InvalidRequestForFileType),
+    }
+}
+
+pub fn mount(id: PartitionId, path: &str) -> Result<(), FSServiceError> {
+    with_partition(id, |_| Ok(()))?;
+
+    let path = join(&components(path));
+    let mut mounts = MOUNTS.lock();
+    if mounts.contains_key(&path) {
+        return Err(FSServiceError::AlreadyExists);
+    }
+    mounts.insert(path, id);
+    Ok(())
+}
+
+/// Unmounts whatever is at the path after writing back its dirty pages, anything mounted inside
+/// of it has to be unmounted first
+pub fn umount(path: &str) -> Result<PartitionId, FSServiceError> {
+    let path = join(&components(path));
+    let prefix = if path == "/" {
+        path.clone()
+    } else {
+        format!("{path}/")
+    };
+    {
+        let mounts = MOUNTS.lock();
+        if !mounts.contains_key(&path) {
+            return Err(FSServiceError::NotMounted);
+        }
+        if mounts.keys().any(|m| *m != path && m.starts_with(&prefix)) {
+            return Err(FSServiceError::Busy);
+        }
+    }
+
+    page_cache::writeback();
+    MOUNTS
+        .lock()
+        .remove(&path)
+        .ok_or(FSServiceError::NotMounted)
+}
+
+/// Removes every mount of the partition without writing anything back, for when its disk has
+/// gone away
+pub fn unmount_partition(id: PartitionId) {
+    MOUNTS.lock().retain(|path, &mut p| {
+        if p == id {
+            info!("Unmounted {path}, the disk was removed");
+        }
+        p != id
+    });
+}
+
+/// Every mount point and what is mounted there
+pub fn mounts() -> Vec<(String, PartitionId)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|(path, &id)| (path.clone(), id))
+        .collect()
+}
+
+/// Mounts a newly found partition, the first one becomes the root and the rest go in `/diskN`
+pub fn auto_mount(id: PartitionId) {
+    let path = match MOUNTS.lock().contains_key("/") {
+        true => format!("/disk{}", id.0),
+        false => String::from("/"),
+    };
+    match mount(id, &path) {
+        Ok(()) => info!("Mounted partition {} at {path}", id.0),
+        Err(e) => warn!("Failed to mount partition {} at {path}: {e:?}", id.0),
+    }
+}
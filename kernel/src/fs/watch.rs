@@ -0,0 +1,127 @@
+//! Change notifications for files and folders.
+//!
+//! A client watches a path and gets a channel that [`FsEvent`]s are sent down. Watching a folder
+//! reports changes to the folder itself and to anything directly inside of it, watching a file
+//! only reports changes to that file. A watch goes away once the client closes its end.
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use kernel_userspace::{
+    channel::{channel_create_rs, channel_write_rs},
+    fs::{FSServiceError, FsEvent, FsEventKind},
+    object::KernelReference,
+    service::serialize,
+};
+
+use crate::mutex::Spinlock;
+
+use super::{get_file_by_id, vfs, VFileID, VFileSpecialized};
+
+struct Watch {
+    /// Canonical path of what is being watched
+    path: String,
+    node: VFileID,
+    channel: KernelReference,
+}
+
+struct Watches {
+    watches: BTreeMap<u64, Watch>,
+    next_id: u64,
+}
+
+static WATCHES: Spinlock<Watches> = Spinlock::new(Watches {
+    watches: BTreeMap::new(),
+    next_id: 0,
+});
+
+/// Starts watching the path, returns the client end of the channel events are sent on
+pub fn add(path: &str) -> Result<KernelReference, FSServiceError> {
+    let path = vfs::canonicalize(path, true)?;
+    let node = vfs::lookup(&path)?.location;
+    let (channel, client) = channel_create_rs();
+
+    let mut watches = WATCHES.lock();
+    let id = watches.next_id;
+    watches.next_id += 1;
+    watches.watches.insert(
+        id,
+        Watch {
+            path,
+            node,
+            channel,
+        },
+    );
+    Ok(client)
+}
+
+/// Sends the events, dropping any watch whose client has gone away
+fn send(events: Vec<(u64, FsEvent)>) {
+    if events.is_empty() {
+        return;
+    }
+    let mut buffer = Vec::new();
+    let mut watches = WATCHES.lock();
+    for (id, event) in events {
+        let Some(watch) = watches.watches.get(&id) else {
+            continue;
+        };
+        let msg = serialize(&event, &mut buffer);
+        if !channel_write_rs(watch.channel.id(), msg, &[]) {
+            watches.watches.remove(&id);
+        }
+    }
+}
+
+/// Something was created or deleted at the canonical path
+pub fn path_changed(path: &str, kind: FsEventKind) {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let parent = if parent.is_empty() { "/" } else { parent };
+
+    let events = WATCHES
+        .lock()
+        .watches
+        .iter()
+        .filter_map(|(&id, watch)| {
+            let name = match watch.path.as_str() {
+                p if p == path => String::new(),
+                p if p == parent => String::from(name),
+                _ => return None,
+            };
+            Some((id, FsEvent { kind, name }))
+        })
+        .collect();
+    send(events);
+}
+
+/// The contents or size of the file changed
+pub fn file_modified(file: VFileID) {
+    let watched: Vec<(u64, VFileID)> = WATCHES
+        .lock()
+        .watches
+        .iter()
+        .map(|(&id, w)| (id, w.node))
+        .collect();
+
+    // Folders are read without the watches locked as that can go to the disk
+    let events = watched
+        .into_iter()
+        .filter_map(|(id, node)| {
+            let name = if node == file {
+                String::new()
+            } else {
+                let VFileSpecialized::Folder(children) = get_file_by_id(node).ok()?.specialized
+                else {
+                    return None;
+                };
+                children.into_iter().find(|(_, c)| *c == file)?.0
+            };
+            Some((
+                id,
+                FsEvent {
+                    kind: FsEventKind::Modified,
+                    name,
+                },
+            ))
+        })
+        .collect();
+    send(events);
+}
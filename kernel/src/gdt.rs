@@ -12,7 +12,7 @@ use x86_64::{
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
-pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
 pub const TSS_STACK_SIZE: usize = 0x1000 * 5;
 
 // GDT Segment Selectors
@@ -82,8 +82,9 @@ pub unsafe fn create_gdt_for_core(gdt: &'static mut CPULocalGDT) {
     gdt.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
         VirtAddr::from_ptr(gdt.tss_stack[1].as_ptr().add(TSS_STACK_SIZE));
 
-    gdt.tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
-        VirtAddr::from_ptr(gdt.tss_stack[2].as_ptr().add(TSS_STACK_SIZE));
+    // NMIs can come before the syscall entry has switched to the kernel stack
+    gdt.tss.interrupt_stack_table[NMI_IST_INDEX as usize] =
+        VirtAddr::from_ptr(gdt.tss_stack[3].as_ptr().add(TSS_STACK_SIZE));
 
     gdt.gdt.add_entry(Descriptor::tss_segment(&gdt.tss));
 }
@@ -6,9 +6,16 @@ use x86_64::{
 
 use crate::{
     cpu_localstorage::CPULocalStorageRW,
-    gdt::{DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX},
-    scheduling::taskmanager::kill_bad_task,
+    fpu::device_not_available_handler,
+    fs::page_cache,
+    gdt::{DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX},
+    paging::{page_mapper::PageFault, swap},
+    scheduling::{
+        process::{ProcessPrivilige, KERNEL_STACKS},
+        taskmanager::kill_bad_task,
+    },
     screen::gop::WRITER,
+    watchdog::nmi_handler,
 };
 
 /// Generates a handler for each PIC lane.
@@ -31,8 +38,11 @@ pub fn set_exceptions_idt(idt: &mut InterruptDescriptorTable) {
     exception_handler!(debug, "DEBUG");
     idt.debug.set_handler_fn(debug);
 
-    exception_handler!(nmi, "NON MASKABLE INTERRUPT");
-    idt.non_maskable_interrupt.set_handler_fn(nmi);
+    unsafe {
+        idt.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(NMI_IST_INDEX);
+    }
 
     idt.breakpoint.set_handler_fn(breakpoint_handler);
 
@@ -46,9 +56,8 @@ pub fn set_exceptions_idt(idt: &mut InterruptDescriptorTable) {
     exception_handler!(invalid_opcode, "INVALID OPCODE");
     idt.invalid_opcode.set_handler_fn(invalid_opcode);
 
-    exception_handler!(device_not_available, "DEVICE NOT AVAILABLE");
     idt.device_not_available
-        .set_handler_fn(device_not_available);
+        .set_handler_fn(device_not_available_handler);
 
     unsafe {
         idt.double_fault
@@ -65,10 +74,10 @@ pub fn set_exceptions_idt(idt: &mut InterruptDescriptorTable) {
             .set_handler_fn(general_protection_handler);
         // .set_stack_index(tss::DOUBLE_FAULT_IST_INDEX);
 
+        // Runs on the faulting thread's stack so that it can sleep on disk io, a fault that
+        // can't be pushed to an overflowed stack ends up in the double fault handler
         idt.page_fault
-            .set_handler_addr(VirtAddr::new(page_fault_handler as u64))
-            .set_stack_index(PAGE_FAULT_IST_INDEX);
-        // .disable_interrupts(false);
+            .set_handler_addr(VirtAddr::new(page_fault_handler as u64));
     }
     // idt.alignment_check
     // idt.simd_floating_point
@@ -86,6 +95,24 @@ extern "x86-interrupt" fn double_fault_handler(
 ) -> ! {
     WRITER.get().unwrap().lock().reset_screen(0xFF_00_00);
 
+    // A page fault that couldn't be delivered because the stack it would be pushed to ran out
+    let addr = Cr2::read().as_u64();
+    let sp = stack_frame.stack_pointer.as_u64();
+    let process_stack = CPULocalStorageRW::get_context() != 0 && {
+        let process = unsafe { CPULocalStorageRW::get_current_task() }.process();
+        process.privilege == ProcessPrivilige::KERNEL
+            && process
+                .memory
+                .try_lock()
+                .is_some_and(|m| m.stacks().is_guard(addr) || m.stacks().is_guard(sp))
+    };
+    if KERNEL_STACKS.is_guard(addr) || KERNEL_STACKS.is_guard(sp) || process_stack {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT: kernel stack overflow (accessed {:#x})\n{:#?}",
+            addr, stack_frame
+        );
+    }
+
     panic!("EXCEPTION: DOUBLE FAULT {}\n{:#?}", error_code, stack_frame);
 }
 
@@ -132,7 +159,16 @@ unsafe extern "x86-interrupt" fn page_fault_handler(
     // unsafe { WRITER.force_unlock() };
     // WRITER.lock().fill_screen(0xFF_00_00);
     // WRITER.lock().pos.y = 0;
-    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+    // Writes to present pages are allowed through as they might be copy on write
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && !write {
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            error!(
+                "EXCEPTION: PAGE FAULT: Executed non executable memory at {:?}",
+                addr
+            );
+            kill_bad_task()
+        }
         error!(
             "EXCEPTION: PAGE FAULT: Protection violation at {:?} {error_code:?}",
             addr
@@ -140,18 +176,58 @@ unsafe extern "x86-interrupt" fn page_fault_handler(
         kill_bad_task()
     }
 
+    let address = addr.as_u64() as usize;
     let process = CPULocalStorageRW::get_current_task().process();
     let mut mem = process.memory.lock();
-    if mem
-        .page_mapper
-        .page_fault_handler(addr.as_u64() as usize)
-        .is_none()
-    {
-        warn!(
-            "EXCEPTION: PAGE FAULT: Failed to map {:?} {:?}",
-            addr, stack_frame.instruction_pointer
-        );
-        drop(mem);
-        kill_bad_task()
+    match mem.page_mapper.page_fault_handler(address, write) {
+        Some(PageFault::Mapped) => (),
+        Some(PageFault::SwappedOut(slot)) => {
+            // The disk driver needs the memory lock
+            drop(mem);
+            let page = swap::read_slot(slot);
+            match page {
+                Some(page) => {
+                    let mut mem = process.memory.lock();
+                    mem.page_mapper.swap_in(address, slot, page);
+                    drop(mem);
+                    swap::free_slot(slot);
+                }
+                None => {
+                    swap::free_slot(slot);
+                    error!("EXCEPTION: PAGE FAULT: Failed to read {:?} from swap", addr);
+                    kill_bad_task()
+                }
+            }
+        }
+        Some(PageFault::FileBacked(file, index)) => {
+            drop(mem);
+            match page_cache::get_page(file, index) {
+                Ok(page) => process.memory.lock().page_mapper.file_in(address, page),
+                Err(e) => {
+                    error!(
+                        "EXCEPTION: PAGE FAULT: Failed to read page {index} of {file:?} for {:?}: {e:?}",
+                        addr
+                    );
+                    kill_bad_task()
+                }
+            }
+        }
+        None => {
+            let kernel_stack = KERNEL_STACKS.is_guard(addr.as_u64())
+                || (process.privilege == ProcessPrivilige::KERNEL
+                    && mem.stacks().is_guard(addr.as_u64()));
+            drop(mem);
+            if kernel_stack {
+                panic!(
+                    "EXCEPTION: PAGE FAULT: kernel stack overflow, accessed {:?} by {:?}",
+                    addr, stack_frame.instruction_pointer
+                );
+            }
+            warn!(
+                "EXCEPTION: PAGE FAULT: Failed to map {:?} {:?}",
+                addr, stack_frame.instruction_pointer
+            );
+            kill_bad_task()
+        }
     }
 }
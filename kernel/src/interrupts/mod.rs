@@ -1,4 +1,9 @@
-use core::{mem::MaybeUninit, u64};
+use core::{
+    mem::MaybeUninit,
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    u64,
+};
 
 use alloc::{sync::Arc, vec::Vec};
 use conquer_once::spin::Lazy;
@@ -10,7 +15,7 @@ use kernel_userspace::{
     port::{PortNotification, PortNotificationType},
     process::publish_handle,
     syscall::spawn_thread,
-    INT_COM1, INT_KB, INT_MOUSE, INT_PCI,
+    INT_ACPI, INT_COM1, INT_KB, INT_MOUSE, INT_PCI,
 };
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -19,9 +24,11 @@ pub mod exceptions;
 pub mod pic;
 
 use crate::{
+    acpi,
     cpu_localstorage::CPULocalStorageRW,
-    kassert, lapic,
+    ioapic, kassert, lapic,
     mutex::Spinlock,
+    paging::tlb::tlb_shootdown_handler,
     port::KPort,
     scheduling::{
         process::{Thread, ThreadState},
@@ -38,7 +45,24 @@ use self::pic::disable_pic;
 // 0..32 = Exceptions
 // 32..48 = PIC Possible spurrius interrupts
 const IRQ_OFFSET: usize = 49;
-const LAPIC_INT: usize = 60;
+pub const KB_VECTOR: usize = 50;
+pub const MOUSE_VECTOR: usize = 51;
+pub const PCI_VECTOR: usize = 52;
+pub const COM1_VECTOR: usize = 53;
+pub const SCI_VECTOR: usize = 54;
+/// Channel 0 of the pit, when it stands in for the local apic timer
+pub const PIT_VECTOR: usize = 55;
+/// Faults from the dma remapping units, see [`crate::iommu`]
+pub const IOMMU_FAULT_VECTOR: usize = 56;
+pub const LAPIC_INT: usize = 60;
+/// Handed out to devices using message signalled interrupts, see [`crate::pci::msi`]
+const MSI_VECTORS: Range<usize> = 64..96;
+const MSI_COUNT: usize = MSI_VECTORS.end - MSI_VECTORS.start;
+const IPI_VECTOR: usize = 100;
+/// Sent to wake a core out of hlt, does nothing itself
+pub const WAKEUP_IPI: usize = 101;
+/// Sent to flush the TLB, see [`crate::paging::tlb`]
+pub const TLB_SHOOTDOWN_IPI: usize = 102;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -87,10 +111,28 @@ pub static IDT: Lazy<Spinlock<InterruptDescriptorTable>> = Lazy::new(|| {
     Spinlock::new(idt)
 });
 
+/// How many times each vector has fired since boot
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+pub fn count_interrupt(vector: usize) {
+    INTERRUPT_COUNTS[vector].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Every vector that has fired and how many times
+pub fn interrupt_counts() -> Vec<(usize, u64)> {
+    INTERRUPT_COUNTS
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed))
+        .enumerate()
+        .filter(|&(_, c)| c > 0)
+        .collect()
+}
+
 #[macro_export]
 macro_rules! interrupt_handler {
-    ($fn: ident => $w:ident) => {
+    ($fn: ident => $w:ident, $vector:expr) => {
         pub extern "x86-interrupt" fn $w(i: InterruptStackFrame) {
+            $crate::interrupts::count_interrupt($vector);
             // let y: u16;
             // unsafe { core::arch::asm!("mov {0:x}, gs", out(reg) y) };
             // println!("Core: {y} received int");
@@ -115,17 +157,28 @@ pub fn init_idt() {
 
     IDT.lock()[LAPIC_INT].set_handler_fn(lapic::tick_handler);
     // set_irq_handler(101, task_switch_handler);
-    set_irq_handler(100, ipi_interrupt_handler);
+    set_irq_handler(IPI_VECTOR, ipi_interrupt_handler);
+    set_irq_handler(WAKEUP_IPI, wakeup_interrupt_handler);
+    set_irq_handler(TLB_SHOOTDOWN_IPI, tlb_shootdown_interrupt_handler);
     set_irq_handler(0xFF, spurious_handler);
+    for (vector, handler) in MSI_VECTORS.zip(MSI_HANDLERS) {
+        set_irq_handler(vector, handler);
+    }
 }
 
-interrupt_handler!(ipi_handler => ipi_interrupt_handler);
+interrupt_handler!(ipi_handler => ipi_interrupt_handler, IPI_VECTOR);
 
 pub fn ipi_handler(s: InterruptStackFrame) {
     info!("IPI {:?}", s)
 }
 
-interrupt_handler!(spurious => spurious_handler);
+interrupt_handler!(wakeup => wakeup_interrupt_handler, WAKEUP_IPI);
+
+pub fn wakeup(_: InterruptStackFrame) {}
+
+interrupt_handler!(tlb_shootdown_handler => tlb_shootdown_interrupt_handler, TLB_SHOOTDOWN_IPI);
+
+interrupt_handler!(spurious => spurious_handler, 0xFF);
 
 pub fn spurious(s: InterruptStackFrame) {
     debug!("Spurious {:?}", s)
@@ -136,34 +189,95 @@ fn int_interrupt_handler(vector: usize) {
     INTERRUPT_SOURCES[vector]
         .lock()
         .iter()
+        .filter(|e| subscribed(e))
         .for_each(|e| e.trigger());
 }
 
-interrupt_handler!(kb_interrupt_handler => keyboard_int_handler);
+/// False once the process that subscribed has dropped its handle, usually by exiting
+fn subscribed(handle: &Arc<KInterruptHandle>) -> bool {
+    Arc::strong_count(handle) > 1
+}
+
+interrupt_handler!(kb_interrupt_handler => keyboard_int_handler, KB_VECTOR);
 fn kb_interrupt_handler(_: InterruptStackFrame) {
     int_interrupt_handler(INT_KB)
 }
 
-interrupt_handler!(mouse_interrupt_handler => mouse_int_handler);
+interrupt_handler!(mouse_interrupt_handler => mouse_int_handler, MOUSE_VECTOR);
 fn mouse_interrupt_handler(_: InterruptStackFrame) {
     int_interrupt_handler(INT_MOUSE)
 }
 
-interrupt_handler!(pci_interrupt_handler => pci_int_handler);
+interrupt_handler!(pci_interrupt_handler => pci_int_handler, PCI_VECTOR);
 fn pci_interrupt_handler(_: InterruptStackFrame) {
     int_interrupt_handler(INT_PCI)
 }
-interrupt_handler!(com1_interrupt_handler => com1_int_handler);
+interrupt_handler!(com1_interrupt_handler => com1_int_handler, COM1_VECTOR);
 fn com1_interrupt_handler(_: InterruptStackFrame) {
     int_interrupt_handler(INT_COM1)
 }
 
-static INTERRUPT_SOURCES: Lazy<[Arc<Spinlock<Vec<Arc<KInterruptHandle>>>>; 4]> = Lazy::new(|| {
+interrupt_handler!(sci_interrupt_handler => sci_int_handler, SCI_VECTOR);
+fn sci_interrupt_handler(_: InterruptStackFrame) {
+    if acpi::events::handle_sci() {
+        int_interrupt_handler(INT_ACPI)
+    }
+    if ioapic::sci_shares_pci() {
+        int_interrupt_handler(INT_PCI)
+    }
+}
+
+/// Each msi vector's handle, None while it is free
+static MSI_HANDLES: [Spinlock<Option<Arc<KInterruptHandle>>>; MSI_COUNT] =
+    [const { Spinlock::new(None) }; MSI_COUNT];
+
+macro_rules! msi_handlers {
+    ($($vector:literal),*) => {
+        const MSI_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); MSI_COUNT] = [$({
+            extern "x86-interrupt" fn handler(_: InterruptStackFrame) {
+                count_interrupt($vector);
+                if let Some(h) = &*MSI_HANDLES[$vector - MSI_VECTORS.start].lock() {
+                    h.trigger();
+                }
+                unsafe { core::ptr::write_volatile(0xfee000b0 as *mut u32, 0) }
+            }
+            handler
+        }),*];
+    };
+}
+
+msi_handlers!(
+    64, 65, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87,
+    88, 89, 90, 91, 92, 93, 94, 95
+);
+
+/// Takes a free vector for a device to send messages to, which triggers the handle that
+/// comes with it
+pub fn alloc_msi_vector() -> Option<(u8, Arc<KInterruptHandle>)> {
+    MSI_HANDLES.iter().enumerate().find_map(|(i, slot)| {
+        let mut slot = slot.lock();
+        if slot.is_some() {
+            return None;
+        }
+        let handle = Arc::new(KInterruptHandle::new());
+        *slot = Some(handle.clone());
+        Some(((MSI_VECTORS.start + i) as u8, handle))
+    })
+}
+
+pub fn free_msi_vector(vector: u8) {
+    MSI_HANDLES[vector as usize - MSI_VECTORS.start]
+        .lock()
+        .take();
+}
+
+static INTERRUPT_SOURCES: Lazy<[Arc<Spinlock<Vec<Arc<KInterruptHandle>>>>; 5]> = Lazy::new(|| {
     [
         Arc::new(Default::default()),
         Arc::new(Default::default()),
         Arc::new(Default::default()),
         Arc::new(Default::default()),
+        Arc::new(Default::default()),
     ]
 });
 
@@ -193,7 +307,7 @@ pub fn check_interrupts() {
 
                     let req = unsafe { val.assume_init() };
 
-                    if req > 3 {
+                    if req >= INTERRUPT_SOURCES.len() {
                         error!("INTERRUPTS service got invalid id");
                         return;
                     }
@@ -205,7 +319,12 @@ pub fn check_interrupts() {
                         KernelReference::from_id(thread.process().add_value(h.clone().into()))
                     });
 
-                    INTERRUPT_SOURCES[req].lock().push(h);
+                    // Dead subscriptions are only dropped here, so it never happens in an
+                    // interrupt handler
+                    let mut source = INTERRUPT_SOURCES[req].lock();
+                    source.retain(subscribed);
+                    source.push(h);
+                    drop(source);
 
                     channel_write_rs(handle.id(), &[], &[id.id()]);
                 }
@@ -1,6 +1,7 @@
 use core::{
     mem,
     ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
 };
 
 use acpi::{sdt::SdtHeader, AcpiTable};
@@ -11,9 +12,13 @@ use conquer_once::noblock::OnceCell;
 use crate::{
     cpu_localstorage::CPULocalStorageRW,
     interrupts::{
-        com1_int_handler, keyboard_int_handler, mouse_int_handler, pci_int_handler, set_irq_handler,
+        com1_int_handler, keyboard_int_handler, mouse_int_handler, pci_int_handler,
+        sci_int_handler, set_irq_handler, COM1_VECTOR, KB_VECTOR, MOUSE_VECTOR, PCI_VECTOR,
+        PIT_VECTOR, SCI_VECTOR,
     },
+    lapic::{pit_tick_handler, tick_source, TickSource},
     paging::{
+        ensure_ident_map_curr_process,
         page::{Page, Size4KB},
         page_allocator::global_allocator,
         page_table::{Mapper, PageTable, TableLevel4},
@@ -22,6 +27,11 @@ use crate::{
 };
 
 static IOAPIC: OnceCell<IOApic> = OnceCell::uninit();
+static OVERRIDES: OnceCell<Vec<ApicInterruptOveride>> = OnceCell::uninit();
+
+/// The line the SCI came in on, u32::MAX until it has been routed
+static SCI_GSI: AtomicU32 = AtomicU32::new(u32::MAX);
+static SCI_SHARES_PCI: AtomicBool = AtomicBool::new(false);
 
 pub fn enable_apic(madt: &Madt, mapper: &mut PageTable<TableLevel4>) {
     let (_, _, io_apics, apic_ints) = madt.find_ioapic();
@@ -42,27 +52,105 @@ pub fn enable_apic(madt: &Madt, mapper: &mut PageTable<TableLevel4>) {
 
     IOAPIC.try_init_once(|| *apic).unwrap();
 
-    for i in apic_ints {
+    for i in &apic_ints {
         debug!("Int override: {:?}", i);
     }
+    OVERRIDES.try_init_once(|| apic_ints).unwrap();
+
+    // The pit only interrupts if the local apic timer can't be used, it is usually overridden
+    // to gsi 2
+    if tick_source() == Some(TickSource::Pit) {
+        set_irq_handler(PIT_VECTOR, pit_tick_handler);
+        route_isa_irq(0, PIT_VECTOR as u8);
+    }
+
+    set_irq_handler(KB_VECTOR, keyboard_int_handler);
+    set_redirect_entry(apic.apic_addr, 0, 1, KB_VECTOR as u8, true);
 
-    // Timer is usually overridden to irq 2
-    // TODO: Parse overides and use those
-    // 0xFF all cores
-    // set_redirect_entry(apic.apic_addr, 0xFF, 2, 49, true);
+    set_irq_handler(MOUSE_VECTOR, mouse_int_handler);
+    set_redirect_entry(apic.apic_addr, 0, 12, MOUSE_VECTOR as u8, true);
+
+    // The pci lines are routed as devices are found, see [`route_pci_irq`]
+    set_irq_handler(PCI_VECTOR, pci_int_handler);
+
+    set_irq_handler(COM1_VECTOR, com1_int_handler);
+    set_redirect_entry(apic.apic_addr, 0, 4, COM1_VECTOR as u8, true);
+}
 
-    set_irq_handler(50, keyboard_int_handler);
-    set_redirect_entry(apic.apic_addr, 0, 1, 50, true);
+/// Sends an interrupt line a pci device uses to [`PCI_VECTOR`]. It is left edge triggered like
+/// the others, the drivers check their cards on every interrupt. A line shared with the SCI
+/// stays on [`SCI_VECTOR`], which passes it on, see [`sci_shares_pci`].
+pub fn route_pci_irq(gsi: u32, active_low: bool) {
+    if gsi == SCI_GSI.load(Ordering::Relaxed) {
+        SCI_SHARES_PCI.store(true, Ordering::Relaxed);
+        return;
+    }
+    route_gsi(gsi, PCI_VECTOR as u8, active_low);
+}
 
-    set_irq_handler(51, mouse_int_handler);
-    set_redirect_entry(apic.apic_addr, 0, 12, 51, true);
+/// If a pci device is on the same line as the SCI
+pub fn sci_shares_pci() -> bool {
+    SCI_SHARES_PCI.load(Ordering::Relaxed)
+}
 
-    set_irq_handler(52, pci_int_handler);
-    set_redirect_entry(apic.apic_addr, 0, 10, 52, true);
-    set_redirect_entry(apic.apic_addr, 0, 11, 52, true);
+/// Sends the isa irq the FADT says the SCI is on to [`SCI_VECTOR`]. The SCI is active low
+/// unless the madt overrides it. It is edge triggered like the pci lines, the handler clears
+/// every event it enables.
+pub fn route_sci(irq: u8) {
+    let (gsi, active_low) = OVERRIDES
+        .try_get()
+        .ok()
+        .and_then(|o| o.iter().find(|o| o.irq_source == irq))
+        .map_or((irq as u32, true), |o| {
+            let flags = o.flags;
+            // 0b00 is conforming to the bus, which for the SCI means active low
+            (o.interrupt_num, flags & 0b11 != 0b01)
+        });
+    set_irq_handler(SCI_VECTOR, sci_int_handler);
+    route_gsi(gsi, SCI_VECTOR as u8, active_low);
+    SCI_GSI.store(gsi, Ordering::Relaxed);
+}
 
-    set_irq_handler(53, com1_int_handler);
-    set_redirect_entry(apic.apic_addr, 0, 4, 53, true);
+/// Sends an isa irq to the vector on the gsi the madt overrides it to. Isa irqs are active high
+/// unless overridden.
+fn route_isa_irq(irq: u8, vector: u8) {
+    let (gsi, active_low) = OVERRIDES
+        .try_get()
+        .ok()
+        .and_then(|o| o.iter().find(|o| o.irq_source == irq))
+        .map_or((irq as u32, false), |o| {
+            let flags = o.flags;
+            (o.interrupt_num, flags & 0b11 == 0b11)
+        });
+    route_gsi(gsi, vector, active_low);
+}
+
+fn route_gsi(gsi: u32, vector: u8, active_low: bool) {
+    let Ok(apic) = IOAPIC.try_get() else {
+        return;
+    };
+    let apic_base = apic.apic_addr;
+    unsafe {
+        ensure_ident_map_curr_process(
+            Page::<Size4KB>::new(apic_base as u64),
+            MemoryMappingFlags::WRITEABLE,
+        )
+    };
+
+    let max_entry = read_ioapic_register(apic_base, 1).get_bits(16..24);
+    let Some(irq) = gsi
+        .checked_sub(apic.interrupt_base)
+        .filter(|&i| i <= max_entry)
+    else {
+        error!("Interrupt {gsi} isn't on the ioapic");
+        return;
+    };
+    let irq = irq as u8;
+
+    set_redirect_entry(apic_base, 0, irq, vector, true);
+    let mut low = read_ioapic_register(apic_base, 0x10 + 2 * irq);
+    low.set_bit(13, active_low);
+    write_ioapic_register(apic_base, 0x10 + 2 * irq, low);
 }
 
 pub fn send_ipi_to(apic_id: u8, vector: u8) {
@@ -74,6 +162,29 @@ pub fn send_ipi_to(apic_id: u8, vector: u8) {
     unsafe { write_volatile((0xfee00000u64 + 0x300) as *mut u32, vector as u32 | 1 << 14) };
 }
 
+/// Sends an NMI to the core, which gets through even if it is holding interrupts
+pub fn send_nmi_to(apic_id: u8) {
+    // Check no IPI pending
+    while unsafe { read_volatile((0xfee00000u64 + 0x300) as *const u32) & (1 << 12) > 0 } {}
+    // Target
+    unsafe { write_volatile((0xfee00000u64 + 0x310) as *mut u32, (apic_id as u32) << 24) };
+    // NMI delivery mode, the vector is ignored
+    unsafe { write_volatile((0xfee00000u64 + 0x300) as *mut u32, 0b100 << 8 | 1 << 14) };
+}
+
+/// Sends the vector to every core but this one
+pub fn send_ipi_to_others(vector: u8) {
+    // Check no IPI pending
+    while unsafe { read_volatile((0xfee00000u64 + 0x300) as *const u32) & (1 << 12) > 0 } {}
+    // Send interrupt with the all excluding self shorthand
+    unsafe {
+        write_volatile(
+            (0xfee00000u64 + 0x300) as *mut u32,
+            vector as u32 | 1 << 14 | 0b11 << 18,
+        )
+    };
+}
+
 fn set_redirect_entry(apic_base: u32, processor: u32, irq: u8, vector: u8, enable: bool) {
     let mut low = read_ioapic_register(apic_base, 0x10 + 2 * irq);
     let mut high = read_ioapic_register(apic_base, 0x11 + 2 * irq);
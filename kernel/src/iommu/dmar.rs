@@ -0,0 +1,136 @@
+use acpi::{
+    sdt::{SdtHeader, Signature},
+    AcpiTable,
+};
+
+const TYPE_DRHD: u16 = 0;
+const TYPE_RMRR: u16 = 1;
+
+const DRHD_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+/// A pci device, the only kind a unit translates the dma of
+pub const SCOPE_ENDPOINT: u8 = 1;
+/// A bridge and everything behind it
+pub const SCOPE_BRIDGE: u8 = 2;
+
+/// The DMA remapping reporting table, which lists the remapping units and the memory that devices
+/// need to keep reaching
+#[repr(C, packed)]
+pub struct Dmar {
+    header: SdtHeader,
+    pub host_address_width: u8,
+    pub flags: u8,
+    _reserved: [u8; 10],
+}
+
+unsafe impl AcpiTable for Dmar {
+    const SIGNATURE: Signature = Signature::DMAR;
+
+    fn header(&self) -> &acpi::sdt::SdtHeader {
+        &self.header
+    }
+}
+
+pub enum Structure<'a> {
+    /// A remapping unit
+    Drhd {
+        /// It covers every device in the segment that no other unit lists
+        include_all: bool,
+        segment: u16,
+        registers: u64,
+        scopes: &'a [u8],
+    },
+    /// Memory the firmware keeps using for the devices, like usb legacy emulation
+    Rmrr {
+        segment: u16,
+        base: u64,
+        limit: u64,
+        scopes: &'a [u8],
+    },
+}
+
+/// A device that a remapping structure applies to
+pub struct DeviceScope<'a> {
+    pub kind: u8,
+    pub start_bus: u8,
+    /// Device and function pairs from the start bus through bridges to the device
+    pub path: &'a [u8],
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+impl Dmar {
+    pub fn structures(&self) -> impl Iterator<Item = Structure<'_>> {
+        let length = self.header.length as usize - core::mem::size_of::<Dmar>();
+        let mut bytes = unsafe {
+            let ptr = (self as *const Dmar as *const u8).add(core::mem::size_of::<Dmar>());
+            core::slice::from_raw_parts(ptr, length)
+        };
+
+        core::iter::from_fn(move || loop {
+            if bytes.len() < 4 {
+                return None;
+            }
+            let kind = u16_at(bytes, 0);
+            let len = u16_at(bytes, 2) as usize;
+            if len < 4 || len > bytes.len() {
+                return None;
+            }
+            let (s, rest) = bytes.split_at(len);
+            bytes = rest;
+
+            match kind {
+                TYPE_DRHD if len >= 16 => {
+                    return Some(Structure::Drhd {
+                        include_all: s[4] & DRHD_INCLUDE_PCI_ALL != 0,
+                        segment: u16_at(s, 6),
+                        registers: u64_at(s, 8),
+                        scopes: &s[16..],
+                    })
+                }
+                TYPE_RMRR if len >= 24 => {
+                    return Some(Structure::Rmrr {
+                        segment: u16_at(s, 6),
+                        base: u64_at(s, 8),
+                        limit: u64_at(s, 16),
+                        scopes: &s[24..],
+                    })
+                }
+                _ => (),
+            }
+        })
+    }
+}
+
+pub fn device_scopes(mut bytes: &[u8]) -> impl Iterator<Item = DeviceScope<'_>> {
+    core::iter::from_fn(move || {
+        let len = *bytes.get(1)? as usize;
+        if len < 6 || len > bytes.len() {
+            return None;
+        }
+        let (s, rest) = bytes.split_at(len);
+        bytes = rest;
+        Some(DeviceScope {
+            kind: s[0],
+            start_bus: s[5],
+            path: &s[6..],
+        })
+    })
+}
+
+impl DeviceScope<'_> {
+    /// The device's bus, device and function, if it is right on the start bus. Finding devices
+    /// further down means reading the bridges on the way.
+    pub fn direct_device(&self) -> Option<(u8, u8, u8)> {
+        match self.path {
+            &[device, function] => Some((self.start_bus, device, function)),
+            _ => None,
+        }
+    }
+}
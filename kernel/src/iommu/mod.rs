@@ -0,0 +1,637 @@
+//! Intel VT-d DMA remapping, so a device given to a userspace driver can only reach that driver's
+//! dma allocations rather than all of memory.
+//!
+//! Each of those devices gets its own [`Domain`], whose page tables map the driver's allocations
+//! at their own physical addresses so drivers keep handing devices physical addresses. Every other
+//! device, like the disks the kernel drives itself, passes straight through. Devices are added as
+//! pci is enumerated and translation is turned on after.
+
+use core::{
+    arch::x86_64::{_mm_clflush, _mm_mfence},
+    hint::spin_loop,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use acpi::AcpiTables;
+use alloc::{
+    collections::{btree_map::Entry, BTreeMap},
+    sync::Arc,
+    vec::Vec,
+};
+use kernel_userspace::dma::DmaSegment;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    acpi::FioxaAcpiHandler,
+    interrupt_handler,
+    interrupts::{set_irq_handler, IOMMU_FAULT_VECTOR},
+    lapic::current_apic_id,
+    mutex::Spinlock,
+    paging::{
+        page::{Page, Size4KB},
+        page_allocator::global_allocator,
+        page_table::Mapper,
+        virt_addr_for_phys, AllocatedPage, GlobalPageAllocator, MemoryLoc, MemoryMappingFlags,
+        OFFSET_MAP,
+    },
+};
+
+mod dmar;
+
+const REG_CAP: u64 = 0x08;
+const REG_ECAP: u64 = 0x10;
+const REG_GCMD: u64 = 0x18;
+const REG_GSTS: u64 = 0x1C;
+const REG_RTADDR: u64 = 0x20;
+const REG_CCMD: u64 = 0x28;
+const REG_FSTS: u64 = 0x34;
+const REG_FECTL: u64 = 0x38;
+const REG_FEDATA: u64 = 0x3C;
+const REG_FEADDR: u64 = 0x40;
+const REG_FEUADDR: u64 = 0x44;
+
+const GCMD_TE: u32 = 1 << 31;
+const GCMD_SRTP: u32 = 1 << 30;
+const GCMD_WBF: u32 = 1 << 27;
+const GSTS_TES: u32 = 1 << 31;
+const GSTS_RTPS: u32 = 1 << 30;
+const GSTS_WBFS: u32 = 1 << 27;
+/// The status bits that say what is on rather than which one shot command finished
+const GSTS_PRESERVED: u32 = 0x96FF_FFFF;
+
+const CAP_RWBF: u64 = 1 << 4;
+const CAP_CM: u64 = 1 << 7;
+const ECAP_C: u64 = 1 << 0;
+const ECAP_PT: u64 = 1 << 6;
+
+const CCMD_ICC: u64 = 1 << 63;
+const CCMD_GLOBAL: u64 = 1 << 61;
+
+const IOTLB_IVT: u64 = 1 << 63;
+const IOTLB_GLOBAL: u64 = 1 << 60;
+const IOTLB_DOMAIN: u64 = 2 << 60;
+
+const FSTS_PFO: u32 = 1 << 0;
+const FAULT_F: u64 = 1 << 63;
+const FAULT_READ: u64 = 1 << 62;
+
+const ROOT_PRESENT: u64 = 1 << 0;
+const CONTEXT_PRESENT: u64 = 1 << 0;
+const CONTEXT_PASSTHROUGH: u64 = 2 << 2;
+
+const PTE_RW: u64 = 0b11;
+const PTE_ADDR: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Shared by every device that passes through
+const PASSTHROUGH_DOMAIN: u16 = 1;
+
+type TablePage = AllocatedPage<GlobalPageAllocator>;
+
+/// (bus, device, function)
+type Bdf = (u8, u8, u8);
+
+/// A remapping unit
+struct Unit {
+    /// Where the registers are in the offset map
+    registers: u64,
+    segment: u16,
+    cap: u64,
+    ecap: u64,
+    /// The devices it covers, None when it covers everything no other unit lists
+    scope: Option<Vec<Bdf>>,
+    root: TablePage,
+    /// Context tables by bus
+    contexts: BTreeMap<u8, TablePage>,
+    /// How many levels of second level page tables the domains use, 3 or 4
+    levels: u32,
+}
+
+/// Memory the firmware keeps using for the devices
+struct Rmrr {
+    segment: u16,
+    base: u64,
+    limit: u64,
+    devices: Vec<Bdf>,
+}
+
+static UNITS: Spinlock<Vec<Unit>> = Spinlock::new(Vec::new());
+static RMRRS: Spinlock<Vec<Rmrr>> = Spinlock::new(Vec::new());
+
+static NEXT_DOMAIN: AtomicU16 = AtomicU16::new(PASSTHROUGH_DOMAIN + 1);
+/// Ids of domains that were dropped
+static FREE_DOMAINS: Spinlock<Vec<u16>> = Spinlock::new(Vec::new());
+
+/// Writes back the cache line so a unit that doesn't snoop sees it
+fn flush_entry(entry: *const u64, coherent: bool) {
+    if !coherent {
+        unsafe { _mm_clflush(entry as *const u8) };
+    }
+    unsafe { _mm_mfence() };
+}
+
+/// Maps the registers into the offset map, which every process shares
+fn map_registers(phys: u64, len: u64) -> u64 {
+    for page in (phys & !0xFFF..phys + len).step_by(0x1000) {
+        let mapped = OFFSET_MAP.lock().map(
+            global_allocator(),
+            Page::<Size4KB>::new(page + MemoryLoc::PhysMapOffset as u64),
+            Page::new(page),
+            MemoryMappingFlags::WRITEABLE,
+        );
+        // It is already mapped if an earlier unit shares the page
+        if let Ok(f) = mapped {
+            f.flush()
+        }
+    }
+    phys + MemoryLoc::PhysMapOffset as u64
+}
+
+/// The devices of the scopes, None if any are behind a bridge
+fn direct_devices(scopes: &[u8]) -> Option<Vec<Bdf>> {
+    let mut devices = Vec::new();
+    for scope in dmar::device_scopes(scopes) {
+        match (scope.kind, scope.direct_device()) {
+            (dmar::SCOPE_ENDPOINT, Some(d)) => devices.push(d),
+            (dmar::SCOPE_ENDPOINT | dmar::SCOPE_BRIDGE, _) => return None,
+            // ioapics and hpets, which only matter for interrupt remapping
+            _ => (),
+        }
+    }
+    Some(devices)
+}
+
+impl Unit {
+    fn new(phys: u64, segment: u16, scope: Option<Vec<Bdf>>) -> Option<Self> {
+        let registers = map_registers(phys, 0x1000);
+        let (cap, ecap) = unsafe {
+            (
+                read_volatile((registers + REG_CAP) as *const u64),
+                read_volatile((registers + REG_ECAP) as *const u64),
+            )
+        };
+        // The fault records and iotlb registers can be past the first page
+        let fault_end = ((cap >> 24) & 0x3FF) * 16 + (((cap >> 40) & 0xFF) + 1) * 16;
+        let iotlb_end = ((ecap >> 8) & 0x3FF) * 16 + 16;
+        map_registers(phys, fault_end.max(iotlb_end));
+
+        if ecap & ECAP_PT == 0 {
+            warn!("The iommu at {phys:#x} can't pass devices through, not using it");
+            return None;
+        }
+        let sagaw = (cap >> 8) & 0x1F;
+        let levels = match sagaw {
+            s if s & (1 << 2) != 0 => 4,
+            s if s & (1 << 1) != 0 => 3,
+            _ => {
+                warn!("The iommu at {phys:#x} doesn't support 3 or 4 level tables, not using it");
+                return None;
+            }
+        };
+        if cap & CAP_CM != 0 {
+            warn!("The iommu at {phys:#x} caches non present entries, which isn't handled");
+        }
+
+        let unit = Self {
+            registers,
+            segment,
+            cap,
+            ecap,
+            scope,
+            root: AllocatedPage::new(GlobalPageAllocator)?,
+            contexts: BTreeMap::new(),
+            levels,
+        };
+        unsafe {
+            unit.write64(REG_RTADDR, unit.root.get_address());
+            unit.command(GCMD_SRTP, |s| s & GSTS_RTPS != 0);
+        }
+        info!("Iommu at {phys:#x} with {levels} level page tables");
+        Some(unit)
+    }
+
+    unsafe fn read32(&self, reg: u64) -> u32 {
+        read_volatile((self.registers + reg) as *const u32)
+    }
+
+    unsafe fn write32(&self, reg: u64, val: u32) {
+        write_volatile((self.registers + reg) as *mut u32, val)
+    }
+
+    unsafe fn read64(&self, reg: u64) -> u64 {
+        read_volatile((self.registers + reg) as *const u64)
+    }
+
+    unsafe fn write64(&self, reg: u64, val: u64) {
+        write_volatile((self.registers + reg) as *mut u64, val)
+    }
+
+    /// Issues a command, keeping whatever is on, and waits until the status says it is done
+    unsafe fn command(&self, cmd: u32, done: impl Fn(u32) -> bool) {
+        let status = self.read32(REG_GSTS) & GSTS_PRESERVED;
+        self.write32(REG_GCMD, status | cmd);
+        while !done(self.read32(REG_GSTS)) {
+            spin_loop();
+        }
+    }
+
+    fn coherent(&self) -> bool {
+        self.ecap & ECAP_C != 0
+    }
+
+    fn max_domains(&self) -> u32 {
+        1 << (4 + 2 * (self.cap & 0b111))
+    }
+
+    /// The context entry's address width for the page tables
+    fn address_width(&self) -> u64 {
+        match self.levels {
+            3 => 1,
+            _ => 2,
+        }
+    }
+
+    fn covers(&self, segment: u16, device: Bdf) -> bool {
+        self.segment == segment && self.scope.as_ref().is_some_and(|s| s.contains(&device))
+    }
+
+    fn flush_write_buffer(&self) {
+        if self.cap & CAP_RWBF != 0 {
+            unsafe { self.command(GCMD_WBF, |s| s & GSTS_WBFS == 0) }
+        }
+    }
+
+    /// Drops the cached context entries and every translation
+    fn invalidate_all(&self) {
+        self.flush_write_buffer();
+        unsafe {
+            self.write64(REG_CCMD, CCMD_ICC | CCMD_GLOBAL);
+            while self.read64(REG_CCMD) & CCMD_ICC != 0 {
+                spin_loop();
+            }
+        }
+        self.invalidate_iotlb(None);
+    }
+
+    /// Drops the cached translations of the domain, or of all of them
+    fn invalidate_iotlb(&self, domain: Option<u16>) {
+        self.flush_write_buffer();
+        let reg = ((self.ecap >> 8) & 0x3FF) * 16 + 8;
+        let granularity = match domain {
+            Some(id) => IOTLB_DOMAIN | (id as u64) << 32,
+            None => IOTLB_GLOBAL,
+        };
+        unsafe {
+            self.write64(reg, IOTLB_IVT | granularity);
+            while self.read64(reg) & IOTLB_IVT != 0 {
+                spin_loop();
+            }
+        }
+    }
+
+    /// Points the device's context entry somewhere else, adding its bus's context table if it
+    /// doesn't have one yet
+    fn set_context(&mut self, (bus, device, function): Bdf, low: u64, high: u64) -> bool {
+        let coherent = self.coherent();
+        let table = match self.contexts.entry(bus) {
+            Entry::Occupied(e) => e.into_mut().get_address(),
+            Entry::Vacant(e) => {
+                let Some(table) = AllocatedPage::new(GlobalPageAllocator) else {
+                    return false;
+                };
+                unsafe {
+                    let root = (virt_addr_for_phys(self.root.get_address()) as *mut u64)
+                        .add(bus as usize * 2);
+                    write_volatile(root, table.get_address() | ROOT_PRESENT);
+                    flush_entry(root, coherent);
+                }
+                e.insert(table).get_address()
+            }
+        };
+        let index = ((device as usize) << 3 | function as usize) * 2;
+        unsafe {
+            let entry = (virt_addr_for_phys(table) as *mut u64).add(index);
+            // Not present while it changes so the unit never uses half of each
+            write_volatile(entry, 0);
+            flush_entry(entry, coherent);
+            write_volatile(entry.add(1), high);
+            write_volatile(entry, low);
+            flush_entry(entry, coherent);
+        }
+        self.invalidate_all();
+        true
+    }
+
+    fn report_faults(&self) {
+        let records = ((self.cap >> 24) & 0x3FF) * 16;
+        let count = ((self.cap >> 40) & 0xFF) + 1;
+        unsafe {
+            for i in 0..count {
+                let record = records + i * 16;
+                let high = self.read64(record + 8);
+                if high & FAULT_F == 0 {
+                    continue;
+                }
+                let address = self.read64(record) & !0xFFF;
+                let source = high as u16;
+                let reason = (high >> 32) as u8;
+                let access = match high & FAULT_READ {
+                    0 => "write",
+                    _ => "read",
+                };
+                error!(
+                    "Iommu blocked a dma {access} of {address:#x} from pci {}.{}.{} (reason {reason:#x})",
+                    source >> 8,
+                    (source >> 3) & 0x1F,
+                    source & 0b111
+                );
+                self.write64(record + 8, FAULT_F);
+            }
+            self.write32(REG_FSTS, FSTS_PFO);
+        }
+    }
+}
+
+fn find_unit(units: &[Unit], segment: u16, device: Bdf) -> Option<usize> {
+    units
+        .iter()
+        .position(|u| u.covers(segment, device))
+        .or_else(|| {
+            units
+                .iter()
+                .position(|u| u.segment == segment && u.scope.is_none())
+        })
+}
+
+/// Reads the DMAR table and sets up a root table for each unit, doesn't turn anything on yet
+pub fn init(acpi_tables: &AcpiTables<FioxaAcpiHandler>) {
+    let Ok(dmar) = acpi_tables.find_table::<dmar::Dmar>() else {
+        info!("There is no iommu, drivers can dma anywhere");
+        return;
+    };
+
+    let mut units = UNITS.lock();
+    for structure in dmar.structures() {
+        match structure {
+            dmar::Structure::Drhd {
+                include_all,
+                segment,
+                registers,
+                scopes,
+            } => {
+                let scope = match include_all {
+                    true => None,
+                    false => match direct_devices(scopes) {
+                        Some(devices) => Some(devices),
+                        None => {
+                            warn!("The iommu at {registers:#x} covers devices behind bridges, not using it");
+                            continue;
+                        }
+                    },
+                };
+                units.extend(Unit::new(registers, segment, scope));
+            }
+            dmar::Structure::Rmrr {
+                segment,
+                base,
+                limit,
+                scopes,
+            } => {
+                let Some(devices) = direct_devices(scopes) else {
+                    warn!(
+                        "Ignoring reserved memory {base:#x}-{limit:#x} for devices behind bridges"
+                    );
+                    continue;
+                };
+                RMRRS.lock().push(Rmrr {
+                    segment,
+                    base,
+                    limit,
+                    devices,
+                });
+            }
+        }
+    }
+}
+
+/// Lets the device keep reaching all of memory, every device should be added before [`enable`]
+pub fn add_device(segment: u16, bus: u8, device: u8, function: u8) {
+    let mut units = UNITS.lock();
+    let Some(index) = find_unit(&units, segment, (bus, device, function)) else {
+        return;
+    };
+    let unit = &mut units[index];
+    let high = unit.address_width() | (PASSTHROUGH_DOMAIN as u64) << 8;
+    if !unit.set_context(
+        (bus, device, function),
+        CONTEXT_PRESENT | CONTEXT_PASSTHROUGH,
+        high,
+    ) {
+        error!("Couldn't add pci {bus}.{device}.{function} to the iommu");
+    }
+}
+
+/// Moves the device into a domain of its own, where it can only reach what is mapped into it.
+/// None if there is no iommu for it.
+pub fn isolate(segment: u16, bus: u8, device: u8, function: u8) -> Option<Arc<Domain>> {
+    let bdf = (bus, device, function);
+    let mut units = UNITS.lock();
+    let index = find_unit(&units, segment, bdf)?;
+    let unit = &mut units[index];
+
+    let id = FREE_DOMAINS
+        .lock()
+        .pop()
+        .unwrap_or_else(|| NEXT_DOMAIN.fetch_add(1, Ordering::Relaxed));
+    // Ids past what the unit has were never valid, so they don't go back on the free list
+    if id as u32 >= unit.max_domains() {
+        warn!("Out of iommu domains, pci {bus}.{device}.{function} can dma anywhere");
+        return None;
+    }
+    let Some(root) = AllocatedPage::new(GlobalPageAllocator) else {
+        FREE_DOMAINS.lock().push(id);
+        return None;
+    };
+
+    // The domain is only made once the device is in it, dropping it takes the units lock
+    let levels = unit.levels;
+    let coherent = unit.coherent();
+    let mut tables = DomainTables {
+        root,
+        tables: Vec::new(),
+    };
+    for rmrr in RMRRS.lock().iter() {
+        if rmrr.segment != segment || !rmrr.devices.contains(&bdf) {
+            continue;
+        }
+        for addr in (rmrr.base & !0xFFF..=rmrr.limit).step_by(0x1000) {
+            tables.map(addr, levels, coherent);
+        }
+    }
+    let low = tables.root.get_address() | CONTEXT_PRESENT;
+
+    if !unit.set_context(bdf, low, unit.address_width() | (id as u64) << 8) {
+        FREE_DOMAINS.lock().push(id);
+        return None;
+    }
+    Some(Arc::new(Domain {
+        id,
+        unit: index,
+        device: bdf,
+        levels,
+        coherent,
+        tables: Spinlock::new(tables),
+    }))
+}
+
+/// Turns on translation, along with reporting faults
+pub fn enable() {
+    let units = UNITS.lock();
+    if units.is_empty() {
+        return;
+    }
+    set_irq_handler(IOMMU_FAULT_VECTOR, fault_interrupt_handler);
+    for unit in units.iter() {
+        unit.invalidate_all();
+        unsafe {
+            unit.write32(REG_FEDATA, IOMMU_FAULT_VECTOR as u32);
+            unit.write32(REG_FEADDR, 0xFEE0_0000 | (current_apic_id() as u32) << 12);
+            unit.write32(REG_FEUADDR, 0);
+            unit.write32(REG_FECTL, 0);
+            unit.command(GCMD_TE, |s| s & GSTS_TES != 0);
+        }
+    }
+    info!("Dma remapping is on");
+}
+
+interrupt_handler!(report_faults => fault_interrupt_handler, IOMMU_FAULT_VECTOR);
+
+fn report_faults(_: InterruptStackFrame) {
+    for unit in UNITS.lock().iter() {
+        unit.report_faults();
+    }
+}
+
+struct DomainTables {
+    root: TablePage,
+    tables: Vec<TablePage>,
+}
+
+impl DomainTables {
+    /// The last level entry for the address
+    fn walk(&mut self, addr: u64, levels: u32, coherent: bool, create: bool) -> Option<*mut u64> {
+        if addr >> (12 + 9 * levels) != 0 {
+            return None;
+        }
+        let entry_in = |table: u64, level: u32| unsafe {
+            let index = (addr >> (12 + 9 * (level - 1))) & 0x1FF;
+            (virt_addr_for_phys(table) as *mut u64).add(index as usize)
+        };
+
+        let mut table = self.root.get_address();
+        for level in (2..=levels).rev() {
+            let entry = entry_in(table, level);
+            if unsafe { *entry } & PTE_RW == 0 {
+                if !create {
+                    return None;
+                }
+                let page = AllocatedPage::new(GlobalPageAllocator)?;
+                unsafe { write_volatile(entry, page.get_address() | PTE_RW) };
+                flush_entry(entry, coherent);
+                self.tables.push(page);
+            }
+            table = unsafe { *entry } & PTE_ADDR;
+        }
+        Some(entry_in(table, 1))
+    }
+
+    fn map(&mut self, addr: u64, levels: u32, coherent: bool) -> bool {
+        let Some(entry) = self.walk(addr, levels, coherent, true) else {
+            return false;
+        };
+        unsafe { write_volatile(entry, addr | PTE_RW) };
+        flush_entry(entry, coherent);
+        true
+    }
+
+    fn unmap(&mut self, addr: u64, levels: u32, coherent: bool) {
+        if let Some(entry) = self.walk(addr, levels, coherent, false) {
+            unsafe { write_volatile(entry, 0) };
+            flush_entry(entry, coherent);
+        }
+    }
+}
+
+/// The page tables an isolated device's dma goes through. Dropping it blocks the device.
+pub struct Domain {
+    id: u16,
+    /// Index into the units
+    unit: usize,
+    device: Bdf,
+    levels: u32,
+    coherent: bool,
+    tables: Spinlock<DomainTables>,
+}
+
+impl Domain {
+    fn pages(segments: &[DmaSegment]) -> impl Iterator<Item = u64> + '_ {
+        segments
+            .iter()
+            .flat_map(|s| (s.phys..s.phys + s.len as u64).step_by(0x1000))
+    }
+
+    /// Lets the device reach the segments
+    pub fn map(&self, segments: &[DmaSegment]) {
+        let mut tables = self.tables.lock();
+        for addr in Self::pages(segments) {
+            if !tables.map(addr, self.levels, self.coherent) {
+                error!("Couldn't let pci {:?} dma into {addr:#x}", self.device);
+            }
+        }
+        drop(tables);
+        UNITS.lock()[self.unit].invalidate_iotlb(Some(self.id));
+    }
+
+    pub fn unmap(&self, segments: &[DmaSegment]) {
+        let mut tables = self.tables.lock();
+        for addr in Self::pages(segments) {
+            tables.unmap(addr, self.levels, self.coherent);
+        }
+        drop(tables);
+        UNITS.lock()[self.unit].invalidate_iotlb(Some(self.id));
+    }
+}
+
+impl Drop for Domain {
+    fn drop(&mut self) {
+        // Blocked rather than passed through, it could still be doing what its driver last told it
+        UNITS.lock()[self.unit].set_context(self.device, 0, 0);
+        FREE_DOMAINS.lock().push(self.id);
+    }
+}
+
+/// Dma memory mapped into the domains of the devices that may use it, for as long as it lives
+pub struct DmaGrant {
+    domains: Vec<Arc<Domain>>,
+    segments: Vec<DmaSegment>,
+}
+
+impl DmaGrant {
+    pub fn new(domains: Vec<Arc<Domain>>, segments: &[DmaSegment]) -> Self {
+        for domain in &domains {
+            domain.map(segments);
+        }
+        Self {
+            domains,
+            segments: segments.to_vec(),
+        }
+    }
+}
+
+impl Drop for DmaGrant {
+    fn drop(&mut self) {
+        for domain in &self.domains {
+            domain.unmap(&self.segments);
+        }
+    }
+}
@@ -1,19 +1,40 @@
-use core::sync::atomic::AtomicU32;
+use core::{
+    arch::x86_64::_mm_mfence,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
-use x86_64::structures::idt::InterruptStackFrame;
+use conquer_once::spin::OnceCell;
+use x86_64::{registers::model_specific::Msr, structures::idt::InterruptStackFrame};
 
 use crate::{
+    cpu::{features, monitor, CpuFeatures},
     cpu_localstorage::CPULocalStorageRW,
+    interrupts::{count_interrupt, LAPIC_INT, PIT_VECTOR},
+    ioapic::send_ipi_to_others,
     paging::{
         page::{Page, Size4KB},
         page_allocator::global_allocator,
         page_table::{MapMemoryError, Mapper, PageTable, TableLevel4},
         MemoryMappingFlags,
     },
-    scheduling::{taskmanager::enter_sched, with_held_interrupts},
-    time::{check_sleep, HPET},
+    scheduling::{stats::sample_load, taskmanager::enter_sched, with_held_interrupts},
+    time::{check_sleep, clock_source, next_wakeup_us, pit, spin_sleep_ms, tsc_at, uptime_ns},
+    watchdog,
 };
 
+const TIMER_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 0b01 << 17;
+const TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/// How long a thread runs before the scheduler gets a look in, in nanoseconds
+const TICK_NS: u64 = 1_000_000;
+
+/// How long the timer is measured for
+const CALIBRATION_MS: u32 = 10;
+/// With a divisor of 16 this is a timer slower than 1.6MHz, which no real one is
+const MIN_TICKS_PER_MS: u32 = 100;
+
 // Local APIC
 /// Do not use before this has been initialized in enable_apic
 pub const LAPIC_ADDR: u64 = 0xfee00000;
@@ -44,43 +65,183 @@ unsafe fn read_lapic(offset: u64) -> u32 {
     addr.read_volatile()
 }
 
+/// The apic id of the core this runs on, which is also its core id
+pub fn current_apic_id() -> u8 {
+    (unsafe { read_lapic(0x20) } >> 24) as u8
+}
+
 pub static LAPIC_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
 
+/// What interrupts each core for the scheduler, the best of them that works is picked by the
+/// bsp and every core uses it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    /// The timer fires once at a tsc value instead of counting down every ms, so sleeping
+    /// threads can be woken when they asked instead of at the next tick
+    TscDeadline,
+    LapicPeriodic,
+    /// Channel 0 of the pit interrupts the bsp, which passes it on to the other cores
+    Pit,
+}
+
+static TICK_SOURCE: OnceCell<TickSource> = OnceCell::uninit();
+
+/// None until the bsp's local apic has been enabled
+pub fn tick_source() -> Option<TickSource> {
+    TICK_SOURCE.get().copied()
+}
+
+fn deadline_mode() -> bool {
+    tick_source() == Some(TickSource::TscDeadline)
+}
+
+/// Measures how many times the timer counts in a ms with a divisor of 16, None if it doesn't
+/// count or is too slow to be right
+unsafe fn calibrate_timer() -> Option<u32> {
+    clock_source()?;
+    write_lapic(0x3E0, 0x3);
+    write_lapic(0x380, 0xFFFFFFFF);
+    spin_sleep_ms(CALIBRATION_MS as u64);
+    let ticks_per_ms = (0xFFFFFFFF - read_lapic(0x390)) / CALIBRATION_MS;
+    // Stop it
+    write_lapic(0x380, 0);
+    trace!("LAPIC Ticks per ms: {ticks_per_ms}");
+    (ticks_per_ms >= MIN_TICKS_PER_MS).then_some(ticks_per_ms)
+}
+
+unsafe fn pick_tick_source() -> TickSource {
+    // The tsc has to be timing uptime for deadlines to be worked out
+    if features().contains(CpuFeatures::TSC_DEADLINE) && tsc_at(0).is_some() {
+        return TickSource::TscDeadline;
+    }
+    if let Some(ticks_per_ms) = calibrate_timer() {
+        LAPIC_TICKS_PER_MS.store(ticks_per_ms, Ordering::SeqCst);
+        return TickSource::LapicPeriodic;
+    }
+    warn!("The LAPIC timer doesn't count right, falling back to the pit");
+    pit::start_tick();
+    TickSource::Pit
+}
+
 pub unsafe fn enable_localapic() {
     with_held_interrupts(|| {
         // Enable + Spurious vector
         write_lapic(0xF0, 1 << 8 | 0xFF);
 
-        // set timer divisor of 16
-        write_lapic(0x3E0, 0x3);
-
-        // measure ticks per ms.
-        // we just want something close to a ms for task switching, we will use HPET for all time tracking
-        write_lapic(0x380, 0xFFFFFFFF);
+        // The bsp picks, and has measured its timer if it picked it
+        let (source, measured) = match tick_source() {
+            Some(source) => (source, false),
+            None => {
+                let source = pick_tick_source();
+                info!("Scheduler tick from {source:?}");
+                TICK_SOURCE.init_once(|| source);
+                (source, true)
+            }
+        };
+
+        match source {
+            TickSource::TscDeadline => {
+                write_lapic(0x320, LAPIC_INT as u32 | TIMER_TSC_DEADLINE);
+                // The mode change has to land before the first deadline is written
+                _mm_mfence();
+                arm_deadline();
+            }
+            TickSource::LapicPeriodic => {
+                // Each core's timer is measured, falling back to what the bsp's was
+                let bsp_ticks_per_ms = LAPIC_TICKS_PER_MS.load(Ordering::SeqCst);
+                let ticks_per_ms = match measured {
+                    true => bsp_ticks_per_ms,
+                    false => calibrate_timer().unwrap_or(bsp_ticks_per_ms),
+                };
+
+                // set timer vector + periodic mode
+                write_lapic(0x320, LAPIC_INT as u32 | TIMER_PERIODIC);
+
+                // set timer divisor of 16
+                write_lapic(0x3E0, 0x3);
+
+                // set timer count
+                write_lapic(0x380, ticks_per_ms);
+            }
+            // The ticks come from the bsp
+            TickSource::Pit => write_lapic(0x320, LAPIC_INT as u32 | TIMER_MASKED),
+        }
+    });
+}
 
-        HPET.get().unwrap().spin_ms(1);
+/// Masks or unmasks the local timer on the current core
+pub unsafe fn set_timer_masked(masked: bool) {
+    match tick_source() {
+        Some(TickSource::TscDeadline | TickSource::LapicPeriodic) => (),
+        // Left masked, the ticks sent by the bsp wake a parked core up to check it is parked
+        _ => return,
+    }
+    let lvt = read_lapic(0x320);
+    if masked {
+        write_lapic(0x320, lvt | TIMER_MASKED);
+    } else {
+        write_lapic(0x320, lvt & !TIMER_MASKED);
+        // A deadline that passed while masked is gone
+        if deadline_mode() {
+            arm_deadline();
+        }
+    }
+}
 
-        let ticks_per_ms = 0xFFFFFFFF - read_lapic(0x390);
-        trace!("LAPIC Ticks per ms: {ticks_per_ms}");
-        LAPIC_TICKS_PER_MS.store(ticks_per_ms, core::sync::atomic::Ordering::SeqCst);
+/// Sets the timer for the next tick or the first sleeping thread, whichever is sooner
+fn arm_deadline() {
+    let mut next = uptime_ns() + TICK_NS;
+    if let Some(wakeup) = next_wakeup_us() {
+        next = next.min(wakeup * 1000);
+    }
+    if let Some(tsc) = tsc_at(next) {
+        unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc) };
+    }
+}
 
-        // set timer vector + periodic mode
-        write_lapic(0x320, 60 | 0x20000);
+/// Brings the current core's timer forward if it would fire after the uptime in
+/// microseconds. Does nothing for the periodic timer, that fires every ms anyway.
+pub fn wake_by(us: u64) {
+    if !deadline_mode() {
+        return;
+    }
+    let Some(tsc) = tsc_at(us * 1000) else {
+        return;
+    };
+    unsafe {
+        let mut msr = Msr::new(IA32_TSC_DEADLINE);
+        // Reads as 0 once it has fired
+        let current = msr.read();
+        if current == 0 || tsc < current {
+            msr.write(tsc);
+        }
+    }
+}
 
-        // set timer divisor of 16
-        write_lapic(0x3E0, 0x3);
+pub extern "x86-interrupt" fn tick_handler(_: InterruptStackFrame) {
+    tick();
+}
 
-        // set timer count
-        write_lapic(0x380, ticks_per_ms);
-    });
+/// Channel 0 of the pit, only routed with [`TickSource::Pit`]
+pub extern "x86-interrupt" fn pit_tick_handler(_: InterruptStackFrame) {
+    count_interrupt(PIT_VECTOR);
+    send_ipi_to_others(LAPIC_INT as u8);
+    tick();
 }
 
-pub extern "x86-interrupt" fn tick_handler(_: InterruptStackFrame) {
+fn tick() {
     unsafe {
         // Ack interrupt
         *(0xfee000b0 as *mut u32) = 0;
+        count_interrupt(LAPIC_INT);
+        watchdog::tick();
 
         check_sleep();
+        sample_load();
+        monitor::sample();
+        if deadline_mode() {
+            arm_deadline();
+        }
 
         // if we are not in sched yield to it
         if CPULocalStorageRW::get_context() > 0 {
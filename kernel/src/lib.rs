@@ -12,7 +12,6 @@ use core::fmt::Write;
 use bootloader::BootInfo;
 use scheduling::taskmanager::kill_bad_task;
 use screen::gop::WRITER;
-use terminal::Writer;
 use x86_64::instructions::interrupts::without_interrupts;
 
 use crate::{cpu_localstorage::CPULocalStorageRW, paging::MemoryLoc};
@@ -31,13 +30,16 @@ pub mod assembly;
 pub mod boot_aps;
 pub mod bootfs;
 pub mod channel;
+pub mod cpu;
 pub mod cpu_localstorage;
 pub mod driver;
 pub mod elf;
+pub mod fpu;
 pub mod fs;
 pub mod gdt;
 pub mod interrupts;
 pub mod ioapic;
+pub mod iommu;
 pub mod lapic;
 pub mod locked_mutex;
 pub mod logging;
@@ -49,16 +51,21 @@ pub mod object;
 pub mod paging;
 pub mod pci;
 pub mod port;
+pub mod random;
 pub mod scheduling;
 pub mod serial;
+pub mod smbios;
 pub mod syscall;
 pub mod terminal;
 pub mod time;
 pub mod uefi;
+pub mod user_mem;
+pub mod watchdog;
 
 pub static mut BOOT_INFO: *const BootInfo = 0 as *const BootInfo;
 extern "C" {
     static KERNEL_START: u8;
+    static KERNEL_TEXT_END: u8;
     static KERNEL_END: u8;
 }
 
@@ -72,6 +79,18 @@ pub fn kernel_memory_loc() -> (u64, u64) {
     }
 }
 
+/// End of the kernels code, everything after it is data and isn't executable
+pub fn kernel_text_end() -> u64 {
+    // Safe since this is our own linker variable
+    unsafe { &KERNEL_TEXT_END as *const u8 as u64 }
+}
+
+/// How far KASLR moved the kernel from the address it was linked at, subtract it from an address to
+/// get the address in the kernel elf
+pub fn kernel_slide() -> u64 {
+    kernel_memory_loc().0 - MemoryLoc::KernelStart as u64
+}
+
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     let context = CPULocalStorageRW::get_context();
@@ -80,11 +99,13 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         // lowest context, no chance of recovery
         without_interrupts(|| {
             let mut w = WRITER.get().unwrap().lock();
+            // Take the screen back from any userspace surface
+            w.resume();
             w.write_fmt(format_args!("KERNEL PANIC: {}\n", info))
                 .unwrap();
             // since we drop context switch manually trigger redraw
             w.redraw_if_needed();
-            crate::stack_trace(&mut w);
+            crate::stack_trace(&mut *w);
             w.redraw_if_needed();
             loop {
                 unsafe { core::arch::asm!("hlt") }
@@ -113,15 +134,21 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 /// Walks rbp to find all call frames, additionally prints out the return address of each frame
 /// TODO: find the associated function from the ip
-pub fn stack_trace(w: &mut Writer) {
+pub fn stack_trace(w: &mut impl Write) {
     unsafe {
         let mut rbp: usize;
-        w.write_str("Performing stack trace...\n").unwrap();
+        let slide = kernel_slide() as usize;
+        w.write_fmt(format_args!(
+            "Performing stack trace... (kernel slide: {slide:#x})\n"
+        ))
+        .unwrap();
         core::arch::asm!("mov {}, rbp", lateout(reg) rbp);
         for depth in 0.. {
             let caller = *((rbp + 8) as *const usize);
+            // Print the linked address so that it can be looked up in the kernel elf
+            let linked = caller.wrapping_sub(slide);
             w.write_fmt(format_args!(
-                "Frame {depth}: base pointer: {rbp:#x}, return address: {caller:#x}\n"
+                "Frame {depth}: base pointer: {rbp:#x}, return address: {caller:#x} ({linked:#x})\n"
             ))
             .unwrap();
 
@@ -12,13 +12,14 @@ use core::ffi::c_void;
 use core::ops::ControlFlow;
 
 use ::acpi::AcpiError;
-use alloc::vec::Vec;
+use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use kernel::acpi::FioxaAcpiHandler;
+use kernel::acpi::{aml, ec, events, power, FioxaAcpiHandler};
 use kernel::boot_aps::boot_aps;
-use kernel::bootfs::{DEFAULT_FONT, PS2_DRIVER, TERMINAL_ELF};
+use kernel::bootfs::{DEFAULT_FONT, POWER_MANAGER, TERMINAL_ELF};
 use kernel::cpu_localstorage::{init_bsp_localstorage, CPULocalStorageRW};
 use kernel::elf::load_elf;
+use kernel::fs::page_cache::page_cache_writeback;
 use kernel::fs::{self, FSDRIVES};
 use kernel::interrupts::{self, check_interrupts};
 
@@ -31,26 +32,32 @@ use kernel::net::ethernet::userspace_networking_main;
 use kernel::object::init_handle_new_proc;
 use kernel::paging::offset_map::{create_kernel_map, create_offset_map, map_gop};
 use kernel::paging::page::{Page, Size4KB};
-use kernel::paging::page_allocator::global_allocator;
+use kernel::paging::page_allocator::{global_allocator, reclaim_boot_memory};
 use kernel::paging::page_table::Mapper;
+use kernel::paging::pressure::memory_pressure_monitor;
+use kernel::paging::swap::pageout;
 use kernel::paging::{
     ensure_ident_map_curr_process, set_mem_offset, virt_addr_offset, MemoryLoc, MemoryMappingFlags,
     KERNEL_DATA_MAP, KERNEL_LVL4, OFFSET_MAP,
 };
 use kernel::pci::enumerate_pci;
 use kernel::scheduling::process::Process;
+use kernel::scheduling::reaper::reaper;
+use kernel::scheduling::stats::stats_service;
+use kernel::scheduling::supervisor::supervisor;
 use kernel::scheduling::taskmanager::{
     core_start_multitasking, spawn_process, PROCESSES, SCHEDULER,
 };
 use kernel::scheduling::with_held_interrupts;
 use kernel::screen::gop;
 use kernel::screen::psf1;
-use kernel::serial::{serial_monitor_stdin, Serial, COM_1, SERIAL};
+use kernel::serial::{Serial, COM_1, SERIAL};
+use kernel::smbios::{self, hwinfo_service};
 use kernel::syscall::syscall_kernel_handler;
 use kernel::terminal::Writer;
 use kernel::time::init_time;
 use kernel::uefi::get_config_table;
-use kernel::{elf, gdt, paging, BOOT_INFO};
+use kernel::{cpu, elf, gdt, iommu, paging, BOOT_INFO};
 
 use bootloader::uefi::table::cfg::ACPI2_GUID;
 use bootloader::uefi::table::{Runtime, SystemTable};
@@ -67,6 +74,16 @@ pub fn main_stage1(info: *const BootInfo) -> ! {
     unsafe {
         x86_64::instructions::interrupts::disable();
 
+        // Pages are mapped no execute unless they are asked to be executable, the APs set this in
+        // their trampoline
+        x86_64::registers::model_specific::Efer::update(|f| {
+            f.insert(x86_64::registers::model_specific::EferFlags::NO_EXECUTE_ENABLE)
+        });
+        // Everything after checks what the cpu can do
+        cpu::init_core(0);
+        // Has to be set up before the framebuffer is mapped
+        paging::pat::init_core();
+
         // init gdt & idt
         gdt::init_bootgdt();
         interrupts::init_idt();
@@ -138,6 +155,7 @@ unsafe extern "C" fn main_stage2() {
     let boot_info = unsafe { core::ptr::read(BOOT_INFO) };
 
     // Initalize GOP stdout
+    let framebuffer = *boot_info.gop.buffer.as_ptr() as u64;
     let font = psf1::load_psf1_font(DEFAULT_FONT).expect("cannot load psf1 font");
     gop::WRITER.init_once(|| Writer::new(boot_info.gop, font).into());
     // Test screen colours
@@ -150,6 +168,8 @@ unsafe extern "C" fn main_stage2() {
     log::set_logger(&KERNEL_LOGGER).unwrap();
     log::set_max_level(log::LevelFilter::Debug);
     info!("Welcome to Fioxa...");
+    info!("CPU features: {:?}", cpu::features());
+    paging::pat::check_framebuffer(framebuffer);
 
     init_bsp_localstorage();
 
@@ -210,6 +230,7 @@ extern "C" fn init() {
             .unwrap();
 
         init_time(&acpi_tables);
+        smbios::init(config_tables);
 
         let madt = acpi_tables.find_table::<Madt>().unwrap();
 
@@ -226,13 +247,50 @@ extern "C" fn init() {
         }
 
         unsafe { boot_aps(&madt) };
+
+        // The UEFI tables were only needed to find ACPI and SMBIOS
+        for addr in [boot_info.uefi_runtime_table, config_tables.as_ptr() as u64] {
+            let mut memory = init_process.memory.lock();
+            let mapper = unsafe { memory.page_mapper.get_mapper_mut() };
+            if let Ok(f) = mapper.unmap(global_allocator(), Page::<Size4KB>::containing(addr)) {
+                f.flush();
+            }
+        }
     });
 
-    // TODO: Reclaim memory, but first need to drop any references to the memory region
-    // unsafe {
-    //     let reclaim = frame_alloc_exec(|f| f.reclaim_memory());
-    //     println!("RECLAIMED MEMORY: {}Mb", reclaim * 0x1000 / 1024 / 1024);
-    // }
+    // Everything still needed from the bootloader is either in the kernel image or gets copied
+    // out here, so the rest of its memory can be given back
+    unsafe {
+        let boot_info = core::ptr::read(BOOT_INFO);
+        let mmap = MemoryMapIter::new(
+            boot_info.mmap_buf,
+            boot_info.mmap_entry_size,
+            boot_info.mmap_len,
+        );
+        BOOT_INFO = Box::leak(Box::new(BootInfo {
+            mmap_buf: core::ptr::null(),
+            mmap_len: 0,
+            ..boot_info
+        }));
+
+        let kernel_start = boot_info.kernel_start as usize / 0x1000;
+        let mut keep = [
+            // The zero page and the AP trampoline are never handed out
+            0..1,
+            0x8000 / 0x1000..0x8000 / 0x1000 + 1,
+            kernel_start..kernel_start + boot_info.kernel_pages as usize,
+        ];
+        let pages = reclaim_boot_memory(mmap, &mut keep);
+        info!(
+            "Reclaimed {}mb of bootloader memory",
+            pages * 0x1000 / 1024 / 1024
+        );
+    }
+
+    #[cfg(all(debug_assertions, feature = "self_test"))]
+    kernel::paging::page_mapper::test::fork_cow_self_test();
+    #[cfg(all(debug_assertions, feature = "self_test"))]
+    kernel::driver::disk::usb_msc::test::self_test();
 
     let mut init_handles = Vec::new();
 
@@ -265,18 +323,26 @@ extern "C" fn init() {
         true,
     );
     spawn_process(testing_proc, &[], &[get_init()], "testing_proc", true);
-    spawn_process(after_boot_pci, &[], &[get_init()], "after_boot_pci", true);
+    spawn_process(stats_service, &[], &[get_init()], "stats_service", true);
+    spawn_process(hwinfo_service, &[], &[get_init()], "hwinfo_service", true);
+    spawn_process(reaper, &[], &[], "reaper", true);
+    spawn_process(pageout, &[], &[], "pageout", true);
     spawn_process(
-        serial_monitor_stdin,
+        memory_pressure_monitor,
         &[],
-        &[get_init()],
-        "serial montior",
+        &[],
+        "memory_pressure_monitor",
         true,
     );
+    spawn_process(page_cache_writeback, &[], &[], "page_cache_writeback", true);
+    spawn_process(after_boot_pci, &[], &[get_init()], "after_boot_pci", true);
 
-    // TODO: Use IO permissions instead of kernel
-    load_elf(PS2_DRIVER, &[], &[get_init()], true).unwrap();
-    load_elf(TERMINAL_ELF, &[], &[get_init()], false).unwrap();
+    spawn_process(supervisor, &[], &[get_init()], "supervisor", true);
+    load_elf(POWER_MANAGER, None, &[], &[get_init()], true, true).unwrap();
+    load_elf(TERMINAL_ELF, None, &[], &[get_init()], false, true).unwrap();
+    if SERIAL.get().is_some() {
+        load_elf(TERMINAL_ELF, None, b"serial", &[get_init()], false, true).unwrap();
+    }
 
     init_handle_new_proc(init_handles);
 }
@@ -338,12 +404,28 @@ fn after_boot_pci() {
         acpi::AcpiTables::from_rsdp(FioxaAcpiHandler, acpi_tables.address as usize).unwrap()
     };
 
+    // Pci interrupts are routed through the aml namespace
+    aml::init(&acpi_tables);
+    ec::init();
+    power::init(&acpi_tables);
+    events::init(&acpi_tables);
+
+    // Devices are added to the iommu as they are found, so translation can only go on after
+    iommu::init(&acpi_tables);
+
     info!("Enumnerating PCI...");
 
     enumerate_pci(acpi_tables);
+    iommu::enable();
 
     spawn_thread(fs::file_handler);
+    spawn_thread(fs::block_cache::readahead_task);
     FSDRIVES.lock().identify();
+    spawn_thread(fs::disk_hotplug);
+    fs::tmpfs::mount_tmpfs("/tmp");
+    fs::devfs::mount_devfs("/dev");
+    fs::bootfs::mount_bootfs("/boot");
+    fs::procfs::mount_procfs("/proc");
 
     exit();
 }
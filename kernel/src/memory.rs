@@ -5,8 +5,6 @@ use crate::paging::{
     virt_addr_offset,
 };
 
-pub const RESERVED_32BIT_MEM_PAGES: usize = 32; // 16Kb
-
 pub fn get_memory_size_pages(mmap: MemoryMapIter) -> u64 {
     let mut memory_size = 0;
     for md in mmap {
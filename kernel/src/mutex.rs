@@ -1,8 +1,16 @@
 use core::sync::atomic::{AtomicBool, Ordering};
 
+use alloc::{collections::VecDeque, sync::Arc};
 use lock_api::{GuardNoSend, RawMutex};
 
-use crate::cpu_localstorage::{is_ls_enabled, CPULocalStorageRW};
+use crate::{
+    cpu_localstorage::{is_ls_enabled, CPULocalStorageRW},
+    paging::tlb::handle_pending,
+    scheduling::{
+        process::{Thread, ThreadPriority, ThreadState},
+        taskmanager::enter_sched,
+    },
+};
 
 pub type Spinlock<T> = lock_api::Mutex<RawSpinlock, T>;
 pub type SpinlockGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinlock, T>;
@@ -27,6 +35,8 @@ unsafe impl RawMutex for RawSpinlock {
         {
             // wait until the lock looks unlocked
             while self.is_locked() {
+                // The holder might be waiting on us to flush
+                handle_pending();
                 core::hint::spin_loop();
             }
         }
@@ -61,3 +71,164 @@ unsafe impl RawMutex for RawSpinlock {
         self.0.load(Ordering::Relaxed)
     }
 }
+
+/// A mutex that puts the thread to sleep while it is contended, use this over [`Spinlock`]
+/// for locks that are held for a long time (e.g. across disk io).
+///
+/// It can only sleep when called from a thread that isn't holding interrupts, otherwise it will spin.
+pub type Mutex<T> = lock_api::Mutex<RawSleepingMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, RawSleepingMutex, T>;
+
+pub struct RawSleepingMutex {
+    inner: Spinlock<SleepingMutexInner>,
+}
+
+struct SleepingMutexInner {
+    locked: bool,
+    owner: Option<Arc<Thread>>,
+    waiters: VecDeque<(Arc<Thread>, ThreadPriority)>,
+}
+
+impl RawSleepingMutex {
+    /// Identifies the mutex in the boosts it gave its owner, it can't move while locked
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+fn can_sleep() -> bool {
+    is_ls_enabled()
+        && CPULocalStorageRW::get_context() != 0
+        && CPULocalStorageRW::hold_interrupts_depth() == 0
+}
+
+fn current_thread() -> Option<Arc<Thread>> {
+    if is_ls_enabled() && CPULocalStorageRW::get_context() != 0 {
+        Some(unsafe { CPULocalStorageRW::get_current_task() }.thread())
+    } else {
+        None
+    }
+}
+
+unsafe impl RawMutex for RawSleepingMutex {
+    const INIT: RawSleepingMutex = RawSleepingMutex {
+        inner: Spinlock::new(SleepingMutexInner {
+            locked: false,
+            owner: None,
+            waiters: VecDeque::new(),
+        }),
+    };
+
+    // The owner is tracked for priority inheritance so it must be unlocked on the same thread
+    type GuardMarker = GuardNoSend;
+
+    fn lock(&self) {
+        if self.try_lock() {
+            return;
+        }
+
+        if !can_sleep() {
+            while !self.try_lock() {
+                handle_pending();
+                core::hint::spin_loop();
+            }
+            return;
+        }
+
+        let thread = unsafe { CPULocalStorageRW::get_current_task() };
+        let priority = thread.sched().lock().effective_priority();
+
+        let mut inner = self.inner.lock();
+        // Unlock takes us off the queue when it hands us the mutex, so we stay queued through
+        // other wakes, like our process being killed
+        let mut queued = false;
+        loop {
+            if !inner.locked {
+                if queued {
+                    inner
+                        .waiters
+                        .retain(|w| !core::ptr::eq(w.0.as_ref(), thread));
+                }
+                inner.locked = true;
+                inner.owner = Some(thread.thread());
+                return;
+            }
+
+            // Boost the owner so that it can't be starved by threads less important than us. It
+            // is kept even if another mutex boosted it more, as that one could be released first.
+            if let Some(owner) = &inner.owner {
+                owner.sched().lock().inherit_priority(self.id(), priority);
+            }
+
+            let mut sched = thread.sched().lock();
+            sched.state = ThreadState::Sleeping;
+            if !queued {
+                inner.waiters.push_back((thread.thread(), priority));
+                queued = true;
+            }
+            drop(inner);
+            enter_sched(&mut sched);
+            drop(sched);
+
+            inner = self.inner.lock();
+            // Unlock hands the mutex directly to the woken thread
+            if inner
+                .owner
+                .as_ref()
+                .is_some_and(|o| core::ptr::eq(o.as_ref(), thread))
+            {
+                return;
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.locked {
+            return false;
+        }
+        inner.locked = true;
+        inner.owner = current_thread();
+        true
+    }
+
+    unsafe fn unlock(&self) {
+        let mut inner = self.inner.lock();
+
+        if let Some(owner) = inner.owner.take() {
+            owner.sched().lock().release_priority(self.id());
+        }
+
+        // A killed thread that isn't in a syscall is exited by the scheduler instead of coming
+        // back to take the mutex
+        inner.waiters.retain(|(t, _)| {
+            let sched = t.sched().lock();
+            sched.state != ThreadState::Zombie && !(sched.killed && !sched.in_syscall)
+        });
+
+        // Pick the most important waiter, the first to wait wins ties
+        let next = inner
+            .waiters
+            .iter()
+            .enumerate()
+            .max_by(|(ai, a), (bi, b)| a.1.cmp(&b.1).then(bi.cmp(ai)))
+            .map(|(i, _)| i);
+
+        match next.and_then(|i| inner.waiters.remove(i)) {
+            Some((next, _)) => {
+                // The remaining waiters are now waiting on the new owner
+                if let Some(boost) = inner.waiters.iter().map(|w| w.1).max() {
+                    next.sched().lock().inherit_priority(self.id(), boost);
+                }
+                inner.owner = Some(next.clone());
+                drop(inner);
+                next.wake();
+            }
+            None => inner.locked = false,
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.inner.lock().locked
+    }
+}
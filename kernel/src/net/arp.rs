@@ -1,11 +1,46 @@
-use alloc::collections::BTreeMap;
-use conquer_once::spin::Lazy;
-use kernel_userspace::net::IPAddr;
+//! Address resolution protocol and the neighbour cache, which ipv6 neighbour discovery fills
+//! as well.
+//!
+//! Each host we talk to directly gets an entry for the interface it is on. While its mac is being asked for, packets to
+//! it wait in the entry and the request is resent a few times by [`arp_timer_task`]. Once the
+//! host answers the packets go out, and the entry is kept for [`REACHABLE_TIME`]. If it never
+//! answers the entry is marked failed so we stop flooding the network with requests for a
+//! while, and anything sent to it in the meantime is dropped.
+
+use core::mem::size_of;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use kernel_userspace::{
+    net::{ArpEntry, ArpEntryState, ArpResponse, IPAddr, TxOffload},
+    syscall::sleep,
+};
 use modular_bitfield::{bitfield, specifiers::B48};
 
-use crate::mutex::Spinlock;
+use crate::{mutex::Mutex, time::uptime};
+
+use super::{
+    ethernet::{
+        send_frame, send_frame_fragments, BROADCAST_MAC, ETHER_TYPE_ARP, ETHER_TYPE_IPV4,
+        ETHER_TYPE_IPV6,
+    },
+    interface::{self, Interface},
+    ndp, route,
+};
 
-use super::ethernet::EthernetFrameHeader;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+/// How long a resolved entry is trusted, in ms
+const REACHABLE_TIME: u64 = 5 * 60_000;
+/// How long to wait for a reply before asking again
+const RETRY_INTERVAL: u64 = 1000;
+/// Requests sent before the host is given up on
+const MAX_REQUESTS: u32 = 3;
+/// How long a host that didn't answer is left alone
+const FAILED_TIME: u64 = 20_000;
+/// How often the timer looks over the cache
+const TICK: u64 = 250;
+const MAX_PENDING: usize = 32;
 
 #[bitfield]
 pub struct ARP {
@@ -21,11 +56,294 @@ pub struct ARP {
     pub dst_ip: u32,
 }
 
-#[repr(C, packed)]
-pub struct ARPEth {
-    pub header: EthernetFrameHeader,
-    pub arp: ARP,
+enum State {
+    Incomplete {
+        requests: u32,
+        /// Packets waiting on the mac, oldest first, gathered into one piece
+        pending: Vec<(Vec<u8>, TxOffload)>,
+    },
+    Reachable(u64),
+    Failed,
+}
+
+struct Neighbour {
+    state: State,
+    /// Uptime in ms when the entry runs out, or the next request is due while incomplete
+    deadline: u64,
+}
+
+/// Keyed by the id of the interface the host is on and its ip
+static NEIGHBOURS: Mutex<BTreeMap<(u32, IPAddr), Neighbour>> = Mutex::new(BTreeMap::new());
+
+pub fn handle_packet(iface: &Interface, data: &[u8]) {
+    if data.len() < size_of::<ARP>() {
+        return;
+    }
+    let arp = ARP::from_bytes(data[..size_of::<ARP>()].try_into().unwrap());
+    if arp.hardware_type() != 1u16.to_be() || arp.protocol() != ETHER_TYPE_IPV4.to_be() {
+        return;
+    }
+
+    let src_ip = IPAddr::ipv4_addr_from_net(arp.src_ip());
+    let src_mac = arp.src_mac();
+    let addr = iface.addr();
+    let for_us = addr != IPAddr::UNSPECIFIED && arp.dst_ip() == addr.as_net_be();
+
+    // Probes come from 0.0.0.0 and tell us nothing
+    if src_ip == IPAddr::UNSPECIFIED || src_mac == 0 || src_mac == BROADCAST_MAC {
+        return;
+    }
+    if src_ip == addr && src_mac != iface.mac {
+        warn!(
+            "{src_ip} on {} is also being used by {src_mac:#X}",
+            iface.name
+        );
+        return;
+    }
+
+    // As in RFC 826, only hosts we already know about or that are talking to us get an
+    // entry, so gratuitous ARPs update the cache without filling it with every host around
+    learn(iface, src_ip, src_mac, for_us);
+    if for_us && arp.operation() == ARP_REQUEST.to_be() {
+        send_packet(iface, ARP_REPLY, src_mac, src_mac, src_ip);
+    }
+}
+
+/// Records the mac a neighbour on the interface's link is at and sends anything that was
+/// waiting on it. Unless create is set this only updates hosts already in the cache.
+pub fn learn(iface: &Interface, ip: IPAddr, mac: u64, create: bool) {
+    let mut neighbours = NEIGHBOURS.lock();
+    let pending = match neighbours.get_mut(&(iface.id, ip)) {
+        Some(n) => {
+            let old = core::mem::replace(&mut n.state, State::Reachable(mac));
+            n.deadline = uptime() + REACHABLE_TIME;
+            match old {
+                State::Incomplete { pending, .. } => pending,
+                _ => Vec::new(),
+            }
+        }
+        None if create => {
+            neighbours.insert(
+                (iface.id, ip),
+                Neighbour {
+                    state: State::Reachable(mac),
+                    deadline: uptime() + REACHABLE_TIME,
+                },
+            );
+            Vec::new()
+        }
+        None => Vec::new(),
+    };
+    drop(neighbours);
+
+    for (packet, offload) in pending {
+        send_frame_fragments(iface, mac, ether_type(ip), &[&packet], offload);
+    }
+}
+
+fn ether_type(ip: IPAddr) -> u16 {
+    match ip {
+        IPAddr::V4(..) => ETHER_TYPE_IPV4,
+        IPAddr::V6(_) => ETHER_TYPE_IPV6,
+    }
 }
 
-pub static ARP_TABLE: Lazy<Spinlock<BTreeMap<IPAddr, u64>>> =
-    Lazy::new(|| Spinlock::new(BTreeMap::new()));
+fn send_packet(
+    iface: &Interface,
+    operation: u16,
+    dst_mac: u64,
+    target_mac: u64,
+    target_ip: IPAddr,
+) {
+    let mut arp = ARP::new();
+    arp.set_hardware_type(1u16.to_be()); // Ethernet
+    arp.set_protocol(ETHER_TYPE_IPV4.to_be()); // ipv4
+    arp.set_hardware_addr_size(6); // mac
+    arp.set_protocol_addr_size(4); // ipv4
+    arp.set_operation(operation.to_be());
+
+    arp.set_src_ip(iface.addr().as_net_be());
+    arp.set_src_mac(iface.mac);
+    arp.set_dst_mac(target_mac);
+    arp.set_dst_ip(target_ip.as_net_be());
+
+    send_frame(iface, dst_mac, ETHER_TYPE_ARP, &arp.into_bytes());
+}
+
+fn send_request(iface: &Interface, ip: IPAddr) {
+    match ip {
+        IPAddr::V4(..) => send_packet(iface, ARP_REQUEST, BROADCAST_MAC, 0, ip),
+        IPAddr::V6(_) => ndp::send_solicitation(iface, ip),
+    }
+}
+
+/// Tells everyone on the interface's link about its address, so stale entries for it
+/// elsewhere get updated
+pub fn announce(iface: &Interface) {
+    let addr = iface.addr();
+    if addr != IPAddr::UNSPECIFIED {
+        send_packet(iface, ARP_REQUEST, BROADCAST_MAC, 0, addr);
+    }
+}
+
+/// Starts resolving the neighbour unless it is already known or being resolved
+fn start_lookup(
+    neighbours: &mut BTreeMap<(u32, IPAddr), Neighbour>,
+    key: (u32, IPAddr),
+    now: u64,
+) -> bool {
+    if neighbours
+        .get(&key)
+        .is_some_and(|n| n.deadline > now || matches!(n.state, State::Incomplete { .. }))
+    {
+        return false;
+    }
+    neighbours.insert(
+        key,
+        Neighbour {
+            state: State::Incomplete {
+                requests: 1,
+                pending: Vec::new(),
+            },
+            deadline: now + RETRY_INTERVAL,
+        },
+    );
+    true
+}
+
+/// Sends an ip packet made of the fragments to a host on the interface's link, holding on to
+/// it until we know the host's mac
+pub fn send_ip(iface: &Interface, ip: IPAddr, packet: &[&[u8]], offload: TxOffload) {
+    if ip == IPAddr::BROADCAST {
+        send_frame_fragments(iface, BROADCAST_MAC, ETHER_TYPE_IPV4, packet, offload);
+        return;
+    }
+    // Multicast groups map straight onto 01:00:5E and the group's low 23 bits
+    if let IPAddr::V4(224..=239, b, c, d) = ip {
+        let mac = u64::from_le_bytes([0x01, 0x00, 0x5E, b & 0x7F, c, d, 0, 0]);
+        send_frame_fragments(iface, mac, ETHER_TYPE_IPV4, packet, offload);
+        return;
+    }
+
+    let now = uptime();
+    let key = (iface.id, ip);
+    let mut neighbours = NEIGHBOURS.lock();
+    let request = start_lookup(&mut neighbours, key, now);
+    match &mut neighbours.get_mut(&key).unwrap().state {
+        State::Reachable(mac) => {
+            let mac = *mac;
+            drop(neighbours);
+            send_frame_fragments(iface, mac, ether_type(ip), packet, offload);
+        }
+        State::Incomplete { pending, .. } => {
+            if pending.len() < MAX_PENDING {
+                pending.push((packet.concat(), offload));
+            }
+            drop(neighbours);
+            if request {
+                send_request(iface, ip);
+            }
+        }
+        State::Failed => trace!("Dropping packet as {ip} is unreachable"),
+    }
+}
+
+/// Looks up the mac for the ip for the `NETWORKING` service, starting to resolve it if it
+/// isn't known. The host is looked for on the interface the routing table sends it out of.
+pub fn lookup(ip: IPAddr) -> ArpResponse {
+    let Some(next) = route::resolve(ip) else {
+        return ArpResponse::Unreachable;
+    };
+    let iface = next.interface;
+    if let IPAddr::V4(..) = ip {
+        let config = iface.config();
+        if let Err(e) = config.addr.same_subnet(&ip, config.netmask) {
+            return ArpResponse::NotSameSubnet(e);
+        }
+    }
+    let now = uptime();
+    let key = (iface.id, ip);
+    let mut neighbours = NEIGHBOURS.lock();
+    let request = start_lookup(&mut neighbours, key, now);
+    let response = match neighbours[&key].state {
+        State::Reachable(mac) => ArpResponse::Mac(mac),
+        State::Incomplete { .. } => ArpResponse::Pending,
+        State::Failed => ArpResponse::Unreachable,
+    };
+    drop(neighbours);
+    if request {
+        send_request(&iface, ip);
+    }
+    response
+}
+
+/// Everything in the cache, for `arp -a`
+pub fn entries() -> Vec<ArpEntry> {
+    let now = uptime();
+    let interfaces = interface::all();
+    NEIGHBOURS
+        .lock()
+        .iter()
+        .filter(|(_, n)| n.deadline > now || matches!(n.state, State::Incomplete { .. }))
+        .filter_map(|((id, ip), n)| {
+            let iface = interfaces.iter().find(|i| i.id == *id)?;
+            Some((iface, ip, n))
+        })
+        .map(|(iface, ip, n)| ArpEntry {
+            ip: *ip,
+            interface: iface.name.clone(),
+            state: match n.state {
+                State::Incomplete { .. } => ArpEntryState::Incomplete,
+                State::Reachable(mac) => ArpEntryState::Reachable(mac),
+                State::Failed => ArpEntryState::Failed,
+            },
+            expires_in: n.deadline.saturating_sub(now),
+        })
+        .collect()
+}
+
+/// Forgets the ipv4 hosts on the interface, as the addresses we knew may not be on its subnet
+/// any more
+pub fn clear(interface: u32) {
+    NEIGHBOURS
+        .lock()
+        .retain(|(id, ip), _| *id != interface || ip.is_ipv6());
+}
+
+pub fn forget_interface(interface: u32) {
+    NEIGHBOURS.lock().retain(|(id, _), _| *id != interface);
+}
+
+/// Resends requests that haven't been answered and drops entries that have run out
+pub fn arp_timer_task() {
+    loop {
+        sleep(TICK);
+        let now = uptime();
+        let mut resend = Vec::new();
+        NEIGHBOURS.lock().retain(|(id, ip), n| {
+            if n.deadline > now {
+                return true;
+            }
+            match &mut n.state {
+                State::Incomplete { requests, .. } if *requests < MAX_REQUESTS => {
+                    *requests += 1;
+                    n.deadline = now + RETRY_INTERVAL;
+                    resend.push((*id, *ip));
+                    true
+                }
+                State::Incomplete { .. } => {
+                    trace!("No arp reply from {ip}");
+                    n.state = State::Failed;
+                    n.deadline = now + FAILED_TIME;
+                    true
+                }
+                State::Reachable(_) | State::Failed => false,
+            }
+        });
+        for (id, ip) in resend {
+            if let Some(iface) = interface::get(id) {
+                send_request(&iface, ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Packet capture. Every frame an interface sends or receives is mirrored to the captures that
+//! want it, which is what `tcpdump` reads from.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use kernel_userspace::{
+    channel::channel_write_rs,
+    net::{CaptureDirection, CaptureFilter, CapturedFrame},
+    object::{object_wait, KernelReference, ObjectSignal},
+    service::serialize,
+    syscall::spawn_thread,
+};
+
+use crate::{mutex::Mutex, time::uptime_us};
+
+use super::interface::Interface;
+
+struct Tap {
+    client: KernelReference,
+    filter: CaptureFilter,
+}
+
+static TAPS: Mutex<BTreeMap<u64, Tap>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+/// Whether there are any taps, so frames only pay for capture while someone is looking
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Starts passing frames to client
+pub fn open(filter: CaptureFilter, client: KernelReference) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut taps = TAPS.lock();
+    taps.insert(
+        id,
+        Tap {
+            client: client.clone(),
+            filter,
+        },
+    );
+    ACTIVE.store(true, Ordering::Relaxed);
+    drop(taps);
+
+    spawn_thread(move || tap_task(id, client));
+}
+
+/// Removes the tap once its owner closes the channel, nothing is read from it
+fn tap_task(id: u64, client: KernelReference) {
+    object_wait(client.id(), ObjectSignal::CHANNEL_CLOSED);
+    let mut taps = TAPS.lock();
+    taps.remove(&id);
+    ACTIVE.store(!taps.is_empty(), Ordering::Relaxed);
+}
+
+/// Mirrors the frame made of the fragments to the taps on the interface. A tap that has fallen
+/// behind misses it.
+pub fn frame(iface: &Interface, direction: CaptureDirection, fragments: &[&[u8]]) {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let timestamp_us = uptime_us();
+    let frame = fragments.concat();
+
+    let taps = TAPS.lock();
+    let mut buffer = Vec::new();
+    for tap in taps
+        .values()
+        .filter(|t| t.filter.interface.is_none_or(|i| i == iface.id))
+    {
+        serialize(
+            &CapturedFrame {
+                interface: iface.id,
+                direction,
+                timestamp_us,
+                len: frame.len() as u32,
+                data: &frame[..frame.len().min(tap.filter.snap_len as usize)],
+            },
+            &mut buffer,
+        );
+        channel_write_rs(tap.client.id(), &buffer, &[]);
+    }
+}
@@ -0,0 +1,144 @@
+//! The network configuration, read from `/boot/net.conf` when the stack starts. It names the
+//! host, picks the ntp server and says how each interface gets its ipv4 address, interfaces
+//! it doesn't mention use dhcp. Each line is one of
+//!
+//! ```text
+//! hostname <name>
+//! ntp <server>
+//! interface <name> dhcp
+//! interface <name> static <addr>/<prefix> [gateway <ip>] [dns <ip>]...
+//! ```
+//!
+//! and `#` starts a comment.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use kernel_userspace::{
+    backoff_sleep,
+    fs::{stat, File},
+    net::{IPAddr, InterfaceConfig},
+};
+
+use crate::mutex::Mutex;
+
+const PATH: &str = "/boot/net.conf";
+
+/// How an interface gets its ipv4 address
+#[derive(Debug, Clone)]
+pub enum Addressing {
+    Dhcp,
+    Static(InterfaceConfig),
+}
+
+struct NetConfig {
+    hostname: Option<String>,
+    ntp: Option<String>,
+    interfaces: Vec<(String, Addressing)>,
+}
+
+static CONFIG: Mutex<NetConfig> = Mutex::new(NetConfig {
+    hostname: None,
+    ntp: None,
+    interfaces: Vec::new(),
+});
+
+/// Reads the configuration, which has to be done before any interface registers
+pub fn load() {
+    // The boot file system is mounted while the stack is starting
+    backoff_sleep(|| stat("/boot", &mut Vec::new()).ok());
+
+    let mut data = Vec::new();
+    if let Err(e) = File::open(PATH).and_then(|mut f| f.read_to_end(&mut data)) {
+        warn!("Couldn't read {PATH}, interfaces will use dhcp: {e:?}");
+        return;
+    }
+    let text = String::from_utf8_lossy(&data);
+    let mut config = CONFIG.lock();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let Some(key) = words.next() else {
+            continue;
+        };
+        if parse_line(&mut config, key, words).is_none() {
+            warn!("{PATH}:{}: ignoring {:?}", i + 1, line.trim());
+        }
+    }
+}
+
+fn parse_line<'a>(
+    config: &mut NetConfig,
+    key: &str,
+    mut words: impl Iterator<Item = &'a str>,
+) -> Option<()> {
+    match key {
+        "hostname" => config.hostname = Some(only(words)?.to_string()),
+        "ntp" => config.ntp = Some(only(words)?.to_string()),
+        "interface" => {
+            let name = words.next()?.to_string();
+            let addressing = match words.next()? {
+                "dhcp" if words.next().is_none() => Addressing::Dhcp,
+                "static" => Addressing::Static(parse_static(words)?),
+                _ => return None,
+            };
+            // The last line for an interface wins
+            config.interfaces.retain(|(n, _)| *n != name);
+            config.interfaces.push((name, addressing));
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// The one word left
+fn only<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let word = words.next()?;
+    words.next().is_none().then_some(word)
+}
+
+fn parse_static<'a>(mut words: impl Iterator<Item = &'a str>) -> Option<InterfaceConfig> {
+    let (addr, prefix_len) = words.next()?.split_once('/')?;
+    let addr: IPAddr = addr.parse().ok()?;
+    let prefix_len: u32 = prefix_len.parse().ok()?;
+    if addr.is_ipv6() || prefix_len > 32 {
+        return None;
+    }
+
+    let mut config = InterfaceConfig {
+        addr,
+        netmask: u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0),
+        gateway: None,
+        dns: Vec::new(),
+    };
+    while let Some(key) = words.next() {
+        let ip = words.next()?.parse().ok()?;
+        match key {
+            "gateway" => config.gateway = Some(ip),
+            "dns" => config.dns.push(ip),
+            _ => return None,
+        }
+    }
+    Some(config)
+}
+
+/// The name we answer to under `.local`, None if there isn't one
+pub fn hostname() -> Option<String> {
+    CONFIG.lock().hostname.clone()
+}
+
+/// The server the clock is synced with, None if there isn't one
+pub fn ntp_server() -> Option<String> {
+    CONFIG.lock().ntp.clone()
+}
+
+/// How the interface with the name gets its ipv4 address
+pub fn addressing(name: &str) -> Addressing {
+    CONFIG
+        .lock()
+        .interfaces
+        .iter()
+        .find(|(n, _)| n == name)
+        .map_or(Addressing::Dhcp, |(_, a)| a.clone())
+}
@@ -0,0 +1,289 @@
+//! Dynamic host configuration protocol client, RFC 2131.
+//!
+//! Each interface gets a client when it comes up. It broadcasts a DISCOVER, takes the first
+//! OFFER and REQUESTs it. Once the server ACKs the interface is configured with what it gave
+//! us, and half way through the lease we ask the same server to renew it. If the server stops
+//! answering we start again from DISCOVER.
+//!
+//! The clients can't share a udp socket, so [`super::udp`] hands replies to [`deliver`] which
+//! passes them on to the client of the interface they came in on.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use kernel_userspace::{
+    channel::{channel_create_rs, channel_write_rs},
+    net::{read_timeout, IPAddr, InterfaceConfig, NetError},
+    object::KernelReference,
+    syscall::sleep,
+};
+
+use crate::{mutex::Mutex, random::random_u64};
+
+use super::{interface::Interface, ip::PROTOCOL_UDP, ipv4, udp};
+
+const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+/// Asks the server to broadcast its replies as we can't take unicast before we have an address
+const FLAG_BROADCAST: u16 = 1 << 15;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Where the options start
+const OPTIONS: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+/// How long to wait for each reply in ms, doubled every attempt
+const INITIAL_TIMEOUT: u64 = 2000;
+const ATTEMPTS: u32 = 4;
+/// How long to wait before starting over when nobody answers
+const RETRY_DELAY: u64 = 10_000;
+/// What to assume if the server doesn't give a lease time, in seconds
+const DEFAULT_LEASE: u32 = 3600;
+
+#[derive(Debug, Default)]
+struct Reply {
+    kind: u8,
+    yiaddr: Option<IPAddr>,
+    server: Option<IPAddr>,
+    netmask: Option<u32>,
+    router: Option<IPAddr>,
+    dns: Vec<IPAddr>,
+    lease: Option<u32>,
+}
+
+fn ip_at(data: &[u8]) -> IPAddr {
+    IPAddr::V4(data[0], data[1], data[2], data[3])
+}
+
+/// Where to pass replies for each interface's client, by interface id
+static CLIENTS: Mutex<BTreeMap<u32, KernelReference>> = Mutex::new(BTreeMap::new());
+
+/// Passes a reply that came in on the interface to its client
+pub fn deliver(iface: &Interface, data: &[u8]) {
+    if let Some(client) = CLIENTS.lock().get(&iface.id) {
+        channel_write_rs(client.id(), data, &[]);
+    }
+}
+
+struct Client {
+    iface: Arc<Interface>,
+    /// Replies passed on by [`deliver`]
+    replies: KernelReference,
+    mac: [u8; 6],
+    xid: u32,
+}
+
+impl Client {
+    fn message(&self, kind: u8, ciaddr: IPAddr, options: &[u8]) -> Vec<u8> {
+        let mut msg = Vec::with_capacity(300);
+        msg.extend_from_slice(&[BOOTREQUEST, HTYPE_ETHERNET, 6, 0]);
+        msg.extend_from_slice(&self.xid.to_be_bytes());
+        msg.extend_from_slice(&0u16.to_be_bytes()); // secs
+        let flags = if ciaddr == IPAddr::UNSPECIFIED {
+            FLAG_BROADCAST
+        } else {
+            0
+        };
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&ciaddr.octets());
+        msg.extend_from_slice(&[0; 12]); // yiaddr, siaddr and giaddr
+        msg.extend_from_slice(&self.mac);
+        msg.extend_from_slice(&[0; 10 + 64 + 128]); // chaddr padding, sname and file
+        msg.extend_from_slice(&MAGIC_COOKIE);
+
+        msg.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, kind]);
+        msg.extend_from_slice(&[
+            OPTION_PARAMETERS,
+            4,
+            OPTION_SUBNET_MASK,
+            OPTION_ROUTER,
+            OPTION_DNS,
+            OPTION_LEASE_TIME,
+        ]);
+        msg.extend_from_slice(options);
+        msg.push(OPTION_END);
+        msg
+    }
+
+    fn parse(&self, data: &[u8]) -> Option<Reply> {
+        if data.len() < OPTIONS
+            || data[0] != BOOTREPLY
+            || data[4..8] != self.xid.to_be_bytes()
+            || data[28..34] != self.mac
+            || data[236..240] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut reply = Reply {
+            yiaddr: Some(ip_at(&data[16..])),
+            ..Default::default()
+        };
+        let mut options = &data[OPTIONS..];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => (),
+            }
+            let len = *rest.first()? as usize;
+            let value = rest.get(1..1 + len)?;
+            match (*kind, len) {
+                (OPTION_MESSAGE_TYPE, 1) => reply.kind = value[0],
+                (OPTION_SUBNET_MASK, 4) => {
+                    reply.netmask = Some(u32::from_be_bytes(value.try_into().unwrap()))
+                }
+                (OPTION_ROUTER, 4..) => reply.router = Some(ip_at(value)),
+                (OPTION_DNS, _) => reply.dns = value.chunks_exact(4).map(ip_at).collect(),
+                (OPTION_LEASE_TIME, 4) => {
+                    reply.lease = Some(u32::from_be_bytes(value.try_into().unwrap()))
+                }
+                (OPTION_SERVER_ID, 4) => reply.server = Some(ip_at(value)),
+                _ => (),
+            }
+            options = &rest[1 + len..];
+        }
+        Some(reply)
+    }
+
+    /// Sends the message out of our interface, from whatever address it has
+    fn send(&self, msg: &[u8], to: IPAddr) {
+        let config = self.iface.config();
+        let next_hop = match config.gateway {
+            Some(gateway) if config.addr.same_subnet(&to, config.netmask).is_err() => gateway,
+            _ => to,
+        };
+        let datagram = udp::datagram(config.addr, CLIENT_PORT, to, SERVER_PORT, msg);
+        ipv4::send_on(
+            &self.iface,
+            config.addr,
+            to,
+            next_hop,
+            PROTOCOL_UDP,
+            &datagram,
+        );
+    }
+
+    /// Sends the message until a reply of one of the kinds comes back
+    fn exchange(&mut self, msg: &[u8], to: IPAddr, kinds: &[u8]) -> Option<Reply> {
+        let mut buf = Vec::new();
+        let mut timeout = INITIAL_TIMEOUT;
+        for _ in 0..ATTEMPTS {
+            self.send(msg, to);
+            loop {
+                match read_timeout(&self.replies, &mut buf, timeout) {
+                    Ok(()) => match self.parse(&buf) {
+                        Some(reply) if kinds.contains(&reply.kind) => return Some(reply),
+                        _ => continue,
+                    },
+                    Err(NetError::WouldBlock) => break,
+                    Err(e) => {
+                        warn!("Failed to read dhcp reply: {e}");
+                        return None;
+                    }
+                }
+            }
+            timeout *= 2;
+        }
+        None
+    }
+
+    /// Asks for the address, to the broadcast address while selecting and straight to the
+    /// server when renewing. Returns the lease time in seconds once the interface is set up.
+    fn request(&mut self, addr: IPAddr, server: IPAddr, renewing: bool) -> Option<u32> {
+        let msg = if renewing {
+            self.message(DHCPREQUEST, addr, &[])
+        } else {
+            let mut options = Vec::new();
+            options.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+            options.extend_from_slice(&addr.octets());
+            options.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+            options.extend_from_slice(&server.octets());
+            self.message(DHCPREQUEST, IPAddr::UNSPECIFIED, &options)
+        };
+        let to = if renewing { server } else { IPAddr::BROADCAST };
+
+        let ack = self.exchange(&msg, to, &[DHCPACK, DHCPNAK])?;
+        if ack.kind == DHCPNAK {
+            warn!("DHCP server refused {addr}");
+            return None;
+        }
+
+        self.iface.set_config(InterfaceConfig {
+            addr: ack.yiaddr?,
+            netmask: ack.netmask.unwrap_or(0xFFFF_FF00),
+            gateway: ack.router,
+            dns: ack.dns,
+        });
+        Some(ack.lease.unwrap_or(DEFAULT_LEASE))
+    }
+
+    /// Goes from nothing to a configured interface, returning the server and lease time
+    fn acquire(&mut self) -> Option<(IPAddr, IPAddr, u32)> {
+        self.xid = random_u64() as u32;
+        let discover = self.message(DHCPDISCOVER, IPAddr::UNSPECIFIED, &[]);
+        let offer = self.exchange(&discover, IPAddr::BROADCAST, &[DHCPOFFER])?;
+        let (addr, server) = (offer.yiaddr?, offer.server?);
+        info!("DHCP offered {addr} from {server} on {}", self.iface.name);
+
+        let lease = self.request(addr, server, false)?;
+        Some((addr, server, lease))
+    }
+}
+
+/// Keeps the interface configured until it goes away
+pub fn dhcp_task(iface: Arc<Interface>) {
+    let (replies, theirs) = channel_create_rs();
+    CLIENTS.lock().insert(iface.id, theirs);
+    let mut client = Client {
+        mac: iface.mac.to_le_bytes()[..6].try_into().unwrap(),
+        iface,
+        replies,
+        xid: 0,
+    };
+
+    while !client.iface.is_removed() {
+        let Some((addr, server, mut lease)) = client.acquire() else {
+            warn!(
+                "No answer from a dhcp server on {}, trying again",
+                client.iface.name
+            );
+            sleep(RETRY_DELAY);
+            continue;
+        };
+
+        // Keep renewing until the server stops agreeing
+        loop {
+            sleep(lease as u64 * 1000 / 2);
+            if client.iface.is_removed() {
+                break;
+            }
+            match client.request(addr, server, true) {
+                Some(l) => lease = l,
+                None => break,
+            }
+        }
+        client.iface.set_config(InterfaceConfig::UNCONFIGURED);
+    }
+    CLIENTS.lock().remove(&client.iface.id);
+}
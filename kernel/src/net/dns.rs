@@ -0,0 +1,255 @@
+//! A caching stub resolver for the `DNS` service. Queries go to the servers dhcp gave us, A and
+//! AAAA are asked for together and whatever comes back is kept for as long as its ttl says.
+//! Names under `.local` are left to [`super::mdns`].
+
+use core::ops::ControlFlow;
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use kernel_userspace::{
+    channel::{channel_read_resize, channel_write_rs, ChannelReadResult},
+    net::{DnsRequest, IPAddr, NetError, Resolved, UdpSocket},
+    service::{deserialize, serialize, Service},
+};
+
+use crate::{random::random_u64, time::uptime};
+
+use super::{interface, mdns};
+
+const DNS_PORT: u16 = 53;
+
+pub const HEADER_LEN: usize = 12;
+/// Asks the server to do the recursion for us
+const FLAG_RD: u16 = 1 << 8;
+const FLAG_QR: u16 = 1 << 15;
+const RCODE_NXDOMAIN: u16 = 3;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+pub const CLASS_IN: u16 = 1;
+
+const MAX_LABEL: usize = 63;
+const MAX_NAME: usize = 253;
+
+/// Rounds through every server, each waiting twice as long as the last
+const ATTEMPTS: u32 = 3;
+const INITIAL_TIMEOUT: u64 = 1000;
+/// The most we will trust a ttl for, in seconds
+const MAX_TTL: u32 = 3600;
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RD.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // questions
+    query.extend_from_slice(&[0; 6]); // answers, authorities and additionals
+    push_name(&mut query, name)?;
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Some(query)
+}
+
+/// Adds the name as labels, None if it isn't a valid name
+pub fn push_name(msg: &mut Vec<u8>, name: &str) -> Option<()> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > MAX_NAME {
+        return None;
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL {
+            return None;
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    Some(())
+}
+
+/// Returns where the name at pos ends
+pub fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer to a name somewhere else ends this one
+            l if l & 0xC0 == 0xC0 => return Some(pos + 2),
+            l => pos += 1 + l,
+        }
+    }
+}
+
+pub fn u16_at(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        msg.get(pos..pos + 2)?.try_into().unwrap(),
+    ))
+}
+
+/// Adds the answers to resolved, returning the lowest ttl. None if the message is malformed.
+fn parse_answers(msg: &[u8], resolved: &mut Resolved) -> Option<u32> {
+    let questions = u16_at(msg, 4)?;
+    let answers = u16_at(msg, 6)?;
+
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut ttl = MAX_TTL;
+    for _ in 0..answers {
+        pos = skip_name(msg, pos)?;
+        let rtype = u16_at(msg, pos)?;
+        let class = u16_at(msg, pos + 2)?;
+        let rttl = u32::from_be_bytes(msg.get(pos + 4..pos + 8)?.try_into().unwrap());
+        let len = u16_at(msg, pos + 8)? as usize;
+        let data = msg.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+
+        // CNAMEs are followed by the records for what they point to, so only the addresses
+        // matter
+        match (rtype, class, data) {
+            (TYPE_A, CLASS_IN, &[a, b, c, d]) => resolved.v4.push(IPAddr::V4(a, b, c, d)),
+            (TYPE_AAAA, CLASS_IN, d) if d.len() == 16 => {
+                resolved.v6.push(IPAddr::V6(d.try_into().unwrap()))
+            }
+            _ => continue,
+        }
+        ttl = ttl.min(rttl);
+    }
+    Some(ttl)
+}
+
+/// Asks the servers for the name, giving back the addresses and how long they are good for
+fn lookup(
+    socket: &mut UdpSocket,
+    servers: &[IPAddr],
+    name: &str,
+) -> Result<(Resolved, u32), NetError> {
+    if servers.is_empty() {
+        return Err(NetError::NoDnsServer);
+    }
+
+    let mut buf = [0; 1500];
+    for attempt in 0..ATTEMPTS {
+        let timeout = INITIAL_TIMEOUT << attempt;
+        for &server in servers {
+            let id = random_u64() as u16;
+            let ids = [id, id.wrapping_add(1)];
+            for (id, qtype) in ids.into_iter().zip([TYPE_A, TYPE_AAAA]) {
+                let query = encode_query(id, name, qtype).ok_or(NetError::HostNotFound)?;
+                socket.send_to(&query, server, DNS_PORT)?;
+            }
+
+            let mut resolved = Resolved::default();
+            let mut ttl = MAX_TTL;
+            let mut answered = [false; 2];
+            let mut not_found = false;
+            while !answered.iter().all(|a| *a) {
+                let (len, src, src_port) = match socket.recv_from_timeout(&mut buf, timeout) {
+                    Ok(r) => r,
+                    Err(NetError::WouldBlock) => break,
+                    Err(e) => return Err(e),
+                };
+                let msg = &buf[..len];
+                let (Some(rid), Some(flags)) = (u16_at(msg, 0), u16_at(msg, 2)) else {
+                    continue;
+                };
+                let Some(i) = ids.iter().position(|id| *id == rid) else {
+                    continue;
+                };
+                if src != server || src_port != DNS_PORT || flags & FLAG_QR == 0 || answered[i] {
+                    continue;
+                }
+
+                match flags & 0xF {
+                    0 => match parse_answers(msg, &mut resolved) {
+                        Some(t) => ttl = ttl.min(t),
+                        None => continue,
+                    },
+                    RCODE_NXDOMAIN => not_found = true,
+                    // The server failed, so try the next one
+                    _ => break,
+                }
+                answered[i] = true;
+            }
+
+            if !resolved.v4.is_empty() || !resolved.v6.is_empty() {
+                return Ok((resolved, ttl));
+            }
+            if not_found || answered.iter().all(|a| *a) {
+                return Err(NetError::HostNotFound);
+            }
+        }
+    }
+    Err(NetError::TimedOut)
+}
+
+struct CacheEntry {
+    resolved: Resolved,
+    /// Uptime in ms when it stops being used
+    expires: u64,
+}
+
+pub fn dns_service() {
+    let mut socket = match UdpSocket::bind(0) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Failed to bind a port for dns: {e}");
+            return;
+        }
+    };
+    let mut cache: BTreeMap<String, CacheEntry> = BTreeMap::new();
+    let mut buffer = Vec::with_capacity(100);
+
+    Service::new(
+        "DNS",
+        || (),
+        |handle, ()| {
+            match channel_read_resize(handle.id(), &mut buffer, &mut Vec::new()) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Closed => return ControlFlow::Break(()),
+                e => {
+                    warn!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+
+            let name = match deserialize(&buffer) {
+                Ok(DnsRequest::Resolve(name)) => name.to_lowercase(),
+                Err(e) => {
+                    warn!("Bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+
+            let now = uptime();
+            cache.retain(|_, e| e.expires > now);
+            let result = match cache.get(&name) {
+                Some(entry) => Ok(entry.resolved.clone()),
+                // The link answers for these, with a cache of its own
+                None if name.trim_end_matches('.').ends_with(".local") => mdns::resolve(&name),
+                None => {
+                    // The servers every interface was given, in the order they came up
+                    let servers: Vec<IPAddr> = interface::all()
+                        .iter()
+                        .flat_map(|i| i.config().dns)
+                        .collect();
+                    lookup(&mut socket, &servers, &name).map(|(resolved, ttl)| {
+                        cache.insert(
+                            name,
+                            CacheEntry {
+                                resolved: resolved.clone(),
+                                expires: now + ttl as u64 * 1000,
+                            },
+                        );
+                        resolved
+                    })
+                }
+            };
+
+            serialize(&result, &mut buffer);
+            channel_write_rs(handle.id(), &buffer, &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
@@ -1,25 +1,29 @@
-use core::{
-    fmt::Debug,
-    mem::{size_of, transmute},
-    ops::ControlFlow,
-};
+use core::{fmt::Debug, mem::size_of, ops::ControlFlow};
 
-use alloc::vec::Vec;
+use alloc::{sync::Arc, vec::Vec};
 use kernel_userspace::{
-    channel::{channel_create_rs, channel_read_rs, channel_write_rs},
-    net::{ArpResponse, IPAddr, Networking, NotSameSubnetError},
+    channel::{channel_create_rs, channel_read_rs, channel_write_rs, ChannelReadResult},
+    net::{ring::PacketRing, CaptureDirection, NetError, Networking, TxOffload},
     object::KernelReference,
-    service::{deserialize, serialize, Service, SimpleService},
+    service::{deserialize, serialize, Service},
     syscall::spawn_thread,
 };
 use modular_bitfield::{bitfield, specifiers::B48};
 
-use crate::{
-    net::arp::{ARP, ARP_TABLE},
-    scheduling::with_held_interrupts,
+use super::{
+    arp, capture, config, dns, firewall, icmp,
+    interface::{self, Interface},
+    ipv4, ipv6, mdns, sntp, socket, tcp, udp,
 };
 
-use super::arp::ARPEth;
+pub const BROADCAST_MAC: u64 = 0xFF_FF_FF_FF_FF_FF;
+/// The most a frame carries after its header
+pub const MTU: usize = 1500;
+pub const ETHER_TYPE_IPV4: u16 = 0x0800;
+pub const ETHER_TYPE_ARP: u16 = 0x0806;
+pub const ETHER_TYPE_IPV6: u16 = 0x86DD;
+/// Frames shorter than this have to be padded
+const MIN_FRAME_LEN: usize = 60;
 
 #[bitfield]
 #[derive(Clone, Copy)]
@@ -45,95 +49,70 @@ pub struct EthernetFrame<'a> {
     pub data: &'a [u8],
 }
 
-pub fn handle_ethernet_frame(frame: EthernetFrame) {
-    trace!("{:?}", frame.header);
-    if frame.header.ether_type_be() == 1544 {
-        with_held_interrupts(|| {
-            assert!(frame.data.len() >= size_of::<ARP>());
-            let arp = unsafe { &*(frame.data.as_ptr() as *const ARP) };
-            if arp.src_mac() != 0xFF_FF_FF && arp.src_mac() != 0 {
-                ARP_TABLE
-                    .lock()
-                    .insert(IPAddr::ipv4_addr_from_net(arp.src_ip()), arp.src_mac());
-            }
-            if arp.dst_mac() != 0xFF_FF_FF && arp.dst_mac() != 0 {
-                ARP_TABLE
-                    .lock()
-                    .insert(IPAddr::ipv4_addr_from_net(arp.dst_ip()), arp.dst_mac());
-            }
-        });
+pub fn handle_ethernet_frame(iface: &Interface, frame: EthernetFrame) {
+    trace!("{}: {:?}", iface.name, frame.header);
+    match u16::from_be(frame.header.ether_type_be()) {
+        ETHER_TYPE_ARP => arp::handle_packet(iface, frame.data),
+        ETHER_TYPE_IPV4 => ipv4::handle_packet(iface, frame.data),
+        ETHER_TYPE_IPV6 => ipv6::handle_packet(iface, frame.data),
+        _ => (),
     }
 }
 
-const IP_ADDR: IPAddr = IPAddr::V4(10, 0, 2, 15);
-const SUBNET: u32 = 0xFF0000;
-
-pub fn send_arp(
-    service: &mut SimpleService,
-    mac_addr: u64,
-    ip: IPAddr,
-) -> Result<(), NotSameSubnetError> {
-    IP_ADDR.same_subnet(&ip, SUBNET)?;
-    let mut arp = ARP::new();
-    arp.set_hardware_type(1u16.to_be()); // Ethernet
-    arp.set_protocol(0x0800u16.to_be()); // ipv4
-    arp.set_hardware_addr_size(6); // mac
-    arp.set_protocol_addr_size(4); // ipv4
-    arp.set_operation(1u16.to_be()); // request
-
-    arp.set_src_ip(IP_ADDR.as_net_be());
-    arp.set_src_mac(mac_addr);
-    arp.set_dst_ip(ip.as_net_be());
+/// Sends a frame out of the interface from its mac, padded to the minimum length
+pub fn send_frame(iface: &Interface, dst_mac: u64, ether_type: u16, payload: &[u8]) {
+    send_frame_fragments(iface, dst_mac, ether_type, &[payload], TxOffload::default());
+}
 
+/// Sends a frame whose payload is the fragments one after the other, leaving what the
+/// offload asks for (with offsets from the start of the payload) to the card
+pub fn send_frame_fragments(
+    iface: &Interface,
+    dst_mac: u64,
+    ether_type: u16,
+    payload: &[&[u8]],
+    mut offload: TxOffload,
+) {
     let mut header = EthernetFrameHeader::new();
-    header.set_dst_mac_be(0xFF_FF_FF_FF_FF_FF);
-    header.set_src_mac_be(mac_addr);
-    header.set_ether_type_be(0x0806u16.to_be());
-    let arp_req = ARPEth { header, arp };
-    let buf: &[u8; size_of::<ARPEth>()] = &unsafe { transmute(arp_req) };
-
-    let mut buffer = Vec::new();
-    serialize(
-        &kernel_userspace::net::PhysicalNet::SendPacket(buf),
-        &mut buffer,
-    );
-
-    let mut handles = Vec::new();
-    service.call(&mut buffer, &mut handles).unwrap();
-
-    Ok(())
+    header.set_dst_mac_be(dst_mac);
+    header.set_src_mac_be(iface.mac);
+    header.set_ether_type_be(ether_type.to_be());
+    let header = header.into_bytes();
+    offload.l4_start += header.len() as u16;
+
+    let mut fragments = Vec::with_capacity(payload.len() + 2);
+    fragments.push(&header[..]);
+    fragments.extend_from_slice(payload);
+    // Zeros don't change a checksum, so padding is fine after an offloaded one
+    let len: usize = fragments.iter().map(|f| f.len()).sum();
+    if len < MIN_FRAME_LEN {
+        fragments.push(&[0; MIN_FRAME_LEN][..MIN_FRAME_LEN - len]);
+    }
+
+    iface.transmit(&fragments, offload);
 }
 
+/// Starts the stack. Network cards join in as their drivers register with the `INTERFACE`
+/// service.
 pub fn userspace_networking_main() {
-    let mut pcnet = SimpleService::with_name("PCNET");
-
     let mut buffer = Vec::with_capacity(100);
 
-    serialize(&kernel_userspace::net::PhysicalNet::MacAddrGet, &mut buffer);
-    pcnet.call(&mut buffer, &mut Vec::new()).unwrap();
-    let mac: u64 = deserialize(&buffer).unwrap();
-
-    let (listen_chan, listen_chan_right) = channel_create_rs();
-
-    serialize(
-        &kernel_userspace::net::PhysicalNet::ListenToPackets,
-        &mut buffer,
-    );
-    let mut handles = Vec::new();
-    handles.push(listen_chan_right.id());
-    pcnet.call(&mut buffer, &mut handles).unwrap();
-
-    spawn_thread(move || monitor_packets(listen_chan));
+    config::load();
+    spawn_thread(arp::arp_timer_task);
+    spawn_thread(tcp::timer_task);
+    spawn_thread(interface::interface_service);
+    spawn_thread(dns::dns_service);
+    spawn_thread(firewall::firewall_service);
+    spawn_thread(mdns::mdns_service);
+    spawn_thread(sntp::sntp_service);
 
     Service::new(
         "NETWORKING",
         || (),
         |handle, ()| {
             match channel_read_rs(handle.id(), &mut buffer, &mut Vec::new()) {
-                kernel_userspace::channel::ChannelReadResult::Ok => (),
-                kernel_userspace::channel::ChannelReadResult::Closed => {
-                    return ControlFlow::Break(())
-                }
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Closed => return ControlFlow::Break(()),
                 e => {
                     warn!("{e:?}");
                     return ControlFlow::Break(());
@@ -142,16 +121,44 @@ pub fn userspace_networking_main() {
 
             match deserialize(&buffer) {
                 Ok(Networking::ArpRequest(ip)) => {
-                    let mac_addr = ARP_TABLE.lock().get(&ip).cloned();
-
-                    let resp = match mac_addr {
-                        Some(mac) => ArpResponse::Mac(mac),
-                        None => ArpResponse::Pending(send_arp(&mut pcnet, mac, ip)),
-                    };
-
-                    serialize(&resp, &mut buffer);
+                    serialize(&arp::lookup(ip), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(Networking::ArpTable) => {
+                    serialize(&arp::entries(), &mut buffer);
                     channel_write_rs(handle.id(), &buffer, &[]);
                 }
+                Ok(Networking::Socket(kind)) => {
+                    let (ours, theirs) = channel_create_rs();
+                    socket::open(kind, ours);
+                    serialize(&Ok::<_, NetError>(()), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[theirs.id()]);
+                }
+                Ok(Networking::IcmpOpen) => {
+                    let (ours, theirs) = channel_create_rs();
+                    match icmp::open(ours) {
+                        Ok(id) => {
+                            serialize(&Ok::<_, NetError>(id), &mut buffer);
+                            channel_write_rs(handle.id(), &buffer, &[theirs.id()]);
+                        }
+                        Err(e) => {
+                            serialize(&Err::<u16, _>(e), &mut buffer);
+                            channel_write_rs(handle.id(), &buffer, &[]);
+                        }
+                    }
+                }
+                Ok(Networking::SocketTable) => {
+                    let mut table = tcp::sockets();
+                    table.extend(udp::sockets());
+                    serialize(&table, &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(Networking::Capture(filter)) => {
+                    let (ours, theirs) = channel_create_rs();
+                    capture::open(filter, ours);
+                    serialize(&Ok::<_, NetError>(()), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[theirs.id()]);
+                }
                 Err(e) => {
                     warn!("Bad message: {e:?}");
                     return ControlFlow::Break(());
@@ -164,19 +171,54 @@ pub fn userspace_networking_main() {
     .run();
 }
 
-pub fn monitor_packets(socket: KernelReference) {
+/// Handles the frames the interface's driver passes up until the driver goes away, which
+/// takes the interface with it
+/// Handles what the card receives, which comes down the channel or for cards that share
+/// rings is in the receive ring
+pub fn monitor_packets(
+    iface: Arc<Interface>,
+    socket: KernelReference,
+    mut ring: Option<PacketRing>,
+) {
     let mut buffer = Vec::with_capacity(2048);
     loop {
         match channel_read_rs(socket.id(), &mut buffer, &mut Vec::new()) {
-            kernel_userspace::channel::ChannelReadResult::Ok => (),
-            e => panic!("{e:?}"),
+            ChannelReadResult::Ok => (),
+            ChannelReadResult::Closed => break,
+            e => {
+                warn!("{}: {e:?}", iface.name);
+                break;
+            }
         };
+        match &mut ring {
+            // The message only says there are frames in the ring
+            Some(ring) => {
+                // The card stops receiving once the ring fills up, until it is told there is
+                // room again
+                let was_full = ring.is_full();
+                while let Some(frame) = ring.peek() {
+                    receive_frame(&iface, frame);
+                    ring.pop();
+                }
+                if was_full {
+                    iface.ring_kick();
+                }
+            }
+            None => receive_frame(&iface, &buffer),
+        }
+    }
+    interface::remove(&iface);
+}
 
-        assert!(buffer.len() > size_of::<EthernetFrameHeader>());
-
-        let header = unsafe { *(buffer.as_ptr() as *const EthernetFrameHeader) };
-        let data = &buffer[size_of::<EthernetFrameHeader>()..];
+fn receive_frame(iface: &Interface, frame: &[u8]) {
+    capture::frame(iface, CaptureDirection::Received, &[frame]);
 
-        handle_ethernet_frame(EthernetFrame { header, data })
+    if frame.len() <= size_of::<EthernetFrameHeader>() {
+        return;
     }
+
+    let header = unsafe { *(frame.as_ptr() as *const EthernetFrameHeader) };
+    let data = &frame[size_of::<EthernetFrameHeader>()..];
+
+    handle_ethernet_frame(iface, EthernetFrame { header, data })
 }
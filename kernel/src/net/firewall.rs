@@ -0,0 +1,191 @@
+//! The firewall. Ip packets are checked against the rules once they are known to be for us on
+//! the way in, and as they are handed to the interface on the way out. Everything goes
+//! through until rules are added.
+
+use core::ops::ControlFlow;
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    net::{
+        firewall::{
+            FirewallAction, FirewallDirection, FirewallEntry, FirewallMessage, FirewallProtocol,
+            FirewallRule, FirewallTable,
+        },
+        IPAddr, NetError,
+    },
+    service::{deserialize, serialize, Service},
+};
+
+use crate::mutex::Mutex;
+
+use super::{
+    interface,
+    ip::{PROTOCOL_TCP, PROTOCOL_UDP},
+    ipv4, ipv6, route,
+};
+
+struct Firewall {
+    policy_in: FirewallAction,
+    policy_out: FirewallAction,
+    rules: Vec<FirewallEntry>,
+}
+
+static FIREWALL: Mutex<Firewall> = Mutex::new(Firewall {
+    policy_in: FirewallAction::Accept,
+    policy_out: FirewallAction::Accept,
+    rules: Vec::new(),
+});
+
+/// The source and destination ports of a tcp segment or udp datagram
+fn ports(protocol: u8, payload: &[&[u8]]) -> Option<(u16, u16)> {
+    if protocol != PROTOCOL_TCP && protocol != PROTOCOL_UDP {
+        return None;
+    }
+    let mut bytes = payload.iter().flat_map(|f| f.iter()).copied();
+    let mut next = || Some(u16::from_be_bytes([bytes.next()?, bytes.next()?]));
+    Some((next()?, next()?))
+}
+
+fn protocol_matches(protocol: FirewallProtocol, number: u8) -> bool {
+    match protocol {
+        FirewallProtocol::Icmp => {
+            number == ipv4::PROTOCOL_ICMP || number == ipv6::NEXT_HEADER_ICMPV6
+        }
+        FirewallProtocol::Tcp => number == PROTOCOL_TCP,
+        FirewallProtocol::Udp => number == PROTOCOL_UDP,
+    }
+}
+
+/// Whether a packet with remote at the other end can go through. The payload is the
+/// packet's without its ip header.
+pub fn allows(
+    direction: FirewallDirection,
+    interface: u32,
+    remote: IPAddr,
+    protocol: u8,
+    payload: &[&[u8]],
+) -> bool {
+    let (local_port, remote_port) = match (direction, ports(protocol, payload)) {
+        (FirewallDirection::In, Some((src, dst))) => (Some(dst), Some(src)),
+        (FirewallDirection::Out, Some((src, dst))) => (Some(src), Some(dst)),
+        (_, None) => (None, None),
+    };
+
+    let mut firewall = FIREWALL.lock();
+    let matched = firewall.rules.iter_mut().find(|e| {
+        let rule = &e.rule;
+        rule.direction == direction
+            && rule.interface.is_none_or(|i| i == interface)
+            && rule.protocol.is_none_or(|p| protocol_matches(p, protocol))
+            && rule
+                .remote
+                .is_none_or(|(ip, len)| route::in_prefix(remote, ip, len))
+            && rule.local_port.is_none_or(|p| local_port == Some(p))
+            && rule.remote_port.is_none_or(|p| remote_port == Some(p))
+    });
+    let action = match matched {
+        Some(entry) => {
+            entry.matched += 1;
+            entry.rule.action
+        }
+        None if direction == FirewallDirection::In => firewall.policy_in,
+        None => firewall.policy_out,
+    };
+    action == FirewallAction::Accept
+}
+
+fn insert(index: Option<u32>, rule: FirewallRule) -> Result<(), NetError> {
+    if rule.interface.is_some_and(|i| interface::get(i).is_none()) {
+        return Err(NetError::NoSuchInterface);
+    }
+    let has_ports = matches!(
+        rule.protocol,
+        Some(FirewallProtocol::Tcp | FirewallProtocol::Udp)
+    );
+    let max_len = |ip: IPAddr| if ip.is_ipv6() { 128 } else { 32 };
+    if ((rule.local_port.is_some() || rule.remote_port.is_some()) && !has_ports)
+        || rule.remote.is_some_and(|(ip, len)| len > max_len(ip))
+    {
+        return Err(NetError::InvalidRule);
+    }
+
+    let mut firewall = FIREWALL.lock();
+    let index = index.map_or(firewall.rules.len(), |i| i as usize);
+    if index > firewall.rules.len() {
+        return Err(NetError::NoSuchRule);
+    }
+    firewall
+        .rules
+        .insert(index, FirewallEntry { rule, matched: 0 });
+    Ok(())
+}
+
+fn delete(index: u32) -> Result<(), NetError> {
+    let mut firewall = FIREWALL.lock();
+    if index as usize >= firewall.rules.len() {
+        return Err(NetError::NoSuchRule);
+    }
+    firewall.rules.remove(index as usize);
+    Ok(())
+}
+
+fn table() -> FirewallTable {
+    let firewall = FIREWALL.lock();
+    FirewallTable {
+        policy_in: firewall.policy_in,
+        policy_out: firewall.policy_out,
+        rules: firewall.rules.clone(),
+    }
+}
+
+pub fn firewall_service() {
+    let mut buffer = Vec::with_capacity(100);
+
+    Service::new(
+        "FIREWALL",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut Vec::new()) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Closed => return ControlFlow::Break(()),
+                e => {
+                    warn!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+
+            match deserialize(&buffer) {
+                Ok(FirewallMessage::List) => {
+                    serialize(&table(), &mut buffer);
+                }
+                Ok(FirewallMessage::Insert(index, rule)) => {
+                    serialize(&insert(index, rule), &mut buffer);
+                }
+                Ok(FirewallMessage::Delete(index)) => {
+                    serialize(&delete(index), &mut buffer);
+                }
+                Ok(FirewallMessage::Flush) => {
+                    FIREWALL.lock().rules.clear();
+                    serialize(&(), &mut buffer);
+                }
+                Ok(FirewallMessage::SetPolicy(direction, action)) => {
+                    let mut firewall = FIREWALL.lock();
+                    match direction {
+                        FirewallDirection::In => firewall.policy_in = action,
+                        FirewallDirection::Out => firewall.policy_out = action,
+                    }
+                    serialize(&(), &mut buffer);
+                }
+                Err(e) => {
+                    warn!("Bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+            channel_write_rs(handle.id(), &buffer, &[]);
+
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
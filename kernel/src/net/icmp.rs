@@ -0,0 +1,132 @@
+//! Internet control message protocol, just enough to answer pings and send our own. Ping
+//! sockets work over both ipv4 and ipv6, [`super::icmpv6`] hands us the replies it gets.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use kernel_userspace::{
+    channel::{channel_read_resize, channel_write_rs, ChannelReadResult},
+    net::{EchoReply, EchoRequest, IPAddr, NetError, MAX_UDP_PAYLOAD},
+    object::KernelReference,
+    service::{deserialize, serialize},
+    syscall::spawn_thread,
+};
+
+use crate::{mutex::Mutex, random::random_below, time::uptime_us};
+
+use super::{
+    icmpv6,
+    ip::checksum,
+    ipv4::{self, PROTOCOL_ICMP},
+};
+
+const HEADER_LEN: usize = 8;
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+struct Socket {
+    client: KernelReference,
+    /// When each outstanding request was sent, by sequence number
+    sent: BTreeMap<u16, u64>,
+}
+
+/// Ping sockets by the identifier in their echo requests
+static SOCKETS: Mutex<BTreeMap<u16, Socket>> = Mutex::new(BTreeMap::new());
+
+fn send_echo(dst: IPAddr, kind: u8, id: u16, seq: u16, data: &[u8]) {
+    let mut msg = Vec::with_capacity(HEADER_LEN + data.len());
+    msg.extend_from_slice(&[kind, 0, 0, 0]); // type, code and checksum
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend_from_slice(data);
+    let sum = checksum(&msg, 0);
+    msg[2..4].copy_from_slice(&sum.to_be_bytes());
+    ipv4::send(dst, PROTOCOL_ICMP, &msg);
+}
+
+pub fn handle_message(src: IPAddr, ttl: u8, data: &[u8]) {
+    if data.len() < HEADER_LEN || checksum(data, 0) != 0 {
+        trace!("Dropping bad icmp message from {src}");
+        return;
+    }
+    let id = u16::from_be_bytes([data[4], data[5]]);
+    let seq = u16::from_be_bytes([data[6], data[7]]);
+    let payload = &data[HEADER_LEN..];
+
+    match data[0] {
+        TYPE_ECHO_REQUEST => send_echo(src, TYPE_ECHO_REPLY, id, seq, payload),
+        TYPE_ECHO_REPLY => deliver_reply(src, ttl, id, seq, payload),
+        t => trace!("Unhandled icmp type {t} from {src}"),
+    }
+}
+
+/// Passes an echo reply on to the ping socket that sent the request
+pub fn deliver_reply(src: IPAddr, ttl: u8, id: u16, seq: u16, data: &[u8]) {
+    let mut sockets = SOCKETS.lock();
+    let Some(socket) = sockets.get_mut(&id) else {
+        return;
+    };
+    let Some(sent) = socket.sent.remove(&seq) else {
+        return;
+    };
+
+    let mut buffer = Vec::new();
+    serialize(
+        &EchoReply {
+            src,
+            seq,
+            ttl,
+            rtt_us: uptime_us() - sent,
+            data,
+        },
+        &mut buffer,
+    );
+    channel_write_rs(socket.client.id(), &buffer, &[]);
+}
+
+/// Opens a ping socket for client, returning its echo identifier
+pub fn open(client: KernelReference) -> Result<u16, NetError> {
+    let mut sockets = SOCKETS.lock();
+    let id = (0..16)
+        .map(|_| random_below(u16::MAX as u64) as u16)
+        .find(|id| !sockets.contains_key(id))
+        .ok_or(NetError::NoFreePorts)?;
+    sockets.insert(
+        id,
+        Socket {
+            client: client.clone(),
+            sent: BTreeMap::new(),
+        },
+    );
+    drop(sockets);
+
+    spawn_thread(move || socket_task(id, client));
+    Ok(id)
+}
+
+/// Sends echo requests for the owner until it closes the channel
+fn socket_task(id: u16, client: KernelReference) {
+    let mut buffer = Vec::with_capacity(MAX_UDP_PAYLOAD + 64);
+    loop {
+        match channel_read_resize(client.id(), &mut buffer, &mut Vec::new()) {
+            ChannelReadResult::Ok => (),
+            ChannelReadResult::Closed => break,
+            e => {
+                warn!("{e:?}");
+                break;
+            }
+        }
+        match deserialize::<EchoRequest>(&buffer) {
+            Ok(req) if req.data.len() <= MAX_UDP_PAYLOAD => {
+                if let Some(socket) = SOCKETS.lock().get_mut(&id) {
+                    socket.sent.insert(req.seq, uptime_us());
+                }
+                match req.dst {
+                    IPAddr::V4(..) => send_echo(req.dst, TYPE_ECHO_REQUEST, id, req.seq, req.data),
+                    IPAddr::V6(_) => icmpv6::send_echo_request(req.dst, id, req.seq, req.data),
+                }
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Bad echo request: {e:?}"),
+        }
+    }
+    SOCKETS.lock().remove(&id);
+}
@@ -0,0 +1,67 @@
+//! Internet control message protocol for ipv6, RFC 4443. Echoes are handled here, neighbour
+//! discovery messages go to [`super::ndp`].
+
+use alloc::vec::Vec;
+use kernel_userspace::net::IPAddr;
+
+use super::{
+    icmp,
+    interface::Interface,
+    ip::{self, checksum, pseudo_header_sum},
+    ipv6::{self, NEXT_HEADER_ICMPV6},
+    ndp,
+};
+
+const HEADER_LEN: usize = 8;
+const TYPE_ECHO_REQUEST: u8 = 128;
+const TYPE_ECHO_REPLY: u8 = 129;
+
+/// Fills in the checksum of a message going from src to dst
+pub fn set_checksum(src: IPAddr, dst: IPAddr, msg: &mut [u8]) {
+    msg[2..4].fill(0);
+    let sum = checksum(
+        msg,
+        pseudo_header_sum(src, dst, NEXT_HEADER_ICMPV6, msg.len()),
+    );
+    msg[2..4].copy_from_slice(&sum.to_be_bytes());
+}
+
+fn send_echo(dst: IPAddr, kind: u8, id: u16, seq: u16, data: &[u8]) {
+    let mut msg = Vec::with_capacity(HEADER_LEN + data.len());
+    msg.extend_from_slice(&[kind, 0, 0, 0]); // type, code and checksum
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&seq.to_be_bytes());
+    msg.extend_from_slice(data);
+    set_checksum(ip::source(dst), dst, &mut msg);
+    ipv6::send(dst, NEXT_HEADER_ICMPV6, &msg);
+}
+
+pub fn send_echo_request(dst: IPAddr, id: u16, seq: u16, data: &[u8]) {
+    send_echo(dst, TYPE_ECHO_REQUEST, id, seq, data);
+}
+
+pub fn handle_message(iface: &Interface, src: IPAddr, dst: IPAddr, hop_limit: u8, data: &[u8]) {
+    if data.len() < HEADER_LEN
+        || checksum(
+            data,
+            pseudo_header_sum(src, dst, NEXT_HEADER_ICMPV6, data.len()),
+        ) != 0
+    {
+        trace!("Dropping bad icmpv6 message from {src}");
+        return;
+    }
+    let id = u16::from_be_bytes([data[4], data[5]]);
+    let seq = u16::from_be_bytes([data[6], data[7]]);
+
+    match data[0] {
+        // Requests sent to a multicast group are left alone
+        TYPE_ECHO_REQUEST if !ipv6::is_multicast(dst) => {
+            send_echo(src, TYPE_ECHO_REPLY, id, seq, &data[HEADER_LEN..])
+        }
+        TYPE_ECHO_REPLY => icmp::deliver_reply(src, hop_limit, id, seq, &data[HEADER_LEN..]),
+        ndp::TYPE_ROUTER_SOLICITATION..=ndp::TYPE_NEIGHBOUR_ADVERTISEMENT => {
+            ndp::handle_message(iface, src, hop_limit, data)
+        }
+        t => trace!("Unhandled icmpv6 type {t} from {src}"),
+    }
+}
@@ -0,0 +1,433 @@
+//! The network interfaces. Every network card driver registers with the `INTERFACE` service
+//! and becomes an interface, named `eth0`, `eth1` and so on in the order they came in. Each
+//! has its own addressing: ipv4 is set by a dhcp client of its own, by [`super::config`] or
+//! by hand, ipv6 is worked out by [`super::ndp`]. Which interface a packet goes out of is up
+//! to [`super::route`].
+
+use core::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use kernel_userspace::{
+    channel::{channel_create_rs, channel_read_resize, channel_write_rs, ChannelReadResult},
+    memory::MemoryHandle,
+    net::{
+        ring::{PacketRing, SLOT_SIZE},
+        CaptureDirection, IPAddr, InterfaceConfig, InterfaceInfo, InterfaceMessage, Ipv6Config,
+        NetError, NicCapabilities, PhysicalNet, TxOffload,
+    },
+    object::KernelReference,
+    service::{deserialize, serialize, Service, SimpleService},
+    syscall::{spawn_thread, yield_now},
+};
+
+use crate::mutex::{Mutex, Spinlock};
+
+use super::{
+    arp, capture,
+    config::{self, Addressing},
+    dhcp,
+    ethernet::{self, monitor_packets},
+    ipv6::{self, is_link_local, is_multicast, solicited_node},
+    mdns, ndp, route,
+};
+
+/// The driver's side of a network card
+struct Nic {
+    service: SimpleService,
+    buffer: Vec<u8>,
+    /// Where frames go for cards that share rings
+    tx_ring: Option<PacketRing>,
+}
+
+impl Nic {
+    /// Tells the driver to look at the rings, false if it has gone away
+    fn kick(&mut self) -> bool {
+        serialize(&PhysicalNet::RingKick, &mut self.buffer);
+        self.service
+            .call(&mut self.buffer, &mut Vec::new())
+            .is_some()
+    }
+}
+
+pub struct Interface {
+    pub id: u32,
+    pub name: String,
+    /// What the driver called itself
+    pub driver: String,
+    pub mac: u64,
+    pub caps: NicCapabilities,
+    /// The most a frame can carry after its ethernet header
+    pub mtu: usize,
+    nic: Mutex<Nic>,
+    /// Set once the driver goes away, which tells the interface's tasks to stop
+    removed: AtomicBool,
+    config: Spinlock<InterfaceConfig>,
+    config_v6: Spinlock<Ipv6Config>,
+    /// The address we are checking nobody else on the link is using
+    tentative: Spinlock<Option<IPAddr>>,
+}
+
+static INTERFACES: Mutex<Vec<Arc<Interface>>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+pub fn get(id: u32) -> Option<Arc<Interface>> {
+    INTERFACES.lock().iter().find(|i| i.id == id).cloned()
+}
+
+/// Every interface, oldest first
+pub fn all() -> Vec<Arc<Interface>> {
+    INTERFACES.lock().clone()
+}
+
+fn as_u32(ip: IPAddr) -> u32 {
+    u32::from_be_bytes(ip.octets())
+}
+
+fn same_prefix(a: IPAddr, b: IPAddr) -> bool {
+    match (a, b) {
+        (IPAddr::V6(a), IPAddr::V6(b)) => a[..8] == b[..8],
+        _ => false,
+    }
+}
+
+impl Interface {
+    pub fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+
+    /// Hands the frame made of the fragments to the driver, which does what the offload asks.
+    /// Cards that can't do anything for us get the frame in one piece, in their ring if they
+    /// share one. Frames for a card that has gone away are dropped.
+    pub fn transmit(&self, fragments: &[&[u8]], offload: TxOffload) {
+        capture::frame(self, CaptureDirection::Sent, fragments);
+        let mut nic = self.nic.lock();
+        if nic.tx_ring.is_some() {
+            self.transmit_ring(&mut nic, fragments);
+            return;
+        }
+        let Nic {
+            service, buffer, ..
+        } = &mut *nic;
+        if self.caps.any() {
+            serialize(
+                &PhysicalNet::SendFragments(fragments.to_vec(), offload),
+                buffer,
+            );
+        } else {
+            serialize(&PhysicalNet::SendPacket(&fragments.concat()), buffer);
+        }
+        if service.call(buffer, &mut Vec::new()).is_none() {
+            trace!("Dropping frame as {} has gone away", self.name);
+        }
+    }
+
+    fn transmit_ring(&self, nic: &mut Nic, fragments: &[&[u8]]) {
+        let len = fragments.iter().map(|f| f.len()).sum();
+        if len > SLOT_SIZE {
+            trace!("Dropping frame of {len} bytes too big for {}", self.name);
+            return;
+        }
+        loop {
+            let ring = nic.tx_ring.as_mut().unwrap();
+            if let Some(slot) = ring.next_buffer() {
+                let mut pos = 0;
+                for f in fragments {
+                    slot[pos..pos + f.len()].copy_from_slice(f);
+                    pos += f.len();
+                }
+                ring.push(len);
+                break;
+            }
+            // Wait for the card to hand back slots it has sent
+            if !nic.kick() {
+                trace!("Dropping frame as {} has gone away", self.name);
+                return;
+            }
+            yield_now();
+        }
+        if !nic.kick() {
+            trace!("Dropping frame as {} has gone away", self.name);
+        }
+    }
+
+    /// Tells a card that shares rings we have made room in its receive ring
+    pub fn ring_kick(&self) {
+        self.nic.lock().kick();
+    }
+
+    pub fn config(&self) -> InterfaceConfig {
+        self.config.lock().clone()
+    }
+
+    /// Our ipv4 address, [`IPAddr::UNSPECIFIED`] until we are configured
+    pub fn addr(&self) -> IPAddr {
+        self.config.lock().addr
+    }
+
+    /// Whether an ipv4 packet sent to dst is for us. Anything goes while we don't have an
+    /// address, as that is how dhcp offers reach us.
+    pub fn accepts(&self, dst: IPAddr) -> bool {
+        let config = self.config.lock();
+        config.addr == IPAddr::UNSPECIFIED
+            || dst == config.addr
+            || dst == IPAddr::BROADCAST
+            || dst == mdns::GROUP_V4
+            || as_u32(dst) == as_u32(config.addr) | !config.netmask
+    }
+
+    pub fn set_config(&self, config: InterfaceConfig) {
+        info!(
+            "{} is now {} netmask {:#X} gateway {:?} dns {:?}",
+            self.name, config.addr, config.netmask, config.gateway, config.dns
+        );
+        route::update_v4(self.id, &config);
+        *self.config.lock() = config;
+        arp::clear(self.id);
+        arp::announce(self);
+    }
+
+    pub fn config_v6(&self) -> Ipv6Config {
+        self.config_v6.lock().clone()
+    }
+
+    pub fn set_config_v6(&self, config: Ipv6Config) {
+        info!(
+            "{} is now {:?} {:?} router {:?}",
+            self.name, config.link_local, config.global, config.router
+        );
+        route::update_v6(self.id, &config);
+        *self.config_v6.lock() = config;
+    }
+
+    pub fn tentative(&self) -> Option<IPAddr> {
+        *self.tentative.lock()
+    }
+
+    pub fn set_tentative(&self, ip: Option<IPAddr>) {
+        *self.tentative.lock() = ip;
+    }
+
+    /// Whether ip is one of our ipv6 addresses
+    pub fn is_ours_v6(&self, ip: IPAddr) -> bool {
+        let config = self.config_v6.lock();
+        config.link_local == Some(ip) || config.global.contains(&ip)
+    }
+
+    /// Whether an ipv6 packet sent to dst is for us
+    pub fn accepts_v6(&self, dst: IPAddr) -> bool {
+        let config = self.config_v6.lock();
+        let tentative = self.tentative.lock();
+        let mut ours = config
+            .link_local
+            .iter()
+            .chain(&config.global)
+            .chain(tentative.iter());
+        dst == ipv6::ALL_NODES
+            || dst == mdns::GROUP_V6
+            || ours.any(|a| *a == dst || solicited_node(*a) == dst)
+    }
+
+    /// The address packets to dst leave this interface from, None if we don't have one yet.
+    /// For ipv6 that is the link local address on the link, otherwise the global address in
+    /// the same /64 if there is one.
+    pub fn source(&self, dst: IPAddr) -> Option<IPAddr> {
+        match dst {
+            IPAddr::V4(..) => Some(self.addr()).filter(|a| *a != IPAddr::UNSPECIFIED),
+            IPAddr::V6(_) => {
+                let config = self.config_v6.lock();
+                if is_link_local(dst) || is_multicast(dst) {
+                    return config.link_local;
+                }
+                config
+                    .global
+                    .iter()
+                    .find(|a| same_prefix(**a, dst))
+                    .or(config.global.first())
+                    .copied()
+            }
+        }
+    }
+
+    fn info(&self) -> InterfaceInfo {
+        InterfaceInfo {
+            id: self.id,
+            name: self.name.clone(),
+            driver: self.driver.clone(),
+            mac: self.mac,
+            caps: self.caps,
+            mtu: self.mtu as u32,
+            config: self.config(),
+            v6: self.config_v6(),
+        }
+    }
+}
+
+/// Sets up a card that has just registered. The driver only starts answering once it has
+/// been told its id, so this can't happen while the service is answering it.
+fn register(id: u32, driver: String, nic: KernelReference) {
+    let mut service = SimpleService::new(nic);
+    let mut buffer = Vec::with_capacity(100);
+
+    serialize(&PhysicalNet::MacAddrGet, &mut buffer);
+    if service.call(&mut buffer, &mut Vec::new()).is_none() {
+        warn!("{driver} went away while registering");
+        return;
+    }
+    let mac: u64 = deserialize(&buffer).unwrap();
+
+    serialize(&PhysicalNet::CapabilitiesGet, &mut buffer);
+    if service.call(&mut buffer, &mut Vec::new()).is_none() {
+        warn!("{driver} went away while registering");
+        return;
+    }
+    let caps: NicCapabilities = deserialize(&buffer).unwrap();
+
+    serialize(&PhysicalNet::RingsGet, &mut buffer);
+    let mut handles = Vec::new();
+    if service.call(&mut buffer, &mut handles).is_none() {
+        warn!("{driver} went away while registering");
+        return;
+    }
+    let (rx_ring, tx_ring) = if deserialize(&buffer).unwrap() {
+        let mut rings = handles
+            .iter()
+            .map(|h| PacketRing::map(&MemoryHandle::from_kref(KernelReference::from_id(*h))));
+        match (rings.next().flatten(), rings.next().flatten()) {
+            (Some(rx), Some(tx)) => (Some(rx), Some(tx)),
+            _ => {
+                warn!("{driver} shared bad rings");
+                return;
+            }
+        }
+    } else {
+        (None, None)
+    };
+
+    let (listen_chan, listen_chan_right) = channel_create_rs();
+    serialize(&PhysicalNet::ListenToPackets, &mut buffer);
+    let mut handles = Vec::new();
+    handles.push(listen_chan_right.id());
+    if service.call(&mut buffer, &mut handles).is_none() {
+        warn!("{driver} went away while registering");
+        return;
+    }
+
+    let iface = Arc::new(Interface {
+        id,
+        name: format!("eth{id}"),
+        driver,
+        mac,
+        caps,
+        mtu: ethernet::MTU,
+        nic: Mutex::new(Nic {
+            service,
+            buffer: Vec::new(),
+            tx_ring,
+        }),
+        removed: AtomicBool::new(false),
+        config: Spinlock::new(InterfaceConfig::UNCONFIGURED),
+        config_v6: Spinlock::new(Ipv6Config::UNCONFIGURED),
+        tentative: Spinlock::new(None),
+    });
+    info!(
+        "{} is {} at {mac:#X} with {caps:?}",
+        iface.name, iface.driver
+    );
+    INTERFACES.lock().push(iface.clone());
+
+    spawn_thread({
+        let iface = iface.clone();
+        move || monitor_packets(iface, listen_chan, rx_ring)
+    });
+    match config::addressing(&iface.name) {
+        Addressing::Static(config) => iface.set_config(config),
+        Addressing::Dhcp => {
+            spawn_thread({
+                let iface = iface.clone();
+                move || dhcp::dhcp_task(iface)
+            });
+        }
+    }
+    spawn_thread(move || ndp::slaac_task(iface));
+}
+
+/// Forgets an interface whose driver has gone away
+pub fn remove(iface: &Interface) {
+    info!("{} has gone away", iface.name);
+    iface.removed.store(true, Ordering::Relaxed);
+    INTERFACES.lock().retain(|i| i.id != iface.id);
+    route::remove_interface(iface.id);
+    arp::forget_interface(iface.id);
+}
+
+pub fn interface_service() {
+    let mut buffer = Vec::with_capacity(100);
+    let mut handles = Vec::new();
+
+    Service::new(
+        "INTERFACE",
+        || (),
+        |handle, ()| {
+            match channel_read_resize(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Closed => return ControlFlow::Break(()),
+                e => {
+                    warn!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+
+            match deserialize(&buffer) {
+                Ok(InterfaceMessage::List) => {
+                    let list: Vec<_> = all().iter().map(|i| i.info()).collect();
+                    serialize(&list, &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(InterfaceMessage::Set(id, config)) => {
+                    let result = match get(id) {
+                        Some(iface) => {
+                            iface.set_config(config);
+                            Ok(())
+                        }
+                        None => Err(NetError::NoSuchInterface),
+                    };
+                    serialize(&result, &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(InterfaceMessage::Register(driver)) => {
+                    let [nic] = handles[..] else {
+                        warn!("{driver} registered without a channel");
+                        return ControlFlow::Break(());
+                    };
+                    let nic = KernelReference::from_id(nic);
+                    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                    spawn_thread(move || register(id, driver, nic));
+                    serialize(&id, &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(InterfaceMessage::Routes) => {
+                    serialize(&route::routes(), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(InterfaceMessage::AddRoute(r)) => {
+                    serialize(&route::add(r), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(InterfaceMessage::DeleteRoute(r)) => {
+                    serialize(&route::delete(&r), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Err(e) => {
+                    warn!("Bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
@@ -0,0 +1,105 @@
+//! What ipv4 and ipv6 have in common, so tcp and udp don't need to care which they run over
+
+use kernel_userspace::net::{IPAddr, TxOffload};
+
+use super::{
+    ipv4, ipv6,
+    route::{self, NextHop},
+};
+
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// Adds data onto a partial sum. Every piece but the last has to be an even length.
+pub fn add_to_sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for w in &mut words {
+        sum += u16::from_be_bytes([w[0], w[1]]) as u32;
+    }
+    if let [b] = words.remainder() {
+        sum += (*b as u32) << 8;
+    }
+    sum
+}
+
+/// Folds a partial sum down to 16 bits, without inverting it
+pub fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The internet checksum of data, starting from the partial sum
+pub fn checksum(data: &[u8], sum: u32) -> u16 {
+    !fold(add_to_sum(data, sum))
+}
+
+/// The partial sum of the pseudo header that tcp, udp and icmpv6 checksums cover
+pub fn pseudo_header_sum(src: IPAddr, dst: IPAddr, protocol: u8, len: usize) -> u32 {
+    let words = |ip: IPAddr| -> u32 {
+        let sum = |bytes: &[u8]| {
+            bytes
+                .chunks_exact(2)
+                .map(|w| u16::from_be_bytes([w[0], w[1]]) as u32)
+                .sum::<u32>()
+        };
+        match ip {
+            IPAddr::V4(..) => sum(&ip.octets()),
+            IPAddr::V6(bytes) => sum(&bytes),
+        }
+    };
+    words(src) + words(dst) + protocol as u32 + len as u32
+}
+
+/// The address packets to dst are sent from, unspecified if there is no route to it
+pub fn source(dst: IPAddr) -> IPAddr {
+    match route::resolve(dst) {
+        Some(next) => next.src,
+        None if dst.is_ipv6() => IPAddr::UNSPECIFIED_V6,
+        None => IPAddr::UNSPECIFIED,
+    }
+}
+
+/// The most a packet to dst can carry without being fragmented
+pub fn max_payload(dst: IPAddr) -> usize {
+    match dst {
+        IPAddr::V4(..) => ipv4::MTU - ipv4::HEADER_LEN,
+        IPAddr::V6(_) => ipv6::MTU - ipv6::HEADER_LEN,
+    }
+}
+
+/// Sends a packet whose payload is the fragments one after the other along the hop already
+/// picked for it, leaving what the offload asks for (with offsets from the start of the
+/// payload) to the card
+pub fn send_fragments(
+    next: &NextHop,
+    dst: IPAddr,
+    protocol: u8,
+    payload: &[&[u8]],
+    offload: TxOffload,
+) {
+    let iface = &next.interface;
+    match dst {
+        IPAddr::V4(..) => {
+            ipv4::transmit(iface, next.src, dst, next.addr, protocol, payload, offload)
+        }
+        IPAddr::V6(_) => ipv6::transmit(
+            iface,
+            next.src,
+            dst,
+            next.addr,
+            protocol,
+            ipv6::DEFAULT_HOP_LIMIT,
+            payload,
+            offload,
+        ),
+    }
+}
+
+pub fn send(dst: IPAddr, protocol: u8, payload: &[u8]) {
+    match dst {
+        IPAddr::V4(..) => ipv4::send(dst, protocol, payload),
+        IPAddr::V6(_) => ipv6::send(dst, protocol, payload),
+    }
+}
@@ -0,0 +1,311 @@
+//! Internet protocol version 4. Options are ignored. Fragments are put back together before
+//! anything else sees them, and packets too big for the interface are sent in fragments.
+//! Packets that fit are sent with don't fragment set.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use kernel_userspace::net::{firewall::FirewallDirection, IPAddr, TxOffload};
+
+use crate::{mutex::Mutex, time::uptime};
+
+use super::{
+    arp, firewall, icmp,
+    interface::Interface,
+    ip::{checksum, PROTOCOL_TCP, PROTOCOL_UDP},
+    route, tcp, udp,
+};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+
+pub const HEADER_LEN: usize = 20;
+pub const MTU: usize = 1500;
+const DEFAULT_TTL: u8 = 64;
+const DONT_FRAGMENT: u16 = 1 << 14;
+const MORE_FRAGMENTS: u16 = 1 << 13;
+/// The fragment offset, in 8 byte units
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+const MAX_PACKET: usize = 65535;
+
+/// How long the fragments of a packet wait for the rest, in ms
+const REASSEMBLY_TIMEOUT: u64 = 30 * 1000;
+/// How many packets can be being put back together at once, the oldest is given up on to
+/// make room
+const MAX_REASSEMBLIES: usize = 16;
+
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+
+/// A packet whose fragments are arriving
+struct Reassembly {
+    data: Vec<u8>,
+    /// The ranges of data that have arrived, sorted and merged so that repeated or overlapping
+    /// fragments take no more room
+    received: Vec<(usize, usize)>,
+    /// How long the packet is, known once the last fragment has arrived
+    len: Option<usize>,
+    /// Uptime in ms when the packet is given up on
+    expires: u64,
+}
+
+impl Reassembly {
+    /// Adds the range, merging it with the ones it overlaps or touches
+    fn add_received(&mut self, start: usize, end: usize) {
+        let first = self.received.partition_point(|r| r.1 < start);
+        let last = self.received.partition_point(|r| r.0 <= end);
+        let merged = self.received[first..last]
+            .iter()
+            .fold((start, end), |(s, e), r| (s.min(r.0), e.max(r.1)));
+        self.received.splice(first..last, [merged]);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.len.is_some_and(|len| {
+            self.received
+                .first()
+                .is_some_and(|r| r.0 == 0 && r.1 >= len)
+        })
+    }
+}
+
+/// Packets being put back together by source, destination, protocol and identification
+static REASSEMBLIES: Mutex<BTreeMap<(IPAddr, IPAddr, u8, u16), Reassembly>> =
+    Mutex::new(BTreeMap::new());
+
+/// Keeps the fragment, giving back the whole payload once every fragment has arrived
+fn reassemble(key: (IPAddr, IPAddr, u8, u16), flags: u16, fragment: &[u8]) -> Option<Vec<u8>> {
+    let start = (flags & FRAGMENT_OFFSET) as usize * 8;
+    let end = start + fragment.len();
+    let last = flags & MORE_FRAGMENTS == 0;
+    // Every fragment but the last carries a multiple of 8 bytes
+    if end > MAX_PACKET || (!last && fragment.len() & 7 != 0) {
+        trace!("Dropping malformed ipv4 fragment from {}", key.0);
+        return None;
+    }
+
+    let now = uptime();
+    let mut reassemblies = REASSEMBLIES.lock();
+    reassemblies.retain(|k, r| {
+        let keep = r.expires > now;
+        if !keep {
+            trace!("Gave up putting together a packet from {}", k.0);
+        }
+        keep
+    });
+    if !reassemblies.contains_key(&key) && reassemblies.len() >= MAX_REASSEMBLIES {
+        let oldest = reassemblies
+            .iter()
+            .min_by_key(|(_, r)| r.expires)
+            .map(|(k, _)| *k)?;
+        reassemblies.remove(&oldest);
+    }
+
+    let r = reassemblies.entry(key).or_insert_with(|| Reassembly {
+        data: Vec::new(),
+        received: Vec::new(),
+        len: None,
+        expires: now + REASSEMBLY_TIMEOUT,
+    });
+    if last {
+        r.len = Some(end);
+    }
+    if r.len.is_some_and(|len| end > len) {
+        trace!("Dropping packet with fragments past its end from {}", key.0);
+        reassemblies.remove(&key);
+        return None;
+    }
+    if r.data.len() < end {
+        r.data.resize(end, 0);
+    }
+    r.data[start..end].copy_from_slice(fragment);
+    r.add_received(start, end);
+
+    if !r.is_complete() {
+        return None;
+    }
+    let mut r = reassemblies.remove(&key).unwrap();
+    r.data.truncate(r.len.unwrap());
+    Some(r.data)
+}
+
+/// Whether ip is in 224.0.0.0/4
+pub fn is_multicast(ip: IPAddr) -> bool {
+    matches!(ip, IPAddr::V4(224..=239, ..))
+}
+
+pub fn handle_packet(iface: &Interface, data: &[u8]) {
+    if data.len() < HEADER_LEN || data[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (data[0] & 0xF) as usize * 4;
+    let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    if header_len < HEADER_LEN || total_len < header_len || total_len > data.len() {
+        trace!("Dropping malformed ipv4 packet");
+        return;
+    }
+    if checksum(&data[..header_len], 0) != 0 {
+        trace!("Dropping ipv4 packet with a bad checksum");
+        return;
+    }
+
+    let src = IPAddr::V4(data[12], data[13], data[14], data[15]);
+    let dst = IPAddr::V4(data[16], data[17], data[18], data[19]);
+    if !iface.accepts(dst) {
+        return;
+    }
+
+    let protocol = data[9];
+    let mut payload = &data[header_len..total_len];
+    let flags = u16::from_be_bytes([data[6], data[7]]);
+    let reassembled;
+    if flags & (MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        match reassemble((src, dst, protocol, id), flags, payload) {
+            Some(p) => reassembled = p,
+            None => return,
+        }
+        payload = &reassembled;
+    }
+
+    if !firewall::allows(FirewallDirection::In, iface.id, src, protocol, &[payload]) {
+        trace!("Firewall dropped an ipv4 packet from {src}");
+        return;
+    }
+    match protocol {
+        PROTOCOL_ICMP => icmp::handle_message(src, data[8], payload),
+        PROTOCOL_TCP => tcp::handle_segment(src, dst, payload),
+        PROTOCOL_UDP => udp::handle_datagram(iface, src, dst, payload),
+        p => trace!("Unhandled ipv4 protocol {p}"),
+    }
+}
+
+/// Sends a packet from us, out of the interface and through the gateway the routing table
+/// picks
+pub fn send(dst: IPAddr, protocol: u8, payload: &[u8]) {
+    let Some(next) = route::resolve(dst) else {
+        trace!("Dropping ipv4 packet as there is no route to {dst}");
+        return;
+    };
+    send_on(&next.interface, next.src, dst, next.addr, protocol, payload);
+}
+
+/// Sends a packet out of a particular interface, as dhcp needs before the interface has an
+/// address
+pub fn send_on(
+    iface: &Interface,
+    src: IPAddr,
+    dst: IPAddr,
+    next_hop: IPAddr,
+    protocol: u8,
+    payload: &[u8],
+) {
+    transmit(
+        iface,
+        src,
+        dst,
+        next_hop,
+        protocol,
+        &[payload],
+        TxOffload::default(),
+    );
+}
+
+/// Sends a packet whose payload is the fragments one after the other, leaving what the
+/// offload asks for (with offsets from the start of the payload) to the card. The header
+/// checksum is left to the card too if it can.
+pub fn transmit(
+    iface: &Interface,
+    src: IPAddr,
+    dst: IPAddr,
+    next_hop: IPAddr,
+    protocol: u8,
+    payload: &[&[u8]],
+    offload: TxOffload,
+) {
+    if !firewall::allows(FirewallDirection::Out, iface.id, dst, protocol, payload) {
+        trace!("Firewall dropped an ipv4 packet to {dst}");
+        return;
+    }
+    let len: usize = payload.iter().map(|f| f.len()).sum();
+    let id = IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    // The card splits up what it segments itself
+    if HEADER_LEN + len <= iface.mtu || offload.tso_mss.is_some() {
+        let flags = DONT_FRAGMENT;
+        send_packet(
+            iface, src, dst, next_hop, protocol, id, flags, payload, offload,
+        );
+        return;
+    }
+    if HEADER_LEN + len > MAX_PACKET {
+        trace!("Dropping ipv4 packet that is too big to send");
+        return;
+    }
+
+    // The card would only see the first fragment, so finish off its checksum here
+    let mut data = payload.concat();
+    if let Some(field) = offload.l4_checksum {
+        let l4 = offload.l4_start as usize;
+        let sum = checksum(&data[l4..], 0);
+        data[l4 + field as usize..][..2].copy_from_slice(&sum.to_be_bytes());
+    }
+    let step = (iface.mtu - HEADER_LEN) & !7;
+    for (i, fragment) in data.chunks(step).enumerate() {
+        let offset = (i * step / 8) as u16;
+        let more = if (i + 1) * step < data.len() {
+            MORE_FRAGMENTS
+        } else {
+            0
+        };
+        let flags = more | offset;
+        let offload = TxOffload::default();
+        send_packet(
+            iface,
+            src,
+            dst,
+            next_hop,
+            protocol,
+            id,
+            flags,
+            &[fragment],
+            offload,
+        );
+    }
+}
+
+/// Puts the header on a packet, or a fragment of one, and sends it
+#[allow(clippy::too_many_arguments)]
+fn send_packet(
+    iface: &Interface,
+    src: IPAddr,
+    dst: IPAddr,
+    next_hop: IPAddr,
+    protocol: u8,
+    id: u16,
+    flags: u16,
+    payload: &[&[u8]],
+    mut offload: TxOffload,
+) {
+    let len: usize = payload.iter().map(|f| f.len()).sum();
+    let mut packet = Vec::with_capacity(HEADER_LEN);
+    packet.push(0x45); // version 4 with no options
+    packet.push(0); // type of service
+    packet.extend_from_slice(&((HEADER_LEN + len) as u16).to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&flags.to_be_bytes());
+    packet.push(DEFAULT_TTL);
+    packet.push(protocol);
+    packet.extend_from_slice(&[0, 0]); // checksum
+    packet.extend_from_slice(&src.octets());
+    packet.extend_from_slice(&dst.octets());
+    if iface.caps.ipv4_checksum {
+        offload.ipv4_checksum = true;
+    } else {
+        let sum = checksum(&packet, 0);
+        packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    }
+    offload.l4_start += HEADER_LEN as u16;
+
+    let mut fragments = Vec::with_capacity(payload.len() + 1);
+    fragments.push(&packet[..]);
+    fragments.extend_from_slice(payload);
+    arp::send_ip(iface, next_hop, &fragments, offload);
+}
@@ -0,0 +1,162 @@
+//! Internet protocol version 6. Extension headers aren't supported and packets carrying them
+//! are dropped. We never fragment, so anything bigger than the link mtu is dropped as well.
+
+use alloc::vec::Vec;
+use kernel_userspace::net::{firewall::FirewallDirection, IPAddr, TxOffload};
+
+use super::{
+    arp,
+    ethernet::{send_frame_fragments, ETHER_TYPE_IPV6},
+    firewall, icmpv6,
+    interface::Interface,
+    ip::{PROTOCOL_TCP, PROTOCOL_UDP},
+    route, tcp, udp,
+};
+
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+
+pub const HEADER_LEN: usize = 40;
+pub const MTU: usize = 1500;
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+
+pub const ALL_NODES: IPAddr = IPAddr::V6([0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+pub const ALL_ROUTERS: IPAddr = IPAddr::V6([0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
+pub fn is_multicast(ip: IPAddr) -> bool {
+    matches!(ip, IPAddr::V6([0xFF, ..]))
+}
+
+/// Whether ip is in fe80::/10
+pub fn is_link_local(ip: IPAddr) -> bool {
+    matches!(ip, IPAddr::V6([0xFE, b, ..]) if b & 0xC0 == 0x80)
+}
+
+/// The multicast group that neighbour solicitations for ip are sent to
+pub fn solicited_node(ip: IPAddr) -> IPAddr {
+    let IPAddr::V6(b) = ip else {
+        panic!("{ip} is not an ipv6 address")
+    };
+    IPAddr::V6([
+        0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0xFF, b[13], b[14], b[15],
+    ])
+}
+
+pub fn handle_packet(iface: &Interface, data: &[u8]) {
+    if data.len() < HEADER_LEN || data[0] >> 4 != 6 {
+        return;
+    }
+    let payload_len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    if HEADER_LEN + payload_len > data.len() {
+        trace!("Dropping malformed ipv6 packet");
+        return;
+    }
+
+    let src = IPAddr::V6(data[8..24].try_into().unwrap());
+    let dst = IPAddr::V6(data[24..40].try_into().unwrap());
+    if !iface.accepts_v6(dst) {
+        return;
+    }
+
+    let payload = &data[HEADER_LEN..HEADER_LEN + payload_len];
+    if !firewall::allows(FirewallDirection::In, iface.id, src, data[6], &[payload]) {
+        trace!("Firewall dropped an ipv6 packet from {src}");
+        return;
+    }
+    match data[6] {
+        NEXT_HEADER_ICMPV6 => icmpv6::handle_message(iface, src, dst, data[7], payload),
+        PROTOCOL_TCP => tcp::handle_segment(src, dst, payload),
+        PROTOCOL_UDP => udp::handle_datagram(iface, src, dst, payload),
+        n => trace!("Unhandled ipv6 next header {n}"),
+    }
+}
+
+/// Sends a packet from us, out of the interface and through the router the routing table
+/// picks
+pub fn send(dst: IPAddr, next_header: u8, payload: &[u8]) {
+    let Some(next) = route::resolve(dst) else {
+        trace!("Dropping ipv6 packet as there is no route to {dst}");
+        return;
+    };
+    transmit(
+        &next.interface,
+        next.src,
+        dst,
+        next.addr,
+        next_header,
+        DEFAULT_HOP_LIMIT,
+        &[payload],
+        TxOffload::default(),
+    );
+}
+
+/// Sends a packet to a neighbour on the interface's link with the source and hop limit given,
+/// as neighbour discovery needs
+pub fn send_on_link(
+    iface: &Interface,
+    src: IPAddr,
+    dst: IPAddr,
+    next_header: u8,
+    hop_limit: u8,
+    payload: &[u8],
+) {
+    transmit(
+        iface,
+        src,
+        dst,
+        dst,
+        next_header,
+        hop_limit,
+        &[payload],
+        TxOffload::default(),
+    );
+}
+
+/// Sends a packet whose payload is the fragments one after the other, leaving what the
+/// offload asks for (with offsets from the start of the payload) to the card
+#[allow(clippy::too_many_arguments)]
+pub fn transmit(
+    iface: &Interface,
+    src: IPAddr,
+    dst: IPAddr,
+    next_hop: IPAddr,
+    next_header: u8,
+    hop_limit: u8,
+    payload: &[&[u8]],
+    mut offload: TxOffload,
+) {
+    if !firewall::allows(FirewallDirection::Out, iface.id, dst, next_header, payload) {
+        trace!("Firewall dropped an ipv6 packet to {dst}");
+        return;
+    }
+    let len: usize = payload.iter().map(|f| f.len()).sum();
+    // The card splits up what it segments itself
+    if HEADER_LEN + len > MTU && offload.tso_mss.is_none() {
+        trace!("Dropping ipv6 packet that is too big for the link");
+        return;
+    }
+    let (IPAddr::V6(src), IPAddr::V6(dst_bytes)) = (src, dst) else {
+        panic!("ipv6 packet from {src} to {dst}")
+    };
+
+    let mut packet = Vec::with_capacity(HEADER_LEN);
+    packet.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, no traffic class or flow label
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    packet.push(next_header);
+    packet.push(hop_limit);
+    packet.extend_from_slice(&src);
+    packet.extend_from_slice(&dst_bytes);
+    offload.l4_start += HEADER_LEN as u16;
+
+    let mut fragments = Vec::with_capacity(payload.len() + 1);
+    fragments.push(&packet[..]);
+    fragments.extend_from_slice(payload);
+
+    if is_multicast(dst) {
+        // Multicast groups map straight onto 33:33 and the group's last four bytes
+        let b = dst_bytes;
+        let mac = u64::from_le_bytes([0x33, 0x33, b[12], b[13], b[14], b[15], 0, 0]);
+        send_frame_fragments(iface, mac, ETHER_TYPE_IPV6, &fragments, offload);
+    } else {
+        arp::send_ip(iface, next_hop, &fragments, offload);
+    }
+}
@@ -0,0 +1,739 @@
+//! Multicast dns and dns service discovery. We answer for the host name in [`super::config`]
+//! and the services programs register on every interface, announcing them whenever an interface's addresses
+//! change. Answers other hosts multicast are kept in a cache that browsing and `.local` lookups
+//! read from. We don't probe for anyone else using our names before claiming them.
+
+use core::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use alloc::{
+    collections::BTreeMap,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use kernel_userspace::{
+    channel::{channel_create_rs, channel_read_resize, channel_write_rs, ChannelReadResult},
+    net::{
+        mdns::{MdnsMessage, MdnsRegistration, MdnsService},
+        IPAddr, NetError, Resolved,
+    },
+    object::{object_wait, KernelReference, ObjectSignal},
+    service::{deserialize, serialize, Service},
+    syscall::{sleep, spawn_thread},
+};
+
+use crate::{mutex::Mutex, time::uptime};
+
+use super::{
+    config,
+    dns::{push_name, skip_name, u16_at, CLASS_IN, HEADER_LEN, TYPE_A, TYPE_AAAA},
+    interface::{self, Interface},
+    ip::PROTOCOL_UDP,
+    ipv4, ipv6, udp,
+};
+
+pub const PORT: u16 = 5353;
+pub const GROUP_V4: IPAddr = IPAddr::V4(224, 0, 0, 251);
+pub const GROUP_V6: IPAddr = IPAddr::V6([0xFF, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFB]);
+
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+/// In a question's class it asks for a unicast answer
+const CLASS_UNICAST_RESPONSE: u16 = 1 << 15;
+/// In a record's class it says the record replaces any others of its name and type
+const CLASS_CACHE_FLUSH: u16 = 1 << 15;
+const FLAG_QR: u16 = 1 << 15;
+const FLAG_AA: u16 = 1 << 10;
+
+/// How long others can keep our records, in seconds
+const TTL: u32 = 120;
+/// What browsing for service types asks for
+const SERVICES_NAME: &str = "_services._dns-sd._udp.local";
+/// How long browsing and lookups wait for answers, in ms
+const WAIT: u64 = 1000;
+const POLL: u64 = 100;
+/// How often interfaces are checked for new addresses to announce, in ms
+const ANNOUNCE_CHECK: u64 = 1000;
+const MAX_CACHE: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RecordData {
+    Addr(IPAddr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+}
+
+impl RecordData {
+    fn rtype(&self) -> u16 {
+        match self {
+            RecordData::Addr(IPAddr::V4(..)) => TYPE_A,
+            RecordData::Addr(IPAddr::V6(_)) => TYPE_AAAA,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Txt(_) => TYPE_TXT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    data: RecordData,
+    ttl: u32,
+}
+
+struct Cached {
+    record: Record,
+    /// Uptime in ms when it stops being used
+    expires: u64,
+}
+
+struct Registration {
+    id: u64,
+    /// Such as `_echo._tcp`
+    service: String,
+    port: u16,
+    txt: Vec<String>,
+}
+
+static SERVICES: Mutex<Vec<Registration>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static CACHE: Mutex<Vec<Cached>> = Mutex::new(Vec::new());
+
+/// Our name under `.local`, None if no host name is configured
+fn host() -> Option<String> {
+    config::hostname().map(|h| format!("{h}.local"))
+}
+
+/// Services can only be registered once there is a host name
+fn instance(service: &str) -> String {
+    format!("{}.{service}.local", config::hostname().unwrap_or_default())
+}
+
+fn record(name: impl ToString, data: RecordData) -> Record {
+    Record {
+        name: name.to_string(),
+        data,
+        ttl: TTL,
+    }
+}
+
+/// The records we answer for on the interface
+fn our_records(iface: &Interface) -> Vec<Record> {
+    let Some(host) = host() else {
+        return Vec::new();
+    };
+    let mut records = Vec::new();
+    let addr = iface.addr();
+    if addr != IPAddr::UNSPECIFIED {
+        records.push(record(&host, RecordData::Addr(addr)));
+    }
+    let v6 = iface.config_v6();
+    for addr in v6.link_local.iter().chain(&v6.global) {
+        records.push(record(&host, RecordData::Addr(*addr)));
+    }
+
+    for s in SERVICES.lock().iter() {
+        let service = format!("{}.local", s.service);
+        let instance = instance(&s.service);
+        let types = record(SERVICES_NAME, RecordData::Ptr(service.clone()));
+        if !records.contains(&types) {
+            records.push(types);
+        }
+        records.push(record(service, RecordData::Ptr(instance.clone())));
+        records.push(record(
+            &instance,
+            RecordData::Srv {
+                port: s.port,
+                target: host.clone(),
+            },
+        ));
+        records.push(record(instance, RecordData::Txt(s.txt.clone())));
+    }
+    records
+}
+
+/// Our addresses on every interface
+fn our_addrs() -> Vec<IPAddr> {
+    interface::all()
+        .iter()
+        .flat_map(|i| our_records(i))
+        .filter_map(|r| match r.data {
+            RecordData::Addr(addr) => Some(addr),
+            _ => None,
+        })
+        .collect()
+}
+
+fn push_record(msg: &mut Vec<u8>, record: &Record) -> Option<()> {
+    push_name(msg, &record.name)?;
+    msg.extend_from_slice(&record.data.rtype().to_be_bytes());
+    // Pointers are shared with other hosts, everything else is only ever ours
+    let class = match record.data {
+        RecordData::Ptr(_) => CLASS_IN,
+        _ => CLASS_IN | CLASS_CACHE_FLUSH,
+    };
+    msg.extend_from_slice(&class.to_be_bytes());
+    msg.extend_from_slice(&record.ttl.to_be_bytes());
+
+    let len_at = msg.len();
+    msg.extend_from_slice(&[0, 0]);
+    match &record.data {
+        RecordData::Addr(IPAddr::V4(a, b, c, d)) => msg.extend_from_slice(&[*a, *b, *c, *d]),
+        RecordData::Addr(IPAddr::V6(bytes)) => msg.extend_from_slice(bytes),
+        RecordData::Ptr(name) => push_name(msg, name)?,
+        RecordData::Srv { port, target } => {
+            msg.extend_from_slice(&[0; 4]); // priority and weight
+            msg.extend_from_slice(&port.to_be_bytes());
+            push_name(msg, target)?;
+        }
+        // There has to be at least one string, even if it is empty
+        RecordData::Txt(strings) if strings.is_empty() => msg.push(0),
+        RecordData::Txt(strings) => {
+            for s in strings {
+                let s = &s.as_bytes()[..s.len().min(255)];
+                msg.push(s.len() as u8);
+                msg.extend_from_slice(s);
+            }
+        }
+    }
+    let len = (msg.len() - len_at - 2) as u16;
+    msg[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+    Some(())
+}
+
+/// A response with the answers and additional records. Answers to legacy unicast queries
+/// have to carry the query's id and questions.
+fn response(
+    id: u16,
+    questions: (u16, &[u8]),
+    answers: &[Record],
+    additional: &[Record],
+) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(512);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&(FLAG_QR | FLAG_AA).to_be_bytes());
+    msg.extend_from_slice(&questions.0.to_be_bytes());
+    msg.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&[0, 0]); // authorities
+    msg.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+    msg.extend_from_slice(questions.1);
+    for r in answers.iter().chain(additional) {
+        // Our own names are always valid
+        push_record(&mut msg, r).unwrap();
+    }
+    msg
+}
+
+/// Sends the message to the group on the interface, over ipv6 or ipv4
+fn multicast(iface: &Interface, v6: bool, msg: &[u8]) {
+    if v6 {
+        if let Some(src) = iface.config_v6().link_local {
+            let datagram = udp::datagram(src, PORT, GROUP_V6, PORT, msg);
+            ipv6::send_on_link(iface, src, GROUP_V6, PROTOCOL_UDP, 255, &datagram);
+        }
+    } else {
+        let src = iface.addr();
+        if src != IPAddr::UNSPECIFIED {
+            let datagram = udp::datagram(src, PORT, GROUP_V4, PORT, msg);
+            ipv4::send_on(iface, src, GROUP_V4, GROUP_V4, PROTOCOL_UDP, &datagram);
+        }
+    }
+}
+
+/// Asks every interface's link the questions
+fn query(questions: &[(&str, u16)]) {
+    let mut msg = Vec::with_capacity(128);
+    msg.extend_from_slice(&[0; 4]); // id and flags
+    msg.extend_from_slice(&(questions.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&[0; 6]);
+    for (name, qtype) in questions {
+        if push_name(&mut msg, name).is_none() {
+            return;
+        }
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    for iface in interface::all() {
+        multicast(&iface, false, &msg);
+        multicast(&iface, true, &msg);
+    }
+}
+
+/// Reads the name at pos following any pointers, returning it and where it ends
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Pointers could go round in circles
+    for _ in 0..128 {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => return Some((name, end.unwrap_or(pos + 1))),
+            l if l & 0xC0 == 0xC0 => {
+                end.get_or_insert(pos + 2);
+                pos = (u16_at(msg, pos)? & 0x3FFF) as usize;
+            }
+            l => {
+                let label = msg.get(pos + 1..pos + 1 + l)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label));
+                pos += 1 + l;
+            }
+        }
+    }
+    None
+}
+
+/// Reads the record at pos, returning it with its class and where it ends. The record is None
+/// if it is a type we don't keep.
+fn read_record(msg: &[u8], pos: usize) -> Option<(Option<Record>, u16, usize)> {
+    let (name, pos) = read_name(msg, pos)?;
+    let rtype = u16_at(msg, pos)?;
+    let class = u16_at(msg, pos + 2)?;
+    let ttl = u32::from_be_bytes(msg.get(pos + 4..pos + 8)?.try_into().unwrap());
+    let len = u16_at(msg, pos + 8)? as usize;
+    let start = pos + 10;
+    let data = msg.get(start..start + len)?;
+
+    let data = match (rtype, data) {
+        (TYPE_A, &[a, b, c, d]) => Some(RecordData::Addr(IPAddr::V4(a, b, c, d))),
+        (TYPE_AAAA, d) if d.len() == 16 => {
+            Some(RecordData::Addr(IPAddr::V6(d.try_into().unwrap())))
+        }
+        (TYPE_PTR, _) => Some(RecordData::Ptr(read_name(msg, start)?.0)),
+        (TYPE_SRV, _) => Some(RecordData::Srv {
+            port: u16_at(msg, start + 4)?,
+            target: read_name(msg, start + 6)?.0,
+        }),
+        (TYPE_TXT, mut d) => {
+            let mut strings = Vec::new();
+            while let Some((&len, rest)) = d.split_first() {
+                let s = rest.get(..len as usize)?;
+                if !s.is_empty() {
+                    strings.push(String::from_utf8_lossy(s).into_owned());
+                }
+                d = &rest[len as usize..];
+            }
+            Some(RecordData::Txt(strings))
+        }
+        _ => None,
+    };
+    let record = data.map(|data| Record { name, data, ttl });
+    Some((record, class, start + len))
+}
+
+/// Keeps the records in a response from another host
+fn cache_response(msg: &[u8]) -> Option<()> {
+    let questions = u16_at(msg, 4)?;
+    let records = u16_at(msg, 6)? as usize + u16_at(msg, 8)? as usize + u16_at(msg, 10)? as usize;
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let mut new = Vec::new();
+    let mut flush = Vec::new();
+    for _ in 0..records {
+        let (record, class, end) = read_record(msg, pos)?;
+        pos = end;
+        let Some(record) = record.filter(|_| class & !CLASS_CACHE_FLUSH == CLASS_IN) else {
+            continue;
+        };
+        if class & CLASS_CACHE_FLUSH != 0 {
+            flush.push((record.name.clone(), record.data.rtype()));
+        }
+        new.push(record);
+    }
+
+    let now = uptime();
+    let mut cache = CACHE.lock();
+    cache.retain(|c| {
+        c.expires > now
+            && !flush
+                .iter()
+                .any(|(n, t)| c.record.name.eq_ignore_ascii_case(n) && c.record.data.rtype() == *t)
+    });
+    for record in new {
+        cache.retain(|c| {
+            !(c.record.name.eq_ignore_ascii_case(&record.name) && c.record.data == record.data)
+        });
+        // A ttl of 0 says the record is going away
+        if record.ttl != 0 {
+            cache.push(Cached {
+                expires: now + record.ttl as u64 * 1000,
+                record,
+            });
+        }
+    }
+    if cache.len() > MAX_CACHE {
+        let extra = cache.len() - MAX_CACHE;
+        cache.drain(..extra);
+    }
+    Some(())
+}
+
+/// Answers the questions in a query with our records
+fn answer(iface: &Interface, src: IPAddr, src_port: u16, msg: &[u8]) -> Option<()> {
+    let id = u16_at(msg, 0)?;
+    let questions = u16_at(msg, 4)?;
+    let ours = our_records(iface);
+
+    // Queries that don't come from the mdns port are from plain resolvers, which only take
+    // unicast answers
+    let legacy = src_port != PORT;
+    let mut unicast = legacy;
+    let mut answers: Vec<Record> = Vec::new();
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        let (name, end) = read_name(msg, pos)?;
+        let qtype = u16_at(msg, end)?;
+        let class = u16_at(msg, end + 2)?;
+        pos = end + 4;
+        if class & CLASS_UNICAST_RESPONSE != 0 {
+            unicast = true;
+        }
+        for r in ours.iter().filter(|r| {
+            r.name.eq_ignore_ascii_case(&name) && (qtype == TYPE_ANY || qtype == r.data.rtype())
+        }) {
+            if !answers.contains(r) {
+                answers.push(r.clone());
+            }
+        }
+    }
+    if answers.is_empty() {
+        return Some(());
+    }
+
+    // What the answers point at comes along, saving the asker another round
+    let mut additional: Vec<Record> = Vec::new();
+    for a in &answers {
+        let target = match &a.data {
+            RecordData::Ptr(target) | RecordData::Srv { target, .. } => target,
+            _ => continue,
+        };
+        for r in ours.iter().filter(|r| r.name.eq_ignore_ascii_case(target)) {
+            if !answers.contains(r) && !additional.contains(r) {
+                additional.push(r.clone());
+            }
+        }
+    }
+    if additional
+        .iter()
+        .any(|r| matches!(r.data, RecordData::Srv { .. }))
+    {
+        let host = host();
+        for r in ours.iter().filter(|r| Some(&r.name) == host.as_ref()) {
+            if !answers.contains(r) && !additional.contains(r) {
+                additional.push(r.clone());
+            }
+        }
+    }
+
+    let msg = match legacy {
+        true => response(
+            id,
+            (questions, &msg[HEADER_LEN..pos]),
+            &answers,
+            &additional,
+        ),
+        false => response(0, (0, &[]), &answers, &additional),
+    };
+    if unicast {
+        udp::send(PORT, src, src_port, &msg);
+    } else {
+        multicast(iface, src.is_ipv6(), &msg);
+    }
+    Some(())
+}
+
+/// Handles a message sent to the mdns port
+pub fn handle_message(iface: &Interface, src: IPAddr, src_port: u16, msg: &[u8]) {
+    let Some(flags) = u16_at(msg, 2) else {
+        return;
+    };
+    if flags & FLAG_QR == 0 {
+        answer(iface, src, src_port, msg);
+    } else if src_port == PORT {
+        cache_response(msg);
+    }
+}
+
+/// The records of a name and type that are still good
+fn cached(name: &str, rtype: u16) -> Vec<RecordData> {
+    let now = uptime();
+    CACHE
+        .lock()
+        .iter()
+        .filter(|c| {
+            c.expires > now
+                && c.record.data.rtype() == rtype
+                && c.record.name.eq_ignore_ascii_case(name)
+        })
+        .map(|c| c.record.data.clone())
+        .collect()
+}
+
+fn cached_addrs(host: &str) -> Vec<IPAddr> {
+    [TYPE_A, TYPE_AAAA]
+        .into_iter()
+        .flat_map(|t| cached(host, t))
+        .filter_map(|d| match d {
+            RecordData::Addr(addr) => Some(addr),
+            _ => None,
+        })
+        .collect()
+}
+
+fn cached_ptrs(name: &str) -> Vec<String> {
+    cached(name, TYPE_PTR)
+        .into_iter()
+        .filter_map(|d| match d {
+            RecordData::Ptr(target) => Some(target),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Looks up a name under `.local`
+pub fn resolve(name: &str) -> Result<Resolved, NetError> {
+    let name = name.trim_end_matches('.');
+    let found = |addrs: Vec<IPAddr>| {
+        let (v6, v4) = addrs.into_iter().partition(|a| a.is_ipv6());
+        Resolved { v4, v6 }
+    };
+    if host().is_some_and(|h| name.eq_ignore_ascii_case(&h)) {
+        return Ok(found(our_addrs()));
+    }
+
+    let addrs = cached_addrs(name);
+    if !addrs.is_empty() {
+        return Ok(found(addrs));
+    }
+    query(&[(name, TYPE_A), (name, TYPE_AAAA)]);
+    for _ in 0..WAIT / POLL {
+        sleep(POLL);
+        let addrs = cached_addrs(name);
+        if !addrs.is_empty() {
+            return Ok(found(addrs));
+        }
+    }
+    Err(NetError::HostNotFound)
+}
+
+/// Turns `_echo._tcp` into `_echo._tcp.local`, which is also fine as it is
+fn service_name(service: &str) -> String {
+    let service = service.trim_end_matches('.');
+    match service.ends_with(".local") {
+        true => service.to_string(),
+        false => format!("{service}.local"),
+    }
+}
+
+/// Finds the instances of the service on every link
+fn browse(service: &str) -> Vec<MdnsService> {
+    let service = service_name(service);
+    query(&[(&service, TYPE_PTR)]);
+    sleep(WAIT);
+
+    // Ask again for the details of the instances that didn't come with their pointer
+    let instances = cached_ptrs(&service);
+    let mut missing = Vec::new();
+    for instance in &instances {
+        match cached(instance, TYPE_SRV).first() {
+            Some(RecordData::Srv { target, .. }) if cached_addrs(target).is_empty() => {
+                missing.push((target.clone(), TYPE_A));
+                missing.push((target.clone(), TYPE_AAAA));
+            }
+            Some(_) => (),
+            None => {
+                missing.push((instance.clone(), TYPE_SRV));
+                missing.push((instance.clone(), TYPE_TXT));
+            }
+        }
+    }
+    if !missing.is_empty() {
+        let questions: Vec<_> = missing.iter().map(|(n, t)| (n.as_str(), *t)).collect();
+        query(&questions);
+        sleep(WAIT / 2);
+    }
+
+    // Our own multicasts don't come back to us
+    let mut found: Vec<MdnsService> = SERVICES
+        .lock()
+        .iter()
+        .filter(|s| service_name(&s.service).eq_ignore_ascii_case(&service))
+        .map(|s| MdnsService {
+            instance: instance(&s.service),
+            host: host().unwrap_or_default(),
+            port: s.port,
+            addrs: Vec::new(),
+            txt: s.txt.clone(),
+        })
+        .collect();
+    for s in &mut found {
+        s.addrs = our_addrs();
+    }
+    for instance in instances {
+        let Some(RecordData::Srv { port, target }) = cached(&instance, TYPE_SRV).pop() else {
+            continue;
+        };
+        let txt = match cached(&instance, TYPE_TXT).pop() {
+            Some(RecordData::Txt(txt)) => txt,
+            _ => Vec::new(),
+        };
+        found.push(MdnsService {
+            instance,
+            addrs: cached_addrs(&target),
+            host: target,
+            port,
+            txt,
+        });
+    }
+    found
+}
+
+/// The types of service on every link, without `.local`
+fn service_types() -> Vec<String> {
+    query(&[(SERVICES_NAME, TYPE_PTR)]);
+    sleep(WAIT);
+
+    let mut types: Vec<String> = SERVICES.lock().iter().map(|s| s.service.clone()).collect();
+    for t in cached_ptrs(SERVICES_NAME) {
+        let t = t.trim_end_matches(".local").to_string();
+        if !types.iter().any(|o| o.eq_ignore_ascii_case(&t)) {
+            types.push(t);
+        }
+    }
+    types
+}
+
+/// Sends every record we have on the interface, or says they are gone with a ttl of 0
+fn announce(iface: &Interface, records: &[Record]) {
+    if records.is_empty() {
+        return;
+    }
+    let msg = response(0, (0, &[]), records, &[]);
+    multicast(iface, false, &msg);
+    multicast(iface, true, &msg);
+}
+
+/// Checks the interfaces for changes to what we answer for, announcing them
+pub fn announce_task() {
+    let mut announced: BTreeMap<u32, Vec<Record>> = BTreeMap::new();
+    loop {
+        let interfaces = interface::all();
+        announced.retain(|id, _| interfaces.iter().any(|i| i.id == *id));
+        for iface in interfaces {
+            let records = our_records(&iface);
+            let old = announced.entry(iface.id).or_default();
+            if *old != records {
+                let gone: Vec<Record> = old
+                    .iter()
+                    .filter(|r| !records.contains(r))
+                    .map(|r| Record {
+                        ttl: 0,
+                        ..r.clone()
+                    })
+                    .collect();
+                announce(&iface, &gone);
+                announce(&iface, &records);
+                *old = records;
+            }
+        }
+        sleep(ANNOUNCE_CHECK);
+    }
+}
+
+/// Checks the registration is for something like `_name._tcp`
+fn valid(reg: &MdnsRegistration) -> bool {
+    let service = reg.service.trim_end_matches('.').trim_end_matches(".local");
+    let Some((name, proto)) = service.split_once('.') else {
+        return false;
+    };
+    name.len() > 1 && name.len() <= 16 && name.starts_with('_') && matches!(proto, "_tcp" | "_udp")
+}
+
+/// Advertises the service until client closes its channel
+fn register(reg: MdnsRegistration, client: KernelReference) -> Result<(), NetError> {
+    if !valid(&reg) {
+        return Err(NetError::InvalidState);
+    }
+    if config::hostname().is_none() {
+        return Err(NetError::NotConfigured);
+    }
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    SERVICES.lock().push(Registration {
+        id,
+        service: reg
+            .service
+            .trim_end_matches('.')
+            .trim_end_matches(".local")
+            .to_string(),
+        port: reg.port,
+        txt: reg.txt,
+    });
+
+    spawn_thread(move || {
+        object_wait(client.id(), ObjectSignal::CHANNEL_CLOSED);
+        SERVICES.lock().retain(|s| s.id != id);
+    });
+    Ok(())
+}
+
+pub fn mdns_service() {
+    spawn_thread(announce_task);
+    let mut buffer = Vec::with_capacity(100);
+
+    Service::new(
+        "MDNS",
+        || (),
+        |handle, ()| {
+            match channel_read_resize(handle.id(), &mut buffer, &mut Vec::new()) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Closed => return ControlFlow::Break(()),
+                e => {
+                    warn!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+
+            match deserialize(&buffer) {
+                Ok(MdnsMessage::Browse(service)) => {
+                    serialize(&browse(&service), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(MdnsMessage::ServiceTypes) => {
+                    serialize(&service_types(), &mut buffer);
+                    channel_write_rs(handle.id(), &buffer, &[]);
+                }
+                Ok(MdnsMessage::Register(reg)) => {
+                    let (ours, theirs) = channel_create_rs();
+                    match register(reg, ours) {
+                        Ok(()) => {
+                            serialize(&Ok::<_, NetError>(()), &mut buffer);
+                            channel_write_rs(handle.id(), &buffer, &[theirs.id()]);
+                        }
+                        Err(e) => {
+                            serialize(&Err::<(), _>(e), &mut buffer);
+                            channel_write_rs(handle.id(), &buffer, &[]);
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
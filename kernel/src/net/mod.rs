@@ -1,2 +1,20 @@
 pub mod arp;
+pub mod capture;
+pub mod config;
+pub mod dhcp;
+pub mod dns;
 pub mod ethernet;
+pub mod firewall;
+pub mod icmp;
+pub mod icmpv6;
+pub mod interface;
+pub mod ip;
+pub mod ipv4;
+pub mod ipv6;
+pub mod mdns;
+pub mod ndp;
+pub mod route;
+pub mod sntp;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
@@ -0,0 +1,314 @@
+//! Neighbour discovery and stateless address autoconfiguration, RFC 4861 and RFC 4862.
+//!
+//! Neighbour solicitations and advertisements fill the same neighbour cache arp does. When an
+//! interface comes up [`slaac_task`] makes a link local address from its mac, checks nobody
+//! else on the link has it and then asks for routers. Each prefix a router advertises for
+//! autoconfiguration gives the interface a global address with the same interface identifier,
+//! which lasts as long as the router says it does.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use kernel_userspace::{
+    net::{IPAddr, Ipv6Config},
+    syscall::sleep,
+};
+
+use crate::{mutex::Mutex, time::uptime};
+
+use super::{
+    arp,
+    icmpv6::set_checksum,
+    interface::Interface,
+    ipv6::{self, is_link_local, solicited_node, NEXT_HEADER_ICMPV6},
+};
+
+pub const TYPE_ROUTER_SOLICITATION: u8 = 133;
+const TYPE_ROUTER_ADVERTISEMENT: u8 = 134;
+const TYPE_NEIGHBOUR_SOLICITATION: u8 = 135;
+pub const TYPE_NEIGHBOUR_ADVERTISEMENT: u8 = 136;
+
+const OPTION_SOURCE_LINK_ADDR: u8 = 1;
+const OPTION_TARGET_LINK_ADDR: u8 = 2;
+const OPTION_PREFIX_INFO: u8 = 3;
+
+const ADVERTISEMENT_SOLICITED: u8 = 0x40;
+const ADVERTISEMENT_OVERRIDE: u8 = 0x20;
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+
+/// Neighbour discovery messages have to come with this, which proves they weren't routed
+const HOP_LIMIT: u8 = 255;
+
+/// How long to listen for someone else using our address, in ms
+const DAD_WAIT: u64 = 1000;
+const ROUTER_SOLICITATIONS: u32 = 3;
+const ROUTER_SOLICITATION_INTERVAL: u64 = 4000;
+/// How often lifetimes are checked
+const TICK: u64 = 1000;
+
+#[derive(Default)]
+struct Slaac {
+    /// Someone else answered for the address we were checking
+    conflict: bool,
+    /// Our global addresses and when they run out
+    global: BTreeMap<IPAddr, u64>,
+    /// The default router and when it stops being one
+    router: Option<(IPAddr, u64)>,
+}
+
+/// The state of each interface, by id
+static SLAAC: Mutex<BTreeMap<u32, Slaac>> = Mutex::new(BTreeMap::new());
+
+fn mac_bytes(iface: &Interface) -> [u8; 6] {
+    iface.mac.to_le_bytes()[..6].try_into().unwrap()
+}
+
+/// The modified EUI-64 made from the interface's mac, the bottom half of all its addresses
+fn interface_id(iface: &Interface) -> [u8; 8] {
+    let m = mac_bytes(iface);
+    [m[0] ^ 2, m[1], m[2], 0xFF, 0xFE, m[3], m[4], m[5]]
+}
+
+fn with_interface_id(iface: &Interface, prefix: &[u8]) -> IPAddr {
+    let mut addr = [0; 16];
+    addr[..8].copy_from_slice(&prefix[..8]);
+    addr[8..].copy_from_slice(&interface_id(iface));
+    IPAddr::V6(addr)
+}
+
+/// Goes through the options after a message's fixed part, giving the kind and the whole option
+fn options(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    core::iter::from_fn(move || {
+        let len = *data.get(1)? as usize * 8;
+        if len == 0 || len > data.len() {
+            return None;
+        }
+        let (option, rest) = data.split_at(len);
+        data = rest;
+        Some((option[0], option))
+    })
+}
+
+fn link_addr(data: &[u8], kind: u8) -> Option<u64> {
+    options(data)
+        .find(|(k, o)| *k == kind && o.len() >= 8)
+        .map(|(_, o)| {
+            let mut mac = [0; 8];
+            mac[..6].copy_from_slice(&o[2..8]);
+            u64::from_le_bytes(mac)
+        })
+}
+
+fn link_addr_option(iface: &Interface, kind: u8) -> [u8; 8] {
+    let m = mac_bytes(iface);
+    [kind, 1, m[0], m[1], m[2], m[3], m[4], m[5]]
+}
+
+fn send(iface: &Interface, src: IPAddr, dst: IPAddr, mut msg: Vec<u8>) {
+    set_checksum(src, dst, &mut msg);
+    ipv6::send_on_link(iface, src, dst, NEXT_HEADER_ICMPV6, HOP_LIMIT, &msg);
+}
+
+fn solicitation(target: IPAddr) -> Vec<u8> {
+    let IPAddr::V6(target) = target else {
+        panic!("{target} is not an ipv6 address")
+    };
+    let mut msg = Vec::with_capacity(32);
+    msg.extend_from_slice(&[TYPE_NEIGHBOUR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0]);
+    msg.extend_from_slice(&target);
+    msg
+}
+
+/// Asks for the mac of a neighbour on the interface's link
+pub fn send_solicitation(iface: &Interface, target: IPAddr) {
+    let Some(src) = iface.source(target) else {
+        return;
+    };
+    let mut msg = solicitation(target);
+    msg.extend_from_slice(&link_addr_option(iface, OPTION_SOURCE_LINK_ADDR));
+    send(iface, src, solicited_node(target), msg);
+}
+
+fn send_advertisement(iface: &Interface, target: IPAddr, dst: IPAddr, flags: u8) {
+    let IPAddr::V6(target_bytes) = target else {
+        unreachable!()
+    };
+    let mut msg = Vec::with_capacity(32);
+    msg.extend_from_slice(&[TYPE_NEIGHBOUR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0]);
+    msg.extend_from_slice(&target_bytes);
+    msg.extend_from_slice(&link_addr_option(iface, OPTION_TARGET_LINK_ADDR));
+    send(iface, target, dst, msg);
+}
+
+/// Notes that someone else is using the address the interface is checking
+fn conflict(iface: &Interface) {
+    if let Some(slaac) = SLAAC.lock().get_mut(&iface.id) {
+        slaac.conflict = true;
+    }
+}
+
+pub fn handle_message(iface: &Interface, src: IPAddr, hop_limit: u8, data: &[u8]) {
+    if hop_limit != HOP_LIMIT || data[1] != 0 {
+        trace!("Dropping neighbour discovery message from {src} that was routed");
+        return;
+    }
+
+    match data[0] {
+        TYPE_NEIGHBOUR_SOLICITATION if data.len() >= 24 => {
+            let target = IPAddr::V6(data[8..24].try_into().unwrap());
+            if iface.tentative() == Some(target) {
+                // Someone else is checking for the same address
+                if src == IPAddr::UNSPECIFIED_V6 {
+                    conflict(iface);
+                }
+                return;
+            }
+            if !iface.is_ours_v6(target) {
+                return;
+            }
+
+            if src == IPAddr::UNSPECIFIED_V6 {
+                send_advertisement(iface, target, ipv6::ALL_NODES, ADVERTISEMENT_OVERRIDE);
+            } else {
+                if let Some(mac) = link_addr(&data[24..], OPTION_SOURCE_LINK_ADDR) {
+                    arp::learn(iface, src, mac, true);
+                }
+                send_advertisement(
+                    iface,
+                    target,
+                    src,
+                    ADVERTISEMENT_SOLICITED | ADVERTISEMENT_OVERRIDE,
+                );
+            }
+        }
+        TYPE_NEIGHBOUR_ADVERTISEMENT if data.len() >= 24 => {
+            let target = IPAddr::V6(data[8..24].try_into().unwrap());
+            if iface.tentative() == Some(target) {
+                conflict(iface);
+                return;
+            }
+            if let Some(mac) = link_addr(&data[24..], OPTION_TARGET_LINK_ADDR) {
+                arp::learn(iface, target, mac, false);
+            }
+        }
+        TYPE_ROUTER_ADVERTISEMENT if data.len() >= 16 && is_link_local(src) => {
+            router_advertisement(iface, src, data)
+        }
+        _ => (),
+    }
+}
+
+fn router_advertisement(iface: &Interface, src: IPAddr, data: &[u8]) {
+    if let Some(mac) = link_addr(&data[16..], OPTION_SOURCE_LINK_ADDR) {
+        arp::learn(iface, src, mac, true);
+    }
+
+    let now = uptime();
+    let expires = |secs: u32| match secs {
+        u32::MAX => u64::MAX,
+        s => now + s as u64 * 1000,
+    };
+    let mut slaacs = SLAAC.lock();
+    let Some(slaac) = slaacs.get_mut(&iface.id) else {
+        return;
+    };
+
+    let lifetime = u16::from_be_bytes([data[6], data[7]]);
+    if lifetime != 0 {
+        slaac.router = Some((src, expires(lifetime as u32)));
+    } else if slaac.router.is_some_and(|(r, _)| r == src) {
+        slaac.router = None;
+    }
+
+    for (_, option) in
+        options(&data[16..]).filter(|(k, o)| *k == OPTION_PREFIX_INFO && o.len() == 32)
+    {
+        let prefix_len = option[2];
+        let flags = option[3];
+        let valid = u32::from_be_bytes(option[4..8].try_into().unwrap());
+        let prefix = &option[16..32];
+        // Our interface identifier only fits in a /64
+        if flags & PREFIX_AUTONOMOUS == 0 || prefix_len != 64 {
+            continue;
+        }
+        let addr = with_interface_id(iface, prefix);
+        if is_link_local(addr) {
+            continue;
+        }
+        if valid == 0 {
+            slaac.global.remove(&addr);
+        } else {
+            slaac.global.insert(addr, expires(valid));
+        }
+    }
+    publish(iface, slaac);
+}
+
+/// Updates the interface if anything has changed
+fn publish(iface: &Interface, slaac: &Slaac) {
+    let old = iface.config_v6();
+    let config = Ipv6Config {
+        link_local: old.link_local,
+        global: slaac.global.keys().copied().collect(),
+        router: slaac.router.map(|(r, _)| r),
+    };
+    if config != old {
+        iface.set_config_v6(config);
+    }
+}
+
+/// Sets up the interface's link local address, then keeps the addresses from routers up to
+/// date until the interface goes away
+pub fn slaac_task(iface: Arc<Interface>) {
+    SLAAC.lock().insert(iface.id, Slaac::default());
+    run_slaac(&iface);
+    SLAAC.lock().remove(&iface.id);
+}
+
+fn run_slaac(iface: &Interface) {
+    // Duplicate address detection, we ask for the address from nowhere and see if anyone
+    // answers for it. The global addresses share its interface identifier, so checking this
+    // once covers them too.
+    let link_local = with_interface_id(iface, &[0xFE, 0x80, 0, 0, 0, 0, 0, 0]);
+    iface.set_tentative(Some(link_local));
+    send(
+        iface,
+        IPAddr::UNSPECIFIED_V6,
+        solicited_node(link_local),
+        solicitation(link_local),
+    );
+    sleep(DAD_WAIT);
+    iface.set_tentative(None);
+    if SLAAC.lock()[&iface.id].conflict {
+        warn!(
+            "{link_local} is already in use, ipv6 is disabled on {}",
+            iface.name
+        );
+        return;
+    }
+    iface.set_config_v6(Ipv6Config {
+        link_local: Some(link_local),
+        ..iface.config_v6()
+    });
+
+    for _ in 0..ROUTER_SOLICITATIONS {
+        if iface.is_removed() || SLAAC.lock()[&iface.id].router.is_some() {
+            break;
+        }
+        let mut msg = Vec::with_capacity(16);
+        msg.extend_from_slice(&[TYPE_ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0]);
+        msg.extend_from_slice(&link_addr_option(iface, OPTION_SOURCE_LINK_ADDR));
+        send(iface, link_local, ipv6::ALL_ROUTERS, msg);
+        sleep(ROUTER_SOLICITATION_INTERVAL);
+    }
+
+    while !iface.is_removed() {
+        sleep(TICK);
+        let now = uptime();
+        let mut slaacs = SLAAC.lock();
+        let slaac = slaacs.get_mut(&iface.id).unwrap();
+        slaac.global.retain(|_, expires| *expires > now);
+        if slaac.router.is_some_and(|(_, expires)| expires <= now) {
+            slaac.router = None;
+        }
+        publish(iface, slaac);
+    }
+}
@@ -0,0 +1,200 @@
+//! The routing table. As the interfaces' addressing changes the table gets a route to the
+//! subnet of each of their addresses and a default route through each gateway or router, more
+//! can be added by hand. A packet goes out of the route with the longest prefix that covers
+//! its destination, ties going to the oldest route.
+
+use alloc::{sync::Arc, vec::Vec};
+use kernel_userspace::net::{IPAddr, InterfaceConfig, Ipv6Config, NetError, Route, RouteOrigin};
+
+use crate::mutex::Mutex;
+
+use super::{
+    interface::{self, Interface},
+    ipv4, ipv6,
+};
+
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+/// Where a packet to some destination goes
+pub struct NextHop {
+    pub interface: Arc<Interface>,
+    pub src: IPAddr,
+    /// The destination if it is on the interface's link, otherwise the gateway
+    pub addr: IPAddr,
+}
+
+/// The address as the top bits of a u128, and how many bits it has
+fn bits(ip: IPAddr) -> (u128, u8) {
+    match ip {
+        IPAddr::V4(..) => ((u32::from_be_bytes(ip.octets()) as u128) << 96, 32),
+        IPAddr::V6(b) => (u128::from_be_bytes(b), 128),
+    }
+}
+
+fn mask(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+/// The first address of the prefix ip is in
+fn network(ip: IPAddr, prefix_len: u8) -> IPAddr {
+    let bits = bits(ip).0 & mask(prefix_len);
+    match ip {
+        IPAddr::V4(..) => {
+            let [a, b, c, d] = ((bits >> 96) as u32).to_be_bytes();
+            IPAddr::V4(a, b, c, d)
+        }
+        IPAddr::V6(_) => IPAddr::V6(bits.to_be_bytes()),
+    }
+}
+
+fn covers(route: &Route, dst: IPAddr) -> bool {
+    in_prefix(dst, route.dst, route.prefix_len)
+}
+
+/// Whether ip is in the prefix of prefix_len bits starting at network
+pub fn in_prefix(ip: IPAddr, network: IPAddr, prefix_len: u8) -> bool {
+    ip.is_ipv6() == network.is_ipv6() && (bits(network).0 ^ bits(ip).0) & mask(prefix_len) == 0
+}
+
+pub fn lookup(dst: IPAddr) -> Option<Route> {
+    ROUTES
+        .lock()
+        .iter()
+        .rev()
+        .filter(|r| covers(r, dst))
+        .max_by_key(|r| r.prefix_len)
+        .cloned()
+}
+
+/// Works out the interface, source address and next hop for a packet to dst, None if there is
+/// no way to get it there
+pub fn resolve(dst: IPAddr) -> Option<NextHop> {
+    // Broadcasts and multicasts don't leave the link, so they go out of the first interface
+    // that has an address to send them from
+    if dst == IPAddr::BROADCAST || ipv4::is_multicast(dst) || ipv6::is_multicast(dst) {
+        return interface::all().into_iter().find_map(|interface| {
+            Some(NextHop {
+                src: interface.source(dst)?,
+                addr: dst,
+                interface,
+            })
+        });
+    }
+
+    let route = lookup(dst)?;
+    let interface = interface::get(route.interface)?;
+    Some(NextHop {
+        src: interface.source(dst)?,
+        addr: route.gateway.unwrap_or(dst),
+        interface,
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+/// Swaps the routes the stack made for one version of ip on the interface for new ones
+fn replace(interface: u32, v6: bool, new: Vec<Route>) {
+    let mut routes = ROUTES.lock();
+    routes.retain(|r| {
+        r.interface != interface || r.dst.is_ipv6() != v6 || r.origin == RouteOrigin::Static
+    });
+    routes.extend(new);
+}
+
+pub fn update_v4(interface: u32, config: &InterfaceConfig) {
+    let mut new = Vec::new();
+    if config.addr != IPAddr::UNSPECIFIED {
+        let prefix_len = config.netmask.leading_ones() as u8;
+        new.push(Route {
+            dst: network(config.addr, prefix_len),
+            prefix_len,
+            gateway: None,
+            interface,
+            origin: RouteOrigin::Connected,
+        });
+        if let Some(gateway) = config.gateway {
+            new.push(Route {
+                dst: IPAddr::UNSPECIFIED,
+                prefix_len: 0,
+                gateway: Some(gateway),
+                interface,
+                origin: RouteOrigin::Gateway,
+            });
+        }
+    }
+    replace(interface, false, new);
+}
+
+pub fn update_v6(interface: u32, config: &Ipv6Config) {
+    let mut new: Vec<Route> = Vec::new();
+    // Each of our addresses is in a /64 that is on our link
+    for addr in config.link_local.iter().chain(&config.global) {
+        let dst = network(*addr, 64);
+        if !new.iter().any(|r| r.dst == dst) {
+            new.push(Route {
+                dst,
+                prefix_len: 64,
+                gateway: None,
+                interface,
+                origin: RouteOrigin::Connected,
+            });
+        }
+    }
+    if let Some(router) = config.router {
+        new.push(Route {
+            dst: IPAddr::UNSPECIFIED_V6,
+            prefix_len: 0,
+            gateway: Some(router),
+            interface,
+            origin: RouteOrigin::Router,
+        });
+    }
+    replace(interface, true, new);
+}
+
+/// Adds a static route, replacing any other to the same destination through the same
+/// interface
+pub fn add(mut route: Route) -> Result<(), NetError> {
+    if interface::get(route.interface).is_none() {
+        return Err(NetError::NoSuchInterface);
+    }
+    if route.prefix_len > bits(route.dst).1
+        || route
+            .gateway
+            .is_some_and(|g| g.is_ipv6() != route.dst.is_ipv6())
+    {
+        return Err(NetError::InvalidRoute);
+    }
+    route.dst = network(route.dst, route.prefix_len);
+    route.origin = RouteOrigin::Static;
+
+    let mut routes = ROUTES.lock();
+    routes.retain(|r| !same_destination(r, &route));
+    routes.push(route);
+    Ok(())
+}
+
+fn same_destination(a: &Route, b: &Route) -> bool {
+    a.interface == b.interface
+        && a.prefix_len == b.prefix_len
+        && a.prefix_len <= bits(b.dst).1
+        && a.dst == network(b.dst, b.prefix_len)
+}
+
+/// Removes the route to the same destination through the same interface. Routes the stack
+/// made come back when the interface's addressing next changes.
+pub fn delete(route: &Route) -> Result<(), NetError> {
+    let mut routes = ROUTES.lock();
+    let len = routes.len();
+    routes.retain(|r| !same_destination(r, route));
+    if routes.len() == len {
+        return Err(NetError::NoSuchRoute);
+    }
+    Ok(())
+}
+
+pub fn remove_interface(interface: u32) {
+    ROUTES.lock().retain(|r| r.interface != interface);
+}
@@ -0,0 +1,175 @@
+//! A simple network time protocol client for the `SNTP` service. The realtime clock is set
+//! from one server's answer, taking off half the round trip, rather than being slewed.
+
+use core::ops::ControlFlow;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use kernel_userspace::{
+    channel::{channel_read_resize, channel_write_rs, ChannelReadResult},
+    net::{
+        resolve,
+        sntp::{SntpMessage, SntpStatus},
+        NetError, UdpSocket,
+    },
+    service::{deserialize, serialize, Service},
+    syscall::{sleep, spawn_thread},
+    time::DateTime,
+};
+
+use crate::{
+    mutex::Mutex,
+    random::random_u64,
+    time::{realtime_us, set_realtime_us, uptime, uptime_us},
+};
+
+use super::config;
+
+const NTP_PORT: u16 = 123;
+const PACKET_LEN: usize = 48;
+/// Leap indicator 0, version 4 and mode 3 for a client
+const CLIENT_HEADER: u8 = 4 << 3 | 3;
+const MODE_SERVER: u8 = 4;
+/// From 1900, when ntp time starts, to 1970
+const UNIX_OFFSET: u64 = 2_208_988_800;
+
+const TIMEOUT: u64 = 2000;
+/// How often the clock is synced, in ms
+const INTERVAL: u64 = 60 * 60 * 1000;
+/// How soon to try again after a sync fails, like when the network isn't up yet
+const RETRY: u64 = 30 * 1000;
+
+static STATUS: Mutex<SntpStatus> = Mutex::new(SntpStatus {
+    server: String::new(),
+    last_sync: None,
+    offset_us: 0,
+    round_trip_us: 0,
+    stratum: 0,
+    last_error: None,
+});
+
+/// An ntp timestamp as microseconds since the unix epoch
+fn to_unix_us(timestamp: &[u8]) -> u64 {
+    let secs = u32::from_be_bytes(timestamp[..4].try_into().unwrap()) as u64;
+    let frac = u32::from_be_bytes(timestamp[4..8].try_into().unwrap()) as u64;
+    secs.saturating_sub(UNIX_OFFSET) * 1_000_000 + ((frac * 1_000_000) >> 32)
+}
+
+/// Asks the server for the time and sets the clock
+fn sync_with(server: &str) -> Result<SntpStatus, NetError> {
+    let addr = resolve(server)?.preferred().ok_or(NetError::HostNotFound)?;
+    let mut socket = UdpSocket::bind(0)?;
+
+    // The server hands back our transmit timestamp, so a random one tells its answer apart
+    let nonce = random_u64().to_be_bytes();
+    let mut request = [0; PACKET_LEN];
+    request[0] = CLIENT_HEADER;
+    request[40..48].copy_from_slice(&nonce);
+    let sent = uptime_us();
+    socket.send_to(&request, addr, NTP_PORT)?;
+
+    let mut buf = [0; 512];
+    let answer = loop {
+        let (len, src, src_port) = socket.recv_from_timeout(&mut buf, TIMEOUT)?;
+        if src == addr && src_port == NTP_PORT && len >= PACKET_LEN && buf[24..32] == nonce {
+            break &buf[..PACKET_LEN];
+        }
+    };
+    let received = uptime_us();
+
+    let stratum = answer[1];
+    // A stratum of 0 is the server telling us to go away
+    if answer[0] & 7 != MODE_SERVER || stratum == 0 || answer[40..48] == [0; 8] {
+        return Err(NetError::BadResponse);
+    }
+    let server_received = to_unix_us(&answer[32..40]);
+    let server_sent = to_unix_us(&answer[40..48]);
+    let round_trip = (received - sent).saturating_sub(server_sent.saturating_sub(server_received));
+
+    let now = server_sent + round_trip / 2 + (uptime_us() - received);
+    let offset = realtime_us().map_or(0, |old| now as i64 - old as i64);
+    set_realtime_us(now);
+    info!(
+        "Clock set to {} by {server}",
+        DateTime::from_unix(now / 1_000_000)
+    );
+
+    Ok(SntpStatus {
+        server: server.to_string(),
+        last_sync: Some(uptime()),
+        offset_us: offset,
+        round_trip_us: round_trip,
+        stratum,
+        last_error: None,
+    })
+}
+
+/// Syncs with the server in the status, keeping how it went
+fn sync() -> Result<SntpStatus, NetError> {
+    let mut status = STATUS.lock();
+    if status.server.is_empty() {
+        return Err(NetError::NotConfigured);
+    }
+    match sync_with(&status.server) {
+        Ok(s) => {
+            *status = s;
+            Ok(status.clone())
+        }
+        Err(e) => {
+            status.last_error = Some(e);
+            Err(e)
+        }
+    }
+}
+
+/// Keeps the clock synced
+fn sync_task() {
+    loop {
+        match sync() {
+            Ok(_) => sleep(INTERVAL),
+            Err(e) => {
+                trace!("Failed to sync the clock: {e}");
+                sleep(RETRY)
+            }
+        };
+    }
+}
+
+pub fn sntp_service() {
+    STATUS.lock().server = config::ntp_server().unwrap_or_default();
+    spawn_thread(sync_task);
+    let mut buffer = Vec::with_capacity(100);
+
+    Service::new(
+        "SNTP",
+        || (),
+        |handle, ()| {
+            match channel_read_resize(handle.id(), &mut buffer, &mut Vec::new()) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Closed => return ControlFlow::Break(()),
+                e => {
+                    warn!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            }
+
+            match deserialize(&buffer) {
+                Ok(SntpMessage::Status) => serialize(&STATUS.lock().clone(), &mut buffer),
+                Ok(SntpMessage::SetServer(server)) => {
+                    STATUS.lock().server = server;
+                    serialize(&sync(), &mut buffer)
+                }
+                Ok(SntpMessage::Sync) => serialize(&sync(), &mut buffer),
+                Err(e) => {
+                    warn!("Bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+            channel_write_rs(handle.id(), &buffer, &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
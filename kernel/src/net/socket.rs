@@ -0,0 +1,154 @@
+//! Serves the socket protocol. Each socket gets a task that carries out its requests with tcp
+//! or udp, which send the socket's events straight down its channel. Connections accepted on
+//! a listening socket get a socket and task of their own.
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    channel::{channel_read_resize, channel_write_rs, ChannelReadResult},
+    net::{max_datagram, NetError, SocketEvent, SocketKind, SocketRequest, MAX_UDP_PAYLOAD},
+    object::KernelReference,
+    service::{deserialize, serialize},
+    syscall::spawn_thread,
+};
+
+use super::{
+    tcp::{self, ConnectionKey},
+    udp,
+};
+
+pub fn open(kind: SocketKind, client: KernelReference) {
+    spawn_thread(move || socket_task(kind, client, None));
+}
+
+/// Sends an event to the socket's owner
+pub fn event(client: &KernelReference, event: &SocketEvent) {
+    let mut buffer = Vec::new();
+    serialize(event, &mut buffer);
+    channel_write_rs(client.id(), &buffer, &[]);
+}
+
+/// Tells a listening socket's owner about a connection it has accepted, passing on the
+/// owner's end of the connection's channel. Ours gets a task to serve it.
+pub fn accepted(
+    listener: &KernelReference,
+    key: ConnectionKey,
+    ours: KernelReference,
+    theirs: KernelReference,
+) {
+    let (ip, port) = key.remote();
+    let mut buffer = Vec::new();
+    serialize(&SocketEvent::Accepted(ip, port), &mut buffer);
+    channel_write_rs(listener.id(), &buffer, &[theirs.id()]);
+    spawn_thread(move || socket_task(SocketKind::Stream, ours, Some(key)));
+}
+
+/// Carries out requests until the owner closes the channel, which closes the socket
+fn socket_task(kind: SocketKind, client: KernelReference, mut connection: Option<ConnectionKey>) {
+    let mut buffer = Vec::with_capacity(MAX_UDP_PAYLOAD + 64);
+    let mut port: Option<u16> = None;
+    let mut listening = false;
+
+    loop {
+        match channel_read_resize(client.id(), &mut buffer, &mut Vec::new()) {
+            ChannelReadResult::Ok => (),
+            ChannelReadResult::Closed => break,
+            e => {
+                warn!("{e:?}");
+                break;
+            }
+        }
+        let request = match deserialize(&buffer) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Bad socket request: {e:?}");
+                break;
+            }
+        };
+
+        // Requests that succeed quietly give None
+        let result = match (kind, request) {
+            (_, SocketRequest::Bind(_)) if port.is_some() || connection.is_some() => {
+                Err(NetError::InvalidState)
+            }
+            (SocketKind::Stream, SocketRequest::Bind(p)) => tcp::bind(p).map(|p| {
+                port = Some(p);
+                Some(SocketEvent::Bound(p))
+            }),
+            (SocketKind::Datagram, SocketRequest::Bind(p)) => {
+                udp::bind(p, client.clone()).map(|p| {
+                    port = Some(p);
+                    Some(SocketEvent::Bound(p))
+                })
+            }
+
+            (SocketKind::Stream, SocketRequest::Connect(..) | SocketRequest::Listen(_))
+                if connection.is_some() || listening =>
+            {
+                Err(NetError::InvalidState)
+            }
+            // Connected is sent by tcp once the handshake is done
+            (SocketKind::Stream, SocketRequest::Connect(ip, p)) => {
+                tcp::connect(ip, p, port, client.clone()).map(|key| {
+                    connection = Some(key);
+                    None
+                })
+            }
+            (SocketKind::Stream, SocketRequest::Listen(backlog)) => match port {
+                Some(p) => tcp::listen(p, backlog, client.clone()).map(|()| {
+                    listening = true;
+                    Some(SocketEvent::Listening)
+                }),
+                None => Err(NetError::InvalidState),
+            },
+            // Accepted is sent by tcp once there is a connection
+            (SocketKind::Stream, SocketRequest::Accept) => match port {
+                Some(p) if listening => tcp::accept(p).map(|()| None),
+                _ => Err(NetError::InvalidState),
+            },
+            (SocketKind::Stream, SocketRequest::Send(data)) => match connection {
+                Some(key) => tcp::send(key, data).map(|()| None),
+                None => Err(NetError::NotConnected),
+            },
+            (SocketKind::Stream, SocketRequest::Shutdown) => match connection {
+                Some(key) => tcp::shutdown(key).map(|()| None),
+                None => Err(NetError::NotConnected),
+            },
+
+            (SocketKind::Datagram, SocketRequest::SendTo(ip, dst_port, data)) => {
+                if data.len() > max_datagram(ip) {
+                    Err(NetError::MessageTooLong)
+                } else {
+                    let src_port = match port {
+                        Some(p) => Ok(p),
+                        None => udp::bind(0, client.clone()).inspect(|p| port = Some(*p)),
+                    };
+                    src_port.map(|p| {
+                        udp::send(p, ip, dst_port, data);
+                        None
+                    })
+                }
+            }
+
+            // The rest are for the other kind of socket
+            _ => Err(NetError::Unsupported),
+        };
+
+        match result {
+            Ok(Some(e)) => event(&client, &e),
+            Ok(None) => (),
+            Err(e) => event(&client, &SocketEvent::Error(e)),
+        }
+    }
+
+    if let Some(key) = connection {
+        tcp::close(key);
+    }
+    if let (true, Some(p)) = (listening, port) {
+        tcp::unlisten(p);
+    }
+    match (kind, port) {
+        (SocketKind::Stream, Some(p)) => tcp::unbind(p),
+        (SocketKind::Datagram, Some(p)) => udp::unbind(p),
+        (_, None) => (),
+    }
+}
@@ -0,0 +1,928 @@
+//! Transmission control protocol.
+//!
+//! Connections are opened by us with [`connect`] or by a peer to a port a socket is
+//! [`listen`]ing on. A connection from a peer gets a channel as soon as its SYN arrives, so
+//! anything it sends before being accepted waits in the channel. Once the handshake is done it
+//! waits on the listener until an [`accept`] hands the channel to the listening socket's owner.
+//!
+//! A connection is driven from three places: segments from the wire in [`handle_segment`],
+//! requests from the socket that owns it through [`send`], [`shutdown`] and [`close`] and the
+//! retransmission timer in [`timer_task`]. They all go through [`CONNECTIONS`] so each connection sees one thing at
+//! a time.
+//!
+//! Received data is handed to the owner as soon as it arrives in order, so we always advertise
+//! the full window. Segments that arrive early are dropped and the peer resends them after our
+//! duplicate ack. Lost segments are resent go-back-N style once the retransmission timeout
+//! passes, which is worked out from the round trip time as in RFC 6298.
+
+use core::{cmp::min, ops::Range};
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    vec::Vec,
+};
+use kernel_userspace::{
+    channel::channel_create_rs,
+    net::{
+        IPAddr, NetError, SocketEvent, SocketInfo, SocketKind, SocketState, SocketStats, TxOffload,
+    },
+    object::KernelReference,
+    syscall::sleep,
+};
+
+use crate::{
+    mutex::Mutex,
+    random::{random_below, random_u64},
+    time::uptime,
+};
+
+use super::{
+    ip::{self, add_to_sum, checksum, fold, pseudo_header_sum, PROTOCOL_TCP},
+    route, socket,
+};
+
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+
+const HEADER_LEN: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// What we have to assume if the peer doesn't say
+const DEFAULT_MSS: u16 = 536;
+const RECV_WINDOW: u16 = u16::MAX;
+/// How much written data is held before the owner is made to wait
+const SEND_BUFFER: usize = 64 * 1024;
+
+const EPHEMERAL_PORTS: u16 = 49152;
+/// The most connections a listener holds on to before they are accepted, counting those
+/// still in the handshake
+const MAX_BACKLOG: u32 = 64;
+
+/// How often the timers are checked, in ms
+const TICK: u64 = 50;
+const INITIAL_RTO: u64 = 1000;
+const MIN_RTO: u64 = 200;
+const MAX_RTO: u64 = 60_000;
+/// Timeouts in a row before the connection is given up on
+const MAX_RETRIES: u32 = 8;
+/// Twice the maximum segment lifetime
+const TIME_WAIT: u64 = 2 * 30_000;
+
+static CONNECTIONS: Mutex<BTreeMap<ConnectionKey, Connection>> = Mutex::new(BTreeMap::new());
+/// Local ports sockets have bound, which connections only use when asked to
+static BOUND: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+/// Keyed by port. Locked after [`CONNECTIONS`] when both are needed.
+static LISTENERS: Mutex<BTreeMap<u16, Listener>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConnectionKey {
+    remote: IPAddr,
+    remote_port: u16,
+    local_port: u16,
+}
+
+impl ConnectionKey {
+    /// Who the connection is with
+    pub fn remote(&self) -> (IPAddr, u16) {
+        (self.remote, self.remote_port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+}
+
+impl From<State> for SocketState {
+    fn from(state: State) -> Self {
+        match state {
+            State::SynSent => SocketState::SynSent,
+            State::SynReceived => SocketState::SynReceived,
+            State::Established => SocketState::Established,
+            State::FinWait1 => SocketState::FinWait1,
+            State::FinWait2 => SocketState::FinWait2,
+            State::Closing => SocketState::Closing,
+            State::TimeWait => SocketState::TimeWait,
+            State::CloseWait => SocketState::CloseWait,
+            State::LastAck => SocketState::LastAck,
+        }
+    }
+}
+
+/// Whether sequence number a comes before b
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+struct Segment<'a> {
+    src_port: u16,
+    dst_port: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    fn parse(src: IPAddr, dst: IPAddr, data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+        let header_len = (data[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > data.len() {
+            return None;
+        }
+        if checksum(data, pseudo_header_sum(src, dst, PROTOCOL_TCP, data.len())) != 0 {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &data[HEADER_LEN..header_len];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                OPTION_END => break,
+                OPTION_NOP => options = rest,
+                _ => {
+                    let len = *rest.first()? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if *kind == OPTION_MSS && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        let u32_at = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+        Some(Self {
+            src_port: u16::from_be_bytes([data[0], data[1]]),
+            dst_port: u16::from_be_bytes([data[2], data[3]]),
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: data[13],
+            window: u16::from_be_bytes([data[14], data[15]]),
+            mss,
+            data: &data[header_len..],
+        })
+    }
+
+    /// How much sequence space the segment takes up
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+/// What fits in a packet to dst after the tcp header
+fn our_mss(dst: IPAddr) -> u16 {
+    (ip::max_payload(dst) - HEADER_LEN) as u16
+}
+
+/// Sends a segment, leaving the checksum to the card if it can. With tso_mss the card splits
+/// the data into segments of that size.
+fn send_segment(
+    key: &ConnectionKey,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    data: &[u8],
+    tso_mss: Option<u16>,
+) {
+    let dst = key.remote;
+    let Some(next) = route::resolve(dst) else {
+        trace!("Dropping tcp segment as there is no route to {dst}");
+        return;
+    };
+
+    // Our mss goes on every SYN
+    let header_len = if flags & SYN != 0 {
+        HEADER_LEN + 4
+    } else {
+        HEADER_LEN
+    };
+    let mut header = Vec::with_capacity(header_len);
+    header.extend_from_slice(&key.local_port.to_be_bytes());
+    header.extend_from_slice(&key.remote_port.to_be_bytes());
+    header.extend_from_slice(&seq.to_be_bytes());
+    header.extend_from_slice(&ack.to_be_bytes());
+    header.push(((header_len / 4) as u8) << 4);
+    header.push(flags);
+    header.extend_from_slice(&RECV_WINDOW.to_be_bytes());
+    header.extend_from_slice(&[0; 4]); // checksum and urgent pointer
+    if flags & SYN != 0 {
+        header.extend_from_slice(&[OPTION_MSS, 4]);
+        header.extend_from_slice(&our_mss(dst).to_be_bytes());
+    }
+
+    let mut offload = TxOffload {
+        tso_mss,
+        ..Default::default()
+    };
+    if next.interface.caps.l4_checksum {
+        // The card finishes the sum off, each segment it splits off has its own length
+        let len = if tso_mss.is_some() {
+            0
+        } else {
+            header_len + data.len()
+        };
+        let sum = fold(pseudo_header_sum(next.src, dst, PROTOCOL_TCP, len));
+        header[16..18].copy_from_slice(&sum.to_be_bytes());
+        offload.l4_checksum = Some(16);
+    } else {
+        let len = header_len + data.len();
+        let sum = checksum(
+            data,
+            add_to_sum(&header, pseudo_header_sum(next.src, dst, PROTOCOL_TCP, len)),
+        );
+        header[16..18].copy_from_slice(&sum.to_be_bytes());
+    }
+    ip::send_fragments(&next, dst, PROTOCOL_TCP, &[&header, data], offload);
+}
+
+/// Answers a segment that doesn't belong to any connection
+fn send_reset(src: IPAddr, seg: &Segment) {
+    if seg.flags & RST != 0 {
+        return;
+    }
+    let key = ConnectionKey {
+        remote: src,
+        remote_port: seg.src_port,
+        local_port: seg.dst_port,
+    };
+    if seg.flags & ACK != 0 {
+        send_segment(&key, seg.ack, 0, RST, &[], None);
+    } else {
+        let ack = seg.seq.wrapping_add(seg.len());
+        send_segment(&key, 0, ack, RST | ACK, &[], None);
+    }
+}
+
+struct Connection {
+    key: ConnectionKey,
+    state: State,
+    /// The channel to the owner, None once it has closed its end
+    client: Option<KernelReference>,
+
+    snd_una: u32,
+    snd_nxt: u32,
+    /// The furthest we have sent, snd_nxt goes back to snd_una when retransmitting
+    snd_max: u32,
+    snd_wnd: u32,
+    mss: usize,
+    /// Everything from snd_una on, both in flight and not sent yet
+    send_buf: VecDeque<u8>,
+    /// The owner has asked us to close
+    fin_queued: bool,
+    /// The sequence number of our FIN once it has been sent
+    fin_seq: Option<u32>,
+
+    rcv_nxt: u32,
+
+    srtt: Option<u64>,
+    rttvar: u64,
+    rto: u64,
+    /// The ack that will end the round trip being timed and when it started
+    rtt_sample: Option<(u32, u64)>,
+    /// When the retransmission timer goes off, or TIME_WAIT ends
+    deadline: Option<u64>,
+    retries: u32,
+    stats: SocketStats,
+    /// The owner's end of the channel of a connection from a peer, until it is established
+    /// and goes to the listener
+    accept: Option<KernelReference>,
+}
+
+/// A port a socket is taking connections on
+struct Listener {
+    /// The listening socket's channel, which accepted connections are handed out on
+    client: KernelReference,
+    backlog: usize,
+    /// Established connections with our and the owner's ends of their channels
+    ready: VecDeque<(ConnectionKey, KernelReference, KernelReference)>,
+    /// Accepts waiting on a connection
+    waiting: usize,
+}
+
+impl Listener {
+    /// Hands ready connections to the accepts waiting for them
+    fn deliver(&mut self) {
+        while self.waiting > 0 {
+            let Some((key, ours, theirs)) = self.ready.pop_front() else {
+                break;
+            };
+            self.waiting -= 1;
+            socket::accepted(&self.client, key, ours, theirs);
+        }
+    }
+}
+
+impl Connection {
+    fn new(key: ConnectionKey, state: State, client: KernelReference, iss: u32) -> Self {
+        let now = uptime();
+        Self {
+            key,
+            state,
+            client: Some(client),
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            mss: DEFAULT_MSS as usize,
+            send_buf: VecDeque::new(),
+            fin_queued: false,
+            fin_seq: None,
+            rcv_nxt: 0,
+            srtt: None,
+            rttvar: 0,
+            rto: INITIAL_RTO,
+            rtt_sample: Some((iss.wrapping_add(1), now)),
+            deadline: Some(now + INITIAL_RTO),
+            retries: 0,
+            stats: SocketStats::default(),
+            accept: None,
+        }
+    }
+
+    fn send(&mut self, seq: u32, flags: u8) {
+        send_segment(&self.key, seq, self.rcv_nxt, flags, &[], None);
+        self.stats.packets_sent += 1;
+    }
+
+    /// Sends part of the send buffer, straight out of it
+    fn send_buffered(&mut self, seq: u32, range: Range<usize>) {
+        let len = range.len();
+        let tso_mss = (len > self.mss).then_some(self.mss as u16);
+        self.send_buf.make_contiguous();
+        let data = &self.send_buf.as_slices().0[range];
+        send_segment(&self.key, seq, self.rcv_nxt, ACK | PSH, data, tso_mss);
+        self.stats.packets_sent += len.div_ceil(self.mss) as u64;
+        self.stats.bytes_sent += len as u64;
+    }
+
+    /// The most data to send in one go, which is more than the mss if the card splits
+    /// segments up itself
+    fn max_send(&self) -> usize {
+        route::resolve(self.key.remote)
+            .map(|next| next.interface.caps)
+            .filter(|caps| caps.l4_checksum)
+            .map_or(self.mss, |caps| self.mss.max(caps.tso_max as usize))
+    }
+
+    fn event(&self, event: &SocketEvent) {
+        if let Some(client) = &self.client {
+            socket::event(client, event);
+        }
+    }
+
+    fn start_timer(&mut self) {
+        if self.deadline.is_none() {
+            self.deadline = Some(uptime() + self.rto);
+        }
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_seq.is_some_and(|f| seq_lt(f, self.snd_una))
+    }
+
+    fn update_rtt(&mut self, rtt: u64) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                (7 * srtt + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + (4 * self.rttvar).max(TICK)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// Sends as much of the buffer as the peer's window allows, then our FIN once it has all
+    /// gone. A probe sends a byte even into a closed window.
+    fn output(&mut self, probe: bool) {
+        if matches!(
+            self.state,
+            State::SynSent | State::SynReceived | State::FinWait2 | State::TimeWait
+        ) {
+            return;
+        }
+        let window = if probe {
+            self.snd_wnd.max(1)
+        } else {
+            self.snd_wnd
+        } as usize;
+        let max_send = self.max_send();
+
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let len = min(
+                max_send,
+                min(
+                    self.send_buf.len().saturating_sub(offset),
+                    window.saturating_sub(offset),
+                ),
+            );
+            if len == 0 {
+                break;
+            }
+            self.send_buffered(self.snd_nxt, offset..offset + len);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+            if self.rtt_sample.is_none() {
+                self.rtt_sample = Some((self.snd_nxt, uptime()));
+            }
+            self.start_timer();
+        }
+
+        let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.fin_queued
+            && offset == self.send_buf.len()
+            && self.fin_seq.is_none_or(|f| f == self.snd_nxt)
+        {
+            self.send(self.snd_nxt, FIN | ACK);
+            self.fin_seq = Some(self.snd_nxt);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.start_timer();
+            self.state = match self.state {
+                State::Established => State::FinWait1,
+                State::CloseWait => State::LastAck,
+                s => s,
+            };
+        }
+
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        // Keep probing a closed window
+        if self.snd_wnd == 0 && !self.send_buf.is_empty() {
+            self.start_timer();
+        }
+    }
+
+    fn ack(&mut self, seg: &Segment) {
+        if seq_lt(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_max) {
+            let acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+            let data = min(acked, self.send_buf.len());
+            self.send_buf.drain(..data);
+            self.snd_una = seg.ack;
+            if seq_lt(self.snd_nxt, seg.ack) {
+                self.snd_nxt = seg.ack;
+            }
+
+            if let Some((end, sent)) = self.rtt_sample {
+                if seq_le(end, seg.ack) {
+                    // Karn's algorithm, a retransmitted segment can't be timed
+                    if self.retries == 0 {
+                        self.update_rtt(uptime() - sent);
+                    }
+                    self.rtt_sample = None;
+                }
+            }
+            self.retries = 0;
+            self.deadline = (self.snd_una != self.snd_max).then(|| uptime() + self.rto);
+        }
+        if seq_le(self.snd_una, seg.ack) && seq_le(seg.ack, self.snd_max) {
+            self.snd_wnd = seg.window as u32;
+        }
+    }
+
+    fn syn_sent(&mut self, seg: &Segment) {
+        if seg.flags & ACK != 0 && seg.ack != self.snd_nxt {
+            send_reset(self.key.remote, seg);
+            return;
+        }
+        if seg.flags & (SYN | ACK) != SYN | ACK {
+            return;
+        }
+
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.snd_una = seg.ack;
+        self.snd_wnd = seg.window as u32;
+        self.mss = seg.mss.unwrap_or(DEFAULT_MSS).min(our_mss(self.key.remote)) as usize;
+        if let Some((_, sent)) = self.rtt_sample.take() {
+            if self.retries == 0 {
+                self.update_rtt(uptime() - sent);
+            }
+        }
+        self.retries = 0;
+        self.deadline = None;
+
+        self.state = State::Established;
+        self.send(self.snd_nxt, ACK);
+        self.event(&SocketEvent::Connected);
+        self.output(false);
+    }
+
+    /// The peer has acked our SYN, so the connection waits on the listener to be accepted. If
+    /// the listener has gone nobody will take it, so we close it straight away.
+    fn syn_received(&mut self) {
+        self.state = State::Established;
+        let theirs = self.accept.take().unwrap();
+        let mut listeners = LISTENERS.lock();
+        match (listeners.get_mut(&self.key.local_port), self.client.clone()) {
+            (Some(listener), Some(ours)) => {
+                listener.ready.push_back((self.key, ours, theirs));
+                listener.deliver();
+            }
+            _ => {
+                self.client = None;
+                self.fin_queued = true;
+            }
+        }
+    }
+
+    /// Handles a segment from the peer, returns true once the connection is gone
+    fn segment(&mut self, seg: Segment) -> bool {
+        self.stats.packets_received += 1;
+        if seg.flags & RST != 0 {
+            if self.state == State::SynSent {
+                if seg.flags & ACK != 0 && seg.ack == self.snd_nxt {
+                    self.event(&SocketEvent::Error(NetError::ConnectionRefused));
+                    return true;
+                }
+            } else if seg.seq.wrapping_sub(self.rcv_nxt) < RECV_WINDOW as u32 {
+                self.event(&SocketEvent::Error(NetError::ConnectionReset));
+                return true;
+            }
+            return false;
+        }
+        if self.state == State::SynSent {
+            self.syn_sent(&seg);
+            return false;
+        }
+        if seg.flags & ACK == 0 {
+            return false;
+        }
+        if self.state == State::SynReceived && seg.ack != self.snd_nxt {
+            send_reset(self.key.remote, &seg);
+            return false;
+        }
+
+        self.ack(&seg);
+        if self.state == State::SynReceived {
+            self.syn_received();
+        }
+        if self.fin_acked() {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing => {
+                    self.state = State::TimeWait;
+                    self.deadline = Some(uptime() + TIME_WAIT);
+                }
+                State::LastAck => return true,
+                _ => (),
+            }
+        }
+
+        // Cut off anything we have already had
+        let mut seq = seg.seq;
+        let mut data = seg.data;
+        if seq_lt(seq, self.rcv_nxt) {
+            let dup = min(self.rcv_nxt.wrapping_sub(seq) as usize, data.len());
+            data = &data[dup..];
+            seq = seq.wrapping_add(dup as u32);
+        }
+        // Anything that takes up sequence space gets acked, even if it was a duplicate or
+        // came early
+        let need_ack = seg.len() != 0;
+
+        if seq == self.rcv_nxt {
+            if !data.is_empty()
+                && matches!(
+                    self.state,
+                    State::Established | State::FinWait1 | State::FinWait2
+                )
+            {
+                self.event(&SocketEvent::Data(data));
+                self.stats.bytes_received += data.len() as u64;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(data.len() as u32);
+            }
+
+            if seg.flags & FIN != 0
+                && seq.wrapping_add(data.len() as u32) == self.rcv_nxt
+                && matches!(
+                    self.state,
+                    State::Established | State::FinWait1 | State::FinWait2
+                )
+            {
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                self.event(&SocketEvent::Eof);
+                self.state = match self.state {
+                    State::Established => State::CloseWait,
+                    State::FinWait1 => State::Closing,
+                    _ => State::TimeWait,
+                };
+                if self.state == State::TimeWait {
+                    self.deadline = Some(uptime() + TIME_WAIT);
+                }
+            }
+        } else if self.state == State::TimeWait && seg.flags & FIN != 0 {
+            // Our last ack got lost
+            self.deadline = Some(uptime() + TIME_WAIT);
+        }
+
+        if need_ack {
+            self.send(self.snd_nxt, ACK);
+        }
+        self.output(false);
+        false
+    }
+
+    /// Handles the timer going off, returns true once the connection is gone
+    fn timeout(&mut self, now: u64) -> bool {
+        if self.deadline.is_none_or(|d| d > now) {
+            return false;
+        }
+        if self.state == State::TimeWait {
+            return true;
+        }
+
+        // Probing a closed window can go on for as long as the peer keeps answering
+        if self.snd_wnd != 0 || matches!(self.state, State::SynSent | State::SynReceived) {
+            self.retries += 1;
+        }
+        if self.retries > MAX_RETRIES {
+            self.event(&SocketEvent::Error(NetError::TimedOut));
+            return true;
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.deadline = Some(now + self.rto);
+        self.stats.retransmits += 1;
+
+        if self.state == State::SynSent {
+            self.send(self.snd_una, SYN);
+        } else if self.state == State::SynReceived {
+            self.send(self.snd_una, SYN | ACK);
+        } else {
+            self.snd_nxt = self.snd_una;
+            self.output(true);
+        }
+        false
+    }
+}
+
+pub fn handle_segment(src: IPAddr, dst: IPAddr, data: &[u8]) {
+    let Some(seg) = Segment::parse(src, dst, data) else {
+        trace!("Dropping bad tcp segment from {src}");
+        return;
+    };
+    let key = ConnectionKey {
+        remote: src,
+        remote_port: seg.src_port,
+        local_port: seg.dst_port,
+    };
+
+    let mut connections = CONNECTIONS.lock();
+    match connections.get_mut(&key) {
+        Some(conn) => {
+            if conn.segment(seg) {
+                connections.remove(&key);
+            }
+        }
+        None if seg.flags & (SYN | ACK | RST) == SYN => {
+            if !passive_open(&mut connections, key, &seg) {
+                send_reset(src, &seg);
+            }
+        }
+        None => send_reset(src, &seg),
+    }
+}
+
+/// Starts the handshake for a SYN to a listening port, false if nobody is listening. SYNs
+/// that would go over the backlog are ignored and the peer will try again.
+fn passive_open(
+    connections: &mut BTreeMap<ConnectionKey, Connection>,
+    key: ConnectionKey,
+    seg: &Segment,
+) -> bool {
+    let listeners = LISTENERS.lock();
+    let Some(listener) = listeners.get(&key.local_port) else {
+        return false;
+    };
+    let half_open = connections
+        .values()
+        .filter(|c| c.state == State::SynReceived && c.key.local_port == key.local_port)
+        .count();
+    if listener.ready.len() + half_open >= listener.backlog {
+        trace!("Backlog of port {} is full", key.local_port);
+        return true;
+    }
+    drop(listeners);
+
+    let (ours, theirs) = channel_create_rs();
+    let iss = random_u64() as u32;
+    let mut conn = Connection::new(key, State::SynReceived, ours, iss);
+    conn.rcv_nxt = seg.seq.wrapping_add(1);
+    conn.snd_wnd = seg.window as u32;
+    conn.mss = seg.mss.unwrap_or(DEFAULT_MSS).min(our_mss(key.remote)) as usize;
+    conn.accept = Some(theirs);
+    conn.send(iss, SYN | ACK);
+    connections.insert(key, conn);
+    true
+}
+
+/// Reserves a local port for a socket, 0 picks a free one. Returns the port that was bound.
+pub fn bind(port: u16) -> Result<u16, NetError> {
+    let connections = CONNECTIONS.lock();
+    let mut bound = BOUND.lock();
+    let in_use = |p: &u16| bound.contains(p) || connections.keys().any(|k| k.local_port == *p);
+    let port = if port == 0 {
+        (0..16)
+            .map(|_| EPHEMERAL_PORTS + random_below((u16::MAX - EPHEMERAL_PORTS) as u64) as u16)
+            .find(|p| !in_use(p))
+            .ok_or(NetError::NoFreePorts)?
+    } else if in_use(&port) {
+        return Err(NetError::AddrInUse);
+    } else {
+        port
+    };
+    bound.insert(port);
+    Ok(port)
+}
+
+pub fn unbind(port: u16) {
+    BOUND.lock().remove(&port);
+}
+
+/// Starts connecting to the remote from the bound port, or a free one if there isn't one.
+/// Events for the connection are sent down client.
+pub fn connect(
+    remote: IPAddr,
+    remote_port: u16,
+    local_port: Option<u16>,
+    client: KernelReference,
+) -> Result<ConnectionKey, NetError> {
+    let mut connections = CONNECTIONS.lock();
+    let key = match local_port {
+        Some(local_port) => Some(ConnectionKey {
+            remote,
+            remote_port,
+            local_port,
+        })
+        .filter(|k| !connections.contains_key(k)),
+        None => {
+            let bound = BOUND.lock();
+            (0..16)
+                .map(|_| ConnectionKey {
+                    remote,
+                    remote_port,
+                    local_port: EPHEMERAL_PORTS
+                        + random_below((u16::MAX - EPHEMERAL_PORTS) as u64) as u16,
+                })
+                .find(|k| !connections.contains_key(k) && !bound.contains(&k.local_port))
+        }
+    }
+    .ok_or(NetError::NoFreePorts)?;
+
+    let iss = random_u64() as u32;
+    let mut conn = Connection::new(key, State::SynSent, client, iss);
+    conn.send(iss, SYN);
+    connections.insert(key, conn);
+    Ok(key)
+}
+
+/// Starts taking connections on a bound port, they are announced down client as they are
+/// accepted
+pub fn listen(port: u16, backlog: u32, client: KernelReference) -> Result<(), NetError> {
+    let mut listeners = LISTENERS.lock();
+    if listeners.contains_key(&port) {
+        return Err(NetError::AddrInUse);
+    }
+    listeners.insert(
+        port,
+        Listener {
+            client,
+            backlog: backlog.clamp(1, MAX_BACKLOG) as usize,
+            ready: VecDeque::new(),
+            waiting: 0,
+        },
+    );
+    Ok(())
+}
+
+/// Hands the next connection to the listening socket's owner, once there is one
+pub fn accept(port: u16) -> Result<(), NetError> {
+    let mut listeners = LISTENERS.lock();
+    let listener = listeners.get_mut(&port).ok_or(NetError::InvalidState)?;
+    listener.waiting += 1;
+    listener.deliver();
+    Ok(())
+}
+
+/// Stops listening, closing the connections nobody accepted
+pub fn unlisten(port: u16) {
+    let listener = LISTENERS.lock().remove(&port);
+    for (key, ..) in listener.into_iter().flat_map(|l| l.ready) {
+        close(key);
+    }
+}
+
+/// Queues data on the connection, waiting while the send buffer is full
+pub fn send(key: ConnectionKey, data: &[u8]) -> Result<(), NetError> {
+    while CONNECTIONS
+        .lock()
+        .get(&key)
+        .is_some_and(|c| c.send_buf.len() >= SEND_BUFFER)
+    {
+        sleep(TICK);
+    }
+
+    let mut connections = CONNECTIONS.lock();
+    let conn = connections.get_mut(&key).ok_or(NetError::NotConnected)?;
+    if conn.fin_queued {
+        return Err(NetError::Closed);
+    }
+    conn.send_buf.extend(data);
+    conn.output(false);
+    Ok(())
+}
+
+/// Sends a FIN once everything queued so far has gone
+pub fn shutdown(key: ConnectionKey) -> Result<(), NetError> {
+    let mut connections = CONNECTIONS.lock();
+    let conn = connections.get_mut(&key).ok_or(NetError::NotConnected)?;
+    conn.fin_queued = true;
+    conn.output(false);
+    Ok(())
+}
+
+/// The socket is gone, so nobody is left to read and we just close our side
+pub fn close(key: ConnectionKey) {
+    let mut connections = CONNECTIONS.lock();
+    let Some(conn) = connections.get_mut(&key) else {
+        return;
+    };
+    conn.client = None;
+    conn.fin_queued = true;
+    if conn.state == State::SynSent {
+        connections.remove(&key);
+    } else {
+        conn.output(false);
+    }
+}
+
+/// Every connection, and every bound port that doesn't have one
+pub fn sockets() -> Vec<SocketInfo> {
+    let connections = CONNECTIONS.lock();
+    let bound = BOUND.lock();
+    let listeners = LISTENERS.lock();
+    let listening = listeners.keys().map(|p| SocketInfo {
+        kind: SocketKind::Stream,
+        local_port: *p,
+        remote: None,
+        state: SocketState::Listen,
+        stats: SocketStats::default(),
+    });
+    let idle = bound
+        .iter()
+        .filter(|p| !listeners.contains_key(p) && !connections.keys().any(|k| k.local_port == **p))
+        .map(|p| SocketInfo {
+            kind: SocketKind::Stream,
+            local_port: *p,
+            remote: None,
+            state: SocketState::Bound,
+            stats: SocketStats::default(),
+        });
+    connections
+        .values()
+        .map(|c| SocketInfo {
+            kind: SocketKind::Stream,
+            local_port: c.key.local_port,
+            remote: Some((c.key.remote, c.key.remote_port)),
+            state: c.state.into(),
+            stats: c.stats,
+        })
+        .chain(listening)
+        .chain(idle)
+        .collect()
+}
+
+/// Retransmits and ends TIME_WAIT
+pub fn timer_task() {
+    loop {
+        sleep(TICK);
+        let now = uptime();
+        CONNECTIONS.lock().retain(|_, c| !c.timeout(now));
+    }
+}
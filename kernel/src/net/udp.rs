@@ -0,0 +1,141 @@
+//! User datagram protocol. Each bound port belongs to a socket, datagrams for ports nobody
+//! has bound are dropped.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use kernel_userspace::{
+    net::{IPAddr, NetError, SocketEvent, SocketInfo, SocketKind, SocketState, SocketStats},
+    object::KernelReference,
+};
+
+use crate::{mutex::Mutex, random::random_below};
+
+use super::{
+    dhcp,
+    interface::Interface,
+    ip::{self, checksum, pseudo_header_sum, PROTOCOL_UDP},
+    mdns, socket,
+};
+
+const HEADER_LEN: usize = 8;
+const EPHEMERAL_PORTS: u16 = 49152;
+
+struct Socket {
+    client: KernelReference,
+    stats: SocketStats,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Socket>> = Mutex::new(BTreeMap::new());
+
+pub fn handle_datagram(iface: &Interface, src: IPAddr, dst: IPAddr, data: &[u8]) {
+    if data.len() < HEADER_LEN {
+        return;
+    }
+    let len = u16::from_be_bytes([data[4], data[5]]) as usize;
+    if len < HEADER_LEN || len > data.len() {
+        trace!("Dropping malformed udp datagram from {src}");
+        return;
+    }
+    let data = &data[..len];
+    // A checksum of 0 means the sender didn't compute one, which ipv6 doesn't allow
+    if (u16::from_be_bytes([data[6], data[7]]) != 0 || src.is_ipv6())
+        && checksum(data, pseudo_header_sum(src, dst, PROTOCOL_UDP, len)) != 0
+    {
+        trace!("Dropping udp datagram with a bad checksum from {src}");
+        return;
+    }
+
+    let src_port = u16::from_be_bytes([data[0], data[1]]);
+    let dst_port = u16::from_be_bytes([data[2], data[3]]);
+    // Each interface has a dhcp client of its own, so their replies go by interface
+    if dst_port == dhcp::CLIENT_PORT && !dst.is_ipv6() {
+        dhcp::deliver(iface, &data[HEADER_LEN..]);
+        return;
+    }
+    if dst_port == mdns::PORT {
+        mdns::handle_message(iface, src, src_port, &data[HEADER_LEN..]);
+        return;
+    }
+    let mut sockets = SOCKETS.lock();
+    let Some(socket) = sockets.get_mut(&dst_port) else {
+        trace!("Dropping udp datagram for unbound port {dst_port}");
+        return;
+    };
+
+    let payload = &data[HEADER_LEN..];
+    socket::event(
+        &socket.client,
+        &SocketEvent::Datagram(src, src_port, payload),
+    );
+    socket.stats.packets_received += 1;
+    socket.stats.bytes_received += payload.len() as u64;
+}
+
+pub fn send(src_port: u16, dst: IPAddr, dst_port: u16, data: &[u8]) {
+    let datagram = datagram(ip::source(dst), src_port, dst, dst_port, data);
+    ip::send(dst, PROTOCOL_UDP, &datagram);
+    if let Some(socket) = SOCKETS.lock().get_mut(&src_port) {
+        socket.stats.packets_sent += 1;
+        socket.stats.bytes_sent += data.len() as u64;
+    }
+}
+
+/// Builds a datagram with its checksum filled in
+pub fn datagram(src: IPAddr, src_port: u16, dst: IPAddr, dst_port: u16, data: &[u8]) -> Vec<u8> {
+    let len = HEADER_LEN + data.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&src_port.to_be_bytes());
+    datagram.extend_from_slice(&dst_port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]); // checksum
+    datagram.extend_from_slice(data);
+
+    let sum = match checksum(&datagram, pseudo_header_sum(src, dst, PROTOCOL_UDP, len)) {
+        // 0 would mean no checksum
+        0 => 0xFFFF,
+        s => s,
+    };
+    datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+    datagram
+}
+
+/// Binds the port to the socket's client, 0 picks a free port. Returns the port that was
+/// bound.
+pub fn bind(port: u16, client: KernelReference) -> Result<u16, NetError> {
+    let mut sockets = SOCKETS.lock();
+    let port = if port == 0 {
+        (0..16)
+            .map(|_| EPHEMERAL_PORTS + random_below((u16::MAX - EPHEMERAL_PORTS) as u64) as u16)
+            .find(|p| !sockets.contains_key(p))
+            .ok_or(NetError::NoFreePorts)?
+    } else if sockets.contains_key(&port) {
+        return Err(NetError::AddrInUse);
+    } else {
+        port
+    };
+    sockets.insert(
+        port,
+        Socket {
+            client,
+            stats: SocketStats::default(),
+        },
+    );
+    Ok(port)
+}
+
+pub fn unbind(port: u16) {
+    SOCKETS.lock().remove(&port);
+}
+
+pub fn sockets() -> Vec<SocketInfo> {
+    SOCKETS
+        .lock()
+        .iter()
+        .map(|(port, socket)| SocketInfo {
+            kind: SocketKind::Datagram,
+            local_port: *port,
+            remote: None,
+            state: SocketState::Bound,
+            stats: socket.stats,
+        })
+        .collect()
+}
@@ -86,6 +86,9 @@ pub trait KObject {
     fn signals<T>(&self, f: impl FnOnce(&mut KObjectSignal) -> T) -> T;
 }
 
+/// Set in the port key of a published handle's close notification, the rest is its id
+const PUBLISHED_KEY: u64 = 1 << 63;
+
 pub fn init_handle_new_proc(channels: Vec<KernelReference>) {
     let port_handle = KernelReference::from_id(port_create());
 
@@ -111,6 +114,11 @@ pub fn init_handle_new_proc(channels: Vec<KernelReference>) {
     loop {
         port_wait(port_handle.id(), &mut notification);
         match notification.ty {
+            // The publisher has gone, like a driver that died. It is forgotten so connecting
+            // waits for whatever publishes the name next.
+            PortNotificationType::SignalOne { .. } if notification.key & PUBLISHED_KEY != 0 => {
+                handles.retain(|_, h| published_key(h) != notification.key);
+            }
             PortNotificationType::SignalOne { .. } => {
                 let chan = chans.get(&notification.key).unwrap().id();
                 if work_on_chan(chan, &mut handles, &mut chans, &port_handle) {
@@ -129,6 +137,10 @@ pub fn init_handle_new_proc(channels: Vec<KernelReference>) {
     }
 }
 
+fn published_key(handle: &KernelReference) -> u64 {
+    handle.id().0.get() as u64 | PUBLISHED_KEY
+}
+
 fn work_on_chan(
     chan: KernelReferenceID,
     refs: &mut HashMap<String, KernelReference>,
@@ -167,25 +179,34 @@ fn work_on_chan(
         };
 
         match msg {
-            InitHandleMessage::GetHandle(h) => match refs.get(h) {
-                Some(handle) => {
-                    let (left, right) = channel_create_rs();
-
-                    channel_write_rs(handle.id(), &[true as u8], &[left.id()]);
+            InitHandleMessage::GetHandle(h) => {
+                let (left, right) = channel_create_rs();
+                let connected = match refs.get(h) {
+                    Some(handle) => channel_write_rs(handle.id(), &[true as u8], &[left.id()]),
+                    None => false,
+                };
+                if connected {
                     channel_write_rs(chan, &[true as u8], &[right.id()]);
-                }
-                None => {
+                } else {
+                    // The publisher may have gone before its close was seen
+                    refs.remove(h);
                     channel_write_rs(chan, &[false as u8], &[]);
                 }
-            },
+            }
             InitHandleMessage::PublishHandle(name) => {
                 if read.handles_len != 1 {
                     warn!("bad handles len");
                     return false;
                 }
 
-                let publisher = unsafe { handles.assume_init() };
-                let old = refs.insert(name.to_string(), KernelReference::from_id(publisher));
+                let publisher = KernelReference::from_id(unsafe { handles.assume_init() });
+                object_wait_port_rs(
+                    publisher.id(),
+                    port_handle.id(),
+                    ObjectSignal::CHANNEL_CLOSED,
+                    published_key(&publisher),
+                );
+                let old = refs.insert(name.to_string(), publisher);
 
                 channel_write_rs(chan, &[old.is_some() as u8], &[]);
             }
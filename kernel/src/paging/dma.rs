@@ -0,0 +1,71 @@
+//! Pinned memory for devices to access directly.
+
+use alloc::{boxed::Box, vec::Vec};
+use kernel_userspace::dma::{DmaConstraints, DmaSegment};
+
+use super::{
+    page::Page,
+    page_allocator::{frame_alloc_exec, pages_in_order, Zone, MAX_ORDER},
+    AllocatedPage, GlobalPageAllocator,
+};
+
+pub type DmaPages = Box<[AllocatedPage<GlobalPageAllocator>]>;
+
+/// Largest single allocation, dma memory is pinned so one can't be allowed to take everything
+pub const MAX_DMA_SIZE: usize = 64 * 1024 * 1024;
+
+/// Allocates size bytes (rounded up to pages) as physically contiguous segments that meet the
+/// constraints, segments are as large as the boundary allows. Only the first segment is aligned
+/// to more than a page.
+pub fn alloc_dma(size: usize, constraints: &DmaConstraints) -> Option<(DmaPages, Vec<DmaSegment>)> {
+    let mut remaining = size.div_ceil(0x1000);
+    let max_block = pages_in_order(MAX_ORDER);
+
+    let align = constraints.align.max(0x1000);
+    let boundary = match constraints.boundary {
+        0 => max_block * 0x1000,
+        b => b,
+    };
+    if remaining == 0
+        || size > MAX_DMA_SIZE
+        || !align.is_power_of_two()
+        || !boundary.is_power_of_two()
+        || boundary < 0x1000
+        || align > boundary
+        || align > max_block * 0x1000
+    {
+        return None;
+    }
+    let align = align / 0x1000;
+    let max_segment = (boundary / 0x1000).min(max_block);
+
+    let zone = match constraints.below_4g {
+        true => Zone::Below32,
+        false => Zone::Normal,
+    };
+
+    // Pages are owned straight away so that a failure frees everything allocated so far
+    let mut pages = Vec::with_capacity(remaining);
+    let mut segments = Vec::new();
+    while remaining > 0 {
+        if segments.len() == constraints.max_segments {
+            return None;
+        }
+        let count = remaining.min(max_segment);
+        let align = if segments.is_empty() { align } else { 1 };
+        let base = frame_alloc_exec(|a| a.allocate_pages_aligned(zone, count, align))?;
+
+        pages.extend((0..count as u64).map(|i| unsafe {
+            AllocatedPage::from_raw(
+                Page::new(base.get_address() + i * 0x1000),
+                GlobalPageAllocator,
+            )
+        }));
+        segments.push(DmaSegment {
+            phys: base.get_address(),
+            len: count * 0x1000,
+        });
+        remaining -= count;
+    }
+    Some((pages.into(), segments))
+}
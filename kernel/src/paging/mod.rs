@@ -9,12 +9,17 @@ use crate::{cpu_localstorage::CPULocalStorageRW, mutex::Spinlock};
 
 use self::page::Page;
 
+pub mod dma;
 pub mod offset_map;
 pub mod page;
 pub mod page_allocator;
 pub mod page_directory;
 pub mod page_mapper;
 pub mod page_table;
+pub mod pat;
+pub mod pressure;
+pub mod swap;
+pub mod tlb;
 
 /// KERNEL map for context 0 / scheduler
 pub static KERNEL_LVL4: Lazy<Spinlock<PageTable<TableLevel4>>> =
@@ -52,6 +57,16 @@ bitflags::bitflags! {
     pub struct MemoryMappingFlags: u8 {
         const WRITEABLE  = 1 << 0;
         const USERSPACE  = 1 << 1;
+        const EXECUTABLE = 1 << 2;
+        /// For framebuffers, see [`pat`]
+        const WRITE_COMBINING = 1 << 3;
+    }
+}
+
+impl MemoryMappingFlags {
+    /// Mappings can't be both writeable and executable (W^X)
+    pub fn is_valid(&self) -> bool {
+        !self.contains(Self::WRITEABLE | Self::EXECUTABLE)
     }
 }
 
@@ -3,7 +3,7 @@ use bootloader::{gop::GopInfo, BootInfo};
 use conquer_once::spin::OnceCell;
 
 use crate::{
-    kernel_memory_loc,
+    kernel_memory_loc, kernel_text_end,
     memory::MemoryMapIter,
     paging::{
         page::{get_chunked_page_range, Page},
@@ -97,7 +97,7 @@ pub unsafe fn map_gop(
             .identity_map(
                 alloc,
                 Page::<Size4KB>::new(i),
-                MemoryMappingFlags::WRITEABLE,
+                MemoryMappingFlags::WRITEABLE | MemoryMappingFlags::WRITE_COMBINING,
             )
             .unwrap()
             .ignore();
@@ -128,15 +128,22 @@ pub unsafe fn create_kernel_map(
     let base = boot_info.kernel_start;
     let pages = boot_info.kernel_pages;
     let (kern_base, _) = kernel_memory_loc();
+    let text_end = kernel_text_end();
 
-    assert!(kern_base == MemoryLoc::KernelStart as u64);
+    assert!(kern_base == MemoryLoc::KernelStart as u64 + boot_info.kernel_slide);
     for i in (0..pages * 0x1000).step_by(0x1000) {
+        // The code is read only, everything else is no execute
+        let flags = if kern_base + i < text_end {
+            MemoryMappingFlags::EXECUTABLE
+        } else {
+            MemoryMappingFlags::WRITEABLE
+        };
         mapper
             .map(
                 alloc,
-                Page::<Size4KB>::new(MemoryLoc::KernelStart as u64 + i),
+                Page::<Size4KB>::new(kern_base + i),
                 Page::<Size4KB>::new(base + i),
-                MemoryMappingFlags::WRITEABLE,
+                flags,
             )
             .unwrap()
             .ignore();
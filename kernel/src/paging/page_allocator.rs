@@ -1,14 +1,14 @@
+use core::ops::Range;
+
+use alloc::vec::Vec;
 use bootloader::uefi::table::boot::MemoryType;
 use conquer_once::spin::OnceCell;
 
-use crate::{
-    memory::{MemoryMapIter, RESERVED_32BIT_MEM_PAGES},
-    mutex::Spinlock,
-};
+use crate::{memory::MemoryMapIter, mutex::Spinlock};
 
 use super::{
-    page::{Page, Size4KB},
-    virt_addr_for_phys, virt_addr_offset_mut, PageAllocator,
+    page::{Page, PageSize, Size2MB, Size4KB},
+    virt_addr_for_phys, virt_addr_offset, virt_addr_offset_mut, PageAllocator,
 };
 
 static GLOBAL_FRAME_ALLOCATOR: OnceCell<Spinlock<PageFrameAllocator>> = OnceCell::uninit();
@@ -29,107 +29,174 @@ pub unsafe fn init(mmap: MemoryMapIter) {
     GLOBAL_FRAME_ALLOCATOR.init_once(|| alloc)
 }
 
-pub struct AllocatedPageOrder {
-    order: usize,
-    base: usize,
+/// Frees the memory used by the bootloader and UEFI boot services, except for the page frames in
+/// keep. The memory map itself lives in that memory so it can't be used afterwards. Returns the
+/// number of pages freed.
+pub unsafe fn reclaim_boot_memory(mmap: MemoryMapIter, keep: &mut [Range<usize>]) -> usize {
+    // Collected before taking the lock as allocating might need more pages
+    let regions: Vec<_> = mmap
+        .map(|e| &*virt_addr_offset(e))
+        .filter(|e| is_ram(e.ty) && e.ty != MemoryType::CONVENTIONAL)
+        .map(|e| {
+            let start = e.phys_start as usize / 0x1000;
+            start..start + e.page_count as usize
+        })
+        .collect();
+    keep.sort_by_key(|h| h.start);
+
+    frame_alloc_exec(|a| {
+        let before = a.free_pages();
+        for range in &regions {
+            a.free_range_except(range.clone(), keep);
+        }
+        a.free_pages() - before
+    })
 }
 
-impl AllocatedPageOrder {
-    pub fn base(&self) -> usize {
-        self.base
+// This counts a 1gb block
+pub const MAX_ORDER: usize = 18;
+
+const ORDER_2MB: usize = (Size2MB::PAGE_SIZE / Size4KB::PAGE_SIZE).ilog2() as usize;
+
+/// Page frames below 4gb
+const BELOW_32_PAGES: usize = (1 << 32) / 0x1000;
+
+/// Marks a page that isn't the first page of a free block
+const NOT_FREE: u8 = u8::MAX;
+
+#[inline(always)]
+pub fn pages_in_order(order: usize) -> usize {
+    1 << order
+}
+
+/// Memory that is or can later become usable
+fn is_ram(ty: MemoryType) -> bool {
+    matches!(
+        ty,
+        MemoryType::CONVENTIONAL
+            | MemoryType::LOADER_CODE
+            | MemoryType::LOADER_DATA
+            | MemoryType::BOOT_SERVICES_CODE
+            | MemoryType::BOOT_SERVICES_DATA
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Memory below 4gb, for devices that can only do 32bit DMA
+    Below32,
+    Normal,
+}
+
+impl Zone {
+    fn of(pfn: usize) -> Self {
+        if pfn < BELOW_32_PAGES {
+            Zone::Below32
+        } else {
+            Zone::Normal
+        }
     }
 }
 
-pub struct PageMetadata {
-    order: usize,
-    // These fields only matter if it is the left node
-    prev_node: Option<*mut PageMetadata>,
-    next_node: Option<*mut PageMetadata>,
+/// Stored in the first page of each free block
+#[derive(Clone, Copy)]
+struct FreeBlock {
+    prev: Option<usize>,
+    next: Option<usize>,
 }
 
-pub struct PageMetadata32 {
-    next_node: Option<*mut PageMetadata32>,
+#[derive(Default)]
+struct ZoneFreeLists {
+    /// Page frame number of the first free block of each order
+    heads: [Option<usize>; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    free_pages: usize,
 }
 
-// This counts a 1gb zone
-const MAX_ORDER: usize = 18;
+#[derive(Debug, Clone)]
+pub struct FrameAllocatorStats {
+    /// Pages the allocator was given at boot
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub free_pages_below_32: usize,
+    /// Number of free blocks of each order
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
 
-/// TODO: Implement a bitmap to determine when we can coalese blocks back together
+impl FrameAllocatorStats {
+    /// Order of the largest free block, a low order with lots of free pages means memory is
+    /// fragmented
+    pub fn largest_free_order(&self) -> Option<usize> {
+        self.free_blocks.iter().rposition(|&c| c > 0)
+    }
+}
 
+/// Buddy allocator for physical memory.
+///
+/// Blocks are aligned to their size and when a block is freed it is merged with its buddy (the
+/// other half of the block one order higher) for as long as the buddy is free.
 pub struct PageFrameAllocator {
-    free_lists: [Option<*mut PageMetadata>; MAX_ORDER + 1],
-    reserved_32bit: Option<*mut PageMetadata32>,
+    zones: [ZoneFreeLists; 2],
+    /// Physical address of a byte for every page frame, holding the order of the free block that
+    /// starts at that page or [`NOT_FREE`]
+    orders: *mut u8,
+    page_count: usize,
+    /// Free pages after boot, the most that can ever be free
+    total_pages: usize,
 
     // we reserve 0x8000 specifically for the purpose of booting AP's
     captured_0x8000: bool,
-    total_free: usize,
 }
 
 unsafe impl Send for PageFrameAllocator {}
 
-#[inline(always)]
-pub fn pages_in_order(order: usize) -> usize {
-    1 << order
-}
-
 impl PageFrameAllocator {
     pub unsafe fn new(mmap: MemoryMapIter) -> Self {
+        let entries = || mmap.clone().map(|e| &*e);
+        let conventional = || entries().filter(|e| e.ty == MemoryType::CONVENTIONAL);
+
+        let page_count = entries()
+            .filter(|e| is_ram(e.ty))
+            .map(|e| (e.phys_start / 0x1000 + e.page_count) as usize)
+            .max()
+            .unwrap();
+
+        // Take the memory for the orders from the first region that is large enough
+        let orders_pages = page_count.div_ceil(0x1000);
+        let orders_start = conventional()
+            .find(|e| e.phys_start > 0x8000 && e.page_count as usize >= orders_pages)
+            .expect("no memory for the frame allocator")
+            .phys_start as usize
+            / 0x1000;
+
+        let orders = (orders_start * 0x1000) as *mut u8;
+        core::ptr::write_bytes(virt_addr_offset_mut(orders), NOT_FREE, page_count);
+
         let mut this = Self {
-            free_lists: Default::default(),
+            zones: Default::default(),
+            orders,
+            page_count,
+            total_pages: 0,
             captured_0x8000: false,
-            reserved_32bit: None,
-            total_free: 0,
         };
 
-        let mut free = mmap
-            .map(|e| &*e)
-            .filter(|e| e.ty == MemoryType::CONVENTIONAL);
-
-        // Capture the reserved pages
-        let mut free_found = 0;
-        loop {
-            let entry = free.by_ref().next().unwrap();
-
-            let range = entry.phys_start as usize
-                ..(entry.phys_start + (entry.page_count * 0x1000)) as usize;
-
-            let pages = if range.contains(&0x8000) {
+        let mut holes = [
+            // The zero page can't be used
+            0..1,
+            orders_start..orders_start + orders_pages,
+            0x8000 / 0x1000..0x8000 / 0x1000 + 1,
+        ];
+        holes.sort_by_key(|h| h.start);
+
+        for entry in conventional() {
+            let start = entry.phys_start as usize / 0x1000;
+            let range = start..start + entry.page_count as usize;
+            if range.contains(&(0x8000 / 0x1000)) {
                 this.captured_0x8000 = true;
-                entry.page_count as usize - 1
-            } else {
-                entry.page_count as usize
-            };
-
-            let taken_amount = core::cmp::min(pages, RESERVED_32BIT_MEM_PAGES - free_found);
-            free_found += taken_amount;
-
-            // Add the pages to the reserved region
-            range
-                .step_by(0x1000)
-                .take(taken_amount)
-                .filter(|&p| p != 0x8000)
-                .for_each(|p| this.free_32bit_reserved_page(p));
-
-            if free_found == RESERVED_32BIT_MEM_PAGES {
-                let amount_left = entry.page_count as usize - taken_amount;
-                this.total_free += amount_left;
-                this.insert_free_of_range(
-                    entry.phys_start as usize + taken_amount * 0x1000,
-                    amount_left,
-                );
-                break;
-            } else if free_found > RESERVED_32BIT_MEM_PAGES {
-                unreachable!("logic error")
             }
+            this.free_range_except(range, &holes);
         }
-
-        for entry in free {
-            let start_addr = entry.phys_start as usize;
-            let pages_left = entry.page_count as usize;
-
-            this.total_free += pages_left;
-            this.insert_free_of_range(start_addr, pages_left);
-        }
+        this.total_pages = this.free_pages();
         this
     }
 
@@ -137,136 +204,203 @@ impl PageFrameAllocator {
         self.captured_0x8000
     }
 
-    // This is safe to call with zero pages
-    unsafe fn insert_free_of_range(&mut self, mut start_addr: usize, mut pages_left: usize) {
-        while pages_left > 0 {
-            // Find the largest order that we can use
-            let pages_order = pages_left.ilog2() as usize;
-            let address_order = (start_addr / 0x1000).ilog2() as usize;
-            let order = core::cmp::min(core::cmp::min(pages_order, address_order), MAX_ORDER);
+    /// Number of free pages in all zones
+    pub fn free_pages(&self) -> usize {
+        self.zones.iter().map(|z| z.free_pages).sum()
+    }
 
-            self.insert_free_of_order(start_addr, order);
+    pub fn stats(&self) -> FrameAllocatorStats {
+        let mut free_blocks = [0; MAX_ORDER + 1];
+        for zone in &self.zones {
+            for (total, count) in free_blocks.iter_mut().zip(zone.free_blocks) {
+                *total += count;
+            }
+        }
 
-            let page_count = pages_in_order(order);
-            pages_left -= page_count;
-            start_addr += page_count * 0x1000;
+        FrameAllocatorStats {
+            total_pages: self.total_pages,
+            free_pages: self.free_pages(),
+            free_pages_below_32: self.zones[Zone::Below32 as usize].free_pages,
+            free_blocks,
         }
     }
 
-    unsafe fn insert_free_of_order(&mut self, base: usize, order: usize) {
-        let left = &mut *virt_addr_offset_mut(base as *mut PageMetadata);
-        left.order = order;
-        left.next_node = None;
-        left.prev_node = None;
-
-        // if order is 0, we only have 1 page
-        if order > 0 {
-            let last_page_addr_offset = (pages_in_order(order) - 1) * 0x1000;
-            let right =
-                &mut *virt_addr_offset_mut((base + last_page_addr_offset) as *mut PageMetadata);
-            right.order = order;
-        }
+    fn order_of(&self, pfn: usize) -> u8 {
+        unsafe { *virt_addr_offset_mut(self.orders).add(pfn) }
+    }
 
-        left.next_node = self.free_lists[order].take();
-        self.free_lists[order] = Some(base as *mut PageMetadata);
+    fn set_order(&mut self, pfn: usize, order: u8) {
+        unsafe { *virt_addr_offset_mut(self.orders).add(pfn) = order }
     }
 
-    // currently splits the left
-    pub fn request_page_of_order(&mut self, order: usize) -> Option<AllocatedPageOrder> {
-        if order > MAX_ORDER {
-            return None;
-        }
+    fn block(pfn: usize) -> &'static mut FreeBlock {
+        unsafe { &mut *(virt_addr_for_phys(pfn as u64 * 0x1000) as *mut FreeBlock) }
+    }
 
-        let base = if let Some(block) = self.free_lists[order] {
-            let b = unsafe { &mut *virt_addr_offset_mut(block) };
+    fn push_free(&mut self, pfn: usize, order: usize) {
+        let zone = &mut self.zones[Zone::of(pfn) as usize];
+        let next = zone.heads[order];
 
-            if let Some(nxt) = b.next_node {
-                let nxt = unsafe { &mut *virt_addr_offset_mut(nxt) };
-                nxt.prev_node = None;
-            }
+        *Self::block(pfn) = FreeBlock { prev: None, next };
+        if let Some(next) = next {
+            Self::block(next).prev = Some(pfn);
+        }
+        zone.heads[order] = Some(pfn);
+        zone.free_blocks[order] += 1;
+        zone.free_pages += pages_in_order(order);
+        self.set_order(pfn, order as u8);
+    }
 
-            self.free_lists[order] = b.next_node;
-            block as usize
-        } else {
-            // Request a larger block and split it
-            let large_block = self.request_page_of_order(order + 1)?;
-            unsafe {
-                self.insert_free_of_range(
-                    large_block.base + pages_in_order(order) * 0x1000,
-                    pages_in_order(order + 1) - pages_in_order(order),
-                )
-            };
-            large_block.base
-        };
+    fn remove_free(&mut self, pfn: usize, order: usize) {
+        let zone = &mut self.zones[Zone::of(pfn) as usize];
+        let FreeBlock { prev, next } = *Self::block(pfn);
 
-        Some(AllocatedPageOrder { order, base })
+        match prev {
+            Some(prev) => Self::block(prev).next = next,
+            None => zone.heads[order] = next,
+        }
+        if let Some(next) = next {
+            Self::block(next).prev = prev;
+        }
+        zone.free_blocks[order] -= 1;
+        zone.free_pages -= pages_in_order(order);
+        self.set_order(pfn, NOT_FREE);
     }
 
-    pub unsafe fn free_32bit_reserved_page(&mut self, page: usize) {
-        let meta = &mut *virt_addr_offset_mut(page as *mut PageMetadata32);
+    /// Frees a block, merging it with its buddy while the buddy is also free.
+    /// Zones never share buddies as the 4gb boundary is aligned to a larger order than MAX_ORDER.
+    unsafe fn free_block(&mut self, mut pfn: usize, mut order: usize) {
+        assert!(pfn + pages_in_order(order) <= self.page_count);
+        assert_eq!(self.order_of(pfn), NOT_FREE, "double free of page {pfn:#x}");
 
-        if let Some(p) = self.reserved_32bit {
-            meta.next_node = Some(p);
-            self.reserved_32bit = Some(page as *mut PageMetadata32);
-        } else {
-            self.reserved_32bit = Some(page as *mut PageMetadata32);
+        while order < MAX_ORDER {
+            let buddy = pfn ^ pages_in_order(order);
+            if buddy >= self.page_count || self.order_of(buddy) != order as u8 {
+                break;
+            }
+            self.remove_free(buddy, order);
+            pfn = pfn.min(buddy);
+            order += 1;
         }
+        self.push_free(pfn, order);
     }
 
-    pub fn free_page_of_order(&mut self, pages: AllocatedPageOrder) {
-        unsafe { self.insert_free_of_order(pages.base, pages.order) }
+    /// Frees a range of pages by splitting it into aligned blocks, this is safe to call with zero
+    /// pages
+    unsafe fn free_range(&mut self, mut pfn: usize, mut count: usize) {
+        while count > 0 {
+            let order = (count.ilog2() as usize)
+                .min(pfn.trailing_zeros() as usize)
+                .min(MAX_ORDER);
+            self.free_block(pfn, order);
+
+            pfn += pages_in_order(order);
+            count -= pages_in_order(order);
+        }
     }
 
-    pub unsafe fn free_page(&mut self, page: Page<Size4KB>) {
-        unsafe { self.insert_free_of_order(page.get_address() as usize, 0) }
+    /// Frees a range of pages skipping over the holes, which must be sorted
+    unsafe fn free_range_except(&mut self, range: Range<usize>, holes: &[Range<usize>]) {
+        let mut start = range.start;
+        for hole in holes {
+            if hole.end <= start || hole.start >= range.end {
+                continue;
+            }
+            if hole.start > start {
+                self.free_range(start, hole.start - start);
+            }
+            start = hole.end;
+        }
+        if start < range.end {
+            self.free_range(start, range.end - start);
+        }
     }
 
-    pub fn allocate_page(&mut self) -> Option<Page<Size4KB>> {
-        let base = self.request_page_of_order(0)?.base as u64;
+    /// Takes a block of exactly order from the zone, splitting a larger block if needed
+    fn take_block(&mut self, zone: Zone, order: usize) -> Option<usize> {
+        let lists = &self.zones[zone as usize];
+        let found = (order..=MAX_ORDER).find(|&o| lists.heads[o].is_some())?;
+        let pfn = lists.heads[found].unwrap();
+        self.remove_free(pfn, found);
 
-        unsafe { core::ptr::write_bytes(virt_addr_for_phys(base) as *mut u8, 0, 0x1000) };
+        // Give back the upper halves until the block is the right size
+        for o in (order..found).rev() {
+            self.push_free(pfn + pages_in_order(o), o);
+        }
+        Some(pfn)
+    }
 
-        Some(Page::new(base))
+    /// Allocates a block, normal allocations fall back to memory below 4gb once there is no other
+    /// memory left
+    fn allocate_block(&mut self, zone: Zone, order: usize) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        match zone {
+            Zone::Normal => self
+                .take_block(Zone::Normal, order)
+                .or_else(|| self.take_block(Zone::Below32, order)),
+            Zone::Below32 => self.take_block(Zone::Below32, order),
+        }
+    }
+
+    /// Allocates count physically contiguous zeroed pages
+    pub fn allocate_pages_in(&mut self, zone: Zone, count: usize) -> Option<Page<Size4KB>> {
+        self.allocate_pages_aligned(zone, count, 1)
     }
 
-    pub fn allocate_page_32bit(&mut self) -> Option<Page<Size4KB>> {
-        let block = self.reserved_32bit?;
-        let b = unsafe { &mut *virt_addr_offset_mut(block) };
-        let base = block as *const _ as u64;
-        self.reserved_32bit = b.next_node;
-        unsafe { core::ptr::write_bytes(virt_addr_for_phys(base) as *mut u8, 0, 0x1000) };
+    /// Allocates count physically contiguous zeroed pages starting at a multiple of align pages,
+    /// which has to be a power of two. The pages never cross a multiple of count rounded up to a
+    /// power of two.
+    pub fn allocate_pages_aligned(
+        &mut self,
+        zone: Zone,
+        count: usize,
+        align: usize,
+    ) -> Option<Page<Size4KB>> {
+        assert!(count > 0 && align.is_power_of_two());
+        let order = count.next_power_of_two().max(align).ilog2() as usize;
+        let pfn = self.allocate_block(zone, order)?;
+
+        // Give back the pages we don't need
+        unsafe { self.free_range(pfn + count, pages_in_order(order) - count) };
+
+        let base = pfn as u64 * 0x1000;
+        unsafe { core::ptr::write_bytes(virt_addr_for_phys(base) as *mut u8, 0, count * 0x1000) };
         Some(Page::new(base))
     }
 
+    pub fn allocate_page(&mut self) -> Option<Page<Size4KB>> {
+        self.allocate_pages_in(Zone::Normal, 1)
+    }
+
     pub fn allocate_pages(&mut self, count: usize) -> Option<Page<Size4KB>> {
-        // Returns the log 2 rounded down
-        let order = count.ilog2() as usize;
+        self.allocate_pages_in(Zone::Normal, count)
+    }
 
-        let base = if pages_in_order(order) == count {
-            // The count is a block size
-            self.request_page_of_order(order)?.base
-        } else {
-            // Split a larger block
-            let large_block = self.request_page_of_order(order + 1)?;
-
-            unsafe {
-                self.insert_free_of_range(
-                    large_block.base + count * 0x1000,
-                    pages_in_order(order + 1) - count,
-                )
-            }
+    pub unsafe fn free_page(&mut self, page: Page<Size4KB>) {
+        self.free_block(page.get_address() as usize / 0x1000, 0)
+    }
 
-            large_block.base
-        };
+    pub unsafe fn free_page_range(&mut self, page: Page<Size4KB>, count: usize) {
+        self.free_range(page.get_address() as usize / 0x1000, count)
+    }
+
+    /// Allocates a 2mb page, which is always aligned to 2mb
+    pub fn allocate_page_2mb(&mut self) -> Option<Page<Size2MB>> {
+        let base = self.allocate_block(Zone::Normal, ORDER_2MB)? as u64 * 0x1000;
         unsafe {
             core::ptr::write_bytes(
-                virt_addr_for_phys(base as u64) as *mut u8,
+                virt_addr_for_phys(base) as *mut u8,
                 0,
-                count * 0x1000,
+                Size2MB::PAGE_SIZE as usize,
             )
         };
+        Some(Page::new(base))
+    }
 
-        Some(Page::new(base as u64))
+    pub unsafe fn free_page_2mb(&mut self, page: Page<Size2MB>) {
+        self.free_block(page.get_address() as usize / 0x1000, ORDER_2MB)
     }
 }
 
@@ -284,9 +418,6 @@ impl PageAllocator for Spinlock<PageFrameAllocator> {
     }
 
     unsafe fn free_pages(&self, page: Page<Size4KB>, count: usize) {
-        let mut this = self.lock();
-        for p in 0..count {
-            this.free_page(Page::new(page.get_address() + p as u64 * 0x1000));
-        }
+        self.lock().free_page_range(page, count);
     }
 }
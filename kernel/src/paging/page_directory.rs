@@ -1,6 +1,6 @@
 use modular_bitfield::{
     bitfield,
-    specifiers::{B12, B3, B40},
+    specifiers::{B11, B3, B40},
 };
 
 #[bitfield(bits = 64)]
@@ -17,7 +17,9 @@ pub struct PageDirectoryEntry {
     pub available: B3,
     internal_address: B40,
     #[skip]
-    _reserved: B12,
+    _reserved: B11,
+    /// Requires EFER.NXE to be set, otherwise the bit is reserved
+    pub no_execute: bool,
 }
 
 impl PageDirectoryEntry {
@@ -1,14 +1,27 @@
 use core::{cmp::Ordering, fmt::Debug, ops::Range};
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
 
-use crate::{mutex::Spinlock, paging::page_table::Mapper};
+use crate::{
+    fs::{
+        page_cache::{self, CachePage},
+        VFileID,
+    },
+    iommu::DmaGrant,
+    mutex::Spinlock,
+    paging::page_table::Mapper,
+    random::random_below,
+};
 
 use super::{
-    page::{Page, Size4KB},
+    dma::DmaPages,
+    page::{Page, PageSize, Size2MB, Size4KB},
     page_allocator::global_allocator,
     page_table::{PageTable, TableLevel4, UnMapMemoryError},
-    AllocatedPage, GlobalPageAllocator, MemoryLoc, MemoryMappingFlags, PageAllocator,
+    swap::{self, SwapSlot},
+    tlb::TlbShootdown,
+    virt_addr_for_phys, AllocatedPage, GlobalPageAllocator, MemoryLoc, MemoryMappingFlags,
+    PageAllocator,
 };
 
 pub struct PageMapperManager {
@@ -16,8 +29,15 @@ pub struct PageMapperManager {
     // start offset, end offset, mapping
     // this should always be ordered
     mappings: Vec<(Range<usize>, Arc<PageMapping>, MemoryMappingFlags)>,
+    mapped_pages: usize,
+    peak_mapped_pages: usize,
+    /// Randomize where [`Self::insert_mapping`] places mappings
+    aslr: bool,
 }
 
+/// How far past the start of a free region a mapping can be placed with ASLR enabled (64gb)
+const ASLR_MMAP_PAGES: u64 = 0x100_0000;
+
 #[derive(Debug)]
 pub struct PageMapping {
     size: usize,
@@ -29,9 +49,51 @@ impl PageMapping {
         self.size
     }
 
+    /// Number of pages that are backed by physical memory
+    pub fn committed_pages(&self) -> usize {
+        match &self.mapping {
+            PageMappingType::MMAP { .. } => self.size / 0x1000,
+            PageMappingType::Dma { pages, .. } => pages.len(),
+            PageMappingType::LazyMapping { pages, .. } => {
+                pages.lock().iter().filter(|p| p.is_some()).count()
+            }
+            PageMappingType::FileBacked { pages, .. } => {
+                pages.lock().iter().filter(|p| p.is_some()).count()
+            }
+        }
+    }
+
+    /// Number of committed pages that are also referenced elsewhere. Every page is shared if the
+    /// mapping itself is.
+    fn shared_pages(&self, mapping_shared: bool) -> usize {
+        match &self.mapping {
+            PageMappingType::MMAP { .. } => 0,
+            PageMappingType::Dma { pages, .. } if mapping_shared => pages.len(),
+            PageMappingType::Dma { .. } => 0,
+            PageMappingType::LazyMapping { pages, .. } => pages
+                .lock()
+                .iter()
+                .flatten()
+                .filter(|p| mapping_shared || Arc::strong_count(p) > 1)
+                .count(),
+            // Always shared with the page cache
+            PageMappingType::FileBacked { pages, .. } => {
+                pages.lock().iter().filter(|p| p.is_some()).count()
+            }
+        }
+    }
+
+    pub fn is_dma(&self) -> bool {
+        matches!(self.mapping, PageMappingType::Dma { .. })
+    }
+
+    pub fn is_mmio(&self) -> bool {
+        matches!(self.mapping, PageMappingType::MMAP { .. })
+    }
+
     pub fn base_top_stack(&self) -> usize {
         match &self.mapping {
-            PageMappingType::LazyMapping { pages } => {
+            PageMappingType::LazyMapping { pages, .. } => {
                 let mut pages = pages.lock();
                 let page = pages.last_mut().unwrap();
                 let p = match page {
@@ -39,7 +101,7 @@ impl PageMapping {
                     None => {
                         let apage = AllocatedPage::new(GlobalPageAllocator).unwrap();
                         let p = apage.get_address();
-                        *page = Some(apage);
+                        *page = Some(Arc::new(apage));
                         p
                     }
                 };
@@ -48,6 +110,40 @@ impl PageMapping {
             _ => panic!(),
         }
     }
+
+    /// Copies data into the mapping at offset, allocating any missing pages
+    pub fn write_at(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.size);
+        match &self.mapping {
+            PageMappingType::LazyMapping { pages, .. } => {
+                let mut pages = pages.lock();
+                let mut written = 0;
+                while written < data.len() {
+                    let pos = offset + written;
+                    let page = &mut pages[pos / 0x1000];
+                    let phys = match page {
+                        Some(p) => p.get_address(),
+                        None => {
+                            let apage = AllocatedPage::new(GlobalPageAllocator).unwrap();
+                            let p = apage.get_address();
+                            *page = Some(Arc::new(apage));
+                            p
+                        }
+                    };
+                    let len = (0x1000 - pos % 0x1000).min(data.len() - written);
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            data[written..].as_ptr(),
+                            (virt_addr_for_phys(phys) as usize + pos % 0x1000) as *mut u8,
+                            len,
+                        );
+                    }
+                    written += len;
+                }
+            }
+            _ => panic!(),
+        }
+    }
 }
 
 pub enum PageMappingType {
@@ -55,7 +151,23 @@ pub enum PageMappingType {
         base_address: usize,
     },
     LazyMapping {
-        pages: Spinlock<Box<[Option<AllocatedPage<GlobalPageAllocator>>]>>,
+        /// Pages are reference counted so that they can be shared copy on write
+        pages: Spinlock<Box<[Option<Arc<AllocatedPage<GlobalPageAllocator>>>]>>,
+        /// Page index to swap slot of pages that have been paged out, always locked after pages
+        swapped: Spinlock<BTreeMap<usize, SwapSlot>>,
+    },
+    /// Pinned pages for devices to access, never paged out
+    Dma {
+        /// Dropped first so the devices lose access before the pages are freed
+        grant: DmaGrant,
+        pages: DmaPages,
+    },
+    /// Pages of a file shared with the page cache, filled in on fault
+    FileBacked {
+        file: VFileID,
+        /// Page of the file that the mapping starts at
+        offset: usize,
+        pages: Spinlock<Box<[Option<CachePage>]>>,
     },
 }
 
@@ -63,7 +175,13 @@ impl Debug for PageMappingType {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Self::MMAP { base_address: _ } => f.debug_struct("MMAP").finish(),
-            Self::LazyMapping { pages: _ } => f.debug_struct("LazyMapping").finish(),
+            Self::LazyMapping { .. } => f.debug_struct("LazyMapping").finish(),
+            Self::Dma { .. } => f.debug_struct("Dma").finish(),
+            Self::FileBacked { file, offset, .. } => f
+                .debug_struct("FileBacked")
+                .field("file", file)
+                .field("offset", offset)
+                .finish(),
         }
     }
 }
@@ -73,31 +191,101 @@ impl PageMapping {
         let b: Box<_> = (0..(size + 0xFFF) / 0x1000).map(|_| None).collect();
         Arc::new(PageMapping {
             size,
-            mapping: PageMappingType::LazyMapping { pages: b.into() },
+            mapping: PageMappingType::LazyMapping {
+                pages: b.into(),
+                swapped: Default::default(),
+            },
         })
     }
 
     pub fn new_lazy_filled(size: usize) -> Arc<PageMapping> {
         let b: Box<_> = (0..(size + 0xFFF) / 0x1000)
-            .map(|_| AllocatedPage::new(GlobalPageAllocator))
+            .map(|_| AllocatedPage::new(GlobalPageAllocator).map(Arc::new))
             .collect();
         Arc::new(PageMapping {
             size,
-            mapping: PageMappingType::LazyMapping { pages: b.into() },
+            mapping: PageMappingType::LazyMapping {
+                pages: b.into(),
+                swapped: Default::default(),
+            },
         })
     }
 
     pub fn new_lazy_prealloc(
         pages: Box<[Option<AllocatedPage<GlobalPageAllocator>>]>,
     ) -> Arc<Self> {
+        let pages: Box<_> = pages
+            .into_vec()
+            .into_iter()
+            .map(|p| p.map(Arc::new))
+            .collect();
         Arc::new(Self {
             size: pages.len() * 0x1000,
             mapping: PageMappingType::LazyMapping {
                 pages: pages.into(),
+                swapped: Default::default(),
+            },
+        })
+    }
+
+    /// A lazy mapping that shares all of this ones pages, to be mapped copy on write
+    fn clone_cow(&self) -> Arc<Self> {
+        match &self.mapping {
+            PageMappingType::LazyMapping { pages, swapped } => {
+                let pages = pages.lock();
+                // Each copy gets its own reference to the slots, they are read back in separately
+                let swapped: BTreeMap<_, _> = swapped
+                    .lock()
+                    .iter()
+                    .map(|(&i, &s)| (i, swap::dup_slot(s)))
+                    .collect();
+                Arc::new(Self {
+                    size: self.size,
+                    mapping: PageMappingType::LazyMapping {
+                        pages: pages.clone().into(),
+                        swapped: swapped.into(),
+                    },
+                })
+            }
+            PageMappingType::MMAP { .. } => panic!("mmap mappings can't be copy on write"),
+            PageMappingType::Dma { .. } => panic!("dma mappings can't be copy on write"),
+            PageMappingType::FileBacked { .. } => {
+                panic!("file backed mappings can't be copy on write")
+            }
+        }
+    }
+
+    /// Maps size bytes of the file starting at page offset of the file
+    pub fn new_file(file: VFileID, offset: usize, size: usize) -> Arc<Self> {
+        let pages: Box<_> = (0..(size + 0xFFF) / 0x1000).map(|_| None).collect();
+        Arc::new(Self {
+            size: (size + 0xFFF) & !0xFFF,
+            mapping: PageMappingType::FileBacked {
+                file,
+                offset,
+                pages: pages.into(),
             },
         })
     }
 
+    /// Maps size bytes of the same file starting page pages further in, None if this isn't file
+    /// backed
+    pub fn new_file_within(&self, page: usize, size: usize) -> Option<Arc<Self>> {
+        match &self.mapping {
+            PageMappingType::FileBacked { file, offset, .. } => {
+                Some(Self::new_file(*file, offset + page, size))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn new_dma(pages: DmaPages, grant: DmaGrant) -> Arc<Self> {
+        Arc::new(Self {
+            size: pages.len() * 0x1000,
+            mapping: PageMappingType::Dma { grant, pages },
+        })
+    }
+
     pub unsafe fn new_mmap(base_address: usize, size: usize) -> Arc<Self> {
         assert_eq!(base_address & 0xFFF, 0);
         assert_eq!(size & 0xFFF, 0);
@@ -108,18 +296,285 @@ impl PageMapping {
     }
 }
 
+impl Drop for PageMapping {
+    fn drop(&mut self) {
+        if let PageMappingType::LazyMapping { swapped, .. } = &self.mapping {
+            for (_, slot) in core::mem::take(&mut *swapped.lock()) {
+                swap::free_slot(slot);
+            }
+        }
+    }
+}
+
+/// How a page fault was handled
+pub enum PageFault {
+    Mapped,
+    /// The page is in swap and has to be read in with [`PageMapperManager::swap_in`], the caller
+    /// is given its own reference to the slot
+    SwappedOut(SwapSlot),
+    /// The page has to be read through the page cache and mapped with
+    /// [`PageMapperManager::file_in`]
+    FileBacked(VFileID, usize),
+}
+
 impl PageMapperManager {
     pub fn new(alloc: &impl PageAllocator) -> Self {
         Self {
             page_mapper: PageTable::new_with_global(alloc),
             mappings: Vec::new(),
+            mapped_pages: 0,
+            peak_mapped_pages: 0,
+            aslr: false,
         }
     }
 
+    pub fn set_aslr(&mut self, aslr: bool) {
+        self.aslr = aslr;
+    }
+
+    fn add_mapped_pages(&mut self, count: usize) {
+        self.mapped_pages += count;
+        self.peak_mapped_pages = self.peak_mapped_pages.max(self.mapped_pages);
+    }
+
+    pub fn mapped_pages(&self) -> usize {
+        self.mapped_pages
+    }
+
+    /// Highest number of pages that were mapped at once
+    pub fn peak_mapped_pages(&self) -> usize {
+        self.peak_mapped_pages
+    }
+
+    /// Pages of anonymous (lazy) memory in the userspace half as (reserved, committed).
+    /// Reserved pages are only given memory the first time they are touched.
+    pub fn anonymous_pages(&self) -> (usize, usize) {
+        self.mappings
+            .iter()
+            .filter(|(r, m, _)| {
+                r.end <= MemoryLoc::EndUserMem as usize
+                    && matches!(m.mapping, PageMappingType::LazyMapping { .. })
+            })
+            .fold((0, 0), |(reserved, committed), (_, m, _)| {
+                (reserved + m.size / 0x1000, committed + m.committed_pages())
+            })
+    }
+
+    /// Pages of memory owned by the mappings as (committed, shared), mmaps of physical memory
+    /// aren't counted
+    pub fn committed_pages(&self) -> (usize, usize) {
+        self.mappings
+            .iter()
+            .filter(|(_, m, _)| !matches!(m.mapping, PageMappingType::MMAP { .. }))
+            .fold((0, 0), |(committed, shared), (_, m, _)| {
+                (
+                    committed + m.committed_pages(),
+                    shared + m.shared_pages(Arc::strong_count(m) > 1),
+                )
+            })
+    }
+
     pub unsafe fn get_mapper_mut(&mut self) -> &mut PageTable<TableLevel4> {
         &mut self.page_mapper
     }
 
+    /// Maps every page of the mapping that is backed by memory, shared lazy pages are mapped
+    /// read only so that the first write copies them
+    fn map_present_pages(&mut self, base: usize, mapping: &PageMapping, flags: MemoryMappingFlags) {
+        let alloc = global_allocator();
+        let end = base + mapping.size;
+        let mut mapped = 0;
+
+        match &mapping.mapping {
+            PageMappingType::MMAP { base_address } => {
+                let mut virt = base;
+                let mut phys = *base_address;
+                while virt < end {
+                    // Use 2mb pages where both sides are aligned
+                    let huge = Size2MB::PAGE_SIZE as usize;
+                    let step = if virt % huge == 0 && phys % huge == 0 && virt + huge <= end {
+                        self.page_mapper
+                            .map(
+                                alloc,
+                                Page::<Size2MB>::new(virt as u64),
+                                Page::<Size2MB>::new(phys as u64),
+                                flags,
+                            )
+                            .unwrap()
+                            .ignore();
+                        huge
+                    } else {
+                        self.page_mapper
+                            .map(
+                                alloc,
+                                Page::<Size4KB>::new(virt as u64),
+                                Page::<Size4KB>::new(phys as u64),
+                                flags,
+                            )
+                            .unwrap()
+                            .ignore();
+                        0x1000
+                    };
+                    virt += step;
+                    phys += step;
+                    mapped += step / 0x1000;
+                }
+            }
+            PageMappingType::Dma { pages, .. } => {
+                for (page, virt) in pages.iter().zip((base..end).step_by(0x1000)) {
+                    self.page_mapper
+                        .map(
+                            alloc,
+                            Page::<Size4KB>::containing(virt as u64),
+                            page.page,
+                            flags,
+                        )
+                        .unwrap()
+                        .ignore();
+                    mapped += 1;
+                }
+            }
+            PageMappingType::LazyMapping { pages, .. } => {
+                for (page, virt) in pages
+                    .lock()
+                    .iter()
+                    .zip((base..end).step_by(0x1000))
+                    .filter_map(|(a, i)| a.as_ref().map(|p| (p, i)))
+                {
+                    let mut flags = flags;
+                    if Arc::strong_count(page) > 1 {
+                        flags.remove(MemoryMappingFlags::WRITEABLE);
+                    }
+                    self.page_mapper
+                        .map(
+                            alloc,
+                            Page::<Size4KB>::containing(virt as u64),
+                            page.page,
+                            flags,
+                        )
+                        .unwrap()
+                        .ignore();
+                    mapped += 1;
+                }
+            }
+            PageMappingType::FileBacked { pages, .. } => {
+                // Mapped read only so that the first write marks the page dirty
+                let flags = flags - MemoryMappingFlags::WRITEABLE;
+                for (page, virt) in pages
+                    .lock()
+                    .iter()
+                    .zip((base..end).step_by(0x1000))
+                    .filter_map(|(a, i)| a.as_ref().map(|p| (p, i)))
+                {
+                    self.page_mapper
+                        .map(
+                            alloc,
+                            Page::<Size4KB>::containing(virt as u64),
+                            page.page,
+                            flags,
+                        )
+                        .unwrap()
+                        .ignore();
+                    mapped += 1;
+                }
+            }
+        }
+        self.add_mapped_pages(mapped);
+    }
+
+    /// Starts a batch of TLB flushes for pages at address, pages in the kernel half have to be
+    /// flushed from every address space
+    fn tlb_shootdown(&self, address: usize) -> TlbShootdown {
+        if address > MemoryLoc::EndUserMem as usize {
+            TlbShootdown::new(None)
+        } else {
+            TlbShootdown::new(Some(self.page_mapper.get_physical_address() as u64))
+        }
+    }
+
+    /// Changes the flags of an already mapped page
+    fn remap(
+        &mut self,
+        virt: usize,
+        phys: Page<Size4KB>,
+        flags: MemoryMappingFlags,
+        shootdown: &mut TlbShootdown,
+    ) {
+        let alloc = global_allocator();
+        let virt = Page::<Size4KB>::containing(virt as u64);
+        if let Ok(f) = self.page_mapper.unmap(alloc, virt) {
+            f.ignore();
+        }
+        self.page_mapper
+            .map(alloc, virt, phys, flags)
+            .unwrap()
+            .ignore();
+        shootdown.add(virt.get_address());
+    }
+
+    /// Duplicates the userspace half of the address space. Private lazy mappings are shared
+    /// copy on write (in both address spaces), mmaps, file backed mappings and lazy mappings that
+    /// are already shared with another address space stay shared.
+    pub fn fork_cow(&mut self) -> PageMapperManager {
+        let mut child = PageMapperManager::new(global_allocator());
+        child.aslr = self.aslr;
+        let mut shootdown = self.tlb_shootdown(0);
+
+        // Whether it is shared has to be checked before cloning the Arc, which adds a count
+        let mappings: Vec<_> = self
+            .mappings
+            .iter()
+            .filter(|(r, ..)| r.end <= MemoryLoc::EndUserMem as usize)
+            .map(|(range, mapping, flags)| {
+                let shared = Arc::strong_count(mapping) > 1
+                    || matches!(
+                        mapping.mapping,
+                        PageMappingType::MMAP { .. }
+                            | PageMappingType::Dma { .. }
+                            | PageMappingType::FileBacked { .. }
+                    );
+                (range.clone(), mapping.clone(), *flags, shared)
+            })
+            .collect();
+
+        for (range, mapping, flags, shared) in mappings {
+            if shared {
+                child.insert_mapping_at_set(range.start, mapping, flags);
+                continue;
+            }
+
+            let copy = mapping.clone_cow();
+            // Write protect our copies now that the pages are shared
+            if flags.contains(MemoryMappingFlags::WRITEABLE) {
+                let PageMappingType::LazyMapping { pages, .. } = &mapping.mapping else {
+                    unreachable!()
+                };
+                let present: Vec<_> = pages
+                    .lock()
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, p)| p.as_ref().map(|p| (range.start + i * 0x1000, p.page)))
+                    .collect();
+                for (virt, phys) in present {
+                    if self
+                        .page_mapper
+                        .address_of(Page::<Size4KB>::containing(virt as u64))
+                        .is_some()
+                    {
+                        self.remap(
+                            virt,
+                            phys,
+                            flags - MemoryMappingFlags::WRITEABLE,
+                            &mut shootdown,
+                        );
+                    }
+                }
+            }
+            child.insert_mapping_at_set(range.start, copy, flags);
+        }
+        child
+    }
+
     pub fn insert_mapping_at(
         &mut self,
         base: usize,
@@ -127,6 +582,10 @@ impl PageMapperManager {
         flags: MemoryMappingFlags,
     ) -> Option<()> {
         assert!(base & 0xFFF == 0);
+        assert!(
+            flags.is_valid(),
+            "mapping can't be writeable and executable"
+        );
 
         let end = base + mapping.size;
 
@@ -152,6 +611,10 @@ impl PageMapperManager {
         flags: MemoryMappingFlags,
     ) -> Option<()> {
         assert!(base & 0xFFF == 0);
+        assert!(
+            flags.is_valid(),
+            "mapping can't be writeable and executable"
+        );
 
         let end = base + mapping.size;
 
@@ -166,44 +629,7 @@ impl PageMapperManager {
             .binary_search_by(|(r, ..)| r.start.cmp(&base))
             .unwrap_err();
 
-        let alloc = global_allocator();
-
-        match &mapping.mapping {
-            PageMappingType::MMAP { base_address } => {
-                for (phys, virt) in
-                    ((*base_address..).step_by(0x1000)).zip((base..end).step_by(0x1000))
-                {
-                    self.page_mapper
-                        .map(
-                            alloc,
-                            Page::<Size4KB>::containing(virt as u64),
-                            Page::<Size4KB>::new(phys as u64),
-                            flags,
-                        )
-                        .unwrap()
-                        .ignore();
-                }
-            }
-            PageMappingType::LazyMapping { pages } => {
-                for page in pages
-                    .lock()
-                    .iter()
-                    .zip((base..end).step_by(0x1000))
-                    .filter_map(|(a, i)| a.as_ref().map(|p| (p.page, i)))
-                {
-                    self.page_mapper
-                        .map(
-                            alloc,
-                            Page::<Size4KB>::containing(page.1 as u64),
-                            page.0,
-                            flags,
-                        )
-                        .unwrap()
-                        .ignore();
-                }
-            }
-        }
-
+        self.map_present_pages(base, &mapping, flags);
         self.mappings.insert(idx, ((base..end), mapping, flags));
 
         // let m = self.mappings.remove(idx);
@@ -211,28 +637,43 @@ impl PageMapperManager {
         Some(())
     }
 
-    pub fn insert_mapping(
-        &mut self,
-        mapping: Arc<PageMapping>,
-        flags: MemoryMappingFlags,
-    ) -> usize {
+    /// Finds a gap for a mapping of size, returns the index to insert it at and its base
+    fn find_free_space(&self, size: usize) -> (usize, usize) {
         let idx = self
             .mappings
             .windows(2)
             .position(|window| {
                 if let [left, right] = window {
                     // add some padding
-                    left.0.end + 0x1000 + mapping.size <= right.0.start
+                    left.0.end + 0x1000 + size <= right.0.start
                 } else {
                     unreachable!()
                 }
             })
             .expect("there should've been space somewhere");
 
-        let base = self.mappings[idx].0.end + 0x1000;
+        let mut base = self.mappings[idx].0.end + 0x1000;
+        if self.aslr {
+            // Keep a page of padding after the mapping as well
+            let slack = (self.mappings[idx + 1].0.start - base - size) / 0x1000;
+            base += random_below((slack as u64).min(ASLR_MMAP_PAGES)) as usize * 0x1000;
+        }
+        (idx + 1, base)
+    }
+
+    pub fn insert_mapping(
+        &mut self,
+        mapping: Arc<PageMapping>,
+        flags: MemoryMappingFlags,
+    ) -> usize {
+        assert!(
+            flags.is_valid(),
+            "mapping can't be writeable and executable"
+        );
+        let (idx, base) = self.find_free_space(mapping.size);
 
         self.mappings
-            .insert(idx + 1, ((base..base + mapping.size), mapping, flags));
+            .insert(idx, ((base..base + mapping.size), mapping, flags));
         base
     }
 
@@ -241,113 +682,305 @@ impl PageMapperManager {
         mapping: Arc<PageMapping>,
         flags: MemoryMappingFlags,
     ) -> usize {
+        assert!(
+            flags.is_valid(),
+            "mapping can't be writeable and executable"
+        );
+        let (idx, base) = self.find_free_space(mapping.size);
+
+        self.map_present_pages(base, &mapping, flags);
+
+        self.mappings
+            .insert(idx, ((base..base + mapping.size), mapping, flags));
+        base
+    }
+
+    /// Changes the flags of the mapping that covers exactly range, its present pages are
+    /// remapped with the new flags. Fails for mmaps of physical memory, dma
+    /// mappings and W^X violations.
+    pub fn set_flags(&mut self, range: Range<usize>, flags: MemoryMappingFlags) -> Option<()> {
+        if !flags.is_valid() {
+            return None;
+        }
         let idx = self
             .mappings
-            .windows(2)
-            .position(|window| {
-                if let [left, right] = window {
-                    // add some padding
-                    left.0.end + 0x1000 + mapping.size <= right.0.start
-                } else {
-                    unreachable!()
-                }
-            })
-            .expect("there should've been space somewhere");
-
-        let base = self.mappings[idx].0.end + 0x1000;
-        let end = base + mapping.size;
+            .binary_search_by(|el| el.0.clone().cmp(range.clone()))
+            .ok()?;
+        let mapping = self.mappings[idx].1.clone();
+        if matches!(
+            mapping.mapping,
+            PageMappingType::MMAP { .. } | PageMappingType::Dma { .. }
+        ) {
+            return None;
+        }
 
+        // Every core has to stop using the old permissions before the new ones are mapped
         let alloc = global_allocator();
-        match &mapping.mapping {
-            PageMappingType::MMAP { base_address } => {
-                for (phys, virt) in
-                    ((*base_address..).step_by(0x1000)).zip((base..end).step_by(0x1000))
-                {
-                    self.page_mapper
-                        .map(
-                            alloc,
-                            Page::<Size4KB>::containing(virt as u64),
-                            Page::<Size4KB>::new(phys as u64),
-                            flags,
-                        )
-                        .unwrap()
-                        .ignore();
-                }
-            }
-            PageMappingType::LazyMapping { pages } => {
-                for page in pages
-                    .lock()
-                    .iter()
-                    .zip((base..end).step_by(0x1000))
-                    .filter_map(|(a, i)| a.as_ref().map(|p| (p.page, i)))
-                {
-                    self.page_mapper
-                        .map(
-                            alloc,
-                            Page::<Size4KB>::containing(page.1 as u64),
-                            page.0,
-                            flags,
-                        )
-                        .unwrap()
-                        .ignore();
-                }
+        let mut shootdown = self.tlb_shootdown(range.start);
+        for page in range.clone().step_by(0x1000) {
+            if let Ok(f) = self
+                .page_mapper
+                .unmap(alloc, Page::<Size4KB>::new(page as u64))
+            {
+                f.ignore();
+                shootdown.add(page as u64);
+                self.mapped_pages = self.mapped_pages.saturating_sub(1);
             }
         }
+        drop(shootdown);
+
+        self.mappings[idx].2 = flags;
+        self.map_present_pages(range.start, &mapping, flags);
+        Some(())
+    }
 
+    /// Index of the mapping that contains address
+    /// The mapping that covers address and where it starts
+    pub fn mapping_at(&self, address: usize) -> Option<(usize, Arc<PageMapping>)> {
+        let (range, mapping, _) = &self.mappings[self.find_mapping(address)?];
+        Some((range.start, mapping.clone()))
+    }
+
+    fn find_mapping(&self, address: usize) -> Option<usize> {
         self.mappings
-            .insert(idx + 1, ((base..base + mapping.size), mapping, flags));
-        base
+            .binary_search_by(|(r, ..)| {
+                if r.start > address {
+                    Ordering::Greater
+                } else if r.end <= address {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .ok()
     }
 
-    pub fn page_fault_handler(&mut self, address: usize) -> Option<()> {
+    pub fn page_fault_handler(&mut self, address: usize, write: bool) -> Option<PageFault> {
         if address > MemoryLoc::EndUserMem as usize {
             return None;
         }
 
-        // find the mapping that address is in
-        let idx = self.mappings.binary_search_by(|(r, ..)| {
-            if r.start > address {
-                Ordering::Greater
-            } else if r.end <= address {
-                Ordering::Less
-            } else {
-                Ordering::Equal
-            }
-        });
-        let idx = idx.ok()?;
+        let idx = self.find_mapping(address)?;
 
         let map = &mut self.mappings[idx];
         let offset = address - map.0.start;
+        let mut flags = map.2;
+        if write && !flags.contains(MemoryMappingFlags::WRITEABLE) {
+            return None;
+        }
+
         let phys = match &map.1.mapping {
             PageMappingType::MMAP { base_address } => {
                 Page::containing((*base_address + offset) as u64)
             }
-            PageMappingType::LazyMapping { pages } => {
+            PageMappingType::Dma { pages, .. } => pages[offset / 0x1000].page,
+            PageMappingType::LazyMapping { pages, swapped } => {
                 let idx = offset / 0x1000;
-                let page = &mut pages.lock()[idx];
+                let mut pages = pages.lock();
+                let page = &mut pages[idx];
+                if page.is_none() {
+                    if let Some(&slot) = swapped.lock().get(&idx) {
+                        return Some(PageFault::SwappedOut(swap::dup_slot(slot)));
+                    }
+                }
                 match page {
-                    Some(p) => p.page,
+                    Some(p) if write => Self::unshare_page(p),
+                    Some(p) => {
+                        // Still shared so the first write has to copy it
+                        if Arc::strong_count(p) > 1 {
+                            flags.remove(MemoryMappingFlags::WRITEABLE);
+                        }
+                        p.page
+                    }
                     None => {
                         let alloc = AllocatedPage::new(GlobalPageAllocator).unwrap();
                         let p = alloc.page;
-                        *page = Some(alloc);
+                        *page = Some(Arc::new(alloc));
                         p
                     }
                 }
             }
+            PageMappingType::FileBacked {
+                file,
+                offset: file_offset,
+                pages,
+            } => {
+                let idx = offset / 0x1000;
+                let pages = pages.lock();
+                let Some(page) = &pages[idx] else {
+                    return Some(PageFault::FileBacked(*file, file_offset + idx));
+                };
+                if write {
+                    page_cache::mark_dirty(*file, file_offset + idx);
+                } else {
+                    // Keep it read only until the first write so that it gets marked dirty
+                    flags.remove(MemoryMappingFlags::WRITEABLE);
+                }
+                page.page
+            }
         };
         // Make the mapping
-        match self.page_mapper.map(
-            global_allocator(),
-            Page::<Size4KB>::containing(address as u64),
-            phys,
-            map.2,
-        ) {
-            Ok(f) => f.flush(),
+        let virt = Page::<Size4KB>::containing(address as u64);
+        if self.page_mapper.address_of(virt).is_some() {
+            // Write to a copy on write page
+            let mut shootdown = self.tlb_shootdown(address);
+            self.remap(address, phys, flags, &mut shootdown);
+            return Some(PageFault::Mapped);
+        }
+        match self.page_mapper.map(global_allocator(), virt, phys, flags) {
+            Ok(f) => {
+                f.flush();
+                self.add_mapped_pages(1);
+            }
             Err(_) => (), // Already mapped ??
         }
 
-        Some(())
+        Some(PageFault::Mapped)
+    }
+
+    /// Maps a page that was read back in from swap. Does nothing if the page was changed while
+    /// it was being read, the faulting access will just fault again.
+    pub fn swap_in(
+        &mut self,
+        address: usize,
+        slot: SwapSlot,
+        page: AllocatedPage<GlobalPageAllocator>,
+    ) {
+        let Some(idx) = self.find_mapping(address) else {
+            return;
+        };
+        let (range, mapping, flags) = &self.mappings[idx];
+        let PageMappingType::LazyMapping { pages, swapped } = &mapping.mapping else {
+            return;
+        };
+        let flags = *flags;
+        let idx = (address - range.start) / 0x1000;
+
+        let mut pages = pages.lock();
+        let mut swapped = swapped.lock();
+        if pages[idx].is_some() || swapped.get(&idx) != Some(&slot) {
+            return;
+        }
+        swapped.remove(&idx);
+        swap::free_slot(slot);
+
+        let phys = page.page;
+        pages[idx] = Some(Arc::new(page));
+        drop(swapped);
+        drop(pages);
+
+        let virt = Page::<Size4KB>::containing(address as u64);
+        if let Ok(f) = self.page_mapper.map(global_allocator(), virt, phys, flags) {
+            f.flush();
+            self.add_mapped_pages(1);
+        }
+    }
+
+    /// Maps a page that was read in through the page cache. Does nothing if the mapping changed
+    /// while it was being read, the faulting access will just fault again.
+    pub fn file_in(&mut self, address: usize, page: CachePage) {
+        let Some(idx) = self.find_mapping(address) else {
+            return;
+        };
+        let (range, mapping, flags) = &self.mappings[idx];
+        let PageMappingType::FileBacked { pages, .. } = &mapping.mapping else {
+            return;
+        };
+        let flags = *flags - MemoryMappingFlags::WRITEABLE;
+        let idx = (address - range.start) / 0x1000;
+
+        let mut pages = pages.lock();
+        let phys = match &pages[idx] {
+            Some(p) => p.page,
+            None => {
+                let phys = page.page;
+                pages[idx] = Some(page);
+                phys
+            }
+        };
+        drop(pages);
+
+        let virt = Page::<Size4KB>::containing(address as u64);
+        if let Ok(f) = self.page_mapper.map(global_allocator(), virt, phys, flags) {
+            f.flush();
+            self.add_mapped_pages(1);
+        }
+    }
+
+    /// Moves up to max pages of private anonymous memory to swap, returning how many were moved.
+    /// Pages that have been accessed since the last scan are given a second chance.
+    pub fn page_out(&mut self, max: usize) -> usize {
+        let alloc = global_allocator();
+        let mut paged_out = 0;
+        // Only userspace mappings are paged out
+        let mut shootdown = self.tlb_shootdown(0);
+
+        for (range, mapping, _) in &self.mappings {
+            if range.end > MemoryLoc::EndUserMem as usize || Arc::strong_count(mapping) > 1 {
+                continue;
+            }
+            let PageMappingType::LazyMapping { pages, swapped } = &mapping.mapping else {
+                continue;
+            };
+
+            let mut pages = pages.lock();
+            let mut swapped = swapped.lock();
+            for (idx, page) in pages.iter_mut().enumerate() {
+                if paged_out == max {
+                    return paged_out;
+                }
+                // Shared copy on write pages stay in memory
+                if !page.as_ref().is_some_and(|p| Arc::strong_count(p) == 1) {
+                    continue;
+                }
+
+                let virt = Page::<Size4KB>::containing((range.start + idx * 0x1000) as u64);
+                match self.page_mapper.take_accessed(virt) {
+                    Some(true) => {
+                        shootdown.add(virt.get_address());
+                        continue;
+                    }
+                    Some(false) => {
+                        self.page_mapper.unmap(alloc, virt).unwrap().ignore();
+                        shootdown.add(virt.get_address());
+                        self.mapped_pages = self.mapped_pages.saturating_sub(1);
+                    }
+                    None => (),
+                }
+
+                let Ok(owned) = Arc::try_unwrap(page.take().unwrap()) else {
+                    unreachable!("page should not be shared")
+                };
+                match swap::alloc_slot(owned) {
+                    Ok(slot) => {
+                        swapped.insert(idx, slot);
+                        paged_out += 1;
+                    }
+                    Err(owned) => {
+                        // Swap is full, put it back
+                        *page = Some(Arc::new(owned));
+                        return paged_out;
+                    }
+                }
+            }
+        }
+        paged_out
+    }
+
+    /// Gives us our own copy of a page that might be shared copy on write
+    fn unshare_page(page: &mut Arc<AllocatedPage<GlobalPageAllocator>>) -> Page<Size4KB> {
+        if Arc::strong_count(page) > 1 {
+            let copy = AllocatedPage::new(GlobalPageAllocator).unwrap();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    virt_addr_for_phys(page.get_address()) as *const u8,
+                    virt_addr_for_phys(copy.get_address()) as *mut u8,
+                    0x1000,
+                );
+            }
+            *page = Arc::new(copy);
+        }
+        page.page
     }
 
     pub unsafe fn free_mapping(&mut self, range: Range<usize>) -> Result<(), UnMapMemoryError> {
@@ -358,18 +991,112 @@ impl PageMapperManager {
 
         let m = self.mappings.remove(idx);
         let alloc = global_allocator();
-        for page in m.0.step_by(0x1000) {
+        // Dropped before the mapping so that no core can still be using the pages once freed
+        let mut shootdown = self.tlb_shootdown(m.0.start);
+        let huge = Size2MB::PAGE_SIZE as usize;
+        let mut page = m.0.start;
+        while page < m.0.end {
+            if page % huge == 0 && page + huge <= m.0.end {
+                match self
+                    .page_mapper
+                    .unmap(alloc, Page::<Size2MB>::new(page as u64))
+                {
+                    Ok(f) => {
+                        f.ignore();
+                        shootdown.add(page as u64);
+                        self.mapped_pages = self.mapped_pages.saturating_sub(huge / 0x1000);
+                        page += huge;
+                        continue;
+                    }
+                    // Mapped with 4kb pages
+                    Err(UnMapMemoryError::WrongPageSize(_) | UnMapMemoryError::MemNotMapped(_)) => {
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
             match self
                 .page_mapper
                 .unmap(alloc, Page::<Size4KB>::new(page as u64))
             {
-                Ok(f) => f.flush(),
+                Ok(f) => {
+                    f.ignore();
+                    shootdown.add(page as u64);
+                    self.mapped_pages = self.mapped_pages.saturating_sub(1);
+                }
                 Err(UnMapMemoryError::MemNotMapped(_)) => (),
                 Err(e) => return Err(e),
             }
-
-            // TODO: Send IPI to flush on other threads
+            page += 0x1000;
         }
         Ok(())
     }
 }
+
+#[cfg(debug_assertions)]
+pub mod test {
+    use crate::paging::{
+        page::{Page, Size4KB},
+        page_allocator::global_allocator,
+        page_table::Mapper,
+        virt_addr_for_phys, MemoryMappingFlags,
+    };
+
+    use super::{PageFault, PageMapperManager, PageMapping};
+
+    /// Checks that a write after [`PageMapperManager::fork_cow`] is only seen by the side that
+    /// wrote. Faults are handled directly so the address spaces never have to be loaded.
+    pub fn fork_cow_self_test() {
+        let read = |mapper: &PageMapperManager, address: usize| {
+            let page = mapper
+                .page_mapper
+                .address_of(Page::<Size4KB>::containing(address as u64))
+                .expect("page should be mapped");
+            unsafe { *(virt_addr_for_phys(page.get_address()) as *const u64) }
+        };
+        let write = |mapper: &mut PageMapperManager, address: usize, val: u64| {
+            assert!(matches!(
+                mapper.page_fault_handler(address, true),
+                Some(PageFault::Mapped)
+            ));
+            let page = mapper
+                .page_mapper
+                .address_of(Page::<Size4KB>::containing(address as u64))
+                .unwrap();
+            unsafe { *(virt_addr_for_phys(page.get_address()) as *mut u64) = val };
+        };
+
+        let mut parent = PageMapperManager::new(global_allocator());
+        let base =
+            parent.insert_mapping(PageMapping::new_lazy(0x1000), MemoryMappingFlags::WRITEABLE);
+        write(&mut parent, base, 1);
+
+        let mut child = parent.fork_cow();
+        assert_eq!(
+            read(&child, base),
+            1,
+            "child doesn't see the data from before the fork"
+        );
+
+        write(&mut parent, base, 2);
+        assert_eq!(read(&parent, base), 2);
+        assert_eq!(
+            read(&child, base),
+            1,
+            "child saw the parent's write after the fork"
+        );
+
+        write(&mut child, base, 3);
+        assert_eq!(read(&child, base), 3);
+        assert_eq!(
+            read(&parent, base),
+            2,
+            "parent saw the child's write after the fork"
+        );
+
+        unsafe {
+            parent.free_mapping(base..base + 0x1000).unwrap();
+            child.free_mapping(base..base + 0x1000).unwrap();
+        }
+    }
+}
@@ -8,7 +8,7 @@ use crate::{mutex::Spinlock, paging::page::Page};
 use super::{
     page::{PageSize, Size1GB, Size2MB, Size4KB},
     page_directory::PageDirectoryEntry,
-    virt_addr_offset_mut, MemoryLoc, MemoryMappingFlags, PageAllocator, KERNEL_DATA_MAP,
+    pat, virt_addr_offset_mut, MemoryLoc, MemoryMappingFlags, PageAllocator, KERNEL_DATA_MAP,
     KERNEL_HEAP_MAP, OFFSET_MAP, PER_CPU_MAP,
 };
 
@@ -24,6 +24,8 @@ pub enum UnMapMemoryError {
     MemNotMapped(u64),
     #[error("cannot unmap {0} because the page tables don't exist")]
     PathNotFound(u64),
+    #[error("cannot unmap {0} because it is mapped with a different page size")]
+    WrongPageSize(u64),
 }
 
 #[repr(C, align(0x1000))]
@@ -50,6 +52,9 @@ pub trait TableLevel {
 
     const INDEXER: usize = Self::LEVEL * 9 + 3;
 
+    /// Bytes of memory mapped by each entry
+    const ENTRY_SIZE: u64 = 1 << Self::INDEXER;
+
     fn calculate_index(address: usize) -> usize {
         (address >> Self::INDEXER) & 0x1ff
     }
@@ -131,6 +136,10 @@ pub trait Mapper<P: PageSize> {
 
     fn address_of(&self, page: Page<P>) -> Option<Page<P>>;
 
+    /// Clears the accessed bit of a mapped page, returning if it was set.
+    /// The TLB may still cache the old entry so it must be flushed if this returns true.
+    fn take_accessed(&mut self, page: Page<P>) -> Option<bool>;
+
     fn identity_map(
         &mut self,
         alloc: &impl PageAllocator,
@@ -165,6 +174,10 @@ macro_rules! gen_base_tables {
                 fn address_of(&self, page: Page<<$table as TableLevelMap>::Size>) -> Option<Page<<$table as TableLevelMap>::Size>> {
                     self.address_of_inner(page)
                 }
+
+                fn take_accessed(&mut self, page: Page<<$table as TableLevelMap>::Size>) -> Option<bool> {
+                    self.take_accessed_inner(page)
+                }
             }
         )*
     };
@@ -249,6 +262,11 @@ impl<L: TableLevel + TableLevelMap> PageTable<L> {
         e.set_larger_pages(L::LARGER_PAGES);
         e.set_read_write(flags.contains(MemoryMappingFlags::WRITEABLE));
         e.set_user_super(flags.contains(MemoryMappingFlags::USERSPACE));
+        e.set_no_execute(!flags.contains(MemoryMappingFlags::EXECUTABLE));
+        // PWT selects the write combining PAT entry
+        e.set_write_through(
+            flags.contains(MemoryMappingFlags::WRITE_COMBINING) && pat::write_combining(),
+        );
         e.set_address(physical_page.get_address());
         Ok(Flusher(virtual_page.get_address()))
     }
@@ -267,7 +285,9 @@ impl<L: TableLevel + TableLevelMap> PageTable<L> {
             // allow unmap of nothing
             return Err(UnMapMemoryError::MemNotMapped(page.get_address()));
         }
-        assert_eq!(e.larger_pages(), L::LARGER_PAGES);
+        if e.larger_pages() != L::LARGER_PAGES {
+            return Err(UnMapMemoryError::WrongPageSize(page.get_address()));
+        }
 
         e.set_present(false);
         e.set_address(0);
@@ -286,6 +306,51 @@ impl<L: TableLevel + TableLevelMap> PageTable<L> {
             None
         }
     }
+
+    fn take_accessed_inner(&mut self, page: Page<L::Size>) -> Option<bool> {
+        let index = L::calculate_index_page(page);
+        let table = self.table();
+
+        let e = &mut table.entries[index];
+
+        if e.present() && L::LARGER_PAGES == e.larger_pages() {
+            let accessed = e.accessed();
+            e.set_accessed(false);
+            Some(accessed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Replaces a larger page entry with a table of the next level that maps the same memory
+fn split_entry<L: TableLevel + TableLevelNext>(
+    alloc: &impl PageAllocator,
+    e: &mut PageDirectoryEntry,
+) -> PageTable<L::Next> {
+    let next: PageTable<L::Next> = PageTable::new(alloc);
+    let base = e.get_address();
+
+    for (i, entry) in next.table().entries.iter_mut().enumerate() {
+        entry.set_present(true);
+        // Entries in the lowest level are always 4kb pages
+        entry.set_larger_pages(L::Next::LEVEL > 1);
+        entry.set_read_write(e.read_write());
+        entry.set_user_super(e.user_super());
+        entry.set_write_through(e.write_through());
+        entry.set_cache_disabled(e.cache_disabled());
+        entry.set_global(e.global());
+        entry.set_no_execute(e.no_execute());
+        entry.set_address(base + i as u64 * L::Next::ENTRY_SIZE);
+    }
+
+    e.set_larger_pages(false);
+    e.set_read_write(true);
+    e.set_user_super(true);
+    e.set_global(false);
+    e.set_no_execute(false);
+    e.set_address(next.get_physical_address() as u64);
+    next
 }
 
 impl<L: TableLevel + TableLevelNext, P: PageSize> Mapper<P> for PageTable<L>
@@ -314,6 +379,8 @@ where
             e.set_larger_pages(false);
             e.set_read_write(true);
             e.set_user_super(true);
+            // Permissions are only restricted in the last level
+            e.set_no_execute(false);
             e.set_address(table.get_physical_address() as u64);
 
             table
@@ -335,10 +402,13 @@ where
         if !e.present() {
             return Err(UnMapMemoryError::MemNotMapped(page.get_address()));
         }
-        assert!(!e.larger_pages(), "unmap over larger pages");
 
-        let mut next: PageTable<L::Next> =
-            unsafe { PageTable::from_raw(e.get_address() as *mut PhysPageTable) };
+        let mut next: PageTable<L::Next> = if e.larger_pages() {
+            // Only part of the larger page is being unmapped
+            split_entry::<L>(alloc, e)
+        } else {
+            unsafe { PageTable::from_raw(e.get_address() as *mut PhysPageTable) }
+        };
         let r = next.unmap(alloc, page);
 
         // try cleaning up memory
@@ -358,13 +428,35 @@ where
 
         let e = &table.entries[index];
 
-        if e.present() {
-            assert!(!e.larger_pages());
+        if !e.present() {
+            None
+        } else if e.larger_pages() {
+            // The page is part of a larger page
+            let offset = page.get_address() & (L::ENTRY_SIZE - 1);
+            Some(Page::new(e.get_address() + offset))
+        } else {
             let next: PageTable<L::Next> =
                 unsafe { PageTable::from_raw(e.get_address() as *mut PhysPageTable) };
             next.address_of(page)
-        } else {
+        }
+    }
+
+    fn take_accessed(&mut self, page: Page<P>) -> Option<bool> {
+        let index = L::calculate_index_page(page);
+        let table = self.table();
+
+        let e = &mut table.entries[index];
+
+        if !e.present() {
             None
+        } else if e.larger_pages() {
+            let accessed = e.accessed();
+            e.set_accessed(false);
+            Some(accessed)
+        } else {
+            let mut next: PageTable<L::Next> =
+                unsafe { PageTable::from_raw(e.get_address() as *mut PhysPageTable) };
+            next.take_accessed(page)
         }
     }
 }
@@ -0,0 +1,114 @@
+//! Page attribute table setup.
+//!
+//! The PAT entry picked by only the PWT bit is write-through by default, it is changed to
+//! write-combining so that [`MemoryMappingFlags::WRITE_COMBINING`] mappings only have to set PWT,
+//! which works the same for every page size. If the CPU doesn't have a PAT (or it doesn't take the
+//! new value) those mappings fall back to the memory type the MTRRs give them.
+//!
+//! [`MemoryMappingFlags::WRITE_COMBINING`]: super::MemoryMappingFlags::WRITE_COMBINING
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::control::Cr3;
+
+use crate::{
+    assembly::{rdmsr, wrmsr},
+    cpu::{features, CpuFeatures},
+};
+
+const IA32_PAT: u32 = 0x277;
+const IA32_MTRRCAP: u32 = 0xFE;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+
+const TYPE_UC: u64 = 0;
+const TYPE_WC: u64 = 1;
+const TYPE_WB: u64 = 6;
+const TYPE_UC_MINUS: u64 = 7;
+
+/// WB, WC, UC-, UC for both halves (the PAT bit is never set)
+const PAT_VALUE: u64 = {
+    let half = TYPE_WB | TYPE_WC << 8 | TYPE_UC_MINUS << 16 | TYPE_UC << 24;
+    half | half << 32
+};
+
+static WRITE_COMBINING: AtomicBool = AtomicBool::new(false);
+
+/// Programs the PAT of the current core, has to be called on every core before it touches a write
+/// combining mapping
+///
+/// # Safety
+///
+/// Must run in ring 0 on the core being set up, while nothing is mapped write through, as that
+/// entry becomes write combining.
+pub unsafe fn init_core() {
+    if !features().contains(CpuFeatures::PAT) {
+        return;
+    }
+
+    wrmsr(IA32_PAT, PAT_VALUE);
+    if rdmsr(IA32_PAT) != PAT_VALUE {
+        WRITE_COMBINING.store(false, Ordering::Relaxed);
+        return;
+    }
+
+    // Nothing cached can be left with the old type
+    core::arch::asm!("wbinvd", options(nomem, nostack));
+    let (frame, flags) = Cr3::read();
+    Cr3::write(frame, flags);
+
+    WRITE_COMBINING.store(true, Ordering::Relaxed);
+}
+
+/// If write combining mappings actually are write combining
+pub fn write_combining() -> bool {
+    WRITE_COMBINING.load(Ordering::Relaxed)
+}
+
+/// The memory type the MTRRs give the physical address, None if the CPU doesn't have MTRRs
+pub fn mtrr_type(phys: u64) -> Option<u64> {
+    if !features().contains(CpuFeatures::MTRR) {
+        return None;
+    }
+    unsafe {
+        let def_type = rdmsr(IA32_MTRR_DEF_TYPE);
+        // Everything is uncached while they are disabled
+        if def_type & (1 << 11) == 0 {
+            return Some(TYPE_UC);
+        }
+
+        // Only the variable ranges are checked, fixed ranges only cover the first mb
+        let count = rdmsr(IA32_MTRRCAP) & 0xFF;
+        let mut found = None;
+        for i in 0..count as u32 {
+            let base = rdmsr(IA32_MTRR_PHYSBASE0 + i * 2);
+            let mask = rdmsr(IA32_MTRR_PHYSBASE0 + i * 2 + 1);
+            // Valid bit
+            if mask & (1 << 11) == 0 {
+                continue;
+            }
+            let mask = mask & !0xFFF;
+            if phys & mask == base & mask {
+                let ty = base & 0xFF;
+                // When ranges overlap uncached wins
+                if ty == TYPE_UC {
+                    return Some(TYPE_UC);
+                }
+                found = Some(ty);
+            }
+        }
+        Some(found.unwrap_or(def_type & 0xFF))
+    }
+}
+
+/// Logs how the framebuffer at phys will be cached
+pub fn check_framebuffer(phys: u64) {
+    let mtrr = mtrr_type(phys);
+    if !write_combining() {
+        warn!("PAT unavailable, framebuffer stays at MTRR type {mtrr:?}");
+    } else if mtrr == Some(TYPE_WC) {
+        info!("Framebuffer is already write combining in the MTRRs");
+    } else {
+        info!("Framebuffer is write combining (MTRR type {mtrr:?})");
+    }
+}
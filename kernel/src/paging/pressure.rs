@@ -0,0 +1,135 @@
+//! Memory pressure tracking.
+//!
+//! A monitor thread periodically checks how much memory is free and moves between
+//! [`MemoryPressure`] levels. Userspace is told about level changes through ports, kernel caches
+//! register a [`Shrinker`] which gets asked to give memory back while the pressure isn't ok.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use kernel_userspace::{
+    memory::MemoryPressure,
+    num_traits::FromPrimitive,
+    port::{PortNotification, PortNotificationType},
+    syscall::sleep,
+};
+
+use crate::{allocator::slab_allocator::HeapShrinker, mutex::Spinlock, port::KPort};
+
+use super::page_allocator::frame_alloc_exec;
+
+/// Below this many free pages the pressure is low (32mb)
+const LOW_PAGES: usize = 0x2000;
+/// Below this many free pages the pressure is critical (8mb)
+const CRITICAL_PAGES: usize = 0x800;
+/// The pressure only drops once there are this many pages above the threshold, so that it doesn't
+/// flap around a threshold (4mb)
+const HYSTERESIS_PAGES: usize = 0x400;
+const MONITOR_INTERVAL_MS: u64 = 250;
+
+/// A kernel cache that can give memory back
+pub trait Shrinker: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Frees up to `pages` pages, returns how many pages were freed
+    fn shrink(&self, pages: usize, level: MemoryPressure) -> usize;
+}
+
+static PRESSURE: AtomicU8 = AtomicU8::new(MemoryPressure::Ok as u8);
+static SHRINKERS: Spinlock<Vec<&'static dyn Shrinker>> = Spinlock::new(Vec::new());
+static WATCHERS: Spinlock<Vec<(Weak<KPort>, u64)>> = Spinlock::new(Vec::new());
+
+pub fn memory_pressure() -> MemoryPressure {
+    MemoryPressure::from_u8(PRESSURE.load(Ordering::Relaxed)).unwrap()
+}
+
+pub fn register_shrinker(shrinker: &'static dyn Shrinker) {
+    SHRINKERS.lock().push(shrinker);
+}
+
+/// Notifies the port with key every time the pressure changes
+pub fn add_pressure_port(port: Arc<KPort>, key: u64) {
+    let level = memory_pressure();
+    if level != MemoryPressure::Ok {
+        notify(&port, key, level);
+    }
+    WATCHERS.lock().push((Arc::downgrade(&port), key));
+}
+
+fn notify(port: &KPort, key: u64, level: MemoryPressure) {
+    port.notify(PortNotification {
+        key,
+        ty: PortNotificationType::MemoryPressure { level },
+    });
+}
+
+fn level_for(free: usize) -> MemoryPressure {
+    if free < CRITICAL_PAGES {
+        MemoryPressure::Critical
+    } else if free < LOW_PAGES {
+        MemoryPressure::Low
+    } else {
+        MemoryPressure::Ok
+    }
+}
+
+fn next_level(free: usize, current: MemoryPressure) -> MemoryPressure {
+    let level = level_for(free);
+    if level >= current {
+        return level;
+    }
+    level_for(free.saturating_sub(HYSTERESIS_PAGES)).min(current)
+}
+
+fn free_pages() -> usize {
+    frame_alloc_exec(|a| a.free_pages())
+}
+
+/// Asks the shrinkers to free memory until the pressure would be ok again
+fn run_shrinkers(level: MemoryPressure) {
+    let shrinkers = SHRINKERS.lock().clone();
+
+    let mut wanted = (LOW_PAGES + HYSTERESIS_PAGES).saturating_sub(free_pages());
+    for shrinker in shrinkers {
+        if wanted == 0 {
+            break;
+        }
+        let freed = shrinker.shrink(wanted, level);
+        if freed > 0 {
+            debug!("Shrinker {} freed {} pages", shrinker.name(), freed);
+        }
+        wanted = wanted.saturating_sub(freed);
+    }
+}
+
+pub fn memory_pressure_monitor() {
+    register_shrinker(&HeapShrinker);
+
+    loop {
+        sleep(MONITOR_INTERVAL_MS);
+
+        let current = memory_pressure();
+        let level = next_level(free_pages(), current);
+
+        if level != current {
+            info!("Memory pressure changed from {current:?} to {level:?}");
+            PRESSURE.store(level as u8, Ordering::Relaxed);
+
+            let mut watchers = WATCHERS.lock();
+            watchers.retain(|(port, key)| match port.upgrade() {
+                Some(port) => {
+                    notify(&port, *key, level);
+                    true
+                }
+                None => false,
+            });
+        }
+
+        if level != MemoryPressure::Ok {
+            run_shrinkers(level);
+        }
+    }
+}
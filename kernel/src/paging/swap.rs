@@ -0,0 +1,258 @@
+//! Swapping of anonymous memory to a disk partition.
+//!
+//! The pageout daemon wakes up periodically and, if free memory is below [`LOW_WATERMARK`], moves
+//! cold pages of user processes out to swap slots (see [`PageMapperManager::page_out`]). Pages
+//! are first parked in memory as pending and written to disk by the daemon once it has released
+//! the processes memory locks, they are read back in by the page fault handler.
+//!
+//! [`PageMapperManager::page_out`]: super::page_mapper::PageMapperManager::page_out
+
+use alloc::{boxed::Box, collections::BTreeMap, vec, vec::Vec};
+use kernel_userspace::syscall::sleep;
+
+use crate::{
+    fs::FSPartitionDisk,
+    mutex::{Mutex, Spinlock},
+    scheduling::{process::ProcessPrivilige, taskmanager::PROCESSES},
+};
+
+use super::{
+    page_allocator::frame_alloc_exec, virt_addr_for_phys, AllocatedPage, GlobalPageAllocator,
+};
+
+const SECTORS_PER_SLOT: usize = 0x1000 / 512;
+
+/// Start paging out when there are less free pages than this (16mb)
+const LOW_WATERMARK: usize = 0x1000;
+/// Stop paging out once there are this many free pages (32mb)
+const HIGH_WATERMARK: usize = 0x2000;
+const PAGEOUT_INTERVAL_MS: u64 = 1000;
+
+/// A page sized slot in the swap partition.
+///
+/// Slots are reference counted manually, every [`alloc_slot`] or [`dup_slot`] has to be matched
+/// with a [`free_slot`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapSlot(u32);
+
+#[repr(C, align(4096))]
+struct SwapBuffer([u8; 0x1000]);
+
+impl SwapBuffer {
+    fn new() -> Box<Self> {
+        Box::new(SwapBuffer([0; 0x1000]))
+    }
+}
+
+struct SwapState {
+    /// Reference count of every slot, zero means the slot is free
+    refs: Vec<u16>,
+    /// Where to start looking for a free slot
+    next: usize,
+    /// Pages that have been paged out but not written to disk yet
+    pending: BTreeMap<u32, AllocatedPage<GlobalPageAllocator>>,
+    /// The pending slot being written, cleared if it is freed in the meantime
+    writing: Option<u32>,
+}
+
+static SWAP_STATE: Spinlock<SwapState> = Spinlock::new(SwapState {
+    refs: Vec::new(),
+    next: 0,
+    pending: BTreeMap::new(),
+    writing: None,
+});
+
+/// Held across all disk io so that a slot can't be read while its pending page is being written
+static SWAP_DISK: Mutex<Option<FSPartitionDisk>> = Mutex::new(None);
+
+pub fn add_swap_partition(disk: FSPartitionDisk) {
+    let mut swap_disk = SWAP_DISK.lock();
+    if swap_disk.is_some() {
+        warn!("Ignoring extra swap partition");
+        return;
+    }
+
+    let slots = (disk.sectors() / SECTORS_PER_SLOT).min(u32::MAX as usize);
+    info!(
+        "Using swap partition with {}mb",
+        slots * 0x1000 / 1024 / 1024
+    );
+
+    SWAP_STATE.lock().refs = vec![0; slots];
+    *swap_disk = Some(disk);
+}
+
+/// Allocates a slot for the page, the page is kept in memory until the pageout daemon writes it
+pub fn alloc_slot(
+    page: AllocatedPage<GlobalPageAllocator>,
+) -> Result<SwapSlot, AllocatedPage<GlobalPageAllocator>> {
+    let mut state = SWAP_STATE.lock();
+    let len = state.refs.len();
+    let Some(slot) = (0..len)
+        .map(|i| (state.next + i) % len)
+        .find(|&s| state.refs[s] == 0)
+    else {
+        return Err(page);
+    };
+
+    state.refs[slot] = 1;
+    state.next = slot + 1;
+    state.pending.insert(slot as u32, page);
+    Ok(SwapSlot(slot as u32))
+}
+
+pub fn dup_slot(slot: SwapSlot) -> SwapSlot {
+    let mut state = SWAP_STATE.lock();
+    let refs = &mut state.refs[slot.0 as usize];
+    *refs = refs
+        .checked_add(1)
+        .expect("too many references to swap slot");
+    slot
+}
+
+pub fn free_slot(slot: SwapSlot) {
+    let mut state = SWAP_STATE.lock();
+    let refs = &mut state.refs[slot.0 as usize];
+    *refs -= 1;
+    if *refs == 0 {
+        state.pending.remove(&slot.0);
+        if state.writing == Some(slot.0) {
+            state.writing = None;
+        }
+    }
+}
+
+/// Reads the contents of a slot into a newly allocated page.
+///
+/// The caller must own a reference to the slot and can't be holding any memory locks as the disk
+/// driver needs to translate the buffer address.
+pub fn read_slot(slot: SwapSlot) -> Option<AllocatedPage<GlobalPageAllocator>> {
+    let page = AllocatedPage::new(GlobalPageAllocator)?;
+    let dest = virt_addr_for_phys(page.get_address()) as *mut u8;
+
+    let disk = SWAP_DISK.lock();
+    if let Some(pending) = SWAP_STATE.lock().pending.get(&slot.0) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                virt_addr_for_phys(pending.get_address()) as *const u8,
+                dest,
+                0x1000,
+            )
+        };
+        return Some(page);
+    }
+
+    let mut buffer = SwapBuffer::new();
+    disk.as_ref()?.read(
+        slot.0 as usize * SECTORS_PER_SLOT,
+        SECTORS_PER_SLOT as u32,
+        &mut buffer.0,
+    )?;
+    unsafe { core::ptr::copy_nonoverlapping(buffer.0.as_ptr(), dest, 0x1000) };
+    Some(page)
+}
+
+/// Writes one pending page out to disk, freeing its memory. Returns Ok(false) once there is
+/// nothing left to write, or the slot that failed to write. That page stays pending so its
+/// contents aren't lost.
+fn write_pending_page(buffer: &mut SwapBuffer) -> Result<bool, u32> {
+    let disk = SWAP_DISK.lock();
+    let Some(disk) = disk.as_ref() else {
+        return Ok(false);
+    };
+    let slot = {
+        let mut state = SWAP_STATE.lock();
+        let Some((&slot, page)) = state.pending.first_key_value() else {
+            return Ok(false);
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                virt_addr_for_phys(page.get_address()) as *const u8,
+                buffer.0.as_mut_ptr(),
+                0x1000,
+            )
+        };
+        state.writing = Some(slot);
+        slot
+    };
+
+    let written = disk
+        .write(
+            slot as usize * SECTORS_PER_SLOT,
+            SECTORS_PER_SLOT as u32,
+            &mut buffer.0,
+        )
+        .is_some();
+
+    let mut state = SWAP_STATE.lock();
+    // If it was freed while writing the slot may already hold another page
+    let page = match state.writing.take() {
+        Some(_) if written => state.pending.remove(&slot),
+        _ => None,
+    };
+    drop(state);
+    drop(page);
+
+    if written {
+        Ok(true)
+    } else {
+        Err(slot)
+    }
+}
+
+/// Writes out every pending page, false if one failed to write
+fn write_pending() -> bool {
+    let mut buffer = SwapBuffer::new();
+    loop {
+        match write_pending_page(&mut buffer) {
+            Ok(true) => (),
+            Ok(false) => return true,
+            Err(slot) => {
+                error!("Failed to write swap slot {slot}");
+                return false;
+            }
+        }
+    }
+}
+
+fn free_pages() -> usize {
+    frame_alloc_exec(|a| a.free_pages())
+}
+
+/// Pages out memory from user processes until there is [`HIGH_WATERMARK`] free pages
+fn reclaim() {
+    let processes: Vec<_> = PROCESSES
+        .lock()
+        .values()
+        .filter(|p| p.privilege != ProcessPrivilige::KERNEL)
+        .cloned()
+        .collect();
+
+    let mut wanted = HIGH_WATERMARK.saturating_sub(free_pages());
+    for process in processes {
+        if wanted == 0 {
+            break;
+        }
+        let paged_out = process.memory.lock().page_mapper.page_out(wanted);
+        wanted -= paged_out;
+    }
+}
+
+pub fn pageout() {
+    loop {
+        sleep(PAGEOUT_INTERVAL_MS);
+
+        if SWAP_STATE.lock().refs.is_empty() || free_pages() >= LOW_WATERMARK {
+            continue;
+        }
+
+        // Pages that failed to write before are retried first, nothing more is paged out
+        // while the disk can't take them
+        if !write_pending() {
+            continue;
+        }
+
+        reclaim();
+        write_pending();
+    }
+}
@@ -0,0 +1,181 @@
+//! TLB shootdowns.
+//!
+//! Unmapping or downgrading a page only flushes the local TLB, other cores running the same
+//! address space can keep using their stale translation. Changes are collected into a
+//! [`TlbShootdown`] batch which flushes the local core and sends an IPI to every other core that
+//! might have the address space loaded, waiting until they have all flushed.
+//!
+//! Only one shootdown is in flight at a time. Cores that are spinning with interrupts held can't
+//! take the IPI, so the spin loops in [`crate::mutex`] call [`handle_pending`] to avoid
+//! deadlocking with the initiator.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use x86_64::{
+    registers::control::{Cr3, Cr4, Cr4Flags},
+    structures::idt::InterruptStackFrame,
+};
+
+use crate::{
+    cpu_localstorage::{is_ls_enabled, CPULocalStorageRW},
+    interrupts::TLB_SHOOTDOWN_IPI,
+    ioapic::send_ipi_to,
+    mutex::Spinlock,
+    scheduling::stats::is_core_online,
+};
+
+use super::page_table::Flusher;
+
+/// Past this many pages the whole TLB is flushed instead
+const BATCH_SIZE: usize = 32;
+
+/// The address space each core is running, 0 for the kernels own
+static ACTIVE_CR3: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Set by the initiator on every core that has to flush the current request
+static PENDING: [AtomicBool; 256] = [const { AtomicBool::new(false) }; 256];
+
+/// The request being shot down, only written while holding [`SHOOTDOWN_LOCK`]
+struct Request {
+    /// Number of pages, more than [`BATCH_SIZE`] means flush everything
+    count: AtomicUsize,
+    pages: [AtomicU64; BATCH_SIZE],
+    /// If global pages have to be flushed as well
+    global: AtomicBool,
+    /// Cores that haven't flushed yet
+    remaining: AtomicUsize,
+}
+
+static REQUEST: Request = Request {
+    count: AtomicUsize::new(0),
+    pages: [const { AtomicU64::new(0) }; BATCH_SIZE],
+    global: AtomicBool::new(false),
+    remaining: AtomicUsize::new(0),
+};
+
+static SHOOTDOWN_LOCK: Spinlock<()> = Spinlock::new(());
+
+/// Called by the scheduler when switching address spaces
+pub fn set_active_cr3(cr3: u64) {
+    ACTIVE_CR3[CPULocalStorageRW::get_core_id() as usize].store(cr3, Ordering::SeqCst);
+}
+
+fn flush_all(global: bool) {
+    unsafe {
+        if global {
+            // Toggling PGE flushes global pages too
+            let cr4 = Cr4::read();
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        } else {
+            let (frame, flags) = Cr3::read();
+            Cr3::write(frame, flags);
+        }
+    }
+}
+
+fn flush(count: usize, pages: impl Iterator<Item = u64>, global: bool) {
+    if count > BATCH_SIZE {
+        flush_all(global);
+    } else {
+        pages.take(count).for_each(|p| Flusher::new(p).flush());
+    }
+}
+
+/// Flushes the current request if it is targeting this core
+pub fn handle_pending() {
+    if !is_ls_enabled() {
+        return;
+    }
+    let core = CPULocalStorageRW::get_core_id() as usize;
+    if !PENDING[core].swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    flush(
+        REQUEST.count.load(Ordering::Relaxed),
+        REQUEST.pages.iter().map(|p| p.load(Ordering::Relaxed)),
+        REQUEST.global.load(Ordering::Relaxed),
+    );
+    REQUEST.remaining.fetch_sub(1, Ordering::Release);
+}
+
+pub fn tlb_shootdown_handler(_: InterruptStackFrame) {
+    handle_pending();
+}
+
+/// A batch of pages to flush from every core running an address space, the pages are flushed
+/// when the batch is dropped
+pub struct TlbShootdown {
+    /// Address space the pages belong to, None if they are mapped in every address space
+    cr3: Option<u64>,
+    count: usize,
+    pages: [u64; BATCH_SIZE],
+}
+
+impl TlbShootdown {
+    pub fn new(cr3: Option<u64>) -> Self {
+        Self {
+            cr3,
+            count: 0,
+            pages: [0; BATCH_SIZE],
+        }
+    }
+
+    pub fn add(&mut self, addr: u64) {
+        if let Some(p) = self.pages.get_mut(self.count) {
+            *p = addr;
+        }
+        self.count += 1;
+    }
+}
+
+/// Flushes a single page from every core running the address space
+pub fn shootdown_page(cr3: Option<u64>, addr: u64) {
+    let mut shootdown = TlbShootdown::new(cr3);
+    shootdown.add(addr);
+}
+
+impl Drop for TlbShootdown {
+    fn drop(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        let global = self.cr3.is_none();
+        flush(self.count, self.pages.iter().copied(), global);
+
+        if !is_ls_enabled() {
+            return;
+        }
+
+        let _lock = SHOOTDOWN_LOCK.lock();
+        let this_core = CPULocalStorageRW::get_core_id();
+        // No allocations as this is used by the heap allocator
+        let mut targets = [false; 256];
+        for (core, target) in targets.iter_mut().enumerate() {
+            *target = core != this_core as usize
+                && is_core_online(core as u8)
+                && self
+                    .cr3
+                    .is_none_or(|cr3| ACTIVE_CR3[core].load(Ordering::SeqCst) == cr3);
+        }
+
+        REQUEST.count.store(self.count, Ordering::Relaxed);
+        for (slot, &page) in REQUEST.pages.iter().zip(&self.pages) {
+            slot.store(page, Ordering::Relaxed);
+        }
+        REQUEST.global.store(global, Ordering::Relaxed);
+        REQUEST
+            .remaining
+            .store(targets.iter().filter(|&&t| t).count(), Ordering::Relaxed);
+
+        for core in (0..=255u8).filter(|&c| targets[c as usize]) {
+            PENDING[core as usize].store(true, Ordering::Release);
+            send_ipi_to(core, TLB_SHOOTDOWN_IPI as u8);
+        }
+
+        while REQUEST.remaining.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
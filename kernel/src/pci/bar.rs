@@ -0,0 +1,88 @@
+//! The base address registers of a device, which say where its registers are in memory or io
+//! space.
+
+use super::PCIDevice;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Bar {
+    Memory { base: u64, size: u64 },
+    Io { port: u32, size: u32 },
+}
+
+fn bar_offset(bar: u8) -> u32 {
+    0x10 + bar as u32 * 4
+}
+
+/// Works out where the bar is and how big it is. None if it isn't implemented or is the top
+/// half of the 64 bit bar before it.
+///
+/// The size is found by writing all ones to the bar, so the device stops decoding while this
+/// is done and nothing else can be using it.
+pub fn read_bar(device: &mut dyn PCIDevice, bar: u8) -> Option<Bar> {
+    if bar > 5 || unsafe { device.read_u8(0x0E) } & 0x7F != 0 {
+        return None;
+    }
+    if bar > 0 {
+        let prev = unsafe { device.read_u32(bar_offset(bar - 1)) };
+        if prev & 0b111 == 0b100 {
+            return None;
+        }
+    }
+
+    unsafe {
+        let command = device.read_u16(4);
+        device.write_u16(4, command & !(COMMAND_IO | COMMAND_MEMORY));
+        let res = size_bar(device, bar);
+        device.write_u16(4, command);
+        res
+    }
+}
+
+unsafe fn size_bar(device: &mut dyn PCIDevice, bar: u8) -> Option<Bar> {
+    let offset = bar_offset(bar);
+    let low = device.read_u32(offset);
+    device.write_u32(offset, u32::MAX);
+    let low_mask = device.read_u32(offset);
+    device.write_u32(offset, low);
+
+    if low & 1 == 1 {
+        let mask = low_mask & !0b11;
+        if mask == 0 {
+            return None;
+        }
+        // The top half of io bars can read as zero
+        let size = (!(mask | 0xFFFF_0000)).wrapping_add(1);
+        return Some(Bar::Io {
+            port: low & !0b11,
+            size,
+        });
+    }
+
+    let is_64 = low & 0b110 == 0b100;
+    let mut base = (low & !0xF) as u64;
+    let mut mask = (low_mask & !0xF) as u64;
+    if is_64 {
+        if bar == 5 {
+            return None;
+        }
+        let high = device.read_u32(offset + 4);
+        device.write_u32(offset + 4, u32::MAX);
+        let high_mask = device.read_u32(offset + 4);
+        device.write_u32(offset + 4, high);
+        base |= (high as u64) << 32;
+        mask |= (high_mask as u64) << 32;
+    }
+    if mask == 0 {
+        return None;
+    }
+    if !is_64 {
+        mask |= 0xFFFF_FFFF_0000_0000;
+    }
+    Some(Bar::Memory {
+        base,
+        size: (!mask).wrapping_add(1),
+    })
+}
@@ -0,0 +1,118 @@
+//! The capability list in a device's configuration space, which says where the registers of
+//! optional features like power management and message signalled interrupts are.
+
+use kernel_userspace::syscall::sleep;
+
+use super::PCIDevice;
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCIE: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability's registers start, the id and next pointer being the first two bytes
+    pub offset: u32,
+}
+
+/// Walks the list, see [`capabilities`]
+pub struct Capabilities<'a> {
+    device: &'a dyn PCIDevice,
+    next: u8,
+    // Guards against a list that loops back on itself
+    left: u8,
+}
+
+impl Iterator for Capabilities<'_> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        if self.next == 0 || self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let offset = self.next as u32;
+        unsafe {
+            self.next = self.device.read_u8(offset + 1) & !0b11;
+            Some(Capability {
+                id: self.device.read_u8(offset),
+                offset,
+            })
+        }
+    }
+}
+
+pub fn capabilities(device: &dyn PCIDevice) -> Capabilities<'_> {
+    let next = unsafe {
+        if device.read_u16(6) & STATUS_CAPABILITIES == 0 {
+            0
+        } else {
+            device.read_u8(0x34) & !0b11
+        }
+    };
+    Capabilities {
+        device,
+        next,
+        left: 48,
+    }
+}
+
+/// The offset of the first capability with the id
+pub fn find_capability(device: &dyn PCIDevice, id: u8) -> Option<u32> {
+    capabilities(device).find(|c| c.id == id).map(|c| c.offset)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
+}
+
+const PM_SUPPORTS_D1: u16 = 1 << 9;
+const PM_SUPPORTS_D2: u16 = 1 << 10;
+const PM_STATE_MASK: u16 = 0b11;
+
+/// The state the device is in, devices without power management are always in D0
+pub fn power_state(device: &dyn PCIDevice) -> PowerState {
+    let Some(cap) = find_capability(device, CAP_POWER_MANAGEMENT) else {
+        return PowerState::D0;
+    };
+    match unsafe { device.read_u16(cap + 4) } & PM_STATE_MASK {
+        0 => PowerState::D0,
+        1 => PowerState::D1,
+        2 => PowerState::D2,
+        _ => PowerState::D3Hot,
+    }
+}
+
+/// Moves the device to the state, returns false if it doesn't support it. Coming back from
+/// D3 may reset the device, so its bars and command register have to be set up again.
+pub fn set_power_state(device: &mut dyn PCIDevice, state: PowerState) -> bool {
+    let Some(cap) = find_capability(device, CAP_POWER_MANAGEMENT) else {
+        return state == PowerState::D0;
+    };
+    unsafe {
+        let supports = device.read_u16(cap + 2);
+        match state {
+            PowerState::D1 if supports & PM_SUPPORTS_D1 == 0 => return false,
+            PowerState::D2 if supports & PM_SUPPORTS_D2 == 0 => return false,
+            _ => (),
+        }
+
+        let control = device.read_u16(cap + 4);
+        if control & PM_STATE_MASK == state as u16 {
+            return true;
+        }
+        device.write_u16(cap + 4, (control & !PM_STATE_MASK) | state as u16);
+    }
+    // The device can't be touched for 10ms after going to or from D3
+    sleep(10);
+    true
+}
@@ -1,4 +1,4 @@
-use alloc::{boxed::Box, sync::Arc};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 
 use crate::paging::{
     ensure_ident_map_curr_process,
@@ -8,8 +8,10 @@ use crate::paging::{
 
 use super::{mcfg::MCFG, PCIBus, PCIDevice, PCIHeaderCommon};
 
-pub struct ExpressPCI<'mcfg> {
-    mcfg: &'mcfg MCFG,
+pub struct ExpressPCI {
+    /// The segment and where its config space starts, copied out of the MCFG so the buses can
+    /// be scanned again after the acpi tables are gone
+    regions: Vec<(u16, u64)>,
 }
 
 pub struct PCIExpressDevice {
@@ -46,24 +48,27 @@ impl PCIDevice for PCIExpressDevice {
     }
 }
 
-impl<'mcfg> ExpressPCI<'mcfg> {
-    pub fn new(mcfg: &'mcfg MCFG) -> Self {
-        Self { mcfg }
+impl ExpressPCI {
+    pub fn new(mcfg: &MCFG) -> Self {
+        let regions = mcfg
+            .entries()
+            .iter()
+            .map(|e| (e.pci_segment_group, e.base_address))
+            .collect();
+        Self { regions }
     }
 
     fn get_address(&self, segment: u16, bus: u8, device: u8, function: u8) -> Option<u64> {
         let offset = (bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
 
-        for entry in self.mcfg.entries() {
-            if entry.pci_segment_group == segment {
-                return Some(entry.base_address + offset);
-            }
-        }
-        None
+        self.regions
+            .iter()
+            .find(|(s, _)| *s == segment)
+            .map(|(_, base)| base + offset)
     }
 }
 
-impl<'mcfg> PCIBus for ExpressPCI<'mcfg> {
+impl PCIBus for ExpressPCI {
     fn get_device(&mut self, segment: u16, bus: u8, device: u8, function: u8) -> PCIHeaderCommon {
         let address = self.get_address(segment, bus, device, function).unwrap();
 
@@ -1,22 +1,43 @@
 use crate::{
     acpi::FioxaAcpiHandler,
-    bootfs::AMD_PCNET_DRIVER,
-    driver::{disk::ahci::AHCIDriver, driver::Driver},
+    bootfs::{
+        AMD_PCNET_DRIVER, BOCHS_DRIVER, E1000_DRIVER, HDA_DRIVER, RTL8139_DRIVER, VIRTIO_GPU_DRIVER,
+    },
+    cpu_localstorage::CPULocalStorageRW,
+    driver::{
+        disk::{ahci::AHCIDriver, virtio_blk::VirtioBlkDriver},
+        driver::Driver,
+    },
     elf,
     fs::FSDRIVES,
+    ioapic, iommu,
     mutex::Spinlock,
+    paging::page_mapper::PageMapping,
+    scheduling::with_held_interrupts,
 };
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    mem::take,
+    ops::{ControlFlow, Range},
+    sync::atomic::Ordering,
+};
 
 use kernel_userspace::{
-    channel::channel_create_rs, object::KernelReference, process::clone_init_service,
-    service::SimpleService, syscall::spawn_thread,
+    channel::{channel_create_rs, channel_read_rs, channel_write_rs, ChannelReadResult},
+    object::KernelReference,
+    pci::{BarInfo, PciAddress, PciRescan, PciServiceMessage},
+    process::clone_init_service,
+    service::{deserialize, serialize, Service, SimpleService},
+    syscall::spawn_thread,
 };
 use mcfg::MCFG;
+pub mod bar;
+pub mod capability;
 mod express;
 mod legacy;
 mod mcfg;
+pub mod msi;
 mod pci_descriptors;
 
 pub type PCIDriver = Arc<Spinlock<dyn Driver + Send>>;
@@ -87,6 +108,19 @@ impl PCIHeaderCommon {
         unsafe { self.device.read_u8(15) }
     }
 
+    pub fn capabilities(&self) -> capability::Capabilities<'_> {
+        capability::capabilities(&*self.device)
+    }
+
+    /// The offset of the first capability with the id
+    pub fn find_capability(&self, id: u8) -> Option<u32> {
+        capability::find_capability(&*self.device, id)
+    }
+
+    pub fn get_power_state(&self) -> capability::PowerState {
+        capability::power_state(&*self.device)
+    }
+
     pub unsafe fn get_as_header0(self) -> PCIHeader0 {
         PCIHeader0 {
             device: self.device,
@@ -126,58 +160,195 @@ impl PCIHeader0 {
     }
 }
 
-pub fn enumerate_pci(acpi_tables: acpi::AcpiTables<FioxaAcpiHandler>) {
-    // Enable interrupts
-    // set_handler_and_enable_irq(10, interrupt_handler);
-    // set_handler_and_enable_irq(11, interrupt_handler);
+/// How config space is reached
+enum ConfigAccess {
+    Express(express::ExpressPCI),
+    Legacy(legacy::LegacyPCI),
+}
+
+impl PCIBus for ConfigAccess {
+    fn get_device(&mut self, segment: u16, bus: u8, device: u8, function: u8) -> PCIHeaderCommon {
+        match self {
+            Self::Express(e) => e.get_device(segment, bus, device, function),
+            Self::Legacy(l) => l.get_device(segment, bus, device, function),
+        }
+    }
+
+    fn get_device_raw(
+        &mut self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+    ) -> Box<dyn PCIDevice> {
+        match self {
+            Self::Express(e) => e.get_device_raw(segment, bus, device, function),
+            Self::Legacy(l) => l.get_device_raw(segment, bus, device, function),
+        }
+    }
+}
+
+/// Everything needed to scan the buses again, owned by the `PCI` service
+struct PciBuses {
+    access: ConfigAccess,
+    /// The buses of each segment
+    buses: Vec<(u16, Range<u8>)>,
+}
 
-    // Get MCFG
-    let mcfg = acpi_tables.find_table::<MCFG>();
+/// A function that was found by the last scan
+struct PresentDevice {
+    vendor_id: u16,
+    device_id: u16,
+    /// Dropped when the device is removed, which closes the drivers' ends
+    watchers: Vec<KernelReference>,
+}
+
+static DEVICES: Spinlock<BTreeMap<PciAddress, PresentDevice>> = Spinlock::new(BTreeMap::new());
 
-    // Enumerate PCI using mcfg;
-    match &mcfg {
+pub fn enumerate_pci(acpi_tables: acpi::AcpiTables<FioxaAcpiHandler>) {
+    let mut pci = match acpi_tables.find_table::<MCFG>() {
         Ok(mcfg) => {
             debug!("Enumerating PCI using MCFG...");
-            let mut pci_bus = express::ExpressPCI::new(mcfg);
-            for entry in mcfg.entries() {
-                for bus_number in entry.bus_number_start..entry.bus_number_end {
-                    enumerate_bus(&mut pci_bus, entry.pci_segment_group, bus_number)
-                }
+            PciBuses {
+                access: ConfigAccess::Express(express::ExpressPCI::new(&mcfg)),
+                buses: mcfg
+                    .entries()
+                    .iter()
+                    .map(|e| (e.pci_segment_group, e.bus_number_start..e.bus_number_end))
+                    .collect(),
             }
-            return;
         }
-        Err(e) => error!("Error with getting MCFG table: {:?}", e),
-    }
-    // Enumerate using legacy port based
-    {
-        debug!("Enumerating PCI using legacy ports...");
-        let mut pci_bus = legacy::LegacyPCI {};
-        for bus_number in 0..255 {
-            enumerate_bus(&mut pci_bus, 0, bus_number)
+        Err(e) => {
+            error!("Error with getting MCFG table: {:?}", e);
+            debug!("Enumerating PCI using legacy ports...");
+            PciBuses {
+                access: ConfigAccess::Legacy(legacy::LegacyPCI {}),
+                buses: vec![(0, 0..255)],
+            }
         }
-    }
+    };
+
+    rescan(&mut pci);
+    spawn_thread(move || pci_service(pci));
 }
 
-fn enumerate_bus(pci_bus: &mut impl PCIBus, segment: u16, bus: u8) {
-    let pci_header = pci_bus.get_device(segment, bus, 0, 0);
+/// Every function on the buses with its vendor and device id
+fn scan(pci: &mut PciBuses) -> BTreeMap<PciAddress, (u16, u16)> {
+    let present = |header: &PCIHeaderCommon| {
+        let id = header.get_device_id();
+        (id != 0 && id != 0xFFFF).then(|| (header.get_vendor_id(), id))
+    };
 
-    if pci_header.get_device_id() == 0 || pci_header.get_device_id() == 0xFFFF {
-        return;
-    }
-    for device in 0..32 {
-        enumerate_device(pci_bus, segment, bus, device)
+    let mut found = BTreeMap::new();
+    for (segment, buses) in pci.buses.clone() {
+        for bus in buses {
+            if present(&pci.access.get_device(segment, bus, 0, 0)).is_none() {
+                continue;
+            }
+            for device in 0..32 {
+                if present(&pci.access.get_device(segment, bus, device, 0)).is_none() {
+                    continue;
+                }
+                for function in 0..8 {
+                    let header = pci.access.get_device(segment, bus, device, function);
+                    if let Some(ids) = present(&header) {
+                        let address = PciAddress {
+                            segment,
+                            bus,
+                            device,
+                            function,
+                        };
+                        found.insert(address, ids);
+                    }
+                }
+            }
+        }
     }
+    found
 }
 
-fn enumerate_device(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device: u8) {
-    let pci_header = pci_bus.get_device(segment, bus, device, 0);
-
-    if pci_header.get_device_id() == 0 || pci_header.get_device_id() == 0xFFFF {
-        return;
+/// Compares the buses against the last scan, starting drivers for the new functions and
+/// telling the drivers of the ones that are gone
+fn rescan(pci: &mut PciBuses) -> PciRescan {
+    let found = scan(pci);
+    let mut changes = PciRescan::default();
+
+    let mut gone = Vec::new();
+    DEVICES.lock().retain(|address, dev| {
+        let same = found.get(address) == Some(&(dev.vendor_id, dev.device_id));
+        if !same {
+            gone.push((*address, take(&mut dev.watchers)));
+        }
+        same
+    });
+    // The watchers are dropped here rather than with interrupts held
+    for (address, watchers) in gone {
+        info!(
+            "PCI {address} was removed, telling {} drivers",
+            watchers.len()
+        );
+        changes.removed.push(address);
     }
-    for function in 0..8 {
-        enumerate_function(pci_bus, segment, bus, device, function);
+
+    for (address, (vendor_id, device_id)) in found {
+        if DEVICES.lock().contains_key(&address) {
+            continue;
+        }
+        DEVICES.lock().insert(
+            address,
+            PresentDevice {
+                vendor_id,
+                device_id,
+                watchers: Vec::new(),
+            },
+        );
+        let PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        } = address;
+        enumerate_function(&mut pci.access, segment, bus, device, function);
+        changes.added.push(address);
     }
+    changes
+}
+
+fn pci_service(mut pci: PciBuses) {
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+
+    Service::new(
+        "PCI",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            let resp = match deserialize(&buffer) {
+                Ok(PciServiceMessage::Rescan) => {
+                    let changes = rescan(&mut pci);
+                    info!(
+                        "PCI rescan: {} added, {} removed",
+                        changes.added.len(),
+                        changes.removed.len()
+                    );
+                    changes
+                }
+                Err(e) => {
+                    error!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+
+            channel_write_rs(handle.id(), serialize(&resp, &mut buffer), &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
 }
 
 fn enumerate_function(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device: u8, function: u8) {
@@ -186,6 +357,7 @@ fn enumerate_function(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device:
     if pci_header.get_device_id() == 0 || pci_header.get_device_id() == 0xFFFF {
         return;
     }
+    iommu::add_device(segment, bus, device, function);
 
     let class = pci_header.get_class() as usize;
     let cls = if class < pci_descriptors::DEVICE_CLASSES.len() {
@@ -203,6 +375,8 @@ fn enumerate_function(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device:
             .unwrap_or(format!("Unknown device: {:#X}", { pci_header.get_device_id() }).as_str())
     );
 
+    route_interrupt(pci_bus, segment, bus, device, function);
+
     // Specific drivers
     match pci_header.get_vendor_id() {
         // AMD
@@ -210,15 +384,89 @@ fn enumerate_function(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device:
             // AM79c973
             0x2000 => {
                 debug!("AMD PCnet");
-                let sid = pci_dev_handler(pci_bus, segment, bus, device, function);
-
-                elf::load_elf(
+                // The driver uses the registers in memory space and the card does dma
+                enable_bus_master(pci_bus, segment, bus, device, function);
+                // Everything it touches comes through the handles, so it runs as a user process
+                spawn_driver(
+                    pci_bus,
+                    segment,
+                    bus,
+                    device,
+                    function,
                     AMD_PCNET_DRIVER,
-                    &[],
-                    &[KernelReference::from_id(clone_init_service()), sid],
+                    false,
+                );
+                return;
+            }
+            _ => (),
+        },
+        // Intel
+        0x8086 => match pci_header.get_device_id() {
+            // 8254x and 82574 gigabit ethernet
+            0x1004 | 0x100E | 0x100F | 0x107C | 0x10D3 => {
+                debug!("Intel e1000");
+                // The card reads and writes its rings itself
+                enable_bus_master(pci_bus, segment, bus, device, function);
+                spawn_driver(pci_bus, segment, bus, device, function, E1000_DRIVER, true);
+                return;
+            }
+            _ => (),
+        },
+        // Realtek
+        0x10EC => match pci_header.get_device_id() {
+            0x8139 => {
+                debug!("Realtek RTL8139");
+                // The card reads and writes its buffers itself
+                enable_bus_master(pci_bus, segment, bus, device, function);
+                spawn_driver(
+                    pci_bus,
+                    segment,
+                    bus,
+                    device,
+                    function,
+                    RTL8139_DRIVER,
                     true,
-                )
-                .unwrap();
+                );
+                return;
+            }
+            _ => (),
+        },
+        // QEMU
+        0x1234 => match pci_header.get_device_id() {
+            // Standard vga and bochs-display
+            0x1111 => {
+                debug!("Bochs VBE");
+                spawn_driver(pci_bus, segment, bus, device, function, BOCHS_DRIVER, false);
+                return;
+            }
+            _ => (),
+        },
+        // Red Hat (virtio)
+        0x1AF4 => match pci_header.get_device_id() {
+            // Transitional virtio-blk
+            0x1001 => {
+                debug!("virtio-blk");
+                enable_bus_master(pci_bus, segment, bus, device, function);
+                match VirtioBlkDriver::new(pci_header) {
+                    Some(d) => FSDRIVES.lock().add_device(Box::new(d)),
+                    None => error!("virtio-blk Driver failed to init."),
+                }
+                return;
+            }
+            // virtio-gpu, which is modern only
+            0x1050 => {
+                debug!("virtio-gpu");
+                // The host reads the command queue and our framebuffer itself
+                enable_bus_master(pci_bus, segment, bus, device, function);
+                spawn_driver(
+                    pci_bus,
+                    segment,
+                    bus,
+                    device,
+                    function,
+                    VIRTIO_GPU_DRIVER,
+                    false,
+                );
                 return;
             }
             _ => (),
@@ -248,10 +496,27 @@ fn enumerate_function(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device:
             }
             _ => (),
         },
+        // Multimedia
+        0x04 => match pci_header.get_subclass() {
+            // High definition audio
+            0x03 => {
+                debug!("HDA");
+                // The controller reads its command ring and the samples itself
+                enable_bus_master(pci_bus, segment, bus, device, function);
+                spawn_driver(pci_bus, segment, bus, device, function, HDA_DRIVER, false);
+            }
+            _ => (),
+        },
         _ => (),
     }
 }
 
+/// A device's config space for code that only has its address, like the aml interpreter. Only
+/// the first segment can be reached this way.
+pub fn config_space(segment: u16, bus: u8, device: u8, function: u8) -> Option<Box<dyn PCIDevice>> {
+    (segment == 0).then(|| legacy::LegacyPCI {}.get_device_raw(0, bus, device, function))
+}
+
 trait PCIBus {
     fn get_device(&mut self, segment: u16, bus: u8, device: u8, function: u8) -> PCIHeaderCommon;
     fn get_device_raw(
@@ -263,6 +528,85 @@ trait PCIBus {
     ) -> Box<dyn PCIDevice>;
 }
 
+/// Points the ioapic line the device's interrupt pin is wired to at the pci vector, using the
+/// aml routing table and falling back to the line firmware wrote into the device
+fn route_interrupt(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device: u8, function: u8) {
+    let mut dev = pci_bus.get_device_raw(segment, bus, device, function);
+    let pin = unsafe { dev.read_u8(0x3D) };
+    if pin == 0 {
+        return;
+    }
+
+    let routed = (segment == 0 && bus == 0)
+        .then(|| crate::acpi::aml::route_pci_interrupt(device, function, pin))
+        .flatten();
+    match routed {
+        Some((gsi, active_low)) => {
+            ioapic::route_pci_irq(gsi, active_low);
+            // Only for anything that reads it, the line isn't used by anything here
+            if let Ok(line) = u8::try_from(gsi) {
+                unsafe { dev.write_u8(0x3C, line) };
+            }
+        }
+        None => match unsafe { dev.read_u8(0x3C) } {
+            0 | 0xFF => warn!("No interrupt route for pci {bus}.{device}.{function}"),
+            line => ioapic::route_pci_irq(line as u32, false),
+        },
+    }
+}
+
+/// Lets the device respond to IO and memory accesses and do DMA, firmware only sets this up for
+/// devices it used itself
+fn enable_bus_master(pci_bus: &mut impl PCIBus, segment: u16, bus: u8, device: u8, function: u8) {
+    let mut device = pci_bus.get_device_raw(segment, bus, device, function);
+    unsafe {
+        let command = device.read_u16(4);
+        device.write_u16(4, command | 0b111);
+    }
+}
+
+/// The registers a driver can't write to. Drivers are given the bars through handles, and could
+/// point them or the device's interrupt messages anywhere in memory otherwise.
+fn protected_registers(device: &dyn PCIDevice) -> Vec<Range<u32>> {
+    let mut protected = vec![0x10..0x28, 0x30..0x34];
+    for cap in capability::capabilities(device) {
+        match cap.id {
+            capability::CAP_MSI => protected.push(cap.offset..cap.offset + 24),
+            capability::CAP_MSIX => protected.push(cap.offset..cap.offset + 12),
+            _ => (),
+        }
+    }
+    protected
+}
+
+/// Starts a userspace driver for the device, in an iommu domain of its own if there is one
+fn spawn_driver(
+    pci_bus: &mut impl PCIBus,
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+    driver: &[u8],
+    kernel: bool,
+) {
+    let sid = pci_dev_handler(pci_bus, segment, bus, device, function);
+    let domain = iommu::isolate(segment, bus, device, function);
+
+    elf::load_elf_with(
+        driver,
+        None,
+        &[],
+        &[KernelReference::from_id(clone_init_service()), sid],
+        kernel,
+        true,
+        |p| {
+            p.driver.store(true, Ordering::Relaxed);
+            p.dma_domains.lock().extend(domain);
+        },
+    )
+    .unwrap();
+}
+
 fn pci_dev_handler(
     pci_bus: &mut impl PCIBus,
     segment: u16,
@@ -270,13 +614,28 @@ fn pci_dev_handler(
     device: u8,
     function: u8,
 ) -> KernelReference {
+    let address = PciAddress {
+        segment,
+        bus,
+        device,
+        function,
+    };
     let mut device = pci_bus.get_device_raw(segment, bus, device, function);
+    // Firmware can leave devices it didn't use asleep
+    if !capability::set_power_state(&mut *device, capability::PowerState::D0) {
+        error!("Couldn't wake pci device");
+    }
+    // Sized before the driver starts as sizing stops the device decoding for a moment
+    let bars: [Option<bar::Bar>; 6] =
+        core::array::from_fn(|i| bar::read_bar(&mut *device, i as u8));
+    let protected = protected_registers(&*device);
     let (left, right) = channel_create_rs();
     spawn_thread(move || {
         let mut service = SimpleService::new(left);
+        let mut msi_vector = None;
         loop {
             let Some(msg) = service.recv_val(&mut Vec::new()) else {
-                return;
+                break;
             };
 
             match msg {
@@ -284,16 +643,82 @@ fn pci_dev_handler(
                     let resp = device.read_u32(offset);
                     service.send_val(&resp, &[]);
                 },
-                kernel_userspace::pci::PCIDevCmd::Write(offset, data) if offset <= 256 => unsafe {
-                    device.write_u32(offset, data);
+                kernel_userspace::pci::PCIDevCmd::Write(offset, data) if offset <= 256 => {
+                    if offset & 0b11 == 0
+                        && !protected
+                            .iter()
+                            .any(|r| offset < r.end && offset + 4 > r.start)
+                    {
+                        unsafe { device.write_u32(offset, data) };
+                    } else {
+                        warn!("Driver tried to write pci register {offset:#x}");
+                    }
                     service.send_val(&(), &[]);
-                },
+                }
+                kernel_userspace::pci::PCIDevCmd::EnableMsi => {
+                    if let Some(vector) = msi_vector.take() {
+                        msi::disable(&mut *device, vector);
+                    }
+                    match msi::enable(&mut *device) {
+                        Some((vector, h)) => {
+                            msi_vector = Some(vector);
+                            let id = with_held_interrupts(|| unsafe {
+                                let thread = CPULocalStorageRW::get_current_task();
+                                KernelReference::from_id(thread.process().add_value(h.into()))
+                            });
+                            service.send_val(&true, &[id.id()]);
+                        }
+                        None => {
+                            service.send_val(&false, &[]);
+                        }
+                    }
+                }
+                kernel_userspace::pci::PCIDevCmd::GetBar(n) => {
+                    match bars.get(n as usize).copied().flatten() {
+                        Some(bar::Bar::Memory { base, size }) if base != 0 => {
+                            let start = base & !0xFFF;
+                            let end = (base + size + 0xFFF) & !0xFFF;
+                            let mapping = unsafe {
+                                PageMapping::new_mmap(start as usize, (end - start) as usize)
+                            };
+                            let id = with_held_interrupts(|| unsafe {
+                                let thread = CPULocalStorageRW::get_current_task();
+                                KernelReference::from_id(thread.process().add_value(mapping.into()))
+                            });
+                            let info = BarInfo::Memory {
+                                offset: (base - start) as usize,
+                                size,
+                            };
+                            service.send_val(&info, &[id.id()]);
+                        }
+                        Some(bar::Bar::Io { port, size }) if port != 0 => {
+                            service.send_val(&BarInfo::Io { port, size }, &[]);
+                        }
+                        _ => {
+                            service.send_val(&BarInfo::Unused, &[]);
+                        }
+                    }
+                }
+                kernel_userspace::pci::PCIDevCmd::WatchRemoval => {
+                    let (left, right) = channel_create_rs();
+                    // If it is already gone left is dropped, closing the channel straight away
+                    if let Some(dev) = DEVICES.lock().get_mut(&address) {
+                        dev.watchers.push(left);
+                    }
+                    service.send_val(&(), &[right.id()]);
+                }
                 _ => {
                     error!("Bad args to pci");
-                    return;
+                    break;
                 }
             };
         }
+
+        // The driver is gone, its interrupt handle with it
+        info!("Driver for pci device {address} exited");
+        if let Some(vector) = msi_vector {
+            msi::disable(&mut *device, vector);
+        }
     });
 
     right
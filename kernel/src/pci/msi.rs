@@ -0,0 +1,133 @@
+//! Message signalled interrupts. A device using them writes its own vector straight to the
+//! local apic instead of pulling on the pin it shares with the other devices, so each one
+//! gets a handle that only fires for it.
+
+use alloc::sync::Arc;
+
+use crate::{
+    interrupts::{alloc_msi_vector, free_msi_vector, KInterruptHandle},
+    paging::{
+        ensure_ident_map_curr_process,
+        page::{Page, Size4KB},
+        MemoryMappingFlags,
+    },
+};
+
+use super::{
+    capability::{find_capability, CAP_MSI, CAP_MSIX},
+    PCIDevice,
+};
+
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const MSI_ENABLE: u16 = 1;
+const MSI_64BIT: u16 = 1 << 7;
+const MSIX_ENABLE: u16 = 1 << 15;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+
+/// Messages go to the local apic of processor 0, like the ioapic's
+const MSG_ADDRESS: u32 = 0xFEE0_0000;
+
+/// Points the device's messages at a vector of its own and turns off its pin interrupt,
+/// returns the vector and the handle it triggers. None if the device can't send messages or
+/// the vectors have run out, then the pin is left as it was.
+pub fn enable(device: &mut dyn PCIDevice) -> Option<(u8, Arc<KInterruptHandle>)> {
+    let (vector, handle) = alloc_msi_vector()?;
+
+    let enabled = unsafe {
+        if let Some(cap) = find_capability(device, CAP_MSI) {
+            enable_msi(device, cap, vector);
+            true
+        } else if let Some(cap) = find_capability(device, CAP_MSIX) {
+            enable_msix(device, cap, vector)
+        } else {
+            false
+        }
+    };
+    if !enabled {
+        free_msi_vector(vector);
+        return None;
+    }
+
+    unsafe {
+        let command = device.read_u16(4);
+        device.write_u16(
+            4,
+            command | COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE,
+        );
+    }
+    Some((vector, handle))
+}
+
+/// Stops the device sending messages and frees the vector [`enable`] gave it, for when its
+/// driver is gone. The pin is turned back on for whichever driver comes next.
+pub fn disable(device: &mut dyn PCIDevice, vector: u8) {
+    unsafe {
+        if let Some(cap) = find_capability(device, CAP_MSI) {
+            let control = device.read_u16(cap + 2);
+            device.write_u16(cap + 2, control & !MSI_ENABLE);
+        } else if let Some(cap) = find_capability(device, CAP_MSIX) {
+            let control = device.read_u16(cap + 2);
+            device.write_u16(cap + 2, control & !MSIX_ENABLE);
+        }
+        let command = device.read_u16(4);
+        device.write_u16(4, command & !COMMAND_INTX_DISABLE);
+    }
+    free_msi_vector(vector);
+}
+
+unsafe fn enable_msi(device: &mut dyn PCIDevice, cap: u32, vector: u8) {
+    let control = device.read_u16(cap + 2);
+    device.write_u32(cap + 4, MSG_ADDRESS);
+    let data = if control & MSI_64BIT != 0 {
+        device.write_u32(cap + 8, 0);
+        cap + 12
+    } else {
+        cap + 8
+    };
+    device.write_u16(data, vector as u16);
+    // A single message, so the multiple message enable field is left at 0
+    device.write_u16(cap + 2, (control & !(0b111 << 4)) | MSI_ENABLE);
+}
+
+/// Only the first entry of the table is used, devices that want more than one vector send
+/// everything through it
+unsafe fn enable_msix(device: &mut dyn PCIDevice, cap: u32, vector: u8) -> bool {
+    let control = device.read_u16(cap + 2);
+    let table = device.read_u32(cap + 4);
+    let bar_num = table & 0b111;
+    if bar_num > 5 {
+        return false;
+    }
+
+    let bar = device.read_u32(0x10 + bar_num * 4);
+    if bar & 1 == 1 {
+        // The table has to be in memory
+        return false;
+    }
+    let mut base = (bar & !0xF) as u64;
+    if (bar >> 1) & 0b11 == 0b10 {
+        base |= (device.read_u32(0x14 + bar_num * 4) as u64) << 32;
+    }
+    if base == 0 {
+        return false;
+    }
+
+    let entry = base + (table & !0b111) as u64;
+    ensure_ident_map_curr_process(
+        Page::<Size4KB>::containing(entry),
+        MemoryMappingFlags::WRITEABLE,
+    );
+
+    // Nothing can be sent while the entry is half written
+    device.write_u16(cap + 2, control | MSIX_FUNCTION_MASK);
+    let entry = entry as *mut u32;
+    entry.write_volatile(MSG_ADDRESS);
+    entry.add(1).write_volatile(0);
+    entry.add(2).write_volatile(vector as u32);
+    entry.add(3).write_volatile(0);
+    device.write_u16(cap + 2, (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK);
+    true
+}
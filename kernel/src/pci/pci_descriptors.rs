@@ -31,6 +31,7 @@ pub const fn get_vendor_name<'a>(vendor_id: u16) -> Option<&'a str> {
     match vendor_id {
         0x8086 => Some("Intel"),
         0x1022 => Some("AMD"),
+        0x10EC => Some("Realtek"),
         0x10DE => Some("NVIDIA"),
         _ => None,
     }
@@ -39,6 +40,10 @@ pub const fn get_vendor_name<'a>(vendor_id: u16) -> Option<&'a str> {
 pub const fn get_device_name<'a>(vendor_id: u16, device_id: u16) -> Option<&'a str> {
     match vendor_id {
         0x8086 => match device_id {
+            0x1004 => Some("82543GC Gigabit Ethernet Controller (Copper)"),
+            0x100E => Some("82540EM Gigabit Ethernet Controller"),
+            0x100F => Some("82545EM Gigabit Ethernet Controller (Copper)"),
+            0x107C => Some("82541PI Gigabit Ethernet Controller"),
             0x10D3 => Some("82574L Gigabit Network Connection"),
             0x29C0 => Some("Express DRAM Controller"),
             0x2918 => Some("LPC Interface Controller"),
@@ -50,6 +55,10 @@ pub const fn get_device_name<'a>(vendor_id: u16, device_id: u16) -> Option<&'a s
             0x2000 => Some("AMD PCNET (AM79c973)"),
             _ => None,
         },
+        0x10EC => match device_id {
+            0x8139 => Some("RTL-8100/8101L/8139 PCI Fast Ethernet Adapter"),
+            _ => None,
+        },
         0x10DE => match device_id {
             _ => None,
         },
@@ -0,0 +1,24 @@
+//! Randomness for the kernel, used for layout randomization.
+
+use bootloader::random::{rdrand_u64, tsc_u64};
+
+use crate::cpu::{features, CpuFeatures};
+
+/// Gets a random number from rdrand, falling back to the tsc if it is not supported.
+/// Not suitable for anything that needs to be cryptographically secure.
+pub fn random_u64() -> u64 {
+    if features().contains(CpuFeatures::RDRAND) {
+        if let Some(val) = rdrand_u64() {
+            return val;
+        }
+    }
+    tsc_u64()
+}
+
+/// Random number in 0..bound
+pub fn random_below(bound: u64) -> u64 {
+    if bound == 0 {
+        return 0;
+    }
+    random_u64() % bound
+}
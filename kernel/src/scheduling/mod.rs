@@ -1,7 +1,11 @@
 use crate::cpu_localstorage::{is_ls_enabled, CPULocalStorageRW};
 
 pub mod process;
+pub mod reaper;
+pub mod stats;
+pub mod supervisor;
 pub mod taskmanager;
+pub mod trace;
 
 pub fn with_held_interrupts<F, R>(f: F) -> R
 where
@@ -1,11 +1,14 @@
 use core::{
     cell::UnsafeCell,
     fmt::Debug,
+    mem::take,
     num::NonZeroUsize,
-    sync::atomic::{AtomicU64, Ordering},
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
 
 use alloc::{
+    boxed::Box,
     collections::BTreeMap,
     sync::{Arc, Weak},
     vec::Vec,
@@ -15,9 +18,11 @@ use hashbrown::HashMap;
 use kernel_userspace::{
     ids::{ProcessID, ThreadID},
     object::{KernelObjectType, KernelReferenceID, ObjectSignal},
-    process::ProcessExit,
+    process::{ProcessExit, ProcessMemInfo, ProcessRUsage},
+    stats::SchedTraceKind,
 };
 use x86_64::{
+    align_up,
     structures::{gdt::SegmentSelector, idt::InterruptStackFrameValue},
     VirtAddr,
 };
@@ -26,8 +31,10 @@ use crate::{
     assembly::registers::SavedTaskState,
     channel::KChannelHandle,
     cpu_localstorage::CPULocalStorageRW,
+    fpu::FpuState,
     gdt,
     interrupts::KInterruptHandle,
+    iommu,
     message::KMessage,
     mutex::Spinlock,
     object::{KObject, KObjectSignal},
@@ -37,16 +44,59 @@ use crate::{
         virt_addr_for_phys, AllocatedPage, GlobalPageAllocator, MemoryMappingFlags,
     },
     port::KPort,
-    time::HPET,
+    random::random_below,
+    time::{uptime_us, HPET},
 };
 
-use super::taskmanager::{ThreadSchedGlobalData, PROCESSES, SCHEDULER};
+use super::{
+    stats,
+    taskmanager::{ThreadSchedGlobalData, PROCESSES, SCHEDULER},
+    trace,
+};
 
 pub const STACK_ADDR: u64 = 0x100_000_000_000;
+pub const TLS_ADDR: u64 = 0x180_000_000_000;
 pub const KSTACK_ADDR: u64 = 0xffff_800_000_000_000;
 
+/// Stacks and TLS blocks are moved up to this far from their default addresses with ASLR (256gb)
+pub const ASLR_RANGE: u64 = 0x40_0000_0000;
+
 pub const STACK_SIZE: u64 = 0x20000;
 pub const KSTACK_SIZE: u64 = 0x10000;
+pub const STACK_GUARD_SIZE: u64 = 0x1000;
+
+/// Where the stacks of a processes threads go, indexed by thread id. Every stack has an unmapped
+/// guard page below it so that overflowing it faults instead of running into the stack below.
+#[derive(Debug, Clone, Copy)]
+pub struct StackLayout {
+    pub base: u64,
+    pub size: u64,
+}
+
+impl StackLayout {
+    pub const fn new(base: u64, size: u64) -> Self {
+        Self { base, size }
+    }
+
+    pub fn stack_base(&self, tid: ThreadID) -> u64 {
+        self.base + STACK_GUARD_SIZE + (self.size + STACK_GUARD_SIZE) * tid.0
+    }
+
+    pub fn stack_range(&self, tid: ThreadID) -> Range<usize> {
+        let base = self.stack_base(tid) as usize;
+        base..base + self.size as usize
+    }
+
+    /// If address is in the guard page of one of the stacks
+    pub fn is_guard(&self, address: u64) -> bool {
+        address >= self.base
+            && (address - self.base) % (self.size + STACK_GUARD_SIZE) < STACK_GUARD_SIZE
+    }
+}
+
+/// The stacks threads use in kernel mode (syscalls and interrupts), they are the same in every
+/// process
+pub const KERNEL_STACKS: StackLayout = StackLayout::new(KSTACK_ADDR, KSTACK_SIZE);
 
 pub const THREAD_TEMP_COUNT: usize = 8;
 
@@ -55,7 +105,7 @@ fn generate_next_process_id() -> ProcessID {
     ProcessID(PID.fetch_add(1, Ordering::Relaxed))
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessPrivilige {
     KERNEL,
     USER,
@@ -75,6 +125,14 @@ impl ProcessPrivilige {
             ProcessPrivilige::USER => gdt::USER_DATA_SELECTOR,
         }
     }
+
+    /// Flags for the processes own memory, kernel processes can't use user pages with SMAP
+    pub fn mapping_flags(&self) -> MemoryMappingFlags {
+        match self {
+            ProcessPrivilige::KERNEL => MemoryMappingFlags::empty(),
+            ProcessPrivilige::USER => MemoryMappingFlags::USERSPACE,
+        }
+    }
 }
 
 pub struct Process {
@@ -90,6 +148,18 @@ pub struct Process {
     pub exit_status: Spinlock<ProcessExit>,
     pub signals: Spinlock<KObjectSignal>,
     pub name: &'static str,
+    pub rusage: ProcessRUsageCounters,
+    /// The iommu domains of the devices it drives, its dma allocations are mapped into them
+    pub dma_domains: Spinlock<Vec<Arc<iommu::Domain>>>,
+    /// Started to drive a device, which lets it allocate dma memory without being a kernel process
+    pub driver: AtomicBool,
+}
+
+#[derive(Default)]
+pub struct ProcessRUsageCounters {
+    pub user_time_us: AtomicU64,
+    pub kernel_time_us: AtomicU64,
+    pub context_switches: AtomicU64,
 }
 
 #[derive(Default)]
@@ -101,6 +171,57 @@ pub struct ProcessThreads {
 pub struct ProcessMemory {
     pub page_mapper: PageMapperManager,
     pub owned32_pages: Vec<AllocatedPage<GlobalPageAllocator>>,
+    pub tls_template: Option<TLSTemplate>,
+    /// Where the stack of the first thread goes, the stacks of other threads follow it
+    pub stack_base: u64,
+    /// Where the TLS block of the first thread goes
+    pub tls_base: u64,
+}
+
+impl ProcessMemory {
+    pub fn stacks(&self) -> StackLayout {
+        StackLayout::new(self.stack_base, STACK_SIZE)
+    }
+
+    /// Randomizes the placement of stacks, TLS blocks and mmaps, must be called before any threads
+    /// are created
+    pub fn randomize_layout(&mut self) {
+        self.stack_base = STACK_ADDR + random_below(ASLR_RANGE / 0x1000) * 0x1000;
+        self.tls_base = TLS_ADDR + random_below(ASLR_RANGE / 0x1000) * 0x1000;
+        self.page_mapper.set_aslr(true);
+    }
+
+    /// Copy on write duplicate of the userspace address space
+    pub fn fork_cow(&mut self) -> ProcessMemory {
+        ProcessMemory {
+            page_mapper: self.page_mapper.fork_cow(),
+            owned32_pages: Vec::new(),
+            tls_template: self.tls_template.clone(),
+            stack_base: self.stack_base,
+            tls_base: self.tls_base,
+        }
+    }
+}
+
+/// The PT_TLS segment of an elf, used to build the TLS block of each thread
+#[derive(Clone)]
+pub struct TLSTemplate {
+    pub image: Box<[u8]>,
+    pub mem_size: usize,
+    pub align: usize,
+}
+
+impl TLSTemplate {
+    /// Offset of the thread pointer from the start of the block.
+    /// x86_64 uses variant II, so the tls data sits directly below the thread pointer
+    pub fn tp_offset(&self) -> usize {
+        align_up(self.mem_size as u64, self.align.max(8) as u64) as usize
+    }
+
+    /// Size of the block including the TCB (which is just a self pointer), rounded to pages
+    pub fn block_size(&self) -> usize {
+        align_up(self.tp_offset() as u64 + 8, 0x1000) as usize
+    }
 }
 
 pub struct ProcessReferences {
@@ -152,16 +273,33 @@ impl Process {
                 .unwrap();
         }
 
+        Self::with_memory(
+            privilege,
+            args,
+            name,
+            ProcessMemory {
+                page_mapper,
+                owned32_pages: Default::default(),
+                tls_template: None,
+                stack_base: STACK_ADDR,
+                tls_base: TLS_ADDR,
+            },
+        )
+    }
+
+    fn with_memory(
+        privilege: ProcessPrivilige,
+        args: &[u8],
+        name: &'static str,
+        mut memory: ProcessMemory,
+    ) -> Arc<Self> {
         Arc::new_cyclic(|this| Self {
             this: this.clone(),
             pid: generate_next_process_id(),
             privilege,
             args: args.to_vec(),
-            cr3_page: unsafe { page_mapper.get_mapper_mut().get_physical_address() as u64 },
-            memory: Spinlock::new(ProcessMemory {
-                page_mapper,
-                owned32_pages: Default::default(),
-            }),
+            cr3_page: unsafe { memory.page_mapper.get_mapper_mut().get_physical_address() as u64 },
+            memory: Spinlock::new(memory),
             threads: Default::default(),
             references: Spinlock::new(ProcessReferences {
                 references: Default::default(),
@@ -170,6 +308,9 @@ impl Process {
             exit_status: Spinlock::new(ProcessExit::NotExitedYet),
             signals: Default::default(),
             name,
+            rusage: Default::default(),
+            dma_domains: Default::default(),
+            driver: AtomicBool::new(false),
         })
     }
 
@@ -177,31 +318,34 @@ impl Process {
         let mut threads = self.threads.lock();
         let tid = threads.get_next_id();
 
-        // let stack_base = STACK_ADDR.fetch_add(0x1000_000, Ordering::Relaxed);
-        let stack_base = STACK_ADDR + (STACK_SIZE + 0x1000) * tid.0;
+        let mut memory = self.memory.lock();
+        let stack_base = memory.stacks().stack_base(tid);
 
         let stack = match self.privilege {
             ProcessPrivilige::KERNEL => PageMapping::new_lazy_filled(STACK_SIZE as usize),
             _ => PageMapping::new_lazy(STACK_SIZE as usize),
         };
 
-        self.memory
-            .lock()
+        memory
             .page_mapper
-            .insert_mapping_at_set(stack_base as usize, stack, MemoryMappingFlags::all())
+            .insert_mapping_at_set(
+                stack_base as usize,
+                stack,
+                MemoryMappingFlags::WRITEABLE | self.privilege.mapping_flags(),
+            )
             .unwrap();
 
-        let kstack_base = KSTACK_ADDR + (KSTACK_SIZE + 0x1000) * tid.0;
+        let kstack_base = KERNEL_STACKS.stack_base(tid);
         let kstack_top = (kstack_base + KSTACK_SIZE) as usize;
         let stack = PageMapping::new_lazy_filled(KSTACK_SIZE as usize);
         let kstack_ptr_for_start = stack.base_top_stack();
         let kstack_base_virt = virt_addr_for_phys(kstack_ptr_for_start as u64) as usize;
 
-        self.memory
-            .lock()
+        memory
             .page_mapper
             .insert_mapping_at_set(kstack_base as usize, stack, MemoryMappingFlags::WRITEABLE)
             .unwrap();
+        drop(memory);
 
         let interrupt_frame = InterruptStackFrameValue {
             instruction_pointer: VirtAddr::from_ptr(entry_point),
@@ -211,12 +355,15 @@ impl Process {
             stack_segment: self.privilege.get_data_segment().0 as u64,
         };
 
+        let tls = self.new_thread_tls(tid);
+
         unsafe { *(kstack_base_virt as *mut usize) = arg };
         unsafe { *((kstack_base_virt + 8) as *mut InterruptStackFrameValue) = interrupt_frame }
         let thread = Arc::new_cyclic(|this| Thread {
             weak_self: this.clone(),
             process: self.this.upgrade().unwrap(),
             tid,
+            tls,
             sched_global: ThreadSchedGlobal::new(),
             sched: Spinlock::new(ThreadSched {
                 state: ThreadState::Runnable,
@@ -227,19 +374,55 @@ impl Process {
                 kstack_top: VirtAddr::from_ptr(kstack_top as *const ()),
                 in_syscall: false,
                 killed: false,
+                priority: ThreadPriority::NORMAL,
+                inherited_priority: Vec::new(),
+                run_mark: 0,
+                queued_at: 0,
+                fpu_state: None,
             }),
         });
 
         let status = self.exit_status.lock();
 
         if let ProcessExit::Exited = *status {
+            unsafe { thread.free_stacks() };
             return None;
         }
         threads.threads.insert(tid, thread.clone());
+        stats::thread_created();
 
         return Some(thread);
     }
 
+    /// Creates a TLS block from the processes template (if it has one)
+    fn new_thread_tls(&self, tid: ThreadID) -> Option<ThreadTLS> {
+        let mut memory = self.memory.lock();
+        let template = memory.tls_template.as_ref()?;
+
+        let size = template.block_size();
+        let base = memory.tls_base as usize + (size + 0x1000) * tid.0 as usize;
+        let fs_base = (base + template.tp_offset()) as u64;
+
+        let block = PageMapping::new_lazy(size);
+        block.write_at(0, &template.image);
+        // The TCB starts with a pointer to itself
+        block.write_at(template.tp_offset(), &fs_base.to_ne_bytes());
+
+        memory
+            .page_mapper
+            .insert_mapping_at_set(
+                base,
+                block,
+                MemoryMappingFlags::WRITEABLE | self.privilege.mapping_flags(),
+            )
+            .unwrap();
+
+        Some(ThreadTLS {
+            fs_base,
+            range: base..base + size,
+        })
+    }
+
     pub fn add_value(&self, value: KernelValue) -> KernelReferenceID {
         self.references.lock().add_value(value)
     }
@@ -248,19 +431,75 @@ impl Process {
         self.references.lock().references.get(&id).cloned()
     }
 
+    pub fn get_rusage(&self) -> ProcessRUsage {
+        let memory = self.memory.lock();
+        let peak_memory = memory.page_mapper.peak_mapped_pages() as u64 * 0x1000;
+        let (reserved, committed) = memory.page_mapper.anonymous_pages();
+        drop(memory);
+        let handle_count = self.references.lock().references.len() as u64;
+        ProcessRUsage {
+            user_time_us: self.rusage.user_time_us.load(Ordering::Relaxed),
+            kernel_time_us: self.rusage.kernel_time_us.load(Ordering::Relaxed),
+            peak_memory,
+            reserved_memory: reserved as u64 * 0x1000,
+            committed_memory: committed as u64 * 0x1000,
+            handle_count,
+            context_switches: self.rusage.context_switches.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn get_mem_info(&self) -> ProcessMemInfo {
+        let memory = self.memory.lock();
+        let (committed, shared) = memory.page_mapper.committed_pages();
+        ProcessMemInfo {
+            mapped_pages: memory.page_mapper.mapped_pages() as u64,
+            peak_mapped_pages: memory.page_mapper.peak_mapped_pages() as u64,
+            committed_pages: committed as u64,
+            shared_pages: shared as u64,
+        }
+    }
+
+    /// Creates a process with no threads and a copy on write snapshot of our address space.
+    /// Handles are not duplicated.
+    pub fn fork_cow(&self) -> Arc<Process> {
+        let memory = self.memory.lock().fork_cow();
+        let process = Self::with_memory(self.privilege, &self.args, self.name, memory);
+        PROCESSES.lock().insert(process.pid, process.clone());
+        process
+    }
+
     pub fn kill_threads(&self) {
         let threads = self.threads.lock();
-        for t in &threads.threads {
-            t.1.sched().lock().killed = true;
+        let mut sleeping = Vec::new();
+        for t in threads.threads.values() {
+            let mut sched = t.sched().lock();
+            sched.killed = true;
+            if sched.state == ThreadState::Sleeping {
+                sleeping.push(t.clone());
+            }
         }
-        if threads.threads.is_empty() {
-            drop(threads);
-            *self.exit_status.lock() = ProcessExit::Exited;
-            self.signals
-                .lock()
-                .set_signal(ObjectSignal::PROCESS_EXITED, true);
-            PROCESSES.lock().remove(&self.pid);
+        let empty = threads.threads.is_empty();
+        drop(threads);
+
+        // A sleeping thread would never be picked by the scheduler, so wake it up to exit
+        for t in sleeping {
+            t.wake();
         }
+        if empty {
+            self.exit();
+        }
+    }
+
+    /// Marks the process as exited once it has no threads left and drops its handles
+    pub fn exit(&self) {
+        *self.exit_status.lock() = ProcessExit::Exited;
+        // Dropped outside of the lock as closing handles can signal other objects
+        let references = take(self.references.lock().references());
+        drop(references);
+        self.signals
+            .lock()
+            .set_signal(ObjectSignal::PROCESS_EXITED, true);
+        PROCESSES.lock().remove(&self.pid);
     }
 }
 
@@ -323,6 +562,7 @@ pub struct Thread {
     weak_self: Weak<Thread>,
     process: Arc<Process>,
     tid: ThreadID,
+    tls: Option<ThreadTLS>,
 
     sched_global: ThreadSchedGlobal,
     sched: Spinlock<ThreadSched>,
@@ -351,6 +591,26 @@ impl Thread {
         self.tid
     }
 
+    pub fn fs_base(&self) -> u64 {
+        self.tls.as_ref().map_or(0, |t| t.fs_base)
+    }
+
+    /// Unmaps the threads kernel stack, stack and TLS block
+    /// SAFTEY: Must only be called once, after the thread has stopped running for good
+    pub unsafe fn free_stacks(&self) {
+        let mut memory = self.process.memory.lock();
+        let stack = memory.stacks().stack_range(self.tid);
+        memory.page_mapper.free_mapping(stack).unwrap();
+        memory
+            .page_mapper
+            .free_mapping(KERNEL_STACKS.stack_range(self.tid))
+            .unwrap();
+
+        if let Some(tls) = &self.tls {
+            memory.page_mapper.free_mapping(tls.range.clone()).unwrap();
+        }
+    }
+
     /// SAFTEY: Must hold the global sched lock
     pub unsafe fn sched_global(&self) -> &mut ThreadSchedGlobalData {
         &mut *self.sched_global.0.get()
@@ -360,13 +620,29 @@ impl Thread {
         &self.sched
     }
 
+    /// Adds the time since the last mark to the processes user or kernel time
+    pub fn account_time(&self, sched: &mut ThreadSched) {
+        let now = uptime_us();
+        let elapsed = now.saturating_sub(sched.run_mark);
+        sched.run_mark = now;
+
+        let rusage = &self.process.rusage;
+        if sched.in_syscall || self.process.privilege == ProcessPrivilige::KERNEL {
+            rusage.kernel_time_us.fetch_add(elapsed, Ordering::Relaxed);
+        } else {
+            rusage.user_time_us.fetch_add(elapsed, Ordering::Relaxed);
+        }
+    }
+
     pub fn wake(&self) {
         let mut s = self.sched.lock();
         match s.state {
             ThreadState::Zombie | ThreadState::Runnable => (),
             ThreadState::Sleeping => {
                 s.state = ThreadState::Runnable;
+                s.queued_at = uptime_us();
                 drop(s);
+                trace::record(SchedTraceKind::Wake, self, 0);
                 SCHEDULER
                     .lock()
                     .queue_thread(self.weak_self.upgrade().unwrap());
@@ -375,6 +651,11 @@ impl Thread {
     }
 }
 
+pub struct ThreadTLS {
+    fs_base: u64,
+    range: Range<usize>,
+}
+
 /// Data used for the scheduler blocked behind the global lock
 pub struct ThreadSchedGlobal(UnsafeCell<ThreadSchedGlobalData>);
 
@@ -394,6 +675,25 @@ pub struct ThreadSched {
     pub kstack_top: VirtAddr,
     pub in_syscall: bool,
     pub killed: bool,
+    pub priority: ThreadPriority,
+    /// Boosts from higher priority threads blocked on mutexes we own, by the address of the mutex
+    pub inherited_priority: Vec<(usize, ThreadPriority)>,
+    /// Uptime in us of when the thread's time was last accounted
+    pub run_mark: u64,
+    /// Uptime in us of when the thread last became runnable, 0 if unknown
+    pub queued_at: u64,
+    /// Allocated the first time the thread uses the fpu
+    pub fpu_state: Option<FpuState>,
+}
+
+/// Higher values are more important
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadPriority(pub u8);
+
+impl ThreadPriority {
+    /// Only scheduled when nothing else is runnable, for background housekeeping
+    pub const IDLE: ThreadPriority = ThreadPriority(0);
+    pub const NORMAL: ThreadPriority = ThreadPriority(128);
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -404,6 +704,30 @@ pub enum ThreadState {
 }
 
 impl ThreadSched {
+    pub fn effective_priority(&self) -> ThreadPriority {
+        self.inherited_priority
+            .iter()
+            .map(|&(_, p)| p)
+            .fold(self.priority, ThreadPriority::max)
+    }
+
+    /// Raises the boost from the mutex to at least priority
+    pub fn inherit_priority(&mut self, mutex: usize, priority: ThreadPriority) {
+        match self
+            .inherited_priority
+            .iter_mut()
+            .find(|(m, _)| *m == mutex)
+        {
+            Some((_, p)) => *p = (*p).max(priority),
+            None => self.inherited_priority.push((mutex, priority)),
+        }
+    }
+
+    /// Drops the boost from a mutex that is no longer owned, the others stay
+    pub fn release_priority(&mut self, mutex: usize) {
+        self.inherited_priority.retain(|(m, _)| *m != mutex);
+    }
+
     pub fn save(&mut self, state: SavedTaskState) {
         assert!(self.task_state.is_none());
         self.task_state = Some(state);
@@ -417,6 +741,7 @@ pub enum KernelValue {
     Channel(Arc<KChannelHandle>),
     Port(Arc<KPort>),
     Interrupt(Arc<KInterruptHandle>),
+    Memory(Arc<PageMapping>),
 }
 
 impl Debug for KernelValue {
@@ -427,6 +752,7 @@ impl Debug for KernelValue {
             Self::Channel(_) => f.debug_tuple("KernelValue::Channel").finish(),
             Self::Port(_) => f.debug_tuple("KernelValue::Port").finish(),
             Self::Interrupt(_) => f.debug_tuple("KernelValue::Interrupt").finish(),
+            Self::Memory(_) => f.debug_tuple("KernelValue::Memory").finish(),
         }
     }
 }
@@ -439,6 +765,7 @@ impl KernelValue {
             KernelValue::Channel(_) => KernelObjectType::Channel,
             KernelValue::Port(_) => KernelObjectType::Port,
             KernelValue::Interrupt(_) => KernelObjectType::Interrupt,
+            KernelValue::Memory(_) => KernelObjectType::Memory,
         }
     }
 }
@@ -473,17 +800,8 @@ impl Into<KernelValue> for Arc<KInterruptHandle> {
     }
 }
 
-impl Drop for Thread {
-    fn drop(&mut self) {
-        let stack_base = STACK_ADDR + (STACK_SIZE + 0x1000) * self.tid.0;
-
-        unsafe {
-            self.process
-                .memory
-                .lock()
-                .page_mapper
-                .free_mapping(stack_base as usize..(stack_base + STACK_SIZE) as usize)
-                .unwrap();
-        }
+impl Into<KernelValue> for Arc<PageMapping> {
+    fn into(self) -> KernelValue {
+        KernelValue::Memory(self)
     }
 }
@@ -0,0 +1,80 @@
+//! Thread and process teardown.
+//!
+//! The scheduler only marks exiting threads as zombies and hands them here, the reaper kthread
+//! then frees their stacks and, once a process has no threads left, drops its handle table and
+//! signals it as exited. Doing this in a normal thread keeps the scheduler loop short and lets
+//! teardown take sleeping locks.
+
+use core::mem::take;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use crate::{cpu_localstorage::CPULocalStorageRW, mutex::Spinlock};
+
+use super::{
+    process::{Thread, ThreadState},
+    stats,
+    taskmanager::enter_sched,
+};
+
+struct Reaper {
+    thread: Option<Arc<Thread>>,
+    dead: Vec<Arc<Thread>>,
+}
+
+static REAPER: Spinlock<Reaper> = Spinlock::new(Reaper {
+    thread: None,
+    dead: Vec::new(),
+});
+
+/// Queues a zombie thread to be torn down, the thread must never be run again
+pub fn reap(thread: Arc<Thread>) {
+    let mut reaper = REAPER.lock();
+    reaper.dead.push(thread);
+    let waker = reaper.thread.clone();
+    drop(reaper);
+
+    // Threads that die before the reaper starts are picked up on its first pass
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+fn reap_thread(thread: Arc<Thread>) {
+    let process = thread.process().clone();
+    unsafe { thread.free_stacks() };
+
+    let mut threads = process.threads.lock();
+    if threads.threads.remove(&thread.tid()).is_none() {
+        error!("thread should be in thread list {thread:?}")
+    }
+    let empty = threads.threads.is_empty();
+    drop(threads);
+    stats::thread_reaped();
+
+    if empty {
+        process.exit();
+    }
+}
+
+pub fn reaper() {
+    let this = unsafe { CPULocalStorageRW::get_current_task() };
+    REAPER.lock().thread = Some(this.thread());
+
+    loop {
+        let mut reaper = REAPER.lock();
+        if reaper.dead.is_empty() {
+            let mut sched = this.sched().lock();
+            sched.state = ThreadState::Sleeping;
+            drop(reaper);
+            enter_sched(&mut sched);
+            continue;
+        }
+        let dead = take(&mut reaper.dead);
+        drop(reaper);
+
+        for thread in dead {
+            reap_thread(thread);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use core::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    service::{deserialize, serialize, Service},
+    stats::{
+        CoreSchedStats, SchedStats, StatsServiceMessage, StatsServiceMessageResp, LOADAVG_FIXED_1,
+        LOADAVG_FSHIFT,
+    },
+};
+
+use crate::{cpu::monitor, cpu_localstorage::CPULocalStorageRW, time::uptime};
+
+use super::{
+    taskmanager::SCHEDULER,
+    trace::{collect_sched_trace, set_tracing},
+};
+
+pub struct CoreStats {
+    online: AtomicBool,
+    running: AtomicBool,
+    context_switches: AtomicU64,
+    idle_ms: AtomicU64,
+}
+
+impl CoreStats {
+    const fn new() -> Self {
+        Self {
+            online: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            context_switches: AtomicU64::new(0),
+            idle_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Indexed by core id
+static CORE_STATS: [CoreStats; 256] = [const { CoreStats::new() }; 256];
+
+fn this_core() -> &'static CoreStats {
+    &CORE_STATS[CPULocalStorageRW::get_core_id() as usize]
+}
+
+pub fn core_online() {
+    this_core().online.store(true, Ordering::Relaxed);
+}
+
+pub fn is_core_online(core_id: u8) -> bool {
+    CORE_STATS[core_id as usize].online.load(Ordering::Relaxed)
+}
+
+/// Called by the scheduler right before switching into a thread
+pub fn record_switch_in() {
+    let core = this_core();
+    core.context_switches.fetch_add(1, Ordering::Relaxed);
+    core.running.store(true, Ordering::Relaxed);
+}
+
+/// Called by the scheduler once the thread has given back control
+pub fn record_switch_out() {
+    this_core().running.store(false, Ordering::Relaxed);
+}
+
+pub fn record_idle(ms: u64) {
+    this_core().idle_ms.fetch_add(ms, Ordering::Relaxed);
+}
+
+/// Threads that have been created and not yet reaped
+static THREADS: AtomicU64 = AtomicU64::new(0);
+
+pub fn thread_created() {
+    THREADS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn thread_reaped() {
+    THREADS.fetch_sub(1, Ordering::Relaxed);
+}
+
+// Load average, calculated the same way as linux
+// Every LOAD_FREQ ms, load = load * exp + active * (1 - exp)
+const LOAD_FREQ: u64 = 5000;
+// 1 / exp(5sec/1min), 1 / exp(5sec/5min), 1 / exp(5sec/15min) as fixed point
+const EXP: [u64; 3] = [1884, 2014, 2037];
+
+static LOADAVG: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+static NEXT_LOAD_SAMPLE: AtomicU64 = AtomicU64::new(LOAD_FREQ);
+
+fn active_threads() -> u64 {
+    let running = CORE_STATS
+        .iter()
+        .filter(|c| c.running.load(Ordering::Relaxed))
+        .count() as u64;
+    running + SCHEDULER.lock().queue_len() as u64
+}
+
+/// Updates the load averages if a sample is due, should be called from a periodic tick
+pub fn sample_load() {
+    let time = uptime();
+    let next = NEXT_LOAD_SAMPLE.load(Ordering::Relaxed);
+    if time < next {
+        return;
+    }
+    // only one core gets to take the sample
+    if NEXT_LOAD_SAMPLE
+        .compare_exchange(next, next + LOAD_FREQ, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    let active = active_threads() * LOADAVG_FIXED_1;
+    for (load, exp) in LOADAVG.iter().zip(EXP) {
+        let old = load.load(Ordering::Relaxed);
+        let new = (old * exp + active * (LOADAVG_FIXED_1 - exp)) >> LOADAVG_FSHIFT;
+        load.store(new, Ordering::Relaxed);
+    }
+}
+
+pub fn collect_sched_stats() -> SchedStats {
+    let cores = CORE_STATS
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.online.load(Ordering::Relaxed))
+        .map(|(id, c)| CoreSchedStats {
+            core_id: id as u8,
+            running: c.running.load(Ordering::Relaxed),
+            context_switches: c.context_switches.load(Ordering::Relaxed),
+            idle_ms: c.idle_ms.load(Ordering::Relaxed),
+            freq_mhz: monitor::frequency_khz(id as u8).map(|khz| khz / 1000),
+            temperature: monitor::temperature(id as u8),
+        })
+        .collect();
+
+    SchedStats {
+        uptime: uptime(),
+        run_queue_depth: SCHEDULER.lock().queue_len() as u64,
+        threads: THREADS.load(Ordering::Relaxed),
+        loadavg: LOADAVG.each_ref().map(|l| l.load(Ordering::Relaxed)),
+        cores,
+    }
+}
+
+pub fn stats_service() {
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+
+    Service::new(
+        "STATS",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            let resp = match deserialize(&buffer) {
+                Ok(StatsServiceMessage::GetSchedStats) => {
+                    StatsServiceMessageResp::SchedStats(collect_sched_stats())
+                }
+                Ok(StatsServiceMessage::SetSchedTrace(enabled)) => {
+                    set_tracing(enabled);
+                    StatsServiceMessageResp::Ok
+                }
+                Ok(StatsServiceMessage::GetSchedTrace) => {
+                    StatsServiceMessageResp::SchedTrace(collect_sched_trace())
+                }
+                Err(e) => {
+                    error!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+
+            channel_write_rs(handle.id(), serialize(&resp, &mut buffer), &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
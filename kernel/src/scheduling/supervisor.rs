@@ -0,0 +1,86 @@
+//! Restarts the drivers started from bootfs when they die.
+//!
+//! Nothing needs to be torn down here, exiting drops the driver's handles which ends its
+//! interrupt subscriptions and closes the services it published, which the init service then
+//! forgets. A driver that keeps dying straight after starting is restarted less and less often.
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    object::KernelReference,
+    process::{clone_init_service, ProcessHandle},
+    syscall::{sleep, spawn_thread},
+};
+
+use crate::{
+    bootfs::{PS2_DRIVER, SERIAL_DRIVER},
+    cpu_localstorage::CPULocalStorageRW,
+    elf::load_elf,
+    serial::SERIAL,
+    time::uptime,
+};
+
+use super::with_held_interrupts;
+
+/// How long to wait before the first restart, doubled for each quick death after that
+const MIN_BACKOFF_MS: u64 = 100;
+const MAX_BACKOFF_MS: u64 = 30_000;
+/// Running for this long counts as having started fine, which resets the backoff
+const STABLE_MS: u64 = 60_000;
+
+struct Supervised {
+    name: &'static str,
+    elf: &'static [u8],
+}
+
+/// The supervisor kthread, needs an init channel to hand clones of to the drivers
+pub fn supervisor() {
+    let mut drivers = Vec::new();
+    drivers.push(Supervised {
+        name: "ps2",
+        elf: PS2_DRIVER,
+    });
+    if SERIAL.get().is_some() {
+        drivers.push(Supervised {
+            name: "serial",
+            elf: SERIAL_DRIVER,
+        });
+    }
+
+    for driver in drivers {
+        spawn_thread(move || supervise(driver));
+    }
+}
+
+fn supervise(driver: Supervised) {
+    let mut backoff = MIN_BACKOFF_MS;
+    loop {
+        let init = KernelReference::from_id(clone_init_service());
+        // TODO: Use IO permissions instead of kernel
+        match load_elf(driver.elf, None, &[], &[init], true, true) {
+            Ok(process) => {
+                let started = uptime();
+
+                let mut handle = ProcessHandle::from_kref(with_held_interrupts(|| unsafe {
+                    let thread = CPULocalStorageRW::get_current_task();
+                    KernelReference::from_id(thread.process().add_value(process.into()))
+                }));
+                handle.blocking_exit_code();
+
+                if uptime() - started >= STABLE_MS {
+                    backoff = MIN_BACKOFF_MS;
+                }
+                warn!(
+                    "The {} driver exited, restarting it in {backoff}ms",
+                    driver.name
+                );
+            }
+            // Treated like it died straight away, memory might be short for a moment
+            Err(e) => error!(
+                "Couldn't start the {} driver, trying again in {backoff}ms: {e:?}",
+                driver.name
+            ),
+        }
+        sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF_MS);
+    }
+}
@@ -1,35 +1,102 @@
-use core::fmt::Write;
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use alloc::{boxed::Box, collections::BTreeMap, fmt, sync::Arc};
 
 use conquer_once::spin::Lazy;
 use kernel_userspace::{
-    ids::ProcessID,
-    object::{KernelReference, ObjectSignal},
-    process::ProcessExit,
-    syscall::thread_bootstraper,
+    ids::ProcessID, object::KernelReference, stats::SchedTraceKind, syscall::thread_bootstraper,
+};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr0, Cr0Flags},
 };
 
 use crate::{
     assembly::{registers::SavedTaskState, wrmsr},
     cpu_localstorage::CPULocalStorageRW,
+    fpu,
     gdt::{KERNEL_CODE_SELECTOR, USER_CODE_SELECTOR},
+    interrupts::WAKEUP_IPI,
+    ioapic::send_ipi_to,
+    lapic::set_timer_masked,
     mutex::{Spinlock, SpinlockGuard},
+    paging::tlb::set_active_cr3,
     scheduling::{process::ThreadState, with_held_interrupts},
     syscall::{syscall_sysret_handler, SyscallError},
+    time::{clock_source, uptime, uptime_us},
+    user_mem,
 };
 
-use super::process::{Process, Thread, ThreadSched};
+use super::{
+    process::{Process, Thread, ThreadPriority, ThreadSched},
+    reaper, stats, trace,
+};
 
 pub type ProcessesListType = BTreeMap<ProcessID, Arc<Process>>;
 pub static PROCESSES: Lazy<Spinlock<ProcessesListType>> =
     Lazy::new(|| Spinlock::new(BTreeMap::new()));
 
+const FS_BASE_MSR: u32 = 0xC0000100;
+
 pub static SCHEDULER: Spinlock<GlobalSchedData> = Spinlock::new(GlobalSchedData::new());
 
+/// Cores that should stop running threads, indexed by core id
+static PARKED: [AtomicBool; 256] = [const { AtomicBool::new(false) }; 256];
+
+pub fn is_core_parked(core_id: u8) -> bool {
+    PARKED[core_id as usize].load(Ordering::Acquire)
+}
+
+/// Parks an AP, it will stop taking threads once it finishes its current one
+pub fn park_core(core_id: u8) -> bool {
+    // Core 0 is the BSP which receives all the device interrupts
+    if core_id == 0 || !stats::is_core_online(core_id) {
+        return false;
+    }
+    if PARKED[core_id as usize].swap(true, Ordering::AcqRel) {
+        return false;
+    }
+    info!("Parking core: {core_id}");
+    true
+}
+
+pub fn unpark_core(core_id: u8) -> bool {
+    if !PARKED[core_id as usize].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    send_ipi_to(core_id, WAKEUP_IPI as u8);
+    true
+}
+
+/// Stops the current core until it gets unparked
+unsafe fn park_current_core(id: u8) {
+    set_timer_masked(true);
+    loop {
+        // The wakeup ipi can't land between the check and hlt, sti only lets interrupts in
+        // after the instruction following it
+        interrupts::disable();
+        if !is_core_parked(id) {
+            interrupts::enable();
+            break;
+        }
+        interrupts::enable_and_hlt();
+    }
+    set_timer_masked(false);
+    info!("Unparked core: {id}");
+}
+
+/// How long a runnable idle thread can be passed over before it gets a turn anyway
+const IDLE_MAX_WAIT_MS: u64 = 500;
+
 pub struct GlobalSchedData {
-    queue_head: Option<Arc<Thread>>,
-    queue_tail: Option<Arc<Thread>>,
+    queue: RunQueue,
+    /// Threads with [`ThreadPriority::IDLE`], only run when nothing else is runnable
+    idle_queue: RunQueue,
+    /// Uptime in ms when an idle thread was last picked (or the idle queue was last empty)
+    idle_last_run: u64,
 }
 
 pub struct ThreadSchedGlobalData {
@@ -46,63 +113,123 @@ impl ThreadSchedGlobalData {
     }
 }
 
+/// Intrusive FIFO of threads linked through their sched globals
+struct RunQueue {
+    head: Option<Arc<Thread>>,
+    tail: Option<Arc<Thread>>,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+            len: 0,
+        }
+    }
+
+    unsafe fn dump(&self, writer: &mut impl Write) -> fmt::Result {
+        let mut head = &self.head;
+        while let Some(h) = head {
+            writer.write_fmt(format_args!("{h:?}\n"))?;
+            head = &h.sched_global().next;
+        }
+        Ok(())
+    }
+
+    unsafe fn pop(&mut self) -> Option<Arc<Thread>> {
+        let head = self.head.take()?;
+        let sg = head.sched_global();
+        sg.queued = false;
+        self.len -= 1;
+        match sg.next.take() {
+            nxt @ Some(_) => self.head = nxt,
+            None => {
+                // We were head and tail
+                self.tail = None;
+            }
+        }
+        Some(head)
+    }
+
+    unsafe fn push(&mut self, thread: Arc<Thread>) {
+        let sg = thread.sched_global();
+        if sg.queued {
+            return;
+        }
+        sg.queued = true;
+        self.len += 1;
+
+        if self.head.is_none() {
+            // Case 1: nothing else is in the queue, we become head and tail
+            assert!(self.tail.is_none());
+            self.head = Some(thread.clone());
+            self.tail = Some(thread)
+        } else {
+            // Case 2: insert ourself as the new tail
+            if let Some(tail) = self.tail.take() {
+                let tsg = tail.sched_global();
+                assert!(tsg.next.is_none());
+                tsg.next = Some(thread.clone());
+            }
+            self.tail = Some(thread)
+        }
+    }
+}
+
 impl GlobalSchedData {
     pub const fn new() -> Self {
         Self {
-            queue_head: None,
-            queue_tail: None,
+            queue: RunQueue::new(),
+            idle_queue: RunQueue::new(),
+            idle_last_run: 0,
         }
     }
 
+    /// Number of threads waiting to be run
+    pub fn queue_len(&self) -> usize {
+        self.queue.len + self.idle_queue.len
+    }
+
     pub fn dump_runnable(&self, writer: &mut impl Write) -> fmt::Result {
         unsafe {
             writer.write_str("Runnable tasks\n")?;
-            let mut head = &self.queue_head;
-            while let Some(h) = head {
-                writer.write_fmt(format_args!("{h:?}\n"))?;
-                head = &h.sched_global().next;
-            }
-            Ok(())
+            self.queue.dump(writer)?;
+            writer.write_str("Runnable idle tasks\n")?;
+            self.idle_queue.dump(writer)
         }
     }
 
     fn pop_thread(&mut self) -> Option<Arc<Thread>> {
         unsafe {
-            let head = self.queue_head.take()?;
-            let sg = head.sched_global();
-            sg.queued = false;
-            match sg.next.take() {
-                nxt @ Some(_) => self.queue_head = nxt,
-                None => {
-                    // We were head and tail
-                    self.queue_tail = None;
+            // the hpet might not be initialized yet, in which case this is 0 and idle threads wait
+            let now = uptime_us() / 1000;
+            if self.idle_queue.len == 0 {
+                self.idle_last_run = now;
+                return self.queue.pop();
+            }
+
+            // Idle threads still get a turn every so often so that they can't be starved forever
+            let starved = now.saturating_sub(self.idle_last_run) >= IDLE_MAX_WAIT_MS;
+            if !starved {
+                if let Some(thread) = self.queue.pop() {
+                    return Some(thread);
                 }
             }
-            Some(head)
+            self.idle_last_run = now;
+            self.idle_queue.pop()
         }
     }
 
+    /// Locks the threads sched to find its class, so the sched lock must not be held
     pub fn queue_thread(&mut self, thread: Arc<Thread>) {
+        let idle = thread.sched().lock().effective_priority() == ThreadPriority::IDLE;
         unsafe {
-            let sg = thread.sched_global();
-            if sg.queued {
-                return;
-            }
-            sg.queued = true;
-
-            if self.queue_head.is_none() {
-                // Case 1: nothing else is in the queue, we become head and tail
-                assert!(self.queue_tail.is_none());
-                self.queue_head = Some(thread.clone());
-                self.queue_tail = Some(thread)
+            if idle {
+                self.idle_queue.push(thread)
             } else {
-                // Case 2: insert ourself as the new tail
-                if let Some(tail) = self.queue_tail.take() {
-                    let tsg = tail.sched_global();
-                    assert!(tsg.next.is_none());
-                    tsg.next = Some(thread.clone());
-                }
-                self.queue_tail = Some(thread)
+                self.queue.push(thread)
             }
         }
     }
@@ -118,8 +245,8 @@ pub unsafe fn enable_syscall() {
     // set lstar (the rip that it'll go to)
     wrmsr(0xC0000082, syscall_sysret_handler as u64);
 
-    // set flag mask (mask everything)
-    wrmsr(0xC0000084, 0x200);
+    // set flag mask (mask interrupts and AC so that user code can't disable SMAP)
+    wrmsr(0xC0000084, 0x40200);
 
     // enable syscall
     core::arch::asm!(
@@ -135,6 +262,11 @@ pub unsafe fn enable_syscall() {
 
 pub unsafe fn core_start_multitasking() {
     enable_syscall();
+    fpu::init_core();
+    user_mem::init_core();
+    // Make the kernel respect read only pages as well, otherwise a syscall writing to a copy on
+    // write page would write straight through to the shared copy
+    Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT));
 
     // Init complete, start executing tasks
     CPULocalStorageRW::dec_hold_interrupts();
@@ -150,8 +282,13 @@ pub unsafe fn core_start_multitasking() {
 unsafe extern "C" fn scheduler() {
     let id = CPULocalStorageRW::get_core_id();
     info!("Starting scheduler on core: {}", id);
+    stats::core_online();
 
     loop {
+        if is_core_parked(id) {
+            park_current_core(id);
+        }
+
         let task = SCHEDULER.lock().pop_thread();
         if let Some(task) = task {
             let mut sched = task.sched().lock();
@@ -161,7 +298,22 @@ unsafe extern "C" fn scheduler() {
             }
             assert_eq!(sched.state, ThreadState::Runnable);
 
+            stats::record_switch_in();
+            task.process()
+                .rusage
+                .context_switches
+                .fetch_add(1, Ordering::Relaxed);
+            sched.run_mark = uptime_us();
+            let latency = match sched.queued_at {
+                0 => 0,
+                t => sched.run_mark.saturating_sub(t),
+            };
+            trace::record(SchedTraceKind::SwitchIn, &task, latency);
             sched_run_tick(&task, &mut sched);
+            fpu::switch_out(&mut sched);
+            task.account_time(&mut sched);
+            stats::record_switch_out();
+            trace::record(SchedTraceKind::SwitchOut, &task, 0);
 
             if CPULocalStorageRW::hold_interrupts_depth() != 1 {
                 error!("Thread shouldn't be holding interrupts when yielding");
@@ -170,11 +322,11 @@ unsafe extern "C" fn scheduler() {
             }
 
             match sched.state {
-                ThreadState::Zombie => {
-                    panic!("bad state")
-                }
+                // Already handed to the reaper
+                ThreadState::Zombie => (),
                 ThreadState::Runnable => {
                     sched.state = ThreadState::Runnable;
+                    sched.queued_at = uptime_us();
                     drop(sched);
                     SCHEDULER.lock().queue_thread(task);
                 }
@@ -182,7 +334,12 @@ unsafe extern "C" fn scheduler() {
             }
         } else {
             // nothing can run so sleep
-            core::arch::asm!("hlt")
+            // there might not be a clock yet when the bsp first enters the scheduler
+            let start = clock_source().is_some().then(uptime);
+            core::arch::asm!("hlt");
+            if let Some(start) = start {
+                stats::record_idle(uptime() - start);
+            }
         }
     }
 }
@@ -215,22 +372,10 @@ pub fn kill_bad_task() -> ! {
     }
 }
 
-pub fn exit_thread_inner(thread: &Thread, sched: &mut ThreadSched) {
+/// Turns the thread into a zombie and hands it to the reaper to be torn down
+pub fn exit_thread_inner(thread: &Arc<Thread>, sched: &mut ThreadSched) {
     sched.state = ThreadState::Zombie;
-    let p = thread.process();
-    let mut t = p.threads.lock();
-    if t.threads.remove(&thread.tid()).is_none() {
-        error!("thread should be in thread list {thread:?}")
-    }
-
-    if t.threads.is_empty() {
-        drop(t);
-        *p.exit_status.lock() = ProcessExit::Exited;
-        p.signals
-            .lock()
-            .set_signal(ObjectSignal::PROCESS_EXITED, true);
-        PROCESSES.lock().remove(&p.pid);
-    }
+    reaper::reap(thread.clone());
 }
 
 pub fn spawn_process<F>(
@@ -305,7 +450,12 @@ unsafe fn sched_run_tick(task: &Thread, sched: &mut ThreadSched) {
 
     let cr3 = task.process().cr3_page;
 
+    // FSGSBASE is left disabled as it would let userspace change the gs base the kernel relies on,
+    // so the fs base only ever changes here.
+    wrmsr(FS_BASE_MSR, task.fs_base());
+
     CPULocalStorageRW::set_current_task(task, &sched);
+    set_active_cr3(cr3);
 
     let new_sp;
     let new_ip;
@@ -347,6 +497,7 @@ unsafe fn sched_run_tick(task: &Thread, sched: &mut ThreadSched) {
         lateout("rdx") _,
         lateout("rcx") _,
     );
+    set_active_cr3(0);
     CPULocalStorageRW::clear_current_task();
 
     sched.task_state = Some(SavedTaskState {
@@ -0,0 +1,95 @@
+//! Scheduler latency tracing.
+//!
+//! When enabled each core records wakes and context switches into its own ring buffer, only the
+//! most recent [`TRACE_LEN`] events are kept. Read through the STATS service.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+use kernel_userspace::stats::{CoreSchedTrace, SchedTraceEvent, SchedTraceKind};
+
+use crate::{cpu_localstorage::CPULocalStorageRW, mutex::Spinlock, time::uptime_us};
+
+use super::{process::Thread, stats};
+
+const TRACE_LEN: usize = 512;
+
+struct TraceRing {
+    events: Vec<SchedTraceEvent>,
+    /// Index the next event will be written to once the ring is full
+    next: usize,
+}
+
+impl TraceRing {
+    fn new() -> Self {
+        Self {
+            events: Vec::with_capacity(TRACE_LEN),
+            next: 0,
+        }
+    }
+
+    fn push(&mut self, event: SchedTraceEvent) {
+        if self.events.len() < TRACE_LEN {
+            self.events.push(event);
+        } else {
+            self.events[self.next] = event;
+            self.next = (self.next + 1) % TRACE_LEN;
+        }
+    }
+
+    fn ordered(&self) -> Vec<SchedTraceEvent> {
+        let (newer, older) = self.events.split_at(self.next);
+        older.iter().chain(newer).copied().collect()
+    }
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Indexed by core id, only allocated while tracing has been enabled
+static RINGS: [Spinlock<Option<TraceRing>>; 256] = [const { Spinlock::new(None) }; 256];
+
+pub fn set_tracing(enabled: bool) {
+    if enabled {
+        for (id, ring) in RINGS.iter().enumerate() {
+            if stats::is_core_online(id as u8) {
+                *ring.lock() = Some(TraceRing::new());
+            }
+        }
+    }
+    TRACING.store(enabled, Ordering::Release);
+}
+
+/// Records an event on the current core if tracing is enabled
+#[inline]
+pub fn record(kind: SchedTraceKind, thread: &Thread, latency_us: u64) {
+    if !TRACING.load(Ordering::Acquire) {
+        return;
+    }
+
+    let event = SchedTraceEvent {
+        time_us: uptime_us(),
+        kind,
+        pid: thread.process().pid.0,
+        tid: thread.tid().0,
+        latency_us,
+    };
+
+    let id = CPULocalStorageRW::get_core_id();
+    if let Some(ring) = RINGS[id as usize].lock().as_mut() {
+        ring.push(event);
+    }
+}
+
+pub fn collect_sched_trace() -> Vec<CoreSchedTrace> {
+    RINGS
+        .iter()
+        .enumerate()
+        .filter_map(|(id, ring)| {
+            let ring = ring.lock();
+            Some(CoreSchedTrace {
+                core_id: id as u8,
+                events: ring.as_ref()?.ordered(),
+            })
+        })
+        .collect()
+}
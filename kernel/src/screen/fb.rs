@@ -0,0 +1,154 @@
+//! The `FB` service, which lets one userspace client at a time put a surface on the screen in
+//! place of the console.
+
+use core::ops::ControlFlow;
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    display::{Framebuffer, Rect},
+    dma::{DmaBuffer, DmaConstraints},
+    fb::{FbMessage, FbResponse},
+    service::{deserialize, serialize, Service},
+};
+
+use crate::scheduling::with_held_interrupts;
+
+use super::gop::WRITER;
+
+struct Surface {
+    memory: DmaBuffer,
+    framebuffer: Framebuffer,
+    /// What to copy at the next present
+    damage: Option<Rect>,
+}
+
+impl Surface {
+    fn new() -> Option<Self> {
+        let (width, height) = with_held_interrupts(|| {
+            let gop = &WRITER.get().unwrap().lock().screen.gop;
+            (gop.horizonal as u32, gop.vertical as u32)
+        });
+        let memory = DmaBuffer::new(
+            width as usize * height as usize * 4,
+            DmaConstraints::default(),
+        )?;
+        Some(Self {
+            memory,
+            framebuffer: Framebuffer {
+                width,
+                height,
+                stride: width,
+            },
+            damage: None,
+        })
+    }
+
+    fn add_damage(&mut self, rect: Rect) {
+        self.damage = Some(match self.damage {
+            None => Rect {
+                width: rect.width.min(u32::MAX - rect.x),
+                height: rect.height.min(u32::MAX - rect.y),
+                ..rect
+            },
+            Some(d) => {
+                let (x, y) = (d.x.min(rect.x), d.y.min(rect.y));
+                Rect {
+                    x,
+                    y,
+                    width: (d.x + d.width).max(rect.x.saturating_add(rect.width)) - x,
+                    height: (d.y + d.height).max(rect.y.saturating_add(rect.height)) - y,
+                }
+            }
+        });
+    }
+
+    fn present(&mut self) {
+        let Some(damage) = self.damage.take() else {
+            return;
+        };
+        let pixels = unsafe {
+            core::slice::from_raw_parts(
+                self.memory.as_ptr() as *const u32,
+                self.framebuffer.stride as usize * self.framebuffer.height as usize,
+            )
+        };
+        with_held_interrupts(|| {
+            WRITER.get().unwrap().lock().screen.copy_from(
+                pixels,
+                self.framebuffer.stride as usize,
+                damage,
+            )
+        });
+    }
+}
+
+pub fn monitor_fb_task() {
+    let mut data_buf = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+    let mut out = Vec::new();
+    // Whether a connection has a surface on the screen
+    let mut taken = false;
+    let mut service = Service::new(
+        "FB",
+        || None,
+        |handle, surface: &mut Option<Surface>| {
+            let closed = match channel_read_rs(handle.id(), &mut data_buf, &mut handles) {
+                ChannelReadResult::Ok => false,
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => true,
+            };
+            let msg = match closed {
+                false => deserialize(&data_buf).ok(),
+                true => None,
+            };
+            let Some(msg) = msg else {
+                // Give the screen back to the console
+                if surface.take().is_some() {
+                    taken = false;
+                    with_held_interrupts(|| WRITER.get().unwrap().lock().resume());
+                }
+                return ControlFlow::Break(());
+            };
+
+            let mut memory = None;
+            let response = match msg {
+                FbMessage::CreateSurface if surface.is_none() && !taken => match Surface::new() {
+                    Some(new) => {
+                        memory = new.memory.share();
+                        match memory {
+                            Some(_) => {
+                                taken = true;
+                                with_held_interrupts(|| WRITER.get().unwrap().lock().suspend());
+                                let response = FbResponse::Surface(new.framebuffer);
+                                *surface = Some(new);
+                                response
+                            }
+                            None => FbResponse::Failed,
+                        }
+                    }
+                    None => FbResponse::Failed,
+                },
+                FbMessage::CreateSurface => FbResponse::Failed,
+                FbMessage::Damage(rect) => match surface {
+                    Some(surface) => {
+                        surface.add_damage(rect);
+                        FbResponse::Ok
+                    }
+                    None => FbResponse::Failed,
+                },
+                FbMessage::Present => match surface {
+                    Some(surface) => {
+                        surface.present();
+                        FbResponse::Ok
+                    }
+                    None => FbResponse::Failed,
+                },
+            };
+            let ids: Vec<_> = memory.iter().map(|m| m.kref().id()).collect();
+            channel_write_rs(handle.id(), serialize(&response, &mut out), &ids);
+            ControlFlow::Continue(())
+        },
+    );
+    service.run();
+}
@@ -2,12 +2,17 @@ use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use bootloader::gop::GopInfo;
+use bootloader::uefi::proto::console::gop::PixelFormat;
 use conquer_once::spin::OnceCell;
 use core::fmt::Write;
 use core::ops::ControlFlow;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use kernel_userspace::channel::{channel_read_rs, channel_write_rs};
-use kernel_userspace::service::Service;
-use kernel_userspace::syscall::{sleep, spawn_thread};
+use kernel_userspace::display::{ConsoleMessage, ConsoleResponse, Framebuffer, Rect};
+use kernel_userspace::memory::MemoryHandle;
+use kernel_userspace::object::KernelReference;
+use kernel_userspace::service::{deserialize, serialize, Service};
+use kernel_userspace::syscall::{sleep, spawn_thread, unmmap_page};
 
 #[derive(Clone, Copy)]
 pub struct Pos {
@@ -22,9 +27,33 @@ pub struct Screen<'a> {
     pub gop: GopInfo,
     pub font: PSF1Font<'a>,
     pub unicode_table: BTreeMap<char, usize>,
+    /// What has been drawn since a display driver last asked, see [`ConsoleMessage::TakeDamage`]
+    pub damage: Option<Rect>,
 }
 
 impl Screen<'_> {
+    fn add_damage(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let (x, y) = (x as u32, y as u32);
+        let (right, bottom) = (x + width as u32, y + height as u32);
+        self.damage = Some(match self.damage {
+            None => Rect {
+                x,
+                y,
+                width: width as u32,
+                height: height as u32,
+            },
+            Some(d) => {
+                let (min_x, min_y) = (d.x.min(x), d.y.min(y));
+                Rect {
+                    x: min_x,
+                    y: min_y,
+                    width: (d.x + d.width).max(right) - min_x,
+                    height: (d.y + d.height).max(bottom) - min_y,
+                }
+            }
+        });
+    }
+
     pub fn update_cell(&mut self, cell: &Cell, x: usize, y: usize) {
         let mut addr: usize = *self.unicode_table.get(&cell.chr).unwrap_or(&0);
 
@@ -53,6 +82,33 @@ impl Screen<'_> {
             }
             addr += 1;
         }
+        self.add_damage(xoff, yoff, CHAR_WIDTH, CHAR_HEIGHT);
+    }
+
+    /// Copies the part of the pixels of a surface with lines stride long that is on the screen
+    pub fn copy_from(&mut self, pixels: &[u32], stride: usize, rect: Rect) {
+        let x = rect.x as usize;
+        let y = rect.y as usize;
+        if x >= self.gop.horizonal || y >= self.gop.vertical || x >= stride {
+            return;
+        }
+        let width = (rect.width as usize)
+            .min(self.gop.horizonal - x)
+            .min(stride - x);
+        let height = (rect.height as usize)
+            .min(self.gop.vertical - y)
+            .min((pixels.len() / stride).saturating_sub(y));
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let ptr = self.gop.buffer.get_mut();
+        for line in y..y + height {
+            let src = &pixels[line * stride + x..line * stride + x + width];
+            let dst = (x + line * self.gop.stride) * 4;
+            unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), ptr.add(dst) as *mut u32, width) }
+        }
+        self.add_damage(x, y, width, height);
     }
 
     pub fn draw_cursor(&mut self, mut pos: Pos, colour: u32, cursor: &[u16]) {
@@ -78,6 +134,7 @@ impl Screen<'_> {
                 }
             }
         }
+        self.add_damage(pos.x, pos.y, 16, 16);
     }
 }
 
@@ -98,6 +155,7 @@ use crate::scheduling::with_held_interrupts;
 use crate::terminal::{Cell, Writer};
 use crate::BOOT_INFO;
 
+use super::fb::monitor_fb_task;
 use super::mouse::monitor_cursor_task;
 use super::psf1::PSF1Font;
 
@@ -133,6 +191,96 @@ pub fn monitor_stdout_task() {
     service.run();
 }
 
+/// Maps a framebuffer a display driver sent, returns where it is if it is big enough
+fn map_framebuffer(fb: &Framebuffer, memory: MemoryHandle) -> Option<GopInfo> {
+    // Has to fit at least the cursor
+    if fb.width < 16 || fb.height < 16 || fb.stride < fb.width {
+        return None;
+    }
+    let buffer_size = fb.stride as usize * fb.height as usize * 4;
+    let ptr = memory.map_writable()?;
+
+    let size = with_held_interrupts(|| unsafe {
+        let proc = CPULocalStorageRW::get_current_task().process();
+        let mem = proc.memory.lock();
+        mem.page_mapper
+            .mapping_at(ptr as usize)
+            .map_or(0, |(_, m)| m.size())
+    });
+    if size < buffer_size {
+        unmmap_page(ptr as usize, size);
+        return None;
+    }
+
+    Some(GopInfo {
+        buffer: AtomicPtr::new(ptr),
+        buffer_size,
+        horizonal: fb.width as usize,
+        vertical: fb.height as usize,
+        stride: fb.stride as usize,
+        pixel_format: PixelFormat::Bgr,
+    })
+}
+
+/// Lets a display driver move the console onto its own framebuffer and find out what to send
+/// to the display
+pub fn monitor_console_task() {
+    let mut data_buf = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+    let mut out = Vec::new();
+    // The framebuffer a driver gave us, unmapped once it's been replaced
+    let mut mapped: Option<(usize, usize)> = None;
+    let mut service = Service::new(
+        "CONSOLE",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut data_buf, &mut handles) {
+                kernel_userspace::channel::ChannelReadResult::Ok => (),
+                kernel_userspace::channel::ChannelReadResult::Empty => {
+                    return ControlFlow::Continue(());
+                }
+                _ => return ControlFlow::Break(()),
+            };
+
+            let response = match deserialize(&data_buf) {
+                Ok(ConsoleMessage::SetFramebuffer(fb)) => {
+                    let gop = handles
+                        .first()
+                        .map(|h| MemoryHandle::from_kref(KernelReference::from_id(*h)))
+                        .and_then(|memory| map_framebuffer(&fb, memory));
+                    match gop {
+                        Some(gop) => {
+                            let new =
+                                (gop.buffer.load(Ordering::Relaxed) as usize, gop.buffer_size);
+                            with_held_interrupts(|| {
+                                WRITER.get().unwrap().lock().set_framebuffer(gop)
+                            });
+                            if let Some((ptr, size)) = mapped.replace(new) {
+                                unmmap_page(ptr, size);
+                            }
+                            info!("Console is now {}x{}", fb.width, fb.height);
+                            ConsoleResponse::Ok
+                        }
+                        None => ConsoleResponse::Failed,
+                    }
+                }
+                Ok(ConsoleMessage::TakeDamage) => {
+                    ConsoleResponse::Damage(with_held_interrupts(|| {
+                        WRITER.get().unwrap().lock().screen.damage.take()
+                    }))
+                }
+                Err(_) => {
+                    warn!("bad console message");
+                    return ControlFlow::Break(());
+                }
+            };
+            channel_write_rs(handle.id(), serialize(&response, &mut out), &[]);
+            ControlFlow::Continue(())
+        },
+    );
+    service.run();
+}
+
 fn redraw_screen_task() {
     let writer = WRITER.get().unwrap();
     // TODO: Can we VSYNC this? Could stop the tearing.
@@ -151,11 +299,17 @@ pub fn gop_entry() {
         let mut mem = proc.memory.lock();
 
         mem.page_mapper
-            .insert_mapping_at_set(gop.0, gop.1, MemoryMappingFlags::WRITEABLE)
+            .insert_mapping_at_set(
+                gop.0,
+                gop.1,
+                MemoryMappingFlags::WRITEABLE | MemoryMappingFlags::WRITE_COMBINING,
+            )
             .unwrap();
     });
 
     spawn_thread(monitor_cursor_task);
     spawn_thread(redraw_screen_task);
+    spawn_thread(monitor_console_task);
+    spawn_thread(monitor_fb_task);
     monitor_stdout_task();
 }
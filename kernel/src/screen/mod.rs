@@ -1,4 +1,5 @@
 #[macro_use]
 pub mod gop;
+pub mod fb;
 pub mod psf1;
 pub mod mouse;
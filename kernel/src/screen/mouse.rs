@@ -1,7 +1,6 @@
-use alloc::vec::Vec;
-use kernel_userspace::{input::InputServiceMessage, service::SimpleService};
+use kernel_userspace::input::{InputClient, InputServiceMessage};
 
-use input::mouse::MousePacket;
+use input::mouse::{AbsoluteMousePacket, MousePacket, ABSOLUTE_MAX};
 
 use crate::scheduling::with_held_interrupts;
 
@@ -27,40 +26,61 @@ pub const MOUSE_POINTER: &[u16; 16] = &[
 ];
 
 pub fn monitor_cursor_task() {
-    let mut mouse = SimpleService::with_name("INPUT:MOUSE");
+    let mut mouse = InputClient::new("INPUT:MOUSE");
 
     let mut mouse_pos: Pos = Pos { x: 0, y: 0 };
 
-    let mut handles = Vec::new();
-
     loop {
-        let packet = mouse.recv_val(&mut handles).unwrap();
-
-        match packet {
-            InputServiceMessage::KeyboardEvent(_) => panic!(),
+        match mouse.recv_val() {
+            InputServiceMessage::KeyboardEvent(_) | InputServiceMessage::KeyboardLayout(_) => {
+                panic!()
+            }
             InputServiceMessage::MouseEvent(mouse) => print_cursor(&mut mouse_pos, mouse),
+            InputServiceMessage::MouseAbsolute(mouse) => {
+                print_cursor_absolute(&mut mouse_pos, mouse)
+            }
         }
     }
 }
 
 pub fn print_cursor(pos: &mut Pos, mouse: MousePacket) {
+    pos.x = pos.x.saturating_add_signed(mouse.x_mov as isize);
+    pos.y = pos.y.saturating_add_signed(mouse.y_mov as isize);
+
+    draw_cursor(pos, cursor_colour(mouse.left, mouse.right, mouse.middle));
+}
+
+/// Scales the pointer's position to the screen, so it sits under the host's cursor
+pub fn print_cursor_absolute(pos: &mut Pos, mouse: AbsoluteMousePacket) {
+    let (width, height) = with_held_interrupts(|| {
+        let gop = &WRITER.get().unwrap().lock().screen.gop;
+        (gop.horizonal, gop.vertical)
+    });
+    pos.x = mouse.x as usize * width / (ABSOLUTE_MAX as usize + 1);
+    pos.y = mouse.y as usize * height / (ABSOLUTE_MAX as usize + 1);
+
+    draw_cursor(pos, cursor_colour(mouse.left, mouse.right, mouse.middle));
+}
+
+fn cursor_colour(left: bool, right: bool, middle: bool) -> u32 {
     let mut colour: u32 = 0x50_50_50;
 
-    if mouse.left {
+    if left {
         colour |= 0xFF_00_00;
     }
 
-    if mouse.right {
+    if right {
         colour |= 0x00_FF_00;
     }
 
-    if mouse.middle {
+    if middle {
         colour |= 0x00_00_FF;
     }
 
-    pos.x = pos.x.saturating_add_signed(mouse.x_mov as isize);
-    pos.y = pos.y.saturating_add_signed(mouse.y_mov as isize);
+    colour
+}
 
+fn draw_cursor(pos: &mut Pos, colour: u32) {
     with_held_interrupts(|| {
         let gop_mutex = &mut WRITER.get().unwrap().lock();
         let gop_info = &gop_mutex.screen.gop;
@@ -1,18 +1,9 @@
 use core::fmt::Write;
 
-use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
-use kernel_userspace::{
-    interrupt::interrupt_wait, service::SimpleService, syscall::exit, INT_COM1,
-};
-use log::LevelFilter;
 use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
-use crate::{
-    mutex::Spinlock,
-    scheduling::taskmanager::{PROCESSES, SCHEDULER},
-    time::{uptime, SLEPT_PROCESSES},
-};
+use crate::mutex::Spinlock;
 
 pub static SERIAL: OnceCell<Spinlock<Serial>> = OnceCell::uninit();
 
@@ -23,6 +14,9 @@ pub struct Serial {
 }
 
 /// Code based on https://wiki.osdev.org/Serial_Ports
+///
+/// The kernel only polls it to write logs, the serial driver takes over its interrupts and
+/// reading from it.
 impl Serial {
     pub const fn new(bus_base: u16) -> Self {
         Self { bus_base }
@@ -68,31 +62,10 @@ impl Serial {
             // (not-loopback with IRQs enabled and OUT#1 and OUT#2 bits enabled)
             self.get_port(4).write(0x0F);
 
-            // Enable interrupts for readable
-            self.get_port(1).write(0b1);
-
             true
         })
     }
 
-    pub fn readable(&mut self) -> bool {
-        unsafe { self.get_port(5).read() & 1 > 0 }
-    }
-
-    pub fn read_serial(&mut self) -> u8 {
-        unsafe {
-            while !self.readable() {
-                core::hint::spin_loop();
-            }
-
-            self.get_port(0).read()
-        }
-    }
-
-    pub fn try_read(&mut self) -> Option<u8> {
-        unsafe { self.readable().then(|| self.get_port(0).read()) }
-    }
-
     pub fn writeable(&mut self) -> bool {
         unsafe { self.get_port(5).read() & 0x20 > 0 }
     }
@@ -120,78 +93,3 @@ impl Write for Serial {
         Result::Ok(())
     }
 }
-
-pub fn serial_monitor_stdin() {
-    let Some(serial) = SERIAL.get() else {
-        warn!("Serial device not found");
-        exit();
-    };
-    let mut ints = SimpleService::with_name("INTERRUPTS");
-    let mut handles_buf = Vec::with_capacity(1);
-    let _: () = ints.call_val(&INT_COM1, &mut handles_buf);
-
-    let ints = handles_buf[0];
-
-    loop {
-        let mut serial = serial.lock();
-        while let Some(b) = serial.try_read() {
-            let c: char = b.into();
-
-            match c {
-                '\r' => serial.write_serial(b'\n'),
-                's' => {
-                    SCHEDULER.lock().dump_runnable(&mut *serial).unwrap();
-
-                    serial
-                        .write_fmt(format_args!("Slept processes (time: {})\n", uptime()))
-                        .unwrap();
-                    for slept in SLEPT_PROCESSES.lock().iter() {
-                        serial
-                            .write_fmt(format_args!("{} {:?}\n", slept.0.wakeup, slept.0.thread))
-                            .unwrap();
-                    }
-                }
-                'l' => {
-                    serial.write_str("Change log level to: ");
-                    let to = serial.read_serial();
-                    let to = match to {
-                        b'e' => LevelFilter::Error,
-                        b'w' => LevelFilter::Warn,
-                        b'i' => LevelFilter::Info,
-                        b'd' => LevelFilter::Debug,
-                        b't' => LevelFilter::Trace,
-                        _ => {
-                            serial.write_serial(to);
-                            serial.write_str(" Unknown log level\n");
-                            continue;
-                        }
-                    };
-                    log::set_max_level(to);
-                    serial
-                        .write_fmt(format_args!("Set log level to {to}\n"))
-                        .unwrap();
-                }
-                'p' => {
-                    let processes = PROCESSES.lock();
-
-                    for proc in processes.iter() {
-                        serial
-                            .write_fmt(format_args!("{:?} {}\n", proc.0, proc.1.name))
-                            .unwrap();
-
-                        for thread in proc.1.threads.lock().threads.iter() {
-                            serial
-                                .write_fmt(format_args!("\t{:?}\n", thread.1))
-                                .unwrap();
-                        }
-                    }
-                }
-                _ => serial
-                    .write_fmt(format_args!("Unknown cmd: {c}\n"))
-                    .unwrap(),
-            }
-        }
-        drop(serial);
-        interrupt_wait(ints);
-    }
-}
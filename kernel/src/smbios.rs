@@ -0,0 +1,235 @@
+//! Reads what the firmware's SMBIOS tables say about the machine at boot, and serves it as
+//! `HWINFO`.
+
+use core::ops::ControlFlow;
+
+use acpi::AcpiHandler;
+use alloc::{string::String, vec::Vec};
+use bootloader::uefi::table::cfg::{ConfigTableEntry, SMBIOS3_GUID, SMBIOS_GUID};
+use conquer_once::spin::OnceCell;
+use kernel_userspace::{
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    hwinfo::{
+        BiosInfo, HwInfo, HwInfoMessage, HwInfoResponse, MemoryDevice, ProcessorInfo, SystemInfo,
+    },
+    service::{deserialize, serialize, Service},
+};
+
+use crate::{acpi::FioxaAcpiHandler, uefi::get_config_table};
+
+static HWINFO: OnceCell<HwInfo> = OnceCell::uninit();
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+/// Bit 6 of a processor's status
+const PROCESSOR_POPULATED: u8 = 1 << 6;
+
+/// Copies the physical memory out
+fn read_physical(addr: usize, len: usize) -> Vec<u8> {
+    unsafe {
+        let mapping = FioxaAcpiHandler.map_physical_region::<u8>(addr, len);
+        core::slice::from_raw_parts(mapping.virtual_start().as_ptr(), len).to_vec()
+    }
+}
+
+/// Where the structure table is, its length and the version, from the 64 bit entry point
+fn entry_point3(addr: usize) -> Option<(usize, usize, (u8, u8))> {
+    let entry = read_physical(addr, 0x18);
+    if &entry[0..5] != b"_SM3_" {
+        return None;
+    }
+    let len = u32::from_le_bytes(entry[0x0C..0x10].try_into().unwrap());
+    let table = u64::from_le_bytes(entry[0x10..0x18].try_into().unwrap());
+    Some((table as usize, len as usize, (entry[0x07], entry[0x08])))
+}
+
+/// The same from the 32 bit entry point
+fn entry_point(addr: usize) -> Option<(usize, usize, (u8, u8))> {
+    let entry = read_physical(addr, 0x1F);
+    if &entry[0..4] != b"_SM_" || &entry[0x10..0x15] != b"_DMI_" {
+        return None;
+    }
+    let len = u16::from_le_bytes(entry[0x16..0x18].try_into().unwrap());
+    let table = u32::from_le_bytes(entry[0x18..0x1C].try_into().unwrap());
+    Some((table as usize, len as usize, (entry[0x06], entry[0x07])))
+}
+
+/// A structure's formatted part and the strings after it
+struct Structure<'a> {
+    kind: u8,
+    data: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl Structure<'_> {
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// The string the byte at the offset refers to, empty if it isn't there
+    fn string(&self, offset: usize) -> String {
+        let index = match self.u8(offset) {
+            Some(0) | None => return String::new(),
+            Some(i) => i as usize,
+        };
+        self.strings
+            .split(|&b| b == 0)
+            .nth(index - 1)
+            .map(|s| String::from_utf8_lossy(s).trim().into())
+            .unwrap_or_default()
+    }
+}
+
+/// Splits the table into its structures up to the end marker
+fn structures(mut table: &[u8]) -> impl Iterator<Item = Structure<'_>> {
+    core::iter::from_fn(move || {
+        let len = *table.get(1)? as usize;
+        if len < 4 || table.len() < len {
+            return None;
+        }
+        let (data, rest) = table.split_at(len);
+        // The strings end with two nuls, which is all there is if it has none
+        let end = rest.windows(2).position(|w| w == [0, 0])?;
+        table = &rest[end + 2..];
+        let s = Structure {
+            kind: data[0],
+            data,
+            strings: &rest[..end],
+        };
+        (s.kind != TYPE_END).then_some(s)
+    })
+}
+
+fn processor(s: &Structure) -> ProcessorInfo {
+    // Newer tables put counts above 255 in a second field
+    let count = |short: usize, long: usize| match s.u8(short) {
+        Some(0xFF) => s.u16(long).unwrap_or(0),
+        c => c.unwrap_or(0) as u16,
+    };
+    ProcessorInfo {
+        socket: s.string(0x04),
+        manufacturer: s.string(0x07),
+        version: s.string(0x10),
+        populated: s.u8(0x18).is_some_and(|st| st & PROCESSOR_POPULATED != 0),
+        max_speed_mhz: s.u16(0x14).unwrap_or(0),
+        current_speed_mhz: s.u16(0x16).unwrap_or(0),
+        cores: count(0x23, 0x2A),
+        threads: count(0x25, 0x2E),
+    }
+}
+
+fn memory_device(s: &Structure) -> MemoryDevice {
+    let size_mb = match s.u16(0x0C).unwrap_or(0) {
+        // Unknown
+        0xFFFF => 0,
+        // Too big, it's in the extended size
+        0x7FFF => s.u32(0x1C).unwrap_or(0) as u64 & 0x7FFF_FFFF,
+        // In kb
+        size if size & 0x8000 != 0 => (size & 0x7FFF) as u64 / 1024,
+        size => size as u64,
+    };
+    MemoryDevice {
+        locator: s.string(0x10),
+        bank: s.string(0x11),
+        size_mb,
+        memory_type: s.u8(0x12).unwrap_or(0),
+        speed: s.u16(0x15).unwrap_or(0),
+        manufacturer: s.string(0x17),
+        part_number: s.string(0x1A),
+    }
+}
+
+fn parse(table: &[u8], version: (u8, u8)) -> HwInfo {
+    let mut info = HwInfo {
+        version,
+        ..Default::default()
+    };
+    for s in structures(table) {
+        match s.kind {
+            TYPE_BIOS => {
+                info.bios = BiosInfo {
+                    vendor: s.string(0x04),
+                    version: s.string(0x05),
+                    release_date: s.string(0x08),
+                }
+            }
+            TYPE_SYSTEM => {
+                info.system = SystemInfo {
+                    vendor: s.string(0x04),
+                    product: s.string(0x05),
+                    version: s.string(0x06),
+                }
+            }
+            TYPE_PROCESSOR => info.processors.push(processor(&s)),
+            TYPE_MEMORY_DEVICE => info.memory.push(memory_device(&s)),
+            _ => (),
+        }
+    }
+    info
+}
+
+/// Finds and reads the SMBIOS tables, which have to be done while the config tables are mapped
+pub fn init(config_tables: &[ConfigTableEntry]) {
+    let found = get_config_table(SMBIOS3_GUID, config_tables)
+        .and_then(|t| entry_point3(t.address as usize))
+        .or_else(|| {
+            get_config_table(SMBIOS_GUID, config_tables)
+                .and_then(|t| entry_point(t.address as usize))
+        });
+    let Some((table, len, version)) = found else {
+        info!("There are no SMBIOS tables");
+        return;
+    };
+    let info = parse(&read_physical(table, len), version);
+    info!(
+        "SMBIOS {}.{}: {} {}",
+        version.0, version.1, info.system.vendor, info.system.product
+    );
+    HWINFO.init_once(|| info);
+}
+
+pub fn hwinfo_service() {
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+
+    Service::new(
+        "HWINFO",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            let resp = match deserialize(&buffer) {
+                Ok(HwInfoMessage::Get) => match HWINFO.get() {
+                    Some(info) => HwInfoResponse::Info(info.clone()),
+                    None => HwInfoResponse::Unavailable,
+                },
+                Err(e) => {
+                    error!("{e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+
+            channel_write_rs(handle.id(), serialize(&resp, &mut buffer), &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
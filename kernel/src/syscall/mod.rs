@@ -1,36 +1,51 @@
-use core::ptr::slice_from_raw_parts_mut;
+use core::{
+    mem::{offset_of, MaybeUninit},
+    sync::atomic::Ordering,
+};
 
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{sync::Arc, vec::Vec};
 use kernel_userspace::{
     channel::{ChannelCreate, ChannelRead, ChannelReadResult, ChannelSyscall, ChannelWrite},
+    cpu::CpuSyscall,
+    dma::{DmaAlloc, DmaConstraints, DmaSegment},
     interrupt::InterruptSyscall,
+    memory::MemorySyscall,
     message::{MessageCreate, MessageGetSize, MessageRead, SyscallMessageAction},
     num_traits::FromPrimitive,
     object::{KernelReferenceID, ObjectSignal, ReferenceOperation, WaitPort},
     port::{PortNotification, PortSyscall},
-    process::KernelProcessOperation,
+    process::{KernelProcessOperation, ProcessMemInfo, ProcessRUsage},
     syscall::SYSCALL_NUMBER,
+    system::SystemSyscall,
+    time::TimeSyscall,
 };
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 use crate::{
+    acpi::{events, power},
     channel::{channel_create, ChannelMessage, ReadError},
     cpu_localstorage::CPULocalStorageRW,
+    fs::{self, page_cache, PartitionId},
     interrupts::KInterruptHandle,
+    iommu::DmaGrant,
+    lapic,
     message::KMessage,
     object::{KObject, KObjectSignal, SignalWaiter},
     paging::{
-        page_allocator::{frame_alloc_exec, global_allocator},
+        dma::alloc_dma,
+        page_allocator::{frame_alloc_exec, global_allocator, Zone},
         page_mapper::PageMapping,
         page_table::Mapper,
+        pressure::{add_pressure_port, memory_pressure},
         AllocatedPage, GlobalPageAllocator, MemoryMappingFlags,
     },
     port::KPort,
     scheduling::{
-        process::{KernelValue, ThreadState},
+        process::{KernelValue, ProcessPrivilige, ThreadPriority, ThreadState},
         taskmanager::{self, enter_sched, kill_bad_task},
     },
-    time::{uptime, SleptProcess, SLEPT_PROCESSES},
+    time::{self, uptime, SleptProcess, SLEPT_PROCESSES},
+    user_mem::{self, UserBytes, UserPtr, UserSlice},
 };
 
 pub fn set_syscall_idt(idt: &mut InterruptDescriptorTable) {
@@ -231,6 +246,7 @@ unsafe extern "C" fn syscall_handler(
 ) -> usize {
     // Run syscalls without interrupts
     // This means execution should not be interrupted
+    user_mem::clac();
     let thread = CPULocalStorageRW::get_current_task();
     {
         let mut sched = thread.sched().lock();
@@ -239,12 +255,14 @@ unsafe extern "C" fn syscall_handler(
             enter_sched(&mut sched);
             unreachable!("exit thread shouldn't return")
         }
+        thread.account_time(&mut sched);
         sched.in_syscall = true;
     }
     use kernel_userspace::syscall::*;
     let res = match number {
         YIELD_NOW => {
             let mut sched = thread.sched().lock();
+            thread.account_time(&mut sched);
             sched.in_syscall = false;
             enter_sched(&mut sched);
             return 0;
@@ -256,31 +274,48 @@ unsafe extern "C" fn syscall_handler(
             enter_sched(&mut sched);
             unreachable!("exit thread shouldn't return")
         }
+        EXIT_PROCESS => {
+            thread.process().kill_threads();
+            let mut sched = thread.sched().lock();
+            sched.in_syscall = false;
+            enter_sched(&mut sched);
+            unreachable!("exit process shouldn't return")
+        }
         ECHO => echo_handler(arg1),
         SPAWN_THREAD => taskmanager::spawn_thread(arg1, arg2),
         SLEEP => sleep_handler(arg1),
         MMAP_PAGE => mmap_page_handler(arg1, arg2),
         MMAP_PAGE32 => mmap_page32_handler(),
-        UNMMAP_PAGE => {
-            // ! TODO: THIS IS VERY BAD
-            // Another thread can still write to the memory
-            unmmap_page_handler(arg1, arg2)
-        }
+        UNMMAP_PAGE => unmmap_page_handler(arg1, arg2),
         READ_ARGS => read_args_handler(arg1),
         GET_PID => Ok(thread.process().pid.0 as usize),
         MESSAGE => message_handler(arg1, arg2),
         OBJECT => sys_reference_handler(arg1, arg2, arg3),
-        PROCESS => sys_process_handler(arg1, arg2),
+        PROCESS => sys_process_handler(arg1, arg2, arg3),
         CHANNEL => sys_channel_handler(arg1, arg2),
         PORT => sys_port_handler(arg1, arg2, arg3),
         INTERRUPT => sys_interrupt_handler(arg1, arg2, arg3, arg4),
+        CPU => sys_cpu_handler(arg1, arg2),
+        SET_PRIORITY => set_priority_handler(arg1),
+        MEMORY => sys_memory_handler(arg1, arg2, arg3, arg4),
+        TIME => sys_time_handler(arg1, arg2),
+        SYSTEM => sys_system_handler(arg1),
         _ => {
             error!("Unknown syscall class: {}", number);
             Err(SyscallError::Error)
         }
     };
 
-    thread.sched().lock().in_syscall = false;
+    {
+        let mut sched = thread.sched().lock();
+        thread.account_time(&mut sched);
+        sched.in_syscall = false;
+        // The process might have been killed while we were in the syscall
+        if sched.killed {
+            enter_sched(&mut sched);
+            unreachable!("exit thread shouldn't return")
+        }
+    }
     match res {
         Ok(r) => r,
         Err(SyscallError::Error) => kill_bad_task(),
@@ -301,8 +336,7 @@ unsafe fn read_args_handler(arg1: usize) -> Result<usize, SyscallError> {
         Ok(proc.args.len())
     } else {
         let bytes = &proc.args;
-        let buf = unsafe { &mut *slice_from_raw_parts_mut(arg1 as *mut u8, bytes.len()) };
-        buf.copy_from_slice(bytes);
+        kunwrap!(UserBytes::new(arg1, bytes.len())).write(bytes);
         Ok(arg1)
     }
 }
@@ -315,15 +349,12 @@ unsafe fn mmap_page_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallEr
     let mut memory = task.process().memory.lock();
 
     let lazy_page = PageMapping::new_lazy((arg2 + 0xFFF) & !0xFFF);
+    let flags = MemoryMappingFlags::WRITEABLE | task.process().privilege.mapping_flags();
 
     if arg1 == 0 {
-        Ok(memory
-            .page_mapper
-            .insert_mapping(lazy_page, MemoryMappingFlags::all()))
+        Ok(memory.page_mapper.insert_mapping(lazy_page, flags))
     } else {
-        kunwrap!(memory
-            .page_mapper
-            .insert_mapping_at(arg1, lazy_page, MemoryMappingFlags::all()));
+        kunwrap!(memory.page_mapper.insert_mapping_at(arg1, lazy_page, flags));
         Ok(arg1)
     }
 }
@@ -331,7 +362,7 @@ unsafe fn mmap_page_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallEr
 unsafe fn mmap_page32_handler() -> Result<usize, SyscallError> {
     let task = CPULocalStorageRW::get_current_task();
 
-    let page = kunwrap!(frame_alloc_exec(|a| a.allocate_page_32bit()));
+    let page = kunwrap!(frame_alloc_exec(|a| a.allocate_pages_in(Zone::Below32, 1)));
 
     let r = page.get_address() as usize;
 
@@ -340,7 +371,11 @@ unsafe fn mmap_page32_handler() -> Result<usize, SyscallError> {
         memory
             .page_mapper
             .get_mapper_mut()
-            .identity_map(global_allocator(), page, MemoryMappingFlags::all())
+            .identity_map(
+                global_allocator(),
+                page,
+                MemoryMappingFlags::WRITEABLE | task.process().privilege.mapping_flags(),
+            )
             .unwrap()
             .flush();
     }
@@ -430,7 +465,7 @@ unsafe fn sys_reference_handler(
         ReferenceOperation::WaitPort => {
             let val = kunwrap!(refs.references().get(&id)).clone();
 
-            let wait = &*(arg3 as *const WaitPort);
+            let wait = kunwrap!(UserPtr::<WaitPort>::new(arg3)).read();
 
             let port = kunwrap!(refs.references().get(&wait.port_handle)).clone();
             let port = kenum_cast!(port, KernelValue::Port);
@@ -468,7 +503,11 @@ unsafe fn sys_reference_handler(
     }
 }
 
-unsafe fn sys_process_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallError> {
+unsafe fn sys_process_handler(
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> Result<usize, SyscallError> {
     let thread = CPULocalStorageRW::get_current_task();
 
     let operation: KernelProcessOperation = kunwrap!(FromPrimitive::from_usize(arg1));
@@ -483,12 +522,224 @@ unsafe fn sys_process_handler(arg1: usize, arg2: usize) -> Result<usize, Syscall
             proc.kill_threads();
             Ok(0)
         }
+        KernelProcessOperation::ForkCow => {
+            kassert!(thread.process().privilege == ProcessPrivilige::KERNEL);
+            let fork = proc.fork_cow();
+            Ok(thread
+                .process()
+                .add_value(KernelValue::Process(fork))
+                .0
+                .get())
+        }
+        KernelProcessOperation::GetRUsage => {
+            kassert!(arg3 != 0);
+            kunwrap!(UserPtr::<ProcessRUsage>::new(arg3)).write(proc.get_rusage());
+            Ok(0)
+        }
+        KernelProcessOperation::GetMemInfo => {
+            kassert!(arg3 != 0);
+            kunwrap!(UserPtr::<ProcessMemInfo>::new(arg3)).write(proc.get_mem_info());
+            Ok(0)
+        }
+    }
+}
+
+unsafe fn sys_cpu_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallError> {
+    let thread = CPULocalStorageRW::get_current_task();
+    let action = kunwrap!(CpuSyscall::from_usize(arg1));
+    let core_id: u8 = kunwrap!(arg2.try_into());
+
+    match action {
+        CpuSyscall::Park => {
+            kassert!(thread.process().privilege == ProcessPrivilige::KERNEL);
+            Ok(taskmanager::park_core(core_id) as usize)
+        }
+        CpuSyscall::Unpark => {
+            kassert!(thread.process().privilege == ProcessPrivilige::KERNEL);
+            Ok(taskmanager::unpark_core(core_id) as usize)
+        }
+        CpuSyscall::IsParked => Ok(taskmanager::is_core_parked(core_id) as usize),
+    }
+}
+
+unsafe fn sys_memory_handler(
+    syscall: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+) -> Result<usize, SyscallError> {
+    let action = kunwrap!(MemorySyscall::from_usize(syscall));
+    let thread = CPULocalStorageRW::get_current_task();
+
+    match action {
+        MemorySyscall::GetPressure => Ok(memory_pressure() as usize),
+        MemorySyscall::SetPressurePort => {
+            let id = kunwrap!(KernelReferenceID::from_usize(arg1));
+            let port = kunwrap!(thread.process().get_value(id));
+            let port = kenum_cast!(port, KernelValue::Port);
+
+            add_pressure_port(port, arg2 as u64);
+            Ok(0)
+        }
+        MemorySyscall::MapFile => {
+            let file = (PartitionId(arg1 as u64), arg2);
+            let writable = arg3 != 0;
+
+            let Ok(size) = fs::file_size(file) else {
+                return Ok(0);
+            };
+            if size == 0 || (writable && !matches!(fs::is_writable(file.0), Ok(true))) {
+                return Ok(0);
+            }
+
+            let mut flags = thread.process().privilege.mapping_flags();
+            if writable {
+                flags |= MemoryMappingFlags::WRITEABLE;
+            }
+            let mapping = PageMapping::new_file(file, 0, size);
+            Ok(thread
+                .process()
+                .memory
+                .lock()
+                .page_mapper
+                .insert_mapping(mapping, flags))
+        }
+        MemorySyscall::DmaAlloc => sys_dma_alloc(arg1),
+        MemorySyscall::MapObject => {
+            let id = kunwrap!(KernelReferenceID::from_usize(arg1));
+            let mem = kunwrap!(thread.process().get_value(id));
+            let mem = kenum_cast!(mem, KernelValue::Memory);
+
+            let mut flags = thread.process().privilege.mapping_flags();
+            // Other mappings are shared with the page cache or copy on write
+            if arg2 != 0 {
+                kassert!(mem.is_dma() || mem.is_mmio());
+                flags |= MemoryMappingFlags::WRITEABLE;
+            }
+            Ok(thread
+                .process()
+                .memory
+                .lock()
+                .page_mapper
+                .insert_mapping(mem, flags))
+        }
+        MemorySyscall::ShareDma => {
+            let mapping = thread.process().memory.lock().page_mapper.mapping_at(arg1);
+            let Some((_, mapping)) = mapping.filter(|(_, m)| m.is_dma()) else {
+                return Ok(0);
+            };
+            Ok(thread.process().add_value(mapping.into()).0.get())
+        }
+        MemorySyscall::MakeExecutable => {
+            let start = arg1;
+            let end = kunwrap!(start.checked_add((arg2 + 0xFFF) & !0xFFF));
+            kassert!(end <= crate::paging::MemoryLoc::EndUserMem as usize);
+
+            let res = thread.process().memory.lock().page_mapper.set_flags(
+                start..end,
+                thread.process().privilege.mapping_flags() | MemoryMappingFlags::EXECUTABLE,
+            );
+            Ok(res.is_some() as usize)
+        }
+    }
+}
+
+unsafe fn sys_dma_alloc(arg1: usize) -> Result<usize, SyscallError> {
+    let thread = CPULocalStorageRW::get_current_task();
+    // Pinned memory is never swapped, so only drivers can have it
+    kassert!(
+        thread.process().privilege == ProcessPrivilige::KERNEL
+            || thread.process().driver.load(Ordering::Relaxed)
+    );
+
+    // Read once without trusting the bool, userspace can put any byte there
+    let raw = kunwrap!(UserPtr::<MaybeUninit<DmaAlloc>>::new(arg1)).read();
+    let below_4g = *(raw.as_ptr() as *const u8)
+        .add(offset_of!(DmaAlloc, constraints) + offset_of!(DmaConstraints, below_4g));
+    kassert!(below_4g <= 1);
+    let mut req = raw.assume_init();
+    let req_ptr = kunwrap!(UserPtr::<DmaAlloc>::new(arg1));
+    let segments_out = kunwrap!(UserSlice::<DmaSegment>::new(
+        req.segments as usize,
+        req.segments_len
+    ));
+
+    let mut constraints = req.constraints;
+    constraints.max_segments = constraints.max_segments.min(req.segments_len);
+    let Some((pages, segments)) = alloc_dma(req.size, &constraints) else {
+        return Ok(0);
+    };
+
+    let grant = DmaGrant::new(thread.process().dma_domains.lock().clone(), &segments);
+    let flags = MemoryMappingFlags::WRITEABLE | thread.process().privilege.mapping_flags();
+    let base = thread
+        .process()
+        .memory
+        .lock()
+        .page_mapper
+        .insert_mapping_set(PageMapping::new_dma(pages, grant), flags);
+
+    segments_out.write(&segments);
+    req.segments_len = segments.len();
+    req_ptr.write(req);
+    Ok(base)
+}
+
+unsafe fn set_priority_handler(arg1: usize) -> Result<usize, SyscallError> {
+    let thread = CPULocalStorageRW::get_current_task();
+    let priority = ThreadPriority(kunwrap!(arg1.try_into()));
+    kassert!(
+        priority <= ThreadPriority::NORMAL
+            || thread.process().privilege == ProcessPrivilige::KERNEL
+    );
+
+    // The new class takes effect the next time the thread is queued
+    thread.sched().lock().priority = priority;
+    Ok(0)
+}
+
+unsafe fn sys_time_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallError> {
+    let thread = CPULocalStorageRW::get_current_task();
+    let action = kunwrap!(TimeSyscall::from_usize(arg1));
+
+    match action {
+        TimeSyscall::Uptime => Ok(uptime() as usize),
+        TimeSyscall::Realtime => Ok(time::realtime_us().unwrap_or(0) as usize),
+        TimeSyscall::SetRealtime => {
+            kassert!(thread.process().privilege == ProcessPrivilige::KERNEL);
+            time::set_realtime_us(arg2 as u64);
+            Ok(0)
+        }
+    }
+}
+
+/// Anyone at the console can already pull the plug, so turning the machine off isn't
+/// privileged
+unsafe fn sys_system_handler(arg1: usize) -> Result<usize, SyscallError> {
+    let thread = CPULocalStorageRW::get_current_task();
+    let action = kunwrap!(SystemSyscall::from_usize(arg1));
+
+    match action {
+        SystemSyscall::Shutdown => {
+            page_cache::writeback();
+            power::shutdown();
+            Ok(0)
+        }
+        SystemSyscall::Reboot => {
+            page_cache::writeback();
+            power::reboot();
+            Ok(0)
+        }
+        SystemSyscall::TakeEvents => {
+            kassert!(thread.process().privilege == ProcessPrivilige::KERNEL);
+            Ok(events::take_events().bits() as usize)
+        }
     }
 }
 
 unsafe fn sleep_handler(arg1: usize) -> Result<usize, SyscallError> {
     let start = uptime();
-    let time = start + arg1 as u64;
+    let time = time::uptime_us() + arg1 as u64 * 1000;
     let thread = CPULocalStorageRW::get_current_task();
 
     let mut sched = thread.sched().lock();
@@ -500,6 +751,7 @@ unsafe fn sleep_handler(arg1: usize) -> Result<usize, SyscallError> {
             wakeup: time,
             thread: thread.thread(),
         }));
+    lapic::wake_by(time);
 
     enter_sched(&mut sched);
     Ok((uptime() - start) as usize)
@@ -511,25 +763,29 @@ unsafe fn message_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallErro
 
     match action {
         SyscallMessageAction::Create => unsafe {
-            let msg_create = &mut *(arg2 as *mut MessageCreate);
-            let req = &msg_create.before;
-            let data: Box<[u8]> = core::slice::from_raw_parts(req.0, req.1).into();
+            let msg_create = kunwrap!(UserPtr::<MessageCreate>::new(arg2));
+            let req = msg_create.read().before;
+            let data = kunwrap!(UserBytes::new(req.0 as usize, req.1)).read();
             let msg = Arc::new(KMessage { data });
 
-            msg_create.after = thread.process().add_value(msg.into());
+            msg_create.write(MessageCreate {
+                after: thread.process().add_value(msg.into()),
+            });
         },
         SyscallMessageAction::GetSize => unsafe {
-            let msg_size = &mut *(arg2 as *mut MessageGetSize);
+            let msg_size = kunwrap!(UserPtr::<MessageGetSize>::new(arg2));
 
-            let msg = kunwrap!(thread.process().get_value(msg_size.before));
+            let msg = kunwrap!(thread.process().get_value(msg_size.read().before));
             let msg = kenum_cast!(msg, KernelValue::Message);
 
-            msg_size.after = msg.data.len();
+            msg_size.write(MessageGetSize {
+                after: msg.data.len(),
+            });
         },
         SyscallMessageAction::Read => unsafe {
-            let msg_read = &mut *(arg2 as *mut MessageRead);
+            let msg_read = kunwrap!(UserPtr::<MessageRead>::new(arg2)).read();
 
-            let loc = core::slice::from_raw_parts_mut(msg_read.ptr.0, msg_read.ptr.1);
+            let loc = kunwrap!(UserBytes::new(msg_read.ptr.0 as usize, msg_read.ptr.1));
 
             let msg = kunwrap!(thread.process().get_value(msg_read.id));
             let msg = kenum_cast!(msg, KernelValue::Message);
@@ -543,7 +799,7 @@ unsafe fn message_handler(arg1: usize, arg2: usize) -> Result<usize, SyscallErro
                 loc.len()
             );
 
-            loc.copy_from_slice(data);
+            loc.write(data);
         },
     }
 
@@ -557,43 +813,49 @@ unsafe fn sys_channel_handler(syscall: usize, arg2: usize) -> Result<usize, Sysc
 
     match action {
         ChannelSyscall::Create => {
-            let create = &mut *(arg2 as *mut ChannelCreate);
+            let create = kunwrap!(UserPtr::<ChannelCreate>::new(arg2));
 
             let (left, right) = channel_create();
 
             let left = thread.process().add_value(left.into());
             let right = thread.process().add_value(right.into());
 
-            create.left = Some(left);
-            create.right = Some(right);
+            create.write(ChannelCreate {
+                left: Some(left),
+                right: Some(right),
+            });
             Ok(1)
         }
         ChannelSyscall::Read => {
-            let read = &mut *(arg2 as *mut ChannelRead);
+            let read_ptr = kunwrap!(UserPtr::<ChannelRead>::new(arg2));
+            let mut read = read_ptr.read();
             let handle = kunwrap!(thread.process().get_value(read.handle));
 
             let chan = kenum_cast!(handle, KernelValue::Channel);
 
+            let data = kunwrap!(UserBytes::new(read.data as usize, read.data_len));
+            let handles = kunwrap!(UserSlice::<Option<KernelReferenceID>>::new(
+                read.handles as usize,
+                read.handles_len
+            ));
+
             match chan.read(read.data_len, read.handles_len) {
                 Ok(ok) => {
                     read.data_len = ok.data.len();
-                    let data_ptr = core::slice::from_raw_parts_mut(read.data, ok.data.len());
-                    data_ptr.copy_from_slice(&ok.data);
+                    data.write(&ok.data);
 
                     if let Some(h) = ok.handles {
                         read.handles_len = h.len();
-                        let data_ptr: &mut [Option<KernelReferenceID>] =
-                            core::slice::from_raw_parts_mut(read.handles.cast(), h.len());
-
-                        let mut i = 0;
-                        for handle in h {
-                            let id = thread.process().add_value(handle);
-                            data_ptr[i] = Some(id);
-                            i += 1;
-                        }
+                        let ids: Vec<_> = h
+                            .into_vec()
+                            .into_iter()
+                            .map(|handle| Some(thread.process().add_value(handle)))
+                            .collect();
+                        handles.write(&ids);
                     } else {
                         read.handles_len = 0;
                     }
+                    read_ptr.write(read);
                     Ok(ChannelReadResult::Ok as usize)
                 }
                 Err(ReadError::Empty) => Ok(ChannelReadResult::Empty as usize),
@@ -603,24 +865,28 @@ unsafe fn sys_channel_handler(syscall: usize, arg2: usize) -> Result<usize, Sysc
                 }) => {
                     read.data_len = min_bytes;
                     read.handles_len = min_handles;
+                    read_ptr.write(read);
                     Ok(ChannelReadResult::Size as usize)
                 }
                 Err(ReadError::Closed) => Ok(ChannelReadResult::Closed as usize),
             }
         }
         ChannelSyscall::Write => {
-            let write = &mut *(arg2 as *mut ChannelWrite);
+            let write = kunwrap!(UserPtr::<ChannelWrite>::new(arg2)).read();
             let handle = kunwrap!(thread.process().get_value(write.handle));
 
             let chan = kenum_cast!(handle, KernelValue::Channel);
-            let data = core::slice::from_raw_parts(write.data, write.data_len);
+            let data = kunwrap!(UserBytes::new(write.data as usize, write.data_len)).read();
 
             let handles = if !write.handles.is_null() && write.handles_len > 0 {
-                let handles: &[Option<KernelReferenceID>] =
-                    core::slice::from_raw_parts(write.handles.cast(), write.handles_len);
+                let handles = kunwrap!(UserSlice::<Option<KernelReferenceID>>::new(
+                    write.handles as usize,
+                    write.handles_len
+                ))
+                .read();
                 let mut handles_res = Vec::with_capacity(write.handles_len);
                 let mut refs = thread.process().references.lock();
-                for h in handles {
+                for h in handles.iter() {
                     match h {
                         Some(r) => handles_res.push(kunwrap!(refs.references().get(r)).clone()),
                         None => kpanic!("null ref not allowed"),
@@ -631,10 +897,7 @@ unsafe fn sys_channel_handler(syscall: usize, arg2: usize) -> Result<usize, Sysc
                 None
             };
 
-            let msg = ChannelMessage {
-                data: data.into(),
-                handles,
-            };
+            let msg = ChannelMessage { data, handles };
             match chan.send(msg) {
                 Some(()) => Ok(1),
                 None => Ok(0),
@@ -663,7 +926,7 @@ unsafe fn sys_port_handler(
 
             let port = kenum_cast!(handle, KernelValue::Port);
             let v = port.wait();
-            (arg2 as *mut PortNotification).write(v);
+            kunwrap!(UserPtr::<PortNotification>::new(arg2)).write(v);
             Ok(0)
         }
         PortSyscall::Push => {
@@ -671,7 +934,7 @@ unsafe fn sys_port_handler(
             let handle = kunwrap!(thread.process().get_value(handle));
 
             let port = kenum_cast!(handle, KernelValue::Port);
-            let v = (arg2 as *const PortNotification).read();
+            let v = kunwrap!(UserPtr::<PortNotification>::new(arg2)).read();
             port.notify(v);
             Ok(0)
         }
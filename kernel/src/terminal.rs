@@ -28,6 +28,8 @@ pub struct Writer<'a> {
     pub tty: TTY,
     pub mouse_pos: Pos,
     pub mouse_colour: u32,
+    /// A userspace surface is on the screen, so nothing is drawn
+    suspended: bool,
 }
 
 impl<'a> Writer<'a> {
@@ -58,8 +60,10 @@ impl<'a> Writer<'a> {
                 gop,
                 font,
                 unicode_table,
+                damage: None,
             },
             mouse_colour: 0xFF_FF_FF,
+            suspended: false,
         }
     }
 }
@@ -85,7 +89,33 @@ impl Writer<'_> {
         self.tty.pos_y = 0;
     }
 
+    /// Moves onto another framebuffer, keeping as much of the text as fits in its size
+    pub fn set_framebuffer(&mut self, gop: GopInfo) {
+        self.tty
+            .resize(gop.horizonal / CHAR_WIDTH, gop.vertical / CHAR_HEIGHT);
+        self.mouse_pos.x = self.mouse_pos.x.min(gop.horizonal - CHAR_WIDTH);
+        self.mouse_pos.y = self.mouse_pos.y.min(gop.vertical - CHAR_HEIGHT);
+        self.screen.gop = gop;
+        self.screen.damage = None;
+    }
+
+    /// Stops drawing to the screen, for while a surface from the `FB` service is on it
+    pub fn suspend(&mut self) {
+        self.suspended = true;
+    }
+
+    /// Draws the console over everything again
+    pub fn resume(&mut self) {
+        self.suspended = false;
+        self.tty.set_complete_dirty();
+    }
+
     pub fn update_cursor(&mut self, pos: Pos, colour: u32) {
+        if self.suspended {
+            self.mouse_pos = pos;
+            self.mouse_colour = colour;
+            return;
+        }
         // clear the old cursor by resetting a box around the cursor.
         let p = self.mouse_pos;
         let min_x = (p.x / CHAR_WIDTH).saturating_sub(1);
@@ -103,6 +133,9 @@ impl Writer<'_> {
     }
 
     pub fn redraw_if_needed(&mut self) {
+        if self.suspended {
+            return;
+        }
         // redraw section of screen that has been modified
         if let Some(b) = self.tty.dirty_box.take() {
             let cursor_cell = (self.mouse_pos.y / CHAR_HEIGHT) + 1;
@@ -157,6 +190,40 @@ impl TTY {
         }
     }
 
+    /// Changes the size, the lines up to the cursor stay at the bottom of what's kept
+    pub fn resize(&mut self, dims_x: usize, dims_y: usize) {
+        let blank = || Cell {
+            chr: ' ',
+            fg: self.fg_color,
+            bg: self.bg_color,
+        };
+        let kept = (self.pos_y + 1).min(dims_y);
+        let first = self.pos_y + 1 - kept;
+
+        let mut buffer = VecDeque::with_capacity(dims_y);
+        for line in self.buffer.iter_mut().skip(first).take(kept) {
+            let mut cells = core::mem::take(&mut line.cells).into_vec();
+            cells.resize_with(dims_x, blank);
+            buffer.push_back(Line {
+                cells: cells.into_boxed_slice(),
+            });
+        }
+        while buffer.len() < dims_y {
+            let mut cells = Vec::with_capacity(dims_x);
+            cells.resize_with(dims_x, blank);
+            buffer.push_back(Line {
+                cells: cells.into_boxed_slice(),
+            });
+        }
+
+        self.buffer = buffer;
+        self.dims_x = dims_x;
+        self.dims_y = dims_y;
+        self.pos_y = kept - 1;
+        self.pos_x = self.pos_x.min(dims_x - 1);
+        self.set_complete_dirty();
+    }
+
     pub fn set_fg_colour(&mut self, colour: u32) -> u32 {
         core::mem::replace(&mut self.fg_color, colour)
     }
@@ -1,32 +1,199 @@
-use core::cmp::Reverse;
+use core::{
+    arch::x86_64::_rdtsc,
+    cmp::Reverse,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use acpi::AcpiTables;
+use acpi::{fadt::Fadt, AcpiTables};
 use alloc::{collections::binary_heap::BinaryHeap, sync::Arc};
 use conquer_once::spin::OnceCell;
 
-use crate::{acpi::FioxaAcpiHandler, mutex::Spinlock, scheduling::process::Thread};
+use crate::{
+    acpi::FioxaAcpiHandler,
+    cpu::{features, CpuFeatures},
+    mutex::Spinlock,
+    scheduling::process::Thread,
+};
 
 pub mod hpet;
+pub mod pit;
+pub mod rtc;
 
 pub static HPET: OnceCell<hpet::HPET> = OnceCell::uninit();
 
+/// Where uptime is read from, the best of them that works is picked at boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Tsc,
+    Hpet,
+    Pit,
+}
+
+impl ClockSource {
+    fn uptime_ns(self) -> u64 {
+        match self {
+            ClockSource::Tsc => TSC.get().map_or(0, TscClock::uptime_ns),
+            ClockSource::Hpet => HPET.get().map_or(0, |h| h.get_uptime_ns()),
+            ClockSource::Pit => pit::uptime_ns(),
+        }
+    }
+}
+
+static CLOCK: OnceCell<ClockSource> = OnceCell::uninit();
+
+/// The clock uptime is read from, None until [`init_time`]
+pub fn clock_source() -> Option<ClockSource> {
+    CLOCK.get().copied()
+}
+
 pub fn spin_sleep_ms(time: u64) {
-    HPET.get().unwrap().spin_ms(time)
+    let end = uptime_ns() + time * 1_000_000;
+    while uptime_ns() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// How long the tsc is measured against the hpet or pit for, in nanoseconds
+const TSC_CALIBRATION_NS: u64 = 10_000_000;
+/// No tsc counts slower than 100MHz or faster than 20GHz, it was measured wrong
+const TSC_KHZ: core::ops::RangeInclusive<u64> = 100_000..=20_000_000;
+
+/// Set once the tsc has been measured, until then and on machines where the tsc can't be
+/// trusted the hpet or pit is read instead
+static TSC: OnceCell<TscClock> = OnceCell::uninit();
+
+/// The tsc is much cheaper to read than the hpet, but only counts at a fixed rate through
+/// frequency and sleep state changes if it is invariant
+struct TscClock {
+    /// The tsc and the hpet's uptime in nanoseconds when it was measured
+    base_tsc: u64,
+    base_ns: u64,
+    khz: u64,
+}
+
+impl TscClock {
+    /// Measures the tsc against the clock, the reason it can't be used if it can't
+    fn calibrate(reference: ClockSource) -> Result<Self, &'static str> {
+        if !features().contains(CpuFeatures::INVARIANT_TSC) {
+            return Err("it isn't invariant");
+        }
+
+        // Measured twice, a hypervisor can make it count unevenly
+        let first = Self::measure(reference);
+        let tsc = Self::measure(reference);
+        if !TSC_KHZ.contains(&tsc.khz) {
+            return Err("its rate is implausible");
+        }
+        if first.khz.abs_diff(tsc.khz) > tsc.khz / 100 {
+            return Err("its rate isn't steady");
+        }
+        Ok(tsc)
+    }
+
+    fn measure(reference: ClockSource) -> Self {
+        let (start_ns, start_tsc) = (reference.uptime_ns(), unsafe { _rdtsc() });
+        while reference.uptime_ns() < start_ns + TSC_CALIBRATION_NS {
+            core::hint::spin_loop();
+        }
+        let (end_ns, end_tsc) = (reference.uptime_ns(), unsafe { _rdtsc() });
+
+        let khz = (end_tsc - start_tsc) as u128 * 1_000_000 / (end_ns - start_ns) as u128;
+        Self {
+            base_tsc: end_tsc,
+            base_ns: end_ns,
+            khz: khz as u64,
+        }
+    }
+
+    fn uptime_ns(&self) -> u64 {
+        let ticks = unsafe { _rdtsc() }.saturating_sub(self.base_tsc) as u128;
+        self.base_ns + (ticks * 1_000_000 / self.khz as u128) as u64
+    }
+
+    fn tsc_at(&self, ns: u64) -> u64 {
+        let ns = ns.saturating_sub(self.base_ns) as u128;
+        self.base_tsc + (ns * self.khz as u128 / 1_000_000) as u64
+    }
+}
+
+/// What the tsc will read at the uptime in nanoseconds, None if the tsc isn't used
+pub fn tsc_at(ns: u64) -> Option<u64> {
+    TSC.get().map(|tsc| tsc.tsc_at(ns))
 }
 
 pub fn init_time(acpi_tables: &AcpiTables<FioxaAcpiHandler>) {
-    // PIC.lock().set_divisor(10000);
     if let Ok(hpet_info) = acpi::HpetInfo::new(acpi_tables) {
         HPET.init_once(|| hpet::HPET::new(hpet_info));
     };
+
+    // The tsc is measured against whichever of these there is
+    let reference = match HPET.get() {
+        Some(_) => ClockSource::Hpet,
+        None => {
+            warn!("There is no hpet, falling back to the pit");
+            pit::start_clock();
+            ClockSource::Pit
+        }
+    };
+    let clock = match TscClock::calibrate(reference) {
+        Ok(tsc) => {
+            info!("Tsc runs at {}kHz", tsc.khz);
+            TSC.init_once(|| tsc);
+            ClockSource::Tsc
+        }
+        Err(reason) => {
+            info!("Not using the tsc because {reason}");
+            reference
+        }
+    };
+    info!("Timing with the {clock:?}");
+    CLOCK.init_once(|| clock);
+
+    // Good enough until sntp sets it properly
+    let century_reg = acpi_tables.find_table::<Fadt>().map_or(0, |f| f.century);
+    match rtc::read(century_reg) {
+        Some(time) => {
+            info!("Rtc says it's {time}");
+            set_realtime_us(time.to_unix() * 1_000_000);
+        }
+        None => warn!("Couldn't read the rtc"),
+    }
+}
+
+/// Uptime in nanoseconds, or 0 if there is no clock yet
+pub fn uptime_ns() -> u64 {
+    CLOCK.get().map_or(0, |c| c.uptime_ns())
 }
 
+/// Uptime in milliseconds
 pub fn uptime() -> u64 {
-    HPET.get().unwrap().get_uptime()
+    uptime_ns() / 1_000_000
+}
+
+/// Uptime in microseconds, or 0 if there is no clock yet
+pub fn uptime_us() -> u64 {
+    uptime_ns() / 1_000
+}
+
+/// What the realtime clock read at boot, in microseconds since the unix epoch, 0 while the
+/// clock hasn't been set
+static REALTIME_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Microseconds since the unix epoch, None until something has set the clock
+pub fn realtime_us() -> Option<u64> {
+    match REALTIME_AT_BOOT.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(boot + uptime_us()),
+    }
+}
+
+pub fn set_realtime_us(us: u64) {
+    REALTIME_AT_BOOT.store(us.saturating_sub(uptime_us()).max(1), Ordering::Relaxed);
 }
 
 #[derive(Debug)]
 pub struct SleptProcess {
+    /// Uptime in microseconds
     pub wakeup: u64,
     pub thread: Arc<Thread>,
 }
@@ -55,8 +222,13 @@ impl Ord for SleptProcess {
 pub static SLEPT_PROCESSES: Spinlock<BinaryHeap<Reverse<SleptProcess>>> =
     Spinlock::new(BinaryHeap::new());
 
+/// When the first sleeping thread wants to wake up, in microseconds
+pub fn next_wakeup_us() -> Option<u64> {
+    SLEPT_PROCESSES.try_lock()?.peek().map(|p| p.0.wakeup)
+}
+
 pub fn check_sleep() {
-    let uptime = HPET.get().unwrap().get_uptime();
+    let uptime = uptime_us();
 
     // if over the target, try waking up processes
     if let Some(mut procs) = SLEPT_PROCESSES.try_lock() {
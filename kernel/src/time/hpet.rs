@@ -16,6 +16,8 @@ use self::bitfields::CapabilitiesIDRegister;
 
 const FEMPTOSECOND: u64 = 10u64.pow(15);
 const MILLISECOND: u64 = 10u64.pow(3);
+const MICROSECOND: u64 = 10u64.pow(6);
+const NANOSECOND: u64 = 10u64.pow(9);
 
 pub struct HPET {
     pub info: HpetInfo,
@@ -59,6 +61,24 @@ impl HPET {
         }
     }
 
+    // Returns system uptime in microseconds
+    pub fn get_uptime_us(&self) -> u64 {
+        unsafe {
+            let ticks = read_volatile((self.info.base_address + 0xF0) as *const u64) as u128;
+            (ticks * self.capabilities.counter_tick_period() as u128
+                / (FEMPTOSECOND / MICROSECOND) as u128) as u64
+        }
+    }
+
+    // Returns system uptime in nanoseconds
+    pub fn get_uptime_ns(&self) -> u64 {
+        unsafe {
+            let ticks = read_volatile((self.info.base_address + 0xF0) as *const u64) as u128;
+            (ticks * self.capabilities.counter_tick_period() as u128
+                / (FEMPTOSECOND / NANOSECOND) as u128) as u64
+        }
+    }
+
     pub fn spin_ms(&self, ms: u64) {
         let end = self.get_uptime() + ms;
         while end > self.get_uptime() {
@@ -0,0 +1,74 @@
+//! The 8254 programmable interval timer, which every pc has. It is only used when nothing better
+//! works: channel 2 counts freely and is read as the clock when there is no hpet, and channel 0
+//! interrupts every ms for the scheduler when the local apic timer can't.
+
+use x86_64::instructions::port::Port;
+
+use crate::mutex::Spinlock;
+
+/// How fast every channel counts down
+pub const PIT_HZ: u64 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const CHANNEL2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Bit 0 is channel 2's gate and bit 1 connects it to the speaker
+const PORT_B: u16 = 0x61;
+
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const SELECT_CHANNEL2: u8 = 0b10 << 6;
+const ACCESS_LATCH: u8 = 0b00 << 4;
+const ACCESS_LOHI: u8 = 0b11 << 4;
+/// Counts down to 1 then reloads, pulsing the output
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+/// Channel 2's count when it was last read and how much it has counted since it was started
+static CLOCK: Spinlock<Option<(u16, u64)>> = Spinlock::new(None);
+
+unsafe fn set_reload(channel: u16, select: u8, reload: u16) {
+    Port::<u8>::new(COMMAND).write(select | ACCESS_LOHI | MODE_RATE_GENERATOR);
+    let mut data = Port::<u8>::new(channel);
+    data.write(reload as u8);
+    data.write((reload >> 8) as u8);
+}
+
+fn read_channel2() -> u16 {
+    unsafe {
+        Port::<u8>::new(COMMAND).write(SELECT_CHANNEL2 | ACCESS_LATCH);
+        let mut data = Port::<u8>::new(CHANNEL2);
+        let low = data.read();
+        let high = data.read();
+        u16::from_le_bytes([low, high])
+    }
+}
+
+/// Starts channel 2 counting through all 65536 values over and over. It only wraps without
+/// losing time if it is read at least every 55ms, which the scheduler tick does.
+pub fn start_clock() {
+    unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        let b = port_b.read();
+        port_b.write(b & !0b10 | 0b1);
+        // 0 is a reload of 65536
+        set_reload(CHANNEL2, SELECT_CHANNEL2, 0);
+    }
+    *CLOCK.lock() = Some((read_channel2(), 0));
+}
+
+/// Nanoseconds since [`start_clock`], 0 if it hasn't been
+pub fn uptime_ns() -> u64 {
+    let mut clock = CLOCK.lock();
+    let Some((last, counted)) = clock.as_mut() else {
+        return 0;
+    };
+    let now = read_channel2();
+    // It counts down
+    *counted += last.wrapping_sub(now) as u64;
+    *last = now;
+    (*counted as u128 * 1_000_000_000 / PIT_HZ as u128) as u64
+}
+
+/// Has channel 0 raise irq 0 every ms
+pub fn start_tick() {
+    unsafe { set_reload(CHANNEL0, SELECT_CHANNEL0, (PIT_HZ / 1000) as u16) }
+}
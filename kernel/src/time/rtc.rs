@@ -0,0 +1,126 @@
+//! The battery backed clock in the cmos. It only counts whole seconds and is taken to be in
+//! UTC, but it's right enough to start the realtime clock from until sntp sets it properly.
+
+use kernel_userspace::time::DateTime;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use super::uptime_us;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+/// Set while the clock is updating, the registers can be half way through changing
+const STATUS_A_UIP: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+/// An update takes about 2ms, so something is wrong if it takes longer than this
+const UIP_TIMEOUT_US: u64 = 10_000;
+
+fn read_register(reg: u8) -> u8 {
+    without_interrupts(|| unsafe {
+        Port::<u8>::new(CMOS_ADDRESS).write(reg);
+        Port::<u8>::new(CMOS_DATA).read()
+    })
+}
+
+/// The raw registers, in whatever format the clock keeps them in
+#[derive(PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl Registers {
+    fn read(century_reg: u8) -> Option<Self> {
+        let end = uptime_us() + UIP_TIMEOUT_US;
+        while read_register(REG_STATUS_A) & STATUS_A_UIP != 0 {
+            if uptime_us() > end {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+        Some(Self {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: if century_reg != 0 {
+                read_register(century_reg)
+            } else {
+                0
+            },
+        })
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xF)
+}
+
+/// Reads the date and time, None if the clock doesn't answer or has nonsense in it. The
+/// century register is the one the FADT gives, 0 if it doesn't have one, then the year is
+/// taken to be in the 2000s.
+pub fn read(century_reg: u8) -> Option<DateTime> {
+    // An update can start between reading the registers, so read until two reads agree
+    let mut regs = Registers::read(century_reg)?;
+    for _ in 0..5 {
+        let again = Registers::read(century_reg)?;
+        if again == regs {
+            break;
+        }
+        regs = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let convert = |v: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            v
+        } else {
+            from_bcd(v)
+        }
+    };
+
+    let pm = regs.hour & HOURS_PM != 0;
+    let mut hour = convert(regs.hour & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 is the first hour of both halves
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    let century = match regs.century {
+        0 => 20,
+        c => convert(c) as u32,
+    };
+
+    let time = DateTime {
+        year: century * 100 + convert(regs.year) as u32,
+        month: convert(regs.month),
+        day: convert(regs.day),
+        hour,
+        minute: convert(regs.minute),
+        second: convert(regs.second),
+    };
+    let valid = (1..=12).contains(&time.month)
+        && (1..=31).contains(&time.day)
+        && time.hour < 24
+        && time.minute < 60
+        && time.second < 60;
+    valid.then_some(time)
+}
@@ -0,0 +1,188 @@
+//! Guarded access to user memory.
+//!
+//! With SMEP the kernel can't execute user pages and with SMAP it faults on any access to them,
+//! unless RFLAGS.AC has been set with `stac`. Every pointer a syscall is given is wrapped in a
+//! [`UserPtr`] or [`UserBytes`] which checks that it points into user memory and only opens up
+//! access for the copy itself.
+//!
+//! Kernel processes run in ring 0 and pass pointers to their own stacks and the kernel heap, none
+//! of which are user pages, so the range check is skipped for them.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::boxed::Box;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+use crate::{
+    cpu::{features, CpuFeatures},
+    cpu_localstorage::CPULocalStorageRW,
+    paging::MemoryLoc,
+    scheduling::process::ProcessPrivilige,
+};
+
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// The repr of [`MemoryLoc`] is u64, which is the size of usize on x86_64
+const END_USER_MEM: usize = MemoryLoc::EndUserMem as u64 as usize;
+
+/// Enables SMEP and SMAP on the current core if supported, should be called on every core before
+/// it starts scheduling
+///
+/// # Safety
+///
+/// Must run in ring 0 after the cpu features are detected, and before the kernel is mapped
+/// anywhere with the user bit set.
+pub unsafe fn init_core() {
+    let smep = features().contains(CpuFeatures::SMEP);
+    let smap = features().contains(CpuFeatures::SMAP);
+
+    let mut cr4 = Cr4::read();
+    if smep {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if smap {
+        cr4 |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP.store(true, Ordering::Relaxed);
+    }
+    Cr4::write(cr4);
+}
+
+/// Allows the kernel to access user pages until [`clac`]
+#[inline]
+unsafe fn stac() {
+    if SMAP.load(Ordering::Relaxed) {
+        core::arch::asm!("stac", options(nomem, nostack));
+    }
+}
+
+/// Forbids the kernel from accessing user pages, user code can set AC before an `int 0x80` so
+/// syscalls call this on entry as well
+///
+/// # Safety
+///
+/// Must run in ring 0 and not inside of [`with_user_access`].
+#[inline]
+pub unsafe fn clac() {
+    if SMAP.load(Ordering::Relaxed) {
+        core::arch::asm!("clac", options(nomem, nostack));
+    }
+}
+
+/// Runs `f` with access to user pages allowed. `f` must not block as the flag isn't saved when
+/// switching tasks.
+///
+/// # Safety
+///
+/// Every user pointer `f` touches has to have been checked to be in the current process's memory.
+pub unsafe fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    stac();
+    let res = f();
+    clac();
+    res
+}
+
+/// Checks that `len` bytes at `addr` are in the current processes memory
+fn valid_range(addr: usize, len: usize) -> bool {
+    let thread = unsafe { CPULocalStorageRW::get_current_task() };
+    if thread.process().privilege == ProcessPrivilige::KERNEL {
+        return true;
+    }
+    addr != 0
+        && addr
+            .checked_add(len)
+            .is_some_and(|end| end <= END_USER_MEM + 1)
+}
+
+/// A pointer to a `T` given by the current process, `T` has to be plain data
+pub struct UserPtr<T> {
+    ptr: *mut T,
+}
+
+impl<T> UserPtr<T> {
+    pub fn new(addr: usize) -> Option<Self> {
+        if addr % core::mem::align_of::<T>() != 0 || !valid_range(addr, core::mem::size_of::<T>()) {
+            return None;
+        }
+        Some(Self {
+            ptr: addr as *mut T,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// Must be called from the process that gave it, which can change the memory at any time so
+    /// any bit pattern has to be a valid `T`.
+    pub unsafe fn read(&self) -> T {
+        with_user_access(|| self.ptr.read())
+    }
+
+    /// # Safety
+    ///
+    /// Must be called from the process that gave it.
+    pub unsafe fn write(&self, val: T) {
+        with_user_access(|| self.ptr.write(val))
+    }
+}
+
+/// A buffer of `T`s given by the current process, `T` has to be plain data
+pub struct UserSlice<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+pub type UserBytes = UserSlice<u8>;
+
+impl<T> UserSlice<T> {
+    pub fn new(addr: usize, len: usize) -> Option<Self> {
+        let size = len.checked_mul(core::mem::size_of::<T>())?;
+        if len != 0 && (addr % core::mem::align_of::<T>() != 0 || !valid_range(addr, size)) {
+            return None;
+        }
+        Some(Self {
+            ptr: addr as *mut T,
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies the buffer into the kernel
+    ///
+    /// # Safety
+    ///
+    /// Must be called from the process that gave it, which can change the memory at any time so
+    /// any bit pattern has to be a valid `T`.
+    pub unsafe fn read(&self) -> Box<[T]>
+    where
+        T: Clone,
+    {
+        if self.is_empty() {
+            return Box::new([]);
+        }
+        with_user_access(|| core::slice::from_raw_parts(self.ptr, self.len).into())
+    }
+
+    /// Copies `data` into the start of the buffer, which has to be big enough
+    ///
+    /// # Safety
+    ///
+    /// Must be called from the process that gave it.
+    pub unsafe fn write(&self, data: &[T])
+    where
+        T: Copy,
+    {
+        assert!(data.len() <= self.len);
+        if data.is_empty() {
+            return;
+        }
+        with_user_access(|| {
+            core::slice::from_raw_parts_mut(self.ptr, data.len()).copy_from_slice(data)
+        })
+    }
+}
@@ -0,0 +1,116 @@
+//! Finds cores that have stopped taking their tick, which means they are stuck with interrupts
+//! held. Usually that is spinning on a lock that will never be given back.
+//!
+//! Every second whichever core gets there first sends an NMI to every other core, NMIs get through
+//! held interrupts. A core that hasn't ticked between enough of them in a row dumps where it is
+//! stuck to serial. It writes to the port directly since the logger's locks could be the ones it
+//! is stuck on.
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+};
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::{
+    cpu_localstorage::CPULocalStorageRW,
+    ioapic::send_nmi_to,
+    lapic::current_apic_id,
+    scheduling::{stats::is_core_online, taskmanager::is_core_parked},
+    serial::{Serial, COM_1},
+    stack_trace,
+    time::uptime,
+};
+
+/// How often the other cores are sent an NMI, in ms
+const CHECK_MS: u64 = 1000;
+/// How many NMIs in a row a core has to not tick between to be reported
+const STALL_CHECKS: u32 = 5;
+
+struct CoreWatch {
+    ticks: AtomicU64,
+    /// The ticks when the last watchdog NMI came
+    seen: AtomicU64,
+    stalled: AtomicU32,
+    /// Set before sending the NMI, any NMI without it came from the hardware
+    pending: AtomicBool,
+}
+
+impl CoreWatch {
+    const fn new() -> Self {
+        Self {
+            ticks: AtomicU64::new(0),
+            seen: AtomicU64::new(0),
+            stalled: AtomicU32::new(0),
+            pending: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Indexed by core id, which is the core's apic id
+static CORES: [CoreWatch; 256] = [const { CoreWatch::new() }; 256];
+static NEXT_CHECK: AtomicU64 = AtomicU64::new(CHECK_MS);
+
+/// Records that the current core ticked and sends the NMIs if they are due, should be called from
+/// the tick
+pub fn tick() {
+    let id = CPULocalStorageRW::get_core_id();
+    CORES[id as usize].ticks.fetch_add(1, Ordering::Relaxed);
+
+    let time = uptime();
+    let next = NEXT_CHECK.load(Ordering::Relaxed);
+    if time < next {
+        return;
+    }
+    // only one core gets to send them
+    if NEXT_CHECK
+        .compare_exchange(next, time + CHECK_MS, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+
+    for core in 0..=u8::MAX {
+        // Parked cores have their timer masked
+        if core == id || !is_core_online(core) || is_core_parked(core) {
+            continue;
+        }
+        CORES[core as usize].pending.store(true, Ordering::Release);
+        send_nmi_to(core);
+    }
+}
+
+pub extern "x86-interrupt" fn nmi_handler(frame: InterruptStackFrame) {
+    // gs could still be the user's if it came in the middle of entering a syscall
+    let id = current_apic_id();
+    let core = &CORES[id as usize];
+
+    if !core.pending.swap(false, Ordering::Acquire) {
+        warn!(
+            "EXCEPTION: caught NON MASKABLE INTERRUPT, frame: {:?}",
+            frame
+        );
+        return;
+    }
+
+    let ticks = core.ticks.load(Ordering::Relaxed);
+    if core.seen.swap(ticks, Ordering::Relaxed) != ticks {
+        core.stalled.store(0, Ordering::Relaxed);
+        return;
+    }
+    // Only reported once each time it gets stuck
+    if core.stalled.fetch_add(1, Ordering::Relaxed) + 1 != STALL_CHECKS {
+        return;
+    }
+
+    let mut serial = Serial::new(COM_1);
+    let _ = write!(
+        serial,
+        "WATCHDOG: core {id} hasn't ticked in {}s, it is stuck at {:#x}\n{:?}\n",
+        STALL_CHECKS as u64 * CHECK_MS / 1000,
+        frame.instruction_pointer.as_u64(),
+        frame
+    );
+    stack_trace(&mut serial);
+}
@@ -0,0 +1,102 @@
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    object::KernelReference,
+    process::get_handle,
+    service::{deserialize, serialize, SimpleService},
+    syscall::sleep,
+};
+
+/// Everything played is at this rate, in frames a second
+pub const SAMPLE_RATE: u32 = 48000;
+/// A frame is a left and a right sample
+pub type Frame = [i16; 2];
+
+/// Sent to the `AUDIO` service. Every connection is a stream of its own, and what all of them
+/// have queued is mixed together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AudioServiceMessage<'a> {
+    /// Little endian frames to play after what's queued, answered with
+    /// [`AudioServiceResponse::Written`]. Only as many as fit in the queue are taken.
+    Write(&'a [u8]),
+    /// Answered with [`AudioServiceResponse::Queued`]
+    Queued,
+    /// How loud the stream is, 255 leaves the samples as they are
+    SetVolume(u8),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AudioServiceResponse {
+    /// How many frames were taken
+    Written(usize),
+    /// How many frames haven't been played yet
+    Queued(usize),
+    Ok,
+}
+
+/// A stream to play PCM through
+pub struct AudioStream {
+    service: SimpleService,
+    buffer: Vec<u8>,
+}
+
+impl AudioStream {
+    /// None if nothing is providing `AUDIO`, like when there's no sound card
+    pub fn open() -> Option<Self> {
+        let handle = get_handle("AUDIO")?;
+        Some(Self {
+            service: SimpleService::new(KernelReference::from_id(handle)),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn call(&mut self, msg: &AudioServiceMessage) -> Option<AudioServiceResponse> {
+        serialize(msg, &mut self.buffer);
+        self.service.call(&mut self.buffer, &mut Vec::new())?;
+        deserialize(&self.buffer).ok()
+    }
+
+    /// Queues the frames, waiting while the queue is full. Returns false if the stream went
+    /// away.
+    pub fn write(&mut self, frames: &[Frame]) -> bool {
+        let mut data = Vec::with_capacity(frames.len() * 4);
+        for frame in frames {
+            data.extend_from_slice(&frame[0].to_le_bytes());
+            data.extend_from_slice(&frame[1].to_le_bytes());
+        }
+
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let Some(AudioServiceResponse::Written(taken)) =
+                self.call(&AudioServiceMessage::Write(rest))
+            else {
+                return false;
+            };
+            rest = &rest[taken * 4..];
+            if !rest.is_empty() {
+                sleep(10);
+            }
+        }
+        true
+    }
+
+    /// How many frames haven't been played yet
+    pub fn queued(&mut self) -> usize {
+        match self.call(&AudioServiceMessage::Queued) {
+            Some(AudioServiceResponse::Queued(queued)) => queued,
+            _ => 0,
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: u8) {
+        self.call(&AudioServiceMessage::SetVolume(volume));
+    }
+
+    /// Waits for everything written to be played
+    pub fn drain(&mut self) {
+        while self.queued() > 0 {
+            sleep(10);
+        }
+    }
+}
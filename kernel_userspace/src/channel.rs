@@ -165,6 +165,36 @@ pub fn channel_read_rs(
     }
 }
 
+/// Like [`channel_read_rs`] but returns [`ChannelReadResult::Empty`] instead of waiting
+pub fn channel_try_read_rs(
+    handle: KernelReferenceID,
+    data: &mut Vec<u8>,
+    handles: &mut Vec<KernelReferenceID>,
+) -> ChannelReadResult {
+    let mut read = ChannelRead {
+        handle,
+        data: data.as_mut_ptr(),
+        data_len: data.capacity(),
+        handles: handles.as_mut_ptr().cast(),
+        handles_len: handles.capacity(),
+    };
+
+    let res = channel_read(&mut read);
+    unsafe {
+        match res {
+            ChannelReadResult::Ok => {
+                data.set_len(read.data_len);
+                handles.set_len(read.handles_len);
+            }
+            _ => {
+                data.set_len(0);
+                handles.set_len(0);
+            }
+        }
+    }
+    res
+}
+
 pub fn channel_read_resize(
     handle: KernelReferenceID,
     data: &mut Vec<u8>,
@@ -0,0 +1,37 @@
+use num_derive::{FromPrimitive, ToPrimitive};
+
+use crate::make_syscall;
+
+#[derive(FromPrimitive, ToPrimitive)]
+pub enum CpuSyscall {
+    Park,
+    Unpark,
+    IsParked,
+}
+
+/// Stops the core from scheduling threads and masks its timer (privileged)
+/// Returns if the core was parked, the BSP and offline cores can't be parked
+pub fn cpu_park(core_id: u8) -> bool {
+    let res: usize;
+    unsafe {
+        make_syscall!(crate::syscall::CPU, CpuSyscall::Park as usize, core_id as usize => res)
+    };
+    res != 0
+}
+
+/// Wakes up a parked core (privileged)
+pub fn cpu_unpark(core_id: u8) -> bool {
+    let res: usize;
+    unsafe {
+        make_syscall!(crate::syscall::CPU, CpuSyscall::Unpark as usize, core_id as usize => res)
+    };
+    res != 0
+}
+
+pub fn cpu_is_parked(core_id: u8) -> bool {
+    let res: usize;
+    unsafe {
+        make_syscall!(crate::syscall::CPU, CpuSyscall::IsParked as usize, core_id as usize => res)
+    };
+    res != 0
+}
@@ -1 +1,2 @@
 pub mod ata;
+pub mod gpt;
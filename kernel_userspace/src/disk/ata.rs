@@ -138,3 +138,17 @@ pub struct ATADiskIdentify {
     pub _reserved224: [u16; 31],
     pub integrity: u16,
 }
+
+impl ATADiskIdentify {
+    /// The number of addressable sectors, using the 48 bit count when the disk reports one
+    pub fn sector_count(&self) -> u64 {
+        let lba48 = self.lba_size48_1 as u64
+            | (self.lba_size48_2 as u64) << 16
+            | (self.lba_size48_3 as u64) << 32
+            | (self.lba_size48_4 as u64) << 48;
+        if lba48 != 0 {
+            return lba48;
+        }
+        self.lba_size_1 as u64 | (self.lba_size_2 as u64) << 16
+    }
+}
@@ -0,0 +1,109 @@
+//! GUID partition table layout, shared by the kernel which reads it and the tools that write it.
+
+pub const SIGNATURE: [u8; 8] = *b"EFI PART";
+pub const REVISION: u32 = 0x0001_0000;
+/// The MBR partition id covering the disk so that older tools leave it alone
+pub const PROTECTIVE_MBR_ID: u8 = 0xEE;
+/// What the tables are written with, readers take the counts from the header
+pub const ENTRY_COUNT: usize = 128;
+pub const ENTRY_SIZE: usize = 128;
+
+/// Stored at LBA 1 and again in the last sector of the disk
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GptHeader {
+    pub signature: [u8; 8],
+    pub revision: u32,
+    pub header_size: u32,
+    /// Of the header_size bytes with this field zeroed
+    pub header_crc: u32,
+    pub _reserved: u32,
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: [u8; 16],
+    pub entries_lba: u64,
+    pub entry_count: u32,
+    pub entry_size: u32,
+    pub entries_crc: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GptEntry {
+    /// All zero for unused entries
+    pub type_guid: [u8; 16],
+    pub unique_guid: [u8; 16],
+    pub first_lba: u64,
+    /// Inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+    /// UTF-16
+    pub name: [u16; 36],
+}
+
+/// GUIDs are stored with the first three fields little endian
+pub const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6],
+        d[7],
+    ]
+}
+
+pub const TYPE_UNUSED: [u8; 16] = [0; 16];
+pub const TYPE_EFI_SYSTEM: [u8; 16] = guid(
+    0xC12A7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+/// FAT, exFAT or NTFS
+pub const TYPE_BASIC_DATA: [u8; 16] = guid(
+    0xEBD0A0A2,
+    0xB9E5,
+    0x4433,
+    [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+);
+pub const TYPE_LINUX_FS: [u8; 16] = guid(
+    0x0FC63DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+pub const TYPE_LINUX_SWAP: [u8; 16] = guid(
+    0x0657FD6D,
+    0xA4AB,
+    0x43C4,
+    [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F],
+);
+
+/// The CRC-32 used for the header and entries
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+impl GptHeader {
+    /// The crc the header should have
+    pub fn compute_crc(&self) -> u32 {
+        let mut header = *self;
+        header.header_crc = 0;
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                &header as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        };
+        crc32(bytes)
+    }
+}
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    memory::MemoryHandle,
+    object::KernelReference,
+    process::get_handle,
+    service::{deserialize, serialize, SimpleService},
+};
+
+/// A resolution in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplayMode {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An area of the screen in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Sent to the `DISPLAY` service, which the driver of a display that can change its mode
+/// provides
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DisplayMessage {
+    /// Answered with [`DisplayResponse::Mode`]
+    GetMode,
+    /// Answered with [`DisplayResponse::Mode`] of the new mode, or
+    /// [`DisplayResponse::Unsupported`] leaving it as it was
+    SetMode(DisplayMode),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DisplayResponse {
+    Mode(DisplayMode),
+    Unsupported,
+}
+
+/// The layout of a framebuffer, 32 bit pixels of 0x00RRGGBB
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels from the start of one line to the next
+    pub stride: u32,
+}
+
+/// Sent by a display driver to the kernel's `CONSOLE` service, which draws the kernel's terminal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConsoleMessage {
+    /// Moves the console onto the framebuffer, whose memory is the handle sent with it.
+    /// Answered with [`ConsoleResponse::Ok`], or [`ConsoleResponse::Failed`] if it couldn't
+    /// be used.
+    SetFramebuffer(Framebuffer),
+    /// Answered with [`ConsoleResponse::Damage`]
+    TakeDamage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ConsoleResponse {
+    Ok,
+    Failed,
+    /// What has been drawn since it was last asked, for the driver to send to the display
+    Damage(Option<Rect>),
+}
+
+/// A connection to the `DISPLAY` service
+pub struct Display {
+    service: SimpleService,
+    buffer: Vec<u8>,
+}
+
+impl Display {
+    /// None if the display can't change its mode
+    pub fn open() -> Option<Self> {
+        let handle = get_handle("DISPLAY")?;
+        Some(Self {
+            service: SimpleService::new(KernelReference::from_id(handle)),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn call(&mut self, msg: &DisplayMessage) -> Option<DisplayMode> {
+        serialize(msg, &mut self.buffer);
+        self.service.call(&mut self.buffer, &mut Vec::new())?;
+        match deserialize(&self.buffer) {
+            Ok(DisplayResponse::Mode(mode)) => Some(mode),
+            _ => None,
+        }
+    }
+
+    pub fn mode(&mut self) -> Option<DisplayMode> {
+        self.call(&DisplayMessage::GetMode)
+    }
+
+    /// Returns the new mode, None if the display doesn't support it
+    pub fn set_mode(&mut self, mode: DisplayMode) -> Option<DisplayMode> {
+        self.call(&DisplayMessage::SetMode(mode))
+    }
+}
+
+/// A display driver's connection to the `CONSOLE` service
+pub struct Console {
+    service: SimpleService,
+    buffer: Vec<u8>,
+}
+
+impl Console {
+    pub fn open() -> Option<Self> {
+        let handle = get_handle("CONSOLE")?;
+        Some(Self {
+            service: SimpleService::new(KernelReference::from_id(handle)),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn call(&mut self, msg: &ConsoleMessage, memory: Option<&MemoryHandle>) -> ConsoleResponse {
+        serialize(msg, &mut self.buffer);
+        let mut handles: Vec<_> = memory.into_iter().map(|m| m.kref().id()).collect();
+        if self.service.call(&mut self.buffer, &mut handles).is_none() {
+            return ConsoleResponse::Failed;
+        }
+        deserialize(&self.buffer).unwrap_or(ConsoleResponse::Failed)
+    }
+
+    /// Has the console draw into the memory from now on, returns false if it can't
+    pub fn set_framebuffer(&mut self, framebuffer: Framebuffer, memory: &MemoryHandle) -> bool {
+        matches!(
+            self.call(&ConsoleMessage::SetFramebuffer(framebuffer), Some(memory)),
+            ConsoleResponse::Ok
+        )
+    }
+
+    /// What the console has drawn since the last call
+    pub fn take_damage(&mut self) -> Option<Rect> {
+        match self.call(&ConsoleMessage::TakeDamage, None) {
+            ConsoleResponse::Damage(rect) => rect,
+            _ => None,
+        }
+    }
+}
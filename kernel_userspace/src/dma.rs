@@ -0,0 +1,158 @@
+use alloc::vec::Vec;
+
+use crate::{
+    make_syscall,
+    memory::{MemoryHandle, MemorySyscall},
+    object::{KernelReference, KernelReferenceID},
+    syscall::unmmap_page,
+};
+
+/// Requirements a device puts on the memory it accesses
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DmaConstraints {
+    /// Every segment has to be addressable with 32 bits
+    pub below_4g: bool,
+    /// Alignment of the start of the buffer in bytes, has to be a power of two
+    pub align: usize,
+    /// Segments can't cross a multiple of this (a power of two of at least a page), 0 for no limit
+    pub boundary: usize,
+    /// Most segments the device can take, 1 for physically contiguous memory
+    pub max_segments: usize,
+}
+
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self {
+            below_4g: false,
+            align: 0x1000,
+            boundary: 0,
+            max_segments: usize::MAX,
+        }
+    }
+}
+
+/// A physically contiguous part of a [`DmaBuffer`]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DmaSegment {
+    pub phys: u64,
+    pub len: usize,
+}
+
+#[repr(C)]
+pub struct DmaAlloc {
+    pub size: usize,
+    pub constraints: DmaConstraints,
+    /// Filled in with the physical segments in order
+    pub segments: *mut DmaSegment,
+    /// Capacity of segments, set to the amount used
+    pub segments_len: usize,
+}
+
+/// Allocates pinned memory for a device, returns where it is mapped. The size is rounded up to
+/// pages and the memory is zeroed.
+pub fn dma_alloc(alloc: &mut DmaAlloc) -> Option<*mut u8> {
+    let res: usize;
+    unsafe {
+        make_syscall!(
+            crate::syscall::MEMORY,
+            MemorySyscall::DmaAlloc as usize,
+            alloc as *mut DmaAlloc as usize
+            => res
+        )
+    };
+    (res != 0).then_some(res as *mut u8)
+}
+
+/// Memory that a device can access directly, it is never paged out and stays at the same
+/// physical addresses until dropped
+pub struct DmaBuffer {
+    ptr: *mut u8,
+    size: usize,
+    segments: Vec<DmaSegment>,
+}
+
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    pub fn new(size: usize, constraints: DmaConstraints) -> Option<Self> {
+        let size = (size + 0xFFF) & !0xFFF;
+        let mut segments = vec![DmaSegment::default(); constraints.max_segments.min(size / 0x1000)];
+        let mut alloc = DmaAlloc {
+            size,
+            constraints,
+            segments: segments.as_mut_ptr(),
+            segments_len: segments.len(),
+        };
+        let ptr = dma_alloc(&mut alloc)?;
+        segments.truncate(alloc.segments_len);
+        Some(Self {
+            ptr,
+            size,
+            segments,
+        })
+    }
+
+    /// Physically contiguous memory below 4gb, the equivalent of [`crate::syscall::mmap_page32`]
+    pub fn new_32(size: usize) -> Option<Self> {
+        Self::new(
+            size,
+            DmaConstraints {
+                below_4g: true,
+                max_segments: 1,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn segments(&self) -> &[DmaSegment] {
+        &self.segments
+    }
+
+    /// A handle to the same memory for another process to map, so a device's buffers can be
+    /// shared without copying
+    pub fn share(&self) -> Option<MemoryHandle> {
+        let res: usize;
+        unsafe {
+            make_syscall!(
+                crate::syscall::MEMORY,
+                MemorySyscall::ShareDma as usize,
+                self.ptr as usize
+                => res
+            )
+        };
+        let id = KernelReferenceID::from_usize(res)?;
+        Some(MemoryHandle::from_kref(KernelReference::from_id(id)))
+    }
+
+    /// Physical address of the byte at offset
+    pub fn phys_addr(&self, mut offset: usize) -> u64 {
+        for segment in &self.segments {
+            if offset < segment.len {
+                return segment.phys + offset as u64;
+            }
+            offset -= segment.len;
+        }
+        panic!("offset past the end of the dma buffer")
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unmmap_page(self.ptr as usize, self.size);
+    }
+}
@@ -5,10 +5,9 @@ use thiserror::Error;
 use crate::{
     backoff_sleep,
     channel::{channel_read_rs, channel_write_rs},
-    message::MessageHandle,
     object::{KernelReference, KernelReferenceID},
     process::{get_handle, ProcessHandle},
-    service::deserialize,
+    service::{deserialize, serialize},
 };
 
 #[repr(C, packed)]
@@ -43,15 +42,42 @@ pub struct Elf64Phdr {
     pub p_align: u64,
 }
 
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct Elf64Dyn {
+    pub d_tag: i64,
+    pub d_val: u64,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct Elf64Rela {
+    pub r_offset: u64,
+    pub r_info: u64,
+    pub r_addend: i64,
+}
+
 // For the ELF Header https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
 pub const ELFCLASS64: u8 = 2; // 64 BIT
 pub const ELFDATA2LSB: u8 = 1; // LSB not MSB
 
 pub const ET_EXEC: u16 = 2; // Executable file
+pub const ET_DYN: u16 = 3; // Position independent executable
 pub const EM_X86_64: u16 = 62; // AMD x86-64 architecture
 
 // For the ELF Program Header https://refspecs.linuxbase.org/elf/gabi4+/ch5.pheader.html
 pub const PT_LOAD: u32 = 1; // A loadable segment
+pub const PT_DYNAMIC: u32 = 2; // Dynamic linking information
+pub const PT_TLS: u32 = 7; // Thread local storage template
+
+// For the dynamic section https://refspecs.linuxbase.org/elf/gabi4+/ch5.dynamic.html
+pub const DT_NULL: i64 = 0;
+pub const DT_RELA: i64 = 7;
+pub const DT_RELASZ: i64 = 8;
+pub const DT_RELAENT: i64 = 9;
+
+pub const R_X86_64_NONE: u32 = 0;
+pub const R_X86_64_RELATIVE: u32 = 8;
 
 pub const ELF_HEADER_SIG: [u8; 6] = [0x7F, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB];
 
@@ -60,7 +86,7 @@ pub fn validate_elf_header(elf_header: &Elf64Ehdr) -> Result<(), LoadElfError> {
     if elf_header.e_ident[0..6] != ELF_HEADER_SIG {
         return Err(LoadElfError::ElfHeaderSigInvalid(&elf_header.e_ident[0..6]));
     }
-    if elf_header.e_type != ET_EXEC {
+    if elf_header.e_type != ET_EXEC && elf_header.e_type != ET_DYN {
         return Err(LoadElfError::EType(elf_header.e_type));
     }
     if elf_header.e_machine != EM_X86_64 {
@@ -76,12 +102,16 @@ pub fn validate_elf_header(elf_header: &Elf64Ehdr) -> Result<(), LoadElfError> {
 pub enum LoadElfError<'a> {
     #[error("invalid elf header signature (expected {ELF_HEADER_SIG:?}, found {0:?})")]
     ElfHeaderSigInvalid(&'a [u8]),
-    #[error("expected ET_EXEC ({ET_EXEC}), found: {0}")]
+    #[error("expected ET_EXEC ({ET_EXEC}) or ET_DYN ({ET_DYN}), found: {0}")]
     EType(u16),
     #[error("expected EM_X86_64 ({EM_X86_64}), found: {0}")]
     EMachine(u16),
     #[error("unsupported elf version, expected 0, found: {0}")]
     ElfVersion(u32),
+    #[error("unsupported relocation type: {0}")]
+    Relocation(u32),
+    #[error("segment at {0:#x} is both writeable and executable")]
+    WritableExecutableSegment(u64),
     #[error("internal error")]
     InternalError,
 }
@@ -90,17 +120,34 @@ pub enum LoadElfError<'a> {
 pub struct SpawnElfProcess<'a> {
     pub args: &'a [u8],
     pub init_references_count: usize,
+    /// Randomize the address space layout, can be turned off to make debugging easier
+    pub aslr: bool,
 }
 
+/// Starts the elf in a new process, elf is either a [`MessageHandle`] with its contents or a
+/// [`MemoryHandle`] from [`crate::fs::mmap`] which lets read only segments share the page cache
+///
+/// [`MessageHandle`]: crate::message::MessageHandle
+/// [`MemoryHandle`]: crate::memory::MemoryHandle
 pub fn spawn_elf_process<'a>(
-    elf: MessageHandle,
+    elf: &KernelReference,
     args: &[u8],
     initial_ref: KernelReferenceID,
+    aslr: bool,
     buffer: &'a mut Vec<u8>,
 ) -> Result<ProcessHandle, LoadElfError<'a>> {
     let channel = KernelReference::from_id(backoff_sleep(|| get_handle("ELF_LOADER")));
 
-    channel_write_rs(channel.id(), args, &[elf.kref().id(), initial_ref]);
+    let spawn = SpawnElfProcess {
+        args,
+        init_references_count: 1,
+        aslr,
+    };
+    channel_write_rs(
+        channel.id(),
+        serialize(&spawn, buffer),
+        &[elf.id(), initial_ref],
+    );
 
     let mut handles = Vec::with_capacity(1);
 
@@ -0,0 +1,108 @@
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    display::{Framebuffer, Rect},
+    memory::MemoryHandle,
+    object::KernelReference,
+    process::get_handle,
+    service::{deserialize, serialize, SimpleService},
+    syscall::unmmap_page,
+};
+
+/// Sent to the `FB` service. A connection has at most one surface, which is on the screen in
+/// place of the console until the connection is closed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FbMessage {
+    /// Makes a surface the size of the screen, answered with [`FbResponse::Surface`] and the
+    /// memory to draw into. [`FbResponse::Failed`] if someone else has the screen.
+    CreateSurface,
+    /// Marks part of the surface as drawn to, answered with [`FbResponse::Ok`]
+    Damage(Rect),
+    /// Copies what was damaged since the last present to the screen, answered with
+    /// [`FbResponse::Ok`]
+    Present,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FbResponse {
+    Surface(Framebuffer),
+    Ok,
+    Failed,
+}
+
+/// A surface to draw onto the screen with, pixels are 0x00RRGGBB
+pub struct Surface {
+    service: SimpleService,
+    buffer: Vec<u8>,
+    pub framebuffer: Framebuffer,
+    pixels: *mut u32,
+}
+
+impl Surface {
+    /// Takes over the screen, None if there is no `FB` service or someone else has it
+    pub fn open() -> Option<Self> {
+        let handle = get_handle("FB")?;
+        let mut service = SimpleService::new(KernelReference::from_id(handle));
+
+        let mut buffer = Vec::new();
+        let mut handles = Vec::new();
+        serialize(&FbMessage::CreateSurface, &mut buffer);
+        service.call(&mut buffer, &mut handles)?;
+        let Ok(FbResponse::Surface(framebuffer)) = deserialize(&buffer) else {
+            return None;
+        };
+        let memory = MemoryHandle::from_kref(KernelReference::from_id(*handles.first()?));
+        let pixels = memory.map_writable()? as *mut u32;
+        Some(Self {
+            service,
+            buffer,
+            framebuffer,
+            pixels,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.framebuffer.stride as usize * self.framebuffer.height as usize
+    }
+
+    /// The pixels, a line being [`Framebuffer::stride`] long
+    pub fn pixels(&mut self) -> &mut [u32] {
+        unsafe { core::slice::from_raw_parts_mut(self.pixels, self.len()) }
+    }
+
+    fn call(&mut self, msg: &FbMessage) -> bool {
+        serialize(msg, &mut self.buffer);
+        self.service
+            .call(&mut self.buffer, &mut Vec::new())
+            .is_some()
+            && matches!(deserialize(&self.buffer), Ok(FbResponse::Ok))
+    }
+
+    /// Marks part of the surface to be copied at the next [`Surface::present`]
+    pub fn damage(&mut self, rect: Rect) -> bool {
+        self.call(&FbMessage::Damage(rect))
+    }
+
+    /// Marks everything as damaged and presents it
+    pub fn present_all(&mut self) -> bool {
+        let Framebuffer { width, height, .. } = self.framebuffer;
+        self.damage(Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }) && self.present()
+    }
+
+    /// Puts what was damaged onto the screen
+    pub fn present(&mut self) -> bool {
+        self.call(&FbMessage::Present)
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        unmmap_page(self.pixels as usize, (self.len() * 4 + 0xFFF) & !0xFFF);
+    }
+}
@@ -2,24 +2,66 @@ use serde::{Deserialize, Serialize};
 
 use alloc::{
     boxed::Box,
+    format,
     string::{String, ToString},
     vec::Vec,
 };
 
 use crate::{
+    memory::MemoryHandle,
     message::MessageHandle,
-    object::KernelReference,
+    object::{object_wait_port_rs, KernelReference, KernelReferenceID, ObjectSignal},
     service::{deserialize, serialize, SimpleService},
 };
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FSServiceMessage<'a> {
-    // DiskID | Path
-    RunStat(usize, &'a str),
+    // Path
+    RunStat(&'a str),
+    #[serde(borrow)]
+    ReadDir(ReadDirRequest<'a>),
     ReadRequest(ReadRequest),
     ReadFullFileRequest(ReadFullFileRequest),
 
     GetDisksRequest,
+
+    // Path
+    CreateFile(&'a str),
+    CreateFolder(&'a str),
+    Delete(&'a str),
+    // From | To
+    Rename(&'a str, &'a str),
+    // Path | Target
+    CreateSymlink(&'a str, &'a str),
+    // Path
+    ReadLink(&'a str),
+    /// The absolute path with `.`, `..` and symlinks resolved
+    Canonicalize(&'a str),
+    /// The data is sent as a message handle
+    WriteRequest(WriteRequest),
+    TruncateRequest(TruncateRequest),
+
+    // DiskID | Path
+    Mount(usize, &'a str),
+    // Path
+    Umount(&'a str),
+    GetMountsRequest,
+    // DiskID | Repair
+    Check(usize, bool),
+    /// Reads the partition table of the disk in `/dev` again, responds with its partitions
+    Rescan(&'a str),
+
+    /// Sends an [`FsEvent`] down the returned channel whenever the path or anything directly in
+    /// it changes
+    Watch(&'a str),
+
+    /// Sends the file as a [`MemoryHandle`] that shares its pages with the page cache
+    // DiskID | NodeID
+    Mmap(usize, usize),
+
+    /// Opens a channel that takes batches of [`IoRequest`]s and sends back an [`IoCompletion`]
+    /// for each of them
+    OpenIoQueue,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,46 +70,97 @@ pub enum FSServiceError {
     CouldNotFollowPath,
     FileNotFound,
     InvalidRequestForFileType,
+    ReadOnly,
+    InvalidRequest,
+    InvalidName,
+    AlreadyExists,
+    FolderNotEmpty,
+    DiskFull,
+    DiskError,
+    FileTooLarge,
+    NotMounted,
+    Busy,
+    Unsupported,
+    CrossDevice,
+    SymlinkLoop,
+    CorruptedFilesystem,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FSServiceMessageResp<'a> {
     ExpectedQuestion,
 
+    StatResponse(StatResponse),
     #[serde(borrow)]
-    StatResponse(StatResponse<'a>),
+    ReadDirResponse(ReadDirPage<'a>),
 
     ReadResponse(Option<usize>),
 
     GetDisksResponse(Box<[u64]>),
+
+    // Node id of the new file
+    CreateResponse(usize),
+    // File size after the write
+    WriteResponse(usize),
+    DeleteResponse,
+    MountResponse,
+    GetMountsResponse(Vec<MountInfo>),
+    PathResponse(String),
+    /// The watcher is sent as a handle
+    WatchResponse,
+    /// Everything that was found, with repair set it has been fixed
+    CheckResponse(Vec<FsProblem>),
+    /// The queue is sent as a handle
+    IoQueueResponse,
+    /// Size of the file, the memory is sent as a handle
+    MmapResponse(usize),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum StatResponse<'a> {
+pub enum StatResponse {
     File(StatResponseFile),
-    #[serde(borrow)]
-    Folder(StatResponseFolder<'a>),
+    Folder(StatResponseFolder),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StatResponseFile {
+    pub disk_id: usize,
     pub node_id: usize,
     pub file_size: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct StatResponseFolder<'a> {
+pub struct StatResponseFolder {
+    pub disk_id: usize,
     pub node_id: usize,
+    /// The number of children, list them with [`read_dir`]
+    pub entries: usize,
+}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadDirRequest<'a> {
+    pub path: &'a str,
+    /// Carries on from after this name, None starts from the beginning
+    pub after: Option<&'a str>,
+    pub max: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadDirPage<'a> {
+    /// Sorted by name
     #[serde(borrow)]
-    pub children: Vec<&'a str>,
+    pub entries: Vec<&'a str>,
+    /// If there are entries after the last one
+    pub more: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReadRequest {
     pub disk_id: usize,
     pub node_id: usize,
-    pub sector: u32,
+    pub offset: usize,
+    /// Less is read if the file ends first
+    pub len: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -76,33 +169,97 @@ pub struct ReadFullFileRequest {
     pub node_id: usize,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountInfo {
+    pub path: String,
+    pub disk_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WriteRequest {
+    pub disk_id: usize,
+    pub node_id: usize,
+    /// None appends to the end of the file
+    pub offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TruncateRequest {
+    pub disk_id: usize,
+    pub node_id: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IoRequest {
+    /// Picked by the client and handed back in the completion
+    pub key: u64,
+    pub disk_id: usize,
+    pub node_id: usize,
+    pub offset: usize,
+    pub op: IoOp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum IoOp {
+    /// Up to this many bytes, less at the end of the file
+    Read(usize),
+    /// The data is sent as a message handle, one for each write in the batch in order
+    Write,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IoCompletion {
+    pub key: u64,
+    /// The bytes read, or the file size after a write. Reads send the data as a handle.
+    pub result: Result<usize, FSServiceError>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsEventKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsEvent {
+    pub kind: FsEventKind,
+    /// The name of what changed inside the watched folder, empty if it is the watched path
+    pub name: String,
+}
+
+/// An inconsistency found when checking a file system, paths are from the root of the disk
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FsProblem {
+    /// The file's chain runs into a cluster that another file uses
+    CrossLinked { path: String, cluster: u32 },
+    /// The file's chain runs into a free or out of range cluster
+    BadChain { path: String, cluster: u32 },
+    /// The file says it is larger than the space allocated to it
+    SizeMismatch {
+        path: String,
+        size: usize,
+        allocated: usize,
+    },
+    /// Clusters that are allocated but no file uses
+    LostClusters(u32),
+    /// The free cluster count kept by the file system is wrong
+    FreeCount { recorded: u32, actual: u32 },
+}
+
+/// Joins the path onto the folder, the FS service takes care of `.`, `..` and symlinks. Use
+/// [`canonicalize`] for a clean path.
 pub fn add_path(folder: &str, file: &str) -> String {
     if file.starts_with('/') {
         return file.to_string();
     }
-
-    let mut path: Vec<&str> = folder.split('/').filter(|a| !a.is_empty()).collect();
-
-    for sect in file.split('/') {
-        if sect.is_empty() || sect == "." {
-            continue;
-        } else if sect == ".." {
-            path.pop();
-        } else {
-            path.push(sect)
-        }
-    }
-
-    String::from("/") + path.join("/").as_str()
+    format!("{}/{file}", folder.trim_end_matches('/'))
 }
 
-pub fn stat<'a>(
-    disk: usize,
-    file: &str,
-    buffer: &'a mut Vec<u8>,
-) -> Result<StatResponse<'a>, FSServiceError> {
+pub fn stat(file: &str, buffer: &mut Vec<u8>) -> Result<StatResponse, FSServiceError> {
     let mut fs = SimpleService::with_name("FS");
-    serialize(&FSServiceMessage::RunStat(disk, file), buffer);
+    serialize(&FSServiceMessage::RunStat(file), buffer);
     fs.call(buffer, &mut Vec::new()).unwrap();
 
     match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
@@ -111,10 +268,77 @@ pub fn stat<'a>(
     }
 }
 
-pub fn read_file_sector(
+/// Entries asked for at a time by [`ReadDir`]
+const READ_DIR_PAGE: usize = 64;
+
+/// The names in a folder a page at a time, so large folders don't need one huge message
+pub struct ReadDir {
+    path: String,
+    buffer: Vec<u8>,
+    page: Vec<String>,
+    /// The last name handed out, None when the folder has been read to the end
+    after: Option<String>,
+    started: bool,
+}
+
+/// Lists the folder, mount points show up as folders in it
+pub fn read_dir(path: &str) -> ReadDir {
+    ReadDir {
+        path: path.to_string(),
+        buffer: Vec::new(),
+        page: Vec::new(),
+        after: None,
+        started: false,
+    }
+}
+
+impl ReadDir {
+    fn next_page(&mut self) -> Result<(), FSServiceError> {
+        let mut fs = SimpleService::with_name("FS");
+        let req = ReadDirRequest {
+            path: &self.path,
+            after: self.after.as_deref(),
+            max: READ_DIR_PAGE,
+        };
+        serialize(&FSServiceMessage::ReadDir(req), &mut self.buffer);
+        fs.call(&mut self.buffer, &mut Vec::new()).unwrap();
+
+        match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(&self.buffer).unwrap()? {
+            FSServiceMessageResp::ReadDirResponse(page) => {
+                self.after = match page.more {
+                    true => page.entries.last().map(|e| e.to_string()),
+                    false => None,
+                };
+                // Handed out from the back
+                self.page = page.entries.iter().rev().map(|e| e.to_string()).collect();
+                Ok(())
+            }
+            _ => todo!(),
+        }
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<String, FSServiceError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.is_empty() && (!self.started || self.after.is_some()) {
+            self.started = true;
+            if let Err(e) = self.next_page() {
+                self.after = None;
+                return Some(Err(e));
+            }
+        }
+        self.page.pop().map(Ok)
+    }
+}
+
+/// Reads up to len bytes from the offset, None past the end of the file
+fn read_file_range(
     disk: usize,
     node: usize,
-    sector: u32,
+    offset: usize,
+    len: usize,
     buffer: &mut Vec<u8>,
 ) -> Result<Option<MessageHandle>, FSServiceError> {
     let mut fs = SimpleService::with_name("FS");
@@ -122,7 +346,8 @@ pub fn read_file_sector(
         &FSServiceMessage::ReadRequest(ReadRequest {
             disk_id: disk,
             node_id: node,
-            sector,
+            offset,
+            len,
         }),
         buffer,
     );
@@ -171,3 +396,466 @@ pub fn get_disks(buffer: &mut Vec<u8>) -> Result<Box<[u64]>, FSServiceError> {
         _ => todo!(),
     }
 }
+
+fn create(msg: FSServiceMessage, buffer: &mut Vec<u8>) -> Result<usize, FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&msg, buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::CreateResponse(node) => Ok(node),
+        _ => todo!(),
+    }
+}
+
+/// Creates an empty file, returns its node id
+pub fn create_file(path: &str, buffer: &mut Vec<u8>) -> Result<usize, FSServiceError> {
+    create(FSServiceMessage::CreateFile(path), buffer)
+}
+
+/// Creates an empty folder, returns its node id
+pub fn create_folder(path: &str, buffer: &mut Vec<u8>) -> Result<usize, FSServiceError> {
+    create(FSServiceMessage::CreateFolder(path), buffer)
+}
+
+/// Removes a file or an empty folder
+pub fn delete(path: &str, buffer: &mut Vec<u8>) -> Result<(), FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&FSServiceMessage::Delete(path), buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::DeleteResponse => Ok(()),
+        _ => todo!(),
+    }
+}
+
+fn path_call(msg: FSServiceMessage, buffer: &mut Vec<u8>) -> Result<String, FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&msg, buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::PathResponse(path) => Ok(path),
+        _ => todo!(),
+    }
+}
+
+/// The absolute path with `.`, `..` and symlinks resolved, it has to exist
+pub fn canonicalize(path: &str, buffer: &mut Vec<u8>) -> Result<String, FSServiceError> {
+    path_call(FSServiceMessage::Canonicalize(path), buffer)
+}
+
+/// Creates a symlink at path pointing at target, returns its node id
+pub fn symlink(target: &str, path: &str, buffer: &mut Vec<u8>) -> Result<usize, FSServiceError> {
+    create(FSServiceMessage::CreateSymlink(path, target), buffer)
+}
+
+/// Where the symlink at the path points
+pub fn read_link(path: &str, buffer: &mut Vec<u8>) -> Result<String, FSServiceError> {
+    path_call(FSServiceMessage::ReadLink(path), buffer)
+}
+
+/// Moves a file or folder, both paths have to be on the same disk
+pub fn rename(from: &str, to: &str, buffer: &mut Vec<u8>) -> Result<(), FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&FSServiceMessage::Rename(from, to), buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::DeleteResponse => Ok(()),
+        _ => todo!(),
+    }
+}
+
+/// Writes data at the offset growing the file if needed, None appends. Returns the new size of
+/// the file.
+pub fn write_file(
+    disk: usize,
+    node: usize,
+    offset: Option<usize>,
+    data: &[u8],
+    buffer: &mut Vec<u8>,
+) -> Result<usize, FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(
+        &FSServiceMessage::WriteRequest(WriteRequest {
+            disk_id: disk,
+            node_id: node,
+            offset,
+        }),
+        buffer,
+    );
+    let data = MessageHandle::create(data);
+    let mut handles = vec![data.kref().id()];
+    fs.call(buffer, &mut handles).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::WriteResponse(size) => Ok(size),
+        _ => todo!(),
+    }
+}
+
+/// Grows or shrinks the file, anything it grows by reads as zeros
+pub fn truncate_file(
+    disk: usize,
+    node: usize,
+    size: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<(), FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(
+        &FSServiceMessage::TruncateRequest(TruncateRequest {
+            disk_id: disk,
+            node_id: node,
+            size,
+        }),
+        buffer,
+    );
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::WriteResponse(_) => Ok(()),
+        _ => todo!(),
+    }
+}
+
+fn mount_call(msg: FSServiceMessage, buffer: &mut Vec<u8>) -> Result<(), FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&msg, buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::MountResponse => Ok(()),
+        _ => todo!(),
+    }
+}
+
+/// Mounts the disk at the path
+pub fn mount(disk: usize, path: &str, buffer: &mut Vec<u8>) -> Result<(), FSServiceError> {
+    mount_call(FSServiceMessage::Mount(disk, path), buffer)
+}
+
+/// Unmounts whatever is at the path, anything mounted inside of it has to be unmounted first
+pub fn umount(path: &str, buffer: &mut Vec<u8>) -> Result<(), FSServiceError> {
+    mount_call(FSServiceMessage::Umount(path), buffer)
+}
+
+pub fn get_mounts(buffer: &mut Vec<u8>) -> Result<Vec<MountInfo>, FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&FSServiceMessage::GetMountsRequest, buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::GetMountsResponse(m) => Ok(m),
+        _ => todo!(),
+    }
+}
+
+/// Checks the disk for inconsistencies and fixes them if repair is set. Nothing else should be
+/// using the disk at the same time.
+pub fn check(
+    disk: usize,
+    repair: bool,
+    buffer: &mut Vec<u8>,
+) -> Result<Vec<FsProblem>, FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&FSServiceMessage::Check(disk, repair), buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::CheckResponse(p) => Ok(p),
+        _ => todo!(),
+    }
+}
+
+/// Gets the file as memory backed by the page cache along with its size. The handle can be
+/// mapped here or given to another process, such as the ELF loader.
+pub fn mmap(
+    disk: usize,
+    node: usize,
+    buffer: &mut Vec<u8>,
+) -> Result<(MemoryHandle, usize), FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&FSServiceMessage::Mmap(disk, node), buffer);
+    let mut handles = Vec::with_capacity(1);
+    fs.call(buffer, &mut handles).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::MmapResponse(size) => Ok((
+            MemoryHandle::from_kref(KernelReference::from_id(handles[0])),
+            size,
+        )),
+        _ => todo!(),
+    }
+}
+
+/// Reloads the partitions of a disk after its partition table has changed, name is what it is
+/// called in `/dev`. Returns the partitions that were found.
+pub fn rescan(name: &str, buffer: &mut Vec<u8>) -> Result<Box<[u64]>, FSServiceError> {
+    let mut fs = SimpleService::with_name("FS");
+    serialize(&FSServiceMessage::Rescan(name), buffer);
+    fs.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+        FSServiceMessageResp::GetDisksResponse(d) => Ok(d),
+        _ => todo!(),
+    }
+}
+
+/// Queue of reads and writes that are processed while the client gets on with something else.
+/// Requests complete in the order they were submitted.
+pub struct IoQueue {
+    queue: SimpleService,
+    pending: Vec<IoRequest>,
+    data: Vec<MessageHandle>,
+}
+
+impl IoQueue {
+    pub fn open(buffer: &mut Vec<u8>) -> Result<Self, FSServiceError> {
+        let mut fs = SimpleService::with_name("FS");
+        serialize(&FSServiceMessage::OpenIoQueue, buffer);
+        let mut handles = Vec::with_capacity(1);
+        fs.call(buffer, &mut handles).unwrap();
+
+        match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+            FSServiceMessageResp::IoQueueResponse => Ok(Self {
+                queue: SimpleService::new(KernelReference::from_id(handles[0])),
+                pending: Vec::new(),
+                data: Vec::new(),
+            }),
+            _ => todo!(),
+        }
+    }
+
+    /// Queues a read of up to len bytes, it is sent on the next [`IoQueue::submit`]
+    pub fn read(&mut self, key: u64, disk: usize, node: usize, offset: usize, len: usize) {
+        self.pending.push(IoRequest {
+            key,
+            disk_id: disk,
+            node_id: node,
+            offset,
+            op: IoOp::Read(len),
+        });
+    }
+
+    /// Queues a write, it is sent on the next [`IoQueue::submit`]
+    pub fn write(&mut self, key: u64, disk: usize, node: usize, offset: usize, data: &[u8]) {
+        self.pending.push(IoRequest {
+            key,
+            disk_id: disk,
+            node_id: node,
+            offset,
+            op: IoOp::Write,
+        });
+        self.data.push(MessageHandle::create(data));
+    }
+
+    /// Sends everything queued since the last submit in one message
+    pub fn submit(&mut self, buffer: &mut Vec<u8>) {
+        if self.pending.is_empty() {
+            return;
+        }
+        serialize(&self.pending, buffer);
+        let handles: Vec<_> = self.data.iter().map(|d| d.kref().id()).collect();
+        self.queue.send(buffer, &handles);
+        self.pending.clear();
+        self.data.clear();
+    }
+
+    /// Has the port woken up with the key once a completion is ready. This only fires once, it
+    /// has to be called again after every wake up.
+    pub fn notify_port(&self, port: KernelReferenceID, key: u64) {
+        object_wait_port_rs(self.queue.handle().id(), port, ObjectSignal::READABLE, key);
+    }
+
+    /// Waits for the next completion, along with the data if it was a read
+    pub fn completion(
+        &mut self,
+        buffer: &mut Vec<u8>,
+    ) -> Option<(IoCompletion, Option<MessageHandle>)> {
+        let mut handles = Vec::with_capacity(1);
+        self.queue.recv(buffer, &mut handles)?;
+        let completion: IoCompletion = deserialize(buffer).ok()?;
+        let data = handles
+            .first()
+            .map(|h| MessageHandle::from_kref(KernelReference::from_id(*h)));
+        Some((completion, data))
+    }
+}
+
+/// Changes to a file or folder, a rename shows up as a delete and a create
+pub struct Watcher {
+    channel: SimpleService,
+}
+
+impl Watcher {
+    pub fn new(path: &str, buffer: &mut Vec<u8>) -> Result<Self, FSServiceError> {
+        let mut fs = SimpleService::with_name("FS");
+        serialize(&FSServiceMessage::Watch(path), buffer);
+        let mut handles = Vec::with_capacity(1);
+        fs.call(buffer, &mut handles).unwrap();
+
+        match deserialize::<Result<FSServiceMessageResp, FSServiceError>>(buffer).unwrap()? {
+            FSServiceMessageResp::WatchResponse => Ok(Self {
+                channel: SimpleService::new(KernelReference::from_id(handles[0])),
+            }),
+            _ => todo!(),
+        }
+    }
+
+    /// Waits for the next change
+    pub fn next(&mut self, buffer: &mut Vec<u8>) -> Option<FsEvent> {
+        self.channel.recv(buffer, &mut Vec::new())?;
+        deserialize(buffer).ok()
+    }
+
+    /// Has the port woken up with the key once there is an event. This only fires once, it has
+    /// to be called again after every wake up.
+    pub fn notify_port(&self, port: KernelReferenceID, key: u64) {
+        object_wait_port_rs(
+            self.channel.handle().id(),
+            port,
+            ObjectSignal::READABLE,
+            key,
+        );
+    }
+}
+
+/// The most a single read asks the FS service for
+const MAX_READ: usize = 0x10000;
+
+pub enum SeekFrom {
+    Start(usize),
+    End(isize),
+    Current(isize),
+}
+
+/// An open file with a position that reads and writes carry on from
+pub struct File {
+    path: String,
+    disk_id: usize,
+    node_id: usize,
+    offset: usize,
+    buffer: Vec<u8>,
+}
+
+impl File {
+    /// Opens an existing file at the start
+    pub fn open(path: &str) -> Result<Self, FSServiceError> {
+        let mut buffer = Vec::new();
+        match stat(path, &mut buffer)? {
+            StatResponse::File(f) => Ok(Self {
+                path: path.to_string(),
+                disk_id: f.disk_id,
+                node_id: f.node_id,
+                offset: 0,
+                buffer,
+            }),
+            StatResponse::Folder(_) => Err(FSServiceError::InvalidRequestForFileType),
+        }
+    }
+
+    /// Opens the file emptying it, it is created if it doesn't exist
+    pub fn create(path: &str) -> Result<Self, FSServiceError> {
+        let mut file = Self::open_or_create(path)?;
+        file.set_len(0)?;
+        Ok(file)
+    }
+
+    /// Opens the file at its end, it is created if it doesn't exist
+    pub fn append(path: &str) -> Result<Self, FSServiceError> {
+        let mut file = Self::open_or_create(path)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(file)
+    }
+
+    fn open_or_create(path: &str) -> Result<Self, FSServiceError> {
+        match Self::open(path) {
+            Err(FSServiceError::CouldNotFollowPath | FSServiceError::FileNotFound) => {
+                create_file(path, &mut Vec::new())?;
+                Self::open(path)
+            }
+            res => res,
+        }
+    }
+
+    /// Reads from the current position, returns 0 at the end of the file
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FSServiceError> {
+        let len = buf.len().min(MAX_READ);
+        if len == 0 {
+            return Ok(0);
+        }
+        let Some(data) = read_file_range(
+            self.disk_id,
+            self.node_id,
+            self.offset,
+            len,
+            &mut self.buffer,
+        )?
+        else {
+            return Ok(0);
+        };
+        let read = data.get_size().min(len);
+        data.read(&mut buf[..read]);
+        self.offset += read;
+        Ok(read)
+    }
+
+    /// Reads from the current position to the end of the file, returns how much was read
+    pub fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize, FSServiceError> {
+        let start = buf.len();
+        loop {
+            let len = buf.len();
+            buf.resize(len + MAX_READ, 0);
+            let read = self.read(&mut buf[len..])?;
+            buf.truncate(len + read);
+            if read == 0 {
+                return Ok(buf.len() - start);
+            }
+        }
+    }
+
+    /// Writes at the current position growing the file if needed
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FSServiceError> {
+        write_file(
+            self.disk_id,
+            self.node_id,
+            Some(self.offset),
+            data,
+            &mut self.buffer,
+        )?;
+        self.offset += data.len();
+        Ok(data.len())
+    }
+
+    /// Moves the position, returns where it ended up. It can go past the end of the file, a
+    /// write there fills the gap with zeros.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, FSServiceError> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (0, offset as isize),
+            SeekFrom::End(delta) => (self.stat()?.file_size, delta),
+            SeekFrom::Current(delta) => (self.offset, delta),
+        };
+        self.offset = base
+            .checked_add_signed(delta)
+            .ok_or(FSServiceError::InvalidRequest)?;
+        Ok(self.offset)
+    }
+
+    pub fn position(&self) -> usize {
+        self.offset
+    }
+
+    pub fn stat(&mut self) -> Result<StatResponseFile, FSServiceError> {
+        match stat(&self.path, &mut self.buffer)? {
+            StatResponse::File(f) => Ok(f),
+            StatResponse::Folder(_) => Err(FSServiceError::InvalidRequestForFileType),
+        }
+    }
+
+    /// Grows or shrinks the file, the position stays where it is
+    pub fn set_len(&mut self, size: usize) -> Result<(), FSServiceError> {
+        truncate_file(self.disk_id, self.node_id, size, &mut self.buffer)
+    }
+}
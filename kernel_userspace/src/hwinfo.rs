@@ -0,0 +1,110 @@
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    object::KernelReference,
+    process::get_handle,
+    service::{deserialize, serialize, SimpleService},
+};
+
+/// Sent to the kernel's `HWINFO` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HwInfoMessage {
+    /// Answered with [`HwInfoResponse::Info`], or [`HwInfoResponse::Unavailable`] if the
+    /// firmware has no SMBIOS tables
+    Get,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HwInfoResponse {
+    Info(HwInfo),
+    Unavailable,
+}
+
+/// What the firmware's SMBIOS tables say about the machine. Strings the firmware left out are
+/// empty.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HwInfo {
+    /// The SMBIOS version as major, minor
+    pub version: (u8, u8),
+    pub bios: BiosInfo,
+    pub system: SystemInfo,
+    pub processors: Vec<ProcessorInfo>,
+    pub memory: Vec<MemoryDevice>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BiosInfo {
+    pub vendor: String,
+    pub version: String,
+    pub release_date: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SystemInfo {
+    pub vendor: String,
+    pub product: String,
+    pub version: String,
+}
+
+/// A cpu socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessorInfo {
+    pub socket: String,
+    pub manufacturer: String,
+    pub version: String,
+    pub populated: bool,
+    /// 0 if unknown
+    pub max_speed_mhz: u16,
+    pub current_speed_mhz: u16,
+    pub cores: u16,
+    pub threads: u16,
+}
+
+/// A memory slot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryDevice {
+    pub locator: String,
+    pub bank: String,
+    /// 0 if the slot is empty
+    pub size_mb: u64,
+    /// The SMBIOS memory type, see [`MemoryDevice::type_name`]
+    pub memory_type: u8,
+    /// In MT/s, 0 if unknown
+    pub speed: u16,
+    pub manufacturer: String,
+    pub part_number: String,
+}
+
+impl MemoryDevice {
+    pub fn type_name(&self) -> &'static str {
+        match self.memory_type {
+            0x07 => "RAM",
+            0x0F => "SDRAM",
+            0x12 => "DDR",
+            0x13 => "DDR2",
+            0x18 => "DDR3",
+            0x1A => "DDR4",
+            0x1B => "LPDDR",
+            0x1C => "LPDDR2",
+            0x1D => "LPDDR3",
+            0x1E => "LPDDR4",
+            0x22 => "DDR5",
+            0x23 => "LPDDR5",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// None if there is no `HWINFO` service or it has nothing to say
+pub fn get_hwinfo() -> Option<HwInfo> {
+    let handle = get_handle("HWINFO")?;
+    let mut service = SimpleService::new(KernelReference::from_id(handle));
+    let mut buffer = Vec::new();
+    serialize(&HwInfoMessage::Get, &mut buffer);
+    service.call(&mut buffer, &mut Vec::new())?;
+    match deserialize(&buffer) {
+        Ok(HwInfoResponse::Info(info)) => Some(info),
+        _ => None,
+    }
+}
@@ -1,9 +1,121 @@
+use alloc::vec::Vec;
 use serde::{Deserialize, Serialize};
 
-use input::{keyboard::KeyboardEvent, mouse::MousePacket};
+use input::{
+    keyboard::{layout::KeyboardLayout, KeyboardEvent},
+    mouse::{AbsoluteMousePacket, MousePacket},
+};
+
+use crate::{
+    object::KernelReference,
+    process::get_handle,
+    service::{serialize, SimpleService},
+};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum InputServiceMessage {
     KeyboardEvent(KeyboardEvent),
     MouseEvent(MousePacket),
+    /// Sent by `INPUT:MOUSE` instead of [`InputServiceMessage::MouseEvent`] when the pointer
+    /// knows where it is, like a virtual machine's following the host cursor
+    MouseAbsolute(AbsoluteMousePacket),
+    /// Sent by `INPUT:KB` when connecting and whenever the layout is changed, the keyboard
+    /// events after it should be read with the layout
+    KeyboardLayout(KeyboardLayout),
+}
+
+/// A connection to an input service that reconnects when the driver behind it dies, waiting
+/// for the driver to be restarted
+pub struct InputClient {
+    name: &'static str,
+    /// Whether to send something on connecting, which `SERIAL:IN` waits for before sending
+    greet: bool,
+    service: SimpleService,
+}
+
+impl InputClient {
+    pub fn new(name: &'static str) -> Self {
+        Self::connect(name, false)
+    }
+
+    /// Sends an empty message each time it connects
+    pub fn greeting(name: &'static str) -> Self {
+        Self::connect(name, true)
+    }
+
+    fn connect(name: &'static str, greet: bool) -> Self {
+        let mut service = SimpleService::with_name(name);
+        if greet {
+            service.send(&[], &[]);
+        }
+        Self {
+            name,
+            greet,
+            service,
+        }
+    }
+
+    fn reconnect(&mut self) {
+        *self = Self::connect(self.name, self.greet);
+    }
+
+    /// Reads the next message as bytes
+    pub fn recv(&mut self, data: &mut Vec<u8>) {
+        while self.service.recv(data, &mut Vec::new()).is_none() {
+            self.reconnect();
+        }
+    }
+
+    pub fn recv_val(&mut self) -> InputServiceMessage {
+        loop {
+            if let Some(ev) = self.service.recv_val(&mut Vec::new()) {
+                return ev;
+            }
+            self.reconnect();
+        }
+    }
+}
+
+/// Sent on a connection to `INPUT:KB`, which doesn't answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeyboardControl {
+    /// Changes the layout for everyone listening, they are all sent
+    /// [`InputServiceMessage::KeyboardLayout`]
+    SetLayout(KeyboardLayout),
+}
+
+fn open_keyboard() -> Option<SimpleService> {
+    let handle = get_handle("INPUT:KB")?;
+    Some(SimpleService::new(KernelReference::from_id(handle)))
+}
+
+/// Waits for the next layout on the connection, skipping key presses before it
+fn recv_layout(keyboard: &mut SimpleService) -> Option<KeyboardLayout> {
+    loop {
+        if let InputServiceMessage::KeyboardLayout(layout) = keyboard.recv_val(&mut Vec::new())? {
+            return Some(layout);
+        }
+    }
+}
+
+/// The layout the keyboard is using, None if there is no keyboard
+pub fn keyboard_layout() -> Option<KeyboardLayout> {
+    recv_layout(&mut open_keyboard()?)
+}
+
+/// Changes the layout of the keyboard, returning if it was changed
+pub fn set_keyboard_layout(layout: KeyboardLayout) -> bool {
+    let Some(mut keyboard) = open_keyboard() else {
+        return false;
+    };
+    // The first is the layout before it was changed
+    if recv_layout(&mut keyboard).is_none() {
+        return false;
+    }
+    let mut buf = Vec::new();
+    keyboard.send(
+        serialize(&KeyboardControl::SetLayout(layout), &mut buf),
+        &[],
+    );
+    recv_layout(&mut keyboard) == Some(layout)
 }
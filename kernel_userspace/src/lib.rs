@@ -7,21 +7,33 @@ use syscall::sleep;
 #[macro_use]
 extern crate alloc;
 
+pub mod audio;
 pub mod channel;
+pub mod cpu;
 pub mod disk;
+pub mod display;
+pub mod dma;
 pub mod elf;
+pub mod fb;
 pub mod fs;
+pub mod hwinfo;
 pub mod ids;
 pub mod input;
 pub mod interrupt;
+pub mod memory;
 pub mod message;
 pub mod net;
 pub mod object;
 pub mod pci;
 pub mod port;
+pub mod power;
 pub mod process;
 pub mod service;
+pub mod stats;
 pub mod syscall;
+pub mod system;
+pub mod time;
+pub mod virtio;
 
 pub use num_derive;
 pub use num_traits;
@@ -43,3 +55,5 @@ pub const INT_KB: usize = 0;
 pub const INT_MOUSE: usize = 1;
 pub const INT_PCI: usize = 2;
 pub const INT_COM1: usize = 3;
+/// Fires when the power or sleep button is pressed, see [`system::system_take_events`]
+pub const INT_ACPI: usize = 4;
@@ -0,0 +1,128 @@
+use num_derive::{FromPrimitive, ToPrimitive};
+use num_traits::FromPrimitive;
+
+use crate::{
+    make_syscall,
+    object::{KernelReference, KernelReferenceID},
+};
+
+#[derive(FromPrimitive, ToPrimitive)]
+pub enum MemorySyscall {
+    GetPressure,
+    SetPressurePort,
+    MapFile,
+    MakeExecutable,
+    DmaAlloc,
+    MapObject,
+    ShareDma,
+}
+
+/// How short the system is on free memory
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, FromPrimitive, ToPrimitive)]
+pub enum MemoryPressure {
+    Ok,
+    /// Caches should be trimmed
+    Low,
+    /// Caches should be dropped, allocations are close to failing
+    Critical,
+}
+
+pub fn memory_pressure() -> MemoryPressure {
+    let res: usize;
+    unsafe { make_syscall!(crate::syscall::MEMORY, MemorySyscall::GetPressure as usize => res) };
+    MemoryPressure::from_usize(res).unwrap()
+}
+
+/// Sends a [`PortNotificationType::MemoryPressure`] to the port every time the pressure changes.
+/// If the pressure isn't ok one is sent straight away.
+///
+/// [`PortNotificationType::MemoryPressure`]: crate::port::PortNotificationType::MemoryPressure
+pub fn memory_pressure_set_port(port: KernelReferenceID, key: u64) {
+    unsafe {
+        make_syscall!(
+            crate::syscall::MEMORY,
+            MemorySyscall::SetPressurePort as usize,
+            port.0.get(),
+            key as usize
+        )
+    };
+}
+
+/// Maps the whole file into memory through the page cache. Writes to a writable mapping are
+/// written back to the file, which fails if the file system is read only.
+pub fn mmap_file(disk: u64, node: usize, writable: bool) -> Option<*mut u8> {
+    let res: usize;
+    unsafe {
+        make_syscall!(
+            crate::syscall::MEMORY,
+            MemorySyscall::MapFile as usize,
+            disk as usize,
+            node,
+            writable as usize
+            => res
+        )
+    };
+    (res != 0).then_some(res as *mut u8)
+}
+
+/// Turns a mapping from [`crate::syscall::mmap_page`] into executable memory (for JITs), it stops
+/// being writeable as mappings can't be both. The range has to cover the whole mapping.
+pub fn make_executable(ptr: *const u8, length: usize) -> bool {
+    let res: usize;
+    unsafe {
+        make_syscall!(
+            crate::syscall::MEMORY,
+            MemorySyscall::MakeExecutable as usize,
+            ptr as usize,
+            length
+            => res
+        )
+    };
+    res != 0
+}
+
+/// Pages held by the kernel that any process with the handle can map, such as a file from
+/// [`crate::fs::mmap`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct MemoryHandle(KernelReference);
+
+impl MemoryHandle {
+    pub const fn from_kref(kref: KernelReference) -> Self {
+        Self(kref)
+    }
+
+    pub const fn kref(&self) -> &KernelReference {
+        &self.0
+    }
+
+    pub fn into_kref(self) -> KernelReference {
+        self.0
+    }
+
+    /// Maps the whole object read only, it stays mapped until [`crate::syscall::unmmap_page`]
+    pub fn map(&self) -> Option<*const u8> {
+        self.map_inner(false).map(|p| p.cast_const())
+    }
+
+    /// Maps the whole object so it can be written to, which only dma memory from
+    /// [`crate::dma::DmaBuffer::share`] and device memory from [`crate::pci::PCIDevice::get_bar`]
+    /// can be
+    pub fn map_writable(&self) -> Option<*mut u8> {
+        self.map_inner(true)
+    }
+
+    fn map_inner(&self, writable: bool) -> Option<*mut u8> {
+        let res: usize;
+        unsafe {
+            make_syscall!(
+                crate::syscall::MEMORY,
+                MemorySyscall::MapObject as usize,
+                self.0.id().0.get(),
+                writable as usize
+                => res
+            )
+        };
+        (res != 0).then_some(res as *mut u8)
+    }
+}
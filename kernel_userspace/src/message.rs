@@ -46,6 +46,10 @@ impl MessageHandle {
         &self.0
     }
 
+    pub fn into_kref(self) -> KernelReference {
+        self.0
+    }
+
     unsafe fn make_syscall<T>(action: SyscallMessageAction, arg: &mut T) {
         let action = ToPrimitive::to_usize(&action).unwrap();
         make_syscall!(MESSAGE, action, arg as *mut T);
@@ -1,18 +1,104 @@
-use core::fmt::Display;
+pub mod firewall;
+pub mod mdns;
+pub mod ring;
+pub mod sntp;
+pub mod socket;
 
+use core::{fmt::Display, str::FromStr};
+
+use alloc::{string::String, vec, vec::Vec};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+pub use socket::{
+    sockets, Socket, SocketEvent, SocketInfo, SocketKind, SocketRequest, SocketState, SocketStats,
+    TcpListener, TcpStream, UdpSocket,
+};
+
+use crate::{
+    channel::{
+        channel_create_rs, channel_read_resize, channel_try_read_rs, channel_write_rs,
+        ChannelReadResult,
+    },
+    object::{KernelReference, KernelReferenceID},
+    service::{deserialize, serialize, SimpleService},
+    syscall::sleep,
+};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PhysicalNet<'a> {
     MacAddrGet,
     SendPacket(&'a [u8]),
     ListenToPackets,
+    /// Answered with the card's [`NicCapabilities`]
+    CapabilitiesGet,
+    /// Sends the frame made of the fragments one after the other, with the card doing what
+    /// the [`TxOffload`] asks. Only sent to cards that advertise a capability.
+    SendFragments(Vec<&'a [u8]>, TxOffload),
+    /// Answered with whether the card shares [`ring::PacketRing`]s, and if it does the receive
+    /// ring's memory then the transmit ring's. Frames then go through the rings instead of
+    /// [`Self::SendPacket`] and the listen channel, which only says there is something in
+    /// the receive ring. Frames in the transmit ring are finished, so a card sharing rings
+    /// shouldn't advertise any [`NicCapabilities`].
+    RingsGet,
+    /// Tells the card the stack has pushed frames to send or made room to receive into,
+    /// answered once it has looked at the rings
+    RingKick,
+}
+
+/// What a card can do for the stack when sending, so it can skip the work
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NicCapabilities {
+    /// Fills in the ipv4 header checksum
+    pub ipv4_checksum: bool,
+    /// Fills in tcp and udp checksums, over both ipv4 and ipv6
+    pub l4_checksum: bool,
+    /// Takes frames as fragments without them being gathered first
+    pub scatter_gather: bool,
+    /// The most tcp payload the card takes at once and splits into segments itself, 0 if it
+    /// can't
+    pub tso_max: u32,
+}
+
+impl NicCapabilities {
+    pub fn any(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+/// The work left for the card on a frame from [`PhysicalNet::SendFragments`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxOffload {
+    /// Fill in the checksum of the ipv4 header, which follows the ethernet header
+    pub ipv4_checksum: bool,
+    /// Where the tcp or udp header starts in the frame
+    pub l4_start: u16,
+    /// Checksum everything from `l4_start` to the end of the frame into the field this far
+    /// into the tcp or udp header, which holds the sum of the pseudo header to start from
+    pub l4_checksum: Option<u16>,
+    /// Split the tcp payload into segments of this size, fixing up the lengths, sequence
+    /// numbers and checksums of each. The pseudo header sum leaves out the length.
+    pub tso_mss: Option<u16>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Networking {
+    /// Answered with an [`ArpResponse`], asking for the mac if it isn't known
     ArpRequest(IPAddr),
+    /// Answered with a `Vec<ArpEntry>` of the neighbour cache
+    ArpTable,
+    /// Opens a socket, answered with a `Result<(), NetError>` and on success the channel for
+    /// the socket which takes [`SocketRequest`]s and gives [`SocketEvent`]s
+    Socket(SocketKind),
+    /// Opens a socket for pinging, answered with a `Result<u16, NetError>` of the echo
+    /// identifier and on success the channel for the socket which takes [`EchoRequest`]s and
+    /// gives [`EchoReply`]s
+    IcmpOpen,
+    /// Answered with a `Vec<SocketInfo>` of every open socket
+    SocketTable,
+    /// Starts capturing frames, answered with a `Result<(), NetError>` and on success the
+    /// channel that gives a [`CapturedFrame`] for each frame sent or received
+    Capture(CaptureFilter),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -23,18 +109,367 @@ pub enum NetworkingResp {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ArpResponse {
     Mac(u64),
-    Pending(Result<(), NotSameSubnetError>),
+    /// A request has gone out, ask again in a bit
+    Pending,
+    /// The host didn't answer, it won't be asked again until the entry expires
+    Unreachable,
+    NotSameSubnet(NotSameSubnetError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArpEntry {
+    pub ip: IPAddr,
+    /// The name of the interface the host is on
+    pub interface: String,
+    pub state: ArpEntryState,
+    /// How long until the entry runs out in ms, or the next request goes out while incomplete
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ArpEntryState {
+    Incomplete,
+    Reachable(u64),
+    Failed,
+}
+
+/// How a network interface is addressed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceConfig {
+    /// [`IPAddr::UNSPECIFIED`] until we have been given one
+    pub addr: IPAddr,
+    pub netmask: u32,
+    pub gateway: Option<IPAddr>,
+    pub dns: Vec<IPAddr>,
+}
+
+impl InterfaceConfig {
+    pub const UNCONFIGURED: Self = Self {
+        addr: IPAddr::UNSPECIFIED,
+        netmask: 0,
+        gateway: None,
+        dns: Vec::new(),
+    };
+}
+
+/// How a network interface is addressed over ipv6, which the stack works out for itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ipv6Config {
+    /// Made from our mac, None until we know nobody else is using it
+    pub link_local: Option<IPAddr>,
+    /// Made from the prefixes routers advertise, each one is a /64 on our link
+    pub global: Vec<IPAddr>,
+    pub router: Option<IPAddr>,
+}
+
+impl Ipv6Config {
+    pub const UNCONFIGURED: Self = Self {
+        link_local: None,
+        global: Vec::new(),
+        router: None,
+    };
+}
+
+/// A network card the stack is using
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterfaceInfo {
+    pub id: u32,
+    /// `eth0`, `eth1` and so on in the order the cards registered
+    pub name: String,
+    /// What the driver called itself
+    pub driver: String,
+    pub mac: u64,
+    pub caps: NicCapabilities,
+    /// The most a frame can carry, ipv4 packets bigger than this are sent in fragments
+    pub mtu: u32,
+    pub config: InterfaceConfig,
+    pub v6: Ipv6Config,
+}
+
+/// An entry in the routing table. Packets go out of the route with the longest prefix that
+/// covers their destination.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Route {
+    pub dst: IPAddr,
+    pub prefix_len: u8,
+    /// None when the destination is on the interface's link
+    pub gateway: Option<IPAddr>,
+    pub interface: u32,
+    pub origin: RouteOrigin,
+}
+
+/// Where a route came from. All but static routes are kept up to date by the stack as the
+/// interfaces' addressing changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RouteOrigin {
+    /// The subnet of one of the interface's addresses
+    Connected,
+    /// The default route through the interface's ipv4 gateway
+    Gateway,
+    /// The default route through the ipv6 router that advertised itself
+    Router,
+    /// Added by hand
+    Static,
+}
+
+/// Messages to the `INTERFACE` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum InterfaceMessage {
+    /// Answered with a `Vec<InterfaceInfo>` of every interface
+    List,
+    /// Sets the ipv4 addressing of an interface, answered with a `Result<(), NetError>` once
+    /// it is in use
+    Set(u32, InterfaceConfig),
+    /// Sent by a network card driver along with a channel it answers [`PhysicalNet`] requests
+    /// on, giving the name of the driver. Answered with the id of the new interface, which is
+    /// set up once the driver starts answering.
+    Register(String),
+    /// Answered with a `Vec<Route>` of the routing table
+    Routes,
+    /// Answered with a `Result<(), NetError>`
+    AddRoute(Route),
+    /// Removes the route to the same destination through the same interface, answered with a
+    /// `Result<(), NetError>`
+    DeleteRoute(Route),
+}
+
+fn interface_call<T: for<'a> Deserialize<'a>>(
+    msg: &InterfaceMessage,
+    handles: &mut Vec<KernelReferenceID>,
+) -> T {
+    let mut service = SimpleService::with_name("INTERFACE");
+    let mut buffer = Vec::new();
+    serialize(msg, &mut buffer);
+    service.call(&mut buffer, handles).unwrap();
+    deserialize(&buffer).unwrap()
+}
+
+pub fn interfaces() -> Vec<InterfaceInfo> {
+    interface_call(&InterfaceMessage::List, &mut Vec::new())
+}
+
+pub fn set_interface_config(id: u32, config: InterfaceConfig) -> Result<(), NetError> {
+    interface_call(&InterfaceMessage::Set(id, config), &mut Vec::new())
+}
+
+/// Adds a network card to the stack. Returns the interface id and the channel the stack will
+/// send [`PhysicalNet`] requests down, which the driver has to answer in order.
+pub fn register_nic(driver: &str) -> (u32, KernelReference) {
+    let (ours, theirs) = channel_create_rs();
+    let mut handles = vec![theirs.id()];
+    let id = interface_call(&InterfaceMessage::Register(driver.into()), &mut handles);
+    (id, ours)
+}
+
+pub fn routes() -> Vec<Route> {
+    interface_call(&InterfaceMessage::Routes, &mut Vec::new())
+}
+
+pub fn add_route(route: Route) -> Result<(), NetError> {
+    interface_call(&InterfaceMessage::AddRoute(route), &mut Vec::new())
+}
+
+pub fn delete_route(route: Route) -> Result<(), NetError> {
+    interface_call(&InterfaceMessage::DeleteRoute(route), &mut Vec::new())
+}
+
+/// Messages to the `DNS` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DnsRequest<'a> {
+    /// Answered with a `Result<Resolved, NetError>`
+    Resolve(&'a str),
+}
+
+/// The addresses a name resolved to
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Resolved {
+    pub v4: Vec<IPAddr>,
+    pub v6: Vec<IPAddr>,
+}
+
+impl Resolved {
+    /// The address to connect to, ipv4 if there is one as it is more likely to work
+    pub fn preferred(&self) -> Option<IPAddr> {
+        self.v4.first().or(self.v6.first()).copied()
+    }
+}
+
+/// Looks up the addresses for a host name, which can also just be an address
+pub fn resolve(hostname: &str) -> Result<Resolved, NetError> {
+    match hostname.parse() {
+        Ok(ip @ IPAddr::V4(..)) => {
+            return Ok(Resolved {
+                v4: vec![ip],
+                v6: Vec::new(),
+            })
+        }
+        Ok(ip @ IPAddr::V6(_)) => {
+            return Ok(Resolved {
+                v4: Vec::new(),
+                v6: vec![ip],
+            })
+        }
+        Err(_) => (),
+    }
+
+    let mut service = SimpleService::with_name("DNS");
+    let mut buffer = Vec::new();
+    serialize(&DnsRequest::Resolve(hostname), &mut buffer);
+    service
+        .call(&mut buffer, &mut Vec::new())
+        .ok_or(NetError::Closed)?;
+    deserialize(&buffer).unwrap()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EchoRequest<'a> {
+    pub dst: IPAddr,
+    pub seq: u16,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EchoReply<'a> {
+    pub src: IPAddr,
+    pub seq: u16,
+    pub ttl: u8,
+    /// How long since the request was sent, in microseconds
+    pub rtt_us: u64,
+    pub data: &'a [u8],
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+/// Which frames a capture gets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureFilter {
+    /// Only frames on this interface, or every interface
+    pub interface: Option<u32>,
+    /// Frames are cut down to this many bytes
+    pub snap_len: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaptureDirection {
+    Received,
+    Sent,
+}
+
+/// A frame as it went past the stack. Frames being sent are caught before the card does any
+/// offloads, so their checksums can be left unfilled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedFrame<'a> {
+    pub interface: u32,
+    pub direction: CaptureDirection,
+    /// When the frame went past, in microseconds since boot
+    pub timestamp_us: u64,
+    /// How long the whole frame was, data might be cut short by the snap length
+    pub len: u32,
+    pub data: &'a [u8],
+}
+
+/// The most a datagram can hold without being fragmented
+pub const MAX_UDP_PAYLOAD: usize = 1472;
+/// The most a datagram can hold over ipv4, which sends what doesn't fit in a frame in
+/// fragments
+pub const MAX_DATAGRAM: usize = 65507;
+
+/// The most a datagram to ip can hold. Ipv6 doesn't fragment, so its datagrams have to fit in
+/// a frame.
+pub fn max_datagram(ip: IPAddr) -> usize {
+    match ip {
+        IPAddr::V4(..) => MAX_DATAGRAM,
+        IPAddr::V6(_) => MAX_UDP_PAYLOAD - 20,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, Serialize, Deserialize)]
+pub enum NetError {
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("connection reset by peer")]
+    ConnectionReset,
+    #[error("connection timed out")]
+    TimedOut,
+    #[error("no free local ports")]
+    NoFreePorts,
+    #[error("connection closed")]
+    Closed,
+    #[error("address already in use")]
+    AddrInUse,
+    #[error("message too long")]
+    MessageTooLong,
+    #[error("host not found")]
+    HostNotFound,
+    #[error("no dns server is configured")]
+    NoDnsServer,
+    #[error("socket is not connected")]
+    NotConnected,
+    #[error("operation not supported by the socket")]
+    Unsupported,
+    #[error("socket is in the wrong state for that")]
+    InvalidState,
+    #[error("no such interface")]
+    NoSuchInterface,
+    #[error("no such route")]
+    NoSuchRoute,
+    #[error("invalid route")]
+    InvalidRoute,
+    #[error("no such firewall rule")]
+    NoSuchRule,
+    #[error("invalid firewall rule")]
+    InvalidRule,
+    #[error("bad answer from the server")]
+    BadResponse,
+    #[error("not configured")]
+    NotConfigured,
+    /// Nothing arrived in time
+    #[error("no data available")]
+    WouldBlock,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum IPAddr {
     V4(u8, u8, u8, u8),
+    /// In the order it goes on the wire
+    V6([u8; 16]),
 }
 
 impl Display for IPAddr {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             IPAddr::V4(a, b, c, d) => f.write_fmt(format_args!("IPV4({a}.{b}.{c}.{d})")),
+            IPAddr::V6(bytes) => {
+                let groups: [u16; 8] =
+                    core::array::from_fn(|i| u16::from_be_bytes([bytes[i * 2], bytes[i * 2 + 1]]));
+
+                // The longest run of two or more zero groups is written as ::, as in RFC 5952
+                let mut zeros = (0, 0);
+                let mut start = 0;
+                for (i, g) in groups.iter().enumerate() {
+                    if *g != 0 {
+                        start = i + 1;
+                    } else if i + 1 - start > zeros.1 - zeros.0 {
+                        zeros = (start, i + 1);
+                    }
+                }
+                if zeros.1 - zeros.0 < 2 {
+                    zeros = (8, 8);
+                }
+
+                f.write_str("IPV6(")?;
+                for (i, g) in groups.iter().enumerate() {
+                    if i == zeros.0 {
+                        f.write_str("::")?;
+                    }
+                    if (zeros.0..zeros.1).contains(&i) {
+                        continue;
+                    }
+                    if i != 0 && i != zeros.1 {
+                        f.write_str(":")?;
+                    }
+                    write!(f, "{g:x}")?;
+                }
+                f.write_str(")")
+            }
         }
     }
 }
@@ -47,7 +482,76 @@ pub struct NotSameSubnetError {
     subnet: u32,
 }
 
+#[derive(Debug, Clone, Copy, Error)]
+#[error("invalid ip address")]
+pub struct ParseIPAddrError;
+
+impl FromStr for IPAddr {
+    type Err = ParseIPAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(':') {
+            return parse_v6(s);
+        }
+
+        let mut octets = s.split('.').map(|o| o.parse::<u8>());
+        let mut next = || {
+            octets
+                .next()
+                .ok_or(ParseIPAddrError)?
+                .or(Err(ParseIPAddrError))
+        };
+        let ip = IPAddr::V4(next()?, next()?, next()?, next()?);
+        match octets.next() {
+            Some(_) => Err(ParseIPAddrError),
+            None => Ok(ip),
+        }
+    }
+}
+
+fn parse_v6(s: &str) -> Result<IPAddr, ParseIPAddrError> {
+    let parse_groups = |s: &str| -> Result<Vec<u16>, ParseIPAddrError> {
+        if s.is_empty() {
+            return Ok(Vec::new());
+        }
+        s.split(':')
+            .map(|g| match g.len() {
+                1..=4 => u16::from_str_radix(g, 16).or(Err(ParseIPAddrError)),
+                _ => Err(ParseIPAddrError),
+            })
+            .collect()
+    };
+
+    let mut groups = match s.split_once("::") {
+        Some((head, tail)) => {
+            let head = parse_groups(head)?;
+            let tail = parse_groups(tail)?;
+            if head.len() + tail.len() > 7 {
+                return Err(ParseIPAddrError);
+            }
+            let mut groups = head;
+            groups.resize(8 - tail.len(), 0);
+            groups.extend(tail);
+            groups
+        }
+        None => parse_groups(s)?,
+    };
+    if groups.len() != 8 {
+        return Err(ParseIPAddrError);
+    }
+
+    let mut bytes = [0; 16];
+    for (b, g) in bytes.chunks_exact_mut(2).zip(groups.drain(..)) {
+        b.copy_from_slice(&g.to_be_bytes());
+    }
+    Ok(IPAddr::V6(bytes))
+}
+
 impl IPAddr {
+    pub const UNSPECIFIED: Self = Self::V4(0, 0, 0, 0);
+    pub const UNSPECIFIED_V6: Self = Self::V6([0; 16]);
+    pub const BROADCAST: Self = Self::V4(255, 255, 255, 255);
+
     pub fn ipv4_addr_from_net(ip: u32) -> Self {
         Self::V4(
             ip as u8,
@@ -57,14 +561,24 @@ impl IPAddr {
         )
     }
 
+    /// Only for ipv4 addresses
     pub fn as_net_be(&self) -> u32 {
+        u32::from_le_bytes(self.octets())
+    }
+
+    /// The ipv4 address in the order it goes on the wire. Only the ipv6 code deals with ipv6
+    /// addresses, so being given one here is a bug.
+    pub fn octets(&self) -> [u8; 4] {
         match self {
-            Self::V4(a, b, c, d) => {
-                *a as u32 | (*b as u32) << 8 | (*c as u32) << 16 | (*d as u32) << 24
-            }
+            Self::V4(a, b, c, d) => [*a, *b, *c, *d],
+            Self::V6(_) => panic!("{self} is not an ipv4 address"),
         }
     }
 
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self, Self::V6(_))
+    }
+
     pub fn same_subnet(&self, ip2: &IPAddr, subnet: u32) -> Result<(), NotSameSubnetError> {
         match (self, ip2) {
             (Self::V4(a1, b1, c1, d1), Self::V4(a2, b2, c2, d2)) => {
@@ -76,12 +590,120 @@ impl IPAddr {
                     Ok(())
                 } else {
                     Err(NotSameSubnetError {
-                        a: self.clone(),
-                        b: ip2.clone(),
+                        a: *self,
+                        b: *ip2,
                         subnet,
                     })
                 }
             }
+            _ => Err(NotSameSubnetError {
+                a: *self,
+                b: *ip2,
+                subnet,
+            }),
+        }
+    }
+}
+
+/// Reads a message of up to a packet's size from the channel, giving up with
+/// [`NetError::WouldBlock`] after roughly timeout ms
+pub fn read_timeout(
+    channel: &KernelReference,
+    buffer: &mut Vec<u8>,
+    timeout: u64,
+) -> Result<(), NetError> {
+    const POLL: u64 = 10;
+    buffer.clear();
+    buffer.reserve(MAX_DATAGRAM + 64);
+    for _ in 0..=timeout / POLL {
+        match channel_try_read_rs(channel.id(), buffer, &mut Vec::new()) {
+            ChannelReadResult::Ok => return Ok(()),
+            ChannelReadResult::Empty => sleep(POLL),
+            ChannelReadResult::Closed => return Err(NetError::Closed),
+            e => panic!("failed to read from the network {e:?}"),
+        };
+    }
+    Err(NetError::WouldBlock)
+}
+
+/// Gets a copy of the frames going through the stack. A capture that doesn't keep up misses
+/// frames.
+pub struct Capture {
+    channel: KernelReference,
+    buffer: Vec<u8>,
+}
+
+impl Capture {
+    pub fn open(filter: CaptureFilter) -> Result<Self, NetError> {
+        let mut networking = SimpleService::with_name("NETWORKING");
+        let mut buffer = Vec::new();
+        serialize(&Networking::Capture(filter), &mut buffer);
+        let mut handles = Vec::with_capacity(1);
+        networking
+            .call(&mut buffer, &mut handles)
+            .ok_or(NetError::Closed)?;
+        deserialize::<Result<(), NetError>>(&buffer).unwrap()?;
+
+        Ok(Self {
+            channel: KernelReference::from_id(handles[0]),
+            buffer,
+        })
+    }
+
+    /// Waits for the next frame
+    pub fn recv(&mut self) -> Result<CapturedFrame<'_>, NetError> {
+        match channel_read_resize(self.channel.id(), &mut self.buffer, &mut Vec::new()) {
+            ChannelReadResult::Ok => Ok(deserialize(&self.buffer).unwrap()),
+            ChannelReadResult::Closed => Err(NetError::Closed),
+            e => panic!("failed to read from the network {e:?}"),
         }
     }
 }
+
+/// Sends icmp echo requests and gets their replies
+pub struct PingSocket {
+    channel: KernelReference,
+    id: u16,
+    buffer: Vec<u8>,
+}
+
+impl PingSocket {
+    pub fn open() -> Result<Self, NetError> {
+        let mut networking = SimpleService::with_name("NETWORKING");
+        let mut buffer = Vec::new();
+        serialize(&Networking::IcmpOpen, &mut buffer);
+        let mut handles = Vec::with_capacity(1);
+        networking
+            .call(&mut buffer, &mut handles)
+            .ok_or(NetError::Closed)?;
+        let id = deserialize::<Result<u16, NetError>>(&buffer).unwrap()?;
+
+        Ok(Self {
+            channel: KernelReference::from_id(handles[0]),
+            id,
+            buffer,
+        })
+    }
+
+    /// The identifier our echo requests carry
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn send(&mut self, dst: IPAddr, seq: u16, data: &[u8]) -> Result<(), NetError> {
+        // The icmp header is the same size as udp's
+        if data.len() > MAX_UDP_PAYLOAD {
+            return Err(NetError::MessageTooLong);
+        }
+        serialize(&EchoRequest { dst, seq, data }, &mut self.buffer);
+        channel_write_rs(self.channel.id(), &self.buffer, &[])
+            .then_some(())
+            .ok_or(NetError::Closed)
+    }
+
+    /// Waits up to roughly timeout ms for a reply
+    pub fn recv_timeout(&mut self, timeout: u64) -> Result<EchoReply<'_>, NetError> {
+        read_timeout(&self.channel, &mut self.buffer, timeout)?;
+        Ok(deserialize(&self.buffer).unwrap())
+    }
+}
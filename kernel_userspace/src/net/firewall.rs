@@ -0,0 +1,111 @@
+//! The firewall protocol. The networking service checks every ip packet going in or out
+//! against an ordered list of rules, the first rule that matches decides what happens to it
+//! and packets that match none get the direction's policy. Rules are stateless, so letting
+//! in the replies to connections we make takes rules of their own. The rules are changed
+//! through the `FIREWALL` service.
+
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::service::{deserialize, serialize, SimpleService};
+
+use super::{IPAddr, NetError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FirewallDirection {
+    /// Packets that arrived for us
+    In,
+    /// Packets we are sending
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FirewallAction {
+    Accept,
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FirewallProtocol {
+    /// Icmp over ipv4 and ipv6
+    Icmp,
+    Tcp,
+    Udp,
+}
+
+/// A rule matches a packet when everything it gives matches, so a rule with nothing but a
+/// direction and action matches every packet going that way
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FirewallRule {
+    pub direction: FirewallDirection,
+    pub action: FirewallAction,
+    pub interface: Option<u32>,
+    pub protocol: Option<FirewallProtocol>,
+    /// The other end's address and prefix length, the source of packets coming in and the
+    /// destination of packets going out
+    pub remote: Option<(IPAddr, u8)>,
+    /// Our port, only tcp and udp packets have ports
+    pub local_port: Option<u16>,
+    /// The other end's port
+    pub remote_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirewallEntry {
+    pub rule: FirewallRule,
+    /// How many packets the rule has decided
+    pub matched: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirewallTable {
+    /// What happens to packets coming in that no rule matches
+    pub policy_in: FirewallAction,
+    pub policy_out: FirewallAction,
+    /// In the order they are checked
+    pub rules: Vec<FirewallEntry>,
+}
+
+/// Messages to the `FIREWALL` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FirewallMessage {
+    /// Answered with the [`FirewallTable`]
+    List,
+    /// Puts the rule at the index, or last, answered with a `Result<(), NetError>`
+    Insert(Option<u32>, FirewallRule),
+    /// Removes the rule at the index, answered with a `Result<(), NetError>`
+    Delete(u32),
+    /// Removes every rule, answered with `()`
+    Flush,
+    /// Answered with `()`
+    SetPolicy(FirewallDirection, FirewallAction),
+}
+
+fn firewall_call<T: for<'a> Deserialize<'a>>(msg: &FirewallMessage) -> T {
+    let mut service = SimpleService::with_name("FIREWALL");
+    let mut buffer = Vec::new();
+    serialize(msg, &mut buffer);
+    service.call(&mut buffer, &mut Vec::new()).unwrap();
+    deserialize(&buffer).unwrap()
+}
+
+pub fn table() -> FirewallTable {
+    firewall_call(&FirewallMessage::List)
+}
+
+/// Puts the rule at the index, or last if there is none
+pub fn insert(index: Option<u32>, rule: FirewallRule) -> Result<(), NetError> {
+    firewall_call(&FirewallMessage::Insert(index, rule))
+}
+
+pub fn delete(index: u32) -> Result<(), NetError> {
+    firewall_call(&FirewallMessage::Delete(index))
+}
+
+pub fn flush() {
+    firewall_call(&FirewallMessage::Flush)
+}
+
+pub fn set_policy(direction: FirewallDirection, action: FirewallAction) {
+    firewall_call(&FirewallMessage::SetPolicy(direction, action))
+}
@@ -0,0 +1,97 @@
+//! Multicast dns and dns service discovery. The networking service answers for the host name
+//! in `/boot/net.conf` under `.local`, such as `fioxa.local`, and the services programs
+//! advertise through the `MDNS` service, which can also browse for services other hosts on
+//! the link advertise. Names ending in `.local` given to [`resolve`](super::resolve) are
+//! looked up over multicast dns.
+
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    object::KernelReference,
+    service::{deserialize, serialize, SimpleService},
+};
+
+use super::{IPAddr, NetError};
+
+/// Messages to the `MDNS` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MdnsMessage {
+    /// Asks the link for instances of a service type such as `_http._tcp`, answered after about
+    /// a second with a `Vec<MdnsService>` of those that replied
+    Browse(String),
+    /// Asks the link what types of service it has, answered after about a second with a
+    /// `Vec<String>`
+    ServiceTypes,
+    /// Advertises one of our services, answered with a `Result<(), NetError>` and on success
+    /// a channel. Fails with [`NetError::NotConfigured`] if we have no host name. The service is advertised until the channel is closed.
+    Register(MdnsRegistration),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MdnsRegistration {
+    /// The type of service, such as `_echo._tcp`
+    pub service: String,
+    pub port: u16,
+    /// `key=value` strings about the service
+    pub txt: Vec<String>,
+}
+
+/// A service instance found on the link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MdnsService {
+    /// The instance's full name, such as `fioxa._echo._tcp.local`
+    pub instance: String,
+    /// The host it is on, such as `fioxa.local`
+    pub host: String,
+    pub port: u16,
+    /// The host's addresses, empty if they didn't come with the answer
+    pub addrs: Vec<IPAddr>,
+    pub txt: Vec<String>,
+}
+
+fn mdns_call<T: for<'a> Deserialize<'a>>(msg: &MdnsMessage) -> T {
+    let mut service = SimpleService::with_name("MDNS");
+    let mut buffer = Vec::new();
+    serialize(msg, &mut buffer);
+    service.call(&mut buffer, &mut Vec::new()).unwrap();
+    deserialize(&buffer).unwrap()
+}
+
+/// Finds the instances of a service type such as `_http._tcp` on the link, ours included
+pub fn browse(service: &str) -> Vec<MdnsService> {
+    mdns_call(&MdnsMessage::Browse(String::from(service)))
+}
+
+/// The types of service on the link, ours included
+pub fn service_types() -> Vec<String> {
+    mdns_call(&MdnsMessage::ServiceTypes)
+}
+
+/// A service of ours being advertised, which stops when this is dropped
+pub struct MdnsAdvertisement {
+    _channel: KernelReference,
+}
+
+impl MdnsAdvertisement {
+    /// Advertises a service type such as `_echo._tcp` on the port
+    pub fn register(service: &str, port: u16, txt: Vec<String>) -> Result<Self, NetError> {
+        let mut mdns = SimpleService::with_name("MDNS");
+        let mut buffer = Vec::new();
+        serialize(
+            &MdnsMessage::Register(MdnsRegistration {
+                service: String::from(service),
+                port,
+                txt,
+            }),
+            &mut buffer,
+        );
+        let mut handles = Vec::with_capacity(1);
+        mdns.call(&mut buffer, &mut handles)
+            .ok_or(NetError::Closed)?;
+        deserialize::<Result<(), NetError>>(&buffer).unwrap()?;
+        Ok(Self {
+            _channel: KernelReference::from_id(handles[0]),
+        })
+    }
+}
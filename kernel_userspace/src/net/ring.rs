@@ -0,0 +1,212 @@
+//! Packet rings, which a network card's driver shares with the stack so frames don't have to
+//! be copied through a channel. A ring is one piece of dma memory: a page with the ring's
+//! positions and a descriptor for each slot, then a [`SLOT_SIZE`] buffer for each slot that
+//! the card can read or write directly.
+//!
+//! Each ring has one producer and one consumer. The producer fills the buffer at the head,
+//! sets its descriptor and then moves the head on. The consumer reads the buffer at the tail
+//! and moves the tail on once it is done with it, handing the slot back. Both count up
+//! forever and wrap, so the ring is full when they are a ring's worth of slots apart. The
+//! driver produces on the receive ring and consumes the transmit ring, and each side sends
+//! the other a message when there is something to look at.
+
+use core::{
+    mem::size_of,
+    slice,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{
+    dma::{DmaBuffer, DmaConstraints},
+    memory::MemoryHandle,
+    syscall::unmmap_page,
+};
+
+/// Enough for a whole ethernet frame, two fit in a page so a buffer never crosses one
+pub const SLOT_SIZE: usize = 2048;
+const HEADER_SIZE: usize = 0x1000;
+/// The most slots a ring can have for their descriptors to fit after the header
+pub const MAX_SLOTS: u32 =
+    ((HEADER_SIZE - size_of::<RingHeader>()) / size_of::<RingDescriptor>()) as u32;
+
+#[repr(C)]
+struct RingHeader {
+    head: AtomicU32,
+    tail: AtomicU32,
+    slots: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct RingDescriptor {
+    /// How much of the slot's buffer is used, 0 for a slot that should be skipped
+    len: u32,
+}
+
+enum Backing {
+    /// The driver's side, which the card uses
+    Dma(DmaBuffer),
+    /// The stack's side, mapped from the driver's
+    Mapped(usize),
+}
+
+pub struct PacketRing {
+    base: *mut u8,
+    slots: u32,
+    backing: Backing,
+}
+
+unsafe impl Send for PacketRing {}
+unsafe impl Sync for PacketRing {}
+
+/// The whole ring's size, in pages
+fn ring_size(slots: u32) -> usize {
+    (HEADER_SIZE + slots as usize * SLOT_SIZE + 0xFFF) & !0xFFF
+}
+
+impl PacketRing {
+    /// Allocates an empty ring, slots has to be at most [`MAX_SLOTS`]
+    pub fn new(slots: u32, constraints: DmaConstraints) -> Option<Self> {
+        if slots == 0 || slots > MAX_SLOTS {
+            return None;
+        }
+        let buffer = DmaBuffer::new(ring_size(slots), constraints)?;
+        let ring = Self {
+            base: buffer.as_ptr(),
+            slots,
+            backing: Backing::Dma(buffer),
+        };
+        // The memory starts zeroed, which is an empty ring
+        unsafe { (*ring.header_ptr()).slots = slots };
+        Some(ring)
+    }
+
+    /// Maps a ring the other side shared. The driver that shares it can reach all of memory
+    /// anyway, so the ring is trusted to be as big as it says.
+    pub fn map(handle: &MemoryHandle) -> Option<Self> {
+        let base = handle.map_writable()?;
+        let slots = unsafe { (*(base as *const RingHeader)).slots };
+        // How big it really is isn't known, so a bad ring stays mapped
+        if slots == 0 || slots > MAX_SLOTS {
+            return None;
+        }
+        Some(Self {
+            base,
+            slots,
+            backing: Backing::Mapped(ring_size(slots)),
+        })
+    }
+
+    /// A handle for the other side to [`Self::map`], only the driver's side can share
+    pub fn share(&self) -> Option<MemoryHandle> {
+        match &self.backing {
+            Backing::Dma(buffer) => buffer.share(),
+            Backing::Mapped(_) => None,
+        }
+    }
+
+    pub fn slots(&self) -> u32 {
+        self.slots
+    }
+
+    fn header_ptr(&self) -> *mut RingHeader {
+        self.base.cast()
+    }
+
+    fn header(&self) -> &RingHeader {
+        unsafe { &*self.header_ptr() }
+    }
+
+    fn descriptor(&self, pos: u32) -> *mut RingDescriptor {
+        unsafe {
+            self.base
+                .add(size_of::<RingHeader>())
+                .cast::<RingDescriptor>()
+                .add((pos % self.slots) as usize)
+        }
+    }
+
+    fn buffer_ptr(&self, pos: u32) -> *mut u8 {
+        unsafe {
+            self.base
+                .add(HEADER_SIZE + (pos % self.slots) as usize * SLOT_SIZE)
+        }
+    }
+
+    /// Where the producer fills next
+    pub fn head(&self) -> u32 {
+        self.header().head.load(Ordering::Acquire)
+    }
+
+    /// Where the consumer reads next
+    pub fn tail(&self) -> u32 {
+        self.header().tail.load(Ordering::Acquire)
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.head().wrapping_sub(self.tail()) >= self.slots
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head() == self.tail()
+    }
+
+    /// Physical address of the slot's buffer at pos, for the card (driver's side only)
+    pub fn buffer_phys(&self, pos: u32) -> Option<u64> {
+        match &self.backing {
+            Backing::Dma(buffer) => {
+                Some(buffer.phys_addr(HEADER_SIZE + (pos % self.slots) as usize * SLOT_SIZE))
+            }
+            Backing::Mapped(_) => None,
+        }
+    }
+
+    /// The buffer at the head for the producer to fill, None if the ring is full
+    pub fn next_buffer(&mut self) -> Option<&mut [u8]> {
+        if self.is_full() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts_mut(self.buffer_ptr(self.head()), SLOT_SIZE) })
+    }
+
+    /// Hands the slot at the head to the consumer with len bytes of its buffer used. The
+    /// producer has to have checked the ring isn't full.
+    pub fn push(&mut self, len: usize) {
+        let head = self.head();
+        unsafe {
+            self.descriptor(head)
+                .write_volatile(RingDescriptor { len: len as u32 })
+        };
+        self.header()
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+    }
+
+    /// The used part of the buffer at pos, which the consumer has to be between the tail and
+    /// head. Empty for slots to skip.
+    pub fn get(&self, pos: u32) -> &[u8] {
+        let len = unsafe { self.descriptor(pos).read_volatile() }.len as usize;
+        unsafe { slice::from_raw_parts(self.buffer_ptr(pos), len.min(SLOT_SIZE)) }
+    }
+
+    /// The oldest buffer the consumer hasn't finished with
+    pub fn peek(&self) -> Option<&[u8]> {
+        (!self.is_empty()).then(|| self.get(self.tail()))
+    }
+
+    /// Hands the slot at the tail back to the producer
+    pub fn pop(&mut self) {
+        let tail = self.tail();
+        self.header()
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl Drop for PacketRing {
+    fn drop(&mut self) {
+        if let Backing::Mapped(len) = self.backing {
+            unmmap_page(self.base as usize, len);
+        }
+    }
+}
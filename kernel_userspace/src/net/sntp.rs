@@ -0,0 +1,58 @@
+//! The clock's network time client. The networking service asks the ntp server in
+//! `/boot/net.conf` for the time soon after boot and then every so often, setting the realtime
+//! clock from the answer. The `SNTP` service reports how that is going, and can change the
+//! server or sync straight away.
+
+use alloc::{string::String, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::service::{deserialize, serialize, SimpleService};
+
+use super::NetError;
+
+/// Messages to the `SNTP` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SntpMessage {
+    /// Answered with the [`SntpStatus`]
+    Status,
+    /// Syncs with the server from now on, answered like [`SntpMessage::Sync`]
+    SetServer(String),
+    /// Syncs straight away, answered with a `Result<SntpStatus, NetError>`
+    Sync,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SntpStatus {
+    /// The name or address of the server
+    pub server: String,
+    /// Uptime in ms of the last sync that worked
+    pub last_sync: Option<u64>,
+    /// How far the last sync moved the clock in microseconds, 0 if it wasn't set before
+    pub offset_us: i64,
+    /// How long the last sync's request and answer took, in microseconds
+    pub round_trip_us: u64,
+    /// How many servers away from a reference clock the server is
+    pub stratum: u8,
+    /// Why the last attempt failed, if it did
+    pub last_error: Option<NetError>,
+}
+
+fn sntp_call<T: for<'a> Deserialize<'a>>(msg: &SntpMessage) -> T {
+    let mut service = SimpleService::with_name("SNTP");
+    let mut buffer = Vec::new();
+    serialize(msg, &mut buffer);
+    service.call(&mut buffer, &mut Vec::new()).unwrap();
+    deserialize(&buffer).unwrap()
+}
+
+pub fn status() -> SntpStatus {
+    sntp_call(&SntpMessage::Status)
+}
+
+pub fn set_server(server: &str) -> Result<SntpStatus, NetError> {
+    sntp_call(&SntpMessage::SetServer(String::from(server)))
+}
+
+pub fn sync() -> Result<SntpStatus, NetError> {
+    sntp_call(&SntpMessage::Sync)
+}
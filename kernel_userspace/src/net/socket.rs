@@ -0,0 +1,423 @@
+//! The socket protocol, which is how programs use the network stack.
+//!
+//! A socket is a channel to the networking service, opened with [`Networking::Socket`]. The
+//! program sends [`SocketRequest`]s down it and the stack answers them and passes on what
+//! arrives from the network as [`SocketEvent`]s. The channel is signalled
+//! [`ObjectSignal::READABLE`](crate::object::ObjectSignal::READABLE) whenever an event is
+//! waiting, so a socket can be waited on with a port like any other channel.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    channel::{channel_read_resize, channel_write_rs, ChannelReadResult},
+    object::{KernelReference, KernelReferenceID},
+    service::{deserialize, serialize, SimpleService},
+};
+
+use super::{max_datagram, read_timeout, IPAddr, NetError, Networking};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SocketKind {
+    /// A tcp connection
+    Stream,
+    /// Udp datagrams
+    Datagram,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SocketRequest<'a> {
+    /// Answered with [`SocketEvent::Bound`], 0 picks a free port
+    Bind(u16),
+    /// Streams only, answered with [`SocketEvent::Connected`] once the handshake is done
+    Connect(IPAddr, u16),
+    /// Streams only, answered with [`SocketEvent::Listening`]. The socket has to be bound.
+    Listen(u32),
+    /// Answered with [`SocketEvent::Accepted`] and the channel for the new connection
+    Accept,
+    /// Streams only, queues the data to be sent
+    Send(&'a [u8]),
+    /// Datagrams only, binds a free port first if the socket isn't bound
+    SendTo(IPAddr, u16, &'a [u8]),
+    /// Streams only, sends a FIN once everything so far has gone
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SocketEvent<'a> {
+    Bound(u16),
+    Connected,
+    Listening,
+    /// Who the new connection is from
+    Accepted(IPAddr, u16),
+    Data(&'a [u8]),
+    Datagram(IPAddr, u16, &'a [u8]),
+    /// The peer has closed its side, no more data will come
+    Eof,
+    /// The last request failed, or for a stream that the connection is gone
+    Error(NetError),
+}
+
+/// A socket the stack has open, from [`sockets`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketInfo {
+    pub kind: SocketKind,
+    pub local_port: u16,
+    /// Who a tcp connection is with
+    pub remote: Option<(IPAddr, u16)>,
+    pub state: SocketState,
+    pub stats: SocketStats,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SocketState {
+    /// Has a port but no connection, which is as far as udp sockets go
+    Bound,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+}
+
+/// Counts of what has gone through a socket. Bytes are those of the payload, packets are
+/// every segment or datagram including tcp's empty acks.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SocketStats {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Times the tcp retransmission timer has gone off
+    pub retransmits: u64,
+}
+
+/// Every socket the networking service has open, connections still closing included
+pub fn sockets() -> Vec<SocketInfo> {
+    let mut networking = SimpleService::with_name("NETWORKING");
+    let mut buffer = Vec::new();
+    serialize(&Networking::SocketTable, &mut buffer);
+    networking.call(&mut buffer, &mut Vec::new()).unwrap();
+    deserialize(&buffer).unwrap()
+}
+
+/// A socket opened through the networking service
+pub struct Socket {
+    channel: KernelReference,
+    kind: SocketKind,
+    buffer: Vec<u8>,
+    /// Stream data that has been received but not read yet
+    pending: VecDeque<u8>,
+    eof: bool,
+    /// Where a connected datagram socket sends to and takes datagrams from
+    peer: Option<(IPAddr, u16)>,
+}
+
+impl Socket {
+    pub fn new(kind: SocketKind) -> Result<Self, NetError> {
+        let mut networking = SimpleService::with_name("NETWORKING");
+        let mut buffer = Vec::new();
+        serialize(&Networking::Socket(kind), &mut buffer);
+        let mut handles = Vec::with_capacity(1);
+        networking
+            .call(&mut buffer, &mut handles)
+            .ok_or(NetError::Closed)?;
+        deserialize::<Result<(), NetError>>(&buffer).unwrap()?;
+
+        Ok(Self::from_channel(
+            KernelReference::from_id(handles[0]),
+            kind,
+        ))
+    }
+
+    fn from_channel(channel: KernelReference, kind: SocketKind) -> Self {
+        Self {
+            channel,
+            kind,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            eof: false,
+            peer: None,
+        }
+    }
+
+    pub fn kind(&self) -> SocketKind {
+        self.kind
+    }
+
+    /// The channel to the stack, for waiting on the socket with a port
+    pub fn handle(&self) -> &KernelReference {
+        &self.channel
+    }
+
+    fn request(&mut self, request: &SocketRequest) -> Result<(), NetError> {
+        serialize(request, &mut self.buffer);
+        channel_write_rs(self.channel.id(), &self.buffer, &[])
+            .then_some(())
+            .ok_or(NetError::Closed)
+    }
+
+    /// Binds the local port, 0 picks a free one. Returns the port that was bound.
+    pub fn bind(&mut self, port: u16) -> Result<u16, NetError> {
+        self.request(&SocketRequest::Bind(port))?;
+        match next_event(&self.channel, &mut self.buffer, &mut Vec::new())? {
+            SocketEvent::Bound(port) => Ok(port),
+            e => unexpected(e),
+        }
+    }
+
+    /// Connects a stream, waiting until the handshake is done. A datagram socket just
+    /// remembers the peer for [`Self::send`] and [`Self::recv`].
+    pub fn connect(&mut self, ip: IPAddr, port: u16) -> Result<(), NetError> {
+        if self.kind == SocketKind::Datagram {
+            self.peer = Some((ip, port));
+            return Ok(());
+        }
+        self.request(&SocketRequest::Connect(ip, port))?;
+        match next_event(&self.channel, &mut self.buffer, &mut Vec::new())? {
+            SocketEvent::Connected => Ok(()),
+            e => unexpected(e),
+        }
+    }
+
+    /// Starts taking connections on the bound port, up to backlog of them wait for
+    /// [`Self::accept`]
+    pub fn listen(&mut self, backlog: u32) -> Result<(), NetError> {
+        self.request(&SocketRequest::Listen(backlog))?;
+        match next_event(&self.channel, &mut self.buffer, &mut Vec::new())? {
+            SocketEvent::Listening => Ok(()),
+            e => unexpected(e),
+        }
+    }
+
+    /// Waits for a connection to a listening socket, returning it and who it is from
+    pub fn accept(&mut self) -> Result<(Socket, IPAddr, u16), NetError> {
+        self.request(&SocketRequest::Accept)?;
+        let mut handles = Vec::with_capacity(1);
+        match next_event(&self.channel, &mut self.buffer, &mut handles)? {
+            SocketEvent::Accepted(ip, port) => Ok((
+                Self::from_channel(KernelReference::from_id(handles[0]), self.kind),
+                ip,
+                port,
+            )),
+            e => unexpected(e),
+        }
+    }
+
+    /// Queues data on a stream, or sends it to the peer of a connected datagram socket. This
+    /// returns before the peer has received it.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), NetError> {
+        match (self.kind, self.peer) {
+            (SocketKind::Stream, _) => self.request(&SocketRequest::Send(data)),
+            (SocketKind::Datagram, Some((ip, port))) => self.send_to(data, ip, port),
+            (SocketKind::Datagram, None) => Err(NetError::NotConnected),
+        }
+    }
+
+    pub fn send_to(&mut self, data: &[u8], ip: IPAddr, port: u16) -> Result<(), NetError> {
+        if self.kind != SocketKind::Datagram {
+            return Err(NetError::Unsupported);
+        }
+        if data.len() > max_datagram(ip) {
+            return Err(NetError::MessageTooLong);
+        }
+        self.request(&SocketRequest::SendTo(ip, port, data))
+    }
+
+    /// Reads into buf, blocking until there is something to read. For a stream this returns 0
+    /// once the peer has closed its side, a datagram is cut short if buf is too small.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        if self.kind == SocketKind::Datagram {
+            loop {
+                let (len, ip, port) = self.recv_from(buf)?;
+                if self.peer.is_none_or(|p| p == (ip, port)) {
+                    return Ok(len);
+                }
+            }
+        }
+
+        while self.pending.is_empty() && !self.eof {
+            match next_event(&self.channel, &mut self.buffer, &mut Vec::new())? {
+                SocketEvent::Data(data) => self.pending.extend(data),
+                SocketEvent::Eof => self.eof = true,
+                e => return unexpected(e),
+            }
+        }
+        let len = buf.len().min(self.pending.len());
+        for (b, p) in buf.iter_mut().zip(self.pending.drain(..len)) {
+            *b = p;
+        }
+        Ok(len)
+    }
+
+    /// Waits for a datagram, it is cut short if buf is too small. Returns the length and who
+    /// sent it.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, IPAddr, u16), NetError> {
+        let event = next_event(&self.channel, &mut self.buffer, &mut Vec::new())?;
+        copy_datagram(event, buf)
+    }
+
+    /// Like [`Self::recv_from`] but gives up with [`NetError::WouldBlock`] after roughly
+    /// timeout ms
+    pub fn recv_from_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: u64,
+    ) -> Result<(usize, IPAddr, u16), NetError> {
+        read_timeout(&self.channel, &mut self.buffer, timeout)?;
+        match deserialize(&self.buffer).unwrap() {
+            SocketEvent::Error(e) => Err(e),
+            event => copy_datagram(event, buf),
+        }
+    }
+
+    /// Closes our side of a stream, data can still be read until the peer closes theirs
+    pub fn shutdown(&mut self) -> Result<(), NetError> {
+        self.request(&SocketRequest::Shutdown)
+    }
+}
+
+fn copy_datagram(event: SocketEvent, buf: &mut [u8]) -> Result<(usize, IPAddr, u16), NetError> {
+    match event {
+        SocketEvent::Datagram(ip, port, data) => {
+            let len = buf.len().min(data.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((len, ip, port))
+        }
+        e => unexpected(e),
+    }
+}
+
+/// An event that doesn't answer what was asked, the socket is being misused
+fn unexpected<T>(_: SocketEvent) -> Result<T, NetError> {
+    Err(NetError::InvalidState)
+}
+
+fn next_event<'a>(
+    channel: &KernelReference,
+    buffer: &'a mut Vec<u8>,
+    handles: &mut Vec<KernelReferenceID>,
+) -> Result<SocketEvent<'a>, NetError> {
+    match channel_read_resize(channel.id(), buffer, handles) {
+        ChannelReadResult::Ok => (),
+        ChannelReadResult::Closed => return Err(NetError::Closed),
+        e => panic!("failed to read socket event {e:?}"),
+    }
+    match deserialize(buffer).unwrap() {
+        SocketEvent::Error(e) => Err(e),
+        e => Ok(e),
+    }
+}
+
+/// A tcp connection made through the networking service
+pub struct TcpStream {
+    socket: Socket,
+}
+
+impl TcpStream {
+    /// Connects to a port on the host, waiting until the handshake is done
+    pub fn connect(ip: IPAddr, port: u16) -> Result<Self, NetError> {
+        let mut socket = Socket::new(SocketKind::Stream)?;
+        socket.connect(ip, port)?;
+        Ok(Self { socket })
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    /// Reads into buf, blocking until there is something to read. Returns 0 once the peer has
+    /// closed its side.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, NetError> {
+        self.socket.recv(buf)
+    }
+
+    /// Queues data to be sent, this returns before the peer has received it
+    pub fn write(&mut self, data: &[u8]) -> Result<(), NetError> {
+        self.socket.send(data)
+    }
+
+    /// Closes our side of the connection, data can still be read until the peer closes theirs
+    pub fn shutdown(&mut self) -> Result<(), NetError> {
+        self.socket.shutdown()
+    }
+}
+
+/// A tcp socket taking connections
+pub struct TcpListener {
+    socket: Socket,
+    port: u16,
+}
+
+impl TcpListener {
+    /// Binds the port, 0 picks a free one, and starts listening. Up to backlog connections
+    /// wait to be accepted.
+    pub fn bind(port: u16, backlog: u32) -> Result<Self, NetError> {
+        let mut socket = Socket::new(SocketKind::Stream)?;
+        let port = socket.bind(port)?;
+        socket.listen(backlog)?;
+        Ok(Self { socket, port })
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for a connection, returning it and who it is from
+    pub fn accept(&mut self) -> Result<(TcpStream, IPAddr, u16), NetError> {
+        let (socket, ip, port) = self.socket.accept()?;
+        Ok((TcpStream { socket }, ip, port))
+    }
+}
+
+/// A bound udp socket
+pub struct UdpSocket {
+    socket: Socket,
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds the port, 0 picks a free one
+    pub fn bind(port: u16) -> Result<Self, NetError> {
+        let mut socket = Socket::new(SocketKind::Datagram)?;
+        let port = socket.bind(port)?;
+        Ok(Self { socket, port })
+    }
+
+    pub fn socket(&self) -> &Socket {
+        &self.socket
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&mut self, data: &[u8], ip: IPAddr, port: u16) -> Result<(), NetError> {
+        self.socket.send_to(data, ip, port)
+    }
+
+    /// Waits for a datagram, it is cut short if buf is too small. Returns the length and who
+    /// sent it.
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> Result<(usize, IPAddr, u16), NetError> {
+        self.socket.recv_from(buf)
+    }
+
+    /// Like [`Self::recv_from`] but gives up with [`NetError::WouldBlock`] after roughly
+    /// timeout ms
+    pub fn recv_from_timeout(
+        &mut self,
+        buf: &mut [u8],
+        timeout: u64,
+    ) -> Result<(usize, IPAddr, u16), NetError> {
+        self.socket.recv_from_timeout(buf, timeout)
+    }
+}
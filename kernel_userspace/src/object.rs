@@ -48,6 +48,7 @@ pub enum KernelObjectType {
     Channel,
     Port,
     Interrupt,
+    Memory,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -1,13 +1,139 @@
+use core::fmt::Display;
+
 use alloc::{sync::Arc, vec::Vec};
 use serde::{Deserialize, Serialize};
 use spin::Mutex;
 
-use crate::service::SimpleService;
+use crate::{
+    memory::MemoryHandle,
+    object::KernelReference,
+    process::get_handle,
+    service::{deserialize, serialize, SimpleService},
+    syscall::sleep,
+};
+
+/// Sent to the kernel's `PCI` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PciServiceMessage {
+    /// Scans the buses again, starting drivers for functions that appeared and telling the
+    /// drivers of ones that went away. Answered with a [`PciRescan`].
+    Rescan,
+}
+
+/// Where a function is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// What changed since the last scan. A function that now has a different vendor or device
+/// is in both.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PciRescan {
+    pub added: Vec<PciAddress>,
+    pub removed: Vec<PciAddress>,
+}
+
+/// None if there is no `PCI` service
+pub fn rescan() -> Option<PciRescan> {
+    let handle = get_handle("PCI")?;
+    let mut service = SimpleService::new(KernelReference::from_id(handle));
+    let mut buffer = Vec::new();
+    serialize(&PciServiceMessage::Rescan, &mut buffer);
+    service.call(&mut buffer, &mut Vec::new())?;
+    deserialize(&buffer).ok()
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PCIDevCmd {
     Read(u32),
     Write(u32, u32),
+    /// Answered with true and the interrupt handle if the device now uses its own vector
+    EnableMsi,
+    /// Answered with a [`BarInfo`], and for memory bars the handle to map it with
+    GetBar(u8),
+    /// Answered with a channel that is closed when the device is removed
+    WatchRemoval,
+}
+
+/// What one of the device's bars is
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum BarInfo {
+    Unused,
+    /// The handle covers the pages the bar is in, so the bar starts `offset` into it
+    Memory {
+        offset: usize,
+        size: u64,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+pub enum Bar {
+    Memory {
+        handle: MemoryHandle,
+        offset: usize,
+        size: u64,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+
+impl Bar {
+    /// Maps a memory bar, returning where its registers start
+    pub fn map(&self) -> Option<*mut u8> {
+        match self {
+            Bar::Memory { handle, offset, .. } => {
+                handle.map_writable().map(|p| unsafe { p.add(*offset) })
+            }
+            Bar::Io { .. } => None,
+        }
+    }
+}
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCIE: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const PM_SUPPORTS_D1: u16 = 1 << 9;
+const PM_SUPPORTS_D2: u16 = 1 << 10;
+const PM_STATE_MASK: u16 = 0b11;
+
+/// An entry in the device's capability list
+#[derive(Debug, Clone, Copy)]
+pub struct PCICapability {
+    pub id: u8,
+    /// Where the capability's registers start, the id and next pointer being the first two bytes
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0 = 0,
+    D1 = 1,
+    D2 = 2,
+    D3Hot = 3,
 }
 
 pub struct PCIDevice {
@@ -49,6 +175,46 @@ impl PCIDevice {
         self.device_service
             .call_val(&PCIDevCmd::Write(offset, data), &mut Vec::new())
     }
+
+    /// Moves the device onto a message signalled interrupt of its own, the handle is waited on
+    /// with `interrupt_wait`. None if it can't, then it keeps using [`crate::INT_PCI`].
+    pub fn enable_msi(&mut self) -> Option<KernelReference> {
+        let mut handles = Vec::with_capacity(1);
+        let enabled: bool = self
+            .device_service
+            .call_val(&PCIDevCmd::EnableMsi, &mut handles);
+        enabled.then(|| KernelReference::from_id(handles[0]))
+    }
+
+    /// Where one of the device's bars is, None if it doesn't have that bar. This is the only
+    /// way to get at memory bars, the bars themselves can't be written to.
+    pub fn get_bar(&mut self, bar: u8) -> Option<Bar> {
+        let mut handles = Vec::with_capacity(1);
+        let info: BarInfo = self
+            .device_service
+            .call_val(&PCIDevCmd::GetBar(bar), &mut handles);
+        match info {
+            BarInfo::Unused => None,
+            BarInfo::Memory { offset, size } => Some(Bar::Memory {
+                handle: MemoryHandle::from_kref(KernelReference::from_id(*handles.first()?)),
+                offset,
+                size,
+            }),
+            BarInfo::Io { port, size } => Some(Bar::Io { port, size }),
+        }
+    }
+
+    /// A channel that gets [`ObjectSignal::CHANNEL_CLOSED`] when the device is removed, to wait
+    /// on alongside the device's interrupt
+    ///
+    /// [`ObjectSignal::CHANNEL_CLOSED`]: crate::object::ObjectSignal::CHANNEL_CLOSED
+    pub fn watch_removal(&mut self) -> KernelReference {
+        let mut handles = Vec::with_capacity(1);
+        let _: () = self
+            .device_service
+            .call_val(&PCIDevCmd::WatchRemoval, &mut handles);
+        KernelReference::from_id(handles[0])
+    }
 }
 
 pub struct PCIHeaderCommon {
@@ -107,6 +273,79 @@ impl PCIHeaderCommon {
         unsafe { self.device.lock().read_u8(15) }
     }
 
+    /// Reads the dword at the offset, for registers like those of a vendor capability
+    pub fn read_config(&self, offset: u32) -> u32 {
+        unsafe { self.device.lock().read_u32(offset) }
+    }
+
+    pub fn capabilities(&self) -> Vec<PCICapability> {
+        let mut device = self.device.lock();
+        let mut caps = Vec::new();
+        unsafe {
+            if device.read_u16(6) & STATUS_CAPABILITIES == 0 {
+                return caps;
+            }
+            let mut next = device.read_u8(0x34) & !0b11;
+            // Guards against a list that loops back on itself
+            while next != 0 && caps.len() < 48 {
+                let offset = next as u32;
+                caps.push(PCICapability {
+                    id: device.read_u8(offset),
+                    offset,
+                });
+                next = device.read_u8(offset + 1) & !0b11;
+            }
+        }
+        caps
+    }
+
+    /// The offset of the first capability with the id
+    pub fn find_capability(&self, id: u8) -> Option<u32> {
+        self.capabilities()
+            .into_iter()
+            .find(|c| c.id == id)
+            .map(|c| c.offset)
+    }
+
+    /// The state the device is in, devices without power management are always in D0
+    pub fn get_power_state(&self) -> PowerState {
+        let Some(cap) = self.find_capability(CAP_POWER_MANAGEMENT) else {
+            return PowerState::D0;
+        };
+        match unsafe { self.device.lock().read_u16(cap + 4) } & PM_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Moves the device to the state, returns false if it doesn't support it. Coming back from
+    /// D3 may reset the device, so its registers have to be set up again.
+    pub fn set_power_state(&self, state: PowerState) -> bool {
+        let Some(cap) = self.find_capability(CAP_POWER_MANAGEMENT) else {
+            return state == PowerState::D0;
+        };
+        unsafe {
+            let mut device = self.device.lock();
+            let supports = device.read_u16(cap + 2);
+            match state {
+                PowerState::D1 if supports & PM_SUPPORTS_D1 == 0 => return false,
+                PowerState::D2 if supports & PM_SUPPORTS_D2 == 0 => return false,
+                _ => (),
+            }
+
+            let control = device.read_u16(cap + 4);
+            if control & PM_STATE_MASK == state as u16 {
+                return true;
+            }
+            device.write_u16(cap + 4, (control & !PM_STATE_MASK) | state as u16);
+        }
+        // The device can't be touched for 10ms after going to or from D3
+        sleep(10);
+        true
+    }
+
     pub unsafe fn get_as_header0(self) -> PCIHeader0 {
         PCIHeader0 {
             device: self.device.clone(),
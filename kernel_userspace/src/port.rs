@@ -2,6 +2,7 @@ use num_derive::{FromPrimitive, ToPrimitive};
 
 use crate::{
     make_syscall,
+    memory::MemoryPressure,
     object::{KernelReferenceID, ObjectSignal},
 };
 
@@ -35,6 +36,9 @@ pub enum PortNotificationType {
     Interrupt {
         timestamp: u64,
     },
+    MemoryPressure {
+        level: MemoryPressure,
+    },
     User([u8; 8]),
 }
 
@@ -0,0 +1,67 @@
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    service::{deserialize, serialize, SimpleService},
+    syscall::spawn_thread,
+};
+
+/// Sent to the power manager's `POWER` service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PowerServiceMessage {
+    /// Stops everything listening and turns the machine off, answered with false if it
+    /// couldn't be turned off
+    Shutdown,
+    /// The same as [`PowerServiceMessage::Shutdown`] but restarts the machine
+    Reboot,
+    /// The connection gets [`PowerEvent`]s from now on
+    Listen,
+    /// The answer to [`PowerEvent::ShuttingDown`]
+    Stopped,
+}
+
+/// Sent to connections that are listening
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PowerEvent {
+    /// The machine is about to go off, stop and answer with [`PowerServiceMessage::Stopped`]
+    ShuttingDown,
+}
+
+fn call(msg: &PowerServiceMessage) -> bool {
+    let mut power = SimpleService::with_name("POWER");
+    let mut buffer = Vec::new();
+    serialize(msg, &mut buffer);
+    power.call(&mut buffer, &mut Vec::new()).is_some() && deserialize(&buffer).unwrap_or(false)
+}
+
+/// Turns the machine off once everything listening has stopped, only returns if it couldn't
+pub fn shutdown() -> bool {
+    call(&PowerServiceMessage::Shutdown)
+}
+
+/// Restarts the machine once everything listening has stopped, only returns if it couldn't
+pub fn reboot() -> bool {
+    call(&PowerServiceMessage::Reboot)
+}
+
+/// Runs `stop` on a thread of its own when the machine is about to go off. The power manager
+/// waits a few seconds for it before going on without it.
+pub fn on_shutdown(stop: impl FnOnce() + Send + Sync + 'static) {
+    spawn_thread(move || {
+        let mut power = SimpleService::with_name("POWER");
+        let mut buffer = Vec::new();
+        power.send(serialize(&PowerServiceMessage::Listen, &mut buffer), &[]);
+
+        loop {
+            if power.recv(&mut buffer, &mut Vec::new()).is_none() {
+                return;
+            }
+            if let Ok(PowerEvent::ShuttingDown) = deserialize(&buffer) {
+                break;
+            }
+        }
+
+        stop();
+        power.send(serialize(&PowerServiceMessage::Stopped, &mut buffer), &[]);
+    });
+}
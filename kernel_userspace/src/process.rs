@@ -16,6 +16,41 @@ use crate::{
 pub enum KernelProcessOperation {
     GetExitCode,
     Kill,
+    GetRUsage,
+    ForkCow,
+    GetMemInfo,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ProcessRUsage {
+    /// Time spent running in userspace in microseconds
+    pub user_time_us: u64,
+    /// Time spent running in the kernel (syscalls or kernel processes) in microseconds
+    pub kernel_time_us: u64,
+    /// Highest number of bytes the process has had mapped at once
+    pub peak_memory: u64,
+    /// Bytes of anonymous memory the process has mapped
+    pub reserved_memory: u64,
+    /// Bytes of the reserved memory that have been touched and given physical pages
+    pub committed_memory: u64,
+    pub handle_count: u64,
+    pub context_switches: u64,
+}
+
+/// Current memory usage of a process in pages
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct ProcessMemInfo {
+    /// Pages currently present in the page tables
+    pub mapped_pages: u64,
+    /// Highest number of pages that were mapped at once
+    pub peak_mapped_pages: u64,
+    /// Pages of memory owned by the processes mappings, including pages that aren't mapped yet
+    /// or are shared
+    pub committed_pages: u64,
+    /// Committed pages that are shared with another mapping, process or the page cache
+    pub shared_pages: u64,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
@@ -46,6 +81,45 @@ pub fn process_kill(handle: KernelReferenceID) {
     }
 }
 
+pub fn process_get_rusage(handle: KernelReferenceID) -> ProcessRUsage {
+    let mut usage = ProcessRUsage::default();
+    unsafe {
+        make_syscall!(
+            crate::syscall::PROCESS,
+            KernelProcessOperation::GetRUsage as usize,
+            handle.0.get(),
+            &mut usage as *mut ProcessRUsage
+        );
+    }
+    usage
+}
+
+pub fn process_get_mem_info(handle: KernelReferenceID) -> ProcessMemInfo {
+    let mut info = ProcessMemInfo::default();
+    unsafe {
+        make_syscall!(
+            crate::syscall::PROCESS,
+            KernelProcessOperation::GetMemInfo as usize,
+            handle.0.get(),
+            &mut info as *mut ProcessMemInfo
+        );
+    }
+    info
+}
+
+/// Creates a copy on write snapshot of the process with no threads (privileged)
+pub fn process_fork_cow(handle: KernelReferenceID) -> KernelReferenceID {
+    let id: usize;
+    unsafe {
+        make_syscall!(
+            crate::syscall::PROCESS,
+            KernelProcessOperation::ForkCow as usize,
+            handle.0.get() => id
+        );
+    }
+    KernelReferenceID::from_usize(id).unwrap()
+}
+
 pub struct ProcessHandle {
     handle: KernelReference,
 }
@@ -72,6 +146,18 @@ impl ProcessHandle {
     pub fn kill(&self) {
         process_kill(self.handle.id())
     }
+
+    pub fn get_rusage(&self) -> ProcessRUsage {
+        process_get_rusage(self.handle.id())
+    }
+
+    pub fn get_mem_info(&self) -> ProcessMemInfo {
+        process_get_mem_info(self.handle.id())
+    }
+
+    pub fn fork_cow(&self) -> ProcessHandle {
+        ProcessHandle::from_kref(KernelReference::from_id(process_fork_cow(self.handle.id())))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -135,6 +135,10 @@ impl SimpleService {
         Self { handle }
     }
 
+    pub fn handle(&self) -> &KernelReference {
+        &self.handle
+    }
+
     pub fn send(&mut self, s: &[u8], handles: &[KernelReferenceID]) -> bool {
         channel_write_rs(self.handle.id(), s, handles)
     }
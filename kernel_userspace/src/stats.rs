@@ -0,0 +1,115 @@
+use serde::{Deserialize, Serialize};
+
+use alloc::vec::Vec;
+
+use crate::service::{deserialize, serialize, SimpleService};
+
+/// Load averages are fixed point with this many fractional bits
+pub const LOADAVG_FSHIFT: u32 = 11;
+pub const LOADAVG_FIXED_1: u64 = 1 << LOADAVG_FSHIFT;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatsServiceMessage {
+    GetSchedStats,
+    /// Turns scheduler tracing on or off, turning it on clears the old events
+    SetSchedTrace(bool),
+    GetSchedTrace,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StatsServiceMessageResp {
+    SchedStats(SchedStats),
+    SchedTrace(Vec<CoreSchedTrace>),
+    Ok,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchedStats {
+    /// Uptime in ms when the stats were collected
+    pub uptime: u64,
+    /// Threads waiting in the run queue
+    pub run_queue_depth: u64,
+    /// Threads that exist and have not been reaped yet
+    pub threads: u64,
+    /// 1, 5 and 15 minute load averages (see [`LOADAVG_FSHIFT`])
+    pub loadavg: [u64; 3],
+    pub cores: Vec<CoreSchedStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoreSchedStats {
+    pub core_id: u8,
+    /// Is the core currently running a thread
+    pub running: bool,
+    /// Number of threads this core has switched into
+    pub context_switches: u64,
+    /// Time in ms this core has spent halted waiting for work
+    pub idle_ms: u64,
+    /// How fast the core ran while it was busy over the last second, if the cpu can tell
+    pub freq_mhz: Option<u32>,
+    /// In degrees celsius, if the core has a thermal sensor
+    pub temperature: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SchedTraceKind {
+    /// A sleeping thread was made runnable
+    Wake,
+    SwitchIn,
+    SwitchOut,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SchedTraceEvent {
+    /// Uptime in us when the event happened
+    pub time_us: u64,
+    pub kind: SchedTraceKind,
+    pub pid: u64,
+    pub tid: u64,
+    /// For [`SchedTraceKind::SwitchIn`], how long the thread was runnable before it got to run
+    pub latency_us: u64,
+}
+
+/// The most recent trace events of a core, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoreSchedTrace {
+    pub core_id: u8,
+    pub events: Vec<SchedTraceEvent>,
+}
+
+impl SchedStats {
+    /// Formats a fixed point load average as (whole, hundredths)
+    pub fn loadavg_parts(load: u64) -> (u64, u64) {
+        let whole = load >> LOADAVG_FSHIFT;
+        let frac = ((load & (LOADAVG_FIXED_1 - 1)) * 100) >> LOADAVG_FSHIFT;
+        (whole, frac)
+    }
+}
+
+pub fn get_sched_stats(buffer: &mut Vec<u8>) -> SchedStats {
+    let mut stats = SimpleService::with_name("STATS");
+    serialize(&StatsServiceMessage::GetSchedStats, buffer);
+    stats.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize(buffer).unwrap() {
+        StatsServiceMessageResp::SchedStats(s) => s,
+        r => panic!("unexpected response {r:?}"),
+    }
+}
+
+pub fn set_sched_trace(enabled: bool, buffer: &mut Vec<u8>) {
+    let mut stats = SimpleService::with_name("STATS");
+    serialize(&StatsServiceMessage::SetSchedTrace(enabled), buffer);
+    stats.call(buffer, &mut Vec::new()).unwrap();
+}
+
+pub fn get_sched_trace(buffer: &mut Vec<u8>) -> Vec<CoreSchedTrace> {
+    let mut stats = SimpleService::with_name("STATS");
+    serialize(&StatsServiceMessage::GetSchedTrace, buffer);
+    stats.call(buffer, &mut Vec::new()).unwrap();
+
+    match deserialize(buffer).unwrap() {
+        StatsServiceMessageResp::SchedTrace(t) => t,
+        r => panic!("unexpected response {r:?}"),
+    }
+}
@@ -26,6 +26,12 @@ pub const INTERRUPT: usize = 13;
 pub const CHANNEL: usize = 14;
 pub const OBJECT: usize = 15;
 pub const PROCESS: usize = 16;
+pub const CPU: usize = 17;
+pub const SET_PRIORITY: usize = 18;
+pub const MEMORY: usize = 19;
+pub const TIME: usize = 20;
+pub const SYSTEM: usize = 21;
+pub const EXIT_PROCESS: usize = 22;
 
 // ! BEWARE, DO NOT USE THIS FROM THE KERNEL
 // As it is static is won't give the correct answer
@@ -256,12 +262,32 @@ pub fn exit() -> ! {
     }
 }
 
+/// Kills every thread of the current process, this one last
+pub fn exit_process() -> ! {
+    unsafe {
+        make_syscall!(EXIT_PROCESS);
+
+        loop {
+            core::arch::asm!("hlt")
+        }
+    }
+}
+
 pub fn sleep(ms: u64) -> u64 {
     let real: u64;
     unsafe { make_syscall!(SLEEP, ms => real) }
     real
 }
 
+/// Thread priority that is only scheduled when nothing else can run
+pub const PRIORITY_IDLE: u8 = 0;
+pub const PRIORITY_NORMAL: u8 = 128;
+
+/// Sets the current threads priority, only kernel processes can go above normal
+pub fn set_thread_priority(priority: u8) {
+    unsafe { make_syscall!(SET_PRIORITY, priority as usize) }
+}
+
 pub fn get_pid() -> ProcessID {
     unsafe {
         let pid: u64;
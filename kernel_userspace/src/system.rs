@@ -0,0 +1,39 @@
+use num_derive::{FromPrimitive, ToPrimitive};
+
+use crate::make_syscall;
+
+#[derive(FromPrimitive, ToPrimitive)]
+pub enum SystemSyscall {
+    Shutdown,
+    Reboot,
+    TakeEvents,
+}
+
+bitflags::bitflags! {
+    /// The fixed acpi events that have happened
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct FixedEvents: u32 {
+        const POWER_BUTTON = 1 << 0;
+        const SLEEP_BUTTON = 1 << 1;
+    }
+}
+
+/// Writes everything cached back to the disks and turns the machine off, only returns if it
+/// couldn't
+pub fn system_shutdown() {
+    unsafe { make_syscall!(crate::syscall::SYSTEM, SystemSyscall::Shutdown as usize) };
+}
+
+/// Writes everything cached back to the disks and restarts the machine, only returns if it
+/// couldn't
+pub fn system_reboot() {
+    unsafe { make_syscall!(crate::syscall::SYSTEM, SystemSyscall::Reboot as usize) };
+}
+
+/// The events since the last call, [`crate::INT_ACPI`] fires when there are new ones
+/// (privileged)
+pub fn system_take_events() -> FixedEvents {
+    let res: usize;
+    unsafe { make_syscall!(crate::syscall::SYSTEM, SystemSyscall::TakeEvents as usize => res) };
+    FixedEvents::from_bits_truncate(res as u32)
+}
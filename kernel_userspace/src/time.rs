@@ -0,0 +1,114 @@
+use core::fmt::Display;
+
+use num_derive::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
+
+use crate::make_syscall;
+
+#[derive(FromPrimitive, ToPrimitive)]
+pub enum TimeSyscall {
+    Uptime,
+    Realtime,
+    SetRealtime,
+}
+
+/// Milliseconds since boot
+pub fn uptime() -> u64 {
+    let res: usize;
+    unsafe { make_syscall!(crate::syscall::TIME, TimeSyscall::Uptime as usize => res) };
+    res as u64
+}
+
+/// Microseconds since the unix epoch, None until something has set the clock
+pub fn realtime_us() -> Option<u64> {
+    let res: usize;
+    unsafe { make_syscall!(crate::syscall::TIME, TimeSyscall::Realtime as usize => res) };
+    Some(res as u64).filter(|t| *t != 0)
+}
+
+/// Sets the clock to microseconds since the unix epoch (privileged)
+pub fn set_realtime_us(us: u64) {
+    unsafe {
+        make_syscall!(
+            crate::syscall::TIME,
+            TimeSyscall::SetRealtime as usize,
+            us as usize
+        )
+    };
+}
+
+/// A UTC date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DateTime {
+    pub year: u32,
+    /// From 1
+    pub month: u8,
+    /// From 1
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The date and time the seconds since the unix epoch land on
+    pub fn from_unix(secs: u64) -> Self {
+        let days = secs / 86400;
+        let secs = secs % 86400;
+
+        // Counts from 0000-03-01 so leap days fall at the end of each year
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as u64;
+
+        Self {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+
+    /// Seconds since the unix epoch, the inverse of [`DateTime::from_unix`]. Dates before the
+    /// epoch give 0.
+    pub fn to_unix(&self) -> u64 {
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        secs.max(0) as u64
+    }
+
+    /// The current date and time, None until something has set the clock
+    pub fn now() -> Option<Self> {
+        realtime_us().map(|us| Self::from_unix(us / 1_000_000))
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
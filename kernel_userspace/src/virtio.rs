@@ -0,0 +1,112 @@
+//! The split virtqueue, laid out the same over the legacy and modern pci transports. The
+//! transport allocates the memory, tells the device where the parts are and notifies it, this
+//! only moves requests through the rings.
+
+use core::{
+    mem::size_of,
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{fence, Ordering},
+};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// Where the parts of a queue go in its memory, the descriptors are at the start
+pub struct VirtqLayout {
+    pub avail: usize,
+    pub used: usize,
+    /// How much memory the queue needs
+    pub len: usize,
+}
+
+impl VirtqLayout {
+    /// The used ring is aligned to used_align, legacy devices want it on a page of its own
+    pub fn new(size: u16, used_align: usize) -> Self {
+        let n = size as usize;
+        let avail = size_of::<VirtqDesc>() * n;
+        let used = (avail + 6 + 2 * n).next_multiple_of(used_align);
+        Self {
+            avail,
+            used,
+            len: used + 6 + 8 * n,
+        }
+    }
+}
+
+/// A queue with one request in it at a time
+pub struct SplitQueue {
+    size: u16,
+    desc: *mut VirtqDesc,
+    /// flags, idx then the ring
+    avail: *mut u16,
+    /// flags, idx then the ring of (id: u32, len: u32)
+    used: *mut u16,
+    last_used: u16,
+}
+
+unsafe impl Send for SplitQueue {}
+
+impl SplitQueue {
+    /// # Safety
+    ///
+    /// The memory at base has to be zeroed, laid out as [`VirtqLayout::new`] says for the size
+    /// and live as long as the queue.
+    pub unsafe fn new(base: *mut u8, size: u16, layout: &VirtqLayout) -> Self {
+        Self {
+            size,
+            desc: base as *mut VirtqDesc,
+            avail: base.add(layout.avail) as *mut u16,
+            used: base.add(layout.used) as *mut u16,
+            last_used: 0,
+        }
+    }
+
+    /// Puts the chain of (address, length, device writable) buffers on the queue starting at
+    /// descriptor 0, the device still has to be notified
+    pub fn submit(&mut self, buffers: &[(u64, u32, bool)]) {
+        assert!(buffers.len() <= self.size as usize);
+        unsafe {
+            for (i, &(addr, len, writable)) in buffers.iter().enumerate() {
+                let mut flags = if writable { VIRTQ_DESC_F_WRITE } else { 0 };
+                if i + 1 < buffers.len() {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                }
+                write_volatile(
+                    self.desc.add(i),
+                    VirtqDesc {
+                        addr,
+                        len,
+                        flags,
+                        next: i as u16 + 1,
+                    },
+                );
+            }
+
+            let idx = read_volatile(self.avail.add(1));
+            write_volatile(self.avail.add(2 + (idx % self.size) as usize), 0);
+            // The descriptors have to be seen before the index that hands them over
+            fence(Ordering::SeqCst);
+            write_volatile(self.avail.add(1), idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Whether the device is done with the request
+    pub fn poll(&mut self) -> bool {
+        let idx = unsafe { read_volatile(self.used.add(1)) };
+        if idx == self.last_used {
+            return false;
+        }
+        fence(Ordering::SeqCst);
+        self.last_used = idx;
+        true
+    }
+}
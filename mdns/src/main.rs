@@ -0,0 +1,55 @@
+#![no_std]
+#![no_main]
+
+use kernel_userspace::{
+    net::mdns,
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: mdns [service type such as _http._tcp]";
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+
+    match (args.next(), args.next()) {
+        (None, _) => {
+            let types = mdns::service_types();
+            if types.is_empty() {
+                println!("No services found");
+            }
+            for t in types {
+                println!("{t}");
+            }
+        }
+        (Some(service), None) => {
+            let found = mdns::browse(service);
+            if found.is_empty() {
+                println!("No instances of {service} found");
+            }
+            for s in found {
+                println!("{} on {}:{}", s.instance, s.host, s.port);
+                for addr in s.addrs {
+                    println!("    {addr}");
+                }
+                for txt in s.txt {
+                    println!("    {txt}");
+                }
+            }
+        }
+        _ => println!("{USAGE}"),
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
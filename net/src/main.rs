@@ -1,11 +1,17 @@
 #![no_std]
 #![no_main]
 
-use alloc::vec::Vec;
+use core::fmt::Display;
+
+use alloc::{format, string::String, vec::Vec};
 use kernel_userspace::{
-    net::{ArpResponse, IPAddr, NotSameSubnetError},
+    net::{
+        add_route, delete_route, interfaces, resolve, routes, set_interface_config, ArpEntry,
+        ArpEntryState, ArpResponse, IPAddr, InterfaceConfig, InterfaceInfo, NetError, Networking,
+        NotSameSubnetError, Route, RouteOrigin, TcpStream, UdpSocket, MAX_UDP_PAYLOAD,
+    },
     service::{deserialize, serialize, SimpleService},
-    syscall::{exit, read_args},
+    syscall::{exit, read_args, sleep},
 };
 
 extern crate alloc;
@@ -22,39 +28,310 @@ pub extern "C" fn main() {
     let cmd = args.next().expect("please provide args");
 
     match cmd.to_uppercase().as_str() {
-        "ARP" => {
-            let mut ip = args.next().unwrap().split('.');
-            let a = ip.next().unwrap();
-            let b = ip.next().unwrap();
-            let c = ip.next().unwrap();
-            let d = ip.next().unwrap();
-            match lookup_ip(IPAddr::V4(
-                a.parse().unwrap(),
-                b.parse().unwrap(),
-                c.parse().unwrap(),
-                d.parse().unwrap(),
-            )) {
-                Ok(Some(mac)) => println!("{a}.{b}.{c}.{d} = {mac:#X?}"),
-                Ok(None) => println!("pending answer, try again later"),
-                Err(e) => println!("Failed to lookup arp because: {e}"),
+        "ARP" => match args.next() {
+            None | Some("-a") => print_arp_table(),
+            Some(ip) => {
+                let Ok(ip) = ip.parse() else {
+                    println!("Usage: net arp [-a | <ip>]");
+                    exit()
+                };
+                match lookup_ip(ip) {
+                    Ok(Some(mac)) => println!("{ip} is at {}", MacAddr(mac)),
+                    Ok(None) => println!("{ip} didn't answer"),
+                    Err(e) => println!("Failed to lookup arp because: {e}"),
+                }
+            }
+        },
+        proto @ ("TCP" | "UDP") => {
+            let (Some(host), Some(Ok(port))) = (args.next(), args.next().map(|a| a.parse::<u16>()))
+            else {
+                println!("Usage: net {} <host> <port> [text]", proto.to_lowercase());
+                exit()
+            };
+            let ip = match resolve(host).map(|r| r.preferred()) {
+                Ok(Some(ip)) => ip,
+                Ok(None) => {
+                    println!("{host} has no address");
+                    exit()
+                }
+                Err(e) => {
+                    println!("{host}: {e}");
+                    exit()
+                }
+            };
+            let mut text = args.fold(String::new(), |mut text, a| {
+                if !text.is_empty() {
+                    text.push(' ');
+                }
+                text.push_str(a);
+                text
+            });
+            text.push_str("\r\n");
+
+            let res = match proto {
+                "TCP" => tcp(ip, port, &text),
+                _ => udp(ip, port, &text),
+            };
+            if let Err(e) = res {
+                println!("{} failed: {e}", proto.to_lowercase());
             }
         }
+        "IFCONFIG" => match args.next() {
+            None => {
+                let list = interfaces();
+                if list.is_empty() {
+                    println!("no interfaces");
+                }
+                list.iter().for_each(print_interface);
+            }
+            Some(name) => {
+                let (Some((addr, prefix_len)), gateway) =
+                    (args.next().and_then(parse_prefix), args.next())
+                else {
+                    println!("Usage: net ifconfig [<interface> <addr>/<prefix> [gateway]]");
+                    exit()
+                };
+                let gateway = match gateway.map(|g| g.parse()) {
+                    None => None,
+                    Some(Ok(g)) => Some(g),
+                    Some(Err(_)) => {
+                        println!("Invalid gateway");
+                        exit()
+                    }
+                };
+                let Some(iface) = find_interface(name) else {
+                    println!("No interface called {name}");
+                    exit()
+                };
+                let config = InterfaceConfig {
+                    addr,
+                    netmask: u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0),
+                    gateway,
+                    dns: iface.config.dns,
+                };
+                if addr.is_ipv6() || prefix_len > 32 {
+                    println!("Only ipv4 addresses can be set by hand");
+                } else if let Err(e) = set_interface_config(iface.id, config) {
+                    println!("Failed to configure {name}: {e}");
+                }
+            }
+        },
+        "ROUTE" => match args.next() {
+            None => print_routes(),
+            Some(op @ ("add" | "del")) => {
+                let (Some((dst, prefix_len)), Some(name)) =
+                    (args.next().and_then(parse_prefix), args.next())
+                else {
+                    println!("Usage: net route {op} <dst>/<prefix> <interface> [gateway]");
+                    exit()
+                };
+                let Some(iface) = find_interface(name) else {
+                    println!("No interface called {name}");
+                    exit()
+                };
+                let gateway = match args.next().map(|g| g.parse()) {
+                    None => None,
+                    Some(Ok(g)) => Some(g),
+                    Some(Err(_)) => {
+                        println!("Invalid gateway");
+                        exit()
+                    }
+                };
+                let route = Route {
+                    dst,
+                    prefix_len,
+                    gateway,
+                    interface: iface.id,
+                    origin: RouteOrigin::Static,
+                };
+                let res = match op {
+                    "add" => add_route(route),
+                    _ => delete_route(route),
+                };
+                if let Err(e) = res {
+                    println!("Failed to {op} the route: {e}");
+                }
+            }
+            Some(_) => println!(
+                "Usage: net route [add <dst>/<prefix> <interface> [gateway] | del <dst>/<prefix> <interface>]"
+            ),
+        },
         _ => println!("Unknown cmd"),
     }
     exit()
 }
 
+/// Asks for the mac of a host on our subnet, waiting until it answers or is given up on
 pub fn lookup_ip(ip: IPAddr) -> Result<Option<u64>, NotSameSubnetError> {
     let mut networking = SimpleService::with_name("NETWORKING");
     let mut buf = Vec::new();
-    serialize(&kernel_userspace::net::Networking::ArpRequest(ip), &mut buf);
+    loop {
+        serialize(&Networking::ArpRequest(ip), &mut buf);
+        networking.call(&mut buf, &mut Vec::new()).unwrap();
+
+        match deserialize(&buf).unwrap() {
+            ArpResponse::Mac(mac) => return Ok(Some(mac)),
+            ArpResponse::Pending => {
+                sleep(100);
+            }
+            ArpResponse::Unreachable => return Ok(None),
+            ArpResponse::NotSameSubnet(e) => return Err(e),
+        }
+    }
+}
+
+/// Parses an address with a prefix length like 10.0.0.0/8
+fn parse_prefix(s: &str) -> Option<(IPAddr, u8)> {
+    let (addr, len) = s.split_once('/')?;
+    Some((addr.parse().ok()?, len.parse().ok()?))
+}
+
+fn find_interface(name: &str) -> Option<InterfaceInfo> {
+    interfaces().into_iter().find(|i| i.name == name)
+}
+
+fn print_interface(iface: &InterfaceInfo) {
+    println!(
+        "{} ({}) ether {} mtu {}",
+        iface.name,
+        iface.driver,
+        MacAddr(iface.mac),
+        iface.mtu
+    );
+    let caps = &iface.caps;
+    if caps.any() {
+        let mut offloads = Vec::new();
+        if caps.ipv4_checksum {
+            offloads.push(String::from("ip-checksum"));
+        }
+        if caps.l4_checksum {
+            offloads.push(String::from("tcp/udp-checksum"));
+        }
+        if caps.scatter_gather {
+            offloads.push(String::from("scatter-gather"));
+        }
+        if caps.tso_max != 0 {
+            offloads.push(format!("tso({})", caps.tso_max));
+        }
+        println!("    offload {}", offloads.join(" "));
+    }
+    let config = &iface.config;
+    if config.addr == IPAddr::UNSPECIFIED {
+        println!("    not configured yet");
+    } else {
+        println!("    inet    {} netmask {:#X}", config.addr, config.netmask);
+        if let Some(g) = config.gateway {
+            println!("    gateway {g}");
+        }
+        for dns in &config.dns {
+            println!("    dns     {dns}");
+        }
+    }
+    for addr in iface.v6.link_local.iter().chain(&iface.v6.global) {
+        println!("    inet6   {addr}");
+    }
+    if let Some(router) = iface.v6.router {
+        println!("    router  {router}");
+    }
+}
+
+pub fn print_routes() {
+    let names = interfaces();
+    let routes = routes();
+    if routes.is_empty() {
+        println!("no routes");
+    }
+    for route in routes {
+        let name = names
+            .iter()
+            .find(|i| i.id == route.interface)
+            .map_or("?", |i| &i.name);
+        let origin = match route.origin {
+            RouteOrigin::Connected => "connected",
+            RouteOrigin::Gateway => "dhcp",
+            RouteOrigin::Router => "ra",
+            RouteOrigin::Static => "static",
+        };
+        match route.gateway {
+            Some(g) => println!(
+                "{}/{} via {g} dev {name} ({origin})",
+                route.dst, route.prefix_len
+            ),
+            None => println!("{}/{} dev {name} ({origin})", route.dst, route.prefix_len),
+        }
+    }
+}
+
+struct MacAddr(u64);
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // The mac is kept in the order it goes on the wire
+        let b = self.0.to_le_bytes();
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            b[0], b[1], b[2], b[3], b[4], b[5]
+        )
+    }
+}
+
+pub fn print_arp_table() {
+    let mut networking = SimpleService::with_name("NETWORKING");
+    let mut buf = Vec::new();
+    serialize(&Networking::ArpTable, &mut buf);
     networking.call(&mut buf, &mut Vec::new()).unwrap();
+    let entries: Vec<ArpEntry> = deserialize(&buf).unwrap();
+
+    if entries.is_empty() {
+        println!("no entries");
+    }
+    for entry in entries {
+        let secs = entry.expires_in / 1000;
+        match entry.state {
+            ArpEntryState::Reachable(mac) => println!(
+                "{} at {} on {} expires in {secs}s",
+                entry.ip,
+                MacAddr(mac),
+                entry.interface
+            ),
+            ArpEntryState::Incomplete => {
+                println!("{} at (incomplete) on {}", entry.ip, entry.interface)
+            }
+            ArpEntryState::Failed => println!(
+                "{} unreachable on {}, retrying in {secs}s",
+                entry.ip, entry.interface
+            ),
+        }
+    }
+}
+
+/// Sends text and prints whatever comes back until the other side closes
+pub fn tcp(ip: IPAddr, port: u16, text: &str) -> Result<(), NetError> {
+    let mut stream = TcpStream::connect(ip, port)?;
+    stream.write(text.as_bytes())?;
+    stream.shutdown()?;
 
-    match deserialize(&buf).unwrap() {
-        ArpResponse::Mac(mac) => return Ok(Some(mac)),
-        ArpResponse::Pending(pend) => pend?,
+    let mut buf = [0; 1024];
+    loop {
+        let len = stream.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        print!("{}", String::from_utf8_lossy(&buf[..len]));
     }
-    Ok(None)
+}
+
+/// Sends text in a datagram and prints the first reply
+pub fn udp(ip: IPAddr, port: u16, text: &str) -> Result<(), NetError> {
+    let mut socket = UdpSocket::bind(0)?;
+    socket.send_to(text.as_bytes(), ip, port)?;
+
+    let mut buf = [0; MAX_UDP_PAYLOAD];
+    let (len, src, src_port) = socket.recv_from_timeout(&mut buf, 2000)?;
+    println!("{src}:{src_port} sent {len} bytes");
+    print!("{}", String::from_utf8_lossy(&buf[..len]));
+    Ok(())
 }
 
 #[panic_handler]
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+
+use alloc::{format, string::String};
+use kernel_userspace::{
+    net::{sockets, IPAddr, SocketKind, SocketState},
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: netstat [tcp|udp]";
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let only = match args.next() {
+        None => None,
+        Some("tcp") => Some(SocketKind::Stream),
+        Some("udp") => Some(SocketKind::Datagram),
+        Some(_) => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+
+    let mut sockets = sockets();
+    sockets.retain(|s| only.is_none_or(|k| s.kind == k));
+    sockets.sort_by_key(|s| (s.kind == SocketKind::Datagram, s.local_port));
+    if sockets.is_empty() {
+        println!("no sockets");
+        exit()
+    }
+
+    println!(
+        "{:<5} {:<6} {:<28} {:<12} {:>16} {:>16} {:>7}",
+        "Proto", "Local", "Remote", "State", "Sent", "Received", "Resent"
+    );
+    for socket in sockets {
+        let proto = match socket.kind {
+            SocketKind::Stream => "tcp",
+            SocketKind::Datagram => "udp",
+        };
+        let remote = match socket.remote {
+            Some((ip @ IPAddr::V6(_), port)) => format!("[{ip}]:{port}"),
+            Some((ip, port)) => format!("{ip}:{port}"),
+            None => String::from("*"),
+        };
+        let stats = socket.stats;
+        println!(
+            "{proto:<5} {:<6} {remote:<28} {:<12} {:>16} {:>16} {:>7}",
+            socket.local_port,
+            state_name(socket.state),
+            traffic(stats.packets_sent, stats.bytes_sent),
+            traffic(stats.packets_received, stats.bytes_received),
+            stats.retransmits
+        );
+    }
+    exit()
+}
+
+fn state_name(state: SocketState) -> &'static str {
+    match state {
+        SocketState::Bound => "BOUND",
+        SocketState::Listen => "LISTEN",
+        SocketState::SynSent => "SYN_SENT",
+        SocketState::SynReceived => "SYN_RECEIVED",
+        SocketState::Established => "ESTABLISHED",
+        SocketState::FinWait1 => "FIN_WAIT1",
+        SocketState::FinWait2 => "FIN_WAIT2",
+        SocketState::Closing => "CLOSING",
+        SocketState::TimeWait => "TIME_WAIT",
+        SocketState::CloseWait => "CLOSE_WAIT",
+        SocketState::LastAck => "LAST_ACK",
+    }
+}
+
+/// Packets and bytes as `12/3.4K`
+fn traffic(packets: u64, bytes: u64) -> String {
+    let bytes = match bytes {
+        0..1024 => format!("{bytes}"),
+        1024..0x100000 => format!("{}.{}K", bytes / 1024, bytes % 1024 * 10 / 1024),
+        _ => format!("{}.{}M", bytes >> 20, ((bytes & 0xFFFFF) * 10) >> 20),
+    };
+    format!("{packets}/{bytes}")
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
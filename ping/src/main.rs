@@ -0,0 +1,131 @@
+#![no_std]
+#![no_main]
+
+use kernel_userspace::{
+    net::{resolve, NetError, PingSocket},
+    syscall::{exit, read_args, sleep},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: ping <host> [count]";
+/// How long to wait for each reply and how often requests go out, in ms
+const INTERVAL: u64 = 1000;
+const PAYLOAD: [u8; 56] = {
+    let mut p = [0; 56];
+    let mut i = 0;
+    while i < p.len() {
+        p[i] = i as u8;
+        i += 1;
+    }
+    p
+};
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let Some(host) = args.next() else {
+        println!("{USAGE}");
+        exit()
+    };
+    let count = match args.next().map(str::parse::<u16>) {
+        None => 4,
+        Some(Ok(count)) if count > 0 => count,
+        Some(_) => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+
+    let ip = match resolve(host).map(|r| r.preferred()) {
+        Ok(Some(ip)) => ip,
+        Ok(None) => {
+            println!("ping: {host} has no address");
+            exit()
+        }
+        Err(e) => {
+            println!("ping: {host}: {e}");
+            exit()
+        }
+    };
+    let mut socket = match PingSocket::open() {
+        Ok(s) => s,
+        Err(e) => {
+            println!("ping: {e}");
+            exit()
+        }
+    };
+
+    println!("PING {host} ({ip}) {} bytes of data", PAYLOAD.len());
+    let mut received = 0;
+    let (mut min, mut max, mut total) = (u64::MAX, 0, 0);
+    for seq in 1..=count {
+        if let Err(e) = socket.send(ip, seq, &PAYLOAD) {
+            println!("ping: {e}");
+            break;
+        }
+
+        // Replies to earlier requests that came in late are skipped
+        let waited = loop {
+            match socket.recv_timeout(INTERVAL) {
+                Ok(reply) if reply.seq == seq => {
+                    let rtt = reply.rtt_us;
+                    println!(
+                        "{} bytes from {}: icmp_seq={seq} ttl={} time={}.{:03} ms",
+                        reply.data.len() + 8,
+                        reply.src,
+                        reply.ttl,
+                        rtt / 1000,
+                        rtt % 1000
+                    );
+                    received += 1;
+                    min = min.min(rtt);
+                    max = max.max(rtt);
+                    total += rtt;
+                    break (rtt / 1000).min(INTERVAL);
+                }
+                Ok(_) => continue,
+                Err(NetError::WouldBlock) => {
+                    println!("Request timeout for icmp_seq={seq}");
+                    break INTERVAL;
+                }
+                Err(e) => {
+                    println!("ping: {e}");
+                    exit()
+                }
+            }
+        };
+        if seq != count {
+            sleep(INTERVAL - waited);
+        }
+    }
+
+    println!("--- {host} ping statistics ---");
+    println!(
+        "{count} packets transmitted, {received} received, {}% packet loss",
+        (count - received) as u32 * 100 / count as u32
+    );
+    if received > 0 {
+        let avg = total / received as u64;
+        println!(
+            "rtt min/avg/max = {}.{:03}/{}.{:03}/{}.{:03} ms",
+            min / 1000,
+            min % 1000,
+            avg / 1000,
+            avg % 1000,
+            max / 1000,
+            max % 1000
+        );
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
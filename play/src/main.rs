@@ -0,0 +1,237 @@
+#![no_std]
+#![no_main]
+
+use alloc::{vec, vec::Vec};
+use kernel_userspace::{
+    audio::{AudioStream, Frame, SAMPLE_RATE},
+    fs::{FSServiceError, File, SeekFrom},
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: play <file.wav> [volume 0-255]";
+/// How much of the data is read at a time
+const CHUNK: usize = 0x4000;
+const FORMAT_PCM: u16 = 1;
+
+enum WavError {
+    Fs(FSServiceError),
+    NotWav,
+    Unsupported(&'static str),
+}
+
+impl core::fmt::Display for WavError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            WavError::Fs(e) => write!(f, "{e:?}"),
+            WavError::NotWav => f.write_str("not a wav file"),
+            WavError::Unsupported(what) => f.write_str(what),
+        }
+    }
+}
+
+impl From<FSServiceError> for WavError {
+    fn from(e: FSServiceError) -> Self {
+        WavError::Fs(e)
+    }
+}
+
+struct Format {
+    channels: usize,
+    rate: u32,
+    bits: u16,
+}
+
+impl Format {
+    fn frame_size(&self) -> usize {
+        self.channels * self.bits as usize / 8
+    }
+
+    /// Turns the frames into stereo 16 bit ones, mono being played out of both sides and any
+    /// more channels than two left out
+    fn decode(&self, data: &[u8], out: &mut Vec<Frame>) {
+        let sample = |s: &[u8]| match self.bits {
+            8 => (s[0] as i16 - 128) << 8,
+            _ => i16::from_le_bytes([s[0], s[1]]),
+        };
+        let size = self.bits as usize / 8;
+        for frame in data.chunks_exact(self.frame_size()) {
+            let left = sample(frame);
+            let right = match self.channels {
+                1 => left,
+                _ => sample(&frame[size..]),
+            };
+            out.push([left, right]);
+        }
+    }
+}
+
+/// Changes the rate of the frames to [`SAMPLE_RATE`], going in a straight line between them
+struct Resampler {
+    /// How far along the input each output frame is, in 32.32 fixed point
+    step: u64,
+    pos: u64,
+    last: Frame,
+}
+
+impl Resampler {
+    fn new(rate: u32) -> Self {
+        Self {
+            step: ((rate as u64) << 32) / SAMPLE_RATE as u64,
+            pos: 0,
+            last: [0; 2],
+        }
+    }
+
+    fn resample(&mut self, input: &[Frame], out: &mut Vec<Frame>) {
+        let Some(&end) = input.last() else {
+            return;
+        };
+        // The last frame of the one before is needed to get to the first of these
+        let at = |i: usize| if i == 0 { self.last } else { input[i - 1] };
+        loop {
+            let i = (self.pos >> 32) as usize;
+            if i >= input.len() {
+                break;
+            }
+            let (a, b) = (at(i), at(i + 1));
+            let frac = (self.pos & 0xFFFF_FFFF) as i64;
+            let lerp = |a: i16, b: i16| (a as i64 + (((b as i64 - a as i64) * frac) >> 32)) as i16;
+            out.push([lerp(a[0], b[0]), lerp(a[1], b[1])]);
+            self.pos += self.step;
+        }
+        self.pos -= (input.len() as u64) << 32;
+        self.last = end;
+    }
+}
+
+/// Finds the format and moves the file to the start of the samples, returns how many bytes of
+/// them there are
+fn read_header(file: &mut File) -> Result<(Format, usize), WavError> {
+    let mut riff = [0; 12];
+    if file.read(&mut riff)? != 12 || &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(WavError::NotWav);
+    }
+
+    let mut format = None;
+    loop {
+        let mut header = [0; 8];
+        if file.read(&mut header)? != 8 {
+            return Err(WavError::NotWav);
+        }
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+        match &header[0..4] {
+            b"fmt " => {
+                let mut fmt = [0; 16];
+                if size < 16 || file.read(&mut fmt)? != 16 {
+                    return Err(WavError::NotWav);
+                }
+                let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+                if u16_at(0) != FORMAT_PCM {
+                    return Err(WavError::Unsupported("only pcm is supported"));
+                }
+                let f = Format {
+                    channels: u16_at(2) as usize,
+                    rate: u32::from_le_bytes(fmt[4..8].try_into().unwrap()),
+                    bits: u16_at(14),
+                };
+                if f.channels == 0 || f.rate == 0 {
+                    return Err(WavError::NotWav);
+                }
+                if f.bits != 8 && f.bits != 16 {
+                    return Err(WavError::Unsupported(
+                        "only 8 and 16 bit samples are supported",
+                    ));
+                }
+                format = Some(f);
+                // Chunks are padded to an even length
+                file.seek(SeekFrom::Current((size - 16 + size % 2) as isize))?;
+            }
+            b"data" => {
+                let format = format.ok_or(WavError::NotWav)?;
+                return Ok((format, size));
+            }
+            _ => {
+                file.seek(SeekFrom::Current((size + size % 2) as isize))?;
+            }
+        }
+    }
+}
+
+fn play(path: &str, volume: u8) -> Result<(), WavError> {
+    let mut file = File::open(path)?;
+    let (format, size) = read_header(&mut file)?;
+    println!(
+        "{} channels, {}Hz, {} bit, {}s",
+        format.channels,
+        format.rate,
+        format.bits,
+        size / format.frame_size() / format.rate as usize
+    );
+
+    let Some(mut stream) = AudioStream::open() else {
+        println!("play: there's nothing to play sound with");
+        exit()
+    };
+    stream.set_volume(volume);
+
+    let mut resampler = Resampler::new(format.rate);
+    // Whole frames are read at a time
+    let chunk = CHUNK - CHUNK % format.frame_size();
+    let mut data = vec![0; chunk];
+    let mut decoded = Vec::new();
+    let mut resampled = Vec::new();
+    let mut left = size;
+    while left > 0 {
+        let len = chunk.min(left);
+        let read = file.read(&mut data[..len])?;
+        if read == 0 {
+            break;
+        }
+        left -= read;
+
+        decoded.clear();
+        format.decode(&data[..read], &mut decoded);
+        resampled.clear();
+        resampler.resample(&decoded, &mut resampled);
+        if !stream.write(&resampled) {
+            println!("play: the audio service went away");
+            break;
+        }
+    }
+    stream.drain();
+    Ok(())
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let Some(path) = args.next() else {
+        println!("{USAGE}");
+        exit()
+    };
+    let volume = match args.next().map(str::parse::<u8>) {
+        None => u8::MAX,
+        Some(Ok(volume)) => volume,
+        Some(Err(_)) => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+
+    if let Err(e) = play(path, volume) {
+        println!("play: {path}: {e}");
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
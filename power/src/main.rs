@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+use core::ops::ControlFlow;
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use kernel_userspace::{
+    backoff_sleep,
+    channel::{channel_read_rs, channel_write_rs, channel_write_val, ChannelReadResult},
+    interrupt::interrupt_wait,
+    object::KernelReference,
+    power::{PowerEvent, PowerServiceMessage},
+    process::get_handle,
+    service::{deserialize, serialize, Service},
+    syscall::{exit, sleep, spawn_thread},
+    system::{system_reboot, system_shutdown, system_take_events, FixedEvents},
+    time::uptime,
+    INT_ACPI,
+};
+
+/// How long everything listening gets to stop, in ms
+const STOP_TIMEOUT: u64 = 5000;
+
+#[derive(Default)]
+struct Power {
+    listeners: Vec<KernelReference>,
+    /// Listeners that haven't said they've stopped yet
+    waiting: usize,
+    /// Set while the machine is going down, so it only happens once
+    going_down: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    Shutdown,
+    Reboot,
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let power = Arc::new(Mutex::new(Power::default()));
+
+    spawn_thread({
+        let power = power.clone();
+        move || buttons(&power)
+    });
+
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+
+    Service::new(
+        "POWER",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            let action = match deserialize(&buffer) {
+                Ok(PowerServiceMessage::Shutdown) => Action::Shutdown,
+                Ok(PowerServiceMessage::Reboot) => Action::Reboot,
+                Ok(PowerServiceMessage::Listen) => {
+                    power.lock().listeners.push(handle.clone());
+                    return ControlFlow::Continue(());
+                }
+                Ok(PowerServiceMessage::Stopped) => {
+                    let mut power = power.lock();
+                    power.waiting = power.waiting.saturating_sub(1);
+                    return ControlFlow::Continue(());
+                }
+                Err(e) => {
+                    println!("Power manager got a bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+
+            // The answers to the listeners come through here, so this can't wait for them
+            let power = power.clone();
+            let handle = handle.clone();
+            spawn_thread(move || {
+                go_down(&power, action);
+                channel_write_rs(handle.id(), serialize(&false, &mut Vec::new()), &[]);
+            });
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
+
+/// Waits for the buttons, the power button turns the machine off
+fn buttons(power: &Mutex<Power>) {
+    let interrupts = backoff_sleep(|| get_handle("INTERRUPTS"));
+    channel_write_val(interrupts, &INT_ACPI, &[]);
+
+    let mut handles = Vec::with_capacity(1);
+    match channel_read_rs(interrupts, &mut Vec::new(), &mut handles) {
+        ChannelReadResult::Ok => (),
+        e => panic!("Couldn't get the acpi interrupt: {e:?}"),
+    }
+    let acpi_ev = KernelReference::from_id(handles[0]);
+
+    loop {
+        interrupt_wait(acpi_ev.id());
+        let events = system_take_events();
+
+        if events.contains(FixedEvents::SLEEP_BUTTON) {
+            println!("Sleep button pressed, but sleeping isn't supported");
+        }
+        if events.contains(FixedEvents::POWER_BUTTON) {
+            println!("Power button pressed");
+            go_down(power, Action::Shutdown);
+        }
+    }
+}
+
+/// Tells everything listening to stop, gives it a while and then turns the machine off.
+/// Only returns if it couldn't.
+fn go_down(power: &Mutex<Power>, action: Action) {
+    let listeners = {
+        let mut power = power.lock();
+        if power.going_down {
+            return;
+        }
+        power.going_down = true;
+        // Ones that have gone away can't answer
+        let mut buffer = Vec::new();
+        let event = serialize(&PowerEvent::ShuttingDown, &mut buffer);
+        power
+            .listeners
+            .retain(|l| channel_write_rs(l.id(), event, &[]));
+        power.waiting = power.listeners.len();
+        power.listeners.len()
+    };
+
+    println!("Waiting for {listeners} services to stop");
+    let start = uptime();
+    while power.lock().waiting > 0 && uptime() - start < STOP_TIMEOUT {
+        sleep(10);
+    }
+    let waiting = power.lock().waiting;
+    if waiting > 0 {
+        println!("{waiting} services didn't stop in time");
+    }
+
+    match action {
+        Action::Shutdown => system_shutdown(),
+        Action::Reboot => system_reboot(),
+    }
+    println!("The machine didn't go down");
+    power.lock().going_down = false;
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
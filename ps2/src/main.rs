@@ -6,15 +6,16 @@ extern crate alloc;
 extern crate userspace;
 extern crate userspace_slaballoc;
 
-use alloc::vec::Vec;
+use alloc::{collections::BTreeMap, vec::Vec};
+use driver_sdk::{interrupt, service};
+use input::keyboard::layout::KeyboardLayout;
 use kernel_userspace::{
-    backoff_sleep,
-    channel::{channel_create_rs, channel_read_rs, channel_write_val, ChannelReadResult},
+    channel::{channel_read_rs, channel_write_val, ChannelReadResult},
+    input::{InputServiceMessage, KeyboardControl},
     interrupt::{interrupt_acknowledge, interrupt_set_port},
     object::{object_wait_port_rs, KernelReference, ObjectSignal},
     port::{port_create, port_wait_rs},
-    process::{get_handle, publish_handle},
-    syscall::exit,
+    service::deserialize,
     INT_KB, INT_MOUSE,
 };
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
@@ -25,9 +26,11 @@ pub mod keyboard;
 pub mod mouse;
 pub mod scancode;
 pub mod translate;
+pub mod vmmouse;
 
 #[export_name = "_start"]
 pub extern "C" fn main() {
+    driver_sdk::log::set_name("PS2");
     println!("Initalizing PS2 devices...");
     let mut ps2_controller = PS2Controller::new();
 
@@ -38,37 +41,23 @@ pub extern "C" fn main() {
     let mut buffer = Vec::with_capacity(100);
     let mut handles_buffer = Vec::with_capacity(1);
 
-    let interrupts = backoff_sleep(|| get_handle("INTERRUPTS"));
-
-    channel_write_val(interrupts, &INT_KB, &[]);
-    match channel_read_rs(interrupts, &mut buffer, &mut handles_buffer) {
-        ChannelReadResult::Ok => (),
-        _ => panic!(),
-    }
-    let kb_ev = handles_buffer[0];
-
-    channel_write_val(interrupts, &INT_MOUSE, &[]);
-    match channel_read_rs(interrupts, &mut buffer, &mut handles_buffer) {
-        ChannelReadResult::Ok => (),
-        _ => panic!(),
-    }
-    let mouse_ev = handles_buffer[0];
+    let kb_ev = interrupt::subscribe(INT_KB);
+    let mouse_ev = interrupt::subscribe(INT_MOUSE);
 
     let kb_cbk = 1;
     let ms_cbk = 2;
     let kb_srv_cbk = 3;
     let ms_srv_cbk = 4;
+    // Keyboard listeners are waited on from here up, for control messages
+    let mut next_kb_listener_cbk = 5;
 
-    let (kb_service, kb_right) = channel_create_rs();
-    publish_handle("INPUT:KB", kb_right.id());
-
-    let (ms_service, m_right) = channel_create_rs();
-    publish_handle("INPUT:MOUSE", m_right.id());
+    let kb_service = service::publish("INPUT:KB");
+    let ms_service = service::publish("INPUT:MOUSE");
 
     let port = port_create();
 
-    interrupt_set_port(kb_ev, port, kb_cbk);
-    interrupt_set_port(mouse_ev, port, ms_cbk);
+    interrupt_set_port(kb_ev.id(), port, kb_cbk);
+    interrupt_set_port(mouse_ev.id(), port, ms_cbk);
 
     ps2_controller.flush();
 
@@ -77,35 +66,76 @@ pub extern "C" fn main() {
     object_wait_port_rs(kb_service.id(), port, ObjectSignal::READABLE, kb_srv_cbk);
     object_wait_port_rs(ms_service.id(), port, ObjectSignal::READABLE, ms_srv_cbk);
 
-    let mut kb_listeners: Vec<KernelReference> = Vec::new();
+    let mut kb_listeners: BTreeMap<u64, KernelReference> = BTreeMap::new();
     let mut ms_listeners: Vec<KernelReference> = Vec::new();
+    let mut layout = KeyboardLayout::Us;
 
     loop {
         let ev = port_wait_rs(port);
 
         if ev.key == kb_cbk {
             if let Some(ev) = ps2_controller.keyboard.check_interrupts() {
-                let message = kernel_userspace::input::InputServiceMessage::KeyboardEvent(ev);
-                kb_listeners.retain(|l| channel_write_val(l.id(), &message, &[]));
+                let message = InputServiceMessage::KeyboardEvent(ev);
+                kb_listeners.retain(|_, l| channel_write_val(l.id(), &message, &[]));
             }
-            interrupt_acknowledge(kb_ev);
+            interrupt_acknowledge(kb_ev.id());
         } else if ev.key == ms_cbk {
-            if let Some(message) = ps2_controller.mouse.check_interrupts() {
-                ms_listeners.retain(|l| channel_write_val(l.id(), &message, &[]));
-            }
-            interrupt_acknowledge(mouse_ev);
+            ps2_controller.mouse.check_interrupts(|message| {
+                ms_listeners.retain(|l| channel_write_val(l.id(), &message, &[]))
+            });
+            interrupt_acknowledge(mouse_ev.id());
         } else if ev.key == kb_srv_cbk {
             match channel_read_rs(kb_service.id(), &mut buffer, &mut handles_buffer) {
                 ChannelReadResult::Ok => (),
                 e => panic!("{e:?}"),
             }
-            kb_listeners.push(KernelReference::from_id(handles_buffer[0]));
+            let listener = KernelReference::from_id(handles_buffer[0]);
+            let message = InputServiceMessage::KeyboardLayout(layout);
+            if channel_write_val(listener.id(), &message, &[]) {
+                object_wait_port_rs(
+                    listener.id(),
+                    port,
+                    ObjectSignal::READABLE | ObjectSignal::CHANNEL_CLOSED,
+                    next_kb_listener_cbk,
+                );
+                kb_listeners.insert(next_kb_listener_cbk, listener);
+                next_kb_listener_cbk += 1;
+            }
+            object_wait_port_rs(kb_service.id(), port, ObjectSignal::READABLE, kb_srv_cbk);
         } else if ev.key == ms_srv_cbk {
             match channel_read_rs(ms_service.id(), &mut buffer, &mut handles_buffer) {
                 ChannelReadResult::Ok => (),
                 e => panic!("{e:?}"),
             }
             ms_listeners.push(KernelReference::from_id(handles_buffer[0]));
+            object_wait_port_rs(ms_service.id(), port, ObjectSignal::READABLE, ms_srv_cbk);
+        } else if let Some(listener) = kb_listeners.get(&ev.key) {
+            let read = match channel_read_rs(listener.id(), &mut buffer, &mut handles_buffer) {
+                ChannelReadResult::Ok => true,
+                ChannelReadResult::Empty => false,
+                _ => {
+                    kb_listeners.remove(&ev.key);
+                    continue;
+                }
+            };
+            object_wait_port_rs(
+                listener.id(),
+                port,
+                ObjectSignal::READABLE | ObjectSignal::CHANNEL_CLOSED,
+                ev.key,
+            );
+            if !read {
+                continue;
+            }
+            match deserialize(&buffer) {
+                Ok(KeyboardControl::SetLayout(new)) => {
+                    println!("Keyboard layout set to {}", new.name());
+                    layout = new;
+                    let message = InputServiceMessage::KeyboardLayout(layout);
+                    kb_listeners.retain(|_, l| channel_write_val(l.id(), &message, &[]));
+                }
+                Err(e) => println!("PS2 got a bad keyboard control message: {e:?}"),
+            }
         }
     }
 }
@@ -265,6 +295,5 @@ impl PS2Controller {
 
 #[panic_handler]
 fn panic(i: &core::panic::PanicInfo) -> ! {
-    println!("{}", i);
-    exit()
+    driver_sdk::log::panic(i)
 }
@@ -1,7 +1,7 @@
 use input::mouse::MousePacket;
 use kernel_userspace::input::InputServiceMessage;
 
-use super::PS2Command;
+use super::{vmmouse::VmMouse, PS2Command};
 
 // Keycodes
 // https://www.win.tue.nl/~aeb/linux/kbd/scancodes-13.html
@@ -25,6 +25,8 @@ pub struct Mouse {
     command: PS2Command,
     mouse_type: MouseTypeId,
     packet_state: PS2MousePackets,
+    /// Where the packets really come from when running in a virtual machine that has it
+    vmmouse: Option<VmMouse>,
 }
 
 impl Mouse {
@@ -33,6 +35,7 @@ impl Mouse {
             command,
             mouse_type: MouseTypeId::Standard,
             packet_state: PS2MousePackets::None,
+            vmmouse: None,
         }
     }
 
@@ -108,21 +111,25 @@ impl Mouse {
             println!("Mode: {}", mode);
         }
         if mode == 3 {
-            // Try and upgrade again
+            // Try and upgrade again, for buttons 4 and 5
             self.send_command(0xF3)?;
             self.send_command(200)?;
 
             self.send_command(0xF3)?;
-            self.send_command(100)?;
+            self.send_command(200)?;
 
             self.send_command(0xF3)?;
             self.send_command(80)?;
 
             self.send_command(0xF2)?;
-            let mode = self.command.read()?;
+            mode = self.command.read()?;
             println!("Mode: {}", mode);
         }
 
+        // Put the sample rate back to the default after the magic sequences
+        self.send_command(0xF3)?;
+        self.send_command(100)?;
+
         // Save mouse type
         self.mouse_type = match mode {
             0 => MouseTypeId::Standard,
@@ -135,38 +142,60 @@ impl Mouse {
         // Enable packet streaming (aka interrupts)
         self.send_command(0xF4)?;
 
+        self.vmmouse = VmMouse::enable();
+        if self.vmmouse.is_some() {
+            println!("Using the vmmouse absolute pointer");
+        }
+
         Ok(())
     }
 
-    pub fn check_interrupts(&mut self) -> Option<InputServiceMessage> {
+    /// Calls f with each event the interrupt finished, a vmmouse can have queued more than one
+    pub fn check_interrupts(&mut self, mut f: impl FnMut(InputServiceMessage)) {
         let data: u8 = unsafe { self.command.data_port.read() };
         let mut res = None;
         self.packet_state = match (&self.packet_state, &self.mouse_type) {
             (PS2MousePackets::None, _) => PS2MousePackets::One(data),
             (PS2MousePackets::One(a), _) => PS2MousePackets::Two(*a, data),
             (PS2MousePackets::Two(a, b), MouseTypeId::Standard) => {
-                res = Some(self.send_packet(*a, *b, data));
+                res = Some(self.send_packet(*a, *b, data, 0));
                 PS2MousePackets::None
             }
             (_, MouseTypeId::Standard) => unreachable!(),
             (PS2MousePackets::Two(a, b), _) => PS2MousePackets::Three(*a, *b, data),
-            (
-                PS2MousePackets::Three(a, b, c),
-                MouseTypeId::WithExtraButtons | MouseTypeId::WithScrollWheel,
-            ) => {
-                // Discard scroll wheel for now
-                res = Some(self.send_packet(*a, *b, *c));
+            (PS2MousePackets::Three(a, b, c), _) => {
+                res = Some(self.send_packet(*a, *b, *c, data));
                 PS2MousePackets::None
             }
         };
-        res
+
+        let Some(packet) = res else {
+            return;
+        };
+        match &mut self.vmmouse {
+            // The ps2 packet is only there to interrupt
+            Some(vmmouse) => vmmouse.read(|p| f(InputServiceMessage::MouseAbsolute(p))),
+            None => f(packet),
+        }
     }
 
-    pub fn send_packet(&mut self, p1: u8, p2: u8, p3: u8) -> InputServiceMessage {
+    /// p4 is only sent by mice with a scroll wheel, it is 0 otherwise
+    pub fn send_packet(&mut self, p1: u8, p2: u8, p3: u8, p4: u8) -> InputServiceMessage {
         let left = p1 & 0b0000_0001 > 0;
         let right = p1 & 0b0000_0010 > 0;
         let middle = p1 & 0b0000_0100 > 0;
 
+        let (wheel, button4, button5) = match self.mouse_type {
+            MouseTypeId::Standard => (0, false, false),
+            MouseTypeId::WithScrollWheel => (p4 as i8, false, false),
+            // The wheel is the bottom 4 bits, sign extended
+            MouseTypeId::WithExtraButtons => (
+                ((p4 << 4) as i8) >> 4,
+                p4 & 0b0001_0000 > 0,
+                p4 & 0b0010_0000 > 0,
+            ),
+        };
+
         let mut x: i16 = p2.into();
         // X is negative
         if p1 & 0b0001_0000 > 0 {
@@ -187,6 +216,9 @@ impl Mouse {
             middle,
             x_mov: x as i8,
             y_mov: y as i8,
+            wheel,
+            button4,
+            button5,
         };
         InputServiceMessage::MouseEvent(packet)
     }
@@ -148,6 +148,7 @@ impl ScancodeSet2 {
             0x5A => Control::Enter.into(),
             0x5B => Misc::RightBracket.into(),
             0x5D => Misc::BackSlash.into(),
+            0x61 => Misc::NonUsBackSlash.into(),
 
             0x66 => Control::Backspace.into(),
             0x69 => Numpad::N1.into(),
@@ -0,0 +1,113 @@
+//! The absolute pointer VMware and QEMU give through the VMware backdoor port.
+//!
+//! The ps2 mouse keeps interrupting, but its packets are only a nudge to read the real ones
+//! from the backdoor's queue. Those have where the host cursor is, so the pointer never drifts
+//! from it like summed up ps2 movements do.
+
+use core::arch::asm;
+
+use input::mouse::AbsoluteMousePacket;
+
+const MAGIC: u32 = 0x564D_5868;
+const PORT: u16 = 0x5658;
+
+const CMD_GETVERSION: u32 = 10;
+const CMD_ABSPOINTER_DATA: u32 = 39;
+const CMD_ABSPOINTER_STATUS: u32 = 40;
+const CMD_ABSPOINTER_COMMAND: u32 = 41;
+
+const ABSPOINTER_ENABLE: u32 = 0x4541_4552;
+const ABSPOINTER_DISABLE: u32 = 0xF5;
+const ABSPOINTER_REQUEST_ABSOLUTE: u32 = 0x5342_4152;
+
+/// Read back after enabling
+const VERSION_ID: u32 = 0x3442_554A;
+/// Set in the status when the queue overflowed, it has to be enabled again
+const STATUS_ERROR: u32 = 0xFFFF_0000;
+/// Each packet is 4 words: buttons, x, y and the wheel
+const PACKET_WORDS: u32 = 4;
+
+const LEFT_BUTTON: u32 = 0x20;
+const RIGHT_BUTTON: u32 = 0x10;
+const MIDDLE_BUTTON: u32 = 0x08;
+
+/// Returns eax, ebx, ecx and edx
+fn backdoor(cmd: u32, arg: u32) -> [u32; 4] {
+    let (eax, ebx, ecx, edx): (u32, u64, u32, u32);
+    // rbx can't be named as an operand, so the argument is swapped in and out of it
+    unsafe {
+        asm!(
+            "xchg {arg}, rbx",
+            "in eax, dx",
+            "xchg {arg}, rbx",
+            arg = inout(reg) arg as u64 => ebx,
+            inout("eax") MAGIC => eax,
+            inout("ecx") cmd => ecx,
+            inout("edx") PORT as u32 => edx,
+            options(nostack, preserves_flags),
+        );
+    }
+    [eax, ebx as u32, ecx, edx]
+}
+
+pub struct VmMouse(());
+
+impl VmMouse {
+    /// Switches the pointer to absolute mode, None if there isn't a backdoor to do it through
+    pub fn enable() -> Option<Self> {
+        // Without a backdoor the read gives all ones and ebx is left as it was
+        let [_, ebx, ..] = backdoor(CMD_GETVERSION, !MAGIC);
+        if ebx != MAGIC {
+            return None;
+        }
+
+        backdoor(CMD_ABSPOINTER_COMMAND, ABSPOINTER_ENABLE);
+        if queued() == 0 {
+            return None;
+        }
+        let [version, ..] = backdoor(CMD_ABSPOINTER_DATA, 1);
+        if version != VERSION_ID {
+            backdoor(CMD_ABSPOINTER_COMMAND, ABSPOINTER_DISABLE);
+            return None;
+        }
+
+        backdoor(CMD_ABSPOINTER_COMMAND, ABSPOINTER_REQUEST_ABSOLUTE);
+        Some(Self(()))
+    }
+
+    /// Takes every packet that is waiting
+    pub fn read(&mut self, mut f: impl FnMut(AbsoluteMousePacket)) {
+        loop {
+            let [status, ..] = backdoor(CMD_ABSPOINTER_STATUS, 0);
+            if status & STATUS_ERROR == STATUS_ERROR {
+                println!("vmmouse queue overflowed, enabling it again");
+                backdoor(CMD_ABSPOINTER_COMMAND, ABSPOINTER_DISABLE);
+                backdoor(CMD_ABSPOINTER_COMMAND, ABSPOINTER_ENABLE);
+                backdoor(CMD_ABSPOINTER_COMMAND, ABSPOINTER_REQUEST_ABSOLUTE);
+                return;
+            }
+            if status & 0xFFFF < PACKET_WORDS {
+                return;
+            }
+
+            let [buttons, x, y, z] = backdoor(CMD_ABSPOINTER_DATA, PACKET_WORDS);
+            f(AbsoluteMousePacket {
+                left: buttons & LEFT_BUTTON != 0,
+                right: buttons & RIGHT_BUTTON != 0,
+                middle: buttons & MIDDLE_BUTTON != 0,
+                x: x as u16,
+                y: y as u16,
+                wheel: z as u8 as i8,
+            });
+        }
+    }
+}
+
+fn queued() -> u32 {
+    let [status, ..] = backdoor(CMD_ABSPOINTER_STATUS, 0);
+    if status & STATUS_ERROR == STATUS_ERROR {
+        0
+    } else {
+        status & 0xFFFF
+    }
+}
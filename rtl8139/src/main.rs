@@ -0,0 +1,303 @@
+//! Driver for the Realtek RTL8139 fast ethernet controller.
+//!
+//! Received frames are copied by the card one after the other into a single ring buffer, each
+//! with a small header in front, and are read out of it in order. Sending goes through the
+//! card's four transmit descriptors, which are used in turn.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+pub mod regs;
+
+use core::{ptr::read_volatile, slice};
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use driver_sdk::{
+    log,
+    net::{self, Nic, SendError},
+    pci,
+};
+use kernel_userspace::{
+    channel::channel_write_rs,
+    dma::{DmaBuffer, DmaConstraints},
+    interrupt::interrupt_wait,
+    net::TxOffload,
+    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
+    pci::PCIDevice,
+    service::SimpleService,
+    syscall::{exit, spawn_thread, yield_now},
+};
+
+use self::regs::*;
+
+/// The receive buffer is 8KiB, with room after it for a frame that runs past the end
+const RX_BUFFER_LEN: usize = 8192;
+const RX_BUFFER_ALLOC: usize = RX_BUFFER_LEN + 16 + 1500;
+const TX_DESCS: usize = 4;
+const TX_BUFFER_SIZE: usize = 2048;
+/// The most a transmit descriptor can send
+const MAX_FRAME: usize = 1792;
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    log::set_name("rtl8139");
+    let pci_ref = KernelReferenceID::from_usize(2).unwrap();
+    assert_eq!(get_type(pci_ref), KernelObjectType::Channel);
+    let mut pci_device = PCIDevice {
+        device_service: SimpleService::new(KernelReference::from_id(pci_ref)),
+    };
+    let pci_ev = pci::interrupt(&mut pci_device);
+
+    let Some(rtl) = RTL8139::new(pci_device) else {
+        println!("RTL8139 failed to init");
+        exit()
+    };
+    let rtl = Arc::new(Mutex::new(rtl));
+
+    spawn_thread({
+        let rtl = rtl.clone();
+        move || loop {
+            interrupt_wait(pci_ev.id());
+            rtl.lock().interrupt_handler();
+        }
+    });
+
+    net::serve("rtl8139", &rtl);
+}
+
+impl Nic for RTL8139 {
+    fn mac(&mut self) -> u64 {
+        self.mac
+    }
+
+    fn listen(&mut self, listener: KernelReference) {
+        self.listeners.push(listener);
+    }
+
+    fn send(&mut self, fragments: &[&[u8]], _offload: TxOffload) -> Result<(), SendError> {
+        self.send_packet(fragments)
+    }
+}
+
+pub struct RTL8139IOPort(u16);
+
+impl RTL8139IOPort {
+    fn read_u8(&mut self, reg: u16) -> u8 {
+        unsafe { Port::new(self.0 + reg).read() }
+    }
+
+    fn read_u16(&mut self, reg: u16) -> u16 {
+        unsafe { Port::new(self.0 + reg).read() }
+    }
+
+    fn read_u32(&mut self, reg: u16) -> u32 {
+        unsafe { Port::new(self.0 + reg).read() }
+    }
+
+    fn write_u8(&mut self, reg: u16, val: u8) {
+        unsafe { Port::new(self.0 + reg).write(val) }
+    }
+
+    fn write_u16(&mut self, reg: u16, val: u16) {
+        unsafe { Port::new(self.0 + reg).write(val) }
+    }
+
+    fn write_u32(&mut self, reg: u16, val: u32) {
+        unsafe { Port::new(self.0 + reg).write(val) }
+    }
+
+    fn reset_device(&mut self) {
+        // Power on
+        self.write_u8(CONFIG1, 0);
+        self.write_u8(CR, CR_RST);
+        // The card clears it once done
+        while self.read_u8(CR) & CR_RST != 0 {
+            yield_now();
+        }
+    }
+
+    fn read_mac_addr(&mut self) -> u64 {
+        (0..6).fold(0, |mac, i| mac | (self.read_u8(IDR0 + i) as u64) << (i * 8))
+    }
+}
+
+pub struct RTL8139 {
+    io: RTL8139IOPort,
+    mac: u64,
+    recv_buffer: DmaBuffer,
+    /// Where the next frame starts in the receive buffer
+    recv_offset: usize,
+    send_buffers: DmaBuffer,
+    send_next: usize,
+    /// Which descriptors have been given a frame, which is done once the card says so
+    send_busy: [bool; TX_DESCS],
+    listeners: Vec<KernelReference>,
+}
+
+impl RTL8139 {
+    fn new(pci_device: PCIDevice) -> Option<Self> {
+        let common_header = kernel_userspace::pci::PCIHeaderCommon {
+            device: Arc::new(Mutex::new(pci_device)),
+        };
+        // Ensure device is actually supported
+        if !(common_header.get_vendor_id() == 0x10EC && common_header.get_device_id() == 0x8139) {
+            return None;
+        }
+
+        let pci_device = unsafe { common_header.get_as_header0() };
+        let mut io = RTL8139IOPort(pci_device.get_port_base()?.try_into().ok()?);
+
+        io.reset_device();
+        let mac = io.read_mac_addr();
+
+        // The card only takes 32 bit addresses, and reads the receive buffer as one block
+        let recv_buffer = DmaBuffer::new_32(RX_BUFFER_ALLOC)?;
+        // Buffers start on a page and are half a page long, so none cross into another page
+        let send_buffers = DmaBuffer::new(
+            TX_BUFFER_SIZE * TX_DESCS,
+            DmaConstraints {
+                below_4g: true,
+                ..Default::default()
+            },
+        )?;
+
+        for i in 0..TX_DESCS {
+            io.write_u32(
+                TSAD0 + i as u16 * 4,
+                send_buffers.phys_addr(i * TX_BUFFER_SIZE) as u32,
+            );
+        }
+
+        let mut this = Self {
+            io,
+            mac,
+            recv_buffer,
+            recv_offset: 0,
+            send_buffers,
+            send_next: 0,
+            send_busy: [false; TX_DESCS],
+            listeners: Vec::new(),
+        };
+        this.start();
+
+        println!("RTL8139 inited");
+        Some(this)
+    }
+
+    /// Sets up receiving from the start of the buffer and turns the card on
+    fn start(&mut self) {
+        self.recv_offset = 0;
+        self.io
+            .write_u32(RBSTART, self.recv_buffer.phys_addr(0) as u32);
+        self.io.write_u16(
+            IMR,
+            INT_ROK | INT_RER | INT_TOK | INT_TER | INT_RXOVW | INT_FOVW,
+        );
+        // Every multicast group is let through as ipv6 neighbour discovery needs them, the
+        // network stack works out which are ours
+        for i in 0..8 {
+            self.io.write_u8(MAR0 + i, 0xFF);
+        }
+        // An 8KiB buffer (RBLEN 0)
+        self.io.write_u32(RCR, RCR_APM | RCR_AM | RCR_AB | RCR_WRAP);
+        self.io.write_u8(CR, CR_RE | CR_TE);
+    }
+
+    fn interrupt_handler(&mut self) {
+        // The interrupt line is shared, so it may not be ours
+        let status = self.io.read_u16(ISR);
+        if status == 0 {
+            return;
+        }
+        // Writing the bits back clears them
+        self.io.write_u16(ISR, status);
+
+        if status & INT_RER != 0 {
+            println!("RTL8139 RECEIVE ERROR")
+        }
+        if status & INT_TER != 0 {
+            println!("RTL8139 TRANSMIT ERROR")
+        }
+        if status & (INT_RXOVW | INT_FOVW) != 0 {
+            println!("RTL8139 RECEIVE OVERFLOW")
+        }
+        if status & (INT_ROK | INT_RXOVW | INT_FOVW) != 0 {
+            self.receive();
+        }
+    }
+
+    /// Gathers the fragments into the next descriptor's buffer
+    fn send_packet(&mut self, fragments: &[&[u8]]) -> Result<(), SendError> {
+        let len: usize = fragments.iter().map(|f| f.len()).sum();
+        if len > MAX_FRAME {
+            return Err(SendError::TooLong);
+        }
+        let i = self.send_next;
+        let tsd = TSD0 + i as u16 * 4;
+        if self.send_busy[i] && self.io.read_u32(tsd) & (TSD_TOK | TSD_TABT) == 0 {
+            return Err(SendError::BufferFull);
+        }
+
+        let send_buffer = unsafe {
+            slice::from_raw_parts_mut(
+                self.send_buffers.as_ptr().add(i * TX_BUFFER_SIZE),
+                TX_BUFFER_SIZE,
+            )
+        };
+        let mut offset = 0;
+        for fragment in fragments {
+            send_buffer[offset..offset + fragment.len()].copy_from_slice(fragment);
+            offset += fragment.len();
+        }
+
+        // Writing the size clears OWN, which starts sending
+        self.io.write_u32(tsd, len as u32 & !TSD_OWN);
+        self.send_busy[i] = true;
+        self.send_next = (i + 1) % TX_DESCS;
+        Ok(())
+    }
+
+    pub fn receive(&mut self) {
+        while self.io.read_u8(CR) & CR_BUFE == 0 {
+            let offset = self.recv_offset;
+            // Each frame has its status and length (crc included) in front of it
+            let (status, len) = unsafe {
+                let header = self.recv_buffer.as_ptr().add(offset) as *const u16;
+                (read_volatile(header), read_volatile(header.add(1)) as usize)
+            };
+            if status & RX_ROK == 0 || !(4..=MAX_FRAME + 4).contains(&len) {
+                // The card has lost its place, which only a restart fixes
+                println!("RTL8139 bad receive header {status:#X}, restarting");
+                self.io.write_u8(CR, CR_TE);
+                self.start();
+                return;
+            }
+
+            let packet = unsafe {
+                slice::from_raw_parts(self.recv_buffer.as_ptr().add(offset + 4), len - 4)
+            };
+            self.listeners
+                .retain(|l| channel_write_rs(l.id(), packet, &[]));
+
+            // Frames start on a 4 byte boundary
+            self.recv_offset = ((offset + 4 + len + 3) & !3) % RX_BUFFER_LEN;
+            // The card wants it 16 short of where we have read up to
+            self.io
+                .write_u16(CAPR, (self.recv_offset as u16).wrapping_sub(16));
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
@@ -0,0 +1,47 @@
+//! The registers and bits of the RTL8139 we use, from Realtek's datasheet. All are offsets
+//! from the I/O base.
+
+/// The mac, six bytes
+pub const IDR0: u16 = 0x00;
+/// The multicast filter, eight bytes
+pub const MAR0: u16 = 0x08;
+/// Transmit status of each of the four descriptors
+pub const TSD0: u16 = 0x10;
+/// Transmit start address of each of the four descriptors
+pub const TSAD0: u16 = 0x20;
+pub const RBSTART: u16 = 0x30;
+pub const CR: u16 = 0x37;
+/// Where we have read the receive buffer up to, less 16
+pub const CAPR: u16 = 0x38;
+pub const IMR: u16 = 0x3C;
+pub const ISR: u16 = 0x3E;
+pub const RCR: u16 = 0x44;
+pub const CONFIG1: u16 = 0x52;
+
+pub const CR_BUFE: u8 = 1;
+pub const CR_TE: u8 = 1 << 2;
+pub const CR_RE: u8 = 1 << 3;
+pub const CR_RST: u8 = 1 << 4;
+
+pub const INT_ROK: u16 = 1;
+pub const INT_RER: u16 = 1 << 1;
+pub const INT_TOK: u16 = 1 << 2;
+pub const INT_TER: u16 = 1 << 3;
+pub const INT_RXOVW: u16 = 1 << 4;
+pub const INT_FOVW: u16 = 1 << 6;
+
+/// Accept frames for our mac
+pub const RCR_APM: u32 = 1 << 1;
+/// Accept multicast frames that pass the filter
+pub const RCR_AM: u32 = 1 << 2;
+pub const RCR_AB: u32 = 1 << 3;
+/// Let a frame run past the end of the buffer rather than wrap it
+pub const RCR_WRAP: u32 = 1 << 7;
+
+/// Set by the card once it has copied the frame out of the buffer
+pub const TSD_OWN: u32 = 1 << 13;
+pub const TSD_TOK: u32 = 1 << 15;
+pub const TSD_TABT: u32 = 1 << 30;
+
+/// Set in the header the card puts before each frame once it was received fine
+pub const RX_ROK: u16 = 1;
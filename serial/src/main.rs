@@ -0,0 +1,176 @@
+#![no_std]
+#![no_main]
+
+//! The driver for the first serial port. Writing bytes to `SERIAL` sends them, it takes the
+//! same messages as `STDOUT` so it can be printed to the same way. A connection to
+//! `SERIAL:IN` gets what's received once it sends anything.
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+use core::ops::ControlFlow;
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use kernel_userspace::{
+    backoff_sleep,
+    channel::{channel_read_rs, channel_write_rs, channel_write_val, ChannelReadResult},
+    interrupt::interrupt_wait,
+    object::KernelReference,
+    process::get_handle,
+    service::Service,
+    syscall::{exit, sleep, spawn_thread},
+    INT_COM1,
+};
+
+use self::uart::{Uart, BAUD};
+
+pub mod ring;
+pub mod uart;
+
+const COM1: u16 = 0x3F8;
+
+struct Serial {
+    uart: Mutex<Uart>,
+    listeners: Mutex<Vec<KernelReference>>,
+}
+
+impl Serial {
+    /// Hands what's been received to the listeners, it's kept until there are some
+    fn deliver(&self) {
+        let mut listeners = self.listeners.lock();
+        if listeners.is_empty() {
+            return;
+        }
+        let mut data = Vec::new();
+        self.uart.lock().take_received(&mut data);
+        if !data.is_empty() {
+            listeners.retain(|l| channel_write_rs(l.id(), &data, &[]));
+        }
+    }
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let mut uart = Uart::new(COM1);
+    if !uart.init() {
+        println!("No serial port");
+        exit();
+    }
+
+    let serial = Arc::new(Serial {
+        uart: Mutex::new(uart),
+        listeners: Mutex::new(Vec::new()),
+    });
+
+    spawn_thread({
+        let serial = serial.clone();
+        move || write_service(&serial)
+    });
+    spawn_thread({
+        let serial = serial.clone();
+        move || listen_service(&serial)
+    });
+
+    let interrupts = backoff_sleep(|| get_handle("INTERRUPTS"));
+    channel_write_val(interrupts, &INT_COM1, &[]);
+
+    let mut handles = Vec::with_capacity(1);
+    match channel_read_rs(interrupts, &mut Vec::new(), &mut handles) {
+        ChannelReadResult::Ok => (),
+        e => panic!("Couldn't get the com1 interrupt: {e:?}"),
+    }
+    let com1_ev = KernelReference::from_id(handles[0]);
+
+    println!("Serial ready at {BAUD} baud");
+
+    let mut dropped = 0;
+    loop {
+        // Something could have come in before the interrupt was asked for
+        serial.uart.lock().handle_interrupt();
+        serial.deliver();
+
+        let now_dropped = serial.uart.lock().dropped;
+        if now_dropped != dropped {
+            println!("Serial dropped {} bytes", now_dropped - dropped);
+            dropped = now_dropped;
+        }
+
+        interrupt_wait(com1_ev.id());
+    }
+}
+
+fn write_service(serial: &Serial) {
+    let mut buffer = Vec::with_capacity(0x1000);
+    let mut handles = Vec::new();
+    let mut out = Vec::with_capacity(0x1000);
+
+    Service::new(
+        "SERIAL",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            // The screen rubs out what's behind the cursor on a backspace, a serial terminal
+            // only moves back over it
+            out.clear();
+            for &b in buffer.iter() {
+                match b {
+                    b'\x08' => out.extend_from_slice(b"\x08 \x08"),
+                    b => out.push(b),
+                }
+            }
+
+            // Waits for room, which holds up the writer when the other end isn't keeping up
+            let mut rest = &out[..];
+            loop {
+                let taken = serial.uart.lock().queue(rest);
+                rest = &rest[taken..];
+                if rest.is_empty() {
+                    break;
+                }
+                sleep(1);
+            }
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
+
+fn listen_service(serial: &Serial) {
+    let mut buffer = Vec::new();
+    let mut handles = Vec::new();
+
+    Service::new(
+        "SERIAL:IN",
+        || false,
+        |handle, listening| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+            if !*listening {
+                *listening = true;
+                serial.listeners.lock().push(handle.clone());
+                // Whatever came in while nobody was listening
+                serial.deliver();
+            }
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
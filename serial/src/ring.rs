@@ -0,0 +1,59 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+
+/// A fixed size queue of bytes, new bytes are refused rather than overwriting old ones
+pub struct RingBuffer {
+    data: Box<[u8]>,
+    read: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size].into_boxed_slice(),
+            read: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn free(&self) -> usize {
+        self.data.len() - self.len
+    }
+
+    /// Returns false if it's full
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.free() == 0 {
+            return false;
+        }
+        let write = (self.read + self.len) % self.data.len();
+        self.data[write] = byte;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.data[self.read];
+        self.read = (self.read + 1) % self.data.len();
+        self.len -= 1;
+        Some(byte)
+    }
+
+    /// Moves everything into out
+    pub fn drain_into(&mut self, out: &mut Vec<u8>) {
+        out.reserve(self.len);
+        while let Some(byte) = self.pop() {
+            out.push(byte);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+use crate::ring::RingBuffer;
+
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const INTERRUPT_ID: u16 = 2;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+const IER_MODEM_STATUS: u8 = 1 << 3;
+
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b1110;
+const IIR_MODEM_STATUS: u8 = 0b0000;
+const IIR_TX_EMPTY: u8 = 0b0010;
+const IIR_RX_AVAILABLE: u8 = 0b0100;
+const IIR_LINE_STATUS: u8 = 0b0110;
+const IIR_RX_TIMEOUT: u8 = 0b1100;
+
+const LCR_8N1: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+
+/// Enable and clear both fifos, interrupting when 14 bytes have been received
+const FCR_ENABLE_14: u8 = 0xC7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+/// Connects the chip's interrupt line on pcs
+const MCR_OUT2: u8 = 1 << 3;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+const MSR_CTS: u8 = 1 << 4;
+
+/// The chip can be given this many bytes each time its transmit fifo empties
+const TX_FIFO_SIZE: usize = 16;
+
+const BAUD_BASE: u32 = 115200;
+pub const BAUD: u32 = 115200;
+
+const RING_SIZE: usize = 0x1000;
+/// The other end is asked to stop above the high mark and to start again below the low one
+const RX_HIGH_WATER: usize = RING_SIZE * 3 / 4;
+const RX_LOW_WATER: usize = RING_SIZE / 4;
+
+/// A 16550 with a ring buffer each way. Bytes only go out while the other end has CTS set, and
+/// RTS is dropped while the received ring is getting full.
+pub struct Uart {
+    base: u16,
+    rx: RingBuffer,
+    tx: RingBuffer,
+    /// RTS is set, the other end can send
+    rts: bool,
+    /// Bytes the chip or the ring had no room for
+    pub dropped: usize,
+}
+
+impl Uart {
+    pub fn new(base: u16) -> Self {
+        Self {
+            base,
+            rx: RingBuffer::new(RING_SIZE),
+            tx: RingBuffer::new(RING_SIZE),
+            rts: true,
+            dropped: 0,
+        }
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    fn read(&self, offset: u16) -> u8 {
+        unsafe { self.port(offset).read() }
+    }
+
+    fn write(&self, offset: u16, value: u8) {
+        unsafe { self.port(offset).write(value) }
+    }
+
+    /// Takes the chip over from the kernel, which only ever polled it to write logs. Returns
+    /// false if there isn't one.
+    pub fn init(&mut self) -> bool {
+        // Nothing answers on an empty port, so the scratch register won't keep what's written
+        self.write(SCRATCH, 0x5A);
+        if self.read(SCRATCH) != 0x5A {
+            return false;
+        }
+
+        self.write(INTERRUPT_ENABLE, 0);
+
+        let divisor = (BAUD_BASE / BAUD) as u16;
+        self.write(LINE_CONTROL, LCR_DLAB);
+        self.write(DATA, divisor as u8);
+        self.write(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+        self.write(LINE_CONTROL, LCR_8N1);
+
+        self.write(FIFO_CONTROL, FCR_ENABLE_14);
+        self.write(MODEM_CONTROL, MCR_DTR | MCR_RTS | MCR_OUT2);
+
+        // Anything left over from before would keep an edge from ever coming
+        while self.read(LINE_STATUS) & LSR_DATA_READY != 0 {
+            self.read(DATA);
+        }
+        self.read(INTERRUPT_ID);
+        self.read(MODEM_STATUS);
+
+        self.write(
+            INTERRUPT_ENABLE,
+            IER_RX_AVAILABLE | IER_TX_EMPTY | IER_LINE_STATUS | IER_MODEM_STATUS,
+        );
+        true
+    }
+
+    /// Handles everything the chip is waiting on. The interrupt is edge triggered, so it has to
+    /// be left with nothing pending or it won't interrupt again.
+    pub fn handle_interrupt(&mut self) {
+        loop {
+            let id = self.read(INTERRUPT_ID);
+            if id & IIR_NONE_PENDING != 0 {
+                break;
+            }
+            match id & IIR_ID_MASK {
+                IIR_LINE_STATUS => {
+                    if self.read(LINE_STATUS) & LSR_OVERRUN != 0 {
+                        self.dropped += 1;
+                    }
+                }
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => self.receive(),
+                IIR_TX_EMPTY => self.transmit(),
+                // CTS may have come back
+                IIR_MODEM_STATUS => {
+                    self.read(MODEM_STATUS);
+                    self.transmit();
+                }
+                _ => (),
+            }
+        }
+        self.update_rts();
+    }
+
+    fn receive(&mut self) {
+        while self.read(LINE_STATUS) & LSR_DATA_READY != 0 {
+            let byte = self.read(DATA);
+            if !self.rx.push(byte) {
+                self.dropped += 1;
+            }
+        }
+    }
+
+    /// Refills the chip's fifo if it's empty and the other end is ready
+    fn transmit(&mut self) {
+        if self.read(MODEM_STATUS) & MSR_CTS == 0 || self.read(LINE_STATUS) & LSR_TX_EMPTY == 0 {
+            return;
+        }
+        for _ in 0..TX_FIFO_SIZE {
+            let Some(byte) = self.tx.pop() else {
+                break;
+            };
+            self.write(DATA, byte);
+        }
+    }
+
+    fn update_rts(&mut self) {
+        let rts = match self.rts {
+            true => self.rx.len() < RX_HIGH_WATER,
+            false => self.rx.len() <= RX_LOW_WATER,
+        };
+        if rts != self.rts {
+            self.rts = rts;
+            let mut control = MCR_DTR | MCR_OUT2;
+            if rts {
+                control |= MCR_RTS;
+            }
+            self.write(MODEM_CONTROL, control);
+        }
+    }
+
+    /// Queues as much of data as fits and starts sending it, returns how much was taken
+    pub fn queue(&mut self, data: &[u8]) -> usize {
+        let taken = data.iter().take_while(|b| self.tx.push(**b)).count();
+        self.transmit();
+        taken
+    }
+
+    /// Moves what's been received into out, letting the other end send again if it was stopped
+    pub fn take_received(&mut self, out: &mut Vec<u8>) {
+        self.rx.drain_into(out);
+        self.update_rts();
+    }
+}
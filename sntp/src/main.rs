@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+use kernel_userspace::{
+    net::sntp::{self, SntpStatus},
+    syscall::{exit, read_args},
+    time::{uptime, DateTime},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: sntp [sync | server <host>]";
+
+fn print_status(status: &SntpStatus) {
+    match DateTime::now() {
+        Some(now) => println!("Time:   {now}"),
+        None => println!("Time:   not set"),
+    }
+    println!("Server: {}", status.server);
+    match status.last_sync {
+        Some(at) => println!(
+            "Synced: {}s ago, stratum {}, moved {}us, round trip {}us",
+            (uptime() - at) / 1000,
+            status.stratum,
+            status.offset_us,
+            status.round_trip_us
+        ),
+        None => println!("Synced: never"),
+    }
+    if let Some(e) = status.last_error {
+        println!("Last attempt failed: {e}");
+    }
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+
+    let res = match (args.next(), args.next()) {
+        (None, _) => Ok(sntp::status()),
+        (Some("sync"), None) => sntp::sync(),
+        (Some("server"), Some(server)) => sntp::set_server(server),
+        _ => {
+            println!("{USAGE}");
+            exit()
+        }
+    };
+    match res {
+        Ok(status) => print_status(&status),
+        Err(e) => println!("sntp: {e}"),
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
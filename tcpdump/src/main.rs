@@ -0,0 +1,252 @@
+#![no_std]
+#![no_main]
+
+use alloc::{format, string::String, vec::Vec};
+use kernel_userspace::{
+    fs::File,
+    net::{interfaces, Capture, CaptureDirection, CaptureFilter, CapturedFrame, IPAddr},
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: tcpdump [-i interface] [-c count] [-s snaplen] [-w file]";
+/// Enough for any frame, even ones the card will split up for us
+const DEFAULT_SNAP_LEN: u32 = 0x10000;
+/// The link type of ethernet frames in a pcap file
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// The header every pcap file starts with
+fn pcap_header(snap_len: u32) -> [u8; 24] {
+    let mut header = [0; 24];
+    header[0..4].copy_from_slice(&0xA1B2C3D4u32.to_le_bytes());
+    header[4..6].copy_from_slice(&2u16.to_le_bytes());
+    header[6..8].copy_from_slice(&4u16.to_le_bytes());
+    // The timezone and timestamp accuracy are left as 0
+    header[16..20].copy_from_slice(&snap_len.to_le_bytes());
+    header[20..24].copy_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    header
+}
+
+/// A frame as a pcap record. There is no wall clock so the timestamps are from boot.
+fn pcap_record(frame: &CapturedFrame, record: &mut Vec<u8>) {
+    record.clear();
+    record.extend_from_slice(&((frame.timestamp_us / 1_000_000) as u32).to_le_bytes());
+    record.extend_from_slice(&((frame.timestamp_us % 1_000_000) as u32).to_le_bytes());
+    record.extend_from_slice(&(frame.data.len() as u32).to_le_bytes());
+    record.extend_from_slice(&frame.len.to_le_bytes());
+    record.extend_from_slice(frame.data);
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn mac(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The ports and what is notable about a tcp or udp segment
+fn transport(protocol: u8, data: &[u8]) -> Option<(u16, u16, String)> {
+    let (src, dst) = (be16(data, 0)?, be16(data, 2)?);
+    let what = match protocol {
+        6 => {
+            let header_len = (*data.get(12)? >> 4) as usize * 4;
+            let flags = *data.get(13)?;
+            let names = [
+                (0x02, 'S'),
+                (0x01, 'F'),
+                (0x04, 'R'),
+                (0x08, 'P'),
+                (0x10, '.'),
+            ];
+            let flags: String = names
+                .iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, c)| *c)
+                .collect();
+            format!(
+                "tcp [{flags}] length {}",
+                data.len().saturating_sub(header_len)
+            )
+        }
+        17 => format!("udp length {}", data.len().saturating_sub(8)),
+        _ => return None,
+    };
+    Some((src, dst, what))
+}
+
+fn ip_summary(src: IPAddr, dst: IPAddr, protocol: u8, payload: &[u8]) -> String {
+    match protocol {
+        1 | 58 => format!("{src} > {dst}: icmp type {}", payload.first().unwrap_or(&0)),
+        p => match transport(p, payload) {
+            Some((sport, dport, what)) => format!("{src}.{sport} > {dst}.{dport}: {what}"),
+            None => format!("{src} > {dst}: protocol {p} length {}", payload.len()),
+        },
+    }
+}
+
+/// One line on what the frame is
+fn summary(data: &[u8]) -> Option<String> {
+    let ether_type = be16(data, 12)?;
+    let packet = &data[14..];
+    Some(match ether_type {
+        0x0800 => {
+            packet.get(..20)?;
+            let header_len = (*packet.first()? & 0xF) as usize * 4;
+            let total_len = be16(packet, 2)? as usize;
+            let src = IPAddr::V4(packet[12], packet[13], packet[14], packet[15]);
+            let dst = IPAddr::V4(packet[16], packet[17], packet[18], packet[19]);
+            let payload = packet.get(header_len..total_len.min(packet.len()))?;
+            // Only the first fragment has the transport header
+            if be16(packet, 6)? & 0x1FFF != 0 {
+                format!("{src} > {dst}: fragment")
+            } else {
+                ip_summary(src, dst, packet[9], payload)
+            }
+        }
+        0x86DD => {
+            packet.get(..40)?;
+            let src: [u8; 16] = packet[8..24].try_into().unwrap();
+            let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+            let len = be16(packet, 4)? as usize;
+            let payload = packet.get(40..(40 + len).min(packet.len()))?;
+            ip_summary(IPAddr::V6(src), IPAddr::V6(dst), packet[6], payload)
+        }
+        0x0806 => {
+            packet.get(..28)?;
+            let sender = IPAddr::V4(packet[14], packet[15], packet[16], packet[17]);
+            let target = IPAddr::V4(packet[24], packet[25], packet[26], packet[27]);
+            match be16(packet, 6)? {
+                1 => format!("arp who-has {target} tell {sender}"),
+                2 => format!("arp reply {sender} is-at {}", mac(&packet[8..14])),
+                op => format!("arp op {op}"),
+            }
+        }
+        t => format!(
+            "{} > {} ethertype {t:#06x} length {}",
+            mac(&data[6..12]),
+            mac(&data[0..6]),
+            data.len()
+        ),
+    })
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let mut interface = None;
+    let mut count = None;
+    let mut snap_len = DEFAULT_SNAP_LEN;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        let ok = match (arg, value) {
+            ("-i", Some(name)) => {
+                interface = interfaces().into_iter().find(|i| i.name == name);
+                if interface.is_none() {
+                    println!("tcpdump: no interface called {name}");
+                    exit()
+                }
+                true
+            }
+            ("-c", Some(c)) => c.parse().map(|c| count = Some(c)).is_ok(),
+            ("-s", Some(s)) => s.parse().map(|s| snap_len = s).is_ok(),
+            ("-w", Some(p)) => {
+                path = Some(p);
+                true
+            }
+            _ => false,
+        };
+        if !ok {
+            println!("{USAGE}");
+            exit()
+        }
+    }
+
+    let names: Vec<_> = interfaces().into_iter().map(|i| (i.id, i.name)).collect();
+    let mut capture = match Capture::open(CaptureFilter {
+        interface: interface.as_ref().map(|i| i.id),
+        snap_len,
+    }) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("tcpdump: {e}");
+            exit()
+        }
+    };
+
+    let mut file = match path {
+        Some(path) => match File::create(path).and_then(|mut f| {
+            f.write(&pcap_header(snap_len))?;
+            Ok(f)
+        }) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                println!("tcpdump: {path}: {e:?}");
+                exit()
+            }
+        },
+        None => None,
+    };
+    match interface {
+        Some(i) => println!("listening on {}", i.name),
+        None => println!("listening on every interface"),
+    }
+
+    let mut record = Vec::new();
+    let mut captured: u64 = 0;
+    while count.is_none_or(|c| captured < c) {
+        let frame = match capture.recv() {
+            Ok(f) => f,
+            Err(e) => {
+                println!("tcpdump: {e}");
+                break;
+            }
+        };
+        captured += 1;
+
+        match &mut file {
+            Some(file) => {
+                pcap_record(&frame, &mut record);
+                if let Err(e) = file.write(&record) {
+                    println!("tcpdump: {e:?}");
+                    break;
+                }
+            }
+            None => {
+                let name = names
+                    .iter()
+                    .find(|(id, _)| *id == frame.interface)
+                    .map_or("?", |(_, n)| n.as_str());
+                let direction = match frame.direction {
+                    CaptureDirection::Received => "<",
+                    CaptureDirection::Sent => ">",
+                };
+                println!(
+                    "{}.{:06} {name} {direction} {}",
+                    frame.timestamp_us / 1_000_000,
+                    frame.timestamp_us % 1_000_000,
+                    summary(frame.data).unwrap_or_else(|| format!("length {}", frame.len))
+                );
+            }
+        }
+    }
+    if let Some(path) = path {
+        println!("{captured} frames written to {path}");
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
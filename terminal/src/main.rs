@@ -2,12 +2,21 @@
 #![no_main]
 
 use kernel_userspace::{
+    backoff_sleep,
+    channel::{channel_create_rs, channel_read_rs, channel_write_rs, ChannelReadResult},
+    display::{Display, DisplayMode},
     elf::spawn_elf_process,
-    fs::{self, add_path, get_disks, read_file_sector, read_full_file, StatResponse},
+    fs::{self, add_path, get_disks, get_mounts, FSServiceError, File, IoQueue, StatResponse},
+    hwinfo::get_hwinfo,
+    input::{keyboard_layout, set_keyboard_layout},
     message::MessageHandle,
-    process::clone_init_service,
-    service::SimpleService,
-    syscall::{exit, sleep},
+    net::resolve,
+    object::REFERENCE_FIRST,
+    pci, power,
+    process::{clone_init_service, get_handle},
+    stats::{get_sched_stats, get_sched_trace, set_sched_trace, SchedStats, SchedTraceKind},
+    syscall::{exit, exit_process, read_args, sleep, spawn_thread},
+    time::DateTime,
 };
 
 extern crate alloc;
@@ -21,88 +30,211 @@ fn panic(i: &core::panic::PanicInfo) -> ! {
     exit()
 }
 
-use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
-use input::keyboard::{
-    virtual_code::{Modifier, VirtualKeyCode},
-    KeyboardEvent,
+use alloc::{boxed::Box, collections::VecDeque, format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use input::keyboard::layout::KeyboardLayout;
+use userspace::{
+    input::{KBInputDecoder, SerialInputDecoder},
+    print::WRITER,
 };
-use userspace::print::WRITER;
-
-pub struct KBInputDecoder {
-    service: SimpleService,
-    lshift: bool,
-    rshift: bool,
-    caps_lock: bool,
-    num_lock: bool,
+
+/// How much `cat` reads at a time and how many reads it keeps queued
+const CAT_CHUNK: usize = 0x1000;
+const CAT_IN_FLIGHT: usize = 8;
+
+/// Writes data to the file at path creating it if needed, either appending or replacing what was
+/// there
+fn write_to_path(path: &str, data: &[u8], append: bool) -> Result<(), FSServiceError> {
+    let mut file = match append {
+        true => File::append(path)?,
+        false => File::create(path)?,
+    };
+    file.write(data)?;
+    Ok(())
 }
 
-impl KBInputDecoder {
-    pub fn new(service: SimpleService) -> Self {
-        Self {
-            service,
-            lshift: false,
-            rshift: false,
-            caps_lock: false,
-            num_lock: false,
+/// Threads spawned at a time by `threadtest`, and how many are left running to be killed
+const THREADTEST_BATCH: usize = 64;
+const THREADTEST_PARKED: usize = 32;
+
+/// Spawns short lived threads in batches and waits for them all to finish
+fn spawn_and_reap(total: usize) {
+    let done = Arc::new(AtomicUsize::new(0));
+    for batch in (0..total).step_by(THREADTEST_BATCH) {
+        let end = total.min(batch + THREADTEST_BATCH);
+        for _ in batch..end {
+            let done = done.clone();
+            spawn_thread(move || {
+                drop(MessageHandle::create(&[1, 2, 3]));
+                done.fetch_add(1, Ordering::Relaxed);
+            });
+        }
+        while done.load(Ordering::Relaxed) < end {
+            sleep(1);
         }
     }
 }
 
-impl Iterator for KBInputDecoder {
-    type Item = char;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let ev = self.service.recv_val(&mut Vec::new())?;
-            match ev {
-                kernel_userspace::input::InputServiceMessage::KeyboardEvent(scan_code) => {
-                    match scan_code {
-                        KeyboardEvent::Up(VirtualKeyCode::Modifier(key)) => match key {
-                            Modifier::LeftShift => self.lshift = false,
-                            Modifier::RightShift => self.rshift = false,
-                            _ => {}
-                        },
-                        KeyboardEvent::Up(_) => {}
-                        KeyboardEvent::Down(VirtualKeyCode::Modifier(key)) => match key {
-                            Modifier::LeftShift => self.lshift = true,
-                            Modifier::RightShift => self.rshift = true,
-                            Modifier::CapsLock => self.caps_lock = !self.caps_lock,
-                            Modifier::NumLock => self.num_lock = !self.num_lock,
-                            _ => {}
-                        },
-                        KeyboardEvent::Down(letter) => {
-                            return Some(input::keyboard::us_keyboard::USKeymap::get_unicode(
-                                letter,
-                                self.lshift,
-                                self.rshift,
-                                self.caps_lock,
-                                self.num_lock,
-                            ));
-                        }
-                    }
-                }
-                _ => todo!(),
+/// Runs in a copy of the terminal so that its memory and handles can be looked at from outside.
+/// Lots of short lived threads are spawned and have to be reaped without leaking anything, then
+/// the copy is killed with threads still running and all of them have to go away.
+fn threadtest(total: usize, buffer: &mut Vec<u8>) {
+    let elf = match fs::stat("/boot/terminal.elf", buffer) {
+        Ok(StatResponse::File(f)) => fs::mmap(f.disk_id, f.node_id, buffer).map(|(elf, _)| elf),
+        Ok(StatResponse::Folder(_)) => Err(FSServiceError::InvalidRequestForFileType),
+        Err(e) => Err(e),
+    };
+    let elf = match elf {
+        Ok(elf) => elf,
+        Err(e) => {
+            println!("threadtest: couldn't load the terminal: {e:?}");
+            return;
+        }
+    };
+
+    let before = get_sched_stats(buffer).threads;
+    // The copy is given the channel in place of the init service
+    let (ctl, child_ctl) = channel_create_rs();
+    let args = format!("threadtest {total}");
+    let mut child =
+        match spawn_elf_process(elf.kref(), args.as_bytes(), child_ctl.id(), true, buffer) {
+            Ok(c) => c,
+            Err(e) => {
+                println!("threadtest: couldn't spawn: {e}");
+                return;
+            }
+        };
+    drop(child_ctl);
+
+    // The copy says when it has finished each step and waits for us
+    let step = |buffer: &mut Vec<u8>| {
+        let mut handles = Vec::new();
+        match channel_read_rs(ctl.id(), buffer, &mut handles) {
+            ChannelReadResult::Ok => true,
+            _ => {
+                println!("threadtest: the copy died");
+                false
             }
         }
+    };
+
+    if !step(buffer) {
+        return;
+    }
+    let usage = child.get_rusage();
+    let mem = child.get_mem_info();
+    channel_write_rs(ctl.id(), &[], &[]);
+
+    if !step(buffer) {
+        return;
+    }
+    let mut passed = true;
+    let threads = get_sched_stats(buffer).threads;
+    // Only the copy's main thread should be left
+    if threads > before + 1 {
+        println!("threadtest: {} threads leaked", threads - before - 1);
+        passed = false;
+    }
+    let handles = child.get_rusage().handle_count;
+    if handles > usage.handle_count {
+        println!(
+            "threadtest: {} handles leaked",
+            handles - usage.handle_count
+        );
+        passed = false;
+    }
+    let after = child.get_mem_info();
+    if after.mapped_pages > mem.mapped_pages || after.committed_pages > mem.committed_pages {
+        println!(
+            "threadtest: memory leaked, {}KiB more mapped and {}KiB more committed",
+            after.mapped_pages.saturating_sub(mem.mapped_pages) * 4,
+            after.committed_pages.saturating_sub(mem.committed_pages) * 4
+        );
+        passed = false;
+    }
+    channel_write_rs(ctl.id(), &[], &[]);
+
+    if !step(buffer) {
+        return;
+    }
+    child.kill();
+    child.blocking_exit_code();
+    // Give the reaper a moment to catch up
+    sleep(100);
+    let threads = get_sched_stats(buffer).threads;
+    if threads > before {
+        println!(
+            "threadtest: {} threads were still around after the kill",
+            threads - before
+        );
+        passed = false;
+    }
+
+    if passed {
+        println!("threadtest: spawned and reaped {total} threads, killed {THREADTEST_PARKED}");
     }
 }
 
+/// The copy's side of [`threadtest`]
+fn threadtest_child(total: usize) -> ! {
+    let mut buffer = Vec::new();
+    let mut step = || {
+        channel_write_rs(REFERENCE_FIRST, &[], &[]);
+        let mut handles = Vec::new();
+        if !matches!(
+            channel_read_rs(REFERENCE_FIRST, &mut buffer, &mut handles),
+            ChannelReadResult::Ok
+        ) {
+            exit_process()
+        }
+    };
+
+    // A first batch so the heap has grown to what the threads need before it is measured
+    spawn_and_reap(THREADTEST_BATCH);
+    sleep(100);
+    step();
+
+    spawn_and_reap(total);
+    // Give the reaper a moment to catch up
+    sleep(100);
+    step();
+
+    for _ in 0..THREADTEST_PARKED {
+        spawn_thread(|| loop {
+            sleep(1000);
+        });
+    }
+    // Killed while waiting
+    step();
+    exit_process()
+}
+
 #[export_name = "_start"]
 pub extern "C" fn main() {
+    let args = read_args();
+    if let Some(total) = args.strip_prefix("threadtest ") {
+        threadtest_child(total.parse().unwrap_or(4096));
+    }
+
     let mut cwd: String = String::from("/");
-    let mut partiton_id = 0u64;
 
     let mut buffer = Vec::new();
     let mut file_buffer = Vec::new();
 
-    let keyboard = SimpleService::with_name("INPUT:KB");
-
-    let mut input: KBInputDecoder = KBInputDecoder::new(keyboard);
+    // Started with `serial` it runs on the serial port instead of the screen and keyboard,
+    // programs it starts still print to the screen
+    let mut input: Box<dyn Iterator<Item = char>> = if args == "serial" {
+        let serial = backoff_sleep(|| get_handle("SERIAL"));
+        WRITER.lock().set_output(serial);
+        Box::new(SerialInputDecoder::new())
+    } else {
+        Box::new(KBInputDecoder::new())
+    };
 
     let mut input_history: VecDeque<Box<str>> = VecDeque::new();
 
     loop {
-        print!("{partiton_id}:{cwd} ");
+        print!("{cwd} ");
 
         let mut curr_line = String::new();
         let mut history_pos: usize = 0;
@@ -155,48 +287,157 @@ pub extern "C" fn main() {
         match command {
             "" => (),
             "pwd" => println!("{cwd}"),
-            "echo" => println!("{rest}"),
-            "disk" => {
-                let c = rest.trim();
-                let c = c.chars().next();
-                if let Some(chr) = c {
-                    if let Some(n) = chr.to_digit(10) {
-                        match fs::stat(n as usize, "/", &mut buffer) {
-                            Ok(StatResponse::File(_)) => println!("cd: cannot cd into a file"),
-                            Ok(StatResponse::Folder(_)) => {
-                                partiton_id = n.into();
-                            }
-                            Err(e) => println!("cd: fs error: {e:?}"),
-                        };
-
+            "echo" => match rest.split_once('>') {
+                None => println!("{rest}"),
+                Some((text, file)) => {
+                    let (append, file) = match file.strip_prefix('>') {
+                        Some(file) => (true, file),
+                        None => (false, file),
+                    };
+                    let path = add_path(&cwd, file.trim());
+                    let mut text = String::from(text.trim_end());
+                    text.push('\n');
+                    if let Err(e) = write_to_path(&path, text.as_bytes(), append) {
+                        println!("echo: {e:?}");
+                    }
+                }
+            },
+            "touch" => {
+                for file in rest.split_ascii_whitespace() {
+                    let path = add_path(&cwd, file);
+                    if fs::stat(&path, &mut buffer).is_ok() {
                         continue;
                     }
+                    if let Err(e) = fs::create_file(&path, &mut buffer) {
+                        println!("touch: {file}: {e:?}");
+                    }
                 }
-
+            }
+            "mkdir" => {
+                for folder in rest.split_ascii_whitespace() {
+                    let path = add_path(&cwd, folder);
+                    if let Err(e) = fs::create_folder(&path, &mut buffer) {
+                        println!("mkdir: {folder}: {e:?}");
+                    }
+                }
+            }
+            "rm" => {
+                for file in rest.split_ascii_whitespace() {
+                    let path = add_path(&cwd, file);
+                    if let Err(e) = fs::delete(&path, &mut buffer) {
+                        println!("rm: {file}: {e:?}");
+                    }
+                }
+            }
+            "mv" => {
+                let mut args = rest.split_ascii_whitespace();
+                let (Some(from), Some(to), None) = (args.next(), args.next(), args.next()) else {
+                    println!("mv: expected a source and a destination");
+                    continue;
+                };
+                let from = add_path(&cwd, from);
+                let to = add_path(&cwd, to);
+                if let Err(e) = fs::rename(&from, &to, &mut buffer) {
+                    println!("mv: {e:?}");
+                }
+            }
+            "ln" => {
+                let mut args = rest.split_ascii_whitespace();
+                let (Some("-s"), Some(target), Some(path), None) =
+                    (args.next(), args.next(), args.next(), args.next())
+                else {
+                    println!("ln: only symlinks are supported, ln -s <target> <path>");
+                    continue;
+                };
+                let path = add_path(&cwd, path);
+                if let Err(e) = fs::symlink(target, &path, &mut buffer) {
+                    println!("ln: {e:?}");
+                }
+            }
+            "readlink" => {
+                for file in rest.split_ascii_whitespace() {
+                    match fs::read_link(&add_path(&cwd, file), &mut buffer) {
+                        Ok(target) => println!("{target}"),
+                        Err(e) => println!("readlink: {file}: {e:?}"),
+                    }
+                }
+            }
+            "disk" => {
+                let mounts = get_mounts(&mut buffer).unwrap();
                 println!("Drives:");
                 for part in get_disks(&mut buffer).unwrap().iter() {
-                    println!("{}:", part)
+                    print!("{part}:");
+                    for mount in mounts.iter().filter(|m| m.disk_id == *part) {
+                        print!(" {}", mount.path);
+                    }
+                    println!();
+                }
+            }
+            "mount" => {
+                let Some((disk, path)) = rest.split_once(' ') else {
+                    println!("mount: expected a disk and a path");
+                    continue;
+                };
+                let Ok(disk) = disk.parse() else {
+                    println!("mount: invalid disk {disk}");
+                    continue;
+                };
+                let path = add_path(&cwd, path.trim());
+                if let Err(e) = fs::mount(disk, &path, &mut buffer) {
+                    println!("mount: {e:?}");
+                }
+            }
+            "umount" => {
+                let path = add_path(&cwd, rest);
+                if let Err(e) = fs::umount(&path, &mut buffer) {
+                    println!("umount: {e:?}");
                 }
             }
             "ls" => {
                 let path = add_path(&cwd, rest);
 
-                match fs::stat(partiton_id as usize, path.as_str(), &mut buffer) {
+                match fs::stat(path.as_str(), &mut buffer) {
                     Ok(StatResponse::File(_)) => println!("This is a file"),
-                    Ok(StatResponse::Folder(c)) => {
-                        for child in c.children {
-                            println!("{child}")
+                    Ok(StatResponse::Folder(_)) => {
+                        for child in fs::read_dir(&path) {
+                            match child {
+                                Ok(child) => println!("{child}"),
+                                Err(e) => {
+                                    println!("Error: {e:?}");
+                                    break;
+                                }
+                            }
                         }
                     }
                     Err(e) => println!("Error: {e:?}"),
                 };
             }
-            "cd" => cwd = add_path(&cwd, rest),
+            "cd" => {
+                let path = match fs::canonicalize(&add_path(&cwd, rest), &mut buffer) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        println!("cd: {e:?}");
+                        continue;
+                    }
+                };
+                match fs::stat(&path, &mut buffer) {
+                    Ok(StatResponse::Folder(_)) => cwd = path,
+                    Ok(StatResponse::File(_)) => println!("cd: Not a folder"),
+                    Err(e) => println!("cd: {e:?}"),
+                }
+            }
             "cat" => {
+                let mut queue = match IoQueue::open(&mut buffer) {
+                    Ok(q) => q,
+                    Err(e) => {
+                        println!("Error: {e:?}");
+                        continue;
+                    }
+                };
                 for file in rest.split_ascii_whitespace() {
                     let path = add_path(&cwd, file);
 
-                    let file = match fs::stat(partiton_id as usize, path.as_str(), &mut buffer) {
+                    let file = match fs::stat(path.as_str(), &mut buffer) {
                         Ok(StatResponse::File(f)) => f,
                         Ok(StatResponse::Folder(_)) => {
                             println!("Not a file");
@@ -208,35 +449,51 @@ pub extern "C" fn main() {
                         }
                     };
 
-                    for i in 0..file.file_size / 512 {
-                        let sect = match read_file_sector(
-                            partiton_id as usize,
-                            file.node_id,
-                            i as u32,
-                            &mut file_buffer,
-                        ) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                println!("Error: {e:?}");
+                    // Keep a few reads queued so the disk is never waiting on us
+                    let chunks = file.file_size.div_ceil(CAT_CHUNK);
+                    let mut sent = 0;
+                    let mut done = 0;
+                    while done < chunks {
+                        while sent < chunks && sent - done < CAT_IN_FLIGHT {
+                            let offset = sent * CAT_CHUNK;
+                            queue.read(sent as u64, file.disk_id, file.node_id, offset, CAT_CHUNK);
+                            sent += 1;
+                        }
+                        queue.submit(&mut buffer);
+
+                        done += 1;
+                        match queue.completion(&mut buffer) {
+                            Some((c, Some(data))) if c.result.is_ok() => {
+                                data.read_into_vec(&mut file_buffer);
+                                WRITER.lock().write_raw(&file_buffer);
+                            }
+                            Some((c, _)) => {
+                                println!("Error: {:?}", c.result);
+                                break;
+                            }
+                            None => {
+                                print!("Error reading");
                                 break;
                             }
-                        };
-                        if let Some(data) = sect {
-                            data.read_into_vec(&mut file_buffer);
-                            WRITER.lock().write_raw(&file_buffer);
-                        } else {
-                            print!("Error reading");
-                            break;
                         }
                     }
+                    // Don't leave the rest for the next file
+                    for _ in done..sent {
+                        queue.completion(&mut buffer);
+                    }
                 }
             }
             "exec" => {
+                // --no-aslr gives the process a predictable layout for debugging
+                let (aslr, rest) = match rest.strip_prefix("--no-aslr") {
+                    Some(rest) => (false, rest.trim_start()),
+                    None => (true, rest),
+                };
                 let (prog, args) = rest.split_once(' ').unwrap_or((rest, ""));
 
                 let path = add_path(&cwd, prog);
 
-                let stat = fs::stat(partiton_id as usize, path.as_str(), &mut buffer);
+                let stat = fs::stat(path.as_str(), &mut buffer);
 
                 let file = match stat {
                     Ok(StatResponse::File(f)) => f,
@@ -249,24 +506,24 @@ pub extern "C" fn main() {
                         continue;
                     }
                 };
-                println!("READING...");
-                let contents =
-                    match read_full_file(partiton_id as usize, file.node_id, &mut file_buffer) {
-                        Ok(Some(c)) => c,
-                        Ok(None) => {
-                            println!("Failed to read file");
-                            continue;
-                        }
-                        Err(e) => {
-                            println!("Error: {e:?}");
-                            continue;
-                        }
-                    };
+                // Read only segments are mapped straight from the page cache
+                let elf = match fs::mmap(file.disk_id, file.node_id, &mut buffer) {
+                    Ok((elf, _)) => elf,
+                    Err(e) => {
+                        println!("Error: {e:?}");
+                        continue;
+                    }
+                };
 
                 println!("SPAWNING...");
 
-                let proc =
-                    spawn_elf_process(contents, args.as_bytes(), clone_init_service(), &mut buffer);
+                let proc = spawn_elf_process(
+                    elf.kref(),
+                    args.as_bytes(),
+                    clone_init_service(),
+                    aslr,
+                    &mut buffer,
+                );
 
                 let mut proc = match proc {
                     Ok(p) => p,
@@ -278,6 +535,25 @@ pub extern "C" fn main() {
                 println!("proc!");
 
                 proc.blocking_exit_code();
+
+                let usage = proc.get_rusage();
+                println!(
+                    "user {}ms, kernel {}ms, peak mem {}KiB, committed {}/{}KiB, handles {}, switches {}",
+                    usage.user_time_us / 1000,
+                    usage.kernel_time_us / 1000,
+                    usage.peak_memory / 1024,
+                    usage.committed_memory / 1024,
+                    usage.reserved_memory / 1024,
+                    usage.handle_count,
+                    usage.context_switches
+                );
+                let mem = proc.get_mem_info();
+                println!(
+                    "still mapped {}KiB, committed {}KiB, shared {}KiB",
+                    mem.mapped_pages * 4,
+                    mem.committed_pages * 4,
+                    mem.shared_pages * 4
+                );
             }
             // "uptime" => {
             //     let mut uptime = time::uptime() / 1000;
@@ -287,6 +563,70 @@ pub extern "C" fn main() {
             //     uptime /= 60;
             //     println!("Up: {:02}:{:02}:{:02}", uptime, minutes, seconds)
             // }
+            "host" => match resolve(rest) {
+                Ok(resolved) => {
+                    for ip in resolved.v4 {
+                        println!("{rest} has address {ip}");
+                    }
+                    for ip in resolved.v6 {
+                        println!("{rest} has IPv6 address {ip}");
+                    }
+                }
+                Err(e) => println!("host: {rest}: {e}"),
+            },
+            "date" => match DateTime::now() {
+                Some(now) => println!("{now}"),
+                None => println!("date: the clock hasn't been set"),
+            },
+            "mode" => {
+                let Some(mut display) = Display::open() else {
+                    println!("mode: the display can't change its mode");
+                    continue;
+                };
+                let mode = if rest.is_empty() {
+                    display.mode()
+                } else {
+                    match rest.split_once('x').map(|(w, h)| (w.parse(), h.parse())) {
+                        Some((Ok(width), Ok(height))) => {
+                            display.set_mode(DisplayMode { width, height })
+                        }
+                        _ => {
+                            println!("Usage: mode [<width>x<height>]");
+                            continue;
+                        }
+                    }
+                };
+                match mode {
+                    Some(mode) => println!("{}x{}", mode.width, mode.height),
+                    None => println!("mode: {rest} isn't supported"),
+                }
+            }
+            "setkmap" => {
+                if rest.is_empty() {
+                    match keyboard_layout() {
+                        Some(layout) => println!("{}", layout.name()),
+                        None => println!("setkmap: there is no keyboard"),
+                    }
+                    continue;
+                }
+                match KeyboardLayout::from_name(rest) {
+                    Some(layout) => {
+                        if !set_keyboard_layout(layout) {
+                            println!("setkmap: couldn't set the layout");
+                        }
+                    }
+                    None => {
+                        print!("Usage: setkmap [");
+                        for (i, layout) in KeyboardLayout::ALL.iter().enumerate() {
+                            if i != 0 {
+                                print!("|");
+                            }
+                            print!("{}", layout.name());
+                        }
+                        println!("]");
+                    }
+                }
+            }
             "sleep" => match rest.parse::<u64>() {
                 Ok(n) => {
                     let act = sleep(n);
@@ -294,6 +634,103 @@ pub extern "C" fn main() {
                 }
                 Err(e) => println!("sleep: {e:?}"),
             },
+            "stats" => {
+                let stats = get_sched_stats(&mut buffer);
+                let [l1, l5, l15] = stats.loadavg.map(SchedStats::loadavg_parts);
+                println!(
+                    "load average: {}.{:02} {}.{:02} {}.{:02}, run queue: {}",
+                    l1.0, l1.1, l5.0, l5.1, l15.0, l15.1, stats.run_queue_depth
+                );
+                for core in stats.cores {
+                    print!(
+                        "core {}: switches {}, idle {}%",
+                        core.core_id,
+                        core.context_switches,
+                        core.idle_ms * 100 / stats.uptime.max(1)
+                    );
+                    if let Some(mhz) = core.freq_mhz {
+                        print!(", {mhz}MHz");
+                    }
+                    if let Some(temp) = core.temperature {
+                        print!(", {temp}C");
+                    }
+                    println!();
+                }
+            }
+            "sysinfo" => {
+                let Some(info) = get_hwinfo() else {
+                    println!("sysinfo: the firmware has no SMBIOS tables");
+                    continue;
+                };
+                let (system, bios) = (&info.system, &info.bios);
+                println!("{} {} {}", system.vendor, system.product, system.version);
+                println!(
+                    "BIOS: {} {} ({}), SMBIOS {}.{}",
+                    bios.vendor, bios.version, bios.release_date, info.version.0, info.version.1
+                );
+                for cpu in info.processors.iter().filter(|c| c.populated) {
+                    println!(
+                        "{}: {} {}, {} cores {} threads at {}MHz",
+                        cpu.socket,
+                        cpu.manufacturer,
+                        cpu.version,
+                        cpu.cores,
+                        cpu.threads,
+                        cpu.current_speed_mhz
+                    );
+                }
+                for dimm in info.memory.iter().filter(|m| m.size_mb > 0) {
+                    print!("{}: {}MB {}", dimm.locator, dimm.size_mb, dimm.type_name());
+                    if dimm.speed != 0 {
+                        print!(" {}MT/s", dimm.speed);
+                    }
+                    println!(" {} {}", dimm.manufacturer, dimm.part_number);
+                }
+            }
+            "trace" => match rest {
+                "on" => set_sched_trace(true, &mut buffer),
+                "off" => set_sched_trace(false, &mut buffer),
+                "" => {
+                    for core in get_sched_trace(&mut buffer) {
+                        let latencies = core
+                            .events
+                            .iter()
+                            .filter(|e| e.kind == SchedTraceKind::SwitchIn)
+                            .map(|e| e.latency_us);
+                        let count = latencies.clone().count() as u64;
+                        let max = latencies.clone().max().unwrap_or(0);
+                        let avg = latencies.sum::<u64>() / count.max(1);
+                        println!(
+                            "core {}: {} switches, runnable to running avg {}us max {}us",
+                            core.core_id, count, avg, max
+                        );
+                    }
+                }
+                _ => println!("trace: expected on, off or nothing"),
+            },
+            "rescan" => {
+                let Some(changes) = pci::rescan() else {
+                    println!("rescan: there is no pci service");
+                    continue;
+                };
+                for address in &changes.removed {
+                    println!("removed {address}");
+                }
+                for address in &changes.added {
+                    println!("added {address}");
+                }
+                if changes.added.is_empty() && changes.removed.is_empty() {
+                    println!("rescan: nothing changed");
+                }
+            }
+            "shutdown" => {
+                power::shutdown();
+                println!("shutdown: the machine couldn't be turned off");
+            }
+            "reboot" => {
+                power::reboot();
+                println!("reboot: the machine couldn't be restarted");
+            }
             "test" => {
                 let test: [u8; 6] = [1, 2, 45, 29, 23, 45];
 
@@ -306,6 +743,10 @@ pub extern "C" fn main() {
 
                 println!("Passed test");
             }
+            "threadtest" => {
+                let total = rest.parse::<usize>().unwrap_or(4096);
+                threadtest(total, &mut buffer);
+            }
             _ => {
                 println!("{command}: command not found")
             }
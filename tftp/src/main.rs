@@ -0,0 +1,200 @@
+#![no_std]
+#![no_main]
+
+use alloc::{format, string::String, vec::Vec};
+use kernel_userspace::{
+    fs::File,
+    net::{resolve, IPAddr, NetError, UdpSocket},
+    syscall::{exit, read_args},
+};
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+const USAGE: &str = "Usage: tftp <host> <remote file> [local path]";
+const TFTP_PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+/// The block size servers use if they don't understand the blksize option
+const DEFAULT_BLOCK: usize = 512;
+/// The block size asked for, a block and its headers fit in one 1500 byte ethernet frame
+const WANTED_BLOCK: usize = 1468;
+/// How long to wait for the server before sending the last packet again, in ms
+const TIMEOUT: u64 = 1000;
+const RETRIES: u32 = 5;
+
+fn error_packet(code: u16, msg: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(5 + msg.len());
+    packet.extend_from_slice(&OP_ERROR.to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(msg.as_bytes());
+    packet.push(0);
+    packet
+}
+
+fn ack_packet(block: u16) -> [u8; 4] {
+    let mut packet = [0; 4];
+    packet[..2].copy_from_slice(&OP_ACK.to_be_bytes());
+    packet[2..].copy_from_slice(&block.to_be_bytes());
+    packet
+}
+
+/// Reads the null terminated strings of an option acknowledgement as name value pairs
+fn options(data: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    let mut strings = data
+        .split(|b| *b == 0)
+        .map(|s| core::str::from_utf8(s).unwrap_or_default());
+    core::iter::from_fn(move || Some((strings.next()?, strings.next()?)))
+}
+
+/// Pulls the file from the server into path, returns how many bytes it had
+fn get(ip: IPAddr, remote: &str, path: &str) -> Result<usize, String> {
+    let mut socket = UdpSocket::bind(0).map_err(|e| format!("{e}"))?;
+
+    // Octet mode, asking for bigger blocks to cut down on round trips
+    let mut request = Vec::new();
+    request.extend_from_slice(&OP_RRQ.to_be_bytes());
+    for part in [remote, "octet", "blksize", &format!("{WANTED_BLOCK}")] {
+        request.extend_from_slice(part.as_bytes());
+        request.push(0);
+    }
+
+    let mut buf = [0; 4 + WANTED_BLOCK];
+    let mut last = request;
+    let mut last_port = TFTP_PORT;
+    // The server answers from a port of its own that the rest of the transfer uses
+    let mut server_port = None;
+    let mut block_size = DEFAULT_BLOCK;
+    let mut next_block: u16 = 1;
+    let mut total = 0;
+    let mut file = None;
+    let mut retries = 0;
+
+    loop {
+        socket
+            .send_to(&last, ip, last_port)
+            .map_err(|e| format!("{e}"))?;
+
+        let (len, port) = loop {
+            match socket.recv_from_timeout(&mut buf, TIMEOUT) {
+                Ok((len, from, port)) if from == ip && server_port.is_none_or(|p| p == port) => {
+                    break (len, port)
+                }
+                Ok((_, from, port)) => {
+                    let _ = socket.send_to(&error_packet(5, "Unknown transfer ID"), from, port);
+                }
+                Err(NetError::WouldBlock) => {
+                    retries += 1;
+                    if retries > RETRIES {
+                        return Err(String::from("server stopped responding"));
+                    }
+                    break (0, 0);
+                }
+                Err(e) => return Err(format!("{e}")),
+            }
+        };
+        if len < 4 {
+            // Timed out or a runt, try the last packet again
+            continue;
+        }
+        retries = 0;
+        server_port = Some(port);
+        last_port = port;
+
+        let op = u16::from_be_bytes([buf[0], buf[1]]);
+        let data = &buf[4..len];
+        match op {
+            OP_OACK if next_block == 1 => {
+                for (name, value) in options(&buf[2..len]) {
+                    if name.eq_ignore_ascii_case("blksize") {
+                        match value.parse::<usize>() {
+                            Ok(size @ 8..=WANTED_BLOCK) => block_size = size,
+                            _ => {
+                                let _ =
+                                    socket.send_to(&error_packet(8, "Bad block size"), ip, port);
+                                return Err(String::from("server picked a bad block size"));
+                            }
+                        }
+                    }
+                }
+                last = ack_packet(0).to_vec();
+            }
+            OP_DATA => {
+                let block = u16::from_be_bytes([buf[2], buf[3]]);
+                if block == next_block {
+                    // The file is only replaced once the server has agreed to send it
+                    let file = match &mut file {
+                        Some(f) => f,
+                        None => file.insert(File::create(path).map_err(|e| format!("{e:?}"))?),
+                    };
+                    file.write(data).map_err(|e| format!("{e:?}"))?;
+                    total += data.len();
+                    next_block = next_block.wrapping_add(1);
+                    last = ack_packet(block).to_vec();
+                    if data.len() < block_size {
+                        let _ = socket.send_to(&last, ip, port);
+                        return Ok(total);
+                    }
+                }
+                // Anything else is a resend of a block we already have, so ack it again
+            }
+            OP_ERROR => {
+                let msg = data.split(|b| *b == 0).next().unwrap_or_default();
+                return Err(format!(
+                    "server error {}: {}",
+                    u16::from_be_bytes([buf[2], buf[3]]),
+                    String::from_utf8_lossy(msg)
+                ));
+            }
+            _ => {
+                let _ = socket.send_to(&error_packet(4, "Illegal TFTP operation"), ip, port);
+                return Err(format!("unexpected opcode {op}"));
+            }
+        }
+    }
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let args = read_args();
+    let mut args = args.split_whitespace();
+    let (Some(host), Some(remote)) = (args.next(), args.next()) else {
+        println!("{USAGE}");
+        exit()
+    };
+    let path = match args.next() {
+        Some(path) => String::from(path),
+        None => format!("/{}", remote.rsplit('/').next().unwrap_or(remote)),
+    };
+
+    let ip = match resolve(host).map(|r| r.preferred()) {
+        Ok(Some(ip)) => ip,
+        Ok(None) => {
+            println!("tftp: {host} has no address");
+            exit()
+        }
+        Err(e) => {
+            println!("tftp: {host}: {e}");
+            exit()
+        }
+    };
+
+    match get(ip, remote, &path) {
+        Ok(len) => println!("saved {len} bytes to {path}"),
+        Err(e) => println!("tftp: {remote}: {e}"),
+    }
+    exit()
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
@@ -0,0 +1,167 @@
+//! Keyboard input for apps that read from the keyboard directly.
+//!
+//! The terminal stops reading `INPUT:KB` while it waits on a program, so the program can read it
+//! instead. A terminal on the serial port gets its keys from `SERIAL:IN` as bytes.
+
+use ::input::keyboard::{
+    layout::KeyboardLayout,
+    virtual_code::{Modifier, VirtualKeyCode},
+    KeyboardEvent,
+};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use kernel_userspace::input::{InputClient, InputServiceMessage};
+
+pub struct KBInputDecoder {
+    service: InputClient,
+    /// Kept up to date by `INPUT:KB`
+    layout: KeyboardLayout,
+    lshift: bool,
+    rshift: bool,
+    altgr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+}
+
+impl KBInputDecoder {
+    pub fn new() -> Self {
+        Self {
+            service: InputClient::new("INPUT:KB"),
+            layout: KeyboardLayout::Us,
+            lshift: false,
+            rshift: false,
+            altgr: false,
+            caps_lock: false,
+            num_lock: false,
+        }
+    }
+}
+
+impl Default for KBInputDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for KBInputDecoder {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.service.recv_val() {
+                InputServiceMessage::KeyboardLayout(layout) => self.layout = layout,
+                InputServiceMessage::KeyboardEvent(scan_code) => match scan_code {
+                    KeyboardEvent::Up(VirtualKeyCode::Modifier(key)) => match key {
+                        Modifier::LeftShift => self.lshift = false,
+                        Modifier::RightShift => self.rshift = false,
+                        Modifier::RightAlt => self.altgr = false,
+                        _ => {}
+                    },
+                    KeyboardEvent::Up(_) => {}
+                    KeyboardEvent::Down(VirtualKeyCode::Modifier(key)) => match key {
+                        Modifier::LeftShift => self.lshift = true,
+                        Modifier::RightShift => self.rshift = true,
+                        Modifier::RightAlt => self.altgr = true,
+                        Modifier::CapsLock => self.caps_lock = !self.caps_lock,
+                        Modifier::NumLock => self.num_lock = !self.num_lock,
+                        _ => {}
+                    },
+                    KeyboardEvent::Down(letter) => {
+                        return Some(self.layout.get_unicode(
+                            letter,
+                            self.lshift | self.rshift,
+                            self.altgr,
+                            self.caps_lock,
+                            self.num_lock,
+                        ));
+                    }
+                },
+                _ => todo!(),
+            }
+        }
+    }
+}
+
+/// Turns what a serial terminal sends into the same characters [`KBInputDecoder`] gives. Only
+/// ascii is understood, and of the escape sequences only the up and down arrows.
+pub struct SerialInputDecoder {
+    service: InputClient,
+    pending: VecDeque<u8>,
+    /// Terminals that send `\r\n` for enter shouldn't give two newlines
+    last_cr: bool,
+}
+
+impl SerialInputDecoder {
+    pub fn new() -> Self {
+        Self {
+            // Anything sent starts it listening
+            service: InputClient::greeting("SERIAL:IN"),
+            pending: VecDeque::new(),
+            last_cr: false,
+        }
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        while self.pending.is_empty() {
+            let mut data = Vec::new();
+            self.service.recv(&mut data);
+            self.pending.extend(data);
+        }
+        self.pending.pop_front()
+    }
+}
+
+impl Default for SerialInputDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for SerialInputDecoder {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let b = self.next_byte()?;
+            let last_cr = core::mem::replace(&mut self.last_cr, b == b'\r');
+            match b {
+                b'\r' => return Some('\n'),
+                b'\n' if last_cr => (),
+                0x7F => return Some('\x08'),
+                0x1B => {
+                    if self.next_byte()? != b'[' {
+                        continue;
+                    }
+                    match self.next_byte()? {
+                        b'A' => return Some('\u{2191}'),
+                        b'B' => return Some('\u{2193}'),
+                        _ => (),
+                    }
+                }
+                b if b.is_ascii() => return Some(b as char),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Reads a line from the keyboard echoing it as it is typed, without the newline
+pub fn read_line(input: &mut KBInputDecoder) -> String {
+    let mut line = String::new();
+    for c in input {
+        match c {
+            '\n' => break,
+            '\x08' => {
+                if line.pop().is_some() {
+                    crate::print!("\x08");
+                }
+            }
+            c if c.is_control() || ('\u{2190}'..='\u{21FF}').contains(&c) => (),
+            c => {
+                line.push(c);
+                crate::print!("{c}");
+            }
+        }
+    }
+    crate::print!("\n");
+    line
+}
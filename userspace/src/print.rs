@@ -25,6 +25,12 @@ pub static WRITER: Lazy<Mutex<Writer>> = Lazy::new(|| {
 });
 
 impl Writer {
+    /// Sends what's printed somewhere other than `STDOUT`, anything that takes the same messages
+    pub fn set_output(&mut self, handle: KernelReferenceID) {
+        self.stdout_socket = handle;
+        self.in_flight = 0;
+    }
+
     pub fn write_raw(&mut self, bytes: &[u8]) {
         for chunk in bytes.chunks(0x1000) {
             if self.in_flight > 100 {
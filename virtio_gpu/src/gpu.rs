@@ -0,0 +1,403 @@
+//! The 2D commands of virtio-gpu. Resources are images the host holds a copy of, backed by our
+//! memory. After drawing into the backing, the changed part is transferred to the host and
+//! flushed to the screen the resource is the scanout of.
+
+use core::{
+    mem::size_of,
+    ptr::{read_volatile, write_volatile},
+};
+
+use alloc::vec::Vec;
+use kernel_userspace::{
+    display::{DisplayMode, Rect},
+    dma::{DmaBuffer, DmaConstraints},
+};
+
+use crate::virtio::{VirtioPci, Virtqueue};
+
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+const CMD_RESOURCE_DETACH_BACKING: u32 = 0x0107;
+
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Bytes B, G, R then unused, which is 0x00RRGGBB as a little endian u32
+const FORMAT_B8G8R8X8_UNORM: u32 = 2;
+
+// Device configuration
+const CONFIG_EVENTS_READ: usize = 0x00;
+const CONFIG_EVENTS_CLEAR: usize = 0x04;
+/// The host wants the display to be a different size, like when its window was resized
+pub const EVENT_DISPLAY: u32 = 1;
+
+const CONTROL_QUEUE: u16 = 0;
+const MAX_SCANOUTS: usize = 16;
+/// The scanout the console is shown on
+const SCANOUT: u32 = 0;
+
+/// Our memory is handed to the host as at most this many pieces
+const MAX_BACKING_ENTRIES: usize = 1024;
+/// Requests are at the start of the command buffer and the response goes here
+const RESPONSE_OFFSET: usize = 0x8000;
+const COMMAND_BUFFER_SIZE: usize = 0x9000;
+
+/// Used if the host doesn't say what size it would like
+const DEFAULT_MODE: DisplayMode = DisplayMode {
+    width: 1024,
+    height: 768,
+};
+/// No mode is bigger than 8k
+const MAX_SIZE: u32 = 8192;
+
+#[repr(C)]
+#[derive(Default)]
+struct CtrlHeader {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    _padding: [u8; 3],
+}
+
+impl CtrlHeader {
+    fn new(kind: u32) -> Self {
+        Self {
+            kind,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl From<Rect> for GpuRect {
+    fn from(r: Rect) -> Self {
+        Self {
+            x: r.x,
+            y: r.y,
+            width: r.width,
+            height: r.height,
+        }
+    }
+}
+
+#[repr(C)]
+struct DisplayOne {
+    rect: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct RespDisplayInfo {
+    header: CtrlHeader,
+    modes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct ResourceCreate2d {
+    header: CtrlHeader,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+struct ResourceUnref {
+    header: CtrlHeader,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+struct SetScanout {
+    header: CtrlHeader,
+    rect: GpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+struct ResourceFlush {
+    header: CtrlHeader,
+    rect: GpuRect,
+    resource_id: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+struct TransferToHost2d {
+    header: CtrlHeader,
+    rect: GpuRect,
+    offset: u64,
+    resource_id: u32,
+    _padding: u32,
+}
+
+/// Followed by nr_entries entries of a u64 address, u32 length and u32 of padding
+#[repr(C)]
+struct ResourceAttachBacking {
+    header: CtrlHeader,
+    resource_id: u32,
+    nr_entries: u32,
+}
+
+/// Same layout as [`ResourceUnref`]
+type ResourceDetachBacking = ResourceUnref;
+
+/// A resource shown on the scanout
+pub struct Surface {
+    pub mode: DisplayMode,
+    resource_id: u32,
+    /// What the resource is backed by, which the console draws into
+    pub memory: DmaBuffer,
+}
+
+pub struct Gpu {
+    virtio: VirtioPci,
+    control: Virtqueue,
+    commands: DmaBuffer,
+    pub surface: Option<Surface>,
+    next_resource: u32,
+}
+
+impl Gpu {
+    pub fn new(virtio: VirtioPci) -> Option<Self> {
+        let control = virtio.queue(CONTROL_QUEUE)?;
+        let commands = DmaBuffer::new(
+            COMMAND_BUFFER_SIZE,
+            DmaConstraints {
+                max_segments: 1,
+                ..Default::default()
+            },
+        )?;
+        virtio.driver_ok();
+        Some(Self {
+            virtio,
+            control,
+            commands,
+            surface: None,
+            next_resource: 1,
+        })
+    }
+
+    /// Sends the request, which is followed by extra, and returns the type of the response
+    /// that's put at [`RESPONSE_OFFSET`]
+    fn command<T>(&mut self, request: T, extra: &[u8], response_len: usize) -> Option<u32> {
+        let request_len = size_of::<T>() + extra.len();
+        assert!(
+            request_len <= RESPONSE_OFFSET && response_len <= COMMAND_BUFFER_SIZE - RESPONSE_OFFSET
+        );
+        unsafe {
+            let base = self.commands.as_ptr();
+            write_volatile(base as *mut T, request);
+            core::ptr::copy_nonoverlapping(extra.as_ptr(), base.add(size_of::<T>()), extra.len());
+            write_volatile(base.add(RESPONSE_OFFSET) as *mut u32, 0);
+        }
+
+        let done = self.control.submit(&[
+            (self.commands.phys_addr(0), request_len as u32, false),
+            (
+                self.commands.phys_addr(RESPONSE_OFFSET),
+                response_len as u32,
+                true,
+            ),
+        ]);
+        if !done {
+            println!("virtio-gpu didn't answer");
+            return None;
+        }
+        Some(unsafe { read_volatile(self.commands.as_ptr().add(RESPONSE_OFFSET) as *const u32) })
+    }
+
+    /// A command answered with only a header
+    fn simple_command<T>(&mut self, request: T, extra: &[u8]) -> Option<()> {
+        match self.command(request, extra, size_of::<CtrlHeader>())? {
+            RESP_OK_NODATA => Some(()),
+            err => {
+                println!("virtio-gpu command failed with {err:#x}");
+                None
+            }
+        }
+    }
+
+    /// The size the host would like the display to be
+    pub fn preferred_mode(&mut self) -> DisplayMode {
+        let request = CtrlHeader::new(CMD_GET_DISPLAY_INFO);
+        if self.command(request, &[], size_of::<RespDisplayInfo>()) != Some(RESP_OK_DISPLAY_INFO) {
+            return DEFAULT_MODE;
+        }
+        let info =
+            unsafe { &*(self.commands.as_ptr().add(RESPONSE_OFFSET) as *const RespDisplayInfo) };
+        let display = &info.modes[SCANOUT as usize];
+        match display.enabled {
+            0 => DEFAULT_MODE,
+            _ => DisplayMode {
+                width: display.rect.width,
+                height: display.rect.height,
+            },
+        }
+    }
+
+    /// Events the host has raised since the last call, acknowledging them
+    pub fn take_events(&mut self) -> u32 {
+        let events = self.virtio.read_device32(CONFIG_EVENTS_READ);
+        if events != 0 {
+            self.virtio.write_device32(CONFIG_EVENTS_CLEAR, events);
+        }
+        events
+    }
+
+    /// Makes a resource of the size and shows it on the scanout in place of the old one, which
+    /// is returned so it can be destroyed once nothing draws into it
+    pub fn set_mode(&mut self, mode: DisplayMode) -> Option<Option<Surface>> {
+        if mode.width == 0 || mode.height == 0 || mode.width > MAX_SIZE || mode.height > MAX_SIZE {
+            return None;
+        }
+        let memory = DmaBuffer::new(
+            mode.width as usize * mode.height as usize * 4,
+            DmaConstraints {
+                max_segments: MAX_BACKING_ENTRIES,
+                ..Default::default()
+            },
+        )?;
+        let resource_id = self.next_resource;
+        self.next_resource += 1;
+
+        self.simple_command(
+            ResourceCreate2d {
+                header: CtrlHeader::new(CMD_RESOURCE_CREATE_2D),
+                resource_id,
+                format: FORMAT_B8G8R8X8_UNORM,
+                width: mode.width,
+                height: mode.height,
+            },
+            &[],
+        )?;
+
+        let mut entries = Vec::with_capacity(memory.segments().len() * 16);
+        for segment in memory.segments() {
+            entries.extend_from_slice(&segment.phys.to_le_bytes());
+            entries.extend_from_slice(&(segment.len as u32).to_le_bytes());
+            entries.extend_from_slice(&0u32.to_le_bytes());
+        }
+        let surface = Surface {
+            mode,
+            resource_id,
+            memory,
+        };
+        let attached = self.simple_command(
+            ResourceAttachBacking {
+                header: CtrlHeader::new(CMD_RESOURCE_ATTACH_BACKING),
+                resource_id,
+                nr_entries: surface.memory.segments().len() as u32,
+            },
+            &entries,
+        );
+        if attached.is_none() {
+            self.destroy(surface);
+            return None;
+        }
+
+        let rect = GpuRect {
+            x: 0,
+            y: 0,
+            width: mode.width,
+            height: mode.height,
+        };
+        let shown = self.simple_command(
+            SetScanout {
+                header: CtrlHeader::new(CMD_SET_SCANOUT),
+                rect,
+                scanout_id: SCANOUT,
+                resource_id,
+            },
+            &[],
+        );
+        if shown.is_none() {
+            self.destroy(surface);
+            return None;
+        }
+        Some(self.surface.replace(surface))
+    }
+
+    /// Frees a resource that isn't on the scanout anymore
+    pub fn destroy(&mut self, surface: Surface) {
+        self.simple_command(
+            ResourceDetachBacking {
+                header: CtrlHeader::new(CMD_RESOURCE_DETACH_BACKING),
+                resource_id: surface.resource_id,
+                _padding: 0,
+            },
+            &[],
+        );
+        self.simple_command(
+            ResourceUnref {
+                header: CtrlHeader::new(CMD_RESOURCE_UNREF),
+                resource_id: surface.resource_id,
+                _padding: 0,
+            },
+            &[],
+        );
+    }
+
+    /// Sends the part of the surface that has been drawn to the host and puts it on the screen
+    pub fn flush(&mut self, rect: Rect) {
+        let Some(surface) = &self.surface else {
+            return;
+        };
+        let mode = surface.mode;
+        let resource_id = surface.resource_id;
+        // The console can be a bit behind after a mode change
+        if rect.x >= mode.width || rect.y >= mode.height {
+            return;
+        }
+        let rect = GpuRect {
+            width: rect.width.min(mode.width - rect.x),
+            height: rect.height.min(mode.height - rect.y),
+            ..rect.into()
+        };
+
+        let transferred = self.simple_command(
+            TransferToHost2d {
+                header: CtrlHeader::new(CMD_TRANSFER_TO_HOST_2D),
+                rect,
+                offset: (rect.y as u64 * mode.width as u64 + rect.x as u64) * 4,
+                resource_id,
+                _padding: 0,
+            },
+            &[],
+        );
+        if transferred.is_some() {
+            self.simple_command(
+                ResourceFlush {
+                    header: CtrlHeader::new(CMD_RESOURCE_FLUSH),
+                    rect,
+                    resource_id,
+                    _padding: 0,
+                },
+                &[],
+            );
+        }
+    }
+
+    /// Stops the device so the host stops reading our memory
+    pub fn reset(&mut self) {
+        self.virtio.reset();
+    }
+}
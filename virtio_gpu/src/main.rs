@@ -0,0 +1,152 @@
+//! Driver for virtio-gpu, like QEMU's virtio-gpu-pci and virtio-vga, using only its 2D commands.
+//!
+//! The console is moved off the firmware's framebuffer onto a resource of ours, and what it
+//! draws is sent to the host every frame. Its size follows the host's window and can be changed
+//! through the `DISPLAY` service.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+#[macro_use]
+extern crate userspace;
+extern crate userspace_slaballoc;
+
+pub mod gpu;
+pub mod virtio;
+
+use core::ops::ControlFlow;
+
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use kernel_userspace::{
+    backoff_sleep,
+    channel::{channel_read_rs, channel_write_rs, ChannelReadResult},
+    display::{Console, DisplayMessage, DisplayMode, DisplayResponse, Framebuffer},
+    object::{get_type, KernelObjectType, KernelReference, KernelReferenceID},
+    pci::{PCIDevice, PCIHeaderCommon},
+    power::on_shutdown,
+    service::{deserialize, serialize, Service, SimpleService},
+    syscall::{exit, sleep, spawn_thread},
+};
+
+use self::{
+    gpu::{Gpu, EVENT_DISPLAY},
+    virtio::VirtioPci,
+};
+
+/// How often what the console drew is sent to the host, in ms
+const FRAME_TIME: u64 = 16;
+
+/// Shows a resource of the size and moves the console onto it
+fn set_mode(gpu: &Mutex<Gpu>, console: &mut Console, mode: DisplayMode) -> Option<DisplayMode> {
+    let mut gpu = gpu.lock();
+    let old = gpu.set_mode(mode)?;
+    let surface = gpu.surface.as_ref()?;
+    let framebuffer = Framebuffer {
+        width: mode.width,
+        height: mode.height,
+        stride: mode.width,
+    };
+    let moved = surface
+        .memory
+        .share()
+        .is_some_and(|memory| console.set_framebuffer(framebuffer, &memory));
+    if !moved {
+        println!("virtio-gpu couldn't move the console");
+    }
+    // The console has let go of the old one
+    if let Some(old) = old {
+        gpu.destroy(old);
+    }
+    Some(mode)
+}
+
+#[export_name = "_start"]
+pub extern "C" fn main() {
+    let pci_ref = KernelReferenceID::from_usize(2).unwrap();
+    assert_eq!(get_type(pci_ref), KernelObjectType::Channel);
+    let header = PCIHeaderCommon {
+        device: Arc::new(Mutex::new(PCIDevice {
+            device_service: SimpleService::new(KernelReference::from_id(pci_ref)),
+        })),
+    };
+
+    let Some(gpu) = VirtioPci::new(&header).and_then(Gpu::new) else {
+        println!("virtio-gpu failed to init");
+        exit()
+    };
+    let gpu = Arc::new(Mutex::new(gpu));
+
+    let mut console = backoff_sleep(Console::open);
+    let mode = gpu.lock().preferred_mode();
+    if set_mode(&gpu, &mut console, mode).is_none() {
+        println!("virtio-gpu couldn't set {}x{}", mode.width, mode.height);
+        exit()
+    }
+
+    on_shutdown({
+        let gpu = gpu.clone();
+        move || gpu.lock().reset()
+    });
+
+    spawn_thread({
+        let gpu = gpu.clone();
+        move || loop {
+            sleep(FRAME_TIME);
+            // Follow the size of the host's window
+            if gpu.lock().take_events() & EVENT_DISPLAY != 0 {
+                let mode = gpu.lock().preferred_mode();
+                set_mode(&gpu, &mut console, mode);
+            }
+            if let Some(damage) = console.take_damage() {
+                gpu.lock().flush(damage);
+            }
+        }
+    });
+
+    println!("virtio-gpu ready at {}x{}", mode.width, mode.height);
+
+    let mut buffer = Vec::with_capacity(0x100);
+    let mut handles = Vec::new();
+    let mut out = Vec::new();
+    // Mode changes asked for get their own connection to the console
+    let mut console = backoff_sleep(Console::open);
+
+    Service::new(
+        "DISPLAY",
+        || (),
+        |handle, ()| {
+            match channel_read_rs(handle.id(), &mut buffer, &mut handles) {
+                ChannelReadResult::Ok => (),
+                ChannelReadResult::Empty => return ControlFlow::Continue(()),
+                _ => return ControlFlow::Break(()),
+            }
+
+            let response = match deserialize(&buffer) {
+                Ok(DisplayMessage::GetMode) => match &gpu.lock().surface {
+                    Some(surface) => DisplayResponse::Mode(surface.mode),
+                    None => DisplayResponse::Unsupported,
+                },
+                Ok(DisplayMessage::SetMode(mode)) => match set_mode(&gpu, &mut console, mode) {
+                    Some(mode) => DisplayResponse::Mode(mode),
+                    None => DisplayResponse::Unsupported,
+                },
+                Err(e) => {
+                    println!("virtio-gpu got a bad message: {e:?}");
+                    return ControlFlow::Break(());
+                }
+            };
+            channel_write_rs(handle.id(), serialize(&response, &mut out), &[]);
+            ControlFlow::Continue(())
+        },
+    )
+    .run();
+}
+
+#[panic_handler]
+fn panic(i: &core::panic::PanicInfo) -> ! {
+    println!("{}", i);
+    exit()
+}
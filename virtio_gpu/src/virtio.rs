@@ -0,0 +1,252 @@
+//! The modern (virtio 1.0) PCI transport, which finds its register blocks through vendor
+//! capabilities, with [`SplitQueue`]s that have one request in them at a time.
+
+use core::ptr::{read_volatile, write_volatile};
+
+use kernel_userspace::{
+    dma::{DmaBuffer, DmaConstraints},
+    pci::{PCIHeaderCommon, CAP_VENDOR},
+    time::uptime,
+    virtio::{SplitQueue, VirtqLayout},
+};
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// Common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// Bit 32, without it the device only speaks the legacy interface
+const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+
+/// Requests only ever have a few buffers so a small queue is plenty
+const MAX_QUEUE_SIZE: u16 = 64;
+/// How long the device gets to finish a request, in ms
+const TIMEOUT: u64 = 1000;
+
+fn read32(addr: usize) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write32(addr: usize, val: u32) {
+    unsafe { write_volatile(addr as *mut u32, val) }
+}
+
+fn write64(addr: usize, val: u64) {
+    // Done as two halves, not every device takes 64 bit accesses
+    write32(addr, val as u32);
+    write32(addr + 4, (val >> 32) as u32);
+}
+
+fn read16(addr: usize) -> u16 {
+    unsafe { read_volatile(addr as *const u16) }
+}
+
+fn write16(addr: usize, val: u16) {
+    unsafe { write_volatile(addr as *mut u16, val) }
+}
+
+fn read8(addr: usize) -> u8 {
+    unsafe { read_volatile(addr as *const u8) }
+}
+
+fn write8(addr: usize, val: u8) {
+    unsafe { write_volatile(addr as *mut u8, val) }
+}
+
+/// Where the device's register blocks are mapped
+pub struct VirtioPci {
+    common: usize,
+    notify: usize,
+    notify_multiplier: u32,
+    /// The device type specific configuration
+    pub device: usize,
+}
+
+unsafe impl Send for VirtioPci {}
+
+impl VirtioPci {
+    /// Finds and maps the register blocks, then resets the device and agrees on features. Only
+    /// [`VIRTIO_F_VERSION_1`] is taken as the device's own features aren't needed.
+    pub fn new(header: &PCIHeaderCommon) -> Option<Self> {
+        let mut bars = [None; 6];
+        let (mut common, mut notify, mut device) = (None, None, None);
+        for cap in header.capabilities() {
+            if cap.id != CAP_VENDOR {
+                continue;
+            }
+            let info = header.read_config(cap.offset);
+            let cfg_type = (info >> 24) as u8;
+            let bar = header.read_config(cap.offset + 4) as u8;
+            let offset = header.read_config(cap.offset + 8) as usize;
+            if !matches!(
+                cfg_type,
+                CFG_TYPE_COMMON | CFG_TYPE_NOTIFY | CFG_TYPE_DEVICE
+            ) || bar > 5
+            {
+                continue;
+            }
+
+            if bars[bar as usize].is_none() {
+                let base = header.device.lock().get_bar(bar).and_then(|b| b.map());
+                bars[bar as usize] = Some(base? as usize);
+            }
+            let addr = bars[bar as usize]? + offset;
+            // The first of each type is the one to use
+            match cfg_type {
+                CFG_TYPE_COMMON => common = common.or(Some(addr)),
+                CFG_TYPE_NOTIFY => {
+                    let multiplier = header.read_config(cap.offset + 16);
+                    notify = notify.or(Some((addr, multiplier)))
+                }
+                _ => device = device.or(Some(addr)),
+            }
+        }
+        let (notify, notify_multiplier) = notify?;
+        let virtio = Self {
+            common: common?,
+            notify,
+            notify_multiplier,
+            device: device?,
+        };
+
+        virtio.set_status(0);
+        if !wait_for(|| virtio.status() == 0) {
+            println!("virtio device didn't reset");
+            return None;
+        }
+        virtio.set_status(STATUS_ACKNOWLEDGE);
+        virtio.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        write32(virtio.common + COMMON_DEVICE_FEATURE_SELECT, 1);
+        if read32(virtio.common + COMMON_DEVICE_FEATURE) & VIRTIO_F_VERSION_1 == 0 {
+            println!("virtio device doesn't support version 1");
+            virtio.set_status(STATUS_FAILED);
+            return None;
+        }
+        write32(virtio.common + COMMON_DRIVER_FEATURE_SELECT, 0);
+        write32(virtio.common + COMMON_DRIVER_FEATURE, 0);
+        write32(virtio.common + COMMON_DRIVER_FEATURE_SELECT, 1);
+        write32(virtio.common + COMMON_DRIVER_FEATURE, VIRTIO_F_VERSION_1);
+
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        virtio.set_status(status);
+        if virtio.status() & STATUS_FEATURES_OK == 0 {
+            println!("virtio device didn't accept our features");
+            virtio.set_status(STATUS_FAILED);
+            return None;
+        }
+        Some(virtio)
+    }
+
+    fn status(&self) -> u8 {
+        read8(self.common + COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        write8(self.common + COMMON_DEVICE_STATUS, status)
+    }
+
+    /// Tells the device we're ready, after the queues have been set up
+    pub fn driver_ok(&self) {
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK);
+    }
+
+    /// Resets the device, which stops it using any of our memory
+    pub fn reset(&self) {
+        self.set_status(0);
+    }
+
+    pub fn read_device32(&self, offset: usize) -> u32 {
+        read32(self.device + offset)
+    }
+
+    pub fn write_device32(&self, offset: usize, val: u32) {
+        write32(self.device + offset, val)
+    }
+
+    /// Sets up the queue and tells the device where it is
+    pub fn queue(&self, index: u16) -> Option<Virtqueue> {
+        write16(self.common + COMMON_QUEUE_SELECT, index);
+        let size = read16(self.common + COMMON_QUEUE_SIZE).min(MAX_QUEUE_SIZE);
+        if size < 3 {
+            return None;
+        }
+        write16(self.common + COMMON_QUEUE_SIZE, size);
+
+        // The used ring has to be 4 byte aligned
+        let layout = VirtqLayout::new(size, 4);
+        let memory = DmaBuffer::new(
+            layout.len,
+            DmaConstraints {
+                max_segments: 1,
+                ..Default::default()
+            },
+        )?;
+
+        write64(self.common + COMMON_QUEUE_DESC, memory.phys_addr(0));
+        write64(
+            self.common + COMMON_QUEUE_DRIVER,
+            memory.phys_addr(layout.avail),
+        );
+        write64(
+            self.common + COMMON_QUEUE_DEVICE,
+            memory.phys_addr(layout.used),
+        );
+        let notify_off = read16(self.common + COMMON_QUEUE_NOTIFY_OFF) as usize;
+        write16(self.common + COMMON_QUEUE_ENABLE, 1);
+
+        Some(Virtqueue {
+            index,
+            notify: self.notify + notify_off * self.notify_multiplier as usize,
+            ring: unsafe { SplitQueue::new(memory.as_ptr(), size, &layout) },
+            _memory: memory,
+        })
+    }
+}
+
+fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+    let end = uptime() + TIMEOUT;
+    while !done() {
+        if uptime() > end {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+pub struct Virtqueue {
+    index: u16,
+    notify: usize,
+    ring: SplitQueue,
+    _memory: DmaBuffer,
+}
+
+impl Virtqueue {
+    /// Puts the chain of (address, length, device writable) buffers on the queue and waits for
+    /// the device to be done with it. Returns false if it took too long.
+    pub fn submit(&mut self, buffers: &[(u64, u32, bool)]) -> bool {
+        self.ring.submit(buffers);
+        write16(self.notify, self.index);
+        wait_for(|| self.ring.poll())
+    }
+}